@@ -0,0 +1,66 @@
+// Serial against parallel offline rendering of a long stereo signal.
+//
+//     cargo bench --bench parallel_render
+//
+// The chain is mostly stateless distortion around one stateful low-pass,
+// and the parallel output is checked against the serial one before the
+// times are printed.
+use std::time::Instant;
+
+use cpal_playbook::effect::ScrubMode;
+use cpal_playbook::filters::BiquadFilter;
+use cpal_playbook::fx::Distortion;
+use cpal_playbook::render::{render, Chain, ParallelRender, RenderMode};
+
+const SAMPLE_RATE: u32 = 48000;
+const SECONDS: usize = 120;
+const RUNS: usize = 3;
+
+fn chain() -> Chain {
+    let mut chain: Chain = Vec::new();
+    for i in 0..8 {
+        chain.push(Box::new(Distortion::new(1.0 + i as f32 * 0.5, 0.8)));
+    }
+    chain.push(Box::new(BiquadFilter::new_lowpass(SAMPLE_RATE as f32, 8000.0, 0.707)));
+    for i in 0..8 {
+        chain.push(Box::new(Distortion::new(1.0 + i as f32 * 0.25, 0.9)));
+    }
+    chain
+}
+
+fn signal(frames: usize, freq: f32) -> Vec<f32> {
+    (0..frames).map(|i| 0.5 * (std::f32::consts::TAU * freq * i as f32 / SAMPLE_RATE as f32).sin()).collect()
+}
+
+// Best of `RUNS`, in seconds, and the last output.
+fn time(mode: RenderMode, input: &[Vec<f32>]) -> (f64, Vec<Vec<f32>>) {
+    let mut best = f64::INFINITY;
+    let mut output = Vec::new();
+    for _ in 0..RUNS {
+        let mut channels = input.to_vec();
+        let start = Instant::now();
+        render(&mut channels, chain, mode, &ScrubMode::Off);
+        best = best.min(start.elapsed().as_secs_f64());
+        output = channels;
+    }
+    (best, output)
+}
+
+fn main() {
+    let frames = SECONDS * SAMPLE_RATE as usize;
+    let input = vec![signal(frames, 220.0), signal(frames, 330.0)];
+    println!("{} s of stereo at {} Hz, best of {}", SECONDS, SAMPLE_RATE, RUNS);
+
+    let (serial, expected) = time(RenderMode::Serial, &input);
+    println!("serial           {:8.3} s", serial);
+    let available = ParallelRender::default().threads;
+    let mut threads = 2;
+    while threads <= available.max(2) {
+        let mode = RenderMode::Parallel(ParallelRender { threads, ..ParallelRender::default() });
+        let (parallel, output) = time(mode, &input);
+        let identical = output.iter().flatten().zip(expected.iter().flatten()).all(|(a, b)| a.to_bits() == b.to_bits());
+        assert!(identical, "parallel output differs from serial");
+        println!("{:2} threads       {:8.3} s  {:5.2}x", threads, parallel, serial / parallel);
+        threads *= 2;
+    }
+}
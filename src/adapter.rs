@@ -0,0 +1,569 @@
+// Format adaptation between any source and any sink: channel up/downmix,
+// streaming sample rate conversion and sample format conversion, composed
+// into one block stage that keeps its state across blocks.
+use crate::write_wav::WavSampleFormat;
+
+/// Half the length of the resampler's interpolation kernel, in input frames.
+pub const RESAMPLER_HALF_TAPS: usize = 16;
+/// Kernel phases tabulated between two input frames; positions in between
+/// are interpolated linearly.
+const RESAMPLER_PHASES: usize = 512;
+/// Anti-aliasing cutoff relative to the lower of the two Nyquist frequencies.
+const RESAMPLER_ROLLOFF: f64 = 0.94;
+/// Output frames the buffers are sized for up front.
+const INITIAL_BLOCK_FRAMES: usize = 4096;
+
+/// Sample rate, channel count and sample format of one end of a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AudioSpec {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub format: WavSampleFormat,
+}
+
+impl AudioSpec {
+    /// A 32-bit float spec, what devices and the DSP code work in.
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        Self {
+            sample_rate,
+            channels: channels.max(1),
+            format: WavSampleFormat::Float32,
+        }
+    }
+
+    pub fn with_format(mut self, format: WavSampleFormat) -> Self {
+        self.format = format;
+        self
+    }
+}
+
+/// Up/downmixes interleaved frames with a `dst × src` gain matrix.
+#[derive(Debug, Clone)]
+pub struct ChannelMapper {
+    src_channels: usize,
+    dst_channels: usize,
+    // Row per output channel.
+    matrix: Vec<f32>,
+    output: Vec<f32>,
+}
+
+impl ChannelMapper {
+    /// The default mapping between two channel counts:
+    ///
+    /// - mono to more channels feeds the first two (left and right),
+    /// - any count to mono averages all channels,
+    /// - 5.1 (L, R, C, LFE, Ls, Rs) to stereo is the ITU downmix: center and
+    ///   surrounds at -3 dB, LFE dropped,
+    /// - otherwise channels are kept by position, extras dropped and missing
+    ///   ones silent.
+    pub fn new(src_channels: u16, dst_channels: u16) -> Self {
+        let (src, dst) = (src_channels.max(1) as usize, dst_channels.max(1) as usize);
+        let mut matrix = vec![0.0f32; src * dst];
+        let mut set = |out: usize, input: usize, gain: f32| matrix[out * src + input] = gain;
+
+        match (src, dst) {
+            (1, _) => {
+                for out in 0..dst.min(2) {
+                    set(out, 0, 1.0);
+                }
+            }
+            (_, 1) => {
+                for input in 0..src {
+                    set(0, input, 1.0 / src as f32);
+                }
+            }
+            (6, 2) => {
+                let minus_3db = std::f32::consts::FRAC_1_SQRT_2;
+                set(0, 0, 1.0);
+                set(1, 1, 1.0);
+                set(0, 2, minus_3db);
+                set(1, 2, minus_3db);
+                set(0, 4, minus_3db);
+                set(1, 5, minus_3db);
+            }
+            _ => {
+                for channel in 0..src.min(dst) {
+                    set(channel, channel, 1.0);
+                }
+            }
+        }
+        Self::with_matrix(src_channels, dst_channels, matrix)
+    }
+
+    /// Mono onto the output channels in `outputs`, counting from 0, and
+    /// silence on the others. Channels beyond `dst_channels` are ignored.
+    pub fn mono_to(dst_channels: u16, outputs: &[usize]) -> Self {
+        let dst = dst_channels.max(1) as usize;
+        let mut matrix = vec![0.0f32; dst];
+        for &out in outputs.iter().filter(|&&out| out < dst) {
+            matrix[out] = 1.0;
+        }
+        Self::with_matrix(1, dst_channels, matrix)
+    }
+
+    /// Every channel to mono at equal power, 1/√n each: -3 dB per channel
+    /// from stereo, so a centred signal comes out 3 dB louder than with
+    /// the averaging of `new`.
+    pub fn equal_power_mono(src_channels: u16) -> Self {
+        let src = src_channels.max(1) as usize;
+        Self::with_matrix(src_channels, 1, vec![1.0 / (src as f32).sqrt(); src])
+    }
+
+    /// A custom mapping; `matrix[out * src_channels + in]` is the gain from
+    /// input channel `in` to output channel `out`.
+    pub fn with_matrix(src_channels: u16, dst_channels: u16, matrix: Vec<f32>) -> Self {
+        let (src, dst) = (src_channels.max(1) as usize, dst_channels.max(1) as usize);
+        assert_eq!(matrix.len(), src * dst, "channel matrix must be dst × src");
+        Self {
+            src_channels: src,
+            dst_channels: dst,
+            matrix,
+            output: Vec::with_capacity(INITIAL_BLOCK_FRAMES * dst),
+        }
+    }
+
+    pub fn process(&mut self, input: &[f32]) -> &[f32] {
+        self.output.clear();
+        for frame in input.chunks_exact(self.src_channels) {
+            for row in self.matrix.chunks_exact(self.src_channels) {
+                self.output.push(row.iter().zip(frame).map(|(gain, sample)| gain * sample).sum());
+            }
+        }
+        &self.output
+    }
+
+    pub fn dst_channels(&self) -> usize {
+        self.dst_channels
+    }
+
+    /// Makes room for blocks of up to `frames`, so `process` doesn't
+    /// allocate on them.
+    pub fn reserve(&mut self, frames: usize) {
+        self.output.reserve(frames * self.dst_channels);
+    }
+}
+
+/// Band-limited sample rate conversion of an interleaved stream.
+///
+/// Windowed-sinc interpolation at arbitrary ratios. Input that the kernel
+/// still needs is kept between calls, so splitting a signal into blocks of
+/// any size gives the same output as processing it in one go. Each call
+/// returns what could be computed so far; `flush` returns the rest.
+#[derive(Debug, Clone)]
+pub struct StreamingResampler {
+    channels: usize,
+    // The position advances by `src_rate` units of 1/`dst_rate` input
+    // frames per output frame, kept in integers so it never drifts.
+    src_rate: u64,
+    dst_rate: u64,
+    // (RESAMPLER_PHASES + 1) rows of 2 * RESAMPLER_HALF_TAPS taps.
+    kernel: Vec<f32>,
+    // Pending interleaved input.
+    buffer: Vec<f32>,
+    // Position of the next output frame in `buffer`, in 1/`dst_rate` frames.
+    position: u64,
+    output: Vec<f32>,
+}
+
+fn blackman(t: f64) -> f64 {
+    let t = std::f64::consts::PI * t;
+    0.42 + 0.5 * t.cos() + 0.08 * (2.0 * t).cos()
+}
+
+impl StreamingResampler {
+    pub fn new(src_rate: u32, dst_rate: u32, channels: u16) -> Self {
+        let (src_rate, dst_rate) = (src_rate.max(1) as u64, dst_rate.max(1) as u64);
+        let cutoff = RESAMPLER_ROLLOFF * (dst_rate as f64 / src_rate as f64).min(1.0);
+        let taps = 2 * RESAMPLER_HALF_TAPS;
+
+        let mut kernel = Vec::with_capacity((RESAMPLER_PHASES + 1) * taps);
+        for phase in 0..=RESAMPLER_PHASES {
+            let fraction = phase as f64 / RESAMPLER_PHASES as f64;
+            let row: Vec<f64> = (0..taps)
+                .map(|j| {
+                    let x = j as f64 + 1.0 - RESAMPLER_HALF_TAPS as f64 - fraction;
+                    let sinc = if x == 0.0 {
+                        1.0
+                    } else {
+                        (std::f64::consts::PI * cutoff * x).sin() / (std::f64::consts::PI * cutoff * x)
+                    };
+                    sinc * blackman(x / RESAMPLER_HALF_TAPS as f64)
+                })
+                .collect();
+            // Unity gain at DC for every phase.
+            let sum: f64 = row.iter().sum();
+            kernel.extend(row.iter().map(|&tap| (tap / sum) as f32));
+        }
+
+        let channels = channels.max(1) as usize;
+        let mut resampler = Self {
+            channels,
+            src_rate,
+            dst_rate,
+            kernel,
+            buffer: Vec::with_capacity((INITIAL_BLOCK_FRAMES + taps) * channels),
+            position: 0,
+            output: Vec::with_capacity(INITIAL_BLOCK_FRAMES * channels),
+        };
+        resampler.reset();
+        resampler
+    }
+
+    /// Forgets all pending input.
+    pub fn reset(&mut self) {
+        self.buffer.clear();
+        self.buffer.resize(RESAMPLER_HALF_TAPS * self.channels, 0.0);
+        self.position = RESAMPLER_HALF_TAPS as u64 * self.dst_rate;
+    }
+
+    /// Makes room for blocks of up to `frames`, so `process` doesn't
+    /// allocate on them.
+    pub fn reserve(&mut self, frames: usize) {
+        let taps = 2 * RESAMPLER_HALF_TAPS;
+        self.buffer.reserve((frames + taps + 1) * self.channels);
+        let output_frames = (frames as u64 * self.dst_rate).div_ceil(self.src_rate) as usize + 1;
+        self.output.reserve(output_frames * self.channels);
+    }
+
+    /// Output frames held back waiting for input the kernel looks ahead to.
+    pub fn latency_frames(&self) -> usize {
+        (RESAMPLER_HALF_TAPS as u64 * self.dst_rate).div_ceil(self.src_rate) as usize
+    }
+
+    pub fn process(&mut self, input: &[f32]) -> &[f32] {
+        self.buffer.extend_from_slice(input);
+        self.output.clear();
+
+        let channels = self.channels;
+        let taps = 2 * RESAMPLER_HALF_TAPS;
+        let frames = self.buffer.len() / channels;
+        let mut weights = [0.0f32; 2 * RESAMPLER_HALF_TAPS];
+
+        while (self.position / self.dst_rate) as usize + RESAMPLER_HALF_TAPS < frames {
+            let index = (self.position / self.dst_rate) as usize;
+            let phase = (self.position % self.dst_rate) as f64 / self.dst_rate as f64 * RESAMPLER_PHASES as f64;
+            let row = (phase as usize).min(RESAMPLER_PHASES - 1);
+            let t = (phase - row as f64) as f32;
+            let (below, above) = (&self.kernel[row * taps..][..taps], &self.kernel[(row + 1) * taps..][..taps]);
+            for (w, (&a, &b)) in weights.iter_mut().zip(below.iter().zip(above)) {
+                *w = a + (b - a) * t;
+            }
+
+            let first = (index + 1 - RESAMPLER_HALF_TAPS) * channels;
+            for channel in 0..channels {
+                let mut acc = 0.0f32;
+                for (j, &w) in weights.iter().enumerate() {
+                    acc += w * self.buffer[first + j * channels + channel];
+                }
+                self.output.push(acc);
+            }
+            self.position += self.src_rate;
+        }
+
+        // Drop the input no future output frame reaches back to.
+        let consumed = ((self.position / self.dst_rate) as usize + 1).saturating_sub(RESAMPLER_HALF_TAPS).min(frames);
+        self.buffer.drain(..consumed * channels);
+        self.position -= consumed as u64 * self.dst_rate;
+        &self.output
+    }
+
+    /// Pushes the pending input out with silence and starts over. Together
+    /// with all earlier calls this gives `ceil(input_frames * dst_rate /
+    /// src_rate)` frames.
+    pub fn flush(&mut self) -> &[f32] {
+        let silence = vec![0.0f32; RESAMPLER_HALF_TAPS * self.channels];
+        self.process(&silence);
+        self.reset();
+        &self.output
+    }
+}
+
+// Rounds to the grid of an integer sample format, clamping like the WAV
+// writer does.
+fn quantize(sample: f32, format: WavSampleFormat) -> f32 {
+    let scale = match format {
+        WavSampleFormat::Int16 => 32767.0,
+        WavSampleFormat::Int24 => 8_388_607.0,
+        WavSampleFormat::Float32 => return sample,
+    };
+    (sample.clamp(-1.0, 1.0) * scale).round() / scale
+}
+
+/// Converts interleaved blocks from `src` to `dst`: channels are mapped
+/// first, then resampled, then quantized if `dst` has fewer bits.
+///
+/// When the specs need no conversion `process` hands the input back
+/// untouched.
+#[derive(Debug, Clone)]
+pub struct FormatAdapter {
+    src: AudioSpec,
+    dst: AudioSpec,
+    mapper: Option<ChannelMapper>,
+    resampler: Option<StreamingResampler>,
+    quantize: Option<WavSampleFormat>,
+    output: Vec<f32>,
+}
+
+impl FormatAdapter {
+    pub fn new(src: AudioSpec, dst: AudioSpec) -> Self {
+        let mapper = (src.channels != dst.channels).then(|| ChannelMapper::new(src.channels, dst.channels));
+        let resampler = (src.sample_rate != dst.sample_rate)
+            .then(|| StreamingResampler::new(src.sample_rate, dst.sample_rate, dst.channels));
+        let loses_bits = src.format == WavSampleFormat::Float32 || src.format.bits_per_sample() > dst.format.bits_per_sample();
+        let quantize = (dst.format != WavSampleFormat::Float32 && loses_bits).then_some(dst.format);
+        Self {
+            src,
+            dst,
+            mapper,
+            resampler,
+            quantize,
+            output: Vec::new(),
+        }
+    }
+
+    /// Maps channels with `mapper` instead of the default for the two
+    /// counts; it has to go from `src` channels to `dst` channels.
+    pub fn with_mapper(mut self, mapper: ChannelMapper) -> Self {
+        assert_eq!(mapper.dst_channels(), self.dst.channels.max(1) as usize, "mapper must output the dst channels");
+        self.mapper = Some(mapper);
+        self
+    }
+
+    pub fn src(&self) -> AudioSpec {
+        self.src
+    }
+
+    pub fn dst(&self) -> AudioSpec {
+        self.dst
+    }
+
+    /// True when blocks pass through bit for bit.
+    pub fn is_identity(&self) -> bool {
+        self.mapper.is_none() && self.resampler.is_none() && self.quantize.is_none()
+    }
+
+    /// Makes room for blocks of up to `frames` `src` frames, so `process`
+    /// doesn't allocate on them.
+    pub fn reserve(&mut self, frames: usize) {
+        if let Some(mapper) = self.mapper.as_mut() {
+            mapper.reserve(frames);
+        }
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.reserve(frames);
+        }
+        let output_frames = match &self.resampler {
+            Some(resampler) => (frames as u64 * resampler.dst_rate).div_ceil(resampler.src_rate) as usize + 1,
+            None => frames,
+        };
+        self.output.reserve(output_frames * self.dst.channels.max(1) as usize);
+    }
+
+    /// Forgets the input the resampler holds, e.g. after a seek.
+    pub fn reset(&mut self) {
+        if let Some(resampler) = self.resampler.as_mut() {
+            resampler.reset();
+        }
+    }
+
+    /// Output frames the resampler holds back; 0 without resampling.
+    pub fn latency_frames(&self) -> usize {
+        self.resampler.as_ref().map_or(0, StreamingResampler::latency_frames)
+    }
+
+    /// Converts one block of whole `src` frames. With resampling the number
+    /// of frames returned varies from block to block.
+    pub fn process<'a>(&'a mut self, input: &'a [f32]) -> &'a [f32] {
+        let mut block = input;
+        if let Some(mapper) = self.mapper.as_mut() {
+            block = mapper.process(block);
+        }
+        if let Some(resampler) = self.resampler.as_mut() {
+            block = resampler.process(block);
+        }
+        match self.quantize {
+            Some(format) => {
+                self.output.clear();
+                self.output.extend(block.iter().map(|&s| quantize(s, format)));
+                &self.output
+            }
+            None => block,
+        }
+    }
+
+    /// Returns what the resampler still holds, after the last block.
+    pub fn flush(&mut self) -> &[f32] {
+        let Some(resampler) = self.resampler.as_mut() else {
+            return &[];
+        };
+        let block = resampler.flush();
+        match self.quantize {
+            Some(format) => {
+                self.output.clear();
+                self.output.extend(block.iter().map(|&s| quantize(s, format)));
+                &self.output
+            }
+            None => block,
+        }
+    }
+
+    /// Converts a whole signal at once, including the flushed tail.
+    pub fn process_all(&mut self, input: &[f32]) -> Vec<f32> {
+        let mut output = self.process(input).to_vec();
+        output.extend_from_slice(self.flush());
+        output
+    }
+}
+
+/// `FormatAdapter::process_all` for planar audio.
+pub fn convert_planar(channels: &[Vec<f32>], src: AudioSpec, dst: AudioSpec) -> Vec<Vec<f32>> {
+    let frames = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+    let interleaved: Vec<f32> = (0..frames).flat_map(|i| channels.iter().map(move |c| c[i])).collect();
+    let converted = FormatAdapter::new(src, dst).process_all(&interleaved);
+    let dst_channels = dst.channels as usize;
+    (0..dst_channels)
+        .map(|c| converted.iter().skip(c).step_by(dst_channels).copied().collect())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE_PAIRS: [(u32, u32); 6] = [(44_100, 48_000), (48_000, 44_100), (8_000, 48_000), (48_000, 8_000), (48_000, 48_000), (22_050, 96_000)];
+
+    fn sine(frames: usize, rate: u32, freq: f32, channels: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let s = (2.0 * std::f32::consts::PI * freq * i as f32 / rate as f32).sin() * 0.5;
+                (0..channels).map(move |c| s * (1.0 - 0.3 * c as f32))
+            })
+            .collect()
+    }
+
+    fn bits(samples: &[f32]) -> Vec<u32> {
+        samples.iter().map(|s| s.to_bits()).collect()
+    }
+
+    // Block sizes from a fixed seed, empty and single-frame blocks included.
+    fn block_sizes(seed: u32) -> impl Iterator<Item = usize> {
+        let mut state = seed;
+        std::iter::repeat_with(move || {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            match (state >> 24) % 8 {
+                0 => 0,
+                1 => 1,
+                n => ((state >> 8) % 600) as usize * n as usize / 7,
+            }
+        })
+    }
+
+    fn resample_in_blocks(resampler: &mut StreamingResampler, input: &[f32], channels: usize, seed: u32) -> Vec<f32> {
+        let mut output = Vec::new();
+        let mut rest = input;
+        for frames in block_sizes(seed) {
+            if rest.is_empty() {
+                break;
+            }
+            let (block, tail) = rest.split_at((frames * channels).min(rest.len()));
+            output.extend_from_slice(resampler.process(block));
+            rest = tail;
+        }
+        output.extend_from_slice(resampler.flush());
+        output
+    }
+
+    #[test]
+    fn resampler_output_does_not_depend_on_block_sizes() {
+        for (src, dst) in RATE_PAIRS {
+            let input = sine(5_000, src, 440.0, 2);
+            let mut whole = StreamingResampler::new(src, dst, 2);
+            let mut expected = whole.process(&input).to_vec();
+            expected.extend_from_slice(whole.flush());
+
+            for seed in 1..4 {
+                // The same instance again, to check flush leaves it fresh.
+                assert_eq!(bits(&resample_in_blocks(&mut whole, &input, 2, seed)), bits(&expected), "{} -> {}", src, dst);
+            }
+        }
+    }
+
+    #[test]
+    fn flushed_output_has_ceil_of_the_ratio_frames() {
+        for (src, dst) in RATE_PAIRS {
+            for frames in [0usize, 1, 2, 7, 160, 1_001, 4_410] {
+                let mut resampler = StreamingResampler::new(src, dst, 3);
+                let output = resample_in_blocks(&mut resampler, &vec![0.1; frames * 3], 3, frames as u32);
+                let expected = (frames as u64 * dst as u64).div_ceil(src as u64) as usize;
+                assert_eq!(output.len(), expected * 3, "{} frames, {} -> {}", frames, src, dst);
+            }
+        }
+    }
+
+    #[test]
+    fn resampled_sine_stays_on_the_ideal_curve() {
+        for (src, dst) in RATE_PAIRS {
+            // Low in the passband: the kernel spans a fixed number of input
+            // frames, so on steep downsampling the rolloff starts early.
+            let freq = src.min(dst) as f32 / 40.0;
+            let mut resampler = StreamingResampler::new(src, dst, 1);
+            let mut output = resampler.process(&sine(8_000, src, freq, 1)).to_vec();
+            output.extend_from_slice(resampler.flush());
+            let ideal = sine(output.len(), dst, freq, 1);
+            // Away from the edges, where the kernel reaches into silence.
+            let margin = 4 * RESAMPLER_HALF_TAPS * dst as usize / src as usize + RESAMPLER_HALF_TAPS;
+            for i in margin..output.len() - margin {
+                assert!((output[i] - ideal[i]).abs() < 2e-3, "{} -> {}: {} vs {} at {}", src, dst, output[i], ideal[i], i);
+            }
+        }
+    }
+
+    #[test]
+    fn default_channel_maps() {
+        assert_eq!(ChannelMapper::new(1, 2).process(&[0.5, -0.25]), [0.5, 0.5, -0.25, -0.25]);
+        assert_eq!(ChannelMapper::new(2, 1).process(&[0.5, -0.25]), [0.125]);
+        assert_eq!(ChannelMapper::new(3, 2).process(&[0.1, 0.2, 0.3]), [0.1, 0.2]);
+        assert_eq!(ChannelMapper::new(2, 3).process(&[0.1, 0.2]), [0.1, 0.2, 0.0]);
+
+        let h = std::f32::consts::FRAC_1_SQRT_2;
+        let downmix = ChannelMapper::new(6, 2).process(&[1.0, 2.0, 3.0, 100.0, 4.0, 5.0]).to_vec();
+        assert_eq!(downmix, [1.0 + 3.0 * h + 4.0 * h, 2.0 + 3.0 * h + 5.0 * h]);
+    }
+
+    #[test]
+    fn matching_specs_pass_through_bit_for_bit() {
+        let spec = AudioSpec::new(44_100, 2);
+        let mut adapter = FormatAdapter::new(spec, spec);
+        assert!(adapter.is_identity());
+        assert_eq!(adapter.latency_frames(), 0);
+        let input = [0.1, f32::MIN_POSITIVE, -1.5, 0.333_333_34];
+        assert_eq!(bits(adapter.process(&input)), bits(&input));
+        assert!(adapter.flush().is_empty());
+
+        // Narrowing the format is a conversion even at the same rate.
+        assert!(!FormatAdapter::new(spec, spec.with_format(WavSampleFormat::Int16)).is_identity());
+        assert!(FormatAdapter::new(spec.with_format(WavSampleFormat::Int16), spec).is_identity());
+    }
+
+    #[test]
+    fn adapter_in_blocks_matches_process_all() {
+        let src = AudioSpec::new(44_100, 1);
+        let dst = AudioSpec::new(48_000, 2).with_format(WavSampleFormat::Int16);
+        let input = sine(6_000, 44_100, 300.0, 1);
+        let expected = FormatAdapter::new(src, dst).process_all(&input);
+        assert_eq!(expected.len(), (6_000usize * 48_000).div_ceil(44_100) * 2);
+
+        let mut adapter = FormatAdapter::new(src, dst);
+        let mut output = Vec::new();
+        for block in input.chunks(333) {
+            output.extend_from_slice(adapter.process(block));
+        }
+        output.extend_from_slice(adapter.flush());
+        assert_eq!(bits(&output), bits(&expected));
+
+        // Quantized onto the 16-bit grid, and both channels the same.
+        for frame in output.chunks_exact(2) {
+            assert_eq!(frame[0], frame[1]);
+            assert_eq!((frame[0] * 32767.0).round(), frame[0] * 32767.0);
+        }
+    }
+}
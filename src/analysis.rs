@@ -0,0 +1,795 @@
+// Measurements that compare or inspect whole signals.
+use rustfft::{num_complex::Complex, FftPlanner};
+
+use crate::json::{check_schema_version, Object, Value, SCHEMA_VERSION};
+use crate::loudness::integrated_loudness;
+use crate::match_eq::average_spectrum;
+use crate::read_wav::read_wav_data;
+use crate::write_wav::{WavSampleFormat, WavStreamWriter};
+
+pub fn linear_to_db(value: f32) -> f32 {
+    20.0 * value.log10()
+}
+
+pub fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    (samples.iter().map(|&x| x * x).sum::<f32>() / samples.len() as f32).sqrt()
+}
+
+/// Full cross-correlation `r[k] = sum_n a[n] * b[n + k]` computed with FFTs,
+/// returned for lags `-(a.len() - 1) ..= b.len() - 1` in that order.
+pub fn cross_correlate(a: &[f32], b: &[f32]) -> Vec<f32> {
+    if a.is_empty() || b.is_empty() {
+        return Vec::new();
+    }
+    let len = a.len() + b.len() - 1;
+    let fft_len = len.next_power_of_two();
+
+    let mut planner = FftPlanner::new();
+    let fft = planner.plan_fft_forward(fft_len);
+    let ifft = planner.plan_fft_inverse(fft_len);
+
+    let to_buffer = |x: &[f32]| {
+        let mut buffer: Vec<Complex<f32>> = x.iter().map(|&re| Complex { re, im: 0.0 }).collect();
+        buffer.resize(fft_len, Complex { re: 0.0, im: 0.0 });
+        buffer
+    };
+    let mut spec_a = to_buffer(a);
+    let mut spec_b = to_buffer(b);
+    fft.process(&mut spec_a);
+    fft.process(&mut spec_b);
+
+    let mut product: Vec<Complex<f32>> = spec_a.iter().zip(&spec_b).map(|(x, y)| x.conj() * y).collect();
+    ifft.process(&mut product);
+
+    // Negative lags wrap around to the end of the circular result.
+    let scale = 1.0 / fft_len as f32;
+    let negative = (1..a.len()).rev().map(|k| product[fft_len - k].re * scale);
+    let positive = (0..b.len()).map(|k| product[k].re * scale);
+    negative.chain(positive).collect()
+}
+
+/// Lag (in samples) at which `signal` best matches `reference`, searched
+/// within `±max_lag`. Positive means `signal` arrives later.
+pub fn estimate_delay(reference: &[f32], signal: &[f32], max_lag: usize) -> isize {
+    let correlation = cross_correlate(reference, signal);
+    let zero_lag = reference.len() as isize - 1;
+
+    correlation
+        .iter()
+        .enumerate()
+        .map(|(i, &value)| (i as isize - zero_lag, value))
+        .filter(|(lag, _)| lag.unsigned_abs() <= max_lag)
+        .fold((0, f32::NEG_INFINITY), |best, (lag, value)| if value > best.1 { (lag, value) } else { best })
+        .0
+}
+
+/// Outcome of `render_difference`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DifferenceReport {
+    /// Frames by which file B was shifted to line up with file A. Positive
+    /// means B started later.
+    pub offset_frames: isize,
+    /// RMS of the residual (before `gain_db`) relative to file A, in dB.
+    /// Negative infinity for a perfect null, including two silent files.
+    pub residual_db: f32,
+    pub frames: usize,
+}
+
+/// Longest delay searched when aligning the two files.
+const MAX_ALIGNMENT_SECONDS: f32 = 1.0;
+
+/// Time-aligns file B to file A, subtracts it, and writes the residual
+/// boosted by `gain_db` to `out_path` as 32-bit float, so the difference
+/// a processing chain made can be listened to.
+///
+/// Where one file is shorter than the other, the missing part counts as
+/// silence, so a length mismatch shows up in the residual instead of being
+/// cut off. A silent file A that doesn't null against B is an error, as
+/// there is no level to measure the residual against.
+pub fn render_difference(
+    path_a: &str,
+    path_b: &str,
+    out_path: &str,
+    gain_db: f32,
+) -> Result<DifferenceReport, Box<dyn std::error::Error>> {
+    let data_a = read_wav_data(path_a)?;
+    let data_b = read_wav_data(path_b)?;
+    if data_a.channel_count() != data_b.channel_count() {
+        return Err("Files have different channel counts".into());
+    }
+    if data_a.sample_rate != data_b.sample_rate {
+        return Err(format!("Sample rates differ: {} Hz vs {} Hz", data_a.sample_rate, data_b.sample_rate).into());
+    }
+    let (channels, rate) = (data_a.channel_count(), data_a.sample_rate);
+
+    let max_lag = (MAX_ALIGNMENT_SECONDS * rate as f32) as usize;
+    let offset = estimate_delay(&data_a.to_mono(), &data_b.to_mono(), max_lag);
+
+    // Frame n of A lines up with frame n + offset of B.
+    let (frames_a, frames_b) = (data_a.frames(), data_b.frames());
+    let frames = frames_a.max((frames_b as isize - offset).max(0) as usize);
+
+    let mut residual = Vec::with_capacity(frames * channels as usize);
+    for n in 0..frames {
+        let frame_b = n as isize + offset;
+        for (a, b) in data_a.channels.iter().zip(&data_b.channels) {
+            let a = a.get(n).copied().unwrap_or(0.0);
+            let b = usize::try_from(frame_b).ok().and_then(|i| b.get(i)).copied().unwrap_or(0.0);
+            residual.push(a - b);
+        }
+    }
+
+    let residual_rms = rms(&residual);
+    let reference_rms = rms(&data_a.channels.concat());
+    let residual_db = if residual_rms == 0.0 {
+        f32::NEG_INFINITY
+    } else if reference_rms == 0.0 {
+        return Err(format!("{} is silent, so the difference has no reference level", path_a).into());
+    } else {
+        linear_to_db(residual_rms / reference_rms)
+    };
+
+    let gain = 10.0_f32.powf(gain_db / 20.0);
+    let boosted: Vec<f32> = residual.iter().map(|&x| x * gain).collect();
+    let mut writer = WavStreamWriter::create(out_path, rate, channels, WavSampleFormat::Float32)?;
+    writer.write_samples(&boosted)?;
+    writer.finish()?;
+
+    Ok(DifferenceReport {
+        offset_frames: offset,
+        residual_db,
+        frames,
+    })
+}
+
+/// Level statistics of a whole file, all channels together.
+///
+/// The JSON field names produced by `to_json` are part of the `--json`
+/// contract (see `json::SCHEMA_VERSION`): levels of silence are negative
+/// infinity and written as `null`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AudioStats {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub frames: u64,
+    pub duration_seconds: f64,
+    pub peak_dbfs: f32,
+    pub rms_dbfs: f32,
+    /// Peak to RMS ratio; negative infinity for silence.
+    pub crest_factor_db: f32,
+    pub dc_offset: f32,
+    /// Samples at or beyond full scale.
+    pub clipped_samples: u64,
+}
+
+impl AudioStats {
+    /// Statistics of planar audio: owned channels, or slices of them such
+    /// as one channel from `WavData::split_channels`.
+    pub fn from_channels<C: AsRef<[f32]>>(channels: &[C], sample_rate: u32) -> AudioStats {
+        let frames = channels.first().map_or(0, |c| c.as_ref().len());
+        let count = (frames * channels.len()).max(1) as f64;
+
+        let mut peak = 0.0f32;
+        let mut sum = 0.0f64;
+        let mut sum_squares = 0.0f64;
+        let mut clipped_samples = 0;
+        for &sample in channels.iter().flat_map(|c| c.as_ref()) {
+            peak = peak.max(sample.abs());
+            sum += sample as f64;
+            sum_squares += sample as f64 * sample as f64;
+            if sample.abs() >= 1.0 {
+                clipped_samples += 1;
+            }
+        }
+        let rms = (sum_squares / count).sqrt() as f32;
+        let crest_factor_db = if rms > 0.0 {
+            linear_to_db(peak / rms)
+        } else {
+            f32::NEG_INFINITY
+        };
+
+        AudioStats {
+            sample_rate,
+            channels: channels.len() as u16,
+            frames: frames as u64,
+            duration_seconds: frames as f64 / sample_rate as f64,
+            peak_dbfs: linear_to_db(peak),
+            rms_dbfs: linear_to_db(rms),
+            crest_factor_db,
+            dc_offset: (sum / count) as f32,
+            clipped_samples,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut object = Object::new();
+        object.insert("sample_rate".into(), self.sample_rate.into());
+        object.insert("channels".into(), self.channels.into());
+        object.insert("frames".into(), self.frames.into());
+        object.insert("duration_seconds".into(), self.duration_seconds.into());
+        object.insert("peak_dbfs".into(), Value::from_db(self.peak_dbfs));
+        object.insert("rms_dbfs".into(), Value::from_db(self.rms_dbfs));
+        object.insert("crest_factor_db".into(), Value::from_db(self.crest_factor_db));
+        object.insert("dc_offset".into(), self.dc_offset.into());
+        object.insert("clipped_samples".into(), self.clipped_samples.into());
+        Value::Object(object)
+    }
+
+    /// Reads the object written by `to_json`, ignoring unknown fields.
+    pub fn from_json(value: &Value) -> Result<AudioStats, String> {
+        let integer = |key: &str| value.require(key)?.as_u64().ok_or(format!("\"{}\" is not an integer", key));
+        let number = |key: &str| value.require(key)?.as_f64().ok_or(format!("\"{}\" is not a number", key));
+        let db = |key: &str| value.require(key)?.as_db().ok_or(format!("\"{}\" is not a level", key));
+
+        Ok(AudioStats {
+            sample_rate: integer("sample_rate")? as u32,
+            channels: integer("channels")? as u16,
+            frames: integer("frames")?,
+            duration_seconds: number("duration_seconds")?,
+            peak_dbfs: db("peak_dbfs")?,
+            rms_dbfs: db("rms_dbfs")?,
+            crest_factor_db: db("crest_factor_db")?,
+            dc_offset: number("dc_offset")? as f32,
+            clipped_samples: integer("clipped_samples")?,
+        })
+    }
+}
+
+/// Channels whose peak stays below this are reported as silent.
+pub const SILENT_CHANNEL_DBFS: f32 = -90.0;
+/// Level difference between two channels that is reported as an imbalance.
+pub const IMBALANCE_WARNING_DB: f32 = 6.0;
+/// Longest delay searched between two channels.
+const MAX_CHANNEL_DELAY_SECONDS: f32 = 0.01;
+/// The delay is estimated on the start of the file only, which keeps the
+/// cross-correlation small on long recordings.
+const CHANNEL_DELAY_EXCERPT_SECONDS: f32 = 30.0;
+
+/// How one channel relates to a reference channel (the first one).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelPair {
+    pub reference: usize,
+    pub channel: usize,
+    /// Correlation at zero lag, from -1 (inverted) to 1 (identical shape);
+    /// `None` when either channel is silent.
+    pub correlation: Option<f32>,
+    /// RMS of `channel` relative to `reference` in dB, positive when
+    /// `channel` is louder; `None` when either is silent.
+    pub balance_db: Option<f32>,
+    /// Frames by which `channel` lags `reference`; `None` when either is
+    /// silent.
+    pub delay_frames: Option<isize>,
+}
+
+impl ChannelPair {
+    pub fn measure(reference: (usize, &[f32]), channel: (usize, &[f32]), sample_rate: u32) -> ChannelPair {
+        let (a, b) = (reference.1, channel.1);
+        let energy = |x: &[f32]| x.iter().map(|&s| s as f64 * s as f64).sum::<f64>();
+        let (energy_a, energy_b) = (energy(a), energy(b));
+
+        let mut pair = ChannelPair {
+            reference: reference.0,
+            channel: channel.0,
+            correlation: None,
+            balance_db: None,
+            delay_frames: None,
+        };
+        if energy_a > 0.0 && energy_b > 0.0 {
+            let product: f64 = a.iter().zip(b).map(|(&x, &y)| x as f64 * y as f64).sum();
+            pair.correlation = Some((product / (energy_a * energy_b).sqrt()) as f32);
+            pair.balance_db = Some((10.0 * (energy_b / energy_a).log10()) as f32);
+
+            let excerpt = ((CHANNEL_DELAY_EXCERPT_SECONDS * sample_rate as f32) as usize).min(a.len()).min(b.len());
+            let max_lag = (MAX_CHANNEL_DELAY_SECONDS * sample_rate as f32) as usize;
+            pair.delay_frames = Some(estimate_delay(&a[..excerpt], &b[..excerpt], max_lag));
+        }
+        pair
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut object = Object::new();
+        object.insert("reference".into(), self.reference.into());
+        object.insert("channel".into(), self.channel.into());
+        object.insert("correlation".into(), Value::from_option(self.correlation));
+        object.insert("balance_db".into(), Value::from_option(self.balance_db));
+        object.insert("delay_frames".into(), Value::from_option(self.delay_frames));
+        Value::Object(object)
+    }
+
+    pub fn from_json(value: &Value) -> Result<ChannelPair, String> {
+        let index = |key: &str| {
+            value.require(key)?.as_u64().map(|n| n as usize).ok_or(format!("\"{}\" is not an integer", key))
+        };
+        let optional = |key: &str| -> Result<Option<f64>, String> {
+            let field = value.require(key)?;
+            if field.is_null() {
+                Ok(None)
+            } else {
+                field.as_f64().map(Some).ok_or(format!("\"{}\" is not a number", key))
+            }
+        };
+        Ok(ChannelPair {
+            reference: index("reference")?,
+            channel: index("channel")?,
+            correlation: optional("correlation")?.map(|n| n as f32),
+            balance_db: optional("balance_db")?.map(|n| n as f32),
+            delay_frames: optional("delay_frames")?.map(|n| n as isize),
+        })
+    }
+}
+
+/// Range of the spectrum `spectral_tilt` fits a line to.
+const TILT_MIN_FREQ: f32 = 50.0;
+const TILT_MAX_FREQ: f32 = 16000.0;
+const TILT_FFT_SIZE: usize = 4096;
+
+/// Slope of the long-term spectrum in dB per octave: the least-squares line
+/// through the average power density of third-octave bands between 50 Hz
+/// and 16 kHz. White noise has a tilt of about 0, pink noise about -3.
+///
+/// `None` when there is no energy in that range (silence, or too short).
+pub fn spectral_tilt(samples: &[f32], sample_rate: u32) -> Option<f32> {
+    let spectrum = average_spectrum(samples, TILT_FFT_SIZE);
+    let bin_width = sample_rate as f32 / TILT_FFT_SIZE as f32;
+    let max_freq = TILT_MAX_FREQ.min(sample_rate as f32 * 0.45);
+
+    // (log2 of the band center, band level in dB)
+    let mut points = Vec::new();
+    let mut low = TILT_MIN_FREQ;
+    while low < max_freq {
+        let high = (low * 2.0_f32.powf(1.0 / 3.0)).min(max_freq);
+        let bins: Vec<f32> = ((low / bin_width).ceil() as usize..=(high / bin_width) as usize)
+            .filter_map(|k| spectrum.get(k))
+            .map(|m| m * m)
+            .collect();
+        if !bins.is_empty() {
+            let power = bins.iter().sum::<f32>() / bins.len() as f32;
+            if power > 0.0 {
+                points.push((((low * high).sqrt()).log2(), 10.0 * power.log10()));
+            }
+        }
+        low = high;
+    }
+    if points.len() < 2 {
+        return None;
+    }
+
+    let n = points.len() as f32;
+    let mean_x = points.iter().map(|p| p.0).sum::<f32>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f32>() / n;
+    let covariance: f32 = points.iter().map(|(x, y)| (x - mean_x) * (y - mean_y)).sum();
+    let variance: f32 = points.iter().map(|(x, _)| (x - mean_x) * (x - mean_x)).sum();
+    Some(covariance / variance)
+}
+
+// Reads an optional array member, each element with `parse`.
+fn array<T>(value: &Value, key: &str, parse: fn(&Value) -> Result<T, String>) -> Result<Vec<T>, String> {
+    match value.get(key) {
+        None => Ok(Vec::new()),
+        Some(field) => field.as_array().ok_or(format!("\"{}\" is not an array", key))?.iter().map(parse).collect(),
+    }
+}
+
+/// Everything `analyze` reports about a file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalyzeReport {
+    pub file: String,
+    pub stats: AudioStats,
+    /// BS.1770 integrated loudness; negative infinity below the gate.
+    pub integrated_loudness_lufs: f32,
+    pub spectral_tilt_db_per_octave: Option<f32>,
+    /// `stats` of each channel on its own.
+    pub channel_stats: Vec<AudioStats>,
+    /// Every channel after the first against the first.
+    pub channel_pairs: Vec<ChannelPair>,
+}
+
+impl AnalyzeReport {
+    pub fn analyze(path: &str) -> Result<AnalyzeReport, Box<dyn std::error::Error>> {
+        let data = read_wav_data(path)?;
+        let channels = data.split_channels();
+        let channel_pairs = match channels.split_first() {
+            Some((&first, rest)) => rest
+                .iter()
+                .enumerate()
+                .map(|(i, &channel)| ChannelPair::measure((0, first), (i + 1, channel), data.sample_rate))
+                .collect(),
+            None => Vec::new(),
+        };
+        Ok(AnalyzeReport {
+            file: path.to_string(),
+            stats: AudioStats::from_channels(&channels, data.sample_rate),
+            integrated_loudness_lufs: integrated_loudness(&data.channels, data.sample_rate) as f32,
+            spectral_tilt_db_per_octave: spectral_tilt(&data.to_mono(), data.sample_rate),
+            channel_stats: channels.iter().map(|&c| AudioStats::from_channels(&[c], data.sample_rate)).collect(),
+            channel_pairs,
+        })
+    }
+
+    /// Problems between the channels worth pointing out, numbering
+    /// channels from 1: silent channels, level imbalances and delays.
+    pub fn warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        if self.channel_stats.len() > 1 {
+            for (i, stats) in self.channel_stats.iter().enumerate() {
+                if stats.peak_dbfs < SILENT_CHANNEL_DBFS {
+                    warnings.push(format!("channel {} is silent", i + 1));
+                }
+            }
+        }
+        for pair in &self.channel_pairs {
+            let (reference, channel) = (pair.reference + 1, pair.channel + 1);
+            if let Some(balance) = pair.balance_db.filter(|b| b.abs() >= IMBALANCE_WARNING_DB) {
+                let louder = if balance > 0.0 { "louder" } else { "quieter" };
+                warnings.push(format!("channel {} is {:.1} dB {} than channel {}", channel, balance.abs(), louder, reference));
+            }
+            if let Some(delay) = pair.delay_frames.filter(|&d| d != 0) {
+                let behind = if delay > 0 { "behind" } else { "ahead of" };
+                warnings.push(format!("channel {} is {} frames {} channel {}", channel, delay.abs(), behind, reference));
+            }
+        }
+        warnings
+    }
+
+    /// The `analyze --json` document:
+    ///
+    /// ```text
+    /// { "schema_version": 1, "file": "...", "stats": { AudioStats },
+    ///   "integrated_loudness_lufs": -23.0 | null,
+    ///   "spectral_tilt_db_per_octave": -3.1 | null,
+    ///   "channel_stats": [{ AudioStats }, ...],
+    ///   "channel_pairs": [{ "reference": 0, "channel": 1,
+    ///     "correlation": 0.9 | null, "balance_db": -0.5 | null,
+    ///     "delay_frames": 0 | null }, ...],
+    ///   "warnings": ["channel 2 is silent", ...] }
+    /// ```
+    pub fn to_json(&self) -> Value {
+        let mut object = Object::new();
+        object.insert("schema_version".into(), SCHEMA_VERSION.into());
+        object.insert("file".into(), self.file.as_str().into());
+        object.insert("stats".into(), self.stats.to_json());
+        object.insert("integrated_loudness_lufs".into(), Value::from_db(self.integrated_loudness_lufs));
+        object.insert(
+            "spectral_tilt_db_per_octave".into(),
+            Value::from_option(self.spectral_tilt_db_per_octave),
+        );
+        object.insert(
+            "channel_stats".into(),
+            self.channel_stats.iter().map(AudioStats::to_json).collect::<Vec<_>>().into(),
+        );
+        object.insert(
+            "channel_pairs".into(),
+            self.channel_pairs.iter().map(ChannelPair::to_json).collect::<Vec<_>>().into(),
+        );
+        object.insert(
+            "warnings".into(),
+            self.warnings().into_iter().map(Value::from).collect::<Vec<_>>().into(),
+        );
+        Value::Object(object)
+    }
+
+    pub fn from_json(value: &Value) -> Result<AnalyzeReport, String> {
+        check_schema_version(value)?;
+        let tilt = value.require("spectral_tilt_db_per_octave")?;
+        Ok(AnalyzeReport {
+            file: value.require("file")?.as_str().ok_or("\"file\" is not a string")?.to_string(),
+            stats: AudioStats::from_json(value.require("stats")?)?,
+            integrated_loudness_lufs: value
+                .require("integrated_loudness_lufs")?
+                .as_db()
+                .ok_or("\"integrated_loudness_lufs\" is not a level")?,
+            spectral_tilt_db_per_octave: if tilt.is_null() {
+                None
+            } else {
+                Some(tilt.as_f32().ok_or("\"spectral_tilt_db_per_octave\" is not a number")?)
+            },
+            // Documents from before per-channel analysis have neither.
+            channel_stats: array(value, "channel_stats", AudioStats::from_json)?,
+            channel_pairs: array(value, "channel_pairs", ChannelPair::from_json)?,
+        })
+    }
+}
+
+/// Total harmonic distortion of a steady tone at `fundamental_hz`, as a
+/// ratio (0.01 = 1%): the RMS of harmonics 2 to `harmonics` relative to the
+/// fundamental. Harmonics above Nyquist are left out.
+pub fn thd(samples: &[f32], sample_rate: u32, fundamental_hz: f32, harmonics: usize) -> f32 {
+    if samples.len() < 2 {
+        return 0.0;
+    }
+    // Largest power of two that fits, with a 4-term Blackman-Harris window
+    // to keep the fundamental's leakage away from the harmonics.
+    let n = 1 << (usize::BITS - 1 - samples.len().leading_zeros());
+    let windowed: Vec<Complex<f32>> = samples[..n]
+        .iter()
+        .enumerate()
+        .map(|(i, &x)| {
+            let phase = 2.0 * std::f32::consts::PI * i as f32 / n as f32;
+            let w = 0.35875 - 0.48829 * phase.cos() + 0.14128 * (2.0 * phase).cos() - 0.01168 * (3.0 * phase).cos();
+            Complex { re: x * w, im: 0.0 }
+        })
+        .collect();
+    let mut spectrum = windowed;
+    FftPlanner::new().plan_fft_forward(n).process(&mut spectrum);
+
+    let bin_width = sample_rate as f32 / n as f32;
+    // The window's main lobe is 4 bins wide on either side.
+    let power_near = |freq: f32| -> f32 {
+        let center = (freq / bin_width).round() as isize;
+        (center - 4..=center + 4)
+            .filter(|&k| k > 0 && (k as usize) < n / 2)
+            .map(|k| spectrum[k as usize].norm_sqr())
+            .sum()
+    };
+
+    let fundamental = power_near(fundamental_hz);
+    if fundamental <= 0.0 {
+        return 0.0;
+    }
+    let distortion: f32 = (2..=harmonics)
+        .map(|k| fundamental_hz * k as f32)
+        .take_while(|&freq| freq < sample_rate as f32 / 2.0)
+        .map(power_near)
+        .sum();
+    (distortion / fundamental).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json;
+
+    fn report() -> AnalyzeReport {
+        let tone: Vec<f32> = (0..4800).map(|i| 0.5 * (i as f32 * 0.05).sin()).collect();
+        let silence = vec![0.0f32; 4800];
+        let channels = [tone, silence];
+        AnalyzeReport {
+            file: "take \"1\".wav".to_string(),
+            stats: AudioStats::from_channels(&channels, 48000),
+            integrated_loudness_lufs: f32::NEG_INFINITY,
+            spectral_tilt_db_per_octave: None,
+            channel_stats: channels.iter().map(|c| AudioStats::from_channels(&[c], 48000)).collect(),
+            channel_pairs: vec![ChannelPair::measure((0, &channels[0]), (1, &channels[1]), 48000)],
+        }
+    }
+
+    #[test]
+    fn report_round_trips_through_json() {
+        let report = report();
+        let text = json::to_string_pretty(&report.to_json());
+        let read = AnalyzeReport::from_json(&json::parse(&text).unwrap()).unwrap();
+        assert_eq!(read, report);
+    }
+
+    #[test]
+    fn silence_is_written_as_null() {
+        let report = report();
+        let document = report.to_json();
+        let silent = &document.require("channel_stats").unwrap().as_array().unwrap()[1];
+        assert!(silent.require("peak_dbfs").unwrap().is_null());
+        assert!(silent.require("crest_factor_db").unwrap().is_null());
+        assert!(document.require("integrated_loudness_lufs").unwrap().is_null());
+        let stats = AudioStats::from_json(silent).unwrap();
+        assert_eq!(stats.peak_dbfs, f32::NEG_INFINITY);
+        assert_eq!(stats.rms_dbfs, f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn unknown_fields_are_ignored() {
+        let mut document = report().to_json();
+        if let Value::Object(object) = &mut document {
+            object.insert("added_later".into(), Value::from("anything"));
+            if let Some(Value::Object(stats)) = object.get_mut("stats") {
+                stats.insert("true_peak_dbfs".into(), Value::from(-1.0f32));
+            }
+        }
+        assert_eq!(AnalyzeReport::from_json(&document).unwrap(), report());
+    }
+
+    #[test]
+    fn documents_without_channel_analysis_still_read() {
+        let mut document = report().to_json();
+        if let Value::Object(object) = &mut document {
+            object.remove("channel_stats");
+            object.remove("channel_pairs");
+        }
+        let read = AnalyzeReport::from_json(&document).unwrap();
+        assert!(read.channel_stats.is_empty() && read.channel_pairs.is_empty());
+    }
+
+    #[test]
+    fn the_documented_field_names_are_written() {
+        let document = report().to_json();
+        for key in [
+            "schema_version",
+            "file",
+            "stats",
+            "integrated_loudness_lufs",
+            "spectral_tilt_db_per_octave",
+            "channel_stats",
+            "channel_pairs",
+            "warnings",
+        ] {
+            assert!(document.get(key).is_some(), "{}", key);
+        }
+        let stats = document.require("stats").unwrap();
+        for key in [
+            "sample_rate",
+            "channels",
+            "frames",
+            "duration_seconds",
+            "peak_dbfs",
+            "rms_dbfs",
+            "crest_factor_db",
+            "dc_offset",
+            "clipped_samples",
+        ] {
+            assert!(stats.get(key).is_some(), "{}", key);
+        }
+    }
+
+    #[test]
+    fn newer_schema_is_rejected() {
+        let mut document = report().to_json();
+        if let Value::Object(object) = &mut document {
+            object.insert("schema_version".into(), (SCHEMA_VERSION + 1).into());
+        }
+        assert!(AnalyzeReport::from_json(&document).is_err());
+    }
+
+    // A path in the temp directory, removed when dropped.
+    struct TempPath(std::path::PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("cpal_playbook_{}_{}", std::process::id(), name)))
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    // A stereo file with different material on each channel, `delay`
+    // frames late and scaled by `gain`.
+    fn stereo_file(name: &str, frames: usize, delay: usize, gain: f32) -> TempPath {
+        let mut state = 12_345u32;
+        let mut noise = || {
+            state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            (state >> 8) as f32 / (1u32 << 24) as f32 - 0.5
+        };
+        let source: Vec<[f32; 2]> = (0..frames).map(|i| [0.3 * (i as f32 * 0.05).sin() + 0.2 * noise(), 0.4 * noise()]).collect();
+        let interleaved: Vec<f32> =
+            (0..frames).flat_map(|i| if i < delay { [0.0; 2] } else { source[i - delay] }).map(|s| s * gain).collect();
+        let file = TempPath::new(name);
+        crate::write_wav::write_wave_file(file.path(), &interleaved, 48_000, 2, WavSampleFormat::Float32).unwrap();
+        file
+    }
+
+    fn silent_file(name: &str, channels: u16) -> TempPath {
+        let file = TempPath::new(name);
+        let samples = vec![0.0; 4_000 * channels as usize];
+        crate::write_wav::write_wave_file(file.path(), &samples, 48_000, channels, WavSampleFormat::Float32).unwrap();
+        file
+    }
+
+    #[test]
+    fn identical_files_null_to_digital_silence() {
+        let a = stereo_file("diff_a.wav", 20_000, 0, 1.0);
+        let out = TempPath::new("diff_null.wav");
+        let report = render_difference(a.path(), a.path(), out.path(), 20.0).unwrap();
+        assert_eq!((report.offset_frames, report.frames), (0, 20_000));
+        assert_eq!(report.residual_db, f32::NEG_INFINITY);
+        let residual = read_wav_data(out.path()).unwrap();
+        assert_eq!(residual.channel_count(), 2);
+        assert!(residual.channels.iter().flatten().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn attenuated_copy_leaves_the_expected_residual() {
+        let a = stereo_file("diff_loud.wav", 20_000, 0, 1.0);
+        let b = stereo_file("diff_quiet.wav", 20_000, 0, 10f32.powf(-1.0 / 20.0));
+        let out = TempPath::new("diff_1db.wav");
+        let report = render_difference(a.path(), b.path(), out.path(), 0.0).unwrap();
+        // 20 * log10(1 - 10^(-1/20))
+        assert!((report.residual_db - -19.27).abs() < 0.02, "{}", report.residual_db);
+    }
+
+    #[test]
+    fn delayed_copy_is_aligned_per_channel() {
+        let a = stereo_file("diff_early.wav", 20_000, 0, 1.0);
+        let b = stereo_file("diff_late.wav", 20_100, 100, 1.0);
+        let out = TempPath::new("diff_aligned.wav");
+        let report = render_difference(a.path(), b.path(), out.path(), 0.0).unwrap();
+        assert_eq!(report.offset_frames, 100);
+        assert_eq!(report.frames, 20_000);
+        // Each channel nulls against its own partner, not its neighbour.
+        assert_eq!(report.residual_db, f32::NEG_INFINITY);
+    }
+
+    #[test]
+    fn silent_reference_is_handled() {
+        let silent = silent_file("diff_silent.wav", 2);
+        let out = TempPath::new("diff_silent_out.wav");
+        let report = render_difference(silent.path(), silent.path(), out.path(), 0.0).unwrap();
+        assert_eq!(report.residual_db, f32::NEG_INFINITY);
+
+        let loud = stereo_file("diff_against_silence.wav", 4_000, 0, 1.0);
+        let error = render_difference(silent.path(), loud.path(), out.path(), 0.0).unwrap_err();
+        assert!(error.to_string().contains("silent"), "{}", error);
+    }
+
+    #[test]
+    fn mismatched_layouts_are_rejected() {
+        let stereo = stereo_file("diff_stereo.wav", 4_000, 0, 1.0);
+        let mono = silent_file("diff_mono.wav", 1);
+        let out = TempPath::new("diff_mismatch.wav");
+        assert!(render_difference(stereo.path(), mono.path(), out.path(), 0.0).is_err());
+    }
+
+    // Noise on the left and `right` built from it, at 48 kHz.
+    fn analyzed(name: &str, right: impl Fn(&[f32], usize) -> f32) -> AnalyzeReport {
+        let mut state = 54_321u32;
+        let left: Vec<f32> = (0..24_000)
+            .map(|_| {
+                state = state.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                0.5 * ((state >> 8) as f32 / (1u32 << 24) as f32 - 0.5)
+            })
+            .collect();
+        let interleaved: Vec<f32> = (0..left.len()).flat_map(|i| [left[i], right(&left, i)]).collect();
+        let file = TempPath::new(name);
+        crate::write_wav::write_wave_file(file.path(), &interleaved, 48_000, 2, WavSampleFormat::Float32).unwrap();
+        AnalyzeReport::analyze(file.path()).unwrap()
+    }
+
+    #[test]
+    fn a_silent_channel_is_reported() {
+        let report = analyzed("analyze_silent_right.wav", |_, _| 0.0);
+        assert_eq!(report.channel_stats.len(), 2);
+        assert!((report.channel_stats[0].peak_dbfs - -12.04).abs() < 0.1, "{}", report.channel_stats[0].peak_dbfs);
+        assert_eq!(report.channel_stats[1].peak_dbfs, f32::NEG_INFINITY);
+        assert_eq!(report.channel_stats[1].channels, 1);
+        // The whole file averages the two channels, 3 dB below the left.
+        assert!((report.stats.rms_dbfs - (report.channel_stats[0].rms_dbfs - 3.01)).abs() < 0.01);
+        assert_eq!(
+            report.channel_pairs,
+            [ChannelPair { reference: 0, channel: 1, correlation: None, balance_db: None, delay_frames: None }]
+        );
+        assert_eq!(report.warnings(), ["channel 2 is silent"]);
+    }
+
+    #[test]
+    fn an_inter_channel_delay_is_reported() {
+        let report = analyzed("analyze_late_right.wav", |left, i| if i < 3 { 0.0 } else { left[i - 3] });
+        let pair = &report.channel_pairs[0];
+        assert_eq!(pair.delay_frames, Some(3));
+        // Noise is uncorrelated with itself three samples on.
+        assert!(pair.correlation.unwrap().abs() < 0.05, "{:?}", pair.correlation);
+        assert!(pair.balance_db.unwrap().abs() < 0.01, "{:?}", pair.balance_db);
+        assert_eq!(report.warnings(), ["channel 2 is 3 frames behind channel 1"]);
+
+        let report = analyzed("analyze_early_right.wav", |left, i| 0.25 * left.get(i + 3).copied().unwrap_or(0.0));
+        let pair = &report.channel_pairs[0];
+        assert_eq!(pair.delay_frames, Some(-3));
+        assert!((pair.balance_db.unwrap() - -12.04).abs() < 0.01, "{:?}", pair.balance_db);
+        assert_eq!(
+            report.warnings(),
+            ["channel 2 is 12.0 dB quieter than channel 1", "channel 2 is 3 frames ahead of channel 1"]
+        );
+
+        // In phase and level, nothing is worth a warning.
+        let report = analyzed("analyze_matched.wav", |left, i| left[i]);
+        assert_eq!(report.channel_pairs[0].delay_frames, Some(0));
+        assert!((report.channel_pairs[0].correlation.unwrap() - 1.0).abs() < 1e-6);
+        assert!(report.warnings().is_empty());
+    }
+}
@@ -0,0 +1,182 @@
+// Mastering a directory of WAV files, with an optional post-command per
+// file (see `hook`).
+//
+// Files are mastered one after another with `master_file_for_streaming`,
+// so any length works. A failed file or post-command is recorded in the
+// report and the batch carries on with the next one.
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::hook::{CommandRunner, HookContext, HookOutcome, PostCommand, DEFAULT_HOOK_TIMEOUT, DEFAULT_MAX_CONCURRENT_HOOKS};
+use crate::master::{master_file_for_streaming, MasterReport, MasterSettings};
+use crate::write_wav::WavSampleFormat;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchSettings {
+    pub master: MasterSettings,
+    pub format: WavSampleFormat,
+    pub post_command: Option<PostCommand>,
+    /// Post-commands allowed to run at the same time.
+    pub max_concurrent_hooks: usize,
+    pub hook_timeout: Duration,
+}
+
+impl Default for BatchSettings {
+    fn default() -> Self {
+        Self {
+            master: MasterSettings::default(),
+            format: WavSampleFormat::Int24,
+            post_command: None,
+            max_concurrent_hooks: DEFAULT_MAX_CONCURRENT_HOOKS,
+            hook_timeout: DEFAULT_HOOK_TIMEOUT,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchFile {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    /// The mastering result, or why the file failed.
+    pub result: Result<MasterReport, String>,
+    /// Outcome of the post-command, when there is one and the file succeeded.
+    pub hook: Option<HookOutcome>,
+}
+
+impl BatchFile {
+    pub fn is_success(&self) -> bool {
+        self.result.is_ok() && self.hook.as_ref().is_none_or(HookOutcome::is_success)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct BatchReport {
+    pub files: Vec<BatchFile>,
+}
+
+impl BatchReport {
+    pub fn failures(&self) -> impl Iterator<Item = &BatchFile> {
+        self.files.iter().filter(|file| !file.is_success())
+    }
+}
+
+/// The `.wav` files directly inside `dir`, sorted by name.
+pub fn wav_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.is_file() && path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("wav")))
+        .collect();
+    files.sort();
+    Ok(files)
+}
+
+/// Masters every WAV file in `in_dir` into `out_dir` under the same name.
+/// Only listing `in_dir` or creating `out_dir` can fail the whole batch.
+pub fn run_batch(in_dir: &Path, out_dir: &Path, settings: &BatchSettings) -> io::Result<BatchReport> {
+    let inputs = wav_files(in_dir)?;
+    fs::create_dir_all(out_dir)?;
+    // Each file is read twice, so writing over it would corrupt it.
+    if fs::canonicalize(in_dir)? == fs::canonicalize(out_dir)? {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "the output directory must differ from the input"));
+    }
+
+    let mut runner = CommandRunner::new(settings.max_concurrent_hooks, settings.hook_timeout);
+    let mut report = BatchReport::default();
+    for (index, input) in inputs.into_iter().enumerate() {
+        let output = out_dir.join(input.file_name().unwrap_or_default());
+        let result = master_file_for_streaming(
+            &input.to_string_lossy(),
+            &output.to_string_lossy(),
+            settings.format,
+            &settings.master,
+        )
+        .map_err(|e| e.to_string());
+
+        if let (Ok(master), Some(post_command)) = (&result, &settings.post_command) {
+            let context = HookContext {
+                input: &input,
+                output: &output,
+                lufs: master.output_lufs,
+                peak_dbfs: master.output_peak_dbfs,
+                duration_seconds: master.duration_seconds(),
+            };
+            runner.submit(index, post_command.command(&context));
+        }
+        report.files.push(BatchFile { input, output, result, hook: None });
+    }
+
+    for (index, outcome) in runner.wait() {
+        report.files[index].hook = Some(outcome);
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_wav::write_wave_file;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("cpal_playbook_{}_{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(path.join("in")).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn write_tone(path: &Path) {
+        let samples: Vec<f32> = (0..48000).map(|i| 0.1 * (i as f32 * 0.05).sin()).collect();
+        write_wave_file(&path.to_string_lossy(), &samples, 48000, 1, WavSampleFormat::Int16).unwrap();
+    }
+
+    #[test]
+    fn failed_files_and_commands_are_recorded_and_the_batch_goes_on() {
+        let dir = TempDir::new("batch_hooks");
+        let (in_dir, out_dir) = (dir.0.join("in"), dir.0.join("out"));
+        write_tone(&in_dir.join("a.wav"));
+        write_tone(&in_dir.join("b.wav"));
+        fs::write(in_dir.join("c.wav"), "not a wav file").unwrap();
+        write_tone(&in_dir.join("d.wav"));
+        fs::write(in_dir.join("notes.txt"), "skipped").unwrap();
+
+        // Leaves the measured loudness next to each output, and fails for b.
+        let template = r#"sh -c 'echo "$PLAYBOOK_LUFS" > "$0.lufs"; case "$0" in *b.wav) exit 3;; esac' {out}"#;
+        let settings = BatchSettings { post_command: Some(PostCommand::parse(template).unwrap()), ..BatchSettings::default() };
+        let report = run_batch(&in_dir, &out_dir, &settings).unwrap();
+
+        let names: Vec<_> = report.files.iter().map(|f| f.input.file_name().unwrap().to_string_lossy().into_owned()).collect();
+        assert_eq!(names, ["a.wav", "b.wav", "c.wav", "d.wav"]);
+        let hooks: Vec<_> = report.files.iter().map(|f| f.hook.clone()).collect();
+        assert_eq!(
+            hooks,
+            [Some(HookOutcome::Succeeded), Some(HookOutcome::Failed(Some(3))), None, Some(HookOutcome::Succeeded)]
+        );
+        assert!(report.files[2].result.is_err());
+        let failures: Vec<_> = report.failures().map(|f| f.output.clone()).collect();
+        assert_eq!(failures, [out_dir.join("b.wav"), out_dir.join("c.wav")]);
+
+        for file in [&report.files[0], &report.files[3]] {
+            let written = fs::read_to_string(format!("{}.lufs", file.output.display())).unwrap();
+            assert_eq!(written.trim(), format!("{:.2}", file.result.as_ref().unwrap().output_lufs));
+        }
+    }
+
+    #[test]
+    fn the_output_directory_must_differ_from_the_input() {
+        let dir = TempDir::new("batch_same_dir");
+        let in_dir = dir.0.join("in");
+        let error = run_batch(&in_dir, &in_dir.join("."), &BatchSettings::default()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+    }
+}
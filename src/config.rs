@@ -0,0 +1,373 @@
+// Persistent application config: preferred devices and defaults, stored as
+// TOML in the platform config directory, or in `playbook.toml` in the
+// working directory for a setup that belongs to one project or machine.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::limiter::SafetyLimiterConfig;
+use crate::toml::{self, Table, Value};
+
+/// Version written to new config files. Bump it when fields change and add
+/// a step to `migrate`.
+pub const CONFIG_VERSION: i64 = 1;
+
+const APP_DIR: &str = "cpal_playbook";
+const CONFIG_FILE: &str = "config.toml";
+/// Read instead of the platform config when it is in the working directory.
+pub const LOCAL_CONFIG_FILE: &str = "playbook.toml";
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(String),
+    /// The file was written by a newer version of the playbook, or its
+    /// version is negative.
+    UnsupportedVersion(i64),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "config I/O error: {}", e),
+            ConfigError::Parse(msg) => write!(f, "invalid config: {}", msg),
+            ConfigError::UnsupportedVersion(v) if *v < 0 => write!(f, "config version {} is not a valid version", v),
+            ConfigError::UnsupportedVersion(v) => {
+                write!(f, "config version {} is newer than supported version {}", v, CONFIG_VERSION)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Matched against device names ignoring case; a part of the name such
+    /// as "scarlett" is enough when it picks out one device.
+    pub input_device: Option<String>,
+    pub output_device: Option<String>,
+    pub sample_rate: Option<u32>,
+    pub buffer_size: Option<u32>,
+    pub eq_preset_path: Option<PathBuf>,
+    /// False opts out of the output safety limiter.
+    pub safety_limiter: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            input_device: None,
+            output_device: None,
+            sample_rate: None,
+            buffer_size: None,
+            eq_preset_path: None,
+            safety_limiter: true,
+        }
+    }
+}
+
+/// Platform config directory for the playbook:
+/// `%APPDATA%\cpal_playbook` on Windows, `~/Library/Application Support/cpal_playbook`
+/// on macOS and `$XDG_CONFIG_HOME/cpal_playbook` (or `~/.config/cpal_playbook`) elsewhere.
+pub fn config_dir() -> Option<PathBuf> {
+    let env_path = |name: &str| std::env::var_os(name).filter(|v| !v.is_empty()).map(PathBuf::from);
+
+    let base = if cfg!(target_os = "windows") {
+        env_path("APPDATA")
+    } else if cfg!(target_os = "macos") {
+        env_path("HOME").map(|home| home.join("Library").join("Application Support"))
+    } else {
+        env_path("XDG_CONFIG_HOME").or_else(|| env_path("HOME").map(|home| home.join(".config")))
+    };
+
+    base.map(|dir| dir.join(APP_DIR))
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(CONFIG_FILE))
+}
+
+impl Config {
+    /// Loads `playbook.toml` from the working directory, or when there is
+    /// none, the config from the platform config directory.
+    ///
+    /// Never fails: a missing file gives the defaults, and a corrupt platform
+    /// config is renamed aside (`config.toml.corrupt`) and regenerated with a
+    /// warning. A broken `playbook.toml` is left alone for its owner to fix,
+    /// and the platform config used instead.
+    pub fn load() -> Config {
+        let local = Path::new(LOCAL_CONFIG_FILE);
+        if local.exists() {
+            match Config::load_from(local) {
+                Ok(config) => return config,
+                Err(e) => eprintln!("Warning: {} ({}), using the platform config", e, local.display()),
+            }
+        }
+        match config_path() {
+            Some(path) => Config::load_or_recover(&path),
+            None => {
+                eprintln!("Warning: no config directory found, using default settings");
+                Config::default()
+            }
+        }
+    }
+
+    pub fn save(&self) -> Result<(), ConfigError> {
+        match config_path() {
+            Some(path) => self.save_to(&path),
+            None => Err(ConfigError::Io(io::Error::new(io::ErrorKind::NotFound, "no config directory found"))),
+        }
+    }
+
+    pub fn load_or_recover(path: &Path) -> Config {
+        match Config::load_from(path) {
+            Ok(config) => config,
+            Err(ConfigError::Io(e)) if e.kind() == io::ErrorKind::NotFound => Config::default(),
+            Err(e @ ConfigError::UnsupportedVersion(_)) => {
+                // Not corrupt, just newer than us: leave the file alone.
+                eprintln!("Warning: {} ({}), using default settings", e, path.display());
+                Config::default()
+            }
+            Err(e) => {
+                let mut aside = path.as_os_str().to_owned();
+                aside.push(".corrupt");
+                eprintln!(
+                    "Warning: {} ({}), moving it to {} and using default settings",
+                    e,
+                    path.display(),
+                    Path::new(&aside).display()
+                );
+                let config = Config::default();
+                if fs::rename(path, &aside).is_ok() {
+                    if let Err(e) = config.save_to(path) {
+                        eprintln!("Warning: could not regenerate config: {}", e);
+                    }
+                }
+                config
+            }
+        }
+    }
+
+    pub fn load_from(path: &Path) -> Result<Config, ConfigError> {
+        let text = fs::read_to_string(path)?;
+        Config::from_toml(&text)
+    }
+
+    pub fn save_to(&self, path: &Path) -> Result<(), ConfigError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_toml())?;
+        Ok(())
+    }
+
+    pub fn from_toml(text: &str) -> Result<Config, ConfigError> {
+        let table = toml::parse(text).map_err(|e| ConfigError::Parse(e.to_string()))?;
+        let table = migrate(table)?;
+
+        let string = |key: &str| -> Result<Option<String>, ConfigError> {
+            match table.get(key) {
+                None => Ok(None),
+                Some(v) => v
+                    .as_str()
+                    .map(|s| Some(s.to_string()))
+                    .ok_or_else(|| ConfigError::Parse(format!("'{}' must be a string", key))),
+            }
+        };
+        let unsigned = |key: &str| -> Result<Option<u32>, ConfigError> {
+            match table.get(key) {
+                None => Ok(None),
+                Some(v) => v
+                    .as_integer()
+                    .and_then(|i| u32::try_from(i).ok())
+                    .map(Some)
+                    .ok_or_else(|| ConfigError::Parse(format!("'{}' must be a positive integer", key))),
+            }
+        };
+
+        let safety_limiter = match table.get("safety_limiter") {
+            None => true,
+            Some(v) => v
+                .as_bool()
+                .ok_or_else(|| ConfigError::Parse("'safety_limiter' must be a boolean".to_string()))?,
+        };
+
+        Ok(Config {
+            input_device: string("input_device")?,
+            output_device: string("output_device")?,
+            sample_rate: unsigned("sample_rate")?,
+            buffer_size: unsigned("buffer_size")?,
+            eq_preset_path: string("eq_preset_path")?.map(PathBuf::from),
+            safety_limiter,
+        })
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut table = Table::new();
+        table.insert("version".to_string(), Value::Integer(CONFIG_VERSION));
+        if let Some(name) = &self.input_device {
+            table.insert("input_device".to_string(), Value::String(name.clone()));
+        }
+        if let Some(name) = &self.output_device {
+            table.insert("output_device".to_string(), Value::String(name.clone()));
+        }
+        if let Some(rate) = self.sample_rate {
+            table.insert("sample_rate".to_string(), Value::Integer(rate as i64));
+        }
+        if let Some(frames) = self.buffer_size {
+            table.insert("buffer_size".to_string(), Value::Integer(frames as i64));
+        }
+        if let Some(path) = &self.eq_preset_path {
+            table.insert("eq_preset_path".to_string(), Value::String(path.to_string_lossy().into_owned()));
+        }
+        table.insert("safety_limiter".to_string(), Value::Boolean(self.safety_limiter));
+        toml::to_string(&table)
+    }
+
+    pub fn safety_limiter_config(&self) -> SafetyLimiterConfig {
+        SafetyLimiterConfig {
+            enabled: self.safety_limiter,
+            ..SafetyLimiterConfig::default()
+        }
+    }
+}
+
+// Upgrades a parsed config table to CONFIG_VERSION one step at a time.
+// Files without a version key predate versioning and count as version 0.
+fn migrate(mut table: Table) -> Result<Table, ConfigError> {
+    let mut version = match table.get("version") {
+        None => 0,
+        Some(v) => v
+            .as_integer()
+            .ok_or_else(|| ConfigError::Parse("'version' must be an integer".to_string()))?,
+    };
+
+    if !(0..=CONFIG_VERSION).contains(&version) {
+        return Err(ConfigError::UnsupportedVersion(version));
+    }
+
+    while version < CONFIG_VERSION {
+        match version {
+            // Version 0 had the same fields, it only lacked the version key.
+            0 => {}
+            _ => unreachable!("no migration from config version {}", version),
+        }
+        version += 1;
+    }
+
+    table.insert("version".to_string(), Value::Integer(version));
+    Ok(table)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A directory of its own in the temp directory, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("cpal_playbook_{}_{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn full_config() -> Config {
+        Config {
+            input_device: Some("Scarlett 2i2 USB".to_string()),
+            output_device: Some("Built-in \"Speakers\"".to_string()),
+            sample_rate: Some(48000),
+            buffer_size: Some(256),
+            eq_preset_path: Some(PathBuf::from("presets/vocal.toml")),
+            safety_limiter: false,
+        }
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let dir = TempDir::new("config_round_trip");
+        let path = dir.0.join("nested").join(CONFIG_FILE);
+        for config in [Config::default(), full_config()] {
+            config.save_to(&path).unwrap();
+            assert_eq!(Config::load_from(&path).unwrap(), config);
+        }
+    }
+
+    #[test]
+    fn missing_file_gives_defaults() {
+        let dir = TempDir::new("config_missing");
+        let path = dir.0.join(CONFIG_FILE);
+        assert_eq!(Config::load_or_recover(&path), Config::default());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn corrupt_file_is_moved_aside_and_regenerated() {
+        let dir = TempDir::new("config_corrupt");
+        fs::create_dir_all(&dir.0).unwrap();
+        let path = dir.0.join(CONFIG_FILE);
+        fs::write(&path, "input_device = \"unterminated").unwrap();
+
+        assert_eq!(Config::load_or_recover(&path), Config::default());
+        let aside = dir.0.join("config.toml.corrupt");
+        assert_eq!(fs::read_to_string(aside).unwrap(), "input_device = \"unterminated");
+        assert_eq!(Config::load_from(&path).unwrap(), Config::default());
+    }
+
+    #[test]
+    fn newer_file_is_left_alone() {
+        let dir = TempDir::new("config_newer");
+        fs::create_dir_all(&dir.0).unwrap();
+        let path = dir.0.join(CONFIG_FILE);
+        let text = format!("version = {}\nsample_rate = 44100\n", CONFIG_VERSION + 1);
+        fs::write(&path, &text).unwrap();
+
+        assert_eq!(Config::load_or_recover(&path), Config::default());
+        assert_eq!(fs::read_to_string(&path).unwrap(), text);
+        assert!(!dir.0.join("config.toml.corrupt").exists());
+    }
+
+    #[test]
+    fn versions_outside_the_supported_range_are_rejected() {
+        assert!(matches!(Config::from_toml("version = -1"), Err(ConfigError::UnsupportedVersion(-1))));
+        let newer = format!("version = {}", CONFIG_VERSION + 1);
+        assert!(matches!(Config::from_toml(&newer), Err(ConfigError::UnsupportedVersion(_))));
+        assert!(matches!(Config::from_toml("version = \"1\""), Err(ConfigError::Parse(_))));
+    }
+
+    #[test]
+    fn unversioned_file_migrates() {
+        let config = Config::from_toml("output_device = \"USB\"\nsafety_limiter = true\n").unwrap();
+        assert_eq!(config.output_device.as_deref(), Some("USB"));
+        assert!(config.to_toml().contains(&format!("version = {}", CONFIG_VERSION)));
+    }
+
+    #[test]
+    fn wrong_types_are_parse_errors() {
+        for text in ["sample_rate = -48000", "sample_rate = \"48000\"", "input_device = 3", "safety_limiter = 1"] {
+            assert!(matches!(Config::from_toml(text), Err(ConfigError::Parse(_))), "{}", text);
+        }
+    }
+
+    #[test]
+    fn safety_limiter_opt_out_reaches_the_limiter() {
+        assert!(Config::default().safety_limiter_config().enabled);
+        assert!(!full_config().safety_limiter_config().enabled);
+    }
+}
@@ -0,0 +1,431 @@
+// Whole-file convolution reverb with mono, stereo and true-stereo impulse
+// responses.
+//
+// The layout is taken from the IR's channel count unless overridden:
+//
+//   1 channel   Mono        the same IR on every input channel
+//   2 channels  DualMono    L IR on L, R IR on R
+//   4 channels  TrueStereo  four paths, in the order L→L, L→R, R→L, R→R:
+//                             out L = in L * LL + in R * RL
+//                             out R = in L * LR + in R * RR
+//
+// With normalization on, the IR is scaled so the reverb puts out the same
+// energy per output channel whatever the layout: a true-stereo output sums
+// two paths, so it is scaled by their combined energy rather than per path,
+// and switching one room between its dual-mono and true-stereo files
+// doesn't change the level.
+use std::fmt;
+
+use crate::adapter::{convert_planar, AudioSpec};
+use crate::fft::overlap_add_convolve;
+use crate::read_wav::read_wav_data;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrLayout {
+    Mono,
+    DualMono,
+    TrueStereo,
+}
+
+impl IrLayout {
+    /// The layout an IR file with `channels` channels is in.
+    pub fn from_channels(channels: usize) -> Option<IrLayout> {
+        match channels {
+            1 => Some(IrLayout::Mono),
+            2 => Some(IrLayout::DualMono),
+            4 => Some(IrLayout::TrueStereo),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IrMode {
+    /// From the channel count, see `IrLayout::from_channels`.
+    #[default]
+    Auto,
+    /// Every IR channel averaged into one.
+    Mono,
+    /// Two channels as they are, or the L→L and R→R paths of a
+    /// true-stereo IR without the cross-feed.
+    DualMono,
+    TrueStereo,
+}
+
+#[derive(Debug)]
+pub enum ConvolutionError {
+    Read(String),
+    /// The IR's channels don't fit the layout.
+    Layout { channels: usize, mode: IrMode },
+    Empty,
+}
+
+impl fmt::Display for ConvolutionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConvolutionError::Read(e) => write!(f, "Cannot read impulse response: {}", e),
+            ConvolutionError::Layout { channels, mode } => {
+                write!(f, "An impulse response with {} channels can't be used as {:?}", channels, mode)
+            }
+            ConvolutionError::Empty => write!(f, "The impulse response is empty"),
+        }
+    }
+}
+
+impl std::error::Error for ConvolutionError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImpulseResponse {
+    pub layout: IrLayout,
+    /// One path for `Mono`, two (L, R) for `DualMono`, four (LL, LR, RL,
+    /// RR) for `TrueStereo`.
+    pub paths: Vec<Vec<f32>>,
+    pub sample_rate: u32,
+}
+
+impl ImpulseResponse {
+    pub fn new(channels: Vec<Vec<f32>>, sample_rate: u32, mode: IrMode) -> Result<ImpulseResponse, ConvolutionError> {
+        let count = channels.len();
+        if channels.iter().all(|c| c.is_empty()) {
+            return Err(ConvolutionError::Empty);
+        }
+        let layout_error = ConvolutionError::Layout { channels: count, mode };
+        let (layout, paths) = match (mode, count) {
+            (IrMode::Auto, _) => (IrLayout::from_channels(count).ok_or(layout_error)?, channels),
+            (IrMode::Mono, _) => {
+                let len = channels.iter().map(|c| c.len()).max().unwrap_or(0);
+                let scale = 1.0 / count as f32;
+                let mono = (0..len).map(|i| channels.iter().filter_map(|c| c.get(i)).sum::<f32>() * scale).collect();
+                (IrLayout::Mono, vec![mono])
+            }
+            (IrMode::DualMono, 2) => (IrLayout::DualMono, channels),
+            (IrMode::DualMono, 4) => {
+                let mut channels = channels;
+                let rr = channels.swap_remove(3);
+                (IrLayout::DualMono, vec![channels.swap_remove(0), rr])
+            }
+            (IrMode::TrueStereo, 4) => (IrLayout::TrueStereo, channels),
+            _ => return Err(layout_error),
+        };
+        Ok(ImpulseResponse { layout, paths, sample_rate })
+    }
+
+    pub fn load(path: &str, mode: IrMode) -> Result<ImpulseResponse, ConvolutionError> {
+        let data = read_wav_data(path).map_err(|e| ConvolutionError::Read(e.to_string()))?;
+        ImpulseResponse::new(data.channels, data.sample_rate, mode)
+    }
+
+    /// Energy the reverb puts into each output channel for a unit-energy
+    /// input on every input channel, averaged over the outputs.
+    pub fn output_energy(&self) -> f64 {
+        let energy = |path: &[f32]| path.iter().map(|&s| s as f64 * s as f64).sum::<f64>();
+        let e: Vec<f64> = self.paths.iter().map(|p| energy(p)).collect();
+        match self.layout {
+            IrLayout::Mono => e[0],
+            IrLayout::DualMono => (e[0] + e[1]) / 2.0,
+            // Out L gets LL and RL, out R gets LR and RR.
+            IrLayout::TrueStereo => ((e[0] + e[2]) + (e[1] + e[3])) / 2.0,
+        }
+    }
+
+    /// Scales the IR to unit `output_energy`.
+    pub fn normalize(&mut self) {
+        let energy = self.output_energy();
+        if energy > 0.0 {
+            let gain = (1.0 / energy.sqrt()) as f32;
+            for sample in self.paths.iter_mut().flatten() {
+                *sample *= gain;
+            }
+        }
+    }
+
+    /// The same IR at `sample_rate`.
+    pub fn resampled(&self, sample_rate: u32) -> ImpulseResponse {
+        if sample_rate == self.sample_rate {
+            return self.clone();
+        }
+        let channels = self.paths.len() as u16;
+        let paths = convert_planar(
+            &self.paths,
+            AudioSpec::new(self.sample_rate, channels),
+            AudioSpec::new(sample_rate, channels),
+        );
+        ImpulseResponse { layout: self.layout, paths, sample_rate }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConvolutionSettings {
+    /// 0 is the dry signal only, 1 the reverb only.
+    pub wet: f32,
+    /// Bring the IR to unit output energy first.
+    pub normalize: bool,
+}
+
+impl Default for ConvolutionSettings {
+    fn default() -> Self {
+        Self { wet: 0.3, normalize: true }
+    }
+}
+
+/// Convolves planar `input` (one or two channels) with `ir`, keeping the
+/// reverb tail: the output is `ir.len() - 1` frames longer than the input.
+///
+/// The output is stereo unless both the input and the IR are mono. A mono
+/// input feeds both inputs of a stereo IR. The IR is resampled to
+/// `sample_rate` if needed.
+pub fn convolution_reverb(
+    input: &[Vec<f32>],
+    sample_rate: u32,
+    ir: &ImpulseResponse,
+    settings: &ConvolutionSettings,
+) -> Vec<Vec<f32>> {
+    let mut ir = ir.resampled(sample_rate);
+    if settings.normalize {
+        ir.normalize();
+    }
+    let (left, right) = match input {
+        [] => return Vec::new(),
+        [mono] => (mono.as_slice(), mono.as_slice()),
+        [left, right, ..] => (left.as_slice(), right.as_slice()),
+    };
+    let paths: Vec<&[f32]> = ir.paths.iter().map(Vec::as_slice).collect();
+
+    let reverb: Vec<Vec<f32>> = match ir.layout {
+        IrLayout::Mono if input.len() == 1 => overlap_add_convolve(left, &paths),
+        IrLayout::Mono => vec![
+            overlap_add_convolve(left, &paths).remove(0),
+            overlap_add_convolve(right, &paths).remove(0),
+        ],
+        IrLayout::DualMono => vec![
+            overlap_add_convolve(left, &paths[0..1]).remove(0),
+            overlap_add_convolve(right, &paths[1..2]).remove(0),
+        ],
+        IrLayout::TrueStereo => {
+            // Each input goes through both of its paths for one transform.
+            let from_left = overlap_add_convolve(left, &[paths[0], paths[1]]);
+            let from_right = overlap_add_convolve(right, &[paths[2], paths[3]]);
+            let sum = |a: &[f32], b: &[f32]| -> Vec<f32> {
+                (0..a.len().max(b.len())).map(|i| a.get(i).unwrap_or(&0.0) + b.get(i).unwrap_or(&0.0)).collect()
+            };
+            vec![sum(&from_left[0], &from_right[0]), sum(&from_left[1], &from_right[1])]
+        }
+    };
+
+    let wet = settings.wet.clamp(0.0, 1.0);
+    let dry_inputs = [left, right];
+    reverb
+        .into_iter()
+        .enumerate()
+        .map(|(c, mut channel)| {
+            for sample in channel.iter_mut() {
+                *sample *= wet;
+            }
+            for (out, &dry) in channel.iter_mut().zip(dry_inputs[c]) {
+                *out += dry * (1.0 - wet);
+            }
+            channel
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_wav::{write_wave_file, WavSampleFormat};
+
+    // A path in the temp directory, removed when dropped.
+    struct TempPath(std::path::PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("cpal_playbook_{}_{}", std::process::id(), name)))
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn ir_file(name: &str, channels: &[Vec<f32>], sample_rate: u32) -> TempPath {
+        let file = TempPath::new(name);
+        let interleaved: Vec<f32> = (0..channels[0].len()).flat_map(|i| channels.iter().map(move |c| c[i])).collect();
+        write_wave_file(file.path(), &interleaved, sample_rate, channels.len() as u16, WavSampleFormat::Float32).unwrap();
+        file
+    }
+
+    // A single tap of `gain` at `at`.
+    fn tap(at: usize, gain: f32, len: usize) -> Vec<f32> {
+        let mut ir = vec![0.0; len];
+        ir[at] = gain;
+        ir
+    }
+
+    fn noise(seed: u32, len: usize, decay: f32) -> Vec<f32> {
+        let mut state = seed;
+        (0..len)
+            .map(|i| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                (state as f32 / u32::MAX as f32 - 0.5) * (-decay * i as f32).exp()
+            })
+            .collect()
+    }
+
+    fn energy_db(samples: &[f32]) -> f64 {
+        10.0 * samples.iter().map(|&s| s as f64 * s as f64).sum::<f64>().log10()
+    }
+
+    fn wet_only() -> ConvolutionSettings {
+        ConvolutionSettings { wet: 1.0, normalize: false }
+    }
+
+    // The positions of the samples that aren't zero, with their values
+    // rounded past the FFT's error.
+    fn taps(channel: &[f32]) -> Vec<(usize, f32)> {
+        channel.iter().enumerate().filter(|(_, s)| s.abs() > 1e-4).map(|(i, &s)| (i, (s * 1e4).round() / 1e4)).collect()
+    }
+
+    #[test]
+    fn the_layout_follows_the_channel_count_or_the_mode() {
+        let paths = |count: usize| (0..count).map(|c| tap(c, 1.0, 8)).collect::<Vec<_>>();
+        let layout = |count, mode| ImpulseResponse::new(paths(count), 48000, mode).map(|ir| ir.layout);
+        assert_eq!(layout(1, IrMode::Auto).unwrap(), IrLayout::Mono);
+        assert_eq!(layout(2, IrMode::Auto).unwrap(), IrLayout::DualMono);
+        assert_eq!(layout(4, IrMode::Auto).unwrap(), IrLayout::TrueStereo);
+        assert!(matches!(layout(3, IrMode::Auto), Err(ConvolutionError::Layout { channels: 3, mode: IrMode::Auto })));
+        assert!(matches!(layout(2, IrMode::TrueStereo), Err(ConvolutionError::Layout { channels: 2, .. })));
+        assert!(matches!(layout(1, IrMode::DualMono), Err(ConvolutionError::Layout { channels: 1, .. })));
+        assert!(matches!(ImpulseResponse::new(vec![Vec::new(); 2], 48000, IrMode::Auto), Err(ConvolutionError::Empty)));
+
+        // Mono averages every channel, dual-mono keeps the LL and RR paths.
+        let mono = ImpulseResponse::new(paths(4), 48000, IrMode::Mono).unwrap();
+        assert_eq!(mono.paths, [[0.25, 0.25, 0.25, 0.25, 0.0, 0.0, 0.0, 0.0]]);
+        let dual = ImpulseResponse::new(paths(4), 48000, IrMode::DualMono).unwrap();
+        assert_eq!(dual.paths, [tap(0, 1.0, 8), tap(3, 1.0, 8)]);
+    }
+
+    #[test]
+    fn irs_load_from_mono_stereo_and_four_channel_files() {
+        for (count, layout) in [(1, IrLayout::Mono), (2, IrLayout::DualMono), (4, IrLayout::TrueStereo)] {
+            let channels: Vec<Vec<f32>> = (0..count).map(|c| tap(c, 0.5, 16)).collect();
+            let file = ir_file(&format!("ir_{}ch.wav", count), &channels, 44100);
+            let ir = ImpulseResponse::load(file.path(), IrMode::Auto).unwrap();
+            assert_eq!(ir, ImpulseResponse { layout, paths: channels, sample_rate: 44100 });
+        }
+
+        let file = ir_file("ir_3ch.wav", &[tap(0, 0.5, 16), tap(1, 0.5, 16), tap(2, 0.5, 16)], 44100);
+        assert!(matches!(ImpulseResponse::load(file.path(), IrMode::Auto), Err(ConvolutionError::Layout { channels: 3, .. })));
+        assert_eq!(ImpulseResponse::load(file.path(), IrMode::Mono).unwrap().layout, IrLayout::Mono);
+        let missing = TempPath::new("ir_missing.wav");
+        assert!(matches!(ImpulseResponse::load(missing.path(), IrMode::Auto), Err(ConvolutionError::Read(_))));
+    }
+
+    #[test]
+    fn an_ir_at_another_rate_is_resampled() {
+        // A 10 ms decay recorded at 24 kHz still lasts 10 ms at 48 kHz.
+        let channels = [noise(1, 2400, 0.002), noise(2, 2400, 0.002)];
+        let file = ir_file("ir_24k.wav", &channels, 24000);
+        let ir = ImpulseResponse::load(file.path(), IrMode::Auto).unwrap();
+        let resampled = ir.resampled(48000);
+        assert_eq!((resampled.layout, resampled.sample_rate), (IrLayout::DualMono, 48000));
+        assert!(resampled.paths.iter().all(|p| (p.len() as i64 - 4800).abs() <= 8), "{:?}", resampled.paths[0].len());
+        assert_eq!(ir.resampled(24000), ir);
+
+        // The reverb tail has the resampled length, and normalization
+        // makes up for the extra samples.
+        let mut impulse = vec![0.0; 100];
+        impulse[0] = 1.0;
+        let settings = ConvolutionSettings { wet: 1.0, normalize: true };
+        let output = convolution_reverb(&[impulse.clone(), impulse], 48000, &ir, &settings);
+        assert_eq!(output[0].len(), 100 + resampled.paths[0].len() - 1);
+        let mean = (energy_db(&output[0]) + energy_db(&output[1])) / 2.0;
+        assert!(mean.abs() < 0.5, "{:.2} dB", mean);
+    }
+
+    #[test]
+    fn each_path_lands_in_its_output() {
+        let ir = ImpulseResponse::new(
+            vec![tap(0, 0.5, 40), tap(10, 0.25, 40), tap(20, -0.5, 40), tap(30, 0.125, 40)],
+            48000,
+            IrMode::Auto,
+        )
+        .unwrap();
+        let (impulse, silence) = (tap(0, 1.0, 8), vec![0.0; 8]);
+
+        // From the left input: LL into out L, LR into out R.
+        let from_left = convolution_reverb(&[impulse.clone(), silence.clone()], 48000, &ir, &wet_only());
+        assert_eq!(from_left.len(), 2);
+        assert_eq!((taps(&from_left[0]), taps(&from_left[1])), (vec![(0, 0.5)], vec![(10, 0.25)]));
+        // From the right input: RL into out L, RR into out R.
+        let from_right = convolution_reverb(&[silence, impulse.clone()], 48000, &ir, &wet_only());
+        assert_eq!((taps(&from_right[0]), taps(&from_right[1])), (vec![(20, -0.5)], vec![(30, 0.125)]));
+        // A mono input feeds both.
+        let from_mono = convolution_reverb(std::slice::from_ref(&impulse), 48000, &ir, &wet_only());
+        assert_eq!((taps(&from_mono[0]), taps(&from_mono[1])), (vec![(0, 0.5), (20, -0.5)], vec![(10, 0.25), (30, 0.125)]));
+
+        let dual = ImpulseResponse::new(vec![tap(5, 0.5, 40), tap(15, 0.25, 40)], 48000, IrMode::Auto).unwrap();
+        let output = convolution_reverb(&[impulse.clone(), impulse.clone()], 48000, &dual, &wet_only());
+        assert_eq!((taps(&output[0]), taps(&output[1])), (vec![(5, 0.5)], vec![(15, 0.25)]));
+        let mono = ImpulseResponse::new(vec![tap(5, 0.5, 40)], 48000, IrMode::Auto).unwrap();
+        let output = convolution_reverb(std::slice::from_ref(&impulse), 48000, &mono, &wet_only());
+        assert_eq!(output.len(), 1);
+        assert_eq!(taps(&output[0]), [(5, 0.5)]);
+    }
+
+    #[test]
+    fn normalized_levels_match_across_layouts() {
+        // One room as a mono, a dual-mono and a true-stereo IR, the last
+        // with cross-feed as loud as the direct paths.
+        let (ll, lr, rl, rr) = (noise(3, 4800, 0.001), noise(4, 4800, 0.001), noise(5, 4800, 0.001), noise(6, 4800, 0.001));
+        let irs = [
+            ImpulseResponse::new(vec![ll.clone()], 48000, IrMode::Auto).unwrap(),
+            ImpulseResponse::new(vec![ll.clone(), rr.clone()], 48000, IrMode::Auto).unwrap(),
+            ImpulseResponse::new(vec![ll, lr, rl, rr], 48000, IrMode::Auto).unwrap(),
+        ];
+        let mut true_stereo = irs[2].clone();
+        true_stereo.normalize();
+        assert!((true_stereo.output_energy() - 1.0).abs() < 1e-6);
+
+        let input = [noise(7, 96000, 0.0), noise(8, 96000, 0.0)];
+        let input_db = (energy_db(&input[0]) + energy_db(&input[1])) / 2.0;
+        let settings = ConvolutionSettings { wet: 1.0, normalize: true };
+        let levels: Vec<f64> = irs
+            .iter()
+            .map(|ir| {
+                let output = convolution_reverb(&input, 48000, ir, &settings);
+                (energy_db(&output[0]) + energy_db(&output[1])) / 2.0 - input_db
+            })
+            .collect();
+        assert!(levels.iter().all(|db| db.abs() < 0.5), "{:?}", levels);
+
+        // Without normalization the true-stereo output is 3 dB up on the
+        // dual-mono one, from summing two paths per output.
+        let raw: Vec<f64> = irs[1..]
+            .iter()
+            .map(|ir| {
+                let output = convolution_reverb(&input, 48000, ir, &wet_only());
+                (energy_db(&output[0]) + energy_db(&output[1])) / 2.0
+            })
+            .collect();
+        assert!((raw[1] - raw[0] - 3.0).abs() < 0.5, "{:?}", raw);
+    }
+
+    #[test]
+    fn dry_signal_is_mixed_in_by_wet() {
+        let ir = ImpulseResponse::new(vec![tap(2, 1.0, 4)], 48000, IrMode::Auto).unwrap();
+        let input = vec![1.0, 0.5, 0.0, 0.0];
+        let settings = |wet| ConvolutionSettings { wet, normalize: false };
+        assert_eq!(convolution_reverb(std::slice::from_ref(&input), 48000, &ir, &settings(0.0))[0], [1.0, 0.5, 0.0, 0.0, 0.0, 0.0, 0.0]);
+        let half = &convolution_reverb(std::slice::from_ref(&input), 48000, &ir, &settings(0.5))[0];
+        assert!(half.iter().zip([0.5, 0.25, 0.5, 0.25, 0.0, 0.0, 0.0]).all(|(a, b)| (a - b).abs() < 1e-6), "{:?}", half);
+        assert!(convolution_reverb(&[], 48000, &ir, &settings(0.5)).is_empty());
+    }
+}
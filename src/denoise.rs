@@ -0,0 +1,346 @@
+// Spectral noise reduction from a learned noise profile.
+//
+// A profile is the average magnitude spectrum of a stretch of noise on its
+// own. Denoising subtracts it from every STFT frame of the signal, as a
+// gain per bin that never drops below a floor, so quiet detail is turned
+// down instead of cut out.
+//
+// The noise can be a hand-picked clip (`learn_noise_profile`) or found
+// automatically (`learn_noise_profile_auto`): the signal is cut into 10 ms
+// windows, a simple level-based voice activity detector marks the windows
+// well above the noise floor, and the quietest inactive stretch of at least
+// `MIN_NOISE_REGION_SECONDS` is used if its spectrum holds still. A region
+// whose spectrum keeps moving is quiet music or speech, not noise, and is
+// refused rather than turned into a bad profile.
+use std::fmt;
+use std::ops::Range;
+
+use rustfft::num_complex::Complex;
+use rustfft::FftPlanner;
+
+use crate::analysis::linear_to_db;
+use crate::limiter::db_to_linear;
+use crate::stft::{hann_window, istft, stft};
+
+/// Analysis frame length, about 46 ms (2048 samples at 44.1 and 48 kHz).
+const FRAME_SECONDS: f32 = 0.046;
+/// Frames overlap by 75%, which keeps the Hann window COLA.
+const HOP_DIVISOR: usize = 4;
+/// Noise estimate multiplier, so the noise's own fluctuations above its
+/// average are caught too.
+const OVER_SUBTRACTION: f32 = 1.5;
+pub const DEFAULT_REDUCTION_DB: f32 = 20.0;
+
+/// Length of the level windows the region search works on.
+const LEVEL_WINDOW_SECONDS: f32 = 0.01;
+pub const MIN_NOISE_REGION_SECONDS: f32 = 0.4;
+/// The region stops growing here; more noise doesn't improve the profile.
+pub const MAX_NOISE_REGION_SECONDS: f32 = 5.0;
+/// Windows this far above the noise floor count as activity.
+const VAD_THRESHOLD_DB: f32 = 10.0;
+/// The 10th percentile of window levels is taken as the noise floor.
+const NOISE_FLOOR_PERCENTILE: f32 = 0.1;
+/// The chosen region grows over neighbouring windows up to this far above
+/// its own level.
+const REGION_MARGIN_DB: f32 = 3.0;
+/// Digital silence carries no noise to learn from.
+const DIGITAL_SILENCE_DBFS: f32 = -120.0;
+/// Bands the spectrum is summed into for measuring flux, so the random
+/// fluctuation of single noise bins averages out.
+const FLUX_BANDS: usize = 32;
+/// Highest mean frame-to-frame change of the band levels a noise region
+/// may have.
+pub const MAX_NOISE_FLUX_DB: f32 = 1.5;
+
+fn frame_size(sample_rate: u32) -> usize {
+    ((sample_rate as f32 * FRAME_SECONDS) as usize).next_power_of_two().max(64)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct NoiseProfile {
+    pub sample_rate: u32,
+    pub fft_size: usize,
+    /// Mean magnitude of each bin, `fft_size / 2 + 1` of them.
+    pub magnitudes: Vec<f32>,
+    /// The samples the profile was learned from, when found automatically.
+    pub region: Option<Range<usize>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NoNoiseFound {
+    /// Shorter than `MIN_NOISE_REGION_SECONDS`.
+    TooShort,
+    /// No stretch of `MIN_NOISE_REGION_SECONDS` without activity.
+    NoQuietRegion,
+    /// The quietest region's spectrum moves too much to be noise.
+    NotStationary { region: Range<usize>, flux_db: f32 },
+}
+
+impl fmt::Display for NoNoiseFound {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NoNoiseFound::TooShort => write!(f, "Too short to find {} s of noise in", MIN_NOISE_REGION_SECONDS),
+            NoNoiseFound::NoQuietRegion => {
+                write!(f, "No quiet stretch of at least {} s to learn the noise from", MIN_NOISE_REGION_SECONDS)
+            }
+            NoNoiseFound::NotStationary { region, flux_db } => write!(
+                f,
+                "The quietest stretch (samples {} to {}) changes too much to be noise ({:.2} dB spectral flux)",
+                region.start, region.end, flux_db
+            ),
+        }
+    }
+}
+
+impl std::error::Error for NoNoiseFound {}
+
+// Magnitude spectra of the frames that fit entirely inside `samples`.
+fn magnitude_frames(samples: &[f32], fft_size: usize) -> Vec<Vec<f32>> {
+    let window = hann_window(fft_size);
+    let hop = fft_size / HOP_DIVISOR;
+    let fft = FftPlanner::new().plan_fft_forward(fft_size);
+    let mut buffer = vec![Complex { re: 0.0, im: 0.0 }; fft_size];
+    let count = if samples.len() >= fft_size { (samples.len() - fft_size) / hop + 1 } else { 0 };
+    (0..count)
+        .map(|frame| {
+            let start = frame * hop;
+            for ((bin, &sample), &w) in buffer.iter_mut().zip(&samples[start..start + fft_size]).zip(&window) {
+                *bin = Complex { re: sample * w, im: 0.0 };
+            }
+            fft.process(&mut buffer);
+            buffer[..fft_size / 2 + 1].iter().map(|c| c.norm()).collect()
+        })
+        .collect()
+}
+
+/// Profile of `noise`, a clip with nothing but the noise to remove in it.
+pub fn learn_noise_profile(noise: &[f32], sample_rate: u32) -> NoiseProfile {
+    let fft_size = frame_size(sample_rate);
+    let frames = magnitude_frames(noise, fft_size);
+    let mut magnitudes = vec![0.0; fft_size / 2 + 1];
+    for frame in &frames {
+        for (sum, &m) in magnitudes.iter_mut().zip(frame) {
+            *sum += m;
+        }
+    }
+    let count = frames.len().max(1) as f32;
+    for m in magnitudes.iter_mut() {
+        *m /= count;
+    }
+    NoiseProfile { sample_rate, fft_size, magnitudes, region: None }
+}
+
+/// Mean change of the band levels from one frame to the next, in dB. Low
+/// for steady noise, high for anything with notes, words or transients.
+pub fn spectral_flux_db(samples: &[f32], sample_rate: u32) -> f32 {
+    let frames = magnitude_frames(samples, frame_size(sample_rate));
+    let band_levels: Vec<Vec<f32>> = frames
+        .iter()
+        .map(|frame| {
+            // DC left out.
+            let bins = &frame[1..];
+            let width = (bins.len() / FLUX_BANDS).max(1);
+            bins.chunks(width)
+                .map(|band| 10.0 * (band.iter().map(|m| m * m).sum::<f32>() + 1e-12).log10())
+                .collect()
+        })
+        .collect();
+    let changes: Vec<f32> = band_levels
+        .windows(2)
+        .map(|pair| {
+            let sum: f32 = pair[0].iter().zip(&pair[1]).map(|(a, b)| (a - b).abs()).sum();
+            sum / pair[0].len() as f32
+        })
+        .collect();
+    if changes.is_empty() {
+        return 0.0;
+    }
+    changes.iter().sum::<f32>() / changes.len() as f32
+}
+
+/// Finds noise on its own in `samples` and learns the profile from it.
+/// The region used is in the profile's `region`.
+pub fn learn_noise_profile_auto(samples: &[f32], sample_rate: u32) -> Result<NoiseProfile, NoNoiseFound> {
+    let window = ((sample_rate as f32 * LEVEL_WINDOW_SECONDS) as usize).max(1);
+    let min_windows = (MIN_NOISE_REGION_SECONDS / LEVEL_WINDOW_SECONDS).ceil() as usize;
+    let max_windows = (MAX_NOISE_REGION_SECONDS / LEVEL_WINDOW_SECONDS) as usize;
+    let energies: Vec<f32> = samples
+        .chunks_exact(window)
+        .map(|chunk| chunk.iter().map(|s| s * s).sum::<f32>() / window as f32)
+        .collect();
+    if energies.len() < min_windows {
+        return Err(NoNoiseFound::TooShort);
+    }
+    let levels: Vec<f32> = energies.iter().map(|&e| linear_to_db(e.sqrt())).collect();
+
+    let mut sorted: Vec<f32> = levels.iter().copied().filter(|&l| l > DIGITAL_SILENCE_DBFS).collect();
+    if sorted.is_empty() {
+        return Err(NoNoiseFound::NoQuietRegion);
+    }
+    sorted.sort_by(f32::total_cmp);
+    let floor = sorted[((sorted.len() - 1) as f32 * NOISE_FLOOR_PERCENTILE) as usize];
+    let usable = |w: usize| levels[w] > DIGITAL_SILENCE_DBFS && levels[w] <= floor + VAD_THRESHOLD_DB;
+
+    // Quietest `min_windows` of usable windows in a row.
+    let mut best: Option<(usize, f32)> = None;
+    let mut run_start = 0;
+    let mut sum = 0.0;
+    for w in 0..levels.len() {
+        if !usable(w) {
+            run_start = w + 1;
+            sum = 0.0;
+            continue;
+        }
+        sum += energies[w];
+        if w + 1 - run_start > min_windows {
+            sum -= energies[w - min_windows];
+        }
+        if w + 1 - run_start >= min_windows && best.is_none_or(|(_, lowest)| sum < lowest) {
+            best = Some((w + 1 - min_windows, sum));
+        }
+    }
+    let (start, sum) = best.ok_or(NoNoiseFound::NoQuietRegion)?;
+
+    // Grow it over neighbours at about the same level.
+    let limit = linear_to_db((sum / min_windows as f32).sqrt()) + REGION_MARGIN_DB;
+    let grows = |w: usize| usable(w) && levels[w] <= limit;
+    let (mut first, mut end) = (start, start + min_windows);
+    while end - first < max_windows {
+        if end < levels.len() && grows(end) {
+            end += 1;
+        } else if first > 0 && grows(first - 1) {
+            first -= 1;
+        } else {
+            break;
+        }
+    }
+
+    let region = first * window..end * window;
+    let flux_db = spectral_flux_db(&samples[region.clone()], sample_rate);
+    if flux_db > MAX_NOISE_FLUX_DB {
+        return Err(NoNoiseFound::NotStationary { region, flux_db });
+    }
+    let mut profile = learn_noise_profile(&samples[region.clone()], sample_rate);
+    profile.region = Some(region);
+    Ok(profile)
+}
+
+/// Removes the noise of `profile` from `samples`, by up to `reduction_db`
+/// per bin. The profile should come from audio at the same sample rate.
+pub fn denoise(samples: &[f32], profile: &NoiseProfile, reduction_db: f32) -> Vec<f32> {
+    let window = hann_window(profile.fft_size);
+    let hop = profile.fft_size / HOP_DIVISOR;
+    let floor = db_to_linear(-reduction_db.abs());
+
+    let mut spectrum = stft(samples, &window, hop);
+    let mut previous = vec![1.0f32; profile.magnitudes.len()];
+    for frame in spectrum.iter_mut() {
+        for ((bin, &noise), previous) in frame.iter_mut().zip(&profile.magnitudes).zip(previous.iter_mut()) {
+            let magnitude = bin.norm();
+            let gain = if magnitude > 0.0 { (1.0 - OVER_SUBTRACTION * noise / magnitude).max(floor) } else { floor };
+            // Averaged with the last frame against isolated bins flickering
+            // on and off ("musical noise").
+            let smoothed = 0.5 * (gain + *previous);
+            *previous = gain;
+            *bin *= smoothed;
+        }
+    }
+    istft(&spectrum, &window, hop, samples.len()).0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const RATE: u32 = 16_000;
+
+    fn noise(seed: u32, len: usize, amplitude: f32) -> Vec<f32> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                amplitude * 2.0 * ((state >> 8) as f32 / (1u32 << 24) as f32 - 0.5)
+            })
+            .collect()
+    }
+
+    // Syllables of 150 ms with a few harmonics of a moving pitch, 50 ms
+    // apart, from `start` on.
+    fn speech_like(len: usize, start: usize) -> Vec<f32> {
+        let syllable = RATE as usize * 150 / 1000;
+        let period = RATE as usize * 200 / 1000;
+        (0..len)
+            .map(|i| {
+                if i < start || (i - start) % period >= syllable {
+                    return 0.0;
+                }
+                let n = (i - start) / period;
+                let pitch = 140.0 + 40.0 * (n % 5) as f32;
+                let t = i as f32 / RATE as f32;
+                let envelope = (PI * ((i - start) % period) as f32 / syllable as f32).sin();
+                let voice: f32 = (1..=6).map(|h| (2.0 * PI * pitch * h as f32 * t).sin() / h as f32).sum();
+                0.2 * envelope * voice
+            })
+            .collect()
+    }
+
+    fn energy(samples: &[f32]) -> f64 {
+        samples.iter().map(|&s| s as f64 * s as f64).sum()
+    }
+
+    fn snr_db(clean: &[f32], signal: &[f32]) -> f64 {
+        let error: Vec<f32> = signal.iter().zip(clean).map(|(s, c)| s - c).collect();
+        10.0 * (energy(clean) / energy(&error)).log10()
+    }
+
+    #[test]
+    fn the_region_is_found_in_the_noise_only_lead_in() {
+        // A second of noise, then three of speech over it.
+        let len = 4 * RATE as usize;
+        let clean = speech_like(len, RATE as usize);
+        let noisy: Vec<f32> = clean.iter().zip(noise(1, len, 0.01)).map(|(c, n)| c + n).collect();
+        let profile = learn_noise_profile_auto(&noisy, RATE).unwrap();
+        let region = profile.region.clone().unwrap();
+        let min_len = (MIN_NOISE_REGION_SECONDS * RATE as f32) as usize;
+        assert!(region.end <= RATE as usize && region.len() >= min_len, "{:?}", region);
+        assert_eq!(profile.magnitudes, learn_noise_profile(&noisy[region], RATE).magnitudes);
+
+        let before = snr_db(&clean, &noisy);
+        let after = snr_db(&clean, &denoise(&noisy, &profile, DEFAULT_REDUCTION_DB));
+        assert!(after - before > 6.0, "{:.1} dB to {:.1} dB", before, after);
+    }
+
+    #[test]
+    fn steady_noise_is_accepted_and_capped_in_length() {
+        let samples = noise(2, 8 * RATE as usize, 0.05);
+        let flux = spectral_flux_db(&samples, RATE);
+        assert!(flux < MAX_NOISE_FLUX_DB, "{} dB", flux);
+        let region = learn_noise_profile_auto(&samples, RATE).unwrap().region.unwrap();
+        assert_eq!(region.len(), (MAX_NOISE_REGION_SECONDS * RATE as f32) as usize, "{:?}", region);
+    }
+
+    #[test]
+    fn music_and_silence_are_refused() {
+        assert_eq!(learn_noise_profile_auto(&noise(3, RATE as usize / 4, 0.05), RATE), Err(NoNoiseFound::TooShort));
+        assert_eq!(learn_noise_profile_auto(&vec![0.0; 2 * RATE as usize], RATE), Err(NoNoiseFound::NoQuietRegion));
+
+        // A new note every 60 ms at a steady level.
+        let note = RATE as usize * 60 / 1000;
+        let music: Vec<f32> = (0..4 * RATE as usize)
+            .map(|i| {
+                let frequency = [262.0, 330.0, 392.0, 523.0, 659.0, 784.0, 1047.0][(i / note) % 7];
+                0.3 * (2.0 * PI * frequency * i as f32 / RATE as f32).sin()
+            })
+            .collect();
+        match learn_noise_profile_auto(&music, RATE) {
+            Err(NoNoiseFound::NotStationary { region, flux_db }) => {
+                assert!(region.len() >= (MIN_NOISE_REGION_SECONDS * RATE as f32) as usize, "{:?}", region);
+                assert!(flux_db > 2.0 * MAX_NOISE_FLUX_DB, "{} dB", flux_db);
+            }
+            other => panic!("expected music to be refused, got {:?}", other.map(|p| p.region)),
+        }
+    }
+}
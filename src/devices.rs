@@ -1,37 +1,1625 @@
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
 use cpal::traits::{DeviceTrait, HostTrait};
-use cpal::{Device, DevicesError};
+use cpal::{
+    BufferSize, Device, DeviceNameError, DevicesError, Host, HostId, HostUnavailable, SampleFormat, StreamConfig,
+    SupportedBufferSize, SupportedStreamConfig, SupportedStreamConfigRange, SupportedStreamConfigsError,
+};
 
-pub fn print_devices() {
-    let host = cpal::default_host();
+use crate::input::{negotiate_input_config, DefaultConfig, StreamRequest};
+use crate::json::{self, check_schema_version, Object, Value, SCHEMA_VERSION};
+use crate::toml::{self, Table};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Input,
+    Output,
+}
 
-    println!("Input Devices:");
-    match host.input_devices() {
-        Ok(devices) => {
-            for device in devices {
-                println!("  Input Device: {}", device.name().unwrap_or_else(|_| "Unknown device".to_string()));
+impl fmt::Display for Direction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Direction::Input => write!(f, "input"),
+            Direction::Output => write!(f, "output"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum DeviceError {
+    /// The host failed to list its devices.
+    Enumeration(DevicesError),
+    NameUnavailable(DeviceNameError),
+    NoDefaultDevice(Direction),
+    /// There is nothing to pick from.
+    NoDevices(Direction),
+    /// The user gave no usable answer to the device picker.
+    NoChoice(String),
+    NoMatch { query: String },
+    /// More than one device matched and none is named exactly `query`.
+    Ambiguous { query: String, matches: Vec<String> },
+    /// The device couldn't list its configs, or none of them will do.
+    UnsupportedConfig(String),
+    /// No host by that name is compiled in on this platform.
+    UnknownHost { name: String, available: Vec<String> },
+    /// Known, but couldn't be started (e.g. no JACK server running).
+    HostUnavailable { name: String, error: HostUnavailable },
+    /// Reading or writing a saved `DeviceSelector`, or talking to the user
+    /// in the device picker.
+    Io(io::Error),
+    /// A saved `DeviceSelector` that doesn't parse.
+    InvalidSelector(String),
+    /// The host can't do what was asked of it (e.g. loopback capture).
+    Unsupported(String),
+}
+
+impl fmt::Display for DeviceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceError::Enumeration(e) => write!(f, "Failed to list devices: {}", e),
+            DeviceError::NameUnavailable(e) => write!(f, "Device name unavailable: {}", e),
+            DeviceError::NoDefaultDevice(direction) => write!(f, "No {} device available", direction),
+            DeviceError::NoDevices(direction) => write!(f, "No {} devices to choose from", direction),
+            DeviceError::NoChoice(reason) => write!(f, "No device picked: {}", reason),
+            DeviceError::NoMatch { query } => write!(f, "No device matches \"{}\"", query),
+            DeviceError::Ambiguous { query, matches } => {
+                write!(f, "\"{}\" matches several devices: {}", query, matches.join(", "))
             }
-        },
-        Err(e) => eprintln!("Error getting input devices: {}", e),
+            DeviceError::UnsupportedConfig(reason) => write!(f, "Unsupported device config: {}", reason),
+            DeviceError::UnknownHost { name, available } => {
+                write!(f, "Unknown audio host \"{}\", available: {}", name, available.join(", "))
+            }
+            DeviceError::HostUnavailable { name, error } => write!(f, "Audio host {} is unavailable: {}", name, error),
+            DeviceError::Io(e) => write!(f, "Device I/O error: {}", e),
+            DeviceError::InvalidSelector(reason) => write!(f, "Invalid saved device: {}", reason),
+            DeviceError::Unsupported(reason) => write!(f, "Unsupported: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for DeviceError {}
+
+impl From<DevicesError> for DeviceError {
+    fn from(e: DevicesError) -> Self {
+        DeviceError::Enumeration(e)
+    }
+}
+
+impl From<io::Error> for DeviceError {
+    fn from(e: io::Error) -> Self {
+        DeviceError::Io(e)
+    }
+}
+
+impl From<DeviceNameError> for DeviceError {
+    fn from(e: DeviceNameError) -> Self {
+        DeviceError::NameUnavailable(e)
+    }
+}
+
+impl From<SupportedStreamConfigsError> for DeviceError {
+    fn from(e: SupportedStreamConfigsError) -> Self {
+        DeviceError::UnsupportedConfig(e.to_string())
+    }
+}
+
+/// Names of the hosts usable on this platform, e.g. "ALSA" and "JACK".
+pub fn available_host_names() -> Vec<String> {
+    cpal::available_hosts().iter().map(|id| id.name().to_string()).collect()
+}
+
+/// The host called `name`, ignoring case: "asio", "wasapi", "jack",
+/// "alsa", "coreaudio" and so on, as far as this platform has them.
+pub fn host_by_name(name: &str) -> Result<Host, DeviceError> {
+    let id = cpal::available_hosts()
+        .into_iter()
+        .find(|id| id.name().eq_ignore_ascii_case(name))
+        .ok_or_else(|| DeviceError::UnknownHost { name: name.to_string(), available: available_host_names() })?;
+    cpal::host_from_id(id).map_err(|error| DeviceError::HostUnavailable { name: id.name().to_string(), error })
+}
+
+// Runs `f` on `host`, or on the default host when there is none.
+fn with_host<T>(host: Option<&Host>, f: impl FnOnce(&Host) -> T) -> T {
+    match host {
+        Some(host) => f(host),
+        None => f(&cpal::default_host()),
+    }
+}
+
+/// Prints `format_devices`.
+pub fn print_devices(host: Option<&Host>) -> Result<(), DeviceError> {
+    print!("{}", format_devices(host)?);
+    Ok(())
+}
+
+/// The devices of `host`, or of the default host, as a table followed by
+/// the configs each one supports. A device that can't list its configs is
+/// shown with the reason instead.
+pub fn format_devices(host: Option<&Host>) -> Result<String, DeviceError> {
+    with_host(host, |host| {
+        let defaults = default_names(host);
+        let mut text = format_device_table(&host_device_info(host)?);
+        text.push_str("\nSupported configs:\n");
+        for device in listed_devices(host)? {
+            let info = device_info(&device, &defaults);
+            let marker = if info.is_default { '*' } else { ' ' };
+            let name = info.name.unwrap_or_else(|| "Unknown device".to_string());
+            let summaries: Vec<String> = [("in", Direction::Input), ("out", Direction::Output)]
+                .iter()
+                .filter_map(|&(label, direction)| Some(format!("{} ({})", label, default_summary(&device, direction)?)))
+                .collect();
+            if summaries.is_empty() {
+                text.push_str(&format!("{} {}\n", marker, name));
+            } else {
+                text.push_str(&format!("{} {}: {}\n", marker, name, summaries.join(", ")));
+            }
+            let listed = [("in", supported_input_configs(&device)), ("out", supported_output_configs(&device))];
+            if let [(_, Err(e)), (_, Err(_))] = &listed {
+                text.push_str(&format!("    {}\n", e));
+                continue;
+            }
+            for (direction, configs) in &listed {
+                for config in configs.iter().flatten() {
+                    text.push_str(&format!("    {:<4}{}\n", direction, config));
+                }
+            }
+            for (label, direction) in [("in", Direction::Input), ("out", Direction::Output)] {
+                let rates = supported_sample_rates(&device, direction).unwrap_or_default();
+                if !rates.is_empty() {
+                    let rates: Vec<String> = rates.iter().map(u32::to_string).collect();
+                    text.push_str(&format!("    {:<4}rates {} Hz\n", label, rates.join(", ")));
+                }
+                if let Ok(LatencyEstimate { buffer_frames: Some(frames), ms: Some(ms), .. }) =
+                    estimated_latency_ms(&device, direction)
+                {
+                    text.push_str(&format!("    {:<4}latency {:.1} ms ({} frames)\n", label, ms, frames));
+                }
+            }
+        }
+        Ok(text)
+    })
+}
+
+// "2ch @ 48000 Hz, f32" for the default config, or only the channels when
+// there is no default config.
+fn default_summary(device: &Device, direction: Direction) -> Option<String> {
+    match default_config(device, direction) {
+        Ok(config) => Some(format!("{}ch @ {} Hz, {}", config.channels(), config.sample_rate().0, config.sample_format())),
+        Err(_) => channel_count(device, direction).ok().map(|channels| format!("{}ch", channels)),
+    }
+}
+
+fn default_config(device: &Device, direction: Direction) -> Result<SupportedStreamConfig, DeviceError> {
+    let config = match direction {
+        Direction::Input => device.default_input_config(),
+        Direction::Output => device.default_output_config(),
+    };
+    config.map_err(|e| DeviceError::UnsupportedConfig(e.to_string()))
+}
+
+/// Channels of the default config, or when the device has none, the most
+/// any of its configs has.
+pub fn channel_count(device: &Device, direction: Direction) -> Result<u16, DeviceError> {
+    if let Ok(config) = default_config(device, direction) {
+        return Ok(config.channels());
+    }
+    supported_ranges(device, direction)?
+        .iter()
+        .map(|range| range.channels())
+        .max()
+        .ok_or_else(|| DeviceError::UnsupportedConfig(format!("no {} configs", direction)))
+}
+
+/// How much delay the buffer of a device's default config adds on its own,
+/// before the backend's and the converter's.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyEstimate {
+    pub sample_rate: u32,
+    /// The smallest buffer the default config allows. `None` on backends
+    /// that report the buffer size as unknown.
+    pub buffer_frames: Option<u32>,
+    /// `buffer_frames` at `sample_rate`, `None` along with it rather than
+    /// a made-up zero.
+    pub ms: Option<f32>,
+}
+
+/// The buffer latency of the default config in `direction`, from the
+/// smallest buffer it allows.
+pub fn estimated_latency_ms(device: &Device, direction: Direction) -> Result<LatencyEstimate, DeviceError> {
+    let config = default_config(device, direction)?;
+    let sample_rate = config.sample_rate().0;
+    let buffer_frames = match *config.buffer_size() {
+        SupportedBufferSize::Range { min, .. } => Some(min),
+        SupportedBufferSize::Unknown => None,
+    };
+    let ms = buffer_frames.map(|frames| frames as f32 * 1000.0 / sample_rate.max(1) as f32);
+    Ok(LatencyEstimate { sample_rate, buffer_frames, ms })
+}
+
+/// The default config of `direction` with the smallest buffer it allows,
+/// for measuring. The backend may still refuse to open it; see
+/// `stream::build_lowest_latency_output`.
+pub fn min_latency_config(device: &Device, direction: Direction) -> Result<StreamConfig, DeviceError> {
+    let default = default_config(device, direction)?;
+    let SupportedBufferSize::Range { min, .. } = *default.buffer_size() else {
+        return Err(DeviceError::UnsupportedConfig(format!("the {} buffer size range is unknown", direction)));
+    };
+    let mut config = default.config();
+    config.buffer_size = BufferSize::Fixed(min);
+    Ok(config)
+}
+
+/// One aligned row per device: name, direction, default marker, channels
+/// and default sample rate, and the hosts when they list devices of more
+/// than one. The names of default devices start with `*`.
+pub fn format_device_table(devices: &[DeviceInfo]) -> String {
+    let io = |input: bool, output: bool| match (input, output) {
+        (true, true) => "in/out".to_string(),
+        (true, false) => "in".to_string(),
+        (false, true) => "out".to_string(),
+        (false, false) => "-".to_string(),
+    };
+    let channels = |count: Option<u16>| count.map_or("-".to_string(), |c| c.to_string());
+    let mut hosts: Vec<&String> = devices.iter().flat_map(|device| &device.hosts).collect();
+    hosts.sort();
+    hosts.dedup();
+    let show_hosts = hosts.len() > 1;
+    let mut header = ["  Name", "Direction", "Default", "Channels", "Rate"].map(str::to_string).to_vec();
+    if show_hosts {
+        header.push("Hosts".to_string());
+    }
+    let rows: Vec<Vec<String>> = devices
+        .iter()
+        .map(|device| {
+            let mut row = vec![
+                format!(
+                    "{} {}",
+                    if device.is_default { '*' } else { ' ' },
+                    device.name.as_deref().unwrap_or("Unknown device")
+                ),
+                io(device.input_channels.is_some(), device.output_channels.is_some()),
+                io(device.is_default_input, device.is_default_output),
+                format!("{}/{}", channels(device.input_channels), channels(device.output_channels)),
+                device.default_sample_rate.map_or("n/a".to_string(), |rate| format!("{} Hz", rate)),
+            ];
+            if show_hosts {
+                row.push(device.hosts.join(", "));
+            }
+            row
+        })
+        .collect();
+
+    let mut widths: Vec<usize> = header.iter().map(|h| h.len()).collect();
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    let mut text = String::new();
+    for row in std::iter::once(&header).chain(&rows) {
+        let cells: Vec<String> = row.iter().zip(&widths).map(|(cell, &width)| format!("{:<width$}", cell)).collect();
+        text.push_str(cells.join("  ").trim_end());
+        text.push('\n');
+    }
+    text
+}
+
+/// One range of stream configs a device supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SupportedConfig {
+    pub min_sample_rate: u32,
+    pub max_sample_rate: u32,
+    pub channels: u16,
+    pub sample_format: SampleFormat,
+}
+
+impl From<&SupportedStreamConfigRange> for SupportedConfig {
+    fn from(range: &SupportedStreamConfigRange) -> Self {
+        Self {
+            min_sample_rate: range.min_sample_rate().0,
+            max_sample_rate: range.max_sample_rate().0,
+            channels: range.channels(),
+            sample_format: range.sample_format(),
+        }
+    }
+}
+
+impl fmt::Display for SupportedConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.min_sample_rate == self.max_sample_rate {
+            write!(f, "{} Hz", self.min_sample_rate)?;
+        } else {
+            write!(f, "{}-{} Hz", self.min_sample_rate, self.max_sample_rate)?;
+        }
+        write!(f, ", {} channels, {}", self.channels, self.sample_format)
+    }
+}
+
+pub fn supported_input_configs(device: &Device) -> Result<Vec<SupportedConfig>, DeviceError> {
+    Ok(device.supported_input_configs()?.map(|range| SupportedConfig::from(&range)).collect())
+}
+
+pub fn supported_output_configs(device: &Device) -> Result<Vec<SupportedConfig>, DeviceError> {
+    Ok(device.supported_output_configs()?.map(|range| SupportedConfig::from(&range)).collect())
+}
+
+fn supported_ranges(device: &Device, direction: Direction) -> Result<Vec<SupportedStreamConfigRange>, DeviceError> {
+    Ok(match direction {
+        Direction::Input => device.supported_input_configs()?.collect(),
+        Direction::Output => device.supported_output_configs()?.collect(),
+    })
+}
+
+// What about `config` and `format` none of `ranges` offers, checked in the
+// order channels, sample rate, sample format.
+fn config_problem(ranges: &[SupportedStreamConfigRange], config: &StreamConfig, format: SampleFormat) -> Option<String> {
+    let at_channels: Vec<&SupportedStreamConfigRange> =
+        ranges.iter().filter(|r| r.channels() == config.channels).collect();
+    if at_channels.is_empty() {
+        let mut counts: Vec<u16> = ranges.iter().map(|r| r.channels()).collect();
+        counts.sort_unstable();
+        counts.dedup();
+        return Some(format!("channels: {} not supported, the device offers {:?}", config.channels, counts));
+    }
+    let rate = config.sample_rate.0;
+    let at_rate: Vec<&&SupportedStreamConfigRange> = at_channels
+        .iter()
+        .filter(|r| (r.min_sample_rate().0..=r.max_sample_rate().0).contains(&rate))
+        .collect();
+    if at_rate.is_empty() {
+        let offered: Vec<String> =
+            at_channels.iter().map(|r| format!("{}-{} Hz", r.min_sample_rate().0, r.max_sample_rate().0)).collect();
+        return Some(format!(
+            "sample rate: {} Hz not supported with {} channels, the device offers {}",
+            rate,
+            config.channels,
+            offered.join(", ")
+        ));
+    }
+    if !at_rate.iter().any(|r| r.sample_format() == format) {
+        let offered: Vec<String> = at_rate.iter().map(|r| r.sample_format().to_string()).collect();
+        return Some(format!(
+            "sample format: {} not supported at {} Hz with {} channels, the device offers {}",
+            format,
+            rate,
+            config.channels,
+            offered.join(", ")
+        ));
+    }
+    None
+}
+
+/// Whether `device` can open a stream with `config` in `format`: one of
+/// its ranges has the channel count, includes the sample rate and uses the
+/// format.
+pub fn is_config_supported(
+    device: &Device,
+    config: &StreamConfig,
+    format: SampleFormat,
+    direction: Direction,
+) -> Result<bool, DeviceError> {
+    Ok(config_problem(&supported_ranges(device, direction)?, config, format).is_none())
+}
+
+/// Like `is_config_supported`, but an unsupported config is an
+/// `UnsupportedConfig` error naming the field that doesn't fit.
+pub fn check_config(
+    device: &Device,
+    config: &StreamConfig,
+    format: SampleFormat,
+    direction: Direction,
+) -> Result<(), DeviceError> {
+    match config_problem(&supported_ranges(device, direction)?, config, format) {
+        Some(problem) => Err(DeviceError::UnsupportedConfig(format!("{} {}", direction, problem))),
+        None => Ok(()),
+    }
+}
+
+/// Whether a fixed buffer size in `config` is within what the device
+/// reports for the channels, rate and format; `BufferSize::Default`, and
+/// ranges whose buffer sizes the backend doesn't know, pass.
+pub fn check_buffer_size(
+    device: &Device,
+    config: &StreamConfig,
+    format: SampleFormat,
+    direction: Direction,
+) -> Result<(), DeviceError> {
+    let BufferSize::Fixed(frames) = config.buffer_size else {
+        return Ok(());
+    };
+    let mut offered = Vec::new();
+    for range in supported_ranges(device, direction)? {
+        let rate = config.sample_rate.0;
+        if range.channels() != config.channels
+            || range.sample_format() != format
+            || !(range.min_sample_rate().0..=range.max_sample_rate().0).contains(&rate)
+        {
+            continue;
+        }
+        match *range.buffer_size() {
+            SupportedBufferSize::Unknown => return Ok(()),
+            SupportedBufferSize::Range { min, max } if (min..=max).contains(&frames) => return Ok(()),
+            SupportedBufferSize::Range { min, max } => offered.push(format!("{}-{} frames", min, max)),
+        }
+    }
+    Err(DeviceError::UnsupportedConfig(format!(
+        "{} buffer size: {} frames not supported, the device offers {}",
+        direction,
+        frames,
+        offered.join(", ")
+    )))
+}
+
+/// The rates `supported_sample_rates` looks for.
+pub const STANDARD_SAMPLE_RATES: [u32; 10] =
+    [8000, 11025, 16000, 22050, 44100, 48000, 88200, 96000, 176400, 192000];
+
+/// Every standard rate inside one of the device's ranges, sorted. A range
+/// of just one rate counts with that rate even if it isn't standard.
+pub fn supported_sample_rates(device: &Device, direction: Direction) -> Result<Vec<u32>, DeviceError> {
+    let mut rates = Vec::new();
+    for range in supported_ranges(device, direction)? {
+        let (min, max) = (range.min_sample_rate().0, range.max_sample_rate().0);
+        if min == max {
+            rates.push(min);
+        } else {
+            rates.extend(STANDARD_SAMPLE_RATES.iter().filter(|&&rate| (min..=max).contains(&rate)));
+        }
+    }
+    rates.sort_unstable();
+    rates.dedup();
+    Ok(rates)
+}
+
+/// Sample formats of the device's ranges in `direction`, each once, in
+/// the order the device lists them. Empty when it can't list its configs.
+pub fn supported_sample_formats(device: &Device, direction: Direction) -> Vec<SampleFormat> {
+    let mut formats = Vec::new();
+    for range in supported_ranges(device, direction).unwrap_or_default() {
+        if !formats.contains(&range.sample_format()) {
+            formats.push(range.sample_format());
+        }
+    }
+    formats
+}
+
+/// The first of `preference` the device supports in `direction`, e.g.
+/// `&[SampleFormat::F32, SampleFormat::I16]` for float where there is
+/// float and 16-bit otherwise.
+pub fn preferred_format(device: &Device, direction: Direction, preference: &[SampleFormat]) -> Option<SampleFormat> {
+    let supported = supported_sample_formats(device, direction);
+    preference.iter().copied().find(|format| supported.contains(format))
+}
+
+/// The config `negotiate_config` settled on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MatchedConfig {
+    pub config: StreamConfig,
+    pub sample_format: SampleFormat,
+    /// The device runs at another rate than the one asked for.
+    pub needs_resampling: bool,
+    /// What couldn't be matched and what was used instead.
+    pub fallbacks: Vec<String>,
+}
+
+/// The supported config of `device` that comes closest to `desired_rate`
+/// and `desired_channels`, e.g. to play a WAV file without converting it.
+/// An exact rate wins, in f32 if offered, else another format at that rate,
+/// else the nearest rate; see `input::negotiate_input_config` for the
+/// details, which apply to output configs the same way.
+pub fn negotiate_config(
+    device: &Device,
+    desired_rate: u32,
+    desired_channels: u16,
+    direction: Direction,
+) -> Result<MatchedConfig, DeviceError> {
+    let ranges = supported_ranges(device, direction)?;
+    let default = default_config(device, direction).ok().map(|config| DefaultConfig { sample_rate: config.sample_rate().0, channels: config.channels() });
+    let request = StreamRequest {
+        sample_rate: Some(desired_rate),
+        channels: Some(desired_channels),
+        ..StreamRequest::default()
+    };
+    let negotiated =
+        negotiate_input_config(&ranges, default, &request).map_err(|e| DeviceError::UnsupportedConfig(e.to_string()))?;
+    Ok(MatchedConfig {
+        config: negotiated.stream_config(),
+        sample_format: negotiated.sample_format,
+        needs_resampling: negotiated.sample_rate != desired_rate,
+        fallbacks: negotiated.fallbacks,
+    })
+}
+
+/// What enumerating, filtering and finding devices needs of a host, so
+/// those can run against something other than the audio system.
+/// `CpalHost` is the real one.
+pub trait HostLike {
+    type Device: DeviceLike;
+
+    fn input_devices(&self) -> Result<Vec<Self::Device>, DeviceError>;
+    fn output_devices(&self) -> Result<Vec<Self::Device>, DeviceError>;
+    fn default_input_device(&self) -> Option<Self::Device>;
+    fn default_output_device(&self) -> Option<Self::Device>;
+
+    fn devices(&self, direction: Direction) -> Result<Vec<Self::Device>, DeviceError> {
+        match direction {
+            Direction::Input => self.input_devices(),
+            Direction::Output => self.output_devices(),
+        }
     }
 
-    println!("\nOutput Devices:");
-    match host.output_devices() {
-        Ok(devices) => {
-            for device in devices {
-                println!("  Output Device: {}", device.name().unwrap_or_else(|_| "Unknown device".to_string()));
+    fn default_device(&self, direction: Direction) -> Result<Self::Device, DeviceError> {
+        let device = match direction {
+            Direction::Input => self.default_input_device(),
+            Direction::Output => self.default_output_device(),
+        };
+        device.ok_or(DeviceError::NoDefaultDevice(direction))
+    }
+}
+
+/// The device half of `HostLike`.
+pub trait DeviceLike {
+    fn device_name(&self) -> Result<String, DeviceError>;
+    fn supported_configs(&self, direction: Direction) -> Result<Vec<SupportedConfig>, DeviceError>;
+}
+
+/// A `cpal::Host` as a `HostLike`.
+pub struct CpalHost<'a>(pub &'a Host);
+
+impl HostLike for CpalHost<'_> {
+    type Device = Device;
+
+    fn input_devices(&self) -> Result<Vec<Device>, DeviceError> {
+        Ok(self.0.input_devices()?.collect())
+    }
+
+    fn output_devices(&self) -> Result<Vec<Device>, DeviceError> {
+        Ok(self.0.output_devices()?.collect())
+    }
+
+    fn default_input_device(&self) -> Option<Device> {
+        self.0.default_input_device()
+    }
+
+    fn default_output_device(&self) -> Option<Device> {
+        self.0.default_output_device()
+    }
+}
+
+impl DeviceLike for Device {
+    fn device_name(&self) -> Result<String, DeviceError> {
+        Ok(self.name()?)
+    }
+
+    fn supported_configs(&self, direction: Direction) -> Result<Vec<SupportedConfig>, DeviceError> {
+        Ok(supported_ranges(self, direction)?.iter().map(SupportedConfig::from).collect())
+    }
+}
+
+pub fn input_devices(host: Option<&Host>) -> Result<Vec<Device>, DeviceError> {
+    with_host(host, |host| CpalHost(host).input_devices())
+}
+
+pub fn output_devices(host: Option<&Host>) -> Result<Vec<Device>, DeviceError> {
+    with_host(host, |host| CpalHost(host).output_devices())
+}
+
+/// Devices that passed a filter, and why others couldn't be checked.
+pub struct FilteredDevices<D = Device> {
+    pub devices: Vec<D>,
+    /// One line per device skipped because it failed to list its configs.
+    pub warnings: Vec<String>,
+}
+
+/// The `host`'s devices in `direction` with a config of at least `min`
+/// channels.
+pub fn devices_with_channels<H: HostLike>(
+    host: &H,
+    direction: Direction,
+    min: u16,
+) -> Result<FilteredDevices<H::Device>, DeviceError> {
+    let mut filtered = FilteredDevices { devices: Vec::new(), warnings: Vec::new() };
+    for device in host.devices(direction)? {
+        match device.supported_configs(direction) {
+            Ok(configs) => {
+                if configs.iter().any(|config| config.channels >= min) {
+                    filtered.devices.push(device);
+                }
             }
-        },
-        Err(e) => eprintln!("Error getting output devices: {}", e),
+            Err(e) => filtered.warnings.push(format!(
+                "Skipped {}: {}",
+                device.device_name().unwrap_or_else(|_| "Unknown device".to_string()),
+                e
+            )),
+        }
+    }
+    Ok(filtered)
+}
+
+/// Input devices with at least `min` channels in one of their configs.
+pub fn input_devices_with_channels(host: Option<&Host>, min: u16) -> Result<FilteredDevices, DeviceError> {
+    with_host(host, |host| devices_with_channels(&CpalHost(host), Direction::Input, min))
+}
+
+/// Output devices with at least `min` channels in one of their configs.
+pub fn output_devices_with_channels(host: Option<&Host>, min: u16) -> Result<FilteredDevices, DeviceError> {
+    with_host(host, |host| devices_with_channels(&CpalHost(host), Direction::Output, min))
+}
+
+pub fn default_input_device() -> Result<Device, DeviceError> {
+    CpalHost(&cpal::default_host()).default_device(Direction::Input)
+}
+
+pub fn default_output_device() -> Result<Device, DeviceError> {
+    CpalHost(&cpal::default_host()).default_device(Direction::Output)
+}
+
+pub fn device_name(device: &Device) -> Result<String, DeviceError> {
+    device.device_name()
+}
+
+/// The one device of the `host` in `direction` whose name contains
+/// `query`, ignoring case. A name that equals it settles a tie. Devices
+/// without a readable name are skipped.
+pub fn find_device<H: HostLike>(host: &H, direction: Direction, query: &str) -> Result<H::Device, DeviceError> {
+    let needle = query.to_lowercase();
+    let mut matches: Vec<(String, H::Device)> = host
+        .devices(direction)?
+        .into_iter()
+        .filter_map(|device| Some((device.device_name().ok()?, device)))
+        .filter(|(name, _)| name.to_lowercase().contains(&needle))
+        .collect();
+    if let Some(exact) = matches.iter().position(|(name, _)| name.to_lowercase() == needle) {
+        return Ok(matches.swap_remove(exact).1);
+    }
+    match matches.len() {
+        0 => Err(DeviceError::NoMatch { query: query.to_string() }),
+        1 => Ok(matches.remove(0).1),
+        _ => Err(DeviceError::Ambiguous {
+            query: query.to_string(),
+            matches: matches.into_iter().map(|(name, _)| name).collect(),
+        }),
+    }
+}
+
+/// Input device whose name contains `query`, ignoring case, e.g.
+/// "scarlett" for "Scarlett 2i2 USB".
+pub fn find_input_device(query: &str) -> Result<Device, DeviceError> {
+    find_device(&CpalHost(&cpal::default_host()), Direction::Input, query)
+}
+
+/// Output device whose name contains `query`, ignoring case, e.g.
+/// "blackhole" for "BlackHole 2ch".
+pub fn find_output_device(query: &str) -> Result<Device, DeviceError> {
+    find_device(&CpalHost(&cpal::default_host()), Direction::Output, query)
+}
+
+
+/// Input device matching the given name (see `find_input_device`), or
+/// the default input device (with a warning) when no name is given or no
+/// single device matches.
+pub fn input_device_or_default(preferred: Option<&str>) -> Result<Device, DeviceError> {
+    if let Some(name) = preferred {
+        match find_input_device(name) {
+            Ok(device) => return Ok(device),
+            Err(e) => eprintln!("Warning: {}, using the default input device", e),
+        }
     }
+    default_input_device()
 }
 
-pub fn input_devices() -> Result<Vec<Device>, DevicesError> {
+/// Output device matching the given name (see `find_output_device`), or
+/// the default output device (with a warning) when no name is given or no
+/// single device matches.
+pub fn output_device_or_default(preferred: Option<&str>) -> Result<Device, DeviceError> {
+    if let Some(name) = preferred {
+        match find_output_device(name) {
+            Ok(device) => return Ok(device),
+            Err(e) => eprintln!("Warning: {}, using the default output device", e),
+        }
+    }
+    default_output_device()
+}
+
+/// The device of `direction` matching `preferred`, as with
+/// `input_device_or_default` and `output_device_or_default`.
+pub fn device_or_default(direction: Direction, preferred: Option<&str>) -> Result<Device, DeviceError> {
+    match direction {
+        Direction::Input => input_device_or_default(preferred),
+        Direction::Output => output_device_or_default(preferred),
+    }
+}
+
+/// Variables naming the input and output device, matched like the names
+/// in the config file.
+pub const INPUT_DEVICE_ENV: &str = "CPAL_PLAYBOOK_INPUT";
+pub const OUTPUT_DEVICE_ENV: &str = "CPAL_PLAYBOOK_OUTPUT";
+
+/// The device name set in the environment for `direction`, if any.
+pub fn device_query_from_env(direction: Direction) -> Option<String> {
+    let name = match direction {
+        Direction::Input => INPUT_DEVICE_ENV,
+        Direction::Output => OUTPUT_DEVICE_ENV,
+    };
+    std::env::var(name).ok().filter(|query| !query.trim().is_empty())
+}
+
+/// The device named by `CPAL_PLAYBOOK_INPUT` or `CPAL_PLAYBOOK_OUTPUT`, or
+/// the default device when the variable is unset or matches no single
+/// device.
+pub fn device_from_env(direction: Direction) -> Result<Device, DeviceError> {
+    device_or_default(direction, device_query_from_env(direction).as_deref())
+}
+
+// WASAPI is the only host that lets an output device be opened as an
+// input, and it only exists on Windows.
+#[cfg(target_os = "windows")]
+fn supports_loopback(host: HostId) -> bool {
+    host == HostId::Wasapi
+}
+
+#[cfg(not(target_os = "windows"))]
+fn supports_loopback(_host: HostId) -> bool {
+    false
+}
+
+/// The default output device, to capture what it is playing with
+/// `stream::make_loopback_stream`. Only the WASAPI host supports this.
+pub fn loopback_device() -> Result<Device, DeviceError> {
     let host = cpal::default_host();
+    if !supports_loopback(host.id()) {
+        return Err(DeviceError::Unsupported(format!(
+            "loopback capture needs the WASAPI host on Windows, not {}",
+            host.id().name()
+        )));
+    }
+    CpalHost(&host).default_device(Direction::Output)
+}
 
-    match host.input_devices() {
-        Ok(devices) => Ok(devices.collect()),
-        Err(e) => Err(e),
+/// Tries the device picker gives before it stops asking.
+const PICK_ATTEMPTS: usize = 3;
+
+/// Shows the `direction` devices of the default host as a numbered table
+/// (the one `format_devices` starts with) and returns the one whose number
+/// is typed on stdin. Asks again on anything else, up to three times.
+pub fn pick_device_interactive(direction: Direction) -> Result<Device, DeviceError> {
+    let host = cpal::default_host();
+    let mut devices = CpalHost(&host).devices(direction)?;
+    if devices.is_empty() {
+        return Err(DeviceError::NoDevices(direction));
+    }
+    let defaults = default_names(&host);
+    let info: Vec<DeviceInfo> = devices.iter().map(|device| device_info(device, &defaults)).collect();
+    let width = devices.len().to_string().len();
+    for (i, line) in format_device_table(&info).lines().enumerate() {
+        match i {
+            0 => println!("{:>width$}  {}", "", line),
+            _ => println!("{:>width$}  {}", i, line),
+        }
+    }
+
+    let stdin = io::stdin();
+    let mut line = String::new();
+    for _ in 0..PICK_ATTEMPTS {
+        print!("{} device [1-{}]: ", direction, devices.len());
+        io::stdout().flush()?;
+        line.clear();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Err(DeviceError::NoChoice("stdin closed".to_string()));
+        }
+        match line.trim().parse::<usize>() {
+            Ok(number) if (1..=devices.len()).contains(&number) => return Ok(devices.swap_remove(number - 1)),
+            _ => println!("Type a number from 1 to {}", devices.len()),
+        }
+    }
+    Err(DeviceError::NoChoice(format!("no valid number in {} tries", PICK_ATTEMPTS)))
+}
+
+/// A device remembered by host and name rather than by its position in
+/// the list, which changes between boots. Saved as a small TOML file:
+///
+/// ```text
+/// direction = "input"
+/// host = "WASAPI"
+/// name = "Microphone (Scarlett 2i2 USB)"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceSelector {
+    pub host: String,
+    pub name: String,
+    pub direction: Direction,
+}
+
+impl DeviceSelector {
+    pub fn from_device(host: &Host, device: &Device, direction: Direction) -> Result<Self, DeviceError> {
+        Ok(Self { host: host.id().name().to_string(), name: device.name()?, direction })
+    }
+
+    pub fn to_toml(&self) -> String {
+        let mut table = Table::new();
+        table.insert("direction".to_string(), toml::Value::String(self.direction.to_string()));
+        table.insert("host".to_string(), toml::Value::String(self.host.clone()));
+        table.insert("name".to_string(), toml::Value::String(self.name.clone()));
+        toml::to_string(&table)
+    }
+
+    pub fn from_toml(text: &str) -> Result<Self, DeviceError> {
+        let table = toml::parse(text).map_err(|e| DeviceError::InvalidSelector(e.to_string()))?;
+        let string = |key: &str| {
+            table
+                .get(key)
+                .and_then(toml::Value::as_str)
+                .map(str::to_string)
+                .ok_or_else(|| DeviceError::InvalidSelector(format!("'{}' must be a string", key)))
+        };
+        let direction = match string("direction")?.as_str() {
+            "input" => Direction::Input,
+            "output" => Direction::Output,
+            other => return Err(DeviceError::InvalidSelector(format!("unknown direction '{}'", other))),
+        };
+        Ok(Self { host: string("host")?, name: string("name")?, direction })
     }
 
+    pub fn save(&self, path: &Path) -> Result<(), DeviceError> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, self.to_toml())?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self, DeviceError> {
+        Self::from_toml(&fs::read_to_string(path)?)
+    }
+
+    /// The saved device on the current system. When its host or the device
+    /// itself is gone, the default host or device is used with a warning.
+    pub fn resolve(&self) -> Result<Device, DeviceError> {
+        let host = host_by_name(&self.host).unwrap_or_else(|e| {
+            eprintln!("Warning: {}, using the default host", e);
+            cpal::default_host()
+        });
+        let mut devices = match self.direction {
+            Direction::Input => host.input_devices()?,
+            Direction::Output => host.output_devices()?,
+        };
+        if let Some(device) = devices.find(|d| d.name().is_ok_and(|name| name == self.name)) {
+            return Ok(device);
+        }
+        eprintln!(
+            "Warning: saved {} device \"{}\" not found, using the default {} device",
+            self.direction, self.name, self.direction
+        );
+        let default = match self.direction {
+            Direction::Input => host.default_input_device(),
+            Direction::Output => host.default_output_device(),
+        };
+        default.ok_or(DeviceError::NoDefaultDevice(self.direction))
+    }
 }
 
+/// One entry of the `devices` listing. Field names of `to_json` are part
+/// of the `--json` contract (see `json::SCHEMA_VERSION`); values a device
+/// doesn't report are `null`, so a device that fails to give its name or
+/// configs is still listed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct DeviceInfo {
+    pub name: Option<String>,
+    /// Default input or default output.
+    pub is_default: bool,
+    pub is_default_input: bool,
+    pub is_default_output: bool,
+    /// Channels of the default input config, `None` for output-only devices.
+    pub input_channels: Option<u16>,
+    pub output_channels: Option<u16>,
+    /// Sample rate of the default config (input if there is one, else output).
+    pub default_sample_rate: Option<u32>,
+    /// Every format of the supported input and output configs, e.g. "f32",
+    /// `None` when the device couldn't list them.
+    pub sample_formats: Option<Vec<String>>,
+    /// Hosts listing the device, more than one after `deduplicate`.
+    pub hosts: Vec<String>,
+}
+
+impl DeviceInfo {
+    pub fn to_json(&self) -> Value {
+        let mut object = Object::new();
+        object.insert("name".into(), Value::from_option(self.name.as_deref()));
+        object.insert("is_default".into(), self.is_default.into());
+        object.insert("is_default_input".into(), self.is_default_input.into());
+        object.insert("is_default_output".into(), self.is_default_output.into());
+        object.insert("input_channels".into(), Value::from_option(self.input_channels));
+        object.insert("output_channels".into(), Value::from_option(self.output_channels));
+        object.insert("default_sample_rate".into(), Value::from_option(self.default_sample_rate));
+        let formats = self.sample_formats.as_ref().map(|formats| {
+            Value::from(formats.iter().map(|format| Value::from(format.as_str())).collect::<Vec<_>>())
+        });
+        object.insert("sample_formats".into(), formats.unwrap_or(Value::Null));
+        let hosts: Vec<Value> = self.hosts.iter().map(|host| Value::from(host.as_str())).collect();
+        object.insert("hosts".into(), hosts.into());
+        Value::Object(object)
+    }
+
+    /// Reads the object written by `to_json`, ignoring unknown fields.
+    pub fn from_json(value: &Value) -> Result<DeviceInfo, String> {
+        let flag = |key: &str| value.require(key)?.as_bool().ok_or(format!("\"{}\" is not a boolean", key));
+        let optional = |key: &str| match value.require(key)? {
+            Value::Null => Ok(None),
+            v => v.as_u64().map(Some).ok_or(format!("\"{}\" is not an integer", key)),
+        };
+        let name = match value.require("name")? {
+            Value::Null => None,
+            v => Some(v.as_str().ok_or("\"name\" is not a string")?.to_string()),
+        };
+        let sample_formats = match value.require("sample_formats")? {
+            Value::Null => None,
+            v => Some(
+                v.as_array()
+                    .ok_or("\"sample_formats\" is not an array")?
+                    .iter()
+                    .map(|format| format.as_str().map(str::to_string).ok_or("\"sample_formats\" holds a non-string"))
+                    .collect::<Result<Vec<_>, _>>()?,
+            ),
+        };
+
+        // Missing from documents written before it was added.
+        let hosts = match value.get("hosts") {
+            None => Vec::new(),
+            Some(v) => v
+                .as_array()
+                .ok_or("\"hosts\" is not an array")?
+                .iter()
+                .map(|host| host.as_str().map(str::to_string).ok_or("\"hosts\" holds a non-string"))
+                .collect::<Result<Vec<_>, _>>()?,
+        };
+
+        Ok(DeviceInfo {
+            name,
+            is_default: flag("is_default")?,
+            is_default_input: flag("is_default_input")?,
+            is_default_output: flag("is_default_output")?,
+            input_channels: optional("input_channels")?.map(|c| c as u16),
+            output_channels: optional("output_channels")?.map(|c| c as u16),
+            default_sample_rate: optional("default_sample_rate")?.map(|r| r as u32),
+            sample_formats,
+            hosts,
+        })
+    }
+}
+
+// Formats of the supported input and output configs, each once.
+fn sample_formats(device: &Device) -> Option<Vec<String>> {
+    let input = supported_input_configs(device);
+    let output = supported_output_configs(device);
+    if input.is_err() && output.is_err() {
+        return None;
+    }
+    let mut formats: Vec<String> = Vec::new();
+    for config in input.unwrap_or_default().iter().chain(&output.unwrap_or_default()) {
+        let format = config.sample_format.to_string();
+        if !formats.contains(&format) {
+            formats.push(format);
+        }
+    }
+    Some(formats)
+}
+
+/// Describes every input and output device of `host`, or of the default
+/// host.
+pub fn collect_device_info(host: Option<&Host>) -> Result<Vec<DeviceInfo>, DeviceError> {
+    with_host(host, host_device_info)
+}
+
+fn host_device_info(host: &Host) -> Result<Vec<DeviceInfo>, DeviceError> {
+    let defaults = default_names(host);
+    let host_name = host.id().name().to_string();
+    Ok(listed_devices(host)?
+        .iter()
+        .map(|device| DeviceInfo { hosts: vec![host_name.clone()], ..device_info(device, &defaults) })
+        .collect())
+}
+
+/// `collect_device_info` of every host available on this platform, one
+/// after the other. Hosts that can't be started or listed are left out
+/// with a warning.
+pub fn collect_all_device_info() -> Vec<DeviceInfo> {
+    let mut all = Vec::new();
+    for id in cpal::available_hosts() {
+        let host = match cpal::host_from_id(id) {
+            Ok(host) => host,
+            Err(error) => {
+                eprintln!("Warning: {}", DeviceError::HostUnavailable { name: id.name().to_string(), error });
+                continue;
+            }
+        };
+        match host_device_info(&host) {
+            Ok(info) => all.extend(info),
+            Err(e) => eprintln!("Warning: skipped {}: {}", id.name(), e),
+        }
+    }
+    all
+}
+
+// Case and runs of whitespace don't tell devices apart.
+fn normalized_name(name: &str) -> String {
+    name.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+/// Merges the entries of one device listed by several hosts, i.e. with
+/// the same name ignoring case and whitespace, into the first of them: it
+/// keeps the first name, the most channels, the first known sample rate
+/// and every format and host. Entries without a name are kept as they are.
+pub fn deduplicate(infos: Vec<DeviceInfo>) -> Vec<DeviceInfo> {
+    let mut merged: Vec<DeviceInfo> = Vec::new();
+    for info in infos {
+        let key = info.name.as_deref().map(normalized_name);
+        let existing = key.as_ref().and_then(|key| {
+            merged.iter_mut().find(|other| other.name.as_deref().map(normalized_name).as_ref() == Some(key))
+        });
+        let Some(existing) = existing else {
+            merged.push(info);
+            continue;
+        };
+        existing.is_default |= info.is_default;
+        existing.is_default_input |= info.is_default_input;
+        existing.is_default_output |= info.is_default_output;
+        existing.input_channels = existing.input_channels.max(info.input_channels);
+        existing.output_channels = existing.output_channels.max(info.output_channels);
+        existing.default_sample_rate = existing.default_sample_rate.or(info.default_sample_rate);
+        if let Some(formats) = info.sample_formats {
+            let known = existing.sample_formats.get_or_insert_with(Vec::new);
+            for format in formats {
+                if !known.contains(&format) {
+                    known.push(format);
+                }
+            }
+        }
+        for host in info.hosts {
+            if !existing.hosts.contains(&host) {
+                existing.hosts.push(host);
+            }
+        }
+    }
+    merged
+}
+
+// All devices of `host`, and after them the default devices the host
+// leaves out of its list (PulseAudio's "default", for one).
+fn listed_devices(host: &Host) -> Result<Vec<Device>, DeviceError> {
+    let mut devices: Vec<Device> = host.devices()?.collect();
+    let mut names: Vec<Option<String>> = devices.iter().map(|device| device.name().ok()).collect();
+    for default in [host.default_input_device(), host.default_output_device()].into_iter().flatten() {
+        let name = default.name().ok();
+        if name.is_some() && !names.contains(&name) {
+            names.push(name);
+            devices.push(default);
+        }
+    }
+    Ok(devices)
+}
+
+// Names of the default input and output device.
+fn default_names(host: &Host) -> (Option<String>, Option<String>) {
+    (
+        host.default_input_device().and_then(|d| d.name().ok()),
+        host.default_output_device().and_then(|d| d.name().ok()),
+    )
+}
+
+fn device_info(device: &Device, (default_input, default_output): &(Option<String>, Option<String>)) -> DeviceInfo {
+    let name = device.name().ok();
+    let input = device.default_input_config().ok();
+    let output = device.default_output_config().ok();
+    let is_default_input = name.is_some() && *default_input == name;
+    let is_default_output = name.is_some() && *default_output == name;
+    DeviceInfo {
+        is_default: is_default_input || is_default_output,
+        is_default_input,
+        is_default_output,
+        input_channels: input.as_ref().map(|c| c.channels()),
+        output_channels: output.as_ref().map(|c| c.channels()),
+        default_sample_rate: input.as_ref().or(output.as_ref()).map(|c| c.sample_rate().0),
+        sample_formats: sample_formats(device),
+        name,
+        hosts: Vec::new(),
+    }
+}
+
+/// The `devices --json` document:
+/// `{ "schema_version": 1, "devices": [ DeviceInfo, ... ] }`.
+pub fn device_info_document(devices: &[DeviceInfo]) -> Value {
+    let mut object = Object::new();
+    object.insert("schema_version".into(), SCHEMA_VERSION.into());
+    object.insert("devices".into(), devices.iter().map(DeviceInfo::to_json).collect::<Vec<_>>().into());
+    Value::Object(object)
+}
+
+/// Every device of `host`, or of the default host, as the `devices --json`
+/// document.
+pub fn device_info_json(host: Option<&Host>) -> Result<String, DeviceError> {
+    Ok(json::to_string_pretty(&device_info_document(&collect_device_info(host)?)))
+}
+
+pub fn device_info_from_json(value: &Value) -> Result<Vec<DeviceInfo>, String> {
+    check_schema_version(value)?;
+    value
+        .require("devices")?
+        .as_array()
+        .ok_or("\"devices\" is not an array")?
+        .iter()
+        .map(DeviceInfo::from_json)
+        .collect()
+}
+
+fn open_host(host: Option<HostId>) -> Result<Host, DeviceError> {
+    match host {
+        Some(id) => {
+            cpal::host_from_id(id).map_err(|error| DeviceError::HostUnavailable { name: id.name().to_string(), error })
+        }
+        None => Ok(cpal::default_host()),
+    }
+}
+
+/// A host's devices, listed once and served from memory until `refresh`,
+/// for tools that look at the list often. Listing can take hundreds of
+/// milliseconds on some systems.
+///
+/// The devices handed out are clones, each a handle of its own: a stream
+/// opened on one keeps working when the cache is refreshed.
+pub struct DeviceCache {
+    host: Host,
+    inputs: Vec<Device>,
+    outputs: Vec<Device>,
+    default_input: Option<Device>,
+    default_output: Option<Device>,
+    enumerated_at: Instant,
+}
+
+impl DeviceCache {
+    /// Lists the devices of `host`, or of the default host.
+    pub fn new(host: Option<HostId>) -> Result<Self, DeviceError> {
+        let host = open_host(host)?;
+        let (inputs, outputs) = (input_devices(Some(&host))?, output_devices(Some(&host))?);
+        Ok(Self {
+            default_input: host.default_input_device(),
+            default_output: host.default_output_device(),
+            host,
+            inputs,
+            outputs,
+            enumerated_at: Instant::now(),
+        })
+    }
+
+    pub fn inputs(&self) -> &[Device] {
+        &self.inputs
+    }
+
+    pub fn outputs(&self) -> &[Device] {
+        &self.outputs
+    }
+
+    /// When the devices were last listed.
+    pub fn enumerated_at(&self) -> Instant {
+        self.enumerated_at
+    }
+
+    /// Whether the list is older than `max_age`.
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        self.enumerated_at.elapsed() > max_age
+    }
+
+    /// Lists the devices again. On an error the old list is kept.
+    pub fn refresh(&mut self) -> Result<(), DeviceError> {
+        let (inputs, outputs) = (input_devices(Some(&self.host))?, output_devices(Some(&self.host))?);
+        self.default_input = self.host.default_input_device();
+        self.default_output = self.host.default_output_device();
+        self.inputs = inputs;
+        self.outputs = outputs;
+        self.enumerated_at = Instant::now();
+        Ok(())
+    }
+}
+
+/// Lets `find_device` and `devices_with_channels` work from the cache.
+impl HostLike for DeviceCache {
+    type Device = Device;
+
+    fn input_devices(&self) -> Result<Vec<Device>, DeviceError> {
+        Ok(self.inputs.clone())
+    }
+
+    fn output_devices(&self) -> Result<Vec<Device>, DeviceError> {
+        Ok(self.outputs.clone())
+    }
+
+    fn default_input_device(&self) -> Option<Device> {
+        self.default_input.clone()
+    }
+
+    fn default_output_device(&self) -> Option<Device> {
+        self.default_output.clone()
+    }
+}
+
+/// Longest the watcher thread sleeps before looking at its stop flag.
+const WATCHER_STOP_CHECK: Duration = Duration::from_millis(50);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DeviceEvent {
+    Added(String),
+    Removed(String),
+}
+
+/// Polls a host's devices and reports the ones that came and went since
+/// the last poll. Devices are told apart by name only, so a reordered list
+/// is no change; two devices of the same name count separately. Polls
+/// where the host fails to list its devices are skipped. Stops when
+/// dropped.
+pub struct DeviceWatcher {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    events: Receiver<DeviceEvent>,
+}
+
+// Sorted names of the devices of `host`, `None` when they can't be listed.
+fn device_names(host: &Host) -> Option<Vec<String>> {
+    let mut names: Vec<String> = host.devices().ok()?.filter_map(|device| device.name().ok()).collect();
+    names.sort();
+    Some(names)
+}
+
+// What changed from `old` to `new`, both sorted.
+fn diff_names(old: &[String], new: &[String]) -> Vec<DeviceEvent> {
+    let (mut i, mut j) = (0, 0);
+    let mut events = Vec::new();
+    while i < old.len() || j < new.len() {
+        match (old.get(i), new.get(j)) {
+            (Some(a), Some(b)) if a == b => {
+                i += 1;
+                j += 1;
+            }
+            (Some(a), Some(b)) if a < b => {
+                events.push(DeviceEvent::Removed(a.clone()));
+                i += 1;
+            }
+            (Some(a), None) => {
+                events.push(DeviceEvent::Removed(a.clone()));
+                i += 1;
+            }
+            (_, Some(b)) => {
+                events.push(DeviceEvent::Added(b.clone()));
+                j += 1;
+            }
+            (None, None) => break,
+        }
+    }
+    events
+}
+
+impl DeviceWatcher {
+    /// Watches `host`, or the default host, every `poll_interval`.
+    pub fn spawn(host: Option<HostId>, poll_interval: Duration) -> Result<Self, DeviceError> {
+        let host = open_host(host)?;
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, events) = mpsc::channel();
+        let thread_stop = Arc::clone(&stop);
+        let thread = thread::spawn(move || watch(&host, poll_interval, &thread_stop, &sender));
+        Ok(Self { stop, thread: Some(thread), events })
+    }
+
+    pub fn try_event(&self) -> Option<DeviceEvent> {
+        self.events.try_recv().ok()
+    }
+
+    /// Stops polling and waits for the thread to finish.
+    pub fn stop(mut self) {
+        self.join();
+    }
+
+    fn join(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+fn watch(host: &Host, poll_interval: Duration, stop: &AtomicBool, events: &Sender<DeviceEvent>) {
+    let mut last = device_names(host).unwrap_or_default();
+    let mut next_poll = Instant::now() + poll_interval;
+    while !stop.load(Ordering::Acquire) {
+        let now = Instant::now();
+        if now < next_poll {
+            thread::sleep((next_poll - now).min(WATCHER_STOP_CHECK));
+            continue;
+        }
+        next_poll = now + poll_interval;
+
+        let Some(current) = device_names(host) else { continue };
+        for event in diff_names(&last, &current) {
+            if events.send(event).is_err() {
+                return;
+            }
+        }
+        last = current;
+    }
+}
+
+impl Drop for DeviceWatcher {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(name: Option<&str>) -> DeviceInfo {
+        DeviceInfo {
+            name: name.map(str::to_string),
+            is_default: true,
+            is_default_input: false,
+            is_default_output: true,
+            input_channels: None,
+            output_channels: Some(2),
+            default_sample_rate: Some(48000),
+            sample_formats: Some(vec!["f32".to_string(), "i16".to_string()]),
+            hosts: vec!["ALSA".to_string()],
+        }
+    }
+
+    #[test]
+    fn device_info_round_trips_through_json() {
+        let devices = [device(Some("Scarlett 2i2 USB")), DeviceInfo { sample_formats: None, ..device(None) }];
+        let text = json::to_string(&device_info_document(&devices));
+        let document = json::parse(&text).unwrap();
+        let read: Vec<DeviceInfo> = document
+            .require("devices")
+            .unwrap()
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|d| DeviceInfo::from_json(d).unwrap())
+            .collect();
+        assert_eq!(read, devices);
+    }
+
+    // An input, an interface with both directions and a long name, an
+    // output without a known rate, and a device that reported nothing.
+    fn device_table() -> Vec<DeviceInfo> {
+        let host = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        vec![
+            DeviceInfo {
+                is_default_input: true,
+                is_default_output: false,
+                input_channels: Some(1),
+                output_channels: None,
+                hosts: host(&["CoreAudio"]),
+                ..device(Some("MacBook Pro Microphone"))
+            },
+            DeviceInfo {
+                is_default: false,
+                is_default_output: false,
+                input_channels: Some(18),
+                output_channels: Some(20),
+                default_sample_rate: Some(96000),
+                hosts: host(&["CoreAudio", "JACK"]),
+                ..device(Some("Focusrite Scarlett 18i20 3rd Gen USB Audio Interface"))
+            },
+            DeviceInfo { default_sample_rate: None, hosts: host(&["CoreAudio"]), ..device(Some("HDMI")) },
+            DeviceInfo {
+                is_default: false,
+                is_default_output: false,
+                output_channels: None,
+                default_sample_rate: None,
+                sample_formats: None,
+                hosts: host(&["JACK"]),
+                ..device(None)
+            },
+        ]
+    }
+
+    #[test]
+    fn device_table_is_aligned_with_hosts_when_there_are_several() {
+        let table = format_device_table(&device_table());
+        assert!(table.ends_with('\n'));
+        assert_eq!(
+            table.lines().collect::<Vec<_>>(),
+            [
+                "  Name                                                  Direction  Default  Channels  Rate      Hosts",
+                "* MacBook Pro Microphone                                in         in       1/-       48000 Hz  CoreAudio",
+                "  Focusrite Scarlett 18i20 3rd Gen USB Audio Interface  in/out     -        18/20     96000 Hz  CoreAudio, JACK",
+                "* HDMI                                                  out        out      -/2       n/a       CoreAudio",
+                "  Unknown device                                        -          -        -/-       n/a       JACK",
+            ]
+        );
+    }
+
+    #[test]
+    fn device_table_leaves_out_a_single_host() {
+        let mut devices = device_table();
+        for device in &mut devices {
+            device.hosts = vec!["CoreAudio".to_string()];
+        }
+        assert_eq!(
+            format_device_table(&devices).lines().collect::<Vec<_>>(),
+            [
+                "  Name                                                  Direction  Default  Channels  Rate",
+                "* MacBook Pro Microphone                                in         in       1/-       48000 Hz",
+                "  Focusrite Scarlett 18i20 3rd Gen USB Audio Interface  in/out     -        18/20     96000 Hz",
+                "* HDMI                                                  out        out      -/2       n/a",
+                "  Unknown device                                        -          -        -/-       n/a",
+            ]
+        );
+        assert_eq!(format_device_table(&[]), "  Name  Direction  Default  Channels  Rate\n");
+    }
+
+    #[test]
+    fn old_and_new_device_documents_read() {
+        let mut value = device(Some("USB")).to_json();
+        if let Value::Object(object) = &mut value {
+            object.remove("hosts");
+            object.insert("added_later".into(), Value::from(true));
+        }
+        let read = DeviceInfo::from_json(&value).unwrap();
+        assert_eq!(read, DeviceInfo { hosts: Vec::new(), ..device(Some("USB")) });
+    }
+
+    // Devices with scripted names and configs, either of which can fail.
+    #[derive(Debug, Clone)]
+    struct MockDevice {
+        name: Option<&'static str>,
+        channels: Option<Vec<u16>>,
+    }
+
+    impl MockDevice {
+        fn named(name: &'static str, channels: &[u16]) -> Self {
+            Self { name: Some(name), channels: Some(channels.to_vec()) }
+        }
+    }
+
+    impl DeviceLike for MockDevice {
+        fn device_name(&self) -> Result<String, DeviceError> {
+            self.name.map(str::to_string).ok_or_else(|| {
+                DeviceError::NameUnavailable(DeviceNameError::BackendSpecific {
+                    err: cpal::BackendSpecificError { description: "unplugged".to_string() },
+                })
+            })
+        }
+
+        fn supported_configs(&self, _: Direction) -> Result<Vec<SupportedConfig>, DeviceError> {
+            let channels = self.channels.as_ref().ok_or_else(|| DeviceError::UnsupportedConfig("busy".to_string()))?;
+            Ok(channels
+                .iter()
+                .map(|&channels| SupportedConfig {
+                    min_sample_rate: 44100,
+                    max_sample_rate: 48000,
+                    channels,
+                    sample_format: SampleFormat::F32,
+                })
+                .collect())
+        }
+    }
+
+    #[derive(Default)]
+    struct MockHost {
+        inputs: Vec<MockDevice>,
+        outputs: Vec<MockDevice>,
+        // Index into the list of the direction.
+        default_output: Option<usize>,
+        enumeration_fails: bool,
+    }
+
+    impl MockHost {
+        fn with_outputs(outputs: Vec<MockDevice>) -> Self {
+            Self { outputs, ..Self::default() }
+        }
+
+        fn list(&self, devices: &[MockDevice]) -> Result<Vec<MockDevice>, DeviceError> {
+            if self.enumeration_fails {
+                return Err(DeviceError::Enumeration(DevicesError::BackendSpecific {
+                    err: cpal::BackendSpecificError { description: "no server".to_string() },
+                }));
+            }
+            Ok(devices.to_vec())
+        }
+    }
+
+    impl HostLike for MockHost {
+        type Device = MockDevice;
+
+        fn input_devices(&self) -> Result<Vec<MockDevice>, DeviceError> {
+            self.list(&self.inputs)
+        }
+
+        fn output_devices(&self) -> Result<Vec<MockDevice>, DeviceError> {
+            self.list(&self.outputs)
+        }
+
+        fn default_input_device(&self) -> Option<MockDevice> {
+            None
+        }
+
+        fn default_output_device(&self) -> Option<MockDevice> {
+            self.default_output.map(|i| self.outputs[i].clone())
+        }
+    }
+
+    fn studio() -> MockHost {
+        MockHost::with_outputs(vec![
+            MockDevice::named("Scarlett 2i2 USB", &[2]),
+            MockDevice::named("Scarlett 18i20 USB", &[8, 18]),
+            MockDevice::named("HDMI", &[2, 8]),
+            MockDevice { name: None, channels: Some(vec![2]) },
+            MockDevice { name: Some("Busy interface"), channels: None },
+        ])
+    }
+
+    #[test]
+    fn empty_host_finds_nothing() {
+        let host = MockHost::default();
+        assert!(matches!(find_device(&host, Direction::Output, "usb"), Err(DeviceError::NoMatch { .. })));
+        let filtered = devices_with_channels(&host, Direction::Input, 1).unwrap();
+        assert!(filtered.devices.is_empty() && filtered.warnings.is_empty());
+        assert!(matches!(host.default_device(Direction::Output), Err(DeviceError::NoDefaultDevice(Direction::Output))));
+        assert!(matches!(host.default_device(Direction::Input), Err(DeviceError::NoDefaultDevice(Direction::Input))));
+    }
+
+    #[test]
+    fn enumeration_errors_are_passed_on() {
+        let host = MockHost { enumeration_fails: true, ..studio() };
+        assert!(matches!(find_device(&host, Direction::Output, "hdmi"), Err(DeviceError::Enumeration(_))));
+        assert!(matches!(devices_with_channels(&host, Direction::Output, 2), Err(DeviceError::Enumeration(_))));
+    }
+
+    #[test]
+    fn finds_by_part_of_the_name_ignoring_case() {
+        let host = studio();
+        assert_eq!(find_device(&host, Direction::Output, "hdmi").unwrap().name, Some("HDMI"));
+        assert_eq!(find_device(&host, Direction::Output, "2I2").unwrap().name, Some("Scarlett 2i2 USB"));
+        // Skipping the device without a name on the way.
+        assert!(matches!(find_device(&host, Direction::Output, "nothing"), Err(DeviceError::NoMatch { .. })));
+        assert!(matches!(find_device(&host, Direction::Input, "hdmi"), Err(DeviceError::NoMatch { .. })));
+    }
+
+    #[test]
+    fn ambiguous_matches_list_every_candidate() {
+        match find_device(&studio(), Direction::Output, "scarlett") {
+            Err(DeviceError::Ambiguous { query, matches }) => {
+                assert_eq!(query, "scarlett");
+                assert_eq!(matches, ["Scarlett 2i2 USB", "Scarlett 18i20 USB"]);
+            }
+            other => panic!("expected an ambiguous match, got {:?}", other.map(|d| d.name)),
+        }
+    }
+
+    #[test]
+    fn exact_name_settles_a_tie() {
+        let host = MockHost::with_outputs(vec![MockDevice::named("USB", &[2]), MockDevice::named("USB Pro", &[2])]);
+        assert_eq!(find_device(&host, Direction::Output, "usb").unwrap().name, Some("USB"));
+    }
+
+    #[test]
+    fn filters_by_channels_and_warns_about_unlistable_devices() {
+        let filtered = devices_with_channels(&studio(), Direction::Output, 8).unwrap();
+        let names: Vec<_> = filtered.devices.iter().map(|d| d.name).collect();
+        assert_eq!(names, [Some("Scarlett 18i20 USB"), Some("HDMI")]);
+        assert_eq!(filtered.warnings.len(), 1);
+        assert!(filtered.warnings[0].contains("Busy interface"), "{}", filtered.warnings[0]);
+    }
+
+    #[test]
+    fn default_device_comes_from_the_host() {
+        let host = MockHost { default_output: Some(2), ..studio() };
+        assert_eq!(host.default_device(Direction::Output).unwrap().name, Some("HDMI"));
+    }
+}
@@ -7,12 +7,12 @@ const HPF_CUTOFF_FREQ: f32 = 100.0;  // Cutoff frequency for the high-pass filte
 ///
 /// # Arguments
 ///
-/// * `input` - A reference of vector of `f32` values representing the audio signal.
+/// * `input` - A slice of `f32` values representing the audio signal.
 ///
 /// # Returns
 ///
 /// A new `f32` vector with reduced reverberation.
-pub fn dereverb(input: &Vec<f32>) -> Vec<f32> {
+pub fn dereverb(input: &[f32]) -> Vec<f32> {
     let mut output = Vec::with_capacity(input.len());
     
     // High-pass filter state variables
@@ -62,11 +62,11 @@ fn early_reflection_suppression(sample: &f32) -> f32 {
     if sample.abs() < reflection_threshold {
         sample * attenuation_factor
     } else {
-        sample
+        *sample
     }
 }
 
-fn convolve(signal: &[f32], impulse_response: &[f32]) -> Vec<f32> {
+pub fn convolve(signal: &[f32], impulse_response: &[f32]) -> Vec<f32> {
     let n = signal.len();
     let m = impulse_response.len();
     let mut output = vec![0.0; n + m - 1];
@@ -81,13 +81,13 @@ fn convolve(signal: &[f32], impulse_response: &[f32]) -> Vec<f32> {
     output
 }
 
-enum WindowType {
+pub enum WindowType {
     Hamming,
     Hann,
     Blackman,
 }
 
-fn apply_window(samples: &mut [f32], window_type: WindowType) {
+pub fn apply_window(samples: &mut [f32], window_type: WindowType) {
     let n = samples.len();
     for (i, sample) in samples.iter_mut().enumerate() {
         let multiplier = match window_type {
@@ -106,7 +106,7 @@ fn apply_window(samples: &mut [f32], window_type: WindowType) {
     }
 }
 
-fn envelope_detection(samples: &[f32]) -> Vec<f32> {
+pub fn envelope_detection(samples: &[f32]) -> Vec<f32> {
     let mut envelope = Vec::with_capacity(samples.len());
     let mut previous = 0.0;
     let alpha = 0.1; // Smoothing factor (adjustable)
@@ -121,18 +121,59 @@ fn envelope_detection(samples: &[f32]) -> Vec<f32> {
     envelope
 }
 
-fn calculate_rms(samples: &[f32]) -> f32 {
+/// Envelope follower with separate attack and release times, for effects
+/// that need a running level rather than a whole-buffer envelope.
+pub struct EnvelopeFollower {
+    attack_coeff: f32,
+    release_coeff: f32,
+    envelope: f32,
+}
+
+impl EnvelopeFollower {
+    pub fn new(attack_ms: f32, release_ms: f32, sample_rate: f32) -> Self {
+        Self {
+            attack_coeff: (-1.0 / (attack_ms * 0.001 * sample_rate)).exp(),
+            release_coeff: (-1.0 / (release_ms * 0.001 * sample_rate)).exp(),
+            envelope: 0.0,
+        }
+    }
+
+    /// Feeds one sample and returns the updated envelope.
+    pub fn process(&mut self, sample: f32) -> f32 {
+        let rectified = sample.abs();
+        let coeff = if rectified > self.envelope {
+            self.attack_coeff
+        } else {
+            self.release_coeff
+        };
+        self.envelope = coeff * (self.envelope - rectified) + rectified;
+        self.envelope
+    }
+
+    pub fn value(&self) -> f32 {
+        self.envelope
+    }
+
+    pub fn reset(&mut self) {
+        self.envelope = 0.0;
+    }
+}
+
+pub fn calculate_rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
     let sum_squares: f32 = samples.iter().map(|&x| x * x).sum();
     let mean_square = sum_squares / samples.len() as f32;
     mean_square.sqrt()
 }
 
-fn peak_detection(samples: &[f32]) -> f32 {
+pub fn peak_detection(samples: &[f32]) -> f32 {
     samples.iter().map(|&x| x.abs()).fold(0.0, f32::max)
 }
 
 // Resample based on linear interpolation
-fn resample(samples: &[f32], original_rate: f32, target_rate: f32) -> Vec<f32> {
+pub fn resample(samples: &[f32], original_rate: f32, target_rate: f32) -> Vec<f32> {
     let resample_ratio = target_rate / original_rate;
     let new_length = ((samples.len() as f32) * resample_ratio).round() as usize;
     let mut resampled = Vec::with_capacity(new_length);
@@ -150,7 +191,7 @@ fn resample(samples: &[f32], original_rate: f32, target_rate: f32) -> Vec<f32> {
     resampled
 }
 
-fn normalize(samples: &mut [f32]) {
+pub fn normalize(samples: &mut [f32]) {
     if let Some(max_amplitude) = samples.iter().map(|&x| x.abs()).fold(None, |max, x| {
         Some(if let Some(current_max) = max {
             if x > current_max {
@@ -173,23 +214,102 @@ fn normalize(samples: &mut [f32]) {
 
 
 // Separating or combining mid and side signals:
-fn mid_side_encode(left_channel: &[f32], right_channel: &[f32]) -> (Vec<f32>, Vec<f32>) {
-    let mid: Vec<f32> = left_channel
-        .iter()
-        .zip(right_channel)
-        .map(|(&l, &r)| (l + r) * 0.5)
-        .collect();
-    let side: Vec<f32> = left_channel
-        .iter()
-        .zip(right_channel)
-        .map(|(&l, &r)| (l - r) * 0.5)
-        .collect();
+//
+// Channels of different lengths are not truncated to the shorter one: the
+// missing samples count as silence, so the output is as long as the longer
+// input.
+pub fn mid_side_encode(left_channel: &[f32], right_channel: &[f32]) -> (Vec<f32>, Vec<f32>) {
+    let len = left_channel.len().max(right_channel.len());
+    let l = |i: usize| left_channel.get(i).copied().unwrap_or(0.0);
+    let r = |i: usize| right_channel.get(i).copied().unwrap_or(0.0);
+    let mid: Vec<f32> = (0..len).map(|i| (l(i) + r(i)) * 0.5).collect();
+    let side: Vec<f32> = (0..len).map(|i| (l(i) - r(i)) * 0.5).collect();
     (mid, side)
 }
 
-fn mid_side_decode(mid: &[f32], side: &[f32]) -> (Vec<f32>, Vec<f32>) {
-    let left_channel: Vec<f32> = mid.iter().zip(side).map(|(&m, &s)| m + s).collect();
-    let right_channel: Vec<f32> = mid.iter().zip(side).map(|(&m, &s)| m - s).collect();
+/// Decodes mid/side back to left/right.
+///
+/// Headroom: |L| and |R| can reach |M| + |S|, so once the side signal has
+/// been boosted (stereo widening) the result may exceed ±1.0 even when both
+/// inputs are within range. Use `mid_side_decode_checked` to find out.
+pub fn mid_side_decode(mid: &[f32], side: &[f32]) -> (Vec<f32>, Vec<f32>) {
+    let len = mid.len().max(side.len());
+    let m = |i: usize| mid.get(i).copied().unwrap_or(0.0);
+    let s = |i: usize| side.get(i).copied().unwrap_or(0.0);
+    let left_channel: Vec<f32> = (0..len).map(|i| m(i) + s(i)).collect();
+    let right_channel: Vec<f32> = (0..len).map(|i| m(i) - s(i)).collect();
     (left_channel, right_channel)
 }
 
+/// Largest absolute sample of a decode.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeakInfo {
+    pub peak: f32,
+    /// Frame of the first sample at the peak.
+    pub frame: usize,
+}
+
+impl PeakInfo {
+    pub fn of(left_channel: &[f32], right_channel: &[f32]) -> PeakInfo {
+        let mut info = PeakInfo { peak: 0.0, frame: 0 };
+        for (frame, (l, r)) in left_channel.iter().zip(right_channel).enumerate() {
+            let value = l.abs().max(r.abs());
+            if value > info.peak {
+                info = PeakInfo { peak: value, frame };
+            }
+        }
+        info
+    }
+
+    pub fn clips(&self) -> bool {
+        self.peak > 1.0
+    }
+}
+
+/// `mid_side_decode` that also reports the peak of the decoded signal.
+pub fn mid_side_decode_checked(mid: &[f32], side: &[f32]) -> (Vec<f32>, Vec<f32>, PeakInfo) {
+    let (left_channel, right_channel) = mid_side_decode(mid, side);
+    let peak = PeakInfo::of(&left_channel, &right_channel);
+    (left_channel, right_channel, peak)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mid_side_pads_the_shorter_channel_with_silence() {
+        let (mid, side) = mid_side_encode(&[1.0, 0.5, 0.25], &[0.5]);
+        assert_eq!(mid, [0.75, 0.25, 0.125]);
+        assert_eq!(side, [0.25, 0.25, 0.125]);
+        let (mid, side) = mid_side_encode(&[], &[0.5, -1.0]);
+        assert_eq!(mid, [0.25, -0.5]);
+        assert_eq!(side, [-0.25, 0.5]);
+
+        let (left, right) = mid_side_decode(&[0.5, 0.25], &[0.25, 0.5, -0.5]);
+        assert_eq!(left, [0.75, 0.75, -0.5]);
+        assert_eq!(right, [0.25, -0.25, 0.5]);
+        assert_eq!(mid_side_decode(&[], &[]), (vec![], vec![]));
+
+        // Padding with silence means encode and decode still invert each other.
+        let (mid, side) = mid_side_encode(&[0.5, -0.25, 1.0, 0.75], &[-0.5, 0.25]);
+        assert_eq!(mid_side_decode(&mid, &side), (vec![0.5, -0.25, 1.0, 0.75], vec![-0.5, 0.25, 0.0, 0.0]));
+    }
+
+    #[test]
+    fn peak_info_reports_the_first_frame_at_the_peak() {
+        let left = [0.1, -0.9, 0.3, 0.9, 0.0];
+        let right = [0.2, 0.5, -0.95, 0.95, 0.0];
+        assert_eq!(PeakInfo::of(&left, &right), PeakInfo { peak: 0.95, frame: 2 });
+        assert_eq!(PeakInfo::of(&[0.0; 3], &[0.0; 3]), PeakInfo { peak: 0.0, frame: 0 });
+        assert!(!PeakInfo::of(&[1.0], &[-1.0]).clips());
+        assert!(PeakInfo::of(&[0.0], &[-1.01]).clips());
+
+        // A widened side signal pushes the decode past full scale.
+        let (left, right, peak) = mid_side_decode_checked(&[0.5, 0.6, 0.1], &[0.3, 0.7, 0.0]);
+        assert_eq!(peak.frame, 1);
+        assert!((peak.peak - 1.3).abs() < 1e-6);
+        assert!(peak.clips());
+        assert_eq!(PeakInfo::of(&left, &right), peak);
+    }
+}
@@ -0,0 +1,573 @@
+// Common interface for block-based effects, so the offline render path and
+// the stream callbacks can run any effect the same way.
+use std::fmt;
+use std::sync::Arc;
+
+use crate::filters::BiquadFilter;
+use crate::fx::{AutoWah, CombReverb, Compressor, Delay, Distortion, Flanger, NoiseGate, Tremolo};
+use crate::limiter::{db_to_linear, Limiter};
+use crate::realtime::CALLBACK_SCRATCH_SAMPLES;
+use crate::stream::SmoothedGain;
+
+/// Ramp length of the wrapper parameters until `with_sample_rate` is called:
+/// `GAIN_RAMP_MS` at 48 kHz.
+const DEFAULT_RAMP_FRAMES: usize = 480;
+
+pub trait Effect: Send {
+    /// Processes one block of mono samples in place. Blocks may have any
+    /// length; feeding a signal in one block or many must give the same output.
+    fn process_block(&mut self, block: &mut [f32]);
+
+    /// Hint that each output sample depends only on the matching input sample,
+    /// so a buffer may be split anywhere and processed by separate instances.
+    fn is_stateless(&self) -> bool {
+        false
+    }
+
+    /// Frames by which the output lags the input (lookahead and the like),
+    /// so callers can line processed audio back up with the dry signal.
+    fn latency_frames(&self) -> usize {
+        0
+    }
+}
+
+impl Effect for BiquadFilter {
+    fn process_block(&mut self, block: &mut [f32]) {
+        for sample in block.iter_mut() {
+            *sample = self.process_sample(*sample);
+        }
+    }
+}
+
+impl Effect for Compressor {
+    fn process_block(&mut self, block: &mut [f32]) {
+        Compressor::process_block(self, block);
+    }
+}
+
+impl Effect for AutoWah {
+    fn process_block(&mut self, block: &mut [f32]) {
+        AutoWah::process_block(self, block);
+    }
+}
+
+impl Effect for Delay {
+    fn process_block(&mut self, block: &mut [f32]) {
+        Delay::process_block(self, block);
+    }
+}
+
+impl Effect for CombReverb {
+    fn process_block(&mut self, block: &mut [f32]) {
+        CombReverb::process_block(self, block);
+    }
+}
+
+impl Effect for Tremolo {
+    fn process_block(&mut self, block: &mut [f32]) {
+        Tremolo::process_block(self, block);
+    }
+}
+
+impl Effect for Flanger {
+    fn process_block(&mut self, block: &mut [f32]) {
+        Flanger::process_block(self, block);
+    }
+}
+
+impl Effect for NoiseGate {
+    fn process_block(&mut self, block: &mut [f32]) {
+        NoiseGate::process_block(self, block);
+    }
+}
+
+impl Effect for Distortion {
+    fn process_block(&mut self, block: &mut [f32]) {
+        Distortion::process_block(self, block);
+    }
+
+    fn is_stateless(&self) -> bool {
+        true
+    }
+}
+
+impl Effect for Limiter {
+    fn process_block(&mut self, block: &mut [f32]) {
+        Limiter::process_block(self, block);
+    }
+
+    fn latency_frames(&self) -> usize {
+        Limiter::latency_frames(self)
+    }
+}
+
+/// What to do about NaN/inf samples at a chain boundary.
+///
+/// Effects themselves don't check their output; the scan happens once per
+/// block where a chain hands audio on (end of an `EffectChain`, end of a
+/// render), so the cost doesn't grow with the number of effects.
+#[derive(Clone, Default)]
+pub enum ScrubMode {
+    /// No scan at all.
+    #[default]
+    Off,
+    /// Count non-finite samples and report each affected block's count to
+    /// the callback, leaving the audio as is.
+    Detect { callback: Arc<dyn Fn(usize) + Send + Sync> },
+    /// Replace non-finite samples.
+    Scrub { replace_with: f32 },
+}
+
+impl fmt::Debug for ScrubMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScrubMode::Off => write!(f, "Off"),
+            ScrubMode::Detect { .. } => write!(f, "Detect"),
+            ScrubMode::Scrub { replace_with } => write!(f, "Scrub {{ replace_with: {} }}", replace_with),
+        }
+    }
+}
+
+/// Applies `mode` to a block and returns the number of non-finite samples found.
+pub fn apply_scrub(mode: &ScrubMode, block: &mut [f32]) -> usize {
+    match mode {
+        ScrubMode::Off => 0,
+        ScrubMode::Detect { callback } => {
+            let count = block.iter().filter(|s| !s.is_finite()).count();
+            if count > 0 {
+                callback(count);
+            }
+            count
+        }
+        ScrubMode::Scrub { replace_with } => {
+            let mut count = 0;
+            for sample in block.iter_mut() {
+                if !sample.is_finite() {
+                    *sample = *replace_with;
+                    count += 1;
+                }
+            }
+            count
+        }
+    }
+}
+
+/// Panics in debug builds when a block handed to a public entry point
+/// already contains NaN/inf, pointing at whoever produced it. Chains,
+/// `Mix`, `Gain`, renders and the stream callbacks check what they are
+/// given; release builds leave it to `ScrubMode` and the safety limiter.
+pub fn debug_assert_finite(context: &str, block: &[f32]) {
+    if cfg!(debug_assertions) {
+        if let Some(i) = block.iter().position(|s| !s.is_finite()) {
+            panic!("{}: non-finite sample {} at index {}", context, block[i], i);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ScrubStats {
+    pub non_finite_samples: u64,
+    pub affected_blocks: u64,
+}
+
+/// Effects run in order on each block, with the scrub policy applied once
+/// at the end.
+#[derive(Default)]
+pub struct EffectChain {
+    effects: Vec<Box<dyn Effect>>,
+    scrub: ScrubMode,
+    stats: ScrubStats,
+}
+
+impl EffectChain {
+    pub fn new(effects: Vec<Box<dyn Effect>>) -> Self {
+        Self {
+            effects,
+            scrub: ScrubMode::Off,
+            stats: ScrubStats::default(),
+        }
+    }
+
+    pub fn with_scrub(mut self, scrub: ScrubMode) -> Self {
+        self.scrub = scrub;
+        self
+    }
+
+    pub fn push(&mut self, effect: Box<dyn Effect>) {
+        self.effects.push(effect);
+    }
+
+    pub fn len(&self) -> usize {
+        self.effects.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.effects.is_empty()
+    }
+
+    pub fn stats(&self) -> ScrubStats {
+        self.stats
+    }
+}
+
+impl Effect for EffectChain {
+    fn process_block(&mut self, block: &mut [f32]) {
+        debug_assert_finite("EffectChain input", block);
+        for effect in self.effects.iter_mut() {
+            effect.process_block(block);
+        }
+        let found = apply_scrub(&self.scrub, block);
+        if found > 0 {
+            self.stats.non_finite_samples += found as u64;
+            self.stats.affected_blocks += 1;
+        }
+    }
+
+    fn is_stateless(&self) -> bool {
+        self.effects.iter().all(|e| e.is_stateless())
+    }
+
+    fn latency_frames(&self) -> usize {
+        self.effects.iter().map(|e| e.latency_frames()).sum()
+    }
+}
+
+/// Blends an effect with the dry signal.
+///
+/// The effect runs on a copy of each block; the dry path is delayed by the
+/// effect's reported latency so both stay aligned. At a wet level of 0 the
+/// dry signal comes out bit for bit, at 1 the effect's output does.
+pub struct Mix {
+    effect: Box<dyn Effect>,
+    wet: SmoothedGain,
+    scratch: Vec<f32>,
+    // Dry samples waiting for the effect's latency, used as a ring.
+    dry_delay: Vec<f32>,
+    delay_pos: usize,
+}
+
+impl Mix {
+    pub fn new(effect: Box<dyn Effect>, wet: f32) -> Self {
+        let latency = effect.latency_frames();
+        Self {
+            effect,
+            wet: SmoothedGain::new(wet.clamp(0.0, 1.0), DEFAULT_RAMP_FRAMES),
+            scratch: Vec::with_capacity(CALLBACK_SCRATCH_SAMPLES),
+            dry_delay: vec![0.0; latency],
+            delay_pos: 0,
+        }
+    }
+
+    /// Ramps wet level changes over `GAIN_RAMP_MS` at `sample_rate`.
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.wet = SmoothedGain::with_rate(self.wet.target(), sample_rate);
+        self
+    }
+
+    /// Moves the wet level to `wet` (0 to 1) along a short ramp.
+    pub fn set_wet(&mut self, wet: f32) {
+        self.wet.set_target(wet.clamp(0.0, 1.0));
+    }
+
+    pub fn wet(&self) -> f32 {
+        self.wet.target()
+    }
+
+    pub fn inner(&self) -> &dyn Effect {
+        self.effect.as_ref()
+    }
+
+    fn process_piece(&mut self, block: &mut [f32]) {
+        self.scratch.clear();
+        self.scratch.extend_from_slice(block);
+        self.effect.process_block(&mut self.scratch);
+
+        for (sample, &wet_sample) in block.iter_mut().zip(&self.scratch) {
+            let dry = if self.dry_delay.is_empty() {
+                *sample
+            } else {
+                let delayed = std::mem::replace(&mut self.dry_delay[self.delay_pos], *sample);
+                self.delay_pos = (self.delay_pos + 1) % self.dry_delay.len();
+                delayed
+            };
+            // 0 and 1 are special-cased so a non-finite wet signal can't leak
+            // into a fully dry mix, or the other way round.
+            *sample = match self.wet.next_gain() {
+                w if w <= 0.0 => dry,
+                w if w >= 1.0 => wet_sample,
+                w => dry * (1.0 - w) + wet_sample * w,
+            };
+        }
+    }
+}
+
+impl Effect for Mix {
+    fn process_block(&mut self, block: &mut [f32]) {
+        debug_assert_finite("Mix input", block);
+        // In pieces the scratch buffer holds, so a callback never allocates.
+        for piece in block.chunks_mut(CALLBACK_SCRATCH_SAMPLES) {
+            self.process_piece(piece);
+        }
+    }
+
+    fn latency_frames(&self) -> usize {
+        self.dry_delay.len()
+    }
+}
+
+/// Output gain in dB, ramped when changed while running.
+pub struct Gain {
+    gain: SmoothedGain,
+}
+
+impl Gain {
+    pub fn new(db: f32) -> Self {
+        Self { gain: SmoothedGain::new(db_to_linear(db), DEFAULT_RAMP_FRAMES) }
+    }
+
+    /// Ramps gain changes over `GAIN_RAMP_MS` at `sample_rate`.
+    pub fn with_sample_rate(mut self, sample_rate: u32) -> Self {
+        self.gain = SmoothedGain::with_rate(self.gain.target(), sample_rate);
+        self
+    }
+
+    pub fn set_db(&mut self, db: f32) {
+        self.gain.set_target(db_to_linear(db));
+    }
+
+    pub fn db(&self) -> f32 {
+        20.0 * self.gain.target().log10()
+    }
+}
+
+impl Effect for Gain {
+    fn process_block(&mut self, block: &mut [f32]) {
+        debug_assert_finite("Gain input", block);
+        for sample in block.iter_mut() {
+            *sample *= self.gain.next_gain();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fx::Delay;
+    use crate::limiter::LimiterSettings;
+
+    // Replaces every sample, NaN included, so a leak into the dry path shows.
+    struct Garbage;
+
+    impl Effect for Garbage {
+        fn process_block(&mut self, block: &mut [f32]) {
+            for (i, sample) in block.iter_mut().enumerate() {
+                *sample = if i % 3 == 0 { f32::NAN } else { 7.0 };
+            }
+        }
+    }
+
+    // Turns the marker values 0.5, 0.25 and -0.25 into NaN, inf and -inf,
+    // leaving everything else alone.
+    struct Poison;
+
+    impl Effect for Poison {
+        fn process_block(&mut self, block: &mut [f32]) {
+            for sample in block.iter_mut() {
+                *sample = match *sample {
+                    0.5 => f32::NAN,
+                    0.25 => f32::INFINITY,
+                    -0.25 => f32::NEG_INFINITY,
+                    other => other,
+                };
+            }
+        }
+    }
+
+    // Blocks with 0, 3 and 1 markers.
+    fn marked_blocks() -> Vec<Vec<f32>> {
+        vec![vec![0.1, -0.2, 0.3, 0.0], vec![0.5, 0.1, 0.25, -0.25], vec![0.0, 0.0, 0.0, 0.5]]
+    }
+
+    fn signal(frames: usize) -> Vec<f32> {
+        (0..frames).map(|i| (i as f32 * 0.031).sin() * 0.4 + (i as f32 * 0.27).cos() * 0.1).collect()
+    }
+
+    fn bits(block: &[f32]) -> Vec<u32> {
+        block.iter().map(|s| s.to_bits()).collect()
+    }
+
+    fn limiter(sample_rate: u32) -> Box<dyn Effect> {
+        Box::new(Limiter::new(sample_rate, 1, LimiterSettings { ceiling_db: -1.0, lookahead_ms: 5.0, release_ms: 50.0 }))
+    }
+
+    #[test]
+    fn zero_wet_is_bit_transparent() {
+        let input = signal(5_000);
+        let inners: Vec<Box<dyn Effect>> = vec![Box::new(Garbage), Box::new(Delay::new(48_000.0, 3.0, 0.5))];
+        for inner in inners {
+            let mut mix = Mix::new(inner, 0.0);
+            let mut output = input.clone();
+            mix.process_block(&mut output);
+            assert_eq!(bits(&output), bits(&input));
+        }
+    }
+
+    #[test]
+    fn full_wet_equals_the_raw_effect() {
+        let input = signal(5_000);
+        let mut raw = input.clone();
+        Delay::new(48_000.0, 3.0, 0.5).process_block(&mut raw);
+
+        let mut mix = Mix::new(Box::new(Delay::new(48_000.0, 3.0, 0.5)), 1.0);
+        let mut output = input.clone();
+        // Blocks longer than the scratch buffer are processed in pieces.
+        for block in output.chunks_mut(CALLBACK_SCRATCH_SAMPLES + 17) {
+            mix.process_block(block);
+        }
+        assert_eq!(bits(&output), bits(&raw));
+    }
+
+    #[test]
+    fn dry_path_is_delayed_to_match_the_effect() {
+        let mut mix = Mix::new(limiter(48_000), 0.5);
+        let latency = mix.latency_frames();
+        assert_eq!(latency, 240);
+
+        // Under the ceiling the limiter only delays, so a half-wet mix of an
+        // impulse is a single impulse, not two half-height ones.
+        let mut block = vec![0.0; 2_000];
+        block[100] = 0.5;
+        mix.process_block(&mut block);
+        for (i, &sample) in block.iter().enumerate() {
+            let expected = if i == 100 + latency { 0.5 } else { 0.0 };
+            assert!((sample - expected).abs() < 1e-6, "{} at {}", sample, i);
+        }
+    }
+
+    #[test]
+    fn wet_changes_ramp_instead_of_jumping() {
+        let mut mix = Mix::new(Box::new(Gain::new(-120.0)), 0.0).with_sample_rate(48_000);
+        mix.set_wet(1.0);
+        assert_eq!(mix.wet(), 1.0);
+        let mut block = vec![1.0; 1_000];
+        mix.process_block(&mut block);
+        // From the dry 1.0 down to the silenced wet signal over 10 ms.
+        for pair in block.windows(2) {
+            assert!(pair[1] <= pair[0] && pair[0] - pair[1] < 0.01, "{:?}", pair);
+        }
+        assert!(block[0] > 0.99 && block[999] < 1e-5);
+    }
+
+    #[test]
+    fn gain_scales_by_decibels() {
+        let input = signal(1_000);
+        let mut unity = input.clone();
+        Gain::new(0.0).process_block(&mut unity);
+        assert_eq!(bits(&unity), bits(&input));
+
+        let mut half = input.clone();
+        Gain::new(-20.0 * 2f32.log10()).process_block(&mut half);
+        for (h, x) in half.iter().zip(&input) {
+            assert!((h - x * 0.5).abs() < 1e-6);
+        }
+
+        let mut gain = Gain::new(-6.0).with_sample_rate(48_000);
+        gain.set_db(0.0);
+        assert!((gain.db()).abs() < 1e-5);
+        let mut ramp = vec![1.0; 1_000];
+        gain.process_block(&mut ramp);
+        assert!(ramp[0] < 0.6 && ramp[999] == 1.0);
+        assert!(ramp.windows(2).all(|pair| pair[1] >= pair[0]));
+    }
+
+    #[test]
+    fn chain_scrubs_what_its_own_effects_produce() {
+        // Only the input is checked on the way in; NaN made inside the
+        // chain is left to the scrub policy at its end.
+        let mut chain = EffectChain::new(vec![Box::new(Garbage)])
+            .with_scrub(ScrubMode::Scrub { replace_with: 0.0 });
+        let mut block = signal(9);
+        chain.process_block(&mut block);
+        assert_eq!(block, [0.0, 7.0, 7.0, 0.0, 7.0, 7.0, 0.0, 7.0, 7.0]);
+        assert_eq!(chain.stats(), ScrubStats { non_finite_samples: 3, affected_blocks: 1 });
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "EffectChain input: non-finite sample")]
+    fn chain_rejects_non_finite_input_in_debug_builds() {
+        EffectChain::new(vec![]).process_block(&mut [0.0, f32::NAN]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Mix input: non-finite sample inf at index 1")]
+    fn mix_rejects_non_finite_input_in_debug_builds() {
+        Mix::new(Box::new(Gain::new(0.0)), 0.5).process_block(&mut [0.0, f32::INFINITY]);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "Gain input: non-finite sample")]
+    fn gain_rejects_non_finite_input_in_debug_builds() {
+        Gain::new(0.0).process_block(&mut [f32::NAN]);
+    }
+
+    #[test]
+    fn detect_counts_exactly_and_leaves_the_samples_alone() {
+        let reported = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let callback = {
+            let reported = Arc::clone(&reported);
+            Arc::new(move |count: usize| reported.lock().unwrap().push(count))
+        };
+        let mut detecting = EffectChain::new(vec![Box::new(Poison)]).with_scrub(ScrubMode::Detect { callback });
+        let mut unchecked = EffectChain::new(vec![Box::new(Poison)]);
+        for block in marked_blocks() {
+            let (mut detected, mut plain) = (block.clone(), block);
+            detecting.process_block(&mut detected);
+            unchecked.process_block(&mut plain);
+            assert_eq!(bits(&detected), bits(&plain));
+        }
+        // Only the affected blocks are reported, with their own counts.
+        assert_eq!(*reported.lock().unwrap(), [3, 1]);
+        assert_eq!(detecting.stats(), ScrubStats { non_finite_samples: 4, affected_blocks: 2 });
+
+        let mut block = vec![f32::NAN, 1.0, f32::INFINITY, f32::NEG_INFINITY, f32::MAX, f32::MIN_POSITIVE];
+        let before = bits(&block);
+        let mode = ScrubMode::Detect { callback: Arc::new(|_| {}) };
+        assert_eq!(apply_scrub(&mode, &mut block), 3);
+        assert_eq!(bits(&block), before);
+    }
+
+    #[test]
+    fn off_leaves_the_buffer_untouched() {
+        let mut chain = EffectChain::new(vec![Box::new(Poison)]);
+        let mut outputs = Vec::new();
+        for mut block in marked_blocks() {
+            chain.process_block(&mut block);
+            outputs.push(block);
+        }
+        assert_eq!(bits(&outputs[1]), bits(&[f32::NAN, 0.1, f32::INFINITY, f32::NEG_INFINITY]));
+        assert_eq!(bits(&outputs[2][3..]), bits(&[f32::NAN]));
+        assert_eq!(chain.stats(), ScrubStats::default());
+
+        let mut block = vec![f32::NAN, -0.0, f32::INFINITY, 0.5];
+        let before = bits(&block);
+        assert_eq!(apply_scrub(&ScrubMode::Off, &mut block), 0);
+        assert_eq!(bits(&block), before);
+    }
+
+    #[test]
+    fn scrub_replaces_every_kind_of_non_finite_sample() {
+        let mut chain = EffectChain::new(vec![Box::new(Poison)]).with_scrub(ScrubMode::Scrub { replace_with: -1.0 });
+        let outputs: Vec<Vec<f32>> = marked_blocks()
+            .into_iter()
+            .map(|mut block| {
+                chain.process_block(&mut block);
+                block
+            })
+            .collect();
+        assert_eq!(outputs, [vec![0.1, -0.2, 0.3, 0.0], vec![-1.0, 0.1, -1.0, -1.0], vec![0.0, 0.0, 0.0, -1.0]]);
+        assert_eq!(chain.stats(), ScrubStats { non_finite_samples: 4, affected_blocks: 2 });
+    }
+}
@@ -1,10 +1,16 @@
-struct EQBand {
-    frequency: f32,
-    gain_db: f32,
-    q_factor: f32,
+// Parametric EQ made of peaking bands, presets of bands, and morphing
+// between presets.
+use crate::effect::Effect;
+use crate::filters::BiquadFilter;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EqBand {
+    pub frequency: f32,
+    pub gain_db: f32,
+    pub q_factor: f32,
 }
 
-fn equalizer(samples: &mut [f32], sample_rate: f32, bands: &[EQBand]) {
+pub fn equalizer(samples: &mut [f32], sample_rate: f32, bands: &[EqBand]) {
     let mut filters: Vec<BiquadFilter> = bands
         .iter()
         .map(|band| BiquadFilter::new_peaking_eq(sample_rate, band.frequency, band.q_factor, band.gain_db))
@@ -19,28 +25,236 @@ fn equalizer(samples: &mut [f32], sample_rate: f32, bands: &[EQBand]) {
     }
 }
 
-impl BiquadFilter {
-    fn new_peaking_eq(sample_rate: f32, freq: f32, q: f32, gain_db: f32) -> Self {
-        let omega = 2.0 * std::f32::consts::PI * freq / sample_rate;
-        let alpha = omega.sin() / (2.0 * q);
-        let a = 10.0_f32.powf(gain_db / 40.0);
+/// A set of peaking bands, e.g. the correction curve of one microphone.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct EqPreset {
+    pub bands: Vec<EqBand>,
+}
+
+// Geometric interpolation, for frequencies and Q.
+fn lerp_log(a: f32, b: f32, t: f32) -> f32 {
+    (a.ln() + (b.ln() - a.ln()) * t).exp()
+}
 
-        let b0 = 1.0 + alpha * a;
-        let b1 = -2.0 * omega.cos();
-        let b2 = 1.0 - alpha * a;
-        let a0 = 1.0 + alpha / a;
-        let a1 = -2.0 * omega.cos();
-        let a2 = 1.0 - alpha / a;
+impl EqPreset {
+    pub fn new(bands: Vec<EqBand>) -> Self {
+        Self { bands }
+    }
+
+    fn sorted_bands(&self) -> Vec<EqBand> {
+        let mut bands = self.bands.clone();
+        bands.sort_by(|x, y| x.frequency.total_cmp(&y.frequency));
+        bands
+    }
 
-        Self {
-            a0: b0 / a0,
-            a1: b1 / a0,
-            a2: b2 / a0,
-            b1: a1 / a0,
-            b2: a2 / a0,
-            z1: 0.0,
-            z2: 0.0,
+    /// The preset a fraction `t` of the way from `a` to `b`.
+    ///
+    /// Bands are paired in order of frequency. Paired bands move on a log
+    /// scale in frequency and Q and linearly in dB. When one preset has more
+    /// bands, its extra ones keep their frequency and Q and fade to 0 dB as
+    /// `t` moves towards the other preset.
+    ///
+    /// The paired bands come first, sorted by frequency, then the extra
+    /// ones, so a band keeps its index throughout a morph. At the endpoints
+    /// the result is `a` or `b` with the bands sorted, which is the same
+    /// order minus the extra bands that faded out.
+    pub fn morph(a: &EqPreset, b: &EqPreset, t: f32) -> EqPreset {
+        let (a_bands, b_bands) = (a.sorted_bands(), b.sorted_bands());
+        if t <= 0.0 {
+            return EqPreset { bands: a_bands };
         }
+        if t >= 1.0 {
+            return EqPreset { bands: b_bands };
+        }
+        let paired = a_bands.len().min(b_bands.len());
+
+        let mut bands: Vec<EqBand> = a_bands
+            .iter()
+            .zip(&b_bands)
+            .map(|(x, y)| EqBand {
+                frequency: lerp_log(x.frequency, y.frequency, t),
+                gain_db: x.gain_db + (y.gain_db - x.gain_db) * t,
+                q_factor: lerp_log(x.q_factor, y.q_factor, t),
+            })
+            .collect();
+        bands.extend(a_bands[paired..].iter().map(|band| EqBand { gain_db: band.gain_db * (1.0 - t), ..*band }));
+        bands.extend(b_bands[paired..].iter().map(|band| EqBand { gain_db: band.gain_db * t, ..*band }));
+        EqPreset { bands }
     }
 }
 
+/// Length of the transition when `Equalizer::set_preset` is called.
+pub const EQ_RAMP_MS: f32 = 20.0;
+/// Samples between coefficient updates during a transition.
+const EQ_UPDATE_INTERVAL: usize = 32;
+
+/// Peaking EQ that glides to a new preset instead of jumping, so presets
+/// can be automated (e.g. a morph position moved while playing).
+pub struct Equalizer {
+    sample_rate: f32,
+    filters: Vec<BiquadFilter>,
+    // The transition runs from `from` to `to`.
+    from: EqPreset,
+    to: EqPreset,
+    ramp_len: usize,
+    ramp_pos: usize,
+    // Samples left until the next coefficient update of a transition.
+    until_update: usize,
+}
+
+impl Equalizer {
+    pub fn new(sample_rate: f32, preset: EqPreset) -> Self {
+        let preset = EqPreset { bands: preset.sorted_bands() };
+        let ramp_len = ((EQ_RAMP_MS * 0.001 * sample_rate) as usize).max(1);
+        let mut equalizer = Self {
+            sample_rate,
+            filters: Vec::new(),
+            from: preset.clone(),
+            to: preset.clone(),
+            ramp_len,
+            ramp_pos: ramp_len,
+            until_update: 0,
+        };
+        equalizer.apply(&preset);
+        equalizer
+    }
+
+    /// Target preset; reached `EQ_RAMP_MS` after the last change.
+    pub fn preset(&self) -> &EqPreset {
+        &self.to
+    }
+
+    /// Starts a transition from where the EQ is now to `preset`.
+    pub fn set_preset(&mut self, preset: EqPreset) {
+        self.from = self.current();
+        self.to = preset;
+        self.ramp_pos = 0;
+    }
+
+    fn current(&self) -> EqPreset {
+        EqPreset::morph(&self.from, &self.to, self.ramp_pos as f32 / self.ramp_len as f32)
+    }
+
+    // Moves the filters to `preset`, keeping their state. A change in the
+    // number of bands adds or drops filters at the end.
+    fn apply(&mut self, preset: &EqPreset) {
+        self.filters.resize_with(preset.bands.len(), || BiquadFilter::from_coefficients(1.0, 0.0, 0.0, 0.0, 0.0));
+        for (filter, band) in self.filters.iter_mut().zip(&preset.bands) {
+            let target = BiquadFilter::new_peaking_eq(self.sample_rate, band.frequency, band.q_factor, band.gain_db);
+            filter.set_coefficients_from(&target);
+        }
+    }
+}
+
+impl Effect for Equalizer {
+    fn process_block(&mut self, block: &mut [f32]) {
+        // Updates happen every EQ_UPDATE_INTERVAL samples of the signal,
+        // wherever the block boundaries fall.
+        let mut start = 0;
+        while start < block.len() {
+            if self.until_update == 0 && self.ramp_pos < self.ramp_len {
+                self.ramp_pos = (self.ramp_pos + EQ_UPDATE_INTERVAL).min(self.ramp_len);
+                let current = self.current();
+                self.apply(&current);
+                self.until_update = EQ_UPDATE_INTERVAL;
+            }
+            let remaining = block.len() - start;
+            let len = if self.until_update > 0 { self.until_update.min(remaining) } else { remaining };
+            self.until_update = self.until_update.saturating_sub(len);
+
+            for sample in block[start..start + len].iter_mut() {
+                for filter in self.filters.iter_mut() {
+                    *sample = filter.process_sample(*sample);
+                }
+            }
+            start += len;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn band(frequency: f32, gain_db: f32, q_factor: f32) -> EqBand {
+        EqBand { frequency, gain_db, q_factor }
+    }
+
+    fn close(x: f32, y: f32) -> bool {
+        (x - y).abs() <= 1e-4 * y.abs().max(1.0)
+    }
+
+    // Out of order on purpose: pairing goes by sorted frequency.
+    fn preset_a() -> EqPreset {
+        EqPreset::new(vec![band(4_000.0, 3.0, 2.0), band(100.0, -6.0, 0.5), band(1_000.0, 0.0, 1.0)])
+    }
+
+    fn preset_b() -> EqPreset {
+        EqPreset::new(vec![band(200.0, 6.0, 2.0), band(8_000.0, -3.0, 0.5)])
+    }
+
+    #[test]
+    fn endpoints_are_the_presets_sorted() {
+        let (a, b) = (preset_a(), preset_b());
+        let sorted_a = EqPreset::new(vec![a.bands[1], a.bands[2], a.bands[0]]);
+        assert_eq!(EqPreset::morph(&a, &b, 0.0), sorted_a);
+        assert_eq!(EqPreset::morph(&a, &b, -1.0), sorted_a);
+        assert_eq!(EqPreset::morph(&a, &b, 1.0), b);
+        assert_eq!(EqPreset::morph(&a, &b, 2.0), b);
+        assert_eq!(EqPreset::morph(&b, &a, 1.0), sorted_a);
+    }
+
+    #[test]
+    fn paired_bands_move_on_log_frequency_and_q_and_linear_gain() {
+        let a = EqPreset::new(vec![band(100.0, -6.0, 0.5)]);
+        let b = EqPreset::new(vec![band(400.0, 6.0, 2.0)]);
+        let mid = EqPreset::morph(&a, &b, 0.5).bands[0];
+        assert!(close(mid.frequency, 200.0), "{}", mid.frequency);
+        assert!(close(mid.q_factor, 1.0), "{}", mid.q_factor);
+        assert!(close(mid.gain_db, 0.0), "{}", mid.gain_db);
+
+        let quarter = EqPreset::morph(&a, &b, 0.25).bands[0];
+        assert!(close(quarter.frequency, 100.0 * 2f32.sqrt()), "{}", quarter.frequency);
+        assert!(close(quarter.gain_db, -3.0), "{}", quarter.gain_db);
+    }
+
+    #[test]
+    fn bands_pair_in_order_of_frequency() {
+        let mid = EqPreset::morph(&preset_a(), &preset_b(), 0.5);
+        assert!(close(mid.bands[0].frequency, (100.0f32 * 200.0).sqrt()));
+        assert!(close(mid.bands[1].frequency, (1_000.0f32 * 8_000.0).sqrt()));
+    }
+
+    #[test]
+    fn extra_bands_fade_to_flat() {
+        let (a, b) = (preset_a(), preset_b());
+        for t in [0.1, 0.5, 0.9] {
+            // a's 4 kHz band has no partner; it stays put and fades out.
+            let extra = EqPreset::morph(&a, &b, t).bands[2];
+            assert_eq!((extra.frequency, extra.q_factor), (4_000.0, 2.0));
+            assert!(close(extra.gain_db, 3.0 * (1.0 - t)), "{} at {}", extra.gain_db, t);
+
+            // The other way round it fades in.
+            let extra = EqPreset::morph(&b, &a, t).bands[2];
+            assert!(close(extra.gain_db, 3.0 * t), "{} at {}", extra.gain_db, t);
+        }
+        // Almost at b the extra band is almost flat, so dropping it at t = 1
+        // is no jump.
+        assert!(EqPreset::morph(&a, &b, 0.9999).bands[2].gain_db.abs() < 1e-3);
+        assert_eq!(EqPreset::morph(&a, &b, 0.5).bands.len(), 3);
+    }
+
+    #[test]
+    fn morph_is_continuous_at_the_endpoints() {
+        let (a, b) = (preset_a(), preset_b());
+        let near_a = EqPreset::morph(&a, &b, 1e-6);
+        for (near, exact) in near_a.bands.iter().zip(&EqPreset::morph(&a, &b, 0.0).bands) {
+            assert!(close(near.frequency, exact.frequency) && close(near.gain_db, exact.gain_db));
+            assert!(close(near.q_factor, exact.q_factor));
+        }
+        let near_b = EqPreset::morph(&a, &b, 1.0 - 1e-6);
+        for (near, exact) in near_b.bands.iter().zip(&b.bands) {
+            assert!(close(near.frequency, exact.frequency) && close(near.gain_db, exact.gain_db));
+        }
+    }
+}
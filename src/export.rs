@@ -0,0 +1,330 @@
+// Export presets: the WAV writer plus whatever processing the target format
+// needs, always in this order:
+//
+//   resample → true-peak limit → dither → quantize
+//
+// Limiting has to follow resampling because the resampler's filter makes
+// new peaks between (and on) samples, so a signal limited first can be
+// over again at the new rate. Dither has to come after every gain change
+// and right before quantizing, or it is scaled away from the one rounding
+// step it is meant to decorrelate.
+use std::io;
+use std::path::Path;
+
+use crate::adapter::{convert_planar, AudioSpec};
+use crate::analysis::linear_to_db;
+use crate::limiter::{db_to_linear, Limiter, LimiterSettings};
+use crate::loudness::true_peak;
+use crate::write_wav::{WavSampleFormat, WavStreamWriter};
+
+/// Limiter passes before giving up on reaching a true-peak limit. Each
+/// pass lowers the sample ceiling by what the last one missed by.
+const TRUE_PEAK_PASSES: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Dither {
+    None,
+    /// Triangular noise of ±1 LSB at the output bit depth.
+    Tpdf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ExportSettings {
+    /// `None` keeps the source rate.
+    pub sample_rate: Option<u32>,
+    pub format: WavSampleFormat,
+    /// Maximum true peak in dBTP, `None` for no limiting.
+    pub true_peak_limit_db: Option<f32>,
+    /// Only applied to integer formats.
+    pub dither: Dither,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ExportPreset {
+    /// 44.1 kHz, 16-bit, TPDF dither.
+    Cd,
+    /// 16-bit with TPDF dither, limited to -1 dBTP; at 44.1 or 48 kHz.
+    Podcast { sample_rate: u32 },
+    /// The source rate in 32-bit float, untouched.
+    Archive,
+    Custom(ExportSettings),
+}
+
+impl ExportPreset {
+    pub fn settings(&self) -> ExportSettings {
+        match *self {
+            ExportPreset::Cd => ExportSettings {
+                sample_rate: Some(44_100),
+                format: WavSampleFormat::Int16,
+                true_peak_limit_db: None,
+                dither: Dither::Tpdf,
+            },
+            ExportPreset::Podcast { sample_rate } => ExportSettings {
+                sample_rate: Some(sample_rate),
+                format: WavSampleFormat::Int16,
+                true_peak_limit_db: Some(-1.0),
+                dither: Dither::Tpdf,
+            },
+            ExportPreset::Archive => ExportSettings {
+                sample_rate: None,
+                format: WavSampleFormat::Float32,
+                true_peak_limit_db: None,
+                dither: Dither::None,
+            },
+            ExportPreset::Custom(settings) => settings,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportReport {
+    /// What was done to the audio, in order.
+    pub steps: Vec<String>,
+    pub sample_rate: u32,
+    pub format: WavSampleFormat,
+    pub frames: usize,
+    /// Samples beyond full scale that quantizing had to clip.
+    pub clipped_samples: usize,
+    /// True peak of the samples as written, in dBTP.
+    pub true_peak_db: f32,
+}
+
+// xorshift32: cheap, and the same noise on every export of the same input.
+struct NoiseSource(u32);
+
+impl NoiseSource {
+    // Uniform in [-0.5, 0.5).
+    fn next(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 17;
+        self.0 ^= self.0 << 5;
+        (self.0 >> 8) as f32 / (1u32 << 24) as f32 - 0.5
+    }
+}
+
+fn interleave(channels: &[Vec<f32>]) -> Vec<f32> {
+    let frames = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+    (0..frames).flat_map(|i| channels.iter().map(move |c| c[i])).collect()
+}
+
+fn deinterleave(samples: &[f32], channels: usize) -> Vec<Vec<f32>> {
+    (0..channels).map(|c| samples.iter().skip(c).step_by(channels).copied().collect()).collect()
+}
+
+// Limits until the true peak is within `limit_db`. Returns the result, the
+// sample ceiling that got it there and the passes it took.
+fn limit_true_peak(channels: &[Vec<f32>], sample_rate: u32, limit_db: f32) -> (Vec<Vec<f32>>, f32, usize) {
+    let interleaved = interleave(channels);
+    let mut ceiling_db = limit_db;
+    let mut limited = channels.to_vec();
+    for pass in 1..=TRUE_PEAK_PASSES {
+        let settings = LimiterSettings { ceiling_db, ..LimiterSettings::default() };
+        let output = Limiter::new(sample_rate, channels.len() as u16, settings).process_offline(&interleaved);
+        limited = deinterleave(&output, channels.len());
+        let over_db = linear_to_db(true_peak(&limited, sample_rate)) - limit_db;
+        if over_db <= 0.0 {
+            return (limited, ceiling_db, pass);
+        }
+        ceiling_db -= over_db;
+    }
+    (limited, ceiling_db, TRUE_PEAK_PASSES)
+}
+
+/// Writes planar `samples` at `sample_rate` to `path` in the format of
+/// `preset`, converting as needed.
+pub fn write_with_preset<P: AsRef<Path>>(
+    path: P,
+    samples: &[Vec<f32>],
+    sample_rate: u32,
+    preset: ExportPreset,
+) -> io::Result<ExportReport> {
+    let settings = preset.settings();
+    let channels = samples.len().max(1);
+    let mut steps = Vec::new();
+
+    let output_rate = settings.sample_rate.unwrap_or(sample_rate);
+    let mut audio = if output_rate != sample_rate {
+        steps.push(format!("resampled {} Hz to {} Hz", sample_rate, output_rate));
+        let spec = |rate| AudioSpec::new(rate, channels as u16);
+        convert_planar(samples, spec(sample_rate), spec(output_rate))
+    } else {
+        samples.to_vec()
+    };
+
+    let is_integer = settings.format != WavSampleFormat::Float32;
+    let scale = ((1u32 << (settings.format.bits_per_sample() - 1)) - 1) as f32;
+    if let Some(limit_db) = settings.true_peak_limit_db {
+        let peak_db = linear_to_db(true_peak(&audio, output_rate));
+        // Dither and rounding still come, and may add up to 1.5 LSB to a
+        // peak; 2 leaves room for what that does between samples.
+        let headroom = if is_integer { 2.0 / scale } else { 0.0 };
+        let target_db = linear_to_db(db_to_linear(limit_db) - headroom);
+        if peak_db > target_db {
+            let (limited, ceiling_db, passes) = limit_true_peak(&audio, output_rate, target_db);
+            audio = limited;
+            steps.push(format!(
+                "limited from {:.2} dBTP to {:.1} dBTP (sample ceiling {:.2} dB, {} pass{})",
+                peak_db,
+                limit_db,
+                ceiling_db,
+                passes,
+                if passes == 1 { "" } else { "es" }
+            ));
+        } else {
+            steps.push(format!("true peak {:.2} dBTP already within {:.1} dBTP", peak_db, limit_db));
+        }
+    }
+
+    let mut clipped_samples = 0;
+    if is_integer {
+        if settings.dither == Dither::Tpdf {
+            let mut noise = NoiseSource(0x9E37_79B9);
+            for sample in audio.iter_mut().flatten() {
+                *sample += (noise.next() + noise.next()) / scale;
+            }
+            steps.push(format!("TPDF dither at {}-bit", settings.format.bits_per_sample()));
+        }
+        // Rounded here rather than in the writer so the true peak is that
+        // of the samples actually stored.
+        for sample in audio.iter_mut().flatten() {
+            if sample.abs() > 1.0 {
+                clipped_samples += 1;
+            }
+            *sample = (sample.clamp(-1.0, 1.0) * scale).round() / scale;
+        }
+        steps.push(format!("quantized to {}-bit", settings.format.bits_per_sample()));
+        if clipped_samples > 0 {
+            steps.push(format!("clipped {} samples beyond full scale", clipped_samples));
+        }
+    }
+
+    let frames = audio.iter().map(|c| c.len()).min().unwrap_or(0);
+    let mut writer = WavStreamWriter::create(path, output_rate, channels as u16, settings.format)?;
+    writer.write_samples(&interleave(&audio))?;
+    writer.finish()?;
+    Ok(ExportReport {
+        steps,
+        sample_rate: output_rate,
+        format: settings.format,
+        frames,
+        clipped_samples,
+        true_peak_db: linear_to_db(true_peak(&audio, output_rate)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_wav::read_wave_file;
+
+    // A path in the temp directory, removed when dropped.
+    struct TempPath(std::path::PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("cpal_playbook_{}_{}", std::process::id(), name)))
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn noise(seed: u32, frames: usize, amplitude: f32) -> Vec<f32> {
+        let mut noise = NoiseSource(seed);
+        (0..frames).map(|_| 2.0 * amplitude * noise.next()).collect()
+    }
+
+    // A loud tone near a quarter of the rate, sampled away from its peaks
+    // so that they fall between samples.
+    fn hot_tone(frames: usize, sample_rate: u32) -> Vec<f32> {
+        let frequency = sample_rate as f32 / 4.0 - 7.0;
+        (0..frames)
+            .map(|i| 0.99 * (2.0 * std::f32::consts::PI * frequency * i as f32 / sample_rate as f32 + 0.785).sin())
+            .collect()
+    }
+
+    fn read_back(file: &TempPath, channels: usize) -> (Vec<Vec<f32>>, hound::WavSpec) {
+        let (samples, spec) = read_wave_file(file.0.to_str().unwrap()).unwrap();
+        (deinterleave(&samples, channels), spec)
+    }
+
+    #[test]
+    fn archive_is_bit_transparent() {
+        let mut left = noise(1, 48_000, 1.0);
+        // Extremes and values a conversion would round away.
+        left[..6].copy_from_slice(&[1.0, -1.0, f32::MIN_POSITIVE, -1e-30, 0.123_456_79, -0.0]);
+        let input = vec![left, noise(2, 48_000, 0.25)];
+        let file = TempPath::new("export_archive.wav");
+        let report = write_with_preset(&file.0, &input, 48_000, ExportPreset::Archive).unwrap();
+        assert!(report.steps.is_empty(), "{:?}", report.steps);
+        assert_eq!((report.sample_rate, report.format, report.frames, report.clipped_samples), (48_000, WavSampleFormat::Float32, 48_000, 0));
+
+        let (output, spec) = read_back(&file, 2);
+        assert_eq!((spec.sample_rate, spec.bits_per_sample, spec.sample_format), (48_000, 32, hound::SampleFormat::Float));
+        let bits = |channels: &[Vec<f32>]| channels.iter().flatten().map(|s| s.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&output), bits(&input));
+    }
+
+    #[test]
+    fn limiting_follows_resampling() {
+        let input = vec![hot_tone(48_000, 48_000); 2];
+        assert!(linear_to_db(true_peak(&input, 48_000)) > -0.2);
+        let file = TempPath::new("export_podcast.wav");
+        let report = write_with_preset(&file.0, &input, 48_000, ExportPreset::Podcast { sample_rate: 44_100 }).unwrap();
+        let kinds: Vec<&str> = report.steps.iter().map(|step| step.split(' ').next().unwrap()).collect();
+        assert_eq!(kinds, ["resampled", "limited", "TPDF", "quantized"], "{:?}", report.steps);
+        assert_eq!(report.steps[0], "resampled 48000 Hz to 44100 Hz");
+
+        // No overs between the samples at the rate actually written.
+        let (output, spec) = read_back(&file, 2);
+        assert_eq!((spec.sample_rate, spec.bits_per_sample), (44_100, 16));
+        let written_db = linear_to_db(true_peak(&output, 44_100));
+        assert!(written_db <= -1.0, "{} dBTP", written_db);
+        assert!((written_db - report.true_peak_db).abs() < 1e-3);
+        assert!(written_db > -1.5, "{} dBTP", written_db);
+    }
+
+    #[test]
+    fn dither_comes_right_before_quantizing() {
+        // Digital silence comes out as dither noise within an LSB, every
+        // sample on the 16-bit grid.
+        let input = vec![vec![0.0; 44_100]];
+        let file = TempPath::new("export_cd.wav");
+        let report = write_with_preset(&file.0, &input, 44_100, ExportPreset::Cd).unwrap();
+        assert_eq!(report.steps, ["TPDF dither at 16-bit", "quantized to 16-bit"]);
+        let (output, spec) = read_back(&file, 1);
+        assert_eq!((spec.sample_rate, spec.bits_per_sample), (44_100, 16));
+        let lsbs: Vec<f32> = output[0].iter().map(|s| s * 32_768.0).collect();
+        assert!(lsbs.iter().all(|&lsb| lsb.round() == lsb && lsb.abs() <= 1.0));
+        // Triangular noise of ±1 LSB rounds away from zero a quarter of the time.
+        let nonzero = lsbs.iter().filter(|&&lsb| lsb != 0.0).count() as f32 / lsbs.len() as f32;
+        assert!((nonzero - 0.25).abs() < 0.01, "{}", nonzero);
+
+        // Without dither it stays silent, and what is beyond full scale is
+        // clipped and counted.
+        let plain = ExportPreset::Custom(ExportSettings {
+            sample_rate: None,
+            format: WavSampleFormat::Int16,
+            true_peak_limit_db: None,
+            dither: Dither::None,
+        });
+        let report = write_with_preset(&file.0, &[vec![0.0, 1.5, -2.0, 0.5]], 44_100, plain).unwrap();
+        assert_eq!(report.steps, ["quantized to 16-bit", "clipped 2 samples beyond full scale"]);
+        assert_eq!(report.clipped_samples, 2);
+        let (output, _) = read_back(&file, 1);
+        // Read back over 32768, the written codes are 0, ±32767 and 16384.
+        assert_eq!(output[0], [0.0, 32_767.0 / 32_768.0, -32_767.0 / 32_768.0, 0.5]);
+    }
+
+    #[test]
+    fn quiet_audio_is_not_limited() {
+        let input = vec![noise(3, 48_000, 0.1)];
+        let file = TempPath::new("export_quiet.wav");
+        let report = write_with_preset(&file.0, &input, 48_000, ExportPreset::Podcast { sample_rate: 48_000 }).unwrap();
+        assert!(report.steps[0].starts_with("true peak ") && report.steps[0].ends_with("already within -1.0 dBTP"), "{:?}", report.steps);
+        assert_eq!(report.steps[1..], ["TPDF dither at 16-bit", "quantized to 16-bit"]);
+    }
+}
@@ -1,6 +1,6 @@
 use rustfft::{FftPlanner, num_complex::Complex};
 
-fn fft(samples: &[f32]) -> Vec<Complex<f32>> {
+pub fn fft(samples: &[f32]) -> Vec<Complex<f32>> {
     let mut planner = FftPlanner::new();
     let fft = planner.plan_fft_forward(samples.len());
 
@@ -10,7 +10,7 @@ fn fft(samples: &[f32]) -> Vec<Complex<f32>> {
     buffer
 }
 
-fn ifft(frequency_data: &[Complex<f32>]) -> Vec<f32> {
+pub fn ifft(frequency_data: &[Complex<f32>]) -> Vec<f32> {
     let mut planner = FftPlanner::new();
     let ifft = planner.plan_fft_inverse(frequency_data.len());
 
@@ -20,3 +20,81 @@ fn ifft(frequency_data: &[Complex<f32>]) -> Vec<f32> {
     // Normalize the output
     buffer.iter().map(|c| c.re / frequency_data.len() as f32).collect()
 }
+
+/// Linear convolution of `signal` with `impulse_response` through one
+/// zero-padded FFT. The result has `signal.len() + impulse_response.len() - 1`
+/// samples.
+pub fn fft_convolve(signal: &[f32], impulse_response: &[f32]) -> Vec<f32> {
+    if signal.is_empty() || impulse_response.is_empty() {
+        return Vec::new();
+    }
+    let len = signal.len() + impulse_response.len() - 1;
+    let fft_len = len.next_power_of_two();
+
+    let mut padded_signal = signal.to_vec();
+    padded_signal.resize(fft_len, 0.0);
+    let mut padded_ir = impulse_response.to_vec();
+    padded_ir.resize(fft_len, 0.0);
+
+    let product: Vec<Complex<f32>> = fft(&padded_signal)
+        .iter()
+        .zip(fft(&padded_ir).iter())
+        .map(|(a, b)| a * b)
+        .collect();
+
+    let mut output = ifft(&product);
+    output.truncate(len);
+    output
+}
+
+/// Convolves `signal` with each of `impulse_responses` by overlap-add, so
+/// memory stays proportional to the longest response rather than to the
+/// signal. Each block of the signal is transformed once and reused for all
+/// responses. Every output has `signal.len() + longest - 1` samples.
+pub fn overlap_add_convolve(signal: &[f32], impulse_responses: &[&[f32]]) -> Vec<Vec<f32>> {
+    let longest = impulse_responses.iter().map(|ir| ir.len()).max().unwrap_or(0);
+    if signal.is_empty() || longest == 0 {
+        return vec![Vec::new(); impulse_responses.len()];
+    }
+    let block = longest.next_power_of_two();
+    let fft_len = 2 * block;
+    let mut planner = FftPlanner::new();
+    let forward = planner.plan_fft_forward(fft_len);
+    let inverse = planner.plan_fft_inverse(fft_len);
+
+    let spectra: Vec<Vec<Complex<f32>>> = impulse_responses
+        .iter()
+        .map(|ir| {
+            let mut buffer: Vec<Complex<f32>> = ir.iter().map(|&re| Complex { re, im: 0.0 }).collect();
+            buffer.resize(fft_len, Complex { re: 0.0, im: 0.0 });
+            forward.process(&mut buffer);
+            buffer
+        })
+        .collect();
+
+    let len = signal.len() + longest - 1;
+    let scale = 1.0 / fft_len as f32;
+    let mut outputs = vec![vec![0.0f32; len]; impulse_responses.len()];
+    let mut input = vec![Complex { re: 0.0, im: 0.0 }; fft_len];
+    let mut product = vec![Complex { re: 0.0, im: 0.0 }; fft_len];
+    for (index, chunk) in signal.chunks(block).enumerate() {
+        let start = index * block;
+        input.fill(Complex { re: 0.0, im: 0.0 });
+        for (slot, &x) in input.iter_mut().zip(chunk) {
+            slot.re = x;
+        }
+        forward.process(&mut input);
+
+        for (spectrum, output) in spectra.iter().zip(outputs.iter_mut()) {
+            for ((p, a), b) in product.iter_mut().zip(&input).zip(spectrum) {
+                *p = a * b;
+            }
+            inverse.process(&mut product);
+            let end = (start + fft_len).min(len);
+            for (out, p) in output[start..end].iter_mut().zip(&product) {
+                *out += p.re * scale;
+            }
+        }
+    }
+    outputs
+}
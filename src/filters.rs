@@ -1,6 +1,6 @@
 // First order low-pass IIR filter
 // y[n] = y[n-1] + α * (x[n] - y[n-1]).
-fn low_pass_filter(samples: &mut [f32], sample_rate: f32, cutoff_freq: f32) {
+pub fn low_pass_filter(samples: &mut [f32], sample_rate: f32, cutoff_freq: f32) {
     // Time constant
     let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff_freq);
     let dt = 1.0 / sample_rate;
@@ -17,7 +17,7 @@ fn low_pass_filter(samples: &mut [f32], sample_rate: f32, cutoff_freq: f32) {
 
 // First order high-pass IIR filter
 // y[n] = α * (y[n-1] + x[n] - x[n-1])
-fn high_pass_filter(samples: &mut [f32], sample_rate: f32, cutoff_freq: f32) {
+pub fn high_pass_filter(samples: &mut [f32], sample_rate: f32, cutoff_freq: f32) {
     let rc = 1.0 / (2.0 * std::f32::consts::PI * cutoff_freq);
     let dt = 1.0 / sample_rate;
     let alpha = rc / (rc + dt);
@@ -43,7 +43,8 @@ fn high_pass_filter(samples: &mut [f32], sample_rate: f32, cutoff_freq: f32) {
 //    }
 //}
 
-struct BiquadFilter {
+#[derive(Debug, Clone)]
+pub struct BiquadFilter {
     // Feedforward coefficients
     b0: f32,
     b1: f32,
@@ -51,23 +52,45 @@ struct BiquadFilter {
     // Feedback coefficients
     a1: f32,
     a2: f32,
-    // Delayed samples (for processing)
+    // State of the transposed direct form II
     z1: f32,
     z2: f32,
 }
 
 impl BiquadFilter {
     /// Processes a single sample through the filter.
-    fn process_sample(&mut self, input: f32) -> f32 {
-        let output = self.b0 * input + self.b1 * self.z1 + self.b2 * self.z2
-            - self.a1 * self.z1 - self.a2 * self.z2;
-        self.z2 = self.z1;
-        self.z1 = output;
+    pub fn process_sample(&mut self, input: f32) -> f32 {
+        // Transposed direct form II: the input and output histories are folded
+        // into two state variables instead of sharing one delay line.
+        let output = self.b0 * input + self.z1;
+        self.z1 = self.b1 * input - self.a1 * output + self.z2;
+        self.z2 = self.b2 * input - self.a2 * output;
         output
     }
 
+    /// Clears the filter state, keeping the coefficients.
+    pub fn reset(&mut self) {
+        self.z1 = 0.0;
+        self.z2 = 0.0;
+    }
+
+    /// Takes over the coefficients of `other` but keeps this filter's state,
+    /// so parameters can change while audio runs through it.
+    pub fn set_coefficients_from(&mut self, other: &BiquadFilter) {
+        self.b0 = other.b0;
+        self.b1 = other.b1;
+        self.b2 = other.b2;
+        self.a1 = other.a1;
+        self.a2 = other.a2;
+    }
+
+    /// Creates a filter from coefficients already normalized by a0.
+    pub fn from_coefficients(b0: f32, b1: f32, b2: f32, a1: f32, a2: f32) -> Self {
+        Self { b0, b1, b2, a1, a2, z1: 0.0, z2: 0.0 }
+    }
+
     /// Creates a low-pass filter.
-    fn new_lowpass(sample_rate: f32, cutoff_freq: f32, q_factor: f32) -> Self {
+    pub fn new_lowpass(sample_rate: f32, cutoff_freq: f32, q_factor: f32) -> Self {
         let omega = 2.0 * std::f32::consts::PI * cutoff_freq / sample_rate;
         let sin_omega = omega.sin();
         let cos_omega = omega.cos();
@@ -92,7 +115,7 @@ impl BiquadFilter {
     }
 
     /// Creates a high-pass filter.
-    fn new_highpass(sample_rate: f32, cutoff_freq: f32, q_factor: f32) -> Self {
+    pub fn new_highpass(sample_rate: f32, cutoff_freq: f32, q_factor: f32) -> Self {
         let omega = 2.0 * std::f32::consts::PI * cutoff_freq / sample_rate;
         let sin_omega = omega.sin();
         let cos_omega = omega.cos();
@@ -117,7 +140,7 @@ impl BiquadFilter {
     }
 
     /// Creates a low-shelf filter.
-    fn new_lowshelf(sample_rate: f32, cutoff_freq: f32, gain_db: f32, slope: f32) -> Self {
+    pub fn new_lowshelf(sample_rate: f32, cutoff_freq: f32, gain_db: f32, slope: f32) -> Self {
         let a = 10.0_f32.powf(gain_db / 40.0);
         let omega = 2.0 * std::f32::consts::PI * cutoff_freq / sample_rate;
         let sin_omega = omega.sin();
@@ -144,7 +167,7 @@ impl BiquadFilter {
     }
 
     /// Creates a high-shelf filter.
-    fn new_highshelf(sample_rate: f32, cutoff_freq: f32, gain_db: f32, slope: f32) -> Self {
+    pub fn new_highshelf(sample_rate: f32, cutoff_freq: f32, gain_db: f32, slope: f32) -> Self {
         let a = 10.0_f32.powf(gain_db / 40.0);
         let omega = 2.0 * std::f32::consts::PI * cutoff_freq / sample_rate;
         let sin_omega = omega.sin();
@@ -171,7 +194,7 @@ impl BiquadFilter {
     }
 
     /// Creates a peaking EQ filter.
-    fn new_peaking_eq(sample_rate: f32, freq: f32, q_factor: f32, gain_db: f32) -> Self {
+    pub fn new_peaking_eq(sample_rate: f32, freq: f32, q_factor: f32, gain_db: f32) -> Self {
         let a = 10.0_f32.powf(gain_db / 40.0);
         let omega = 2.0 * std::f32::consts::PI * freq / sample_rate;
         let sin_omega = omega.sin();
@@ -197,3 +220,105 @@ impl BiquadFilter {
     }
 }
 
+/// Parameters of a biquad, independent of the sample rate it will run at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilterSpec {
+    LowPass { cutoff_freq: f32, q_factor: f32 },
+    HighPass { cutoff_freq: f32, q_factor: f32 },
+    LowShelf { cutoff_freq: f32, gain_db: f32, slope: f32 },
+    HighShelf { cutoff_freq: f32, gain_db: f32, slope: f32 },
+    PeakingEq { freq: f32, q_factor: f32, gain_db: f32 },
+}
+
+impl FilterSpec {
+    /// Creates the filter described by the spec.
+    pub fn build(&self, sample_rate: f32) -> BiquadFilter {
+        match *self {
+            FilterSpec::LowPass { cutoff_freq, q_factor } => {
+                BiquadFilter::new_lowpass(sample_rate, cutoff_freq, q_factor)
+            }
+            FilterSpec::HighPass { cutoff_freq, q_factor } => {
+                BiquadFilter::new_highpass(sample_rate, cutoff_freq, q_factor)
+            }
+            FilterSpec::LowShelf { cutoff_freq, gain_db, slope } => {
+                BiquadFilter::new_lowshelf(sample_rate, cutoff_freq, gain_db, slope)
+            }
+            FilterSpec::HighShelf { cutoff_freq, gain_db, slope } => {
+                BiquadFilter::new_highshelf(sample_rate, cutoff_freq, gain_db, slope)
+            }
+            FilterSpec::PeakingEq { freq, q_factor, gain_db } => {
+                BiquadFilter::new_peaking_eq(sample_rate, freq, q_factor, gain_db)
+            }
+        }
+    }
+}
+
+/// The three simultaneous outputs of the state variable filter.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SvfOutput {
+    pub low: f32,
+    pub band: f32,
+    pub high: f32,
+}
+
+// State variable filter (trapezoidal / zero-delay-feedback form)
+// Unlike the biquad, its state stays valid when the cutoff changes every
+// sample, so it is the filter to use for modulated effects.
+#[derive(Debug, Clone)]
+pub struct StateVariableFilter {
+    sample_rate: f32,
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+    ic1eq: f32,
+    ic2eq: f32,
+}
+
+impl StateVariableFilter {
+    pub fn new(sample_rate: f32, cutoff_freq: f32, q_factor: f32) -> Self {
+        let mut filter = Self {
+            sample_rate,
+            k: 0.0,
+            a1: 0.0,
+            a2: 0.0,
+            a3: 0.0,
+            ic1eq: 0.0,
+            ic2eq: 0.0,
+        };
+        filter.set_params(cutoff_freq, q_factor);
+        filter
+    }
+
+    /// Updates the coefficients without touching the state. The cutoff is
+    /// clamped just below Nyquist.
+    pub fn set_params(&mut self, cutoff_freq: f32, q_factor: f32) {
+        let cutoff_freq = cutoff_freq.clamp(1.0, 0.49 * self.sample_rate);
+        let g = (std::f32::consts::PI * cutoff_freq / self.sample_rate).tan();
+        self.k = 1.0 / q_factor.max(0.01);
+        self.a1 = 1.0 / (1.0 + g * (g + self.k));
+        self.a2 = g * self.a1;
+        self.a3 = g * self.a2;
+    }
+
+    /// Processes a single sample. The band output is scaled for unity gain
+    /// at the center frequency.
+    pub fn process_sample(&mut self, input: f32) -> SvfOutput {
+        let v3 = input - self.ic2eq;
+        let v1 = self.a1 * self.ic1eq + self.a2 * v3;
+        let v2 = self.ic2eq + self.a2 * self.ic1eq + self.a3 * v3;
+        self.ic1eq = 2.0 * v1 - self.ic1eq;
+        self.ic2eq = 2.0 * v2 - self.ic2eq;
+
+        SvfOutput {
+            low: v2,
+            band: self.k * v1,
+            high: input - self.k * v1 - v2,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.ic1eq = 0.0;
+        self.ic2eq = 0.0;
+    }
+}
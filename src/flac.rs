@@ -0,0 +1,500 @@
+// FLAC decoding, with the `flac` feature.
+//
+// Enough of the format for reference recordings: the STREAMINFO block for
+// the stream's rate, channels and bit depth, then every frame with its
+// constant, verbatim, fixed and LPC subframes, Rice-coded residuals and
+// the three stereo decorrelation modes. Other metadata blocks are skipped
+// and the frame and MD5 checksums aren't checked.
+use std::fmt;
+
+#[derive(Debug)]
+pub enum FlacError {
+    NotFlac,
+    /// The stream ended in the middle of a block or frame.
+    Truncated,
+    /// Something the format doesn't allow, or this decoder doesn't know.
+    Invalid(&'static str),
+}
+
+impl fmt::Display for FlacError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlacError::NotFlac => write!(f, "Not a FLAC file"),
+            FlacError::Truncated => write!(f, "The FLAC stream ends early"),
+            FlacError::Invalid(reason) => write!(f, "Invalid FLAC stream: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for FlacError {}
+
+/// A decoded stream, one Vec of raw samples per channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FlacStream {
+    pub sample_rate: u32,
+    pub bits_per_sample: u32,
+    pub channels: Vec<Vec<i32>>,
+}
+
+// Most significant bit first, as FLAC is written.
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    // In bits.
+    position: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, position: 0 }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.position >= self.bytes.len() * 8
+    }
+
+    fn bit(&mut self) -> Result<bool, FlacError> {
+        let byte = *self.bytes.get(self.position / 8).ok_or(FlacError::Truncated)?;
+        let bit = byte >> (7 - self.position % 8) & 1;
+        self.position += 1;
+        Ok(bit == 1)
+    }
+
+    /// Up to 64 bits as an unsigned number.
+    fn bits(&mut self, count: u32) -> Result<u64, FlacError> {
+        let mut value = 0u64;
+        for _ in 0..count {
+            value = value << 1 | self.bit()? as u64;
+        }
+        Ok(value)
+    }
+
+    /// `count` bits as a two's complement number.
+    fn signed(&mut self, count: u32) -> Result<i64, FlacError> {
+        if count == 0 {
+            return Ok(0);
+        }
+        let value = self.bits(count)?;
+        Ok(((value << (64 - count)) as i64) >> (64 - count))
+    }
+
+    /// Zeros before the next one.
+    fn unary(&mut self) -> Result<u32, FlacError> {
+        let mut zeros = 0;
+        while !self.bit()? {
+            zeros += 1;
+        }
+        Ok(zeros)
+    }
+
+    fn align(&mut self) {
+        self.position = self.position.div_ceil(8) * 8;
+    }
+}
+
+/// Decodes a whole FLAC file held in `bytes`.
+pub fn decode(bytes: &[u8]) -> Result<FlacStream, FlacError> {
+    if bytes.len() < 4 || &bytes[0..4] != b"fLaC" {
+        return Err(FlacError::NotFlac);
+    }
+    let mut reader = BitReader::new(&bytes[4..]);
+
+    let mut info = None;
+    loop {
+        let last = reader.bit()?;
+        let kind = reader.bits(7)?;
+        let len = reader.bits(24)? as usize;
+        if kind == 0 {
+            // Block sizes and frame sizes, then what's needed here.
+            reader.bits(16 + 16 + 24 + 24)?;
+            let sample_rate = reader.bits(20)? as u32;
+            let channels = reader.bits(3)? as usize + 1;
+            let bits_per_sample = reader.bits(5)? as u32 + 1;
+            let total_frames = reader.bits(36)? as usize;
+            reader.bits(64)?;
+            reader.bits(64)?;
+            info = Some((sample_rate, channels, bits_per_sample, total_frames));
+        } else {
+            reader.position += len * 8;
+        }
+        if last {
+            break;
+        }
+    }
+    let (sample_rate, channel_count, bits_per_sample, total_frames) =
+        info.ok_or(FlacError::Invalid("no STREAMINFO block"))?;
+
+    // The header's count is only trusted as far as the file could hold it.
+    let mut channels = vec![Vec::with_capacity(total_frames.min(bytes.len())); channel_count];
+    let mut block = vec![Vec::new(); channel_count];
+    while !reader.is_empty() {
+        // Padding after the last frame.
+        if reader.bytes.len() - reader.position / 8 < 2 {
+            break;
+        }
+        decode_frame(&mut reader, bits_per_sample, &mut block)?;
+        for (channel, decoded) in channels.iter_mut().zip(&block) {
+            for &sample in decoded {
+                channel.push(i32::try_from(sample).map_err(|_| FlacError::Invalid("sample out of range"))?);
+            }
+        }
+    }
+    if total_frames > 0 {
+        for channel in channels.iter_mut() {
+            channel.truncate(total_frames);
+        }
+    }
+
+    Ok(FlacStream { sample_rate, bits_per_sample, channels })
+}
+
+fn decode_frame(reader: &mut BitReader, stream_bits: u32, block: &mut [Vec<i64>]) -> Result<(), FlacError> {
+    if reader.bits(14)? != 0x3ffe {
+        return Err(FlacError::Invalid("lost frame sync"));
+    }
+    reader.bits(2)?;
+    let size_code = reader.bits(4)?;
+    let rate_code = reader.bits(4)?;
+    let assignment = reader.bits(4)?;
+    let bits = match reader.bits(3)? {
+        0 => stream_bits,
+        1 => 8,
+        2 => 12,
+        4 => 16,
+        5 => 20,
+        6 => 24,
+        7 => 32,
+        _ => return Err(FlacError::Invalid("reserved sample size")),
+    };
+    reader.bit()?;
+    // The frame or sample number, UTF-8 style: the leading ones of the
+    // first byte say how many continuation bytes follow.
+    let first = reader.bits(8)?;
+    for _ in 0..(first as u8).leading_ones().saturating_sub(1) {
+        reader.bits(8)?;
+    }
+    let block_size = match size_code {
+        1 => 192,
+        2..=5 => 576 << (size_code - 2),
+        6 => reader.bits(8)? as usize + 1,
+        7 => reader.bits(16)? as usize + 1,
+        8..=15 => 256 << (size_code - 8),
+        _ => return Err(FlacError::Invalid("reserved block size")),
+    };
+    match rate_code {
+        12 => {
+            reader.bits(8)?;
+        }
+        13 | 14 => {
+            reader.bits(16)?;
+        }
+        15 => return Err(FlacError::Invalid("reserved sample rate")),
+        _ => {}
+    }
+    // Header CRC-8.
+    reader.bits(8)?;
+
+    let channel_count = match assignment {
+        0..=7 => assignment as usize + 1,
+        8..=10 => 2,
+        _ => return Err(FlacError::Invalid("reserved channel assignment")),
+    };
+    if channel_count != block.len() {
+        return Err(FlacError::Invalid("frame channel count differs from the stream's"));
+    }
+    for (c, samples) in block.iter_mut().enumerate() {
+        // The side channel carries one more bit.
+        let side = matches!((assignment, c), (8, 1) | (9, 0) | (10, 1));
+        decode_subframe(reader, bits + side as u32, block_size, samples)?;
+    }
+    reader.align();
+    // Frame CRC-16.
+    reader.bits(16)?;
+
+    if let [first, second] = block {
+        for (a, b) in first.iter_mut().zip(second.iter_mut()) {
+            match assignment {
+                // Left and side.
+                8 => *b = *a - *b,
+                // Side and right.
+                9 => *a += *b,
+                // Mid and side.
+                10 => {
+                    let mid = *a << 1 | (*b & 1);
+                    let side = *b;
+                    *a = (mid + side) >> 1;
+                    *b = (mid - side) >> 1;
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Coefficients of the fixed predictors, by order.
+const FIXED_COEFFICIENTS: [&[i64]; 5] = [&[], &[1], &[2, -1], &[3, -3, 1], &[4, -6, 4, -1]];
+
+fn decode_subframe(reader: &mut BitReader, bits: u32, block_size: usize, out: &mut Vec<i64>) -> Result<(), FlacError> {
+    if reader.bit()? {
+        return Err(FlacError::Invalid("subframe padding bit set"));
+    }
+    let kind = reader.bits(6)?;
+    let wasted = if reader.bit()? { reader.unary()? + 1 } else { 0 };
+    let bits = bits.checked_sub(wasted).filter(|&bits| bits > 0).ok_or(FlacError::Invalid("too many wasted bits"))?;
+
+    out.clear();
+    match kind {
+        0 => {
+            let value = reader.signed(bits)?;
+            out.resize(block_size, value);
+        }
+        1 => {
+            for _ in 0..block_size {
+                out.push(reader.signed(bits)?);
+            }
+        }
+        8..=12 => {
+            let order = kind as usize - 8;
+            for _ in 0..order {
+                out.push(reader.signed(bits)?);
+            }
+            decode_residual(reader, block_size, order, out)?;
+            predict(out, FIXED_COEFFICIENTS[order], 0)?;
+        }
+        32..=63 => {
+            let order = kind as usize - 31;
+            for _ in 0..order {
+                out.push(reader.signed(bits)?);
+            }
+            let precision = reader.bits(4)? as u32 + 1;
+            if precision == 16 {
+                return Err(FlacError::Invalid("reserved LPC precision"));
+            }
+            let shift = reader.signed(5)?;
+            if shift < 0 {
+                return Err(FlacError::Invalid("negative LPC shift"));
+            }
+            let mut coefficients = [0i64; 32];
+            for coefficient in coefficients.iter_mut().take(order) {
+                *coefficient = reader.signed(precision)?;
+            }
+            decode_residual(reader, block_size, order, out)?;
+            predict(out, &coefficients[..order], shift as u32)?;
+        }
+        _ => return Err(FlacError::Invalid("reserved subframe type")),
+    }
+    if out.len() != block_size {
+        return Err(FlacError::Invalid("subframe longer than its block"));
+    }
+    // Which also keeps the stereo decorrelation from overflowing.
+    let limit = 1i64 << (bits - 1);
+    if out.iter().any(|&sample| sample < -limit || sample >= limit) {
+        return Err(FlacError::Invalid("sample wider than its subframe"));
+    }
+    if wasted > 0 {
+        for sample in out.iter_mut() {
+            *sample <<= wasted;
+        }
+    }
+    Ok(())
+}
+
+// Adds the prediction from the samples before to each residual after the
+// warm-up samples. `coefficients[0]` weighs the sample just before. A
+// valid stream stays far inside i64; a corrupt one can make the samples
+// grow without bound, which is an error rather than an overflow.
+fn predict(samples: &mut [i64], coefficients: &[i64], shift: u32) -> Result<(), FlacError> {
+    const OVERFLOW: FlacError = FlacError::Invalid("prediction overflows");
+    let order = coefficients.len();
+    for i in order..samples.len() {
+        let mut prediction = 0i64;
+        for (j, c) in coefficients.iter().enumerate() {
+            prediction = c.checked_mul(samples[i - 1 - j]).and_then(|p| prediction.checked_add(p)).ok_or(OVERFLOW)?;
+        }
+        samples[i] = samples[i].checked_add(prediction >> shift).ok_or(OVERFLOW)?;
+    }
+    Ok(())
+}
+
+fn decode_residual(reader: &mut BitReader, block_size: usize, order: usize, out: &mut Vec<i64>) -> Result<(), FlacError> {
+    let (parameter_bits, escape) = match reader.bits(2)? {
+        0 => (4, 15),
+        1 => (5, 31),
+        _ => return Err(FlacError::Invalid("reserved residual coding")),
+    };
+    let partition_order = reader.bits(4)? as u32;
+    let partitions = 1usize << partition_order;
+    let partition_len = block_size >> partition_order;
+    if partition_len * partitions != block_size || partition_len < order {
+        return Err(FlacError::Invalid("residual partitions don't fit the block"));
+    }
+    for partition in 0..partitions {
+        let count = if partition == 0 { partition_len - order } else { partition_len };
+        let parameter = reader.bits(parameter_bits)? as u32;
+        if parameter == escape {
+            let raw_bits = reader.bits(5)? as u32;
+            for _ in 0..count {
+                out.push(reader.signed(raw_bits)?);
+            }
+        } else {
+            for _ in 0..count {
+                let folded = (reader.unary()? as u64) << parameter | reader.bits(parameter)?;
+                // Zigzag: 0, -1, 1, -2, ...
+                out.push((folded >> 1) as i64 ^ -((folded & 1) as i64));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Written by testdata/flac/make_fixtures.py, which says what each covers.
+    const STEREO16: &[u8] = include_bytes!("../testdata/flac/stereo16.flac");
+    const STEREO16_RAW: &[u8] = include_bytes!("../testdata/flac/stereo16.flac.raw");
+    const STEREO24: &[u8] = include_bytes!("../testdata/flac/stereo24.flac");
+    const STEREO24_RAW: &[u8] = include_bytes!("../testdata/flac/stereo24.flac.raw");
+    const MONO_WASTED: &[u8] = include_bytes!("../testdata/flac/mono_wasted.flac");
+    const MONO_WASTED_RAW: &[u8] = include_bytes!("../testdata/flac/mono_wasted.flac.raw");
+
+    fn check_fixture(flac: &[u8], raw: &[u8], sample_rate: u32, bits_per_sample: u32, channel_count: usize) {
+        let stream = decode(flac).unwrap();
+        assert_eq!(stream.sample_rate, sample_rate);
+        assert_eq!(stream.bits_per_sample, bits_per_sample);
+        assert_eq!(stream.channels.len(), channel_count);
+        let expected: Vec<i32> = raw.chunks_exact(4).map(|b| i32::from_le_bytes(b.try_into().unwrap())).collect();
+        for (c, channel) in stream.channels.iter().enumerate() {
+            let expected: Vec<i32> = expected.iter().skip(c).step_by(channel_count).copied().collect();
+            assert_eq!(channel, &expected, "channel {}", c);
+        }
+    }
+
+    #[test]
+    fn decodes_16_bit_stereo() {
+        check_fixture(STEREO16, STEREO16_RAW, 44100, 16, 2);
+    }
+
+    #[test]
+    fn decodes_24_bit_stereo() {
+        check_fixture(STEREO24, STEREO24_RAW, 48000, 24, 2);
+    }
+
+    #[test]
+    fn decodes_wasted_bits() {
+        check_fixture(MONO_WASTED, MONO_WASTED_RAW, 96000, 16, 1);
+    }
+
+    #[test]
+    fn rejects_other_files() {
+        assert!(matches!(decode(b"RIFF\0\0\0\0WAVE"), Err(FlacError::NotFlac)));
+        assert!(matches!(decode(b"fL"), Err(FlacError::NotFlac)));
+    }
+
+    #[test]
+    fn truncated_streams_are_errors() {
+        assert!(matches!(decode(&STEREO16[..20]), Err(FlacError::Truncated)));
+        assert!(matches!(decode(&STEREO16[..STEREO16.len() / 2]), Err(FlacError::Truncated)));
+    }
+
+    #[test]
+    fn lost_sync_is_an_error() {
+        // The first frame follows the marker and the 38 bytes of STREAMINFO.
+        let mut bytes = STEREO16.to_vec();
+        bytes[42] ^= 0xff;
+        assert!(matches!(decode(&bytes), Err(FlacError::Invalid("lost frame sync"))));
+    }
+
+    #[test]
+    fn corrupt_streams_do_not_panic() {
+        for position in (4..STEREO16.len()).step_by(7) {
+            let mut bytes = STEREO16.to_vec();
+            bytes[position] ^= 0xa5;
+            let _ = decode(&bytes);
+        }
+    }
+
+    struct BitWriter {
+        bytes: Vec<u8>,
+        bits: usize,
+    }
+
+    impl BitWriter {
+        fn new() -> Self {
+            Self { bytes: Vec::new(), bits: 0 }
+        }
+
+        fn put(&mut self, value: u64, count: u32) {
+            for i in (0..count).rev() {
+                if self.bits.is_multiple_of(8) {
+                    self.bytes.push(0);
+                }
+                let bit = (value >> i & 1) as u8;
+                *self.bytes.last_mut().unwrap() |= bit << (7 - self.bits % 8);
+                self.bits += 1;
+            }
+        }
+    }
+
+    // A mono 16-bit stream of one frame holding an order 1 LPC subframe
+    // whose coefficient multiplies the warm-up sample every step, and zero
+    // residuals.
+    fn runaway_lpc(block_size: u64) -> Vec<u8> {
+        let mut w = BitWriter::new();
+        for &byte in b"fLaC" {
+            w.put(byte as u64, 8);
+        }
+        // STREAMINFO, the last metadata block.
+        w.put(1, 1);
+        w.put(0, 7);
+        w.put(34, 24);
+        w.put(block_size, 16);
+        w.put(block_size, 16);
+        w.put(0, 48);
+        w.put(8000, 20);
+        w.put(0, 3);
+        w.put(15, 5);
+        w.put(block_size, 36);
+        w.put(0, 64);
+        w.put(0, 64);
+        // Frame header: sync, 8-bit block size, rate and bits from
+        // STREAMINFO, mono, frame 0, block size, CRC.
+        w.put(0x3ffe, 14);
+        w.put(0, 2);
+        w.put(6, 4);
+        w.put(0, 4);
+        w.put(0, 4);
+        w.put(0, 3);
+        w.put(0, 1);
+        w.put(0, 8);
+        w.put(block_size - 1, 8);
+        w.put(0, 8);
+        // LPC order 1: warm-up, 15-bit precision, no shift, coefficient.
+        w.put(0, 1);
+        w.put(32, 6);
+        w.put(0, 1);
+        w.put(0x7fff, 16);
+        w.put(14, 4);
+        w.put(0, 5);
+        w.put(0x3fff, 15);
+        // One escaped partition of 0-bit residuals.
+        w.put(0, 2);
+        w.put(0, 4);
+        w.put(15, 4);
+        w.put(0, 5);
+        w.put(0, (8 - w.bits % 8) as u32 % 8);
+        w.put(0, 16);
+        w.bytes
+    }
+
+    #[test]
+    fn prediction_overflow_is_an_error() {
+        assert!(matches!(decode(&runaway_lpc(16)), Err(FlacError::Invalid("prediction overflows"))));
+    }
+
+    #[test]
+    fn samples_wider_than_the_stream_are_errors() {
+        assert!(matches!(decode(&runaway_lpc(2)), Err(FlacError::Invalid("sample wider than its subframe"))));
+    }
+}
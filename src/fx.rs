@@ -1,40 +1,78 @@
-fn delay_effect(samples: &mut [f32], sample_rate: f32, delay_time_ms: f32, feedback: f32) {
-    let delay_samples = (sample_rate * delay_time_ms / 1000.0) as usize;
-    let mut delay_buffer = vec![0.0; delay_samples];
-    let mut delay_index = 0;
+use crate::dsp::{mid_side_decode_checked, mid_side_encode, EnvelopeFollower, PeakInfo};
+use crate::filters::{BiquadFilter, FilterSpec, StateVariableFilter};
 
-    for sample in samples.iter_mut() {
-        let delayed_sample = delay_buffer[delay_index];
-        let new_sample = *sample + delayed_sample * feedback;
-        delay_buffer[delay_index] = new_sample;
-        *sample = delayed_sample;
-        delay_index = (delay_index + 1) % delay_samples;
+pub fn delay_effect(samples: &mut [f32], sample_rate: f32, delay_time_ms: f32, feedback: f32) {
+    Delay::new(sample_rate, delay_time_ms, feedback).process_block(samples);
+}
+
+pub struct Delay {
+    delay_buffer: Vec<f32>,
+    delay_index: usize,
+    feedback: f32,
+}
+
+impl Delay {
+    pub fn new(sample_rate: f32, delay_time_ms: f32, feedback: f32) -> Self {
+        let delay_samples = ((sample_rate * delay_time_ms / 1000.0) as usize).max(1);
+        Self {
+            delay_buffer: vec![0.0; delay_samples],
+            delay_index: 0,
+            feedback,
+        }
+    }
+
+    pub fn process_block(&mut self, samples: &mut [f32]) {
+        for sample in samples.iter_mut() {
+            let delayed_sample = self.delay_buffer[self.delay_index];
+            let new_sample = *sample + delayed_sample * self.feedback;
+            self.delay_buffer[self.delay_index] = new_sample;
+            *sample = delayed_sample;
+            self.delay_index = (self.delay_index + 1) % self.delay_buffer.len();
+        }
     }
 }
 
 // Reverb using multiple delay lines, comb filters
-fn reverb_effect(samples: &mut [f32], sample_rate: f32, room_size: f32, damping: f32) {
-    let delay_times = [29, 37, 41, 43]; // Prime numbers for delay lengths
-    let mut delay_lines: Vec<Vec<f32>> = delay_times
-        .iter()
-        .map(|&t| vec![0.0; (sample_rate * t as f32 / 1000.0) as usize])
-        .collect();
-    let mut indices = vec![0; delay_times.len()];
+pub fn reverb_effect(samples: &mut [f32], sample_rate: f32, room_size: f32, damping: f32) {
+    CombReverb::new(sample_rate, room_size, damping).process_block(samples);
+}
 
-    for sample in samples.iter_mut() {
-        let mut reverberated = 0.0;
-        for (i, delay_line) in delay_lines.iter_mut().enumerate() {
-            let index = indices[i];
-            let delayed_sample = delay_line[index];
-            delay_line[index] = *sample + delayed_sample * damping;
-            reverberated += delayed_sample;
-            indices[i] = (index + 1) % delay_line.len();
+pub struct CombReverb {
+    delay_lines: Vec<Vec<f32>>,
+    indices: Vec<usize>,
+    room_size: f32,
+    damping: f32,
+}
+
+impl CombReverb {
+    pub fn new(sample_rate: f32, room_size: f32, damping: f32) -> Self {
+        let delay_times = [29, 37, 41, 43]; // Prime numbers for delay lengths
+        Self {
+            delay_lines: delay_times
+                .iter()
+                .map(|&t| vec![0.0; ((sample_rate * t as f32 / 1000.0) as usize).max(1)])
+                .collect(),
+            indices: vec![0; delay_times.len()],
+            room_size,
+            damping,
+        }
+    }
+
+    pub fn process_block(&mut self, samples: &mut [f32]) {
+        for sample in samples.iter_mut() {
+            let mut reverberated = 0.0;
+            for (delay_line, index) in self.delay_lines.iter_mut().zip(self.indices.iter_mut()) {
+                let delayed_sample = delay_line[*index];
+                delay_line[*index] = *sample + delayed_sample * self.damping;
+                reverberated += delayed_sample;
+                *index = (*index + 1) % delay_line.len();
+            }
+            *sample = *sample * (1.0 - self.room_size) + reverberated * self.room_size;
         }
-        *sample = *sample * (1.0 - room_size) + reverberated * room_size;
     }
 }
 
-fn compressor(
+pub fn compressor(
     samples: &mut [f32],
     threshold: f32,
     ratio: f32,
@@ -42,27 +80,99 @@ fn compressor(
     release_ms: f32,
     sample_rate: f32,
 ) {
-    let attack_coeff = (-1.0 / (attack_ms * 0.001 * sample_rate)).exp();
-    let release_coeff = (-1.0 / (release_ms * 0.001 * sample_rate)).exp();
+    Compressor::new(threshold, ratio, attack_ms, release_ms, sample_rate).process_block(samples);
+}
 
-    let mut gain = 1.0;
+/// Feed-forward compressor with a separate detector path.
+///
+/// The detector only decides how much gain to apply; the gain is then applied
+/// to the untouched main signal. By default the detector listens to the main
+/// signal itself, but it can be fed a filtered copy (`set_sidechain_filter`)
+/// or an external key signal (`process_block_keyed`).
+pub struct Compressor {
+    threshold: f32,
+    ratio: f32,
+    attack_coeff: f32,
+    release_coeff: f32,
+    sample_rate: f32,
+    sidechain_filter: Option<BiquadFilter>,
+    gain: f32,
+}
 
-    for sample in samples.iter_mut() {
-        let input_level = sample.abs();
-        let over_threshold = input_level > threshold;
+impl Compressor {
+    pub fn new(threshold: f32, ratio: f32, attack_ms: f32, release_ms: f32, sample_rate: f32) -> Self {
+        Self {
+            threshold,
+            ratio,
+            attack_coeff: (-1.0 / (attack_ms * 0.001 * sample_rate)).exp(),
+            release_coeff: (-1.0 / (release_ms * 0.001 * sample_rate)).exp(),
+            sample_rate,
+            sidechain_filter: None,
+            gain: 1.0,
+        }
+    }
 
-        if over_threshold {
-            let compressed_gain = threshold + (input_level - threshold) / ratio;
-            gain = attack_coeff * (gain - compressed_gain) + compressed_gain;
-        } else {
-            gain = release_coeff * (gain - 1.0) + 1.0;
+    /// Filters the detector input, e.g. a high-pass at 100-150 Hz so a kick
+    /// drum doesn't pump the whole mix. The main signal stays unfiltered.
+    pub fn set_sidechain_filter(&mut self, spec: FilterSpec) {
+        self.sidechain_filter = Some(spec.build(self.sample_rate));
+    }
+
+    pub fn clear_sidechain_filter(&mut self) {
+        self.sidechain_filter = None;
+    }
+
+    /// Current gain reduction in dB (positive when compressing).
+    pub fn gain_reduction_db(&self) -> f32 {
+        -20.0 * self.gain.log10()
+    }
+
+    /// Compresses `samples` in place, detecting on the signal itself.
+    pub fn process_block(&mut self, samples: &mut [f32]) {
+        for sample in samples.iter_mut() {
+            let gain = self.detect(*sample);
+            *sample *= gain;
+        }
+    }
+
+    /// Compresses `main` in place, detecting on `key` instead. The blocks
+    /// should be the same length; samples of `main` past the end of a
+    /// shorter `key` are left as they are, at unity gain, and extra key
+    /// samples are ignored.
+    pub fn process_block_keyed(&mut self, main: &mut [f32], key: &[f32]) {
+        for (sample, &key_sample) in main.iter_mut().zip(key) {
+            let gain = self.detect(key_sample);
+            *sample *= gain;
         }
+    }
+
+    // Detector path: sidechain filter, level detection and gain smoothing.
+    // Returns the gain to apply to the current main sample.
+    fn detect(&mut self, key_sample: f32) -> f32 {
+        let key_sample = match self.sidechain_filter.as_mut() {
+            Some(filter) => filter.process_sample(key_sample),
+            None => key_sample,
+        };
 
-        *sample *= gain;
+        let input_level = key_sample.abs();
+        let target_gain = if input_level > self.threshold {
+            let compressed_level = self.threshold + (input_level - self.threshold) / self.ratio;
+            compressed_level / input_level
+        } else {
+            1.0
+        };
+
+        let coeff = if target_gain < self.gain {
+            self.attack_coeff
+        } else {
+            self.release_coeff
+        };
+        self.gain = coeff * (self.gain - target_gain) + target_gain;
+        self.gain
     }
 }
 
-fn normalize(samples: &mut [f32]) {
+pub fn normalize(samples: &mut [f32]) {
     if let Some(max_amplitude) = samples.iter().map(|&x| x.abs()).fold(None, |max, x| {
         Some(if let Some(current_max) = max {
             if x > current_max {
@@ -83,7 +193,7 @@ fn normalize(samples: &mut [f32]) {
     }
 }
 
-fn remove_dc_offset(samples: &mut [f32]) {
+pub fn remove_dc_offset(samples: &mut [f32]) {
     let mean: f32 = samples.iter().sum::<f32>() / samples.len() as f32;
     for sample in samples.iter_mut() {
         *sample -= mean;
@@ -91,30 +201,62 @@ fn remove_dc_offset(samples: &mut [f32]) {
 }
 
 // Soft clipping distortion with harmonic content
-fn distortion(samples: &mut [f32], gain: f32, threshold: f32) {
-    for sample in samples.iter_mut() {
-        *sample *= gain;
-        if *sample > threshold {
-            *sample = threshold + (1.0 - threshold) * ((*sample - threshold) / (1.0 - threshold)).tanh();
-        } else if *sample < -threshold {
-            *sample = -threshold + (-1.0 + threshold) * ((*sample + threshold) / (-1.0 + threshold)).tanh();
+pub fn distortion(samples: &mut [f32], gain: f32, threshold: f32) {
+    Distortion::new(gain, threshold).process_block(samples);
+}
+
+pub struct Distortion {
+    gain: f32,
+    threshold: f32,
+}
+
+impl Distortion {
+    pub fn new(gain: f32, threshold: f32) -> Self {
+        Self { gain, threshold }
+    }
+
+    pub fn process_block(&mut self, samples: &mut [f32]) {
+        let threshold = self.threshold;
+        for sample in samples.iter_mut() {
+            *sample *= self.gain;
+            if *sample > threshold {
+                *sample = threshold + (1.0 - threshold) * ((*sample - threshold) / (1.0 - threshold)).tanh();
+            } else if *sample < -threshold {
+                *sample = -threshold + (-1.0 + threshold) * ((*sample + threshold) / (-1.0 + threshold)).tanh();
+            }
         }
     }
 }
 
-fn tremolo_effect(samples: &mut [f32], sample_rate: f32, rate_hz: f32, depth: f32) {
-    let lfo_increment = 2.0 * std::f32::consts::PI * rate_hz / sample_rate;
-    let mut lfo_phase = 0.0;
+pub fn tremolo_effect(samples: &mut [f32], sample_rate: f32, rate_hz: f32, depth: f32) {
+    Tremolo::new(sample_rate, rate_hz, depth).process_block(samples);
+}
 
-    for sample in samples.iter_mut() {
-        let lfo_value = (lfo_phase.sin() * 0.5 + 0.5) * depth + (1.0 - depth);
-        *sample *= lfo_value;
-        lfo_phase = (lfo_phase + lfo_increment) % (2.0 * std::f32::consts::PI);
-    }
+pub struct Tremolo {
+    lfo_increment: f32,
+    lfo_phase: f32,
+    depth: f32,
 }
 
+impl Tremolo {
+    pub fn new(sample_rate: f32, rate_hz: f32, depth: f32) -> Self {
+        Self {
+            lfo_increment: 2.0 * std::f32::consts::PI * rate_hz / sample_rate,
+            lfo_phase: 0.0,
+            depth,
+        }
+    }
+
+    pub fn process_block(&mut self, samples: &mut [f32]) {
+        for sample in samples.iter_mut() {
+            let lfo_value = (self.lfo_phase.sin() * 0.5 + 0.5) * self.depth + (1.0 - self.depth);
+            *sample *= lfo_value;
+            self.lfo_phase = (self.lfo_phase + self.lfo_increment) % (2.0 * std::f32::consts::PI);
+        }
+    }
+}
 
-fn flanger_effect(
+pub fn flanger_effect(
     samples: &mut [f32],
     sample_rate: f32,
     depth_ms: f32,
@@ -122,29 +264,51 @@ fn flanger_effect(
     feedback: f32,
     mix: f32,
 ) {
-    let max_delay_samples = (sample_rate * depth_ms / 1000.0) as usize;
-    let mut delay_buffer = vec![0.0; max_delay_samples];
-    let mut delay_index = 0;
+    Flanger::new(sample_rate, depth_ms, rate_hz, feedback, mix).process_block(samples);
+}
 
-    let lfo_increment = 2.0 * std::f32::consts::PI * rate_hz / sample_rate;
-    let mut lfo_phase = 0.0;
+pub struct Flanger {
+    delay_buffer: Vec<f32>,
+    delay_index: usize,
+    lfo_increment: f32,
+    lfo_phase: f32,
+    feedback: f32,
+    mix: f32,
+}
 
-    for sample in samples.iter_mut() {
-        let lfo_value = (lfo_phase.sin() * 0.5 + 0.5);
-        let current_delay = (lfo_value * max_delay_samples as f32) as usize;
+impl Flanger {
+    pub fn new(sample_rate: f32, depth_ms: f32, rate_hz: f32, feedback: f32, mix: f32) -> Self {
+        let max_delay_samples = ((sample_rate * depth_ms / 1000.0) as usize).max(1);
+        Self {
+            delay_buffer: vec![0.0; max_delay_samples],
+            delay_index: 0,
+            lfo_increment: 2.0 * std::f32::consts::PI * rate_hz / sample_rate,
+            lfo_phase: 0.0,
+            feedback,
+            mix,
+        }
+    }
+
+    pub fn process_block(&mut self, samples: &mut [f32]) {
+        let max_delay_samples = self.delay_buffer.len();
+        for sample in samples.iter_mut() {
+            let lfo_value = self.lfo_phase.sin() * 0.5 + 0.5;
+            let current_delay = (lfo_value * max_delay_samples as f32) as usize;
 
-        let delayed_sample = delay_buffer[(delay_index + max_delay_samples - current_delay) % max_delay_samples];
-        let new_sample = *sample + delayed_sample * feedback;
+            let delayed_sample =
+                self.delay_buffer[(self.delay_index + max_delay_samples - current_delay) % max_delay_samples];
+            let new_sample = *sample + delayed_sample * self.feedback;
 
-        delay_buffer[delay_index] = new_sample;
-        *sample = *sample * (1.0 - mix) + delayed_sample * mix;
+            self.delay_buffer[self.delay_index] = new_sample;
+            *sample = *sample * (1.0 - self.mix) + delayed_sample * self.mix;
 
-        delay_index = (delay_index + 1) % max_delay_samples;
-        lfo_phase = (lfo_phase + lfo_increment) % (2.0 * std::f32::consts::PI);
+            self.delay_index = (self.delay_index + 1) % max_delay_samples;
+            self.lfo_phase = (self.lfo_phase + self.lfo_increment) % (2.0 * std::f32::consts::PI);
+        }
     }
 }
 
-fn stereo_panning(samples: &[f32], pan: f32) -> (Vec<f32>, Vec<f32>) {
+pub fn stereo_panning(samples: &[f32], pan: f32) -> (Vec<f32>, Vec<f32>) {
     let left_gain = ((1.0 - pan) * std::f32::consts::FRAC_PI_2).cos();
     let right_gain = (pan * std::f32::consts::FRAC_PI_2).cos();
 
@@ -154,28 +318,433 @@ fn stereo_panning(samples: &[f32], pan: f32) -> (Vec<f32>, Vec<f32>) {
     (left_channel, right_channel)
 }
 
-fn noise_gate(
+/// Stereo width through mid/side: the side signal is scaled by `width`
+/// (0 = mono, 1 = unchanged, above 1 = wider).
+///
+/// Widening can push the decoded peak above that of the input. With
+/// `auto_compensate` the output is scaled down just enough that its peak
+/// never exceeds the input's peak; nothing is ever boosted.
+pub struct StereoWidth {
+    width: f32,
+    auto_compensate: bool,
+}
+
+impl StereoWidth {
+    pub fn new(width: f32) -> Self {
+        Self {
+            width: width.max(0.0),
+            auto_compensate: false,
+        }
+    }
+
+    pub fn with_auto_compensation(mut self, auto_compensate: bool) -> Self {
+        self.auto_compensate = auto_compensate;
+        self
+    }
+
+    /// Returns the processed channels and the peak of the output.
+    pub fn process(&self, left_channel: &[f32], right_channel: &[f32]) -> (Vec<f32>, Vec<f32>, PeakInfo) {
+        let (mid, mut side) = mid_side_encode(left_channel, right_channel);
+        for s in side.iter_mut() {
+            *s *= self.width;
+        }
+        let (mut left, mut right, peak) = mid_side_decode_checked(&mid, &side);
+        if !self.auto_compensate {
+            return (left, right, peak);
+        }
+
+        let input_peak = PeakInfo::of(left_channel, right_channel).peak;
+        let gain = Self::compensation_gain(input_peak, peak.peak);
+        for sample in left.iter_mut().chain(right.iter_mut()) {
+            *sample *= gain;
+        }
+        let peak = PeakInfo { peak: peak.peak * gain, frame: peak.frame };
+        (left, right, peak)
+    }
+
+    /// Gain that brings `output_peak` back down to `input_peak`, never above 1.
+    pub fn compensation_gain(input_peak: f32, output_peak: f32) -> f32 {
+        if output_peak > input_peak && output_peak > 0.0 {
+            input_peak / output_peak
+        } else {
+            1.0
+        }
+    }
+}
+
+pub fn noise_gate(
     samples: &mut [f32],
     threshold: f32,
     sample_rate: f32,
     attack_ms: f32,
     release_ms: f32,
 ) {
-    let attack_coeff = (-1.0 / (attack_ms * 0.001 * sample_rate)).exp();
-    let release_coeff = (-1.0 / (release_ms * 0.001 * sample_rate)).exp();
+    NoiseGate::new(threshold, sample_rate, attack_ms, release_ms).process_block(samples);
+}
 
-    let mut gain = 1.0;
+pub struct NoiseGate {
+    threshold: f32,
+    attack_coeff: f32,
+    release_coeff: f32,
+    gain: f32,
+}
 
-    for sample in samples.iter_mut() {
-        let input_level = sample.abs();
+impl NoiseGate {
+    pub fn new(threshold: f32, sample_rate: f32, attack_ms: f32, release_ms: f32) -> Self {
+        Self {
+            threshold,
+            attack_coeff: (-1.0 / (attack_ms * 0.001 * sample_rate)).exp(),
+            release_coeff: (-1.0 / (release_ms * 0.001 * sample_rate)).exp(),
+            gain: 1.0,
+        }
+    }
 
-        if input_level < threshold {
-            gain = attack_coeff * (gain - 0.0) + 0.0;
-        } else {
-            gain = release_coeff * (gain - 1.0) + 1.0;
+    pub fn process_block(&mut self, samples: &mut [f32]) {
+        for sample in samples.iter_mut() {
+            let input_level = sample.abs();
+
+            if input_level < self.threshold {
+                self.gain *= self.attack_coeff;
+            } else {
+                self.gain = self.release_coeff * (self.gain - 1.0) + 1.0;
+            }
+
+            *sample *= self.gain;
         }
+    }
+}
+
+// Envelope filter: the input level sweeps a resonant band-pass between
+// min_freq and max_freq on a logarithmic scale.
+pub fn auto_wah(samples: &mut [f32], sample_rate: f32, sensitivity: f32, min_freq: f32, max_freq: f32, q: f32) {
+    AutoWah::new(sample_rate, sensitivity, min_freq, max_freq, q).process_block(samples);
+}
+
+pub struct AutoWah {
+    follower: EnvelopeFollower,
+    filter: StateVariableFilter,
+    sensitivity: f32,
+    log_min: f32,
+    log_max: f32,
+    q: f32,
+    // Smoothed log2 of the center frequency, so envelope spikes glide
+    // instead of jumping the filter.
+    log_freq: f32,
+    smoothing_coeff: f32,
+}
 
-        *sample *= gain;
+impl AutoWah {
+    const ATTACK_MS: f32 = 2.0;
+    const RELEASE_MS: f32 = 80.0;
+    const SMOOTHING_MS: f32 = 5.0;
+
+    pub fn new(sample_rate: f32, sensitivity: f32, min_freq: f32, max_freq: f32, q: f32) -> Self {
+        let log_min = min_freq.max(1.0).log2();
+        let log_max = max_freq.max(min_freq).log2();
+        Self {
+            follower: EnvelopeFollower::new(Self::ATTACK_MS, Self::RELEASE_MS, sample_rate),
+            filter: StateVariableFilter::new(sample_rate, min_freq, q),
+            sensitivity,
+            log_min,
+            log_max,
+            q,
+            log_freq: log_min,
+            smoothing_coeff: (-1.0 / (Self::SMOOTHING_MS * 0.001 * sample_rate)).exp(),
+        }
+    }
+
+    /// Current center frequency of the filter in Hz.
+    pub fn center_freq(&self) -> f32 {
+        self.log_freq.exp2()
+    }
+
+    pub fn process_block(&mut self, samples: &mut [f32]) {
+        for sample in samples.iter_mut() {
+            let envelope = self.follower.process(*sample);
+            let amount = (envelope * self.sensitivity).clamp(0.0, 1.0);
+            let target = self.log_min + amount * (self.log_max - self.log_min);
+            self.log_freq = self.smoothing_coeff * (self.log_freq - target) + target;
+
+            self.filter.set_params(self.log_freq.exp2(), self.q);
+            *sample = self.filter.process_sample(*sample).band;
+        }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    // Deterministic noise and chunk sizes; the tree has no rand dependency.
+    struct Lcg(u64);
+
+    impl Lcg {
+        fn next(&mut self) -> u32 {
+            self.0 = self.0.wrapping_mul(6_364_136_223_846_793_005).wrapping_add(1_442_695_040_888_963_407);
+            (self.0 >> 33) as u32
+        }
+
+        fn below(&mut self, n: usize) -> usize {
+            self.next() as usize % n
+        }
+
+        // Bursts of tone every other 1000 samples over quiet noise, so the
+        // gate and compressor both open and close.
+        fn signal(&mut self, len: usize) -> Vec<f32> {
+            (0..len)
+                .map(|i| {
+                    let tone = (i as f32 * 0.05).sin() * 0.6 * ((i / 1000) % 2) as f32;
+                    tone + (self.next() as f32 / u32::MAX as f32 - 0.5) * 0.1
+                })
+                .collect()
+        }
+    }
+
+    const RATE: f32 = 48_000.0;
+
+    // One-call output against the same signal in random-sized chunks,
+    // including empty ones, through a fresh instance each.
+    fn assert_chunking_is_invisible(name: &str, mut make: impl FnMut() -> Box<dyn FnMut(&mut [f32])>) {
+        let mut rng = Lcg(0x5eed);
+        let input = rng.signal(10_000);
+
+        let mut whole = input.clone();
+        make()(&mut whole);
+
+        for _ in 0..5 {
+            let mut chunked = input.clone();
+            let mut process = make();
+            let mut start = 0;
+            while start < chunked.len() {
+                let end = (start + rng.below(700)).min(chunked.len());
+                process(&mut chunked[start..end]);
+                start = end;
+            }
+            assert_eq!(whole, chunked, "{} depends on the chunk boundaries", name);
+        }
+    }
+
+    #[test]
+    fn delay_is_chunk_independent() {
+        assert_chunking_is_invisible("Delay", || {
+            let mut fx = Delay::new(RATE, 7.3, 0.6);
+            Box::new(move |block| fx.process_block(block))
+        });
+    }
+
+    #[test]
+    fn reverb_is_chunk_independent() {
+        assert_chunking_is_invisible("CombReverb", || {
+            let mut fx = CombReverb::new(RATE, 0.5, 0.4);
+            Box::new(move |block| fx.process_block(block))
+        });
+    }
+
+    #[test]
+    fn tremolo_is_chunk_independent() {
+        assert_chunking_is_invisible("Tremolo", || {
+            let mut fx = Tremolo::new(RATE, 5.0, 0.8);
+            Box::new(move |block| fx.process_block(block))
+        });
+    }
+
+    #[test]
+    fn flanger_is_chunk_independent() {
+        assert_chunking_is_invisible("Flanger", || {
+            let mut fx = Flanger::new(RATE, 3.0, 0.7, 0.5, 0.5);
+            Box::new(move |block| fx.process_block(block))
+        });
+    }
+
+    #[test]
+    fn compressor_is_chunk_independent() {
+        assert_chunking_is_invisible("Compressor", || {
+            let mut fx = Compressor::new(0.2, 4.0, 5.0, 50.0, RATE);
+            Box::new(move |block| fx.process_block(block))
+        });
+    }
+
+    #[test]
+    fn short_key_leaves_the_rest_of_the_block_at_unity() {
+        let main = Lcg(3).signal(256);
+        let key = vec![0.9; 100];
+        let mut keyed = main.clone();
+        let mut fx = Compressor::new(0.2, 4.0, 0.1, 50.0, RATE);
+        fx.process_block_keyed(&mut keyed, &key);
+        let mut reference = main.clone();
+        Compressor::new(0.2, 4.0, 0.1, 50.0, RATE).process_block_keyed(&mut reference[..100], &key);
+        assert_eq!(keyed[..100], reference[..100]);
+        assert_eq!(keyed[100..], main[100..]);
+    }
+
+    // Mean gain reduction over the last second of two, read every 10 ms,
+    // with and without a 120 Hz high-pass on the detector.
+    fn sidechain_gain_reduction(signal: impl Fn(f32) -> f32) -> (f32, f32) {
+        let input: Vec<f32> = (0..2 * RATE as usize).map(|i| signal(i as f32 / RATE)).collect();
+        let measure = |filtered: bool| {
+            let mut fx = Compressor::new(0.2, 4.0, 5.0, 50.0, RATE);
+            if filtered {
+                fx.set_sidechain_filter(FilterSpec::HighPass { cutoff_freq: 120.0, q_factor: 0.707 });
+            }
+            let mut output = input.clone();
+            let readings: Vec<f32> = output
+                .chunks_mut(480)
+                .map(|block| {
+                    fx.process_block(block);
+                    fx.gain_reduction_db()
+                })
+                .collect();
+            let settled = &readings[readings.len() / 2..];
+            settled.iter().sum::<f32>() / settled.len() as f32
+        };
+        (measure(false), measure(true))
+    }
+
+    #[test]
+    fn high_pass_sidechain_ignores_the_bass() {
+        let bass_heavy = |t: f32| 0.8 * (2.0 * PI * 50.0 * t).sin() + 0.15 * (2.0 * PI * 1_000.0 * t).sin();
+        let (plain, filtered) = sidechain_gain_reduction(bass_heavy);
+        assert!(plain > 3.0, "{plain} dB without the filter");
+        assert!(filtered < plain - 3.0, "{filtered} dB filtered against {plain} dB");
+
+        let midrange = |t: f32| 0.8 * (2.0 * PI * 2_000.0 * t).sin();
+        let (plain, filtered) = sidechain_gain_reduction(midrange);
+        assert!(plain > 3.0, "{plain} dB without the filter");
+        assert!((filtered - plain).abs() < 0.05, "{filtered} dB filtered against {plain} dB");
+    }
+
+    #[test]
+    fn sidechain_filter_leaves_the_main_signal_unfiltered() {
+        // Below the threshold the gain is unity, so the output is the input.
+        let input: Vec<f32> = (0..4_800).map(|i| 0.1 * (2.0 * PI * 50.0 * i as f32 / RATE).sin()).collect();
+        let mut output = input.clone();
+        let mut fx = Compressor::new(0.2, 4.0, 5.0, 50.0, RATE);
+        fx.set_sidechain_filter(FilterSpec::HighPass { cutoff_freq: 120.0, q_factor: 0.707 });
+        fx.process_block(&mut output);
+        assert_eq!(output, input);
+    }
+
+    // Energy of the Hann-windowed `samples` between `low` and `high` Hz.
+    fn band_energy(samples: &[f32], low: f32, high: f32) -> f32 {
+        let window = crate::stft::hann_window(samples.len());
+        let windowed: Vec<f32> = samples.iter().zip(&window).map(|(s, w)| s * w).collect();
+        let bin_hz = RATE / samples.len() as f32;
+        crate::fft::fft(&windowed)[..samples.len() / 2]
+            .iter()
+            .enumerate()
+            .filter(|&(bin, _)| (low..high).contains(&(bin as f32 * bin_hz)))
+            .map(|(_, value)| value.norm_sqr())
+            .sum()
+    }
+
+    #[test]
+    fn auto_wah_opens_up_for_the_loud_burst() {
+        // A 110 Hz sawtooth, quiet for half a second and then loud, so
+        // there are harmonics everywhere the filter can go.
+        let half = RATE as usize / 2;
+        let saw = |i: usize| (i as f32 * 110.0 / RATE).fract() * 2.0 - 1.0;
+        let mut samples: Vec<f32> = (0..2 * half).map(|i| saw(i) * if i < half { 0.02 } else { 0.8 }).collect();
+        let mut fx = AutoWah::new(RATE, 1.5, 200.0, 3_000.0, 4.0);
+        fx.process_block(&mut samples[..half]);
+        let quiet_center = fx.center_freq();
+        fx.process_block(&mut samples[half..]);
+        let loud_center = fx.center_freq();
+        assert!(quiet_center < 300.0, "centered at {quiet_center} Hz while quiet");
+        assert!(loud_center > 2_000.0, "centered at {loud_center} Hz while loud");
+
+        // The last 4096 samples of each half, well past the transition.
+        let tilt = |region: &[f32]| band_energy(region, 1_500.0, 4_000.0) / band_energy(region, 150.0, 400.0);
+        let quiet = tilt(&samples[half - 4_096..half]);
+        let loud = tilt(&samples[2 * half - 4_096..]);
+        assert!(loud > 100.0 * quiet, "high/low band energy {quiet} quiet, {loud} loud");
+        assert!(samples.iter().all(|sample| sample.is_finite()));
+    }
+
+    #[test]
+    fn auto_wah_stays_finite_and_bounded_at_extreme_settings() {
+        let mut rng = Lcg(11);
+        // Full-scale noise with spikes and silent stretches, so the
+        // envelope jumps between the extremes.
+        let input: Vec<f32> = (0..RATE as usize)
+            .map(|i| match i % 4_800 {
+                0..=9 => 1.0,
+                2_400..=3_599 => 0.0,
+                _ => rng.next() as f32 / u32::MAX as f32 * 2.0 - 1.0,
+            })
+            .collect();
+        let settings = [(1_000.0, 1.0, 24_000.0, 0.01), (1_000.0, 20.0, 20_000.0, 50.0), (0.0, 100.0, 100.0, 10.0), (10.0, 0.0, 0.0, 1.0)];
+        for (sensitivity, min_freq, max_freq, q) in settings {
+            let mut samples = input.clone();
+            auto_wah(&mut samples, RATE, sensitivity, min_freq, max_freq, q);
+            let peak = samples.iter().fold(0.0f32, |peak, &sample| peak.max(sample.abs()));
+            assert!(peak.is_finite() && peak <= 2.0, "peak {peak} at {sensitivity}, {min_freq}-{max_freq} Hz, q {q}");
+        }
+    }
+
+    #[test]
+    fn stereo_width_compensation_keeps_the_peak_at_the_input_peak() {
+        let mut rng = Lcg(5);
+        let mut noise = || rng.next() as f32 / u32::MAX as f32 * 1.6 - 0.8;
+        // Partly correlated channels, so widening raises the peak.
+        let left: Vec<f32> = (0..4_000).map(|_| noise()).collect();
+        let right: Vec<f32> = left.iter().map(|l| 0.5 * l + 0.4 * noise()).collect();
+        let input_peak = PeakInfo::of(&left, &right).peak;
+
+        let (_, _, widened) = StereoWidth::new(2.5).process(&left, &right);
+        assert!(widened.peak > input_peak * 1.2);
+        let (l, r, peak) = StereoWidth::new(2.5).with_auto_compensation(true).process(&left, &right);
+        assert!(peak.peak <= input_peak * (1.0 + 1e-6), "{} above {}", peak.peak, input_peak);
+        assert!((peak.peak - input_peak).abs() < 1e-5);
+        assert_eq!(peak.frame, widened.frame);
+        assert_eq!(PeakInfo::of(&l, &r).peak, peak.peak);
+
+        // Narrowing never lowers the peak below the input's, so nothing is boosted.
+        let narrowed = StereoWidth::new(0.5).process(&left, &right);
+        assert_eq!(StereoWidth::new(0.5).with_auto_compensation(true).process(&left, &right), narrowed);
+        assert_eq!(StereoWidth::compensation_gain(0.5, 1.0), 0.5);
+        assert_eq!(StereoWidth::compensation_gain(0.5, 0.25), 1.0);
+        assert_eq!(StereoWidth::compensation_gain(0.0, 0.0), 1.0);
+    }
+
+    #[test]
+    fn noise_gate_is_chunk_independent() {
+        assert_chunking_is_invisible("NoiseGate", || {
+            let mut fx = NoiseGate::new(0.1, RATE, 2.0, 20.0);
+            Box::new(move |block| fx.process_block(block))
+        });
+    }
+
+    #[test]
+    fn free_functions_match_one_block_of_the_struct() {
+        let input = Lcg(7).signal(4_000);
+        type Process = fn(&mut [f32]);
+        let cases: [(&str, Process, Process); 6] = [
+            ("delay", |s| delay_effect(s, RATE, 7.3, 0.6), |s| Delay::new(RATE, 7.3, 0.6).process_block(s)),
+            ("reverb", |s| reverb_effect(s, RATE, 0.5, 0.4), |s| CombReverb::new(RATE, 0.5, 0.4).process_block(s)),
+            ("tremolo", |s| tremolo_effect(s, RATE, 5.0, 0.8), |s| Tremolo::new(RATE, 5.0, 0.8).process_block(s)),
+            (
+                "flanger",
+                |s| flanger_effect(s, RATE, 3.0, 0.7, 0.5, 0.5),
+                |s| Flanger::new(RATE, 3.0, 0.7, 0.5, 0.5).process_block(s),
+            ),
+            (
+                "compressor",
+                |s| compressor(s, 0.2, 4.0, 5.0, 50.0, RATE),
+                |s| Compressor::new(0.2, 4.0, 5.0, 50.0, RATE).process_block(s),
+            ),
+            (
+                "noise gate",
+                |s| noise_gate(s, 0.1, RATE, 2.0, 20.0),
+                |s| NoiseGate::new(0.1, RATE, 2.0, 20.0).process_block(s),
+            ),
+        ];
+        for (name, function, structured) in cases {
+            let mut a = input.clone();
+            let mut b = input.clone();
+            function(&mut a);
+            structured(&mut b);
+            assert_eq!(a, b, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,432 @@
+// User commands run after each file of a batch, e.g. to upload or tag it.
+//
+// A post-command is a template like `upload.sh {out} --lufs {lufs}`. It is
+// split into words first, quotes grouping words the way a shell would, and
+// the placeholders are filled in per word, so a path with spaces stays one
+// argument. No shell is involved. The same values are also passed in
+// `PLAYBOOK_*` environment variables for scripts that prefer those.
+//
+// Commands run in the background while the batch goes on, at most a fixed
+// number at a time, and are killed when they run past a timeout.
+use std::fmt;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_HOOK_TIMEOUT: Duration = Duration::from_secs(60);
+pub const DEFAULT_MAX_CONCURRENT_HOOKS: usize = 4;
+/// How often a running command is checked for having exited.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Placeholders a template may use, with the environment variable carrying
+/// the same value.
+pub const HOOK_VARIABLES: [(&str, &str); 5] = [
+    ("in", "PLAYBOOK_INPUT"),
+    ("out", "PLAYBOOK_OUTPUT"),
+    ("lufs", "PLAYBOOK_LUFS"),
+    ("peak", "PLAYBOOK_PEAK_DBFS"),
+    ("duration", "PLAYBOOK_DURATION"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TemplateError {
+    /// A `{` without its `}`, at this byte offset.
+    Unclosed(usize),
+    /// A `}` that doesn't close anything, at this byte offset.
+    Unopened(usize),
+    UnknownVariable(String),
+    UnterminatedQuote,
+    Empty,
+}
+
+impl fmt::Display for TemplateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TemplateError::Unclosed(at) => write!(f, "unclosed '{{' at {}", at),
+            TemplateError::Unopened(at) => write!(f, "unmatched '}}' at {} (write '}}}}' for a literal one)", at),
+            TemplateError::UnknownVariable(name) => {
+                let known: Vec<&str> = HOOK_VARIABLES.iter().map(|(name, _)| *name).collect();
+                write!(f, "unknown placeholder {{{}}}, expected one of {}", name, known.join(", "))
+            }
+            TemplateError::UnterminatedQuote => write!(f, "unterminated quote"),
+            TemplateError::Empty => write!(f, "empty command"),
+        }
+    }
+}
+
+impl std::error::Error for TemplateError {}
+
+/// Replaces every `{name}` in `template` with its value from `vars`.
+/// `{{` and `}}` stand for literal braces.
+pub fn substitute(template: &str, vars: &[(&str, String)]) -> Result<String, TemplateError> {
+    let mut output = String::with_capacity(template.len());
+    let mut chars = template.char_indices().peekable();
+    while let Some((at, c)) = chars.next() {
+        match c {
+            '{' if chars.peek().is_some_and(|&(_, next)| next == '{') => {
+                chars.next();
+                output.push('{');
+            }
+            '}' if chars.peek().is_some_and(|&(_, next)| next == '}') => {
+                chars.next();
+                output.push('}');
+            }
+            '{' => {
+                let rest = &template[at + 1..];
+                let end = rest.find('}').ok_or(TemplateError::Unclosed(at))?;
+                let name = &rest[..end];
+                let value = vars
+                    .iter()
+                    .find(|(key, _)| *key == name)
+                    .ok_or_else(|| TemplateError::UnknownVariable(name.to_string()))?;
+                output.push_str(&value.1);
+                // Skip the name and the closing brace.
+                for _ in 0..name.chars().count() + 1 {
+                    chars.next();
+                }
+            }
+            '}' => return Err(TemplateError::Unopened(at)),
+            c => output.push(c),
+        }
+    }
+    Ok(output)
+}
+
+/// Splits a command line into words on whitespace. Single or double quotes
+/// group words and are removed; there are no escapes.
+pub fn split_words(line: &str) -> Result<Vec<String>, TemplateError> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+    let mut quote: Option<char> = None;
+    for c in line.chars() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => word.push(c),
+            None if c == '\'' || c == '"' => {
+                quote = Some(c);
+                in_word = true;
+            }
+            None if c.is_whitespace() => {
+                if in_word {
+                    words.push(std::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            None => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if quote.is_some() {
+        return Err(TemplateError::UnterminatedQuote);
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+/// What a finished file is described to its post-command with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HookContext<'a> {
+    pub input: &'a Path,
+    pub output: &'a Path,
+    pub lufs: f64,
+    pub peak_dbfs: f32,
+    pub duration_seconds: f64,
+}
+
+impl HookContext<'_> {
+    /// Values in the order of `HOOK_VARIABLES`.
+    pub fn variables(&self) -> Vec<(&'static str, String)> {
+        let values = [
+            self.input.display().to_string(),
+            self.output.display().to_string(),
+            format!("{:.2}", self.lufs),
+            format!("{:.2}", self.peak_dbfs),
+            format!("{:.3}", self.duration_seconds),
+        ];
+        HOOK_VARIABLES.iter().map(|(name, _)| *name).zip(values).collect()
+    }
+}
+
+/// A parsed post-command template.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PostCommand {
+    words: Vec<String>,
+}
+
+impl PostCommand {
+    /// Parses `template`, checking its quotes and placeholders up front so
+    /// a typo is reported before the batch starts rather than per file.
+    pub fn parse(template: &str) -> Result<PostCommand, TemplateError> {
+        let words = split_words(template)?;
+        if words.is_empty() {
+            return Err(TemplateError::Empty);
+        }
+        let placeholders: Vec<(&str, String)> = HOOK_VARIABLES.iter().map(|(name, _)| (*name, String::new())).collect();
+        for word in &words {
+            substitute(word, &placeholders)?;
+        }
+        Ok(PostCommand { words })
+    }
+
+    /// The command for one file, with its arguments filled in and the
+    /// `PLAYBOOK_*` variables set.
+    pub fn command(&self, context: &HookContext) -> Command {
+        let variables = context.variables();
+        // `parse` already checked each word.
+        let mut words = self.words.iter().map(|word| substitute(word, &variables).unwrap_or_default());
+        let mut command = Command::new(words.next().unwrap_or_default());
+        command.args(words);
+        for ((_, env), (_, value)) in HOOK_VARIABLES.iter().zip(&variables) {
+            command.env(env, value);
+        }
+        command
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum HookOutcome {
+    Succeeded,
+    /// Exited unsuccessfully, with the exit code unless a signal ended it.
+    Failed(Option<i32>),
+    /// Killed after running for the whole timeout.
+    TimedOut,
+    /// Could not be started at all, e.g. the program doesn't exist.
+    SpawnFailed(String),
+}
+
+impl HookOutcome {
+    pub fn is_success(&self) -> bool {
+        *self == HookOutcome::Succeeded
+    }
+}
+
+impl fmt::Display for HookOutcome {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HookOutcome::Succeeded => write!(f, "succeeded"),
+            HookOutcome::Failed(Some(code)) => write!(f, "exited with status {}", code),
+            HookOutcome::Failed(None) => write!(f, "killed by a signal"),
+            HookOutcome::TimedOut => write!(f, "timed out"),
+            HookOutcome::SpawnFailed(e) => write!(f, "could not start: {}", e),
+        }
+    }
+}
+
+/// Runs `command`, killing it if it hasn't exited within `timeout`.
+pub fn run_with_timeout(mut command: Command, timeout: Duration) -> HookOutcome {
+    let mut child = match command.stdin(Stdio::null()).spawn() {
+        Ok(child) => child,
+        Err(e) => return HookOutcome::SpawnFailed(e.to_string()),
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        match child.try_wait() {
+            Ok(Some(status)) if status.success() => return HookOutcome::Succeeded,
+            Ok(Some(status)) => return HookOutcome::Failed(status.code()),
+            Ok(None) if Instant::now() >= deadline => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return HookOutcome::TimedOut;
+            }
+            Ok(None) => thread::sleep(POLL_INTERVAL),
+            Err(e) => return HookOutcome::SpawnFailed(e.to_string()),
+        }
+    }
+}
+
+// Counts running commands; `acquire` blocks while all slots are taken.
+struct Slots {
+    running: Mutex<usize>,
+    freed: Condvar,
+    limit: usize,
+}
+
+impl Slots {
+    fn acquire(&self) {
+        let mut running = self.running.lock().unwrap();
+        while *running >= self.limit {
+            running = self.freed.wait(running).unwrap();
+        }
+        *running += 1;
+    }
+
+    fn release(&self) {
+        *self.running.lock().unwrap() -= 1;
+        self.freed.notify_one();
+    }
+}
+
+/// Runs commands in the background, at most `max_concurrent` at a time.
+pub struct CommandRunner {
+    timeout: Duration,
+    slots: Arc<Slots>,
+    jobs: Vec<(usize, JoinHandle<HookOutcome>)>,
+}
+
+impl CommandRunner {
+    pub fn new(max_concurrent: usize, timeout: Duration) -> Self {
+        Self {
+            timeout,
+            slots: Arc::new(Slots { running: Mutex::new(0), freed: Condvar::new(), limit: max_concurrent.max(1) }),
+            jobs: Vec::new(),
+        }
+    }
+
+    /// Starts `command`, tagged with `id` in the results. Blocks while the
+    /// maximum number of commands is already running.
+    pub fn submit(&mut self, id: usize, command: Command) {
+        self.slots.acquire();
+        let slots = Arc::clone(&self.slots);
+        let timeout = self.timeout;
+        let job = thread::spawn(move || {
+            let outcome = run_with_timeout(command, timeout);
+            slots.release();
+            outcome
+        });
+        self.jobs.push((id, job));
+    }
+
+    /// Waits for every submitted command, returning the outcomes in the
+    /// order they were submitted.
+    pub fn wait(self) -> Vec<(usize, HookOutcome)> {
+        self.jobs
+            .into_iter()
+            .map(|(id, job)| (id, job.join().unwrap_or_else(|_| HookOutcome::SpawnFailed("runner thread panicked".to_string()))))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn context() -> HookContext<'static> {
+        HookContext {
+            input: Path::new("in/take 1.wav"),
+            output: Path::new("out/take 1.wav"),
+            lufs: -14.004,
+            peak_dbfs: -1.25,
+            duration_seconds: 61.5,
+        }
+    }
+
+    fn words(command: &Command) -> Vec<String> {
+        std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|word| word.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn placeholders_are_filled_in_and_braces_escaped() {
+        let vars = [("out", "a.wav".to_string()), ("lufs", "-14.00".to_string())];
+        assert_eq!(substitute("{out}", &vars).unwrap(), "a.wav");
+        assert_eq!(substitute("--file={out}:{lufs}{out}", &vars).unwrap(), "--file=a.wav:-14.00a.wav");
+        assert_eq!(substitute("{{out}} }}{{", &vars).unwrap(), "{out} }{");
+        assert_eq!(substitute("{{{lufs}}}", &vars).unwrap(), "{-14.00}");
+        assert_eq!(substitute("é{out}ü", &vars).unwrap(), "éa.wavü");
+
+        assert_eq!(substitute("x {out", &vars), Err(TemplateError::Unclosed(2)));
+        assert_eq!(substitute("x } {out}", &vars), Err(TemplateError::Unopened(2)));
+        assert_eq!(substitute("{peak}", &vars), Err(TemplateError::UnknownVariable("peak".to_string())));
+    }
+
+    #[test]
+    fn words_are_split_on_whitespace_outside_quotes() {
+        assert_eq!(split_words("  a  b\tc ").unwrap(), ["a", "b", "c"]);
+        assert_eq!(split_words(r#"tag "two words" 'it"s' x""y ''"#).unwrap(), ["tag", "two words", "it\"s", "xy", ""]);
+        assert_eq!(split_words("a 'b"), Err(TemplateError::UnterminatedQuote));
+        assert!(split_words(" ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn templates_are_checked_when_parsed() {
+        assert_eq!(PostCommand::parse("  "), Err(TemplateError::Empty));
+        assert_eq!(PostCommand::parse("tag {size}"), Err(TemplateError::UnknownVariable("size".to_string())));
+        assert_eq!(PostCommand::parse("tag '{out}"), Err(TemplateError::UnterminatedQuote));
+        assert!(PostCommand::parse("tag {in} {out} {lufs} {peak} {duration}").is_ok());
+    }
+
+    #[test]
+    fn commands_get_their_arguments_and_environment() {
+        // A path with a space stays a single argument.
+        let command = PostCommand::parse("upload.sh {out} --lufs={lufs} '{peak} dB' {duration}s").unwrap().command(&context());
+        assert_eq!(words(&command), ["upload.sh", "out/take 1.wav", "--lufs=-14.00", "-1.25 dB", "61.500s"]);
+
+        let envs: Vec<(String, String)> = command
+            .get_envs()
+            .map(|(key, value)| (key.to_string_lossy().into_owned(), value.unwrap().to_string_lossy().into_owned()))
+            .collect();
+        assert_eq!(
+            envs,
+            [
+                ("PLAYBOOK_DURATION", "61.500"),
+                ("PLAYBOOK_INPUT", "in/take 1.wav"),
+                ("PLAYBOOK_LUFS", "-14.00"),
+                ("PLAYBOOK_OUTPUT", "out/take 1.wav"),
+                ("PLAYBOOK_PEAK_DBFS", "-1.25"),
+            ]
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+        );
+    }
+
+    #[test]
+    fn outcomes_follow_the_exit_status() {
+        let timeout = Duration::from_secs(10);
+        assert_eq!(run_with_timeout(Command::new("true"), timeout), HookOutcome::Succeeded);
+        assert_eq!(run_with_timeout(Command::new("false"), timeout), HookOutcome::Failed(Some(1)));
+        let mut command = Command::new("sh");
+        command.args(["-c", "exit 7"]);
+        assert_eq!(run_with_timeout(command, timeout), HookOutcome::Failed(Some(7)));
+        assert!(matches!(
+            run_with_timeout(Command::new("cpal_playbook_no_such_program"), timeout),
+            HookOutcome::SpawnFailed(_)
+        ));
+    }
+
+    #[test]
+    fn a_command_past_its_timeout_is_killed() {
+        let mut command = Command::new("sleep");
+        command.arg("10");
+        let started = Instant::now();
+        assert_eq!(run_with_timeout(command, Duration::from_millis(100)), HookOutcome::TimedOut);
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(100) && elapsed < Duration::from_secs(2), "took {:?}", elapsed);
+    }
+
+    #[test]
+    fn the_runner_caps_concurrent_commands() {
+        // Four commands of 200 ms, two at a time: two rounds.
+        let sleep = || {
+            let mut command = Command::new("sleep");
+            command.arg("0.2");
+            command
+        };
+        let started = Instant::now();
+        let mut runner = CommandRunner::new(2, Duration::from_secs(10));
+        for id in 0..4 {
+            runner.submit(id, sleep());
+        }
+        runner.submit(4, Command::new("false"));
+        let outcomes = runner.wait();
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(400) && elapsed < Duration::from_millis(3000), "took {:?}", elapsed);
+        assert_eq!(
+            outcomes,
+            [
+                (0, HookOutcome::Succeeded),
+                (1, HookOutcome::Succeeded),
+                (2, HookOutcome::Succeeded),
+                (3, HookOutcome::Succeeded),
+                (4, HookOutcome::Failed(Some(1))),
+            ]
+        );
+    }
+}
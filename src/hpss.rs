@@ -0,0 +1,161 @@
+// Harmonic/percussive source separation by median filtering (Fitzgerald).
+//
+// On a spectrogram, sustained tones are horizontal lines and transients are
+// vertical ones. A median across time keeps the lines and removes the
+// spikes, a median across frequency does the opposite. The two filtered
+// spectrograms become complementary soft masks, so the harmonic and
+// percussive outputs add back up to the input.
+use rustfft::num_complex::Complex;
+
+use crate::stft::{hann_window, istft, stft};
+
+/// Analysis frame length, about 46 ms (2048 samples at 44.1 and 48 kHz).
+const FRAME_SECONDS: f32 = 0.046;
+/// Frames overlap by 75%, which keeps the Hann window COLA.
+const HOP_DIVISOR: usize = 4;
+/// Exponent of the soft masks; higher is closer to a binary mask.
+const MASK_POWER: i32 = 2;
+
+/// Median of `values`, reordering them.
+fn median(values: &mut [f32]) -> f32 {
+    let mid = values.len() / 2;
+    *values.select_nth_unstable_by(mid, |a, b| a.total_cmp(b)).1
+}
+
+/// Median over `kernel` neighbours along one axis of `magnitudes`
+/// (`frames × bins`); neighbours past the edges are left out.
+fn median_filter(magnitudes: &[Vec<f32>], kernel: usize, across_time: bool) -> Vec<Vec<f32>> {
+    let frames = magnitudes.len();
+    let bins = magnitudes.first().map_or(0, Vec::len);
+    let half = kernel.max(1) / 2;
+    let mut window = Vec::with_capacity(2 * half + 1);
+
+    (0..frames)
+        .map(|frame| {
+            (0..bins)
+                .map(|bin| {
+                    window.clear();
+                    if across_time {
+                        let range = frame.saturating_sub(half)..(frame + half + 1).min(frames);
+                        window.extend(range.map(|f| magnitudes[f][bin]));
+                    } else {
+                        let range = bin.saturating_sub(half)..(bin + half + 1).min(bins);
+                        window.extend_from_slice(&magnitudes[frame][range]);
+                    }
+                    median(&mut window)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Splits `samples` into a harmonic and a percussive signal of the same
+/// length, which sum back to `samples`.
+///
+/// `kernel_frames` is the length of the median across time (in STFT frames
+/// of about 12 ms hop), `kernel_bins` the length of the median across
+/// frequency. Around 17 for both is a reasonable start; longer kernels
+/// separate more aggressively.
+pub fn hpss(samples: &[f32], sample_rate: u32, kernel_frames: usize, kernel_bins: usize) -> (Vec<f32>, Vec<f32>) {
+    let fft_size = ((sample_rate as f32 * FRAME_SECONDS) as usize).next_power_of_two().max(64);
+    let hop = fft_size / HOP_DIVISOR;
+    let window = hann_window(fft_size);
+
+    let spectrum = stft(samples, &window, hop);
+    let magnitudes: Vec<Vec<f32>> = spectrum.iter().map(|frame| frame.iter().map(|c| c.norm()).collect()).collect();
+    let harmonic_magnitudes = median_filter(&magnitudes, kernel_frames, true);
+    let percussive_magnitudes = median_filter(&magnitudes, kernel_bins, false);
+
+    let mut harmonic = Vec::with_capacity(spectrum.len());
+    let mut percussive = Vec::with_capacity(spectrum.len());
+    for ((frame, h_frame), p_frame) in spectrum.iter().zip(&harmonic_magnitudes).zip(&percussive_magnitudes) {
+        let (h, p): (Vec<Complex<f32>>, Vec<Complex<f32>>) = frame
+            .iter()
+            .zip(h_frame.iter().zip(p_frame))
+            .map(|(&bin, (&h, &p))| {
+                let (h, p) = (h.powi(MASK_POWER), p.powi(MASK_POWER));
+                // Where both medians are zero, split evenly.
+                let mask = if h + p > 0.0 { h / (h + p) } else { 0.5 };
+                (bin * mask, bin * (1.0 - mask))
+            })
+            .unzip();
+        harmonic.push(h);
+        percussive.push(p);
+    }
+
+    (
+        istft(&harmonic, &window, hop, samples.len()).0,
+        istft(&percussive, &window, hop, samples.len()).0,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const RATE: u32 = 22_050;
+
+    fn sine(len: usize) -> Vec<f32> {
+        (0..len).map(|i| 0.5 * (2.0 * PI * 440.0 * i as f32 / RATE as f32).sin()).collect()
+    }
+
+    // Single-sample clicks four times a second.
+    fn clicks(len: usize) -> Vec<f32> {
+        (0..len).map(|i| if i % (RATE as usize / 4) == 1_000 { 0.9 } else { 0.0 }).collect()
+    }
+
+    fn energy(samples: &[f32]) -> f32 {
+        samples.iter().map(|s| s * s).sum()
+    }
+
+    // Energy of `a - b` relative to that of `b`, in dB.
+    fn error_db(a: &[f32], b: &[f32]) -> f32 {
+        let difference: Vec<f32> = a.iter().zip(b).map(|(a, b)| a - b).collect();
+        10.0 * (energy(&difference) / energy(b)).log10()
+    }
+
+    // Energy within 50 Hz of the sine, and above 1 kHz where only the
+    // clicks are.
+    fn tone_and_click_energy(samples: &[f32]) -> (f32, f32) {
+        let bin_hz = RATE as f32 / samples.len() as f32;
+        let spectrum = crate::fft::fft(samples);
+        let band = |keep: &dyn Fn(f32) -> bool| -> f32 {
+            spectrum[..samples.len() / 2]
+                .iter()
+                .enumerate()
+                .filter(|&(bin, _)| keep(bin as f32 * bin_hz))
+                .map(|(_, c)| c.norm_sqr())
+                .sum()
+        };
+        (band(&|freq| (freq - 440.0).abs() < 50.0), band(&|freq| freq > 1_000.0))
+    }
+
+    #[test]
+    fn a_sine_is_harmonic_and_a_click_is_percussive() {
+        let len = 2 * RATE as usize;
+        let (harmonic, percussive) = hpss(&sine(len), RATE, 17, 17);
+        assert!(energy(&harmonic) > 0.95 * energy(&sine(len)), "harmonic keeps {}", energy(&harmonic) / energy(&sine(len)));
+        assert!(energy(&percussive) < 0.01 * energy(&sine(len)));
+
+        let (harmonic, percussive) = hpss(&clicks(len), RATE, 17, 17);
+        assert!(energy(&percussive) > 0.9 * energy(&clicks(len)), "percussive keeps {}", energy(&percussive) / energy(&clicks(len)));
+        assert!(energy(&harmonic) < 0.05 * energy(&clicks(len)));
+    }
+
+    #[test]
+    fn a_mix_separates_into_its_parts_and_sums_back_to_the_input() {
+        let len = 2 * RATE as usize;
+        let mix: Vec<f32> = sine(len).iter().zip(clicks(len)).map(|(t, c)| t + c).collect();
+        let (harmonic, percussive) = hpss(&mix, RATE, 17, 17);
+        assert_eq!((harmonic.len(), percussive.len()), (len, len));
+
+        let ((h_tone, h_clicks), (p_tone, p_clicks)) = (tone_and_click_energy(&harmonic), tone_and_click_energy(&percussive));
+        assert!(h_tone > 0.99 * (h_tone + p_tone), "harmonic has {} of the sine", h_tone / (h_tone + p_tone));
+        assert!(p_clicks > 0.99 * (h_clicks + p_clicks), "percussive has {} of the clicks", p_clicks / (h_clicks + p_clicks));
+
+        let sum: Vec<f32> = harmonic.iter().zip(&percussive).map(|(h, p)| h + p).collect();
+        let null = error_db(&sum, &mix);
+        assert!(null <= -30.0, "the sum nulls to {null} dB");
+    }
+}
@@ -0,0 +1,642 @@
+// Opening input streams with explicit requests, and reporting back what the
+// device actually granted.
+//
+// `negotiate_input_config` is the whole decision, made on the list of
+// config ranges a device supports, so it can be checked against made-up
+// capability sets. Whatever can't be met exactly falls back in a fixed
+// order (channels, then sample rate, then sample format, then buffer size)
+// and each fallback is recorded with its reason. `InputStreamBuilder` runs
+// it against a real device and converts every sample format to f32 for the
+// callback.
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{
+    BufferSize, Device, FromSample, SampleFormat, SizedSample, Stream, StreamConfig, SupportedBufferSize,
+    SupportedStreamConfigRange,
+};
+
+use crate::adapter::AudioSpec;
+use crate::effect::debug_assert_finite;
+use crate::realtime::{callback_scope, log_stream_error, scratch_len};
+use crate::stream::StreamError;
+
+/// Rate used when nothing is requested and the device has no default.
+const FALLBACK_SAMPLE_RATE: u32 = 48000;
+
+/// Sample formats in the order they are picked when the request can't be
+/// met: float first, then the integer formats with the most resolution.
+const FORMAT_PREFERENCE: [SampleFormat; 10] = [
+    SampleFormat::F32,
+    SampleFormat::I32,
+    SampleFormat::I16,
+    SampleFormat::U16,
+    SampleFormat::F64,
+    SampleFormat::I64,
+    SampleFormat::U32,
+    SampleFormat::U64,
+    SampleFormat::I8,
+    SampleFormat::U8,
+];
+
+/// What the caller asks for; `None` leaves the choice to the device default.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct StreamRequest {
+    pub sample_rate: Option<u32>,
+    pub channels: Option<u16>,
+    pub sample_format: Option<SampleFormat>,
+    /// Frames per callback.
+    pub buffer_size: Option<u32>,
+}
+
+/// The device's default config, as far as `negotiate_input_config` cares.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DefaultConfig {
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+/// What a stream was opened with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NegotiatedConfig {
+    pub sample_rate: u32,
+    pub channels: u16,
+    /// Format the device delivers; the callback always sees f32.
+    pub sample_format: SampleFormat,
+    /// Frames per callback granted, `None` when left to the backend.
+    pub buffer_size: Option<u32>,
+    /// One line for every request that couldn't be met, saying what was
+    /// used instead and why.
+    pub fallbacks: Vec<String>,
+}
+
+impl NegotiatedConfig {
+    pub fn stream_config(&self) -> StreamConfig {
+        StreamConfig {
+            channels: self.channels,
+            sample_rate: cpal::SampleRate(self.sample_rate),
+            buffer_size: self.buffer_size.map_or(BufferSize::Default, BufferSize::Fixed),
+        }
+    }
+
+    /// The spec of the f32 samples the callback gets.
+    pub fn audio_spec(&self) -> AudioSpec {
+        AudioSpec::new(self.sample_rate, self.channels)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NegotiationError {
+    /// The device reported no configs at all.
+    NoConfigs,
+}
+
+impl fmt::Display for NegotiationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NegotiationError::NoConfigs => write!(f, "the device has no stream configs"),
+        }
+    }
+}
+
+impl std::error::Error for NegotiationError {}
+
+fn supports_rate(range: &SupportedStreamConfigRange, rate: u32) -> bool {
+    (range.min_sample_rate().0..=range.max_sample_rate().0).contains(&rate)
+}
+
+/// Picks the config to open from `ranges` (as listed by
+/// `supported_input_configs`) for `request`.
+///
+/// - Channels: the requested count, else the smallest count above it (the
+///   extra channels can be dropped), else the largest there is.
+/// - Sample rate: the requested one, else the nearest supported rate at
+///   those channels, preferring the higher one on a tie.
+/// - Sample format: the requested one, else f32, else the best integer
+///   format (see `FORMAT_PREFERENCE`).
+/// - Buffer size: the requested size clamped to what the device allows,
+///   or the backend default when the device doesn't say.
+///
+/// Without a request the device default is used where it is supported.
+pub fn negotiate_input_config(
+    ranges: &[SupportedStreamConfigRange],
+    default: Option<DefaultConfig>,
+    request: &StreamRequest,
+) -> Result<NegotiatedConfig, NegotiationError> {
+    if ranges.is_empty() {
+        return Err(NegotiationError::NoConfigs);
+    }
+    let mut fallbacks = Vec::new();
+
+    let mut counts: Vec<u16> = ranges.iter().map(|r| r.channels()).collect();
+    counts.sort_unstable();
+    counts.dedup();
+    let wanted_channels = request
+        .channels
+        .or(default.map(|d| d.channels).filter(|c| counts.contains(c)))
+        .unwrap_or(counts[0]);
+    let channels = if counts.contains(&wanted_channels) {
+        wanted_channels
+    } else {
+        let chosen = counts.iter().copied().find(|&c| c > wanted_channels).unwrap_or(counts[counts.len() - 1]);
+        fallbacks.push(format!("{} channels requested, the device offers {:?}; using {}", wanted_channels, counts, chosen));
+        chosen
+    };
+    let at_channels: Vec<&SupportedStreamConfigRange> = ranges.iter().filter(|r| r.channels() == channels).collect();
+
+    let wanted_rate = request
+        .sample_rate
+        .or(default.map(|d| d.sample_rate).filter(|&rate| at_channels.iter().any(|r| supports_rate(r, rate))))
+        .unwrap_or(FALLBACK_SAMPLE_RATE);
+    let sample_rate = at_channels
+        .iter()
+        .map(|r| wanted_rate.max(r.min_sample_rate().0).min(r.max_sample_rate().0))
+        .min_by_key(|&rate| (rate.abs_diff(wanted_rate), std::cmp::Reverse(rate)))
+        .unwrap_or(wanted_rate);
+    if sample_rate != wanted_rate && request.sample_rate.is_some() {
+        fallbacks.push(format!(
+            "{} Hz requested, not supported with {} channels; using {} Hz",
+            wanted_rate, channels, sample_rate
+        ));
+    }
+    let candidates: Vec<&SupportedStreamConfigRange> =
+        at_channels.into_iter().filter(|r| supports_rate(r, sample_rate)).collect();
+
+    let formats: Vec<SampleFormat> = candidates.iter().map(|r| r.sample_format()).collect();
+    let sample_format = match request.sample_format {
+        Some(format) if formats.contains(&format) => format,
+        requested => {
+            let chosen =
+                FORMAT_PREFERENCE.iter().copied().find(|f| formats.contains(f)).unwrap_or(formats[0]);
+            if let Some(format) = requested {
+                fallbacks.push(format!("{} samples requested, not supported here; using {}", format, chosen));
+            }
+            chosen
+        }
+    };
+    let range = candidates.iter().find(|r| r.sample_format() == sample_format).unwrap_or(&candidates[0]);
+
+    let buffer_size = match (request.buffer_size, range.buffer_size()) {
+        (None, _) => None,
+        (Some(frames), SupportedBufferSize::Range { min, max }) => {
+            let granted = frames.max(*min).min(*max);
+            if granted != frames {
+                fallbacks.push(format!(
+                    "buffer of {} frames requested, the device allows {} to {}; using {}",
+                    frames, min, max, granted
+                ));
+            }
+            Some(granted)
+        }
+        (Some(frames), SupportedBufferSize::Unknown) => {
+            fallbacks.push(format!(
+                "buffer of {} frames requested, the device doesn't report its buffer sizes; using the default",
+                frames
+            ));
+            None
+        }
+    };
+
+    Ok(NegotiatedConfig { sample_rate, channels, sample_format, buffer_size, fallbacks })
+}
+
+type DataCallback = Box<dyn FnMut(&[f32], &cpal::InputCallbackInfo) + Send + 'static>;
+type ErrorCallback = Box<dyn FnMut(cpal::StreamError) + Send + 'static>;
+
+/// Run state shared between a stream's callback and whoever controls it.
+/// Pausing doesn't go through `Stream::pause`, which some backends don't
+/// honour: the callback keeps being called, and drops the input or plays
+/// silence instead.
+#[derive(Debug, Default)]
+pub struct StreamControl {
+    paused: AtomicBool,
+    stopped: AtomicBool,
+    // Frames processed while running.
+    frames: AtomicU64,
+    // Frames in the most recent callback, 0 before the first one.
+    callback_frames: AtomicUsize,
+}
+
+impl StreamControl {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn pause(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+    }
+
+    /// Does nothing once stopped.
+    pub fn resume(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+    }
+
+    /// For good; calling it again does nothing.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::Relaxed)
+    }
+
+    /// For the callback, at the start of a block of `frames`: whether to
+    /// process it (or else drop it or play silence).
+    pub fn begin_block(&self, frames: usize) -> bool {
+        self.callback_frames.store(frames, Ordering::Relaxed);
+        let running = !self.is_paused() && !self.is_stopped();
+        if running {
+            self.frames.fetch_add(frames as u64, Ordering::Relaxed);
+        }
+        running
+    }
+
+    /// Frames processed while running, not counting paused blocks.
+    pub fn frames(&self) -> u64 {
+        self.frames.load(Ordering::Relaxed)
+    }
+}
+
+/// A running stream; dropping it closes the stream.
+pub struct StreamHandle {
+    _stream: Stream,
+    control: Arc<StreamControl>,
+}
+
+impl StreamHandle {
+    /// `stream`, whose callback consults `control`.
+    pub fn new(stream: Stream, control: Arc<StreamControl>) -> Self {
+        Self { _stream: stream, control }
+    }
+
+    /// Frames the backend delivered in its most recent callback, which may
+    /// differ from the granted buffer size. `None` until the first callback.
+    pub fn callback_frames(&self) -> Option<usize> {
+        Some(self.control.callback_frames.load(Ordering::Relaxed)).filter(|&frames| frames > 0)
+    }
+
+    pub fn pause(&self) {
+        self.control.pause();
+    }
+
+    pub fn resume(&self) {
+        self.control.resume();
+    }
+
+    /// Silences the stream for good. The device stays open until the handle
+    /// is dropped.
+    pub fn stop(&self) {
+        self.control.stop();
+    }
+
+    /// Frames processed so far, not counting paused blocks.
+    pub fn frames(&self) -> u64 {
+        self.control.frames()
+    }
+
+    /// For pausing or stopping from another thread.
+    pub fn control(&self) -> Arc<StreamControl> {
+        Arc::clone(&self.control)
+    }
+}
+
+/// Opens an input stream on `device`:
+///
+/// ```text
+/// let (handle, negotiated) = InputStreamBuilder::new(&device)
+///     .sample_rate(48000)
+///     .channels(1)
+///     .buffer_size(256)
+///     .on_data(|samples| ...)
+///     .build()?;
+/// ```
+pub struct InputStreamBuilder<'d> {
+    device: &'d Device,
+    request: StreamRequest,
+    on_data: Option<DataCallback>,
+    on_error: Option<ErrorCallback>,
+}
+
+impl<'d> InputStreamBuilder<'d> {
+    pub fn new(device: &'d Device) -> Self {
+        Self { device, request: StreamRequest::default(), on_data: None, on_error: None }
+    }
+
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.request.sample_rate = Some(sample_rate);
+        self
+    }
+
+    pub fn channels(mut self, channels: u16) -> Self {
+        self.request.channels = Some(channels);
+        self
+    }
+
+    pub fn sample_format(mut self, sample_format: SampleFormat) -> Self {
+        self.request.sample_format = Some(sample_format);
+        self
+    }
+
+    pub fn buffer_size(mut self, frames: u32) -> Self {
+        self.request.buffer_size = Some(frames);
+        self
+    }
+
+    /// Called with interleaved f32 samples in the negotiated layout. A
+    /// block longer than the granted buffer size and
+    /// `realtime::CALLBACK_SCRATCH_SAMPLES` comes in pieces, one call each.
+    pub fn on_data<F: FnMut(&[f32]) + Send + 'static>(mut self, mut callback: F) -> Self {
+        self.on_data = Some(Box::new(move |data, _| callback(data)));
+        self
+    }
+
+    /// Like `on_data`, with the backend's timestamps of the block.
+    pub fn on_data_with_info<F: FnMut(&[f32], &cpal::InputCallbackInfo) + Send + 'static>(mut self, callback: F) -> Self {
+        self.on_data = Some(Box::new(callback));
+        self
+    }
+
+    /// Called with errors of the running stream; they are printed otherwise.
+    pub fn on_error<F: FnMut(cpal::StreamError) + Send + 'static>(mut self, callback: F) -> Self {
+        self.on_error = Some(Box::new(callback));
+        self
+    }
+
+    /// The config `build` will open, without opening it; for sizing the
+    /// buffers the callbacks need.
+    pub fn negotiate(&self) -> Result<NegotiatedConfig, StreamError> {
+        let ranges: Vec<SupportedStreamConfigRange> = self.device.supported_input_configs()?.collect();
+        let default = self.device.default_input_config().ok().map(|config| DefaultConfig {
+            sample_rate: config.sample_rate().0,
+            channels: config.channels(),
+        });
+        negotiate_input_config(&ranges, default, &self.request).map_err(|e| StreamError::Unsupported(e.to_string()))
+    }
+
+    /// Negotiates the config, then builds and starts the stream.
+    pub fn build(self) -> Result<(StreamHandle, NegotiatedConfig), StreamError> {
+        let negotiated = self.negotiate()?;
+
+        let control = Arc::new(StreamControl::new());
+        let on_data = self.on_data.unwrap_or_else(|| Box::new(|_, _| {}));
+        let on_error = self.on_error.unwrap_or_else(|| Box::new(log_stream_error()));
+        let config = negotiated.stream_config();
+        let (device, shared, channels) = (self.device, Arc::clone(&control), negotiated.channels);
+        let stream = match negotiated.sample_format {
+            SampleFormat::F32 => build::<f32>(device, &config, channels, shared, on_data, on_error),
+            SampleFormat::F64 => build::<f64>(device, &config, channels, shared, on_data, on_error),
+            SampleFormat::I8 => build::<i8>(device, &config, channels, shared, on_data, on_error),
+            SampleFormat::I16 => build::<i16>(device, &config, channels, shared, on_data, on_error),
+            SampleFormat::I32 => build::<i32>(device, &config, channels, shared, on_data, on_error),
+            SampleFormat::I64 => build::<i64>(device, &config, channels, shared, on_data, on_error),
+            SampleFormat::U8 => build::<u8>(device, &config, channels, shared, on_data, on_error),
+            SampleFormat::U16 => build::<u16>(device, &config, channels, shared, on_data, on_error),
+            SampleFormat::U32 => build::<u32>(device, &config, channels, shared, on_data, on_error),
+            SampleFormat::U64 => build::<u64>(device, &config, channels, shared, on_data, on_error),
+            format => return Err(StreamError::Unsupported(format!("{} samples", format))),
+        }?;
+        stream.play()?;
+
+        Ok((StreamHandle::new(stream, control), negotiated))
+    }
+}
+
+// Converts device samples to f32 in -1.0..1.0 (u16 centred on 32768) into
+// the front of `out`, which has to be at least as long, and returns them.
+pub(crate) fn samples_to_f32<'a, T>(samples: &[T], out: &'a mut [f32]) -> &'a [f32]
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let out = &mut out[..samples.len()];
+    for (out, &sample) in out.iter_mut().zip(samples) {
+        *out = f32::from_sample_(sample);
+    }
+    out
+}
+
+// Builds a stream delivering `T` samples and hands them on as f32.
+fn build<T>(
+    device: &Device,
+    config: &StreamConfig,
+    channels: u16,
+    control: Arc<StreamControl>,
+    mut on_data: DataCallback,
+    on_error: ErrorCallback,
+) -> Result<Stream, StreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let channels = channels.max(1) as usize;
+    // Sized for the granted buffer, so that arrives whole; a longer block
+    // is handed on in pieces.
+    let mut converted = vec![0.0f32; match config.buffer_size {
+        BufferSize::Fixed(frames) => (frames as usize * channels).max(scratch_len(channels)),
+        BufferSize::Default => scratch_len(channels),
+    }];
+    let stream = device.build_input_stream(
+        config,
+        move |data: &[T], info: &cpal::InputCallbackInfo| {
+            callback_scope(|| {
+                if !control.begin_block(data.len() / channels) {
+                    return;
+                }
+                for piece in data.chunks(converted.len()) {
+                    let block = samples_to_f32(piece, &mut converted);
+                    debug_assert_finite("input callback", block);
+                    on_data(block, info);
+                }
+            })
+        },
+        on_error,
+        None,
+    )?;
+    Ok(stream)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpal::SampleRate;
+
+    fn range(channels: u16, rates: (u32, u32), format: SampleFormat, buffer: Option<(u32, u32)>) -> SupportedStreamConfigRange {
+        let buffer_size = buffer.map_or(SupportedBufferSize::Unknown, |(min, max)| SupportedBufferSize::Range { min, max });
+        SupportedStreamConfigRange::new(channels, SampleRate(rates.0), SampleRate(rates.1), buffer_size, format)
+    }
+
+    // A typical USB interface: f32 and i16 in mono, stereo and 4 channels
+    // at 44.1 to 96 kHz, plus an i32-only 8-channel mode at 48 kHz.
+    fn interface() -> Vec<SupportedStreamConfigRange> {
+        let mut ranges = Vec::new();
+        for channels in [1, 2, 4] {
+            for format in [SampleFormat::I16, SampleFormat::F32] {
+                ranges.push(range(channels, (44_100, 96_000), format, Some((32, 4096))));
+            }
+        }
+        ranges.push(range(8, (48_000, 48_000), SampleFormat::I32, None));
+        ranges
+    }
+
+    fn request(sample_rate: Option<u32>, channels: Option<u16>, sample_format: Option<SampleFormat>, buffer_size: Option<u32>) -> StreamRequest {
+        StreamRequest { sample_rate, channels, sample_format, buffer_size }
+    }
+
+    fn negotiate(ranges: &[SupportedStreamConfigRange], default: Option<DefaultConfig>, request: StreamRequest) -> NegotiatedConfig {
+        negotiate_input_config(ranges, default, &request).unwrap()
+    }
+
+    #[test]
+    fn a_request_the_device_supports_is_granted_as_asked() {
+        let negotiated = negotiate(&interface(), None, request(Some(96_000), Some(2), Some(SampleFormat::I16), Some(128)));
+        assert_eq!(
+            negotiated,
+            NegotiatedConfig {
+                sample_rate: 96_000,
+                channels: 2,
+                sample_format: SampleFormat::I16,
+                buffer_size: Some(128),
+                fallbacks: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn without_a_request_the_default_is_used_where_supported() {
+        let default = Some(DefaultConfig { sample_rate: 44_100, channels: 4 });
+        let negotiated = negotiate(&interface(), default, StreamRequest::default());
+        assert_eq!((negotiated.sample_rate, negotiated.channels, negotiated.sample_format), (44_100, 4, SampleFormat::F32));
+        assert_eq!((negotiated.buffer_size, negotiated.fallbacks), (None, vec![]));
+
+        // An unsupported default falls back quietly: the fewest channels
+        // and 48 kHz, neither of which was asked for.
+        let default = Some(DefaultConfig { sample_rate: 22_050, channels: 6 });
+        let negotiated = negotiate(&interface(), default, StreamRequest::default());
+        assert_eq!((negotiated.sample_rate, negotiated.channels), (48_000, 1));
+        assert!(negotiated.fallbacks.is_empty());
+        let negotiated = negotiate(&[range(2, (8_000, 16_000), SampleFormat::I16, None)], None, StreamRequest::default());
+        assert_eq!((negotiated.sample_rate, negotiated.channels), (16_000, 2));
+    }
+
+    #[test]
+    fn channels_fall_back_to_the_next_count_up_then_the_largest() {
+        let ranges = interface();
+        for (wanted, granted) in [(1, 1), (3, 4), (5, 8), (12, 8)] {
+            let negotiated = negotiate(&ranges, None, request(Some(48_000), Some(wanted), None, None));
+            assert_eq!(negotiated.channels, granted, "{wanted} channels requested");
+            assert_eq!(negotiated.fallbacks.len(), usize::from(wanted != granted));
+        }
+        let negotiated = negotiate(&ranges, None, request(Some(48_000), Some(3), None, None));
+        assert_eq!(negotiated.fallbacks, ["3 channels requested, the device offers [1, 2, 4, 8]; using 4"]);
+    }
+
+    #[test]
+    fn sample_rate_falls_back_to_the_nearest_at_those_channels() {
+        let ranges = [
+            range(2, (44_100, 44_100), SampleFormat::F32, None),
+            range(2, (48_000, 48_000), SampleFormat::F32, None),
+            range(2, (88_200, 192_000), SampleFormat::F32, None),
+            range(1, (8_000, 8_000), SampleFormat::F32, None),
+        ];
+        // A tie (46 050 Hz) goes to the higher rate; the mono-only 8 kHz
+        // doesn't count for stereo.
+        let cases = [(44_100, 44_100), (45_000, 44_100), (46_050, 48_000), (60_000, 48_000), (70_000, 88_200), (96_000, 96_000), (384_000, 192_000), (8_000, 44_100)];
+        for (wanted, granted) in cases {
+            let negotiated = negotiate(&ranges, None, request(Some(wanted), Some(2), None, None));
+            assert_eq!(negotiated.sample_rate, granted, "{wanted} Hz requested");
+            assert_eq!(negotiated.fallbacks.len(), usize::from(wanted != granted));
+        }
+        // The rate is picked before the format, and only formats at that rate count.
+        let ranges = [range(2, (44_100, 44_100), SampleFormat::F32, None), range(2, (48_000, 48_000), SampleFormat::I16, None)];
+        let negotiated = negotiate(&ranges, None, request(Some(48_000), Some(2), None, None));
+        assert_eq!((negotiated.sample_rate, negotiated.sample_format), (48_000, SampleFormat::I16));
+        assert_eq!(
+            negotiate(&ranges, None, request(Some(45_000), Some(2), None, None)).fallbacks,
+            ["45000 Hz requested, not supported with 2 channels; using 44100 Hz"]
+        );
+    }
+
+    #[test]
+    fn sample_format_falls_back_to_f32_then_the_best_integer_format() {
+        let at = |formats: &[SampleFormat], wanted: Option<SampleFormat>| {
+            let ranges: Vec<_> = formats.iter().map(|&format| range(2, (48_000, 48_000), format, None)).collect();
+            negotiate(&ranges, None, request(None, None, wanted, None))
+        };
+        use SampleFormat::*;
+        assert_eq!(at(&[I16, F32, I32], None).sample_format, F32);
+        assert_eq!(at(&[I16, F32, I32], Some(I32)).sample_format, I32);
+        let cases = [(&[I16, F32][..], F32), (&[U8, I16, I32], I32), (&[U8, U16, I16], I16), (&[I8, U8], I8), (&[U16, F64], U16)];
+        for (formats, granted) in cases {
+            let negotiated = at(formats, Some(U64));
+            assert_eq!(negotiated.sample_format, granted, "from {formats:?}");
+            assert_eq!(negotiated.fallbacks, [format!("u64 samples requested, not supported here; using {granted}")]);
+        }
+        assert!(at(&[U8, I16], None).fallbacks.is_empty());
+    }
+
+    #[test]
+    fn buffer_size_is_clamped_to_what_the_device_allows() {
+        let ranges = interface();
+        let buffer = |frames: Option<u32>, channels: u16| negotiate(&ranges, None, request(Some(48_000), Some(channels), None, frames));
+        assert_eq!(buffer(None, 2).buffer_size, None);
+        assert_eq!((buffer(Some(256), 2).buffer_size, buffer(Some(256), 2).fallbacks.len()), (Some(256), 0));
+        assert_eq!(buffer(Some(8), 2).buffer_size, Some(32));
+        assert_eq!(buffer(Some(100_000), 2).buffer_size, Some(4096));
+        assert_eq!(
+            buffer(Some(8), 2).fallbacks,
+            ["buffer of 8 frames requested, the device allows 32 to 4096; using 32"]
+        );
+        // The 8-channel mode doesn't say what it allows.
+        let unknown = buffer(Some(256), 8);
+        assert_eq!(unknown.buffer_size, None);
+        assert_eq!(
+            unknown.fallbacks,
+            ["buffer of 256 frames requested, the device doesn't report its buffer sizes; using the default"]
+        );
+    }
+
+    #[test]
+    fn fallbacks_are_recorded_in_negotiation_order() {
+        let negotiated = negotiate(&interface(), None, request(Some(192_000), Some(3), Some(SampleFormat::U8), Some(16)));
+        let granted = (negotiated.sample_rate, negotiated.channels, negotiated.sample_format, negotiated.buffer_size);
+        assert_eq!(granted, (96_000, 4, SampleFormat::F32, Some(32)));
+        let reasons: Vec<&str> = negotiated.fallbacks.iter().map(|line| line.split(' ').nth(1).unwrap()).collect();
+        assert_eq!(reasons, ["channels", "Hz", "samples", "of"]);
+    }
+
+    #[test]
+    fn a_device_without_configs_is_an_error() {
+        assert_eq!(negotiate_input_config(&[], None, &StreamRequest::default()), Err(NegotiationError::NoConfigs));
+    }
+
+    #[test]
+    fn integer_samples_convert_to_full_scale_f32() {
+        let mut out = [0.0f32; 4];
+        assert_eq!(samples_to_f32(&[i16::MIN, 0, i16::MAX], &mut out), &[-1.0, 0.0, 32767.0 / 32768.0]);
+        assert!((out[2] - 1.0).abs() < 1e-4);
+        assert_eq!(samples_to_f32(&[u16::MIN, 32768, u16::MAX], &mut out), &[-1.0, 0.0, 32767.0 / 32768.0]);
+        assert_eq!(out[3], 0.0);
+    }
+
+    #[test]
+    fn every_i16_survives_the_round_trip_through_f32() {
+        let samples: Vec<i16> = (i16::MIN..=i16::MAX).collect();
+        let mut converted = vec![0.0f32; samples.len()];
+        let converted = samples_to_f32(&samples, &mut converted);
+        assert!(converted.iter().all(|sample| (-1.0..1.0).contains(sample)));
+        for (&sample, &value) in samples.iter().zip(converted) {
+            assert_eq!(i16::from_sample_(value), sample);
+        }
+        let samples: Vec<u16> = (u16::MIN..=u16::MAX).collect();
+        let mut converted = vec![0.0f32; samples.len()];
+        for (&sample, &value) in samples.iter().zip(samples_to_f32(&samples, &mut converted)) {
+            assert_eq!(u16::from_sample_(value), sample);
+        }
+    }
+}
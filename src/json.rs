@@ -0,0 +1,562 @@
+// Minimal JSON reader/writer for machine-readable output and pipeline files.
+//
+// Numbers are kept as f64. JSON has no representation for NaN or
+// infinities, so the writer emits `null` for them; readers of dB values
+// treat `null` as negative infinity (see `Value::as_db`).
+use std::collections::BTreeMap;
+use std::fmt;
+
+pub type Object = BTreeMap<String, Value>;
+
+/// Version of the documents printed by `--json`. Only bumped when a field
+/// is removed, renamed or changes meaning; new fields may appear at any
+/// time and readers should ignore the ones they don't know.
+pub const SCHEMA_VERSION: u64 = 1;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Object),
+}
+
+impl Value {
+    /// Stores an f32 by its shortest decimal form, so `0.1f32` is written as
+    /// `0.1` rather than `0.10000000149011612`.
+    pub fn from_f32(value: f32) -> Value {
+        if value.is_finite() {
+            Value::Number(value.to_string().parse().unwrap_or(value as f64))
+        } else {
+            Value::Null
+        }
+    }
+
+    /// A level in dB, with negative infinity (silence) written as `null`.
+    pub fn from_db(value: f32) -> Value {
+        Value::from_f32(value)
+    }
+
+    pub fn from_option<T: Into<Value>>(value: Option<T>) -> Value {
+        value.map(Into::into).unwrap_or(Value::Null)
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Value::Null)
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Number(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn as_f32(&self) -> Option<f32> {
+        self.as_f64().map(|n| n as f32)
+    }
+
+    /// Reads a dB value written by `from_db`: `null` is negative infinity.
+    pub fn as_db(&self) -> Option<f32> {
+        match self {
+            Value::Null => Some(f32::NEG_INFINITY),
+            _ => self.as_f32(),
+        }
+    }
+
+    /// Only numbers without a fractional part that fit into a u64.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Number(n) if *n >= 0.0 && n.fract() == 0.0 && *n <= u64::MAX as f64 => Some(*n as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Value>> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn as_object(&self) -> Option<&Object> {
+        match self {
+            Value::Object(o) => Some(o),
+            _ => None,
+        }
+    }
+
+    /// Member `key` of an object, `None` for anything else.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.as_object().and_then(|o| o.get(key))
+    }
+
+    /// Like `get`, but a missing member is an error naming it.
+    pub fn require(&self, key: &str) -> Result<&Value, String> {
+        self.get(key).ok_or_else(|| format!("missing field \"{}\"", key))
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<f32> for Value {
+    fn from(n: f32) -> Value {
+        Value::from_f32(n)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Value {
+        if n.is_finite() {
+            Value::Number(n)
+        } else {
+            Value::Null
+        }
+    }
+}
+
+macro_rules! value_from_integer {
+    ($($t:ty),*) => {
+        $(impl From<$t> for Value {
+            fn from(n: $t) -> Value {
+                Value::Number(n as f64)
+            }
+        })*
+    };
+}
+
+value_from_integer!(u16, u32, u64, usize, i32, i64, isize);
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
+
+impl From<Vec<Value>> for Value {
+    fn from(a: Vec<Value>) -> Value {
+        Value::Array(a)
+    }
+}
+
+impl From<Object> for Value {
+    fn from(o: Object) -> Value {
+        Value::Object(o)
+    }
+}
+
+/// Rejects documents written for a newer, incompatible schema.
+pub fn check_schema_version(value: &Value) -> Result<(), String> {
+    match value.require("schema_version")?.as_u64() {
+        Some(version) if version <= SCHEMA_VERSION => Ok(()),
+        Some(version) => Err(format!("unsupported schema version {}", version)),
+        None => Err("\"schema_version\" is not an integer".to_string()),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn parse(input: &str) -> Result<Value, ParseError> {
+    let mut parser = Parser { chars: input.chars().collect(), pos: 0, line: 1 };
+    parser.skip_whitespace();
+    let value = parser.parse_value()?;
+    parser.skip_whitespace();
+    match parser.peek() {
+        None => Ok(value),
+        Some(c) => parser.error(format!("unexpected '{}' after the document", c)),
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError { line: self.line, message: message.into() })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        match self.bump() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => self.error(format!("expected '{}', found '{}'", expected, c)),
+            None => self.error(format!("expected '{}', found end of input", expected)),
+        }
+    }
+
+    fn expect_word(&mut self, word: &str) -> Result<(), ParseError> {
+        for expected in word.chars() {
+            self.expect(expected)?;
+        }
+        Ok(())
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t') | Some('\n') | Some('\r')) {
+            self.bump();
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            Some('{') => self.parse_object(),
+            Some('[') => self.parse_array(),
+            Some('"') => Ok(Value::String(self.parse_string()?)),
+            Some('t') => self.expect_word("true").map(|_| Value::Bool(true)),
+            Some('f') => self.expect_word("false").map(|_| Value::Bool(false)),
+            Some('n') => self.expect_word("null").map(|_| Value::Null),
+            Some(c) if c == '-' || c.is_ascii_digit() => self.parse_number(),
+            Some(c) => self.error(format!("unexpected '{}'", c)),
+            None => self.error("expected a value, found end of input"),
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Value, ParseError> {
+        self.expect('{')?;
+        let mut object = Object::new();
+        self.skip_whitespace();
+        if self.peek() == Some('}') {
+            self.bump();
+            return Ok(Value::Object(object));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some('"') {
+                return self.error("expected a string key");
+            }
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            self.expect(':')?;
+            self.skip_whitespace();
+            let value = self.parse_value()?;
+            object.insert(key, value);
+            self.skip_whitespace();
+            match self.bump() {
+                Some(',') => continue,
+                Some('}') => return Ok(Value::Object(object)),
+                _ => return self.error("expected ',' or '}' in object"),
+            }
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value, ParseError> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(']') {
+            self.bump();
+            return Ok(Value::Array(items));
+        }
+        loop {
+            self.skip_whitespace();
+            items.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.bump() {
+                Some(',') => continue,
+                Some(']') => return Ok(Value::Array(items)),
+                _ => return self.error("expected ',' or ']' in array"),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.bump() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.bump() {
+                    Some('"') => s.push('"'),
+                    Some('\\') => s.push('\\'),
+                    Some('/') => s.push('/'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('u') => s.push(self.parse_unicode_escape()?),
+                    _ => return self.error("invalid escape sequence"),
+                },
+                Some(c) if (c as u32) < 0x20 => return self.error("control character in string"),
+                Some(c) => s.push(c),
+                None => return self.error("unterminated string"),
+            }
+        }
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, ParseError> {
+        let mut code = 0;
+        for _ in 0..4 {
+            match self.bump().and_then(|c| c.to_digit(16)) {
+                Some(digit) => code = code * 16 + digit,
+                None => return self.error("invalid unicode escape"),
+            }
+        }
+        Ok(code)
+    }
+
+    // \uXXXX, combining UTF-16 surrogate pairs.
+    fn parse_unicode_escape(&mut self) -> Result<char, ParseError> {
+        let high = self.parse_hex4()?;
+        let code = if (0xD800..0xDC00).contains(&high) {
+            self.expect('\\')?;
+            self.expect('u')?;
+            let low = self.parse_hex4()?;
+            if !(0xDC00..0xE000).contains(&low) {
+                return self.error("invalid surrogate pair");
+            }
+            0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+        } else {
+            high
+        };
+        match char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => self.error("invalid unicode escape"),
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+            self.bump();
+        }
+        let text: String = self.chars[start..self.pos].iter().collect();
+        match text.parse::<f64>() {
+            Ok(n) if n.is_finite() => Ok(Value::Number(n)),
+            _ => self.error(format!("invalid number '{}'", text)),
+        }
+    }
+}
+
+/// Compact single-line JSON.
+pub fn to_string(value: &Value) -> String {
+    let mut out = String::new();
+    write_value(&mut out, value, None, 0);
+    out
+}
+
+/// JSON indented by two spaces per level.
+pub fn to_string_pretty(value: &Value) -> String {
+    let mut out = String::new();
+    write_value(&mut out, value, Some(2), 0);
+    out
+}
+
+fn write_value(out: &mut String, value: &Value, indent: Option<usize>, depth: usize) {
+    match value {
+        Value::Null => out.push_str("null"),
+        Value::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+        Value::Number(n) if n.is_finite() => out.push_str(&n.to_string()),
+        Value::Number(_) => out.push_str("null"),
+        Value::String(s) => write_string(out, s),
+        Value::Array(items) => {
+            write_container(out, '[', ']', items.iter().map(|v| (None, v)), items.is_empty(), indent, depth)
+        }
+        Value::Object(object) => write_container(
+            out,
+            '{',
+            '}',
+            object.iter().map(|(k, v)| (Some(k.as_str()), v)),
+            object.is_empty(),
+            indent,
+            depth,
+        ),
+    }
+}
+
+fn write_container<'a>(
+    out: &mut String,
+    open: char,
+    close: char,
+    members: impl Iterator<Item = (Option<&'a str>, &'a Value)>,
+    empty: bool,
+    indent: Option<usize>,
+    depth: usize,
+) {
+    out.push(open);
+    if empty {
+        out.push(close);
+        return;
+    }
+    let newline = |out: &mut String, depth: usize| {
+        if let Some(width) = indent {
+            out.push('\n');
+            out.push_str(&" ".repeat(width * depth));
+        }
+    };
+    for (i, (key, value)) in members.enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        newline(out, depth + 1);
+        if let Some(key) = key {
+            write_string(out, key);
+            out.push(':');
+            if indent.is_some() {
+                out.push(' ');
+            }
+        }
+        write_value(out, value, indent, depth + 1);
+    }
+    newline(out, depth);
+    out.push(close);
+}
+
+fn write_string(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn object(members: &[(&str, Value)]) -> Value {
+        Value::Object(members.iter().map(|(k, v)| (k.to_string(), v.clone())).collect())
+    }
+
+    #[test]
+    fn round_trips_nested_documents() {
+        let document = object(&[
+            ("schema_version", SCHEMA_VERSION.into()),
+            ("empty_array", Value::Array(Vec::new())),
+            ("empty_object", Value::Object(Object::new())),
+            (
+                "nested",
+                Value::Array(vec![
+                    object(&[("a", Value::Array(vec![Value::Null, true.into(), 1.5f64.into()]))]),
+                    Value::Array(vec![Value::Array(vec![(-3i32).into()])]),
+                ]),
+            ),
+            ("text", "plain".into()),
+        ]);
+        for text in [to_string(&document), to_string_pretty(&document)] {
+            assert_eq!(parse(&text).unwrap(), document, "{}", text);
+        }
+    }
+
+    #[test]
+    fn escapes_round_trip() {
+        let text = "quote \" backslash \\ slash / newline \n tab \t bell \u{7} é 漢 🎧";
+        let written = to_string(&Value::from(text));
+        assert!(!written.contains('\n'));
+        assert_eq!(parse(&written).unwrap(), Value::from(text));
+    }
+
+    #[test]
+    fn parses_escapes_and_surrogate_pairs() {
+        let value = parse(r#""é漢🎧\/\b\f\r""#).unwrap();
+        assert_eq!(value.as_str(), Some("é漢🎧/\u{8}\u{c}\r"));
+    }
+
+    #[test]
+    fn non_finite_numbers_are_null() {
+        assert_eq!(to_string(&Value::from(f32::NEG_INFINITY)), "null");
+        assert_eq!(to_string(&Value::from(f64::NAN)), "null");
+        assert_eq!(to_string(&Value::Number(f64::INFINITY)), "null");
+        assert_eq!(parse("null").unwrap().as_db(), Some(f32::NEG_INFINITY));
+        assert_eq!(parse("-6.5").unwrap().as_db(), Some(-6.5));
+        assert_eq!(to_string(&Value::from(0.1f32)), "0.1");
+    }
+
+    #[test]
+    fn rejects_malformed_input() {
+        for text in [
+            "",
+            "{",
+            "[1, 2",
+            "[1, 2,]",
+            "{\"a\" 1}",
+            "{\"a\": 1,}",
+            "{a: 1}",
+            "\"unterminated",
+            "\"bad \\x escape\"",
+            "\"\\ud83c alone\"",
+            "\"raw \u{1} control\"",
+            "tru",
+            "1e999",
+            "-",
+            "NaN",
+            "{} {}",
+        ] {
+            assert!(parse(text).is_err(), "{:?} parsed", text);
+        }
+    }
+
+    #[test]
+    fn errors_have_the_line() {
+        assert_eq!(parse("{\n  \"a\": 1,\n  \"b\": ?\n}").unwrap_err().line, 3);
+    }
+
+    #[test]
+    fn schema_version_is_checked() {
+        assert!(check_schema_version(&object(&[("schema_version", SCHEMA_VERSION.into())])).is_ok());
+        assert!(check_schema_version(&object(&[("schema_version", (SCHEMA_VERSION + 1).into())])).is_err());
+        assert!(check_schema_version(&object(&[])).is_err());
+        assert!(check_schema_version(&object(&[("schema_version", "1".into())])).is_err());
+    }
+}
@@ -0,0 +1,46 @@
+// The library behind the `cpal_playbook` command: devices and streams,
+// WAV and other file I/O, and the DSP and offline processing built on them.
+pub mod devices;
+pub mod adapter;
+pub mod stream;
+pub mod read_wav;
+pub mod write_wav;
+pub mod limiter;
+pub mod dsp;
+pub mod filters;
+pub mod eq;
+pub mod fx;
+pub mod effect;
+pub mod render;
+pub mod stft;
+pub mod analysis;
+pub mod toml;
+pub mod config;
+pub mod peaks;
+pub mod fft;
+pub mod match_eq;
+pub mod json;
+pub mod loudness;
+pub mod mixdown;
+pub mod pipeline;
+pub mod spectrum;
+pub mod project;
+pub mod recorder;
+pub mod hpss;
+pub mod master;
+pub mod hook;
+pub mod batch;
+pub mod session;
+pub mod input;
+pub mod convolution;
+pub mod reverb;
+pub mod scheduler;
+pub mod export;
+pub mod denoise;
+pub mod meter_bus;
+pub mod voice;
+pub mod realtime;
+pub mod signal;
+pub mod shutdown;
+#[cfg(feature = "flac")]
+pub mod flac;
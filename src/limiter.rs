@@ -0,0 +1,449 @@
+// Limiters for the output path
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::mpsc::SyncSender;
+use std::sync::OnceLock;
+
+use crate::realtime::{callback_logger, CallbackLog, CallbackLogger};
+
+/// Ceiling of the safety limiter, in dBFS.
+pub const SAFETY_CEILING_DB: f32 = -0.3;
+/// How long the output may sit above the ceiling before the emergency mute engages.
+pub const SAFETY_MUTE_AFTER_MS: f32 = 100.0;
+
+pub fn db_to_linear(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+/// Why the safety limiter muted the output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SafetyEvent {
+    /// The block contained NaN or infinity.
+    NonFinite { frame: u64 },
+    /// The output stayed above the ceiling for longer than the mute time.
+    SustainedOverload { frame: u64 },
+}
+
+impl fmt::Display for SafetyEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SafetyEvent::NonFinite { frame } => write!(f, "NaN or infinity in the output at frame {}", frame),
+            SafetyEvent::SustainedOverload { frame } => {
+                write!(f, "output above the ceiling for too long at frame {}", frame)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SafetyLimiterConfig {
+    /// Set to false to opt out of the limiter entirely.
+    pub enabled: bool,
+    pub ceiling_db: f32,
+    pub mute_after_ms: f32,
+}
+
+impl Default for SafetyLimiterConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ceiling_db: SAFETY_CEILING_DB,
+            mute_after_ms: SAFETY_MUTE_AFTER_MS,
+        }
+    }
+}
+
+static SAFETY_DEFAULTS: OnceLock<SafetyLimiterConfig> = OnceLock::new();
+
+/// Sets the limiter settings of streams that don't choose their own, from
+/// the config. Only the first call counts; call it at startup.
+pub fn set_safety_defaults(config: SafetyLimiterConfig) {
+    let _ = SAFETY_DEFAULTS.set(config);
+}
+
+/// What `set_safety_defaults` set, else `SafetyLimiterConfig::default()`.
+pub fn safety_defaults() -> SafetyLimiterConfig {
+    SAFETY_DEFAULTS.get().copied().unwrap_or_default()
+}
+
+/// Last stage of every output callback: protects ears and speakers from
+/// unstable filters and other experiments gone wrong.
+///
+/// Samples below the ceiling pass through untouched, everything above it is
+/// hard clipped. The output is muted (and stays muted until `reset()`) when a
+/// block contains NaN/inf, or when the signal hits the ceiling continuously
+/// for more than `mute_after_ms`.
+pub struct SafetyLimiter {
+    enabled: bool,
+    ceiling: f32,
+    channels: usize,
+    mute_after_frames: u64,
+    frames_over: u64,
+    frame_position: u64,
+    muted: Option<SafetyEvent>,
+    events: Option<SyncSender<SafetyEvent>>,
+}
+
+impl SafetyLimiter {
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        Self::with_config(sample_rate, channels, SafetyLimiterConfig::default())
+    }
+
+    pub fn with_config(sample_rate: u32, channels: u16, config: SafetyLimiterConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            ceiling: db_to_linear(config.ceiling_db),
+            channels: channels.max(1) as usize,
+            mute_after_frames: (config.mute_after_ms * 0.001 * sample_rate as f32) as u64,
+            frames_over: 0,
+            frame_position: 0,
+            muted: None,
+            events: None,
+        }
+    }
+
+    /// Report mute events over a bounded channel. Sending never blocks, so it
+    /// is safe from the audio callback; events are dropped if the channel is full.
+    pub fn with_events(mut self, sender: SyncSender<SafetyEvent>) -> Self {
+        self.events = Some(sender);
+        self
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted.is_some()
+    }
+
+    /// The event that engaged the mute, if any.
+    pub fn mute_reason(&self) -> Option<SafetyEvent> {
+        self.muted
+    }
+
+    /// Release the emergency mute.
+    pub fn reset(&mut self) {
+        self.muted = None;
+        self.frames_over = 0;
+    }
+
+    /// Processes an interleaved block in place.
+    pub fn process_block(&mut self, block: &mut [f32]) {
+        if !self.enabled {
+            return;
+        }
+        let block_start = self.frame_position;
+        self.frame_position += (block.len() / self.channels) as u64;
+
+        if self.muted.is_some() {
+            block.fill(0.0);
+            return;
+        }
+
+        if block.iter().any(|s| !s.is_finite()) {
+            block.fill(0.0);
+            self.mute(SafetyEvent::NonFinite { frame: block_start });
+            return;
+        }
+
+        for (i, frame) in block.chunks_mut(self.channels).enumerate() {
+            let mut over = false;
+            for sample in frame.iter_mut() {
+                if sample.abs() > self.ceiling {
+                    *sample = sample.signum() * self.ceiling;
+                    over = true;
+                }
+            }
+
+            if over {
+                self.frames_over += 1;
+            } else {
+                self.frames_over = 0;
+            }
+
+            if self.frames_over > self.mute_after_frames {
+                self.mute(SafetyEvent::SustainedOverload { frame: block_start + i as u64 });
+                break;
+            }
+        }
+
+        if self.muted.is_some() {
+            block.fill(0.0);
+        }
+    }
+
+    fn mute(&mut self, event: SafetyEvent) {
+        self.muted = Some(event);
+        if let Some(events) = &self.events {
+            let _ = events.try_send(event);
+        }
+    }
+}
+
+/// The safety limiter as an output callback's last step: one per stream,
+/// and it tells the logging thread when it mutes.
+pub struct OutputGuard {
+    limiter: SafetyLimiter,
+    logger: &'static CallbackLogger,
+    reported: bool,
+}
+
+impl OutputGuard {
+    /// Build it while setting the stream up, not in the callback.
+    pub fn new(sample_rate: u32, channels: u16, config: SafetyLimiterConfig) -> Self {
+        Self {
+            limiter: SafetyLimiter::with_config(sample_rate, channels, config),
+            logger: callback_logger(),
+            reported: false,
+        }
+    }
+
+    /// `new` with `safety_defaults()`.
+    pub fn with_defaults(sample_rate: u32, channels: u16) -> Self {
+        Self::new(sample_rate, channels, safety_defaults())
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.limiter.is_muted()
+    }
+
+    /// Runs the limiter over an interleaved block in place.
+    #[inline]
+    pub fn process(&mut self, block: &mut [f32]) {
+        self.limiter.process_block(block);
+        if !self.reported {
+            if let Some(event) = self.limiter.mute_reason() {
+                self.reported = true;
+                self.logger.log(CallbackLog::Muted(event));
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LimiterSettings {
+    pub ceiling_db: f32,
+    pub lookahead_ms: f32,
+    pub release_ms: f32,
+}
+
+impl Default for LimiterSettings {
+    fn default() -> Self {
+        Self {
+            ceiling_db: -1.0,
+            lookahead_ms: 5.0,
+            release_ms: 50.0,
+        }
+    }
+}
+
+/// Lookahead peak limiter for interleaved audio, with the gain linked
+/// across channels.
+///
+/// The signal is delayed by the lookahead so the gain can start coming down
+/// before a peak arrives: the required gain of each frame is held for the
+/// lookahead window and then averaged over the same window, which ramps it
+/// down smoothly yet still reaches the target by the time the peak leaves
+/// the delay line. The output never exceeds the ceiling.
+pub struct Limiter {
+    ceiling: f32,
+    channels: usize,
+    lookahead: usize,
+    release_coeff: f32,
+    delay: Vec<f32>,
+    delay_index: usize,
+    // (frame, required gain) candidates for the minimum over the window.
+    hold: VecDeque<(u64, f32)>,
+    // Released gain over the last `lookahead + 1` frames, and their sum.
+    smoothing: Vec<f32>,
+    smoothing_sum: f64,
+    envelope: f32,
+    frame: u64,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: u32, channels: u16, settings: LimiterSettings) -> Self {
+        let channels = channels.max(1) as usize;
+        let lookahead = (settings.lookahead_ms * 0.001 * sample_rate as f32).round() as usize;
+        let window = lookahead + 1;
+        Self {
+            ceiling: db_to_linear(settings.ceiling_db),
+            channels,
+            lookahead,
+            release_coeff: (-1.0 / (settings.release_ms * 0.001 * sample_rate as f32)).exp(),
+            delay: vec![0.0; lookahead * channels],
+            delay_index: 0,
+            hold: VecDeque::with_capacity(window + 1),
+            smoothing: vec![1.0; window],
+            smoothing_sum: window as f64,
+            envelope: 1.0,
+            frame: 0,
+        }
+    }
+
+    /// Delay added by the lookahead, in frames.
+    pub fn latency_frames(&self) -> usize {
+        self.lookahead
+    }
+
+    /// Gain applied to the frame currently leaving the delay line.
+    pub fn current_gain(&self) -> f32 {
+        (self.smoothing_sum / self.smoothing.len() as f64) as f32
+    }
+
+    /// Processes an interleaved block in place. The output lags the input
+    /// by `latency_frames()`.
+    pub fn process_block(&mut self, block: &mut [f32]) {
+        let window = self.smoothing.len();
+        for frame in block.chunks_exact_mut(self.channels) {
+            let peak = frame.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+            let required = if peak > self.ceiling { self.ceiling / peak } else { 1.0 };
+
+            // Sliding minimum of the required gain over the window.
+            while self.hold.back().is_some_and(|&(_, gain)| gain >= required) {
+                self.hold.pop_back();
+            }
+            self.hold.push_back((self.frame, required));
+            while self.hold.front().is_some_and(|&(start, _)| start + window as u64 <= self.frame) {
+                self.hold.pop_front();
+            }
+            let held = self.hold.front().map_or(1.0, |&(_, gain)| gain);
+
+            // Instant attack (the averaging below smooths it), slow release.
+            self.envelope = if held < self.envelope {
+                held
+            } else {
+                held + self.release_coeff * (self.envelope - held)
+            };
+
+            let slot = (self.frame % window as u64) as usize;
+            self.smoothing_sum += self.envelope as f64 - self.smoothing[slot] as f64;
+            self.smoothing[slot] = self.envelope;
+            let gain = self.current_gain();
+
+            for sample in frame.iter_mut() {
+                let delayed = if self.lookahead == 0 {
+                    *sample
+                } else {
+                    let delayed = self.delay[self.delay_index];
+                    self.delay[self.delay_index] = *sample;
+                    self.delay_index = (self.delay_index + 1) % self.delay.len();
+                    delayed
+                };
+                // The clamp only catches rounding in the running sum.
+                *sample = (delayed * gain).clamp(-self.ceiling, self.ceiling);
+            }
+            self.frame += 1;
+        }
+    }
+
+    /// Limits a complete interleaved signal and removes the lookahead
+    /// delay, so the result lines up with the input.
+    pub fn process_offline(&mut self, samples: &[f32]) -> Vec<f32> {
+        let latency = self.lookahead * self.channels;
+        let mut output = samples.to_vec();
+        output.resize(samples.len() + latency, 0.0);
+        self.process_block(&mut output);
+        output.drain(..latency);
+        output
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    #[test]
+    fn non_finite_block_mutes() {
+        let mut limiter = SafetyLimiter::new(RATE, 2);
+        let mut block = vec![0.1; 256];
+        block[17] = f32::NAN;
+        limiter.process_block(&mut block);
+        assert!(block.iter().all(|&s| s == 0.0));
+        assert_eq!(limiter.mute_reason(), Some(SafetyEvent::NonFinite { frame: 0 }));
+
+        // Stays muted on clean blocks until reset.
+        let mut block = vec![0.1; 256];
+        limiter.process_block(&mut block);
+        assert!(block.iter().all(|&s| s == 0.0));
+        limiter.reset();
+        let mut block = vec![0.1; 256];
+        limiter.process_block(&mut block);
+        assert!(block.iter().all(|&s| s == 0.1));
+    }
+
+    #[test]
+    fn infinity_mutes() {
+        let mut limiter = SafetyLimiter::new(RATE, 1);
+        let mut block = [0.0, f32::NEG_INFINITY, 0.0];
+        limiter.process_block(&mut block);
+        assert_eq!(block, [0.0; 3]);
+        assert!(limiter.is_muted());
+    }
+
+    #[test]
+    fn overload_is_clamped_then_muted() {
+        let ceiling = db_to_linear(SAFETY_CEILING_DB);
+        let dc = db_to_linear(6.0);
+        let mut limiter = SafetyLimiter::new(RATE, 2);
+        let limit = (SAFETY_MUTE_AFTER_MS * 0.001 * RATE as f32) as usize;
+
+        // 50 ms: clamped to the ceiling, not muted yet.
+        let mut block = vec![dc; RATE as usize / 20 * 2];
+        limiter.process_block(&mut block);
+        assert!(!limiter.is_muted());
+        assert!(block.iter().all(|&s| s == ceiling));
+
+        // The rest of the 100 ms and a little more.
+        let mut block = vec![-dc; 2 * (limit - RATE as usize / 20 + 10)];
+        limiter.process_block(&mut block);
+        assert_eq!(limiter.mute_reason(), Some(SafetyEvent::SustainedOverload { frame: limit as u64 }));
+        assert!(block.iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn short_overloads_do_not_mute() {
+        let mut limiter = SafetyLimiter::new(RATE, 1);
+        let mut block: Vec<f32> = (0..RATE).map(|i| if i % 1000 < 500 { 2.0 } else { 0.5 }).collect();
+        limiter.process_block(&mut block);
+        assert!(!limiter.is_muted());
+        assert!(block.iter().all(|&s| s.abs() <= db_to_linear(SAFETY_CEILING_DB)));
+    }
+
+    #[test]
+    fn signal_below_ceiling_is_untouched() {
+        let amplitude = db_to_linear(-6.0);
+        let input: Vec<f32> = (0..RATE as usize * 2)
+            .map(|i| amplitude * (std::f32::consts::TAU * 997.0 * (i / 2) as f32 / RATE as f32).sin())
+            .collect();
+        let mut output = input.clone();
+        let mut limiter = SafetyLimiter::new(RATE, 2);
+        for block in output.chunks_mut(512) {
+            limiter.process_block(block);
+        }
+        assert!(input.iter().zip(&output).all(|(a, b)| a.to_bits() == b.to_bits()));
+    }
+
+    #[test]
+    fn disabled_limiter_passes_everything() {
+        let config = SafetyLimiterConfig { enabled: false, ..SafetyLimiterConfig::default() };
+        let mut limiter = SafetyLimiter::with_config(RATE, 1, config);
+        let mut block = [4.0, f32::NAN, -4.0];
+        limiter.process_block(&mut block);
+        assert_eq!(block[0], 4.0);
+        assert!(block[1].is_nan());
+        assert!(!limiter.is_muted());
+    }
+
+    #[test]
+    fn guard_mutes_like_the_limiter() {
+        let mut guard = OutputGuard::new(RATE, 1, SafetyLimiterConfig::default());
+        let mut block = [0.2, f32::NAN];
+        guard.process(&mut block);
+        assert!(guard.is_muted());
+        assert_eq!(block, [0.0, 0.0]);
+    }
+}
@@ -0,0 +1,201 @@
+// Integrated loudness after ITU-R BS.1770-4 / EBU R128.
+//
+// Each channel is K-weighted (a high shelf modelling the head followed by
+// the "RLB" high-pass), mean-square energy is taken over 400 ms blocks
+// overlapping by 75%, and the blocks are gated twice: absolutely at
+// -70 LUFS and relatively at 10 LU below the loudness of the blocks that
+// passed the first gate.
+//
+// `LoudnessMeter` only keeps one value per 100 ms of audio, so files of
+// any length can be measured by feeding them in chunks.
+use crate::adapter::StreamingResampler;
+use crate::filters::BiquadFilter;
+
+pub const ABSOLUTE_GATE_LUFS: f64 = -70.0;
+pub const RELATIVE_GATE_LU: f64 = -10.0;
+const BLOCK_SECONDS: f64 = 0.4;
+/// Blocks start every 100 ms, so one block spans four steps.
+const STEPS_PER_BLOCK: usize = 4;
+
+// Loudness of a mean-square sum: `-0.691 + 10 log10(z)`.
+fn energy_to_lufs(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn lufs_to_energy(lufs: f64) -> f64 {
+    10.0_f64.powf((lufs + 0.691) / 10.0)
+}
+
+/// The two K-weighting stages for `sample_rate`, with the coefficients
+/// derived from the analog prototypes so any rate works, not just 48 kHz.
+pub fn k_weighting_filters(sample_rate: u32) -> [BiquadFilter; 2] {
+    let fs = sample_rate as f64;
+
+    let f0 = 1681.974450955533;
+    let gain_db = 3.999843853973347;
+    let q = 0.7071752369554196;
+    let k = (std::f64::consts::PI * f0 / fs).tan();
+    let vh = 10.0_f64.powf(gain_db / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = BiquadFilter::from_coefficients(
+        ((vh + vb * k / q + k * k) / a0) as f32,
+        (2.0 * (k * k - vh) / a0) as f32,
+        ((vh - vb * k / q + k * k) / a0) as f32,
+        (2.0 * (k * k - 1.0) / a0) as f32,
+        ((1.0 - k / q + k * k) / a0) as f32,
+    );
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (std::f64::consts::PI * f0 / fs).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = BiquadFilter::from_coefficients(
+        1.0,
+        -2.0,
+        1.0,
+        (2.0 * (k * k - 1.0) / a0) as f32,
+        ((1.0 - k / q + k * k) / a0) as f32,
+    );
+
+    [shelf, high_pass]
+}
+
+/// Channel weight from BS.1770: the surround channels of a 5.1 layout
+/// (L, R, C, LFE, Ls, Rs) count +1.5 dB, the LFE is left out.
+fn channel_weight(channel: usize, channels: usize) -> f64 {
+    match (channels, channel) {
+        (6, 3) => 0.0,
+        (6, 4) | (6, 5) => 1.41,
+        _ => 1.0,
+    }
+}
+
+pub struct LoudnessMeter {
+    filters: Vec<[BiquadFilter; 2]>,
+    weights: Vec<f64>,
+    step_frames: usize,
+    // Weighted sum of squares of the step in progress.
+    step_energy: f64,
+    step_position: usize,
+    // Mean-square energy of the last few complete steps.
+    recent_steps: [f64; STEPS_PER_BLOCK],
+    completed_steps: usize,
+    // Mean-square energy of every 400 ms block so far.
+    blocks: Vec<f64>,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        let channels = channels.max(1) as usize;
+        Self {
+            filters: (0..channels).map(|_| k_weighting_filters(sample_rate)).collect(),
+            weights: (0..channels).map(|c| channel_weight(c, channels)).collect(),
+            step_frames: ((BLOCK_SECONDS * sample_rate as f64) as usize / STEPS_PER_BLOCK).max(1),
+            step_energy: 0.0,
+            step_position: 0,
+            recent_steps: [0.0; STEPS_PER_BLOCK],
+            completed_steps: 0,
+            blocks: Vec::new(),
+        }
+    }
+
+    /// Feeds interleaved frames. Chunks may have any number of whole frames.
+    pub fn process_interleaved(&mut self, samples: &[f32]) {
+        let channels = self.filters.len();
+        for frame in samples.chunks_exact(channels) {
+            for (c, &sample) in frame.iter().enumerate() {
+                let [shelf, high_pass] = &mut self.filters[c];
+                let weighted = high_pass.process_sample(shelf.process_sample(sample)) as f64;
+                self.step_energy += self.weights[c] * weighted * weighted;
+            }
+            self.step_position += 1;
+            if self.step_position == self.step_frames {
+                self.finish_step();
+            }
+        }
+    }
+
+    fn finish_step(&mut self) {
+        self.recent_steps[self.completed_steps % STEPS_PER_BLOCK] = self.step_energy / self.step_frames as f64;
+        self.completed_steps += 1;
+        self.step_energy = 0.0;
+        self.step_position = 0;
+        if self.completed_steps >= STEPS_PER_BLOCK {
+            self.blocks.push(self.recent_steps.iter().sum::<f64>() / STEPS_PER_BLOCK as f64);
+        }
+    }
+
+    /// Number of complete 400 ms gating blocks measured so far.
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Gated loudness of everything fed so far in LUFS. Negative infinity
+    /// when nothing is above the absolute gate (silence, or less than 400 ms).
+    pub fn integrated_lufs(&self) -> f64 {
+        let absolute = lufs_to_energy(ABSOLUTE_GATE_LUFS);
+        let mean_above = |threshold: f64| {
+            let (sum, count) = self
+                .blocks
+                .iter()
+                .filter(|&&energy| energy > threshold)
+                .fold((0.0, 0usize), |(sum, count), &energy| (sum + energy, count + 1));
+            if count == 0 {
+                None
+            } else {
+                Some(sum / count as f64)
+            }
+        };
+
+        let Some(ungated) = mean_above(absolute) else {
+            return f64::NEG_INFINITY;
+        };
+        let relative = lufs_to_energy(energy_to_lufs(ungated) + RELATIVE_GATE_LU);
+        match mean_above(absolute.max(relative)) {
+            Some(energy) => energy_to_lufs(energy),
+            None => f64::NEG_INFINITY,
+        }
+    }
+}
+
+/// Integrated loudness of planar audio in LUFS.
+pub fn integrated_loudness(channels: &[Vec<f32>], sample_rate: u32) -> f64 {
+    if channels.is_empty() {
+        return f64::NEG_INFINITY;
+    }
+    let mut meter = LoudnessMeter::new(sample_rate, channels.len() as u16);
+    let frames = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+
+    // Interleave a bit at a time instead of copying the whole signal.
+    const CHUNK_FRAMES: usize = 4096;
+    let mut interleaved = Vec::with_capacity(CHUNK_FRAMES * channels.len());
+    for start in (0..frames).step_by(CHUNK_FRAMES) {
+        interleaved.clear();
+        for frame in start..(start + CHUNK_FRAMES).min(frames) {
+            interleaved.extend(channels.iter().map(|c| c[frame]));
+        }
+        meter.process_interleaved(&interleaved);
+    }
+    meter.integrated_lufs()
+}
+
+/// Oversampling used to find the peaks between samples (BS.1770-4 annex 2).
+pub const TRUE_PEAK_OVERSAMPLING: u32 = 4;
+
+/// Highest absolute level of planar audio including the peaks between
+/// samples, found by oversampling each channel, linear. Never below the
+/// sample peak.
+pub fn true_peak(channels: &[Vec<f32>], sample_rate: u32) -> f32 {
+    let sample_rate = sample_rate.max(1);
+    channels
+        .iter()
+        .map(|channel| {
+            let sample_peak = channel.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+            let mut resampler = StreamingResampler::new(sample_rate, sample_rate * TRUE_PEAK_OVERSAMPLING, 1);
+            let mut peak = resampler.process(channel).iter().fold(sample_peak, |peak, s| peak.max(s.abs()));
+            peak = resampler.flush().iter().fold(peak, |peak, s| peak.max(s.abs()));
+            peak
+        })
+        .fold(0.0, f32::max)
+}
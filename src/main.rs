@@ -1,24 +1,753 @@
 use cpal::traits::DeviceTrait;
-mod devices;
-mod stream;
-mod read_wav;
+use cpal_playbook::{
+    analysis, batch, config, denoise, devices, export, hook, json, limiter, meter_bus, project, read_wav, realtime,
+    recorder, session, shutdown, stream, voice, write_wav,
+};
 
-use read_wav::read_wave_file;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::time::Duration;
+
+use analysis::AnalyzeReport;
+use batch::BatchSettings;
+use config::Config;
+use project::Project;
+use read_wav::{read_audio_file, read_wave_file};
+
+#[cfg(feature = "alloc-check")]
+#[global_allocator]
+static ALLOCATOR: realtime::CountingAllocator = realtime::CountingAllocator;
+
+const USAGE: &str = "Usage:
+  cpal_playbook [--input=NAME] [--output=NAME]
+                                        run the device and wav demo
+  cpal_playbook devices [--json] [--host=NAME] [--min-channels=N]
+                                        list audio devices, of the default host or of NAME
+                                        (e.g. asio, wasapi, jack, alsa, coreaudio), or only
+                                        the ones with at least N channels
+  cpal_playbook devices --all-hosts [--per-host] [--json]
+                                        list the devices of every host, one row per device
+                                        with the hosts it appears under, or with --per-host
+                                        one row per host and device
+  cpal_playbook analyze <file> [--json] [--channel N]
+                                        level, loudness and spectral tilt of a wav file,
+                                        per channel for multichannel files
+  cpal_playbook render <project.toml> <out_dir> [--dry-run]
+                                        render a project, or only print the plan
+  cpal_playbook record <file> [--seconds=N] [--resume=SECONDS]
+                                        record the input until Enter or Ctrl-C, waiting up to
+                                        SECONDS for the device to come back if it is unplugged
+  cpal_playbook listen <dir>            record every sound event on the input into <dir>
+  cpal_playbook loopback <file> [--seconds=N] [--json]
+                                        record what the default output plays (WASAPI only)
+                                        for N seconds (default 5) and analyze it
+  cpal_playbook play <file>...          play wav files back to back without gaps
+  cpal_playbook signal <kind> [--freq=HZ] [--seconds=N] [--level=DB]
+                                        play a test signal: sine (1 kHz), white, pink, sweep
+                                        (20 Hz to 20 kHz) or impulse, at -12 dBFS for 5 s
+  cpal_playbook latency [--chirp]       measure the round trip from the output back to the input,
+                                        with an impulse or a sweep, through a loopback cable
+  cpal_playbook voice [--record=FILE] [--use-saved-device]
+                                        monitor the input through a voice chain with meters,
+                                        optionally recording the processed signal; with
+                                        --use-saved-device on the devices saved by the first run
+  cpal_playbook batch <in_dir> <out_dir> [--target=LUFS] [--post-cmd=CMD] [--jobs=N] [--timeout=SECONDS]
+                                        master every wav in <in_dir> for streaming, running
+                                        CMD after each file ({in} {out} {lufs} {peak} {duration})
+  cpal_playbook process <in> <out> --denoise=auto|NOISE.wav
+                                        remove steady noise, learned from the quietest stretch
+                                        of <in> or from a noise-only file, into a float wav
+
+The demo, play, signal, record, listen, latency and voice let you pick their devices from a list with --pick.
+Otherwise they take them from --input/--output, else from
+CPAL_PLAYBOOK_INPUT/CPAL_PLAYBOOK_OUTPUT, else from playbook.toml in the working directory
+or the platform config, else the defaults. NAME can be any part of a device name.";
 
 fn main() {
-    devices::print_devices();
+    shutdown::install();
+    // Loaded once, so its warnings are printed once.
+    let config = Config::load();
+    limiter::set_safety_defaults(config.safety_limiter_config());
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let json = args.iter().any(|a| a == "--json");
+    let positional = positional_args(&args);
+
+    let result = match positional.as_slice() {
+        [] => {
+            demo(&config, &args);
+            Ok(())
+        }
+        ["devices"] => list_devices(json, &args),
+        ["analyze", path] => analyze(path, json, &args),
+        ["listen", dir] => listen(dir, &config, &args),
+        ["voice"] => voice(&config, &args),
+        ["record", path] => record(path, &config, &args),
+        ["loopback", path] => loopback(path, json, &args),
+        ["latency"] => latency(&config, &args),
+        ["play", paths @ ..] if !paths.is_empty() => play(paths, &config, &args),
+        ["signal", kind] => signal(kind, &config, &args),
+        ["batch", in_dir, out_dir] => batch(in_dir, out_dir, &args),
+        ["process", input, output] => process(input, output, &args),
+        ["render", project, out_dir] => render_project(project, out_dir, args.iter().any(|a| a == "--dry-run")),
+        _ => Err(USAGE.into()),
+    };
+    if cfg!(feature = "alloc-check") {
+        eprintln!("{} allocations inside audio callbacks", realtime::callback_allocations());
+    }
+    if let Err(e) = result {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+}
+
+fn list_devices(json: bool, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    if args.iter().any(|a| a == "--all-hosts") {
+        let mut info = devices::collect_all_device_info();
+        if !args.iter().any(|a| a == "--per-host") {
+            info = devices::deduplicate(info);
+        }
+        if json {
+            println!("{}", json::to_string_pretty(&devices::device_info_document(&info)));
+        } else {
+            print!("{}", devices::format_device_table(&info));
+        }
+        return Ok(());
+    }
+    let host = option(args, "--host").map(devices::host_by_name).transpose()?;
+    if let Some(min) = parsed_option::<u16>(args, "--min-channels")? {
+        let lists = [
+            ("Input", devices::input_devices_with_channels(host.as_ref(), min)?),
+            ("Output", devices::output_devices_with_channels(host.as_ref(), min)?),
+        ];
+        if json {
+            // The same document as without the filter, less the devices
+            // that didn't pass it.
+            let names: Vec<String> =
+                lists.iter().flat_map(|(_, filtered)| &filtered.devices).filter_map(|d| d.name().ok()).collect();
+            let mut info = devices::collect_device_info(host.as_ref())?;
+            info.retain(|device| device.name.as_ref().is_some_and(|name| names.contains(name)));
+            for warning in lists.iter().flat_map(|(_, filtered)| &filtered.warnings) {
+                eprintln!("Warning: {}", warning);
+            }
+            println!("{}", json::to_string_pretty(&devices::device_info_document(&info)));
+            return Ok(());
+        }
+        for (label, filtered) in lists {
+            println!("{} devices with at least {} channels:", label, min);
+            for device in &filtered.devices {
+                println!("  {}", device.name().unwrap_or_else(|_| "Unknown device".to_string()));
+            }
+            for warning in &filtered.warnings {
+                eprintln!("Warning: {}", warning);
+            }
+        }
+        return Ok(());
+    }
+    if json {
+        println!("{}", devices::device_info_json(host.as_ref())?);
+    } else {
+        devices::print_devices(host.as_ref())?;
+    }
+    Ok(())
+}
+
+fn analyze(path: &str, json: bool, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let channel: Option<usize> = parsed_option(args, "--channel")?;
+    let report = AnalyzeReport::analyze(path)?;
+    if let Some(channel) = channel {
+        if channel == 0 || channel > report.channel_stats.len() {
+            return Err(format!("--channel must be between 1 and {}", report.channel_stats.len()).into());
+        }
+    }
+    if json {
+        println!("{}", json::to_string_pretty(&report.to_json()));
+        return Ok(());
+    }
+
+    let stats = &report.stats;
+    println!("File:              {}", report.file);
+    println!("Format:            {} Hz, {} channels", stats.sample_rate, stats.channels);
+    println!("Duration:          {:.2} s ({} frames)", stats.duration_seconds, stats.frames);
+    println!("Peak:              {:.2} dBFS", stats.peak_dbfs);
+    println!("RMS:               {:.2} dBFS", stats.rms_dbfs);
+    println!("Crest factor:      {:.2} dB", stats.crest_factor_db);
+    println!("DC offset:         {:.6}", stats.dc_offset);
+    println!("Clipped samples:   {}", stats.clipped_samples);
+    println!("Loudness:          {:.1} LUFS", report.integrated_loudness_lufs);
+    match report.spectral_tilt_db_per_octave {
+        Some(tilt) => println!("Spectral tilt:     {:.2} dB/octave", tilt),
+        None => println!("Spectral tilt:     n/a"),
+    }
+
+    if report.channel_stats.len() > 1 || channel.is_some() {
+        print_channels(&report, channel);
+    }
+    let warnings = report.warnings();
+    if !warnings.is_empty() {
+        println!("\nWarnings:");
+        for warning in warnings {
+            println!("  {}", warning);
+        }
+    }
+    Ok(())
+}
+
+// Per-channel table and the pairs against the first channel, limited to
+// `only` (numbered from 1) when given.
+fn print_channels(report: &AnalyzeReport, only: Option<usize>) {
+    let shown = |index: usize| only.is_none_or(|c| c == index + 1);
+    let db = |value: Option<f32>| value.map_or("n/a".to_string(), |v| format!("{:.2}", v));
+
+    println!("\nChannel  Peak dBFS  RMS dBFS  Crest dB  DC offset  Clipped");
+    for (i, stats) in report.channel_stats.iter().enumerate().filter(|(i, _)| shown(*i)) {
+        println!(
+            "{:>7}  {:>9.2}  {:>8.2}  {:>8.2}  {:>9.6}  {:>7}",
+            i + 1,
+            stats.peak_dbfs,
+            stats.rms_dbfs,
+            stats.crest_factor_db,
+            stats.dc_offset,
+            stats.clipped_samples
+        );
+    }
+
+    let pairs: Vec<_> = report.channel_pairs.iter().filter(|p| shown(p.reference) || shown(p.channel)).collect();
+    if !pairs.is_empty() {
+        println!("\nPair     Correlation  Balance dB  Delay (frames)");
+        for pair in pairs {
+            println!(
+                "{:<7}  {:>11}  {:>10}  {:>14}",
+                format!("{}-{}", pair.reference + 1, pair.channel + 1),
+                db(pair.correlation),
+                db(pair.balance_db),
+                pair.delay_frames.map_or("n/a".to_string(), |d| d.to_string())
+            );
+        }
+    }
+}
+
+fn render_project(path: &str, out_dir: &str, dry_run: bool) -> Result<(), Box<dyn std::error::Error>> {
+    let project = Project::load(Path::new(path))?;
+    project.validate()?;
+    for step in project.plan(Path::new(out_dir)) {
+        println!("{}", step);
+    }
+    if dry_run {
+        return Ok(());
+    }
+
+    let report = project.render(Path::new(out_dir))?;
+    println!(
+        "Wrote {} ({:.2} s, {:.1} LUFS)",
+        report.output.display(),
+        report.frames as f64 / report.sample_rate as f64,
+        report.loudness_lufs
+    );
+    Ok(())
+}
+
+/// Options that take a value, written `--name=value` or `--name value`.
+const VALUE_OPTIONS: [&str; 15] = [
+    "--channel",
+    "--target",
+    "--post-cmd",
+    "--jobs",
+    "--timeout",
+    "--seconds",
+    "--resume",
+    "--denoise",
+    "--record",
+    "--host",
+    "--min-channels",
+    "--input",
+    "--output",
+    "--freq",
+    "--level",
+];
+
+/// Arguments that are neither options nor the value of one.
+fn positional_args(args: &[String]) -> Vec<&str> {
+    let mut positional = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if VALUE_OPTIONS.contains(&arg.as_str()) {
+            args.next();
+        } else if !arg.starts_with("--") {
+            positional.push(arg.as_str());
+        }
+    }
+    positional
+}
+
+/// Value of a `--name=value` or `--name value` argument.
+fn option<'a>(args: &'a [String], name: &str) -> Option<&'a str> {
+    args.iter().enumerate().find_map(|(i, a)| match a.strip_prefix(name)? {
+        "" => args.get(i + 1).map(String::as_str),
+        rest => rest.strip_prefix('='),
+    })
+}
+
+fn parsed_option<T: std::str::FromStr>(args: &[String], name: &str) -> Result<Option<T>, String> {
+    option(args, name)
+        .map(|value| value.parse().map_err(|_| format!("Invalid value for {}: {}", name, value)))
+        .transpose()
+}
+
+fn batch(in_dir: &str, out_dir: &str, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let mut settings = BatchSettings::default();
+    if let Some(target) = parsed_option(args, "--target")? {
+        settings.master.target_lufs = target;
+    }
+    if let Some(template) = option(args, "--post-cmd") {
+        settings.post_command = Some(hook::PostCommand::parse(template).map_err(|e| format!("--post-cmd: {}", e))?);
+    }
+    if let Some(jobs) = parsed_option(args, "--jobs")? {
+        settings.max_concurrent_hooks = jobs;
+    }
+    if let Some(seconds) = parsed_option::<f64>(args, "--timeout")? {
+        settings.hook_timeout = Duration::from_secs_f64(seconds.max(0.0));
+    }
+
+    let report = batch::run_batch(Path::new(in_dir), Path::new(out_dir), &settings)?;
+    for file in &report.files {
+        match &file.result {
+            Ok(master) => println!(
+                "{} -> {} ({:.1} LUFS, {:.1} dBFS peak)",
+                file.input.display(),
+                file.output.display(),
+                master.output_lufs,
+                master.output_peak_dbfs
+            ),
+            Err(e) => eprintln!("{}: {}", file.input.display(), e),
+        }
+        if let Some(outcome) = file.hook.as_ref().filter(|outcome| !outcome.is_success()) {
+            eprintln!("{}: post-command {}", file.input.display(), outcome);
+        }
+    }
+    let failures = report.failures().count();
+    if failures > 0 {
+        return Err(format!("{} of {} files failed", failures, report.files.len()).into());
+    }
+    Ok(())
+}
 
-    let input_devices = devices::input_devices().unwrap();
+fn process(input: &str, output: &str, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let noise = option(args, "--denoise").ok_or("Nothing to do, give --denoise=auto or --denoise=NOISE.wav")?;
+    let data = read_audio_file(input)?;
+    let profile = if noise == "auto" {
+        let profile = denoise::learn_noise_profile_auto(&data.to_mono(), data.sample_rate)?;
+        if let Some(region) = &profile.region {
+            let seconds = |frame: usize| frame as f64 / data.sample_rate as f64;
+            println!("Learned the noise from {:.2} s to {:.2} s", seconds(region.start), seconds(region.end));
+        }
+        profile
+    } else {
+        let clip = read_audio_file(noise)?;
+        if clip.sample_rate != data.sample_rate {
+            return Err(format!("{} is at {} Hz, {} at {} Hz", noise, clip.sample_rate, input, data.sample_rate).into());
+        }
+        denoise::learn_noise_profile(&clip.to_mono(), clip.sample_rate)
+    };
 
-    println!("\nInput Devices:");
-    for device in &input_devices {
+    let channels: Vec<Vec<f32>> = data
+        .channels
+        .iter()
+        .map(|channel| denoise::denoise(channel, &profile, denoise::DEFAULT_REDUCTION_DB))
+        .collect();
+    export::write_with_preset(output, &channels, data.sample_rate, export::ExportPreset::Archive)?;
+    println!("Wrote {}", output);
+    Ok(())
+}
+
+fn record(path: &str, config: &Config, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let device = resolve_device(args, config, devices::Direction::Input)?;
+    let settings = session::RecordSettings {
+        device: Some(devices::device_name(&device)?),
+        max_duration: parsed_option::<f64>(args, "--seconds")?.map(|s| Duration::from_secs_f64(s.max(0.0))),
+        resume: parsed_option::<f64>(args, "--resume")?
+            .map(|s| session::ResumePolicy::new(Duration::from_secs_f64(s.max(0.0)))),
+        ..session::RecordSettings::default()
+    };
+
+    // Enter stops like Ctrl-C does. Reading stdin blocks, so it gets its
+    // own thread; it is left behind when the recording ends otherwise.
+    let stop = shutdown::flag();
+    std::thread::spawn(move || {
+        let _ = std::io::stdin().read_line(&mut String::new());
+        stop.store(true, Ordering::Release);
+    });
+    println!("Recording into {}, press Enter or Ctrl-C to stop", path);
+
+    let summary = match session::record_to_wav(Path::new(path), &settings, stop) {
+        Ok(summary) => summary,
+        Err(session::RecordError::Interrupted(interrupted)) => {
+            for file in &interrupted.files {
+                println!("Saved {}", file.display());
+            }
+            return Err(interrupted.into());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    for interrupted in &summary.interruptions {
+        eprintln!("{}, resumed", interrupted);
+    }
+    for file in &summary.files {
+        println!("Saved {}", file.display());
+    }
+    println!("Recorded {:.2} s", summary.seconds);
+    Ok(())
+}
+
+fn loopback(path: &str, json: bool, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let seconds = parsed_option::<f64>(args, "--seconds")?.unwrap_or(5.0).max(0.0);
+    let device = devices::loopback_device()?;
+    let mut capture = stream::make_loopback_stream(&device)?;
+    // On stderr, so that --json leaves one document on stdout.
+    eprintln!("Capturing {:.1} s of {}", seconds, devices::device_name(&device)?);
+    let samples = capture.capture(Duration::from_secs_f64(seconds));
+    let mut writer = write_wav::WavStreamWriter::create(
+        path,
+        capture.config.sample_rate.0,
+        capture.config.channels,
+        write_wav::WavSampleFormat::Float32,
+    )?;
+    writer.write_samples(&samples)?;
+    writer.finish()?;
+    eprintln!("Saved {}", path);
+    analyze(path, json, args)
+}
+
+fn play(paths: &[&str], config: &Config, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let device = resolve_device(args, config, devices::Direction::Output)?;
+    let mut playing = None;
+    stream::play_files(&device, paths, |position| {
+        if playing != Some(position.file) {
+            playing = Some(position.file);
+            println!("Playing {} ({}/{})", paths[position.file], position.file + 1, paths.len());
+        }
+    })?;
+    Ok(())
+}
+
+fn signal(kind: &str, config: &Config, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let device = resolve_device(args, config, devices::Direction::Output)?;
+    let seconds = parsed_option::<f32>(args, "--seconds")?.unwrap_or(5.0).max(0.0);
+    let kind = match kind {
+        "sine" => stream::SignalKind::Sine { freq: parsed_option(args, "--freq")?.unwrap_or(1000.0) },
+        "white" => stream::SignalKind::WhiteNoise,
+        "pink" => stream::SignalKind::PinkNoise,
+        "sweep" => stream::SignalKind::Sweep { start: 20.0, end: 20000.0, duration: seconds },
+        "impulse" => stream::SignalKind::Impulse,
+        other => return Err(format!("Unknown signal {}: use sine, white, pink, sweep or impulse", other).into()),
+    };
+    let level_db = parsed_option::<f32>(args, "--level")?.unwrap_or(-12.0).min(0.0);
+    println!("Playing {:?} at {} dBFS on {}", kind, level_db, devices::device_name(&device)?);
+    stream::play_signal(&device, kind, limiter::db_to_linear(level_db), Duration::from_secs_f32(seconds))?;
+    Ok(())
+}
+
+fn latency(config: &Config, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let input = resolve_device(args, config, devices::Direction::Input)?;
+    let output = resolve_device(args, config, devices::Direction::Output)?;
+    let probe = if args.iter().any(|a| a == "--chirp") {
+        stream::LatencyProbe::Chirp { seconds: 0.5 }
+    } else {
+        stream::LatencyProbe::Impulse
+    };
+    println!("Playing on {} and recording {}", devices::device_name(&output)?, devices::device_name(&input)?);
+    let report = stream::measure_latency(&output, &input, probe)?;
+    println!(
+        "Round trip: {} frames ({:.1} ms) at {} Hz, confidence {:.2}",
+        report.frames, report.ms, report.sample_rate, report.confidence
+    );
+    Ok(())
+}
+
+fn listen(dir: &str, config: &Config, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let device = resolve_device(args, config, devices::Direction::Input)?;
+    let recording = recorder::record_triggered(&device, Path::new(dir), recorder::TriggerSettings::default())?;
+    println!("Listening on {}, press Ctrl-C to stop", device.name().unwrap_or_else(|_| "Unknown device".to_string()));
+    for event in recording.events() {
+        match event {
+            recorder::RecorderEvent::Started(path) => println!("Recording {}", path.display()),
+            recorder::RecorderEvent::Finished(path) => println!("Saved {}", path.display()),
+            recorder::RecorderEvent::Failed(e) => eprintln!("Error: {}", e),
+            recorder::RecorderEvent::Interrupted(e) => return Err(format!("Input lost: {}", e).into()),
+        }
+    }
+    Ok(())
+}
+
+fn voice(config: &Config, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let (input, output) = if args.iter().any(|a| a == "--use-saved-device") {
+        (
+            saved_device(args, config, devices::Direction::Input)?,
+            saved_device(args, config, devices::Direction::Output)?,
+        )
+    } else {
+        (
+            resolve_device(args, config, devices::Direction::Input)?,
+            resolve_device(args, config, devices::Direction::Output)?,
+        )
+    };
+    let output_config = output.default_output_config()?;
+    let sample_rate = output_config.sample_rate().0;
+
+    let record_path = option(args, "--record");
+    let mut recording = record_path.map(|path| voice::VoiceRecording::create(path, sample_rate)).transpose()?;
+    let controls = Arc::new(voice::VoiceControls::new());
+    let (input_meter, mut input_tap) = meter_bus::meter_bus(voice::INPUT_METER_SAMPLES);
+    let chain = voice::voice_chain(
+        sample_rate,
+        &voice::VoiceSettings::default(),
+        Arc::clone(&controls),
+        input_meter,
+        recording.as_mut().and_then(|recording| recording.take_tap()),
+    );
+    let options = stream::MonitorOptions {
+        live_chain: Some(chain),
+        safety_limiter: config.safety_limiter_config(),
+        meter: None,
+    };
+    // Nothing to play back; the mix is the live signal alone.
+    let playback = Arc::new(stream::PlayerSource::new(Vec::new(), sample_rate, output_config.channels()));
+    let mix = stream::monitor_mix(&input, &output, playback, Arc::new(stream::MonitorControls::default()), options)?;
+
+    println!("Monitoring {} at {} Hz", input.name().unwrap_or_else(|_| "Unknown device".to_string()), sample_rate);
+    for (i, stage) in voice::STAGES.iter().enumerate() {
+        println!("  {} + Enter bypasses the {}", i + 1, stage.name());
+    }
+    println!("  q + Enter stops");
+    // Only to tell the user why the sound stopped; the mix doesn't reopen
+    // a device that was unplugged.
+    let watcher = devices::DeviceWatcher::spawn(None, Duration::from_secs(1)).ok();
+
+    let (sender, lines) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lines() {
+            let Ok(line) = line else { break };
+            if sender.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    let mut until_meter = 0;
+    while !shutdown::requested() {
+        match lines.try_recv() {
+            Ok(line) if line.trim() == "q" => break,
+            Ok(line) => match line.trim().parse::<usize>().ok().and_then(|n| voice::STAGES.get(n.wrapping_sub(1))) {
+                Some(&stage) => {
+                    let bypassed = controls.toggle(stage);
+                    println!("{} {}", stage.name(), if bypassed { "bypassed" } else { "on" });
+                }
+                None => println!("Unknown command {:?}", line.trim()),
+            },
+            Err(std::sync::mpsc::TryRecvError::Disconnected) => break,
+            Err(std::sync::mpsc::TryRecvError::Empty) => {}
+        }
+        while let Some(event) = watcher.as_ref().and_then(devices::DeviceWatcher::try_event) {
+            match event {
+                devices::DeviceEvent::Added(name) => println!("Device connected: {}", name),
+                devices::DeviceEvent::Removed(name) => println!("Device disconnected: {}", name),
+            }
+        }
+        if let Some(recording) = recording.as_mut() {
+            recording.drain()?;
+        }
+
+        // Meters about four times a second.
+        if until_meter == 0 {
+            until_meter = 5;
+            let (peak, rms) = input_tap
+                .latest()
+                .map_or((0.0, 0.0), |block| (block.peaks()[0], block.rms()[0]));
+            println!(
+                "in {:6.1} dBFS peak {:6.1} dBFS rms | comp -{:4.1} dB | limit -{:4.1} dB | latency {:.1} ms",
+                analysis::linear_to_db(peak),
+                analysis::linear_to_db(rms),
+                controls.take_compressor_reduction_db(),
+                controls.take_limiter_reduction_db(),
+                mix.live_latency().as_secs_f64() * 1000.0
+            );
+        }
+        until_meter -= 1;
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    if mix.live_underruns() > 0 {
+        eprintln!("Warning: the input ran dry {} times", mix.live_underruns());
+    }
+    if mix.live_overruns() > 0 {
+        eprintln!("Warning: the output fell behind the input {} times", mix.live_overruns());
+    }
+    drop(mix);
+    if let Some(watcher) = watcher {
+        watcher.stop();
+    }
+    if let Some(recording) = recording {
+        let overflows = recording.overflows();
+        let seconds = recording.finish()?;
+        println!("Recorded {:.2} s into {}", seconds, record_path.unwrap_or_default());
+        if overflows > 0 {
+            eprintln!("Warning: the file is missing audio from {} blocks the writer fell behind on", overflows);
+        }
+    }
+    Ok(())
+}
 
-        println!("  Input Device: {}", device.name().unwrap_or_else(|_| "Unknown device".to_string()));
+// The device picked from a list with `--pick`, or the one named by
+// `--input`/`--output`, then by the environment
+// (`CPAL_PLAYBOOK_INPUT`/`CPAL_PLAYBOOK_OUTPUT`), then by the config file,
+// or else the default device.
+fn resolve_device(
+    args: &[String],
+    config: &Config,
+    direction: devices::Direction,
+) -> Result<cpal::Device, devices::DeviceError> {
+    let (flag, configured) = match direction {
+        devices::Direction::Input => ("--input", config.input_device.as_deref()),
+        devices::Direction::Output => ("--output", config.output_device.as_deref()),
+    };
+    if args.iter().any(|a| a == "--pick") {
+        return devices::pick_device_interactive(direction);
     }
-    println!("\nThe length of inputs: {}", input_devices.len());
+    if let Some(name) = option(args, flag) {
+        return devices::device_or_default(direction, Some(name));
+    }
+    if devices::device_query_from_env(direction).is_some() {
+        return devices::device_from_env(direction);
+    }
+    devices::device_or_default(direction, configured)
+}
+
+// The device saved by an earlier run, or on the first run the one picked
+// as usual, which is then saved for the next.
+fn saved_device(
+    args: &[String],
+    config: &Config,
+    direction: devices::Direction,
+) -> Result<cpal::Device, Box<dyn std::error::Error>> {
+    let dir = config::config_dir().ok_or("No config directory found to save the device in")?;
+    let path = dir.join(format!("{}_device.toml", direction));
+    if path.exists() {
+        return Ok(devices::DeviceSelector::load(&path)?.resolve()?);
+    }
+    let device = resolve_device(args, config, direction)?;
+    devices::DeviceSelector::from_device(&cpal::default_host(), &device, direction)?.save(&path)?;
+    println!("Saved the {} device in {}", direction, path.display());
+    Ok(device)
+}
 
-    let (samples, sample_rate) = read_wave_file("./examples/speech_with_artificial_reverb.wav").unwrap();
-    println!("Sample rate: {}", sample_rate);
+fn demo(config: &Config, args: &[String]) {
+    if let Err(e) = devices::print_devices(None) {
+        eprintln!("{}", e);
+    }
+
+    println!();
+    // Resolved once, as --pick asks for each.
+    let input = resolve_device(args, config, devices::Direction::Input);
+    let output = resolve_device(args, config, devices::Direction::Output);
+    for (label, device) in [("input", &input), ("output", &output)] {
+        match device {
+            Ok(device) => {
+                println!("Using {} device: {}", label, device.name().unwrap_or_else(|_| "Unknown device".to_string()))
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+    }
+
+    let started = std::time::Instant::now();
+    match devices::DeviceCache::new(None) {
+        Ok(cache) => {
+            let took = cache.enumerated_at().duration_since(started);
+            println!("\nListed the devices in {:.1} ms", took.as_secs_f64() * 1000.0);
+            println!("\nInput Devices:");
+            for device in cache.inputs() {
+                println!("  Input Device: {}", device.name().unwrap_or_else(|_| "Unknown device".to_string()));
+            }
+            println!("\nThe length of inputs: {}", cache.inputs().len());
+            println!("\nOutput Devices:");
+            for device in cache.outputs() {
+                println!("  Output Device: {}", device.name().unwrap_or_else(|_| "Unknown device".to_string()));
+            }
+            println!("\nThe length of outputs: {}", cache.outputs().len());
+        }
+        Err(e) => eprintln!("{}", e),
+    }
+    match devices::default_output_device() {
+        Ok(device) => println!("Default output device: {}", device.name().unwrap_or_else(|_| "Unknown device".to_string())),
+        Err(e) => println!("{}", e),
+    }
+
+    let example = "./examples/speech_with_artificial_reverb.wav";
+    let (samples, spec) = match read_wave_file(example) {
+        Ok(read) => read,
+        Err(e) => {
+            eprintln!("{}: {}", example, e);
+            return;
+        }
+    };
+    println!("Sample rate: {}", spec.sample_rate);
+    println!("Sample format: {:?}, {} bits", spec.sample_format, spec.bits_per_sample);
+    println!("Channels: {}", spec.channels);
     println!("Number of samples: {}", samples.len());
 
+    let devices = [
+        ("Input", &input, devices::Direction::Input),
+        ("Output", &output, devices::Direction::Output),
+    ];
+    for (label, device, direction) in devices {
+        let Ok(device) = device else { continue };
+        match devices::negotiate_config(device, spec.sample_rate, spec.channels, direction) {
+            Ok(matched) => println!(
+                "{} config for it: {} Hz, {} channels, {}{}",
+                label,
+                matched.config.sample_rate.0,
+                matched.config.channels,
+                matched.sample_format,
+                if matched.needs_resampling { " (needs resampling)" } else { "" }
+            ),
+            Err(e) => eprintln!("{} config for it: {}", label, e),
+        }
+        let default = match direction {
+            devices::Direction::Input => device.default_input_config(),
+            devices::Direction::Output => device.default_output_config(),
+        };
+        if let Ok(default) = default {
+            let supported = devices::is_config_supported(device, &default.config(), cpal::SampleFormat::F32, direction);
+            println!("{} default config in f32: {}", label, if supported.unwrap_or(false) { "yes" } else { "no" });
+        }
+    }
+
+    // Opened on silence and closed again, only to see what the backend takes.
+    if let Ok(device) = &output {
+        let silence = |data: &mut [f32], _: &cpal::OutputCallbackInfo| data.fill(0.0);
+        match stream::build_lowest_latency_output(device, silence) {
+            Ok(opened) => println!(
+                "Smallest output buffer: {} frames ({:.1} ms){}",
+                opened.buffer_frames,
+                opened.latency_ms(),
+                if opened.refused.is_empty() { String::new() } else { format!(", refused {:?}", opened.refused) }
+            ),
+            Err(e) => eprintln!("Smallest output buffer: {}", e),
+        }
+
+        match stream::make_output_stream(device, 440.0, 0.2, &stream::StreamOptions::default()) {
+            Ok((tone, config)) => {
+                println!("Playing 2 s of 440 Hz ({} ch @ {} Hz)", config.channels, config.sample_rate.0);
+                std::thread::sleep(Duration::from_secs(2));
+                drop(tone);
+            }
+            Err(e) => eprintln!("{}", e),
+        }
+
+        println!("Playing the example");
+        if let Err(e) =
+            stream::play_samples(device, &samples, spec.sample_rate, spec.channels, &stream::PlaybackOptions::default())
+        {
+            eprintln!("{}", e);
+        }
+    }
 }
@@ -0,0 +1,265 @@
+// Mastering for streaming platforms: gain the whole file to a target
+// integrated loudness, then catch the peaks that pushes over the ceiling
+// with the lookahead limiter.
+//
+// `master_for_streaming` works on audio already in memory. For recordings
+// too long for that, `master_file_for_streaming` reads the file twice in
+// chunks: once to measure it (the meter keeps one value per 100 ms, not the
+// audio) and once to apply the gain and the limiter, writing as it goes.
+use crate::analysis::linear_to_db;
+use crate::limiter::{db_to_linear, Limiter, LimiterSettings};
+use crate::loudness::LoudnessMeter;
+use crate::read_wav::{WavChunkReader, WavData};
+use crate::write_wav::{WavSampleFormat, WavStreamWriter};
+
+/// Frames read, processed and written at a time by the streaming version.
+pub const MASTER_CHUNK_FRAMES: usize = 65536;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MasterSettings {
+    pub target_lufs: f32,
+    pub limiter: LimiterSettings,
+}
+
+impl Default for MasterSettings {
+    fn default() -> Self {
+        Self {
+            target_lufs: -14.0,
+            limiter: LimiterSettings::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MasterReport {
+    /// Integrated loudness of the input in LUFS.
+    pub input_lufs: f64,
+    /// Gain applied before the limiter; 0 for silent input.
+    pub gain_db: f32,
+    /// Integrated loudness and sample peak of the result.
+    pub output_lufs: f64,
+    pub output_peak_dbfs: f32,
+    pub sample_rate: u32,
+    pub frames: usize,
+}
+
+impl MasterReport {
+    pub fn duration_seconds(&self) -> f64 {
+        self.frames as f64 / self.sample_rate.max(1) as f64
+    }
+}
+
+// Loudness and peak of the output, fed as it is produced.
+struct OutputMeter {
+    meter: LoudnessMeter,
+    peak: f32,
+}
+
+impl OutputMeter {
+    fn new(sample_rate: u32, channels: u16) -> Self {
+        Self { meter: LoudnessMeter::new(sample_rate, channels), peak: 0.0 }
+    }
+
+    fn process(&mut self, samples: &[f32]) {
+        self.meter.process_interleaved(samples);
+        self.peak = samples.iter().fold(self.peak, |peak, s| peak.max(s.abs()));
+    }
+}
+
+// Gain that brings `input_lufs` to the target. Silence can't be brought to
+// any loudness, so it is left alone.
+fn normalization_gain_db(input_lufs: f64, target_lufs: f32) -> f32 {
+    if input_lufs.is_finite() {
+        target_lufs - input_lufs as f32
+    } else {
+        0.0
+    }
+}
+
+/// Masters planar audio held in memory.
+pub fn master_for_streaming(data: &WavData, settings: &MasterSettings) -> (WavData, MasterReport) {
+    let channels = data.channels.len().max(1);
+    let frames = data.frames();
+    let interleaved: Vec<f32> = (0..frames).flat_map(|i| data.channels.iter().map(move |c| c[i])).collect();
+
+    let mut meter = LoudnessMeter::new(data.sample_rate, channels as u16);
+    meter.process_interleaved(&interleaved);
+    let input_lufs = meter.integrated_lufs();
+    let gain_db = normalization_gain_db(input_lufs, settings.target_lufs);
+
+    let gain = db_to_linear(gain_db);
+    let gained: Vec<f32> = interleaved.iter().map(|s| s * gain).collect();
+    let limited = Limiter::new(data.sample_rate, channels as u16, settings.limiter).process_offline(&gained);
+    let mut output_meter = OutputMeter::new(data.sample_rate, channels as u16);
+    output_meter.process(&limited);
+
+    let mut output = vec![Vec::with_capacity(frames); data.channels.len()];
+    for frame in limited.chunks_exact(channels) {
+        for (channel, &sample) in output.iter_mut().zip(frame) {
+            channel.push(sample);
+        }
+    }
+    let report = MasterReport {
+        input_lufs,
+        gain_db,
+        output_lufs: output_meter.meter.integrated_lufs(),
+        output_peak_dbfs: linear_to_db(output_meter.peak),
+        sample_rate: data.sample_rate,
+        frames,
+    };
+    (WavData { channels: output, sample_rate: data.sample_rate }, report)
+}
+
+/// Masters the WAV file at `input` into `output` in two passes over the
+/// file, holding no more than `MASTER_CHUNK_FRAMES` frames of audio at once.
+/// The result is the same as `master_for_streaming` on the whole file.
+pub fn master_file_for_streaming(
+    input: &str,
+    output: &str,
+    format: WavSampleFormat,
+    settings: &MasterSettings,
+) -> Result<MasterReport, Box<dyn std::error::Error>> {
+    // Pass one: measure.
+    let mut reader = WavChunkReader::open(input, MASTER_CHUNK_FRAMES)?;
+    let spec = reader.spec();
+    let mut meter = LoudnessMeter::new(spec.sample_rate, spec.channels);
+    while let Some(chunk) = reader.next_chunk()? {
+        meter.process_interleaved(chunk);
+    }
+    let input_lufs = meter.integrated_lufs();
+    let gain_db = normalization_gain_db(input_lufs, settings.target_lufs);
+    let gain = db_to_linear(gain_db);
+
+    // Pass two: gain, limit and write.
+    let mut reader = WavChunkReader::open(input, MASTER_CHUNK_FRAMES)?;
+    let mut limiter = Limiter::new(spec.sample_rate, spec.channels, settings.limiter);
+    let mut writer = WavStreamWriter::create(output, spec.sample_rate, spec.channels, format)?;
+    let mut output_meter = OutputMeter::new(spec.sample_rate, spec.channels);
+    let channels = spec.channels.max(1) as usize;
+    // The limiter's output lags by its lookahead: the first samples out of
+    // it are the empty delay line, and the last ones are pushed out with
+    // silence at the end.
+    let latency = limiter.latency_frames() * channels;
+    let mut to_skip = latency;
+    let mut block = Vec::with_capacity(MASTER_CHUNK_FRAMES * channels);
+    while let Some(chunk) = reader.next_chunk()? {
+        block.clear();
+        block.extend(chunk.iter().map(|s| s * gain));
+        limiter.process_block(&mut block);
+        let skip = to_skip.min(block.len());
+        to_skip -= skip;
+        output_meter.process(&block[skip..]);
+        writer.write_samples(&block[skip..])?;
+    }
+    // A file shorter than the lookahead still comes out at its own length.
+    let mut tail = vec![0.0; latency];
+    limiter.process_block(&mut tail);
+    let written = latency - to_skip;
+    output_meter.process(&tail[to_skip..to_skip + written]);
+    writer.write_samples(&tail[to_skip..to_skip + written])?;
+
+    let frames = writer.frames_written() as usize;
+    writer.finish()?;
+    Ok(MasterReport {
+        input_lufs,
+        gain_db,
+        output_lufs: output_meter.meter.integrated_lufs(),
+        output_peak_dbfs: linear_to_db(output_meter.peak),
+        sample_rate: spec.sample_rate,
+        frames,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_wav::read_wav_data;
+    use crate::write_wav::write_wave_file;
+
+    // A path in the temp directory, removed when dropped.
+    struct TempPath(std::path::PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("cpal_playbook_{}_{}", std::process::id(), name)))
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    // Seconds of a stereo tone swelling from quiet to loud, with a spike, so
+    // the gating and the limiter both have work to do.
+    fn program(seconds: f32, sample_rate: u32) -> WavData {
+        let frames = (seconds * sample_rate as f32) as usize;
+        let make = |freq: f32| -> Vec<f32> {
+            (0..frames)
+                .map(|i| {
+                    let t = i as f32 / sample_rate as f32;
+                    let swell = 0.05 + 0.3 * (t / seconds);
+                    let spike = if i % 20_000 < 40 { 0.6 } else { 0.0 };
+                    swell * (2.0 * std::f32::consts::PI * freq * t).sin() + spike
+                })
+                .collect()
+        };
+        WavData { channels: vec![make(220.0), make(331.0)], sample_rate }
+    }
+
+    fn master_through_files(data: &WavData, settings: &MasterSettings) -> (WavData, MasterReport) {
+        let input = TempPath::new(&format!("master_in_{}.wav", data.frames()));
+        let output = TempPath::new(&format!("master_out_{}.wav", data.frames()));
+        let interleaved: Vec<f32> = (0..data.frames()).flat_map(|i| data.channels.iter().map(move |c| c[i])).collect();
+        write_wave_file(input.path(), &interleaved, data.sample_rate, 2, WavSampleFormat::Float32).unwrap();
+        let report = master_file_for_streaming(input.path(), output.path(), WavSampleFormat::Float32, settings).unwrap();
+        (read_wav_data(output.path()).unwrap(), report)
+    }
+
+    #[test]
+    fn streaming_matches_the_in_memory_version() {
+        // Long enough for more than one chunk.
+        let data = program(4.0, 44_100);
+        assert!(data.frames() > 2 * MASTER_CHUNK_FRAMES);
+        let settings = MasterSettings::default();
+
+        let (in_memory, expected) = master_for_streaming(&data, &settings);
+        let (streamed, report) = master_through_files(&data, &settings);
+
+        assert_eq!(report.frames, expected.frames);
+        assert_eq!(streamed.frames(), data.frames());
+        assert!((report.input_lufs - expected.input_lufs).abs() < 0.1, "{} vs {}", report.input_lufs, expected.input_lufs);
+        assert!((report.gain_db - expected.gain_db).abs() < 0.1);
+        assert!((report.output_lufs - expected.output_lufs).abs() < 0.1, "{} vs {}", report.output_lufs, expected.output_lufs);
+        assert!((report.output_peak_dbfs - expected.output_peak_dbfs).abs() < 0.1);
+        for (a, b) in streamed.channels.iter().flatten().zip(in_memory.channels.iter().flatten()) {
+            assert!((a - b).abs() < 1e-4, "{} vs {}", a, b);
+        }
+    }
+
+    #[test]
+    fn result_reaches_the_target_under_the_ceiling() {
+        let settings = MasterSettings { target_lufs: -16.0, ..MasterSettings::default() };
+        let (_, report) = master_through_files(&program(3.0, 48_000), &settings);
+        assert!((report.output_lufs - -16.0).abs() < 1.0, "{}", report.output_lufs);
+        assert!(report.output_peak_dbfs <= settings.limiter.ceiling_db + 0.01, "{}", report.output_peak_dbfs);
+    }
+
+    #[test]
+    fn silence_and_short_files_keep_their_length() {
+        // Shorter than the limiter's lookahead.
+        let short = WavData { channels: vec![vec![0.2; 100], vec![-0.2; 100]], sample_rate: 48_000 };
+        let (output, report) = master_through_files(&short, &MasterSettings::default());
+        assert_eq!((output.frames(), report.frames), (100, 100));
+
+        let silent = WavData { channels: vec![vec![0.0; 30_000]; 2], sample_rate: 48_000 };
+        let (output, report) = master_through_files(&silent, &MasterSettings::default());
+        assert_eq!(report.gain_db, 0.0);
+        assert!(output.channels.iter().flatten().all(|&s| s == 0.0));
+    }
+}
@@ -0,0 +1,232 @@
+// Match EQ: measure the long-term spectrum of two signals and design the
+// filter that makes the first sound like the second.
+//
+// The correction is exported as a minimum-phase FIR, which puts all of its
+// energy as early as possible (no pre-ringing, minimal latency) while
+// keeping exactly the requested magnitude response. It is obtained from the
+// log-magnitude curve through the real cepstrum: folding the cepstrum onto
+// its causal half and exponentiating gives the minimum-phase spectrum with
+// that magnitude.
+use rustfft::num_complex::Complex;
+
+use crate::fft::{fft, ifft};
+use crate::stft::hann_window;
+use crate::write_wav::{WavSampleFormat, WavStreamWriter};
+
+/// Largest boost or cut the correction curve may apply, in dB. Keeps bins
+/// where the source has (almost) no energy from turning into huge boosts.
+pub const MAX_CORRECTION_DB: f32 = 24.0;
+/// The correction is held constant outside this range.
+const MIN_MATCH_FREQ: f32 = 20.0;
+const MAX_MATCH_FREQ: f32 = 20000.0;
+/// Fraction of the impulse response faded out at its end after truncation.
+const IR_FADE_FRACTION: f32 = 0.125;
+
+/// Long-term magnitude spectrum of `samples`: the average of Hann-windowed
+/// frames of `fft_size` at 50% overlap, `fft_size / 2 + 1` bins from DC to
+/// Nyquist.
+pub fn average_spectrum(samples: &[f32], fft_size: usize) -> Vec<f32> {
+    let window = hann_window(fft_size);
+    let hop = (fft_size / 2).max(1);
+    let mut sum = vec![0.0f32; fft_size / 2 + 1];
+    let mut frames = 0;
+
+    let mut start = 0;
+    while start + fft_size <= samples.len() {
+        let frame: Vec<f32> = samples[start..start + fft_size]
+            .iter()
+            .zip(&window)
+            .map(|(x, w)| x * w)
+            .collect();
+        for (acc, bin) in sum.iter_mut().zip(fft(&frame)) {
+            *acc += bin.norm();
+        }
+        frames += 1;
+        start += hop;
+    }
+
+    if frames > 0 {
+        for acc in sum.iter_mut() {
+            *acc /= frames as f32;
+        }
+    }
+    sum
+}
+
+/// Correction in dB per bin that turns `source_spectrum` into
+/// `reference_spectrum`, limited to ±`MAX_CORRECTION_DB`.
+pub fn match_eq_curve(source_spectrum: &[f32], reference_spectrum: &[f32]) -> Vec<f32> {
+    assert_eq!(
+        source_spectrum.len(),
+        reference_spectrum.len(),
+        "source and reference spectra need the same number of bins"
+    );
+    let floor = f32::MIN_POSITIVE.sqrt();
+    source_spectrum
+        .iter()
+        .zip(reference_spectrum)
+        .map(|(&source, &reference)| {
+            let db = 20.0 * ((reference + floor) / (source + floor)).log10();
+            db.clamp(-MAX_CORRECTION_DB, MAX_CORRECTION_DB)
+        })
+        .collect()
+}
+
+/// Designs a minimum-phase FIR of `ir_length` taps with the magnitude
+/// response `reference_spectrum / source_spectrum`.
+///
+/// Both spectra are magnitudes with bins evenly spaced from DC to the
+/// Nyquist frequency of `sample_rate`, as returned by `average_spectrum`.
+pub fn match_eq_to_ir(
+    source_spectrum: &[f32],
+    reference_spectrum: &[f32],
+    ir_length: usize,
+    sample_rate: u32,
+) -> Vec<f32> {
+    let curve = match_eq_curve(source_spectrum, reference_spectrum);
+    if curve.is_empty() || ir_length == 0 {
+        return Vec::new();
+    }
+
+    // Design on a grid much longer than the IR so that the cepstrum, which
+    // is infinitely long in theory, does not alias much.
+    let n = (4 * ir_length).max(2 * curve.len()).next_power_of_two();
+    let nyquist = sample_rate as f32 / 2.0;
+    let last_bin = (curve.len() - 1).max(1) as f32;
+
+    // Log magnitude (natural log) of the desired response on the design grid.
+    let log_magnitude: Vec<f32> = (0..=n / 2)
+        .map(|j| {
+            let freq = (j as f32 * sample_rate as f32 / n as f32).clamp(MIN_MATCH_FREQ, MAX_MATCH_FREQ.min(nyquist));
+            let position = freq / nyquist * last_bin;
+            let lower = (position.floor() as usize).min(curve.len() - 1);
+            let upper = (lower + 1).min(curve.len() - 1);
+            let fraction = position - lower as f32;
+            let db = curve[lower] * (1.0 - fraction) + curve[upper] * fraction;
+            db / 20.0 * std::f32::consts::LN_10
+        })
+        .collect();
+
+    let full: Vec<Complex<f32>> = (0..n)
+        .map(|k| Complex { re: log_magnitude[if k <= n / 2 { k } else { n - k }], im: 0.0 })
+        .collect();
+    let cepstrum = ifft(&full);
+
+    // Fold the anti-causal half of the cepstrum onto the causal half.
+    let folded: Vec<f32> = (0..n)
+        .map(|k| match k {
+            0 => cepstrum[0],
+            k if k < n / 2 => 2.0 * cepstrum[k],
+            k if k == n / 2 => cepstrum[k],
+            _ => 0.0,
+        })
+        .collect();
+
+    let minimum_phase: Vec<Complex<f32>> = fft(&folded).iter().map(|c| c.exp()).collect();
+    let mut ir = ifft(&minimum_phase);
+    ir.truncate(ir_length);
+
+    // The tail is tiny for sensible lengths, but cut it off smoothly anyway.
+    let fade = ((ir_length as f32 * IR_FADE_FRACTION) as usize).max(1);
+    for (i, sample) in ir.iter_mut().rev().take(fade).enumerate() {
+        *sample *= 0.5 * (1.0 - (std::f32::consts::PI * i as f32 / fade as f32).cos());
+    }
+    ir
+}
+
+/// Writes an impulse response as a mono 32-bit float WAV, the format
+/// convolution plugins load.
+pub fn export_ir_wav(path: &str, ir: &[f32], sample_rate: u32) -> std::io::Result<()> {
+    let mut writer = WavStreamWriter::create(path, sample_rate, 1, WavSampleFormat::Float32)?;
+    writer.write_samples(ir)?;
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fft::fft_convolve;
+    use crate::filters::BiquadFilter;
+
+    const RATE: u32 = 48_000;
+    const FFT_SIZE: usize = 2048;
+
+    // Two seconds of deterministic white noise; the tree has no rand dependency.
+    fn noise() -> Vec<f32> {
+        let mut state = 0x2545_f491u32;
+        (0..2 * RATE as usize)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as f32 / u32::MAX as f32 - 0.5
+            })
+            .collect()
+    }
+
+    // The noise with +9 dB below 200 Hz and -9 dB above 5 kHz.
+    fn tilted(samples: &[f32]) -> Vec<f32> {
+        let mut low = BiquadFilter::new_lowshelf(RATE as f32, 200.0, 9.0, 1.0);
+        let mut high = BiquadFilter::new_highshelf(RATE as f32, 5_000.0, -9.0, 1.0);
+        samples.iter().map(|&x| high.process_sample(low.process_sample(x))).collect()
+    }
+
+    // Mean absolute difference between two spectra over octave bands from
+    // 63 Hz to 16 kHz, in dB.
+    fn band_error_db(spectrum: &[f32], reference: &[f32]) -> f32 {
+        let bin_hz = RATE as f32 / FFT_SIZE as f32;
+        let band_db = |spectrum: &[f32], low: f32| {
+            let bins = (low / bin_hz) as usize..(2.0 * low / bin_hz) as usize;
+            let power: f32 = spectrum[bins.clone()].iter().map(|m| m * m).sum();
+            10.0 * (power / bins.len() as f32).log10()
+        };
+        let bands: Vec<f32> = (0..8).map(|octave| 62.5 * 2f32.powi(octave)).collect();
+        bands.iter().map(|&low| (band_db(spectrum, low) - band_db(reference, low)).abs()).sum::<f32>() / bands.len() as f32
+    }
+
+    #[test]
+    fn the_matched_source_is_closer_to_the_reference() {
+        let source = noise();
+        let reference = tilted(&source);
+        let (source_spectrum, reference_spectrum) = (average_spectrum(&source, FFT_SIZE), average_spectrum(&reference, FFT_SIZE));
+        let ir = match_eq_to_ir(&source_spectrum, &reference_spectrum, 1024, RATE);
+        assert_eq!(ir.len(), 1024);
+
+        let matched = &fft_convolve(&source, &ir)[..source.len()];
+        let before = band_error_db(&source_spectrum, &reference_spectrum);
+        let after = band_error_db(&average_spectrum(matched, FFT_SIZE), &reference_spectrum);
+        assert!(before > 3.0, "the tilt is only {before} dB");
+        assert!(after < 0.2 && after < before / 10.0, "{before} dB apart before matching, {after} dB after");
+    }
+
+    #[test]
+    fn the_impulse_response_is_minimum_phase() {
+        let source = noise();
+        let (source_spectrum, reference_spectrum) = (average_spectrum(&source, FFT_SIZE), average_spectrum(&tilted(&source), FFT_SIZE));
+        let ir = match_eq_to_ir(&source_spectrum, &reference_spectrum, 1024, RATE);
+        let energy = |taps: &[f32]| taps.iter().map(|x| x * x).sum::<f32>();
+        let total = energy(&ir);
+        let peak = ir.iter().enumerate().max_by(|a, b| a.1.abs().total_cmp(&b.1.abs())).unwrap().0;
+        assert!(peak < 4, "the peak is at tap {peak}");
+        // A linear-phase filter would centre its energy on tap 512.
+        assert!(energy(&ir[..128]) > 0.99 * total, "{} of the energy in the first 128 taps", energy(&ir[..128]) / total);
+        // The tail the end fade works on holds next to nothing.
+        assert!(energy(&ir[..ir.len() / 2]) > 100.0 * energy(&ir[ir.len() / 2..]));
+    }
+
+    #[test]
+    fn matching_a_spectrum_to_itself_is_a_unit_impulse() {
+        let spectrum = average_spectrum(&noise(), FFT_SIZE);
+        let ir = match_eq_to_ir(&spectrum, &spectrum, 256, RATE);
+        assert!((ir[0] - 1.0).abs() < 1e-3, "first tap {}", ir[0]);
+        assert!(ir[1..].iter().all(|tap| tap.abs() < 1e-3));
+        assert!(match_eq_to_ir(&spectrum, &spectrum, 0, RATE).is_empty());
+    }
+
+    #[test]
+    fn the_correction_is_clamped() {
+        let curve = match_eq_curve(&[1.0, 1.0, 0.0, 1.0], &[2.0, 0.0, 1.0, 1e6]);
+        assert!((curve[0] - 20.0 * 2f32.log10()).abs() < 1e-4);
+        assert_eq!(curve[1..], [-MAX_CORRECTION_DB, MAX_CORRECTION_DB, MAX_CORRECTION_DB]);
+    }
+}
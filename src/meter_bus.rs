@@ -0,0 +1,265 @@
+// One audio callback, any number of meters: the callback publishes each
+// block once and every consumer reads the newest one on its own schedule.
+//
+// Blocks go round a small ring of slots, each guarded by a sequence lock:
+// the writer makes the slot's sequence odd, writes, and makes it even again;
+// a reader copies the slot and keeps the copy only if the sequence was even
+// and unchanged across the copy, so it never sees half of one block and
+// half of the next. The writer never waits for anyone. A reader that falls
+// behind skips straight to the newest block and learns how many it missed.
+//
+// The samples are stored as atomic bits, so readers racing the writer is
+// well defined and the whole thing needs no unsafe code.
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{atomic, Arc};
+
+/// Slots in the ring. A reader only has to retry if the writer gets this
+/// many blocks ahead during one copy.
+const SLOTS: usize = 4;
+
+struct Slot {
+    // Odd while being written; the block number is `sequence / 2`.
+    sequence: AtomicU64,
+    channels: AtomicUsize,
+    len: AtomicUsize,
+    samples: Box<[AtomicU32]>,
+}
+
+struct Shared {
+    slots: Vec<Slot>,
+    // Blocks published so far.
+    published: AtomicU64,
+}
+
+/// Creates a bus for interleaved blocks of up to `max_block_samples`
+/// samples. Longer blocks are cut to that length.
+pub fn meter_bus(max_block_samples: usize) -> (MeterPublisher, MeterTap) {
+    let shared = Arc::new(Shared {
+        slots: (0..SLOTS)
+            .map(|_| Slot {
+                sequence: AtomicU64::new(0),
+                channels: AtomicUsize::new(1),
+                len: AtomicUsize::new(0),
+                samples: (0..max_block_samples).map(|_| AtomicU32::new(0)).collect(),
+            })
+            .collect(),
+        published: AtomicU64::new(0),
+    });
+    let tap = MeterTap { shared: Arc::clone(&shared), block: MeterBlock::default(), last_read: 0, missed: 0 };
+    (MeterPublisher { shared }, tap)
+}
+
+/// The writing end, for the audio callback. There is only one.
+pub struct MeterPublisher {
+    shared: Arc<Shared>,
+}
+
+impl MeterPublisher {
+    /// Publishes one interleaved block. Never blocks or allocates.
+    pub fn publish(&mut self, samples: &[f32], channels: u16) {
+        let number = self.shared.published.load(Ordering::Relaxed) + 1;
+        let slot = &self.shared.slots[(number % SLOTS as u64) as usize];
+        slot.sequence.store(2 * number - 1, Ordering::Relaxed);
+        // Readers must not see the new samples before the odd sequence.
+        atomic::fence(Ordering::Release);
+
+        let len = samples.len().min(slot.samples.len());
+        for (stored, &sample) in slot.samples.iter().zip(&samples[..len]) {
+            stored.store(sample.to_bits(), Ordering::Relaxed);
+        }
+        slot.len.store(len, Ordering::Relaxed);
+        slot.channels.store(channels.max(1) as usize, Ordering::Relaxed);
+        slot.sequence.store(2 * number, Ordering::Release);
+        self.shared.published.store(number, Ordering::Release);
+    }
+
+    /// Blocks published so far.
+    pub fn published(&self) -> u64 {
+        self.shared.published.load(Ordering::Relaxed)
+    }
+}
+
+/// A consumer's copy of a published block.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MeterBlock {
+    /// Counts from 1 in publishing order.
+    pub number: u64,
+    pub channels: usize,
+    /// Interleaved.
+    pub samples: Vec<f32>,
+}
+
+impl MeterBlock {
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1)
+    }
+
+    /// Highest absolute sample of each channel, linear.
+    pub fn peaks(&self) -> Vec<f32> {
+        let mut peaks = vec![0.0f32; self.channels];
+        for frame in self.samples.chunks_exact(self.channels.max(1)) {
+            for (peak, s) in peaks.iter_mut().zip(frame) {
+                *peak = peak.max(s.abs());
+            }
+        }
+        peaks
+    }
+
+    /// RMS of each channel, linear.
+    pub fn rms(&self) -> Vec<f32> {
+        let mut sums = vec![0.0f32; self.channels];
+        for frame in self.samples.chunks_exact(self.channels.max(1)) {
+            for (sum, s) in sums.iter_mut().zip(frame) {
+                *sum += s * s;
+            }
+        }
+        let frames = self.frames().max(1) as f32;
+        sums.into_iter().map(|sum| (sum / frames).sqrt()).collect()
+    }
+}
+
+/// A reading end. Clone it for every consumer; reading never blocks the
+/// writer or the other taps.
+pub struct MeterTap {
+    shared: Arc<Shared>,
+    block: MeterBlock,
+    last_read: u64,
+    missed: u64,
+}
+
+impl Clone for MeterTap {
+    /// A new tap that starts from the newest block.
+    fn clone(&self) -> Self {
+        Self { shared: Arc::clone(&self.shared), block: MeterBlock::default(), last_read: 0, missed: 0 }
+    }
+}
+
+impl MeterTap {
+    /// The newest block, if one was published since the last call.
+    pub fn latest(&mut self) -> Option<&MeterBlock> {
+        loop {
+            let number = self.shared.published.load(Ordering::Acquire);
+            if number == 0 || number == self.last_read {
+                return None;
+            }
+            let slot = &self.shared.slots[(number % SLOTS as u64) as usize];
+            let before = slot.sequence.load(Ordering::Acquire);
+            if before != 2 * number {
+                // Already being overwritten; a newer block is on its way.
+                continue;
+            }
+            let len = slot.len.load(Ordering::Relaxed);
+            let channels = slot.channels.load(Ordering::Relaxed);
+            self.block.samples.clear();
+            self.block.samples.extend(slot.samples[..len].iter().map(|s| f32::from_bits(s.load(Ordering::Relaxed))));
+            // The copy has to be finished before the sequence is checked.
+            atomic::fence(Ordering::Acquire);
+            if slot.sequence.load(Ordering::Relaxed) != before {
+                continue;
+            }
+
+            if self.last_read > 0 {
+                self.missed += number - self.last_read - 1;
+            }
+            self.last_read = number;
+            self.block.number = number;
+            self.block.channels = channels;
+            return Some(&self.block);
+        }
+    }
+
+    /// Blocks this tap skipped because newer ones had arrived.
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn taps_see_the_newest_block_once() {
+        let (mut publisher, mut tap) = meter_bus(8);
+        assert!(tap.latest().is_none());
+
+        publisher.publish(&[0.5, -0.25, 0.1, -1.0], 2);
+        let block = tap.latest().unwrap().clone();
+        assert_eq!(block, MeterBlock { number: 1, channels: 2, samples: vec![0.5, -0.25, 0.1, -1.0] });
+        assert_eq!(block.frames(), 2);
+        assert_eq!(block.peaks(), [0.5, 1.0]);
+        assert!((block.rms()[0] - ((0.25 + 0.01) / 2.0f32).sqrt()).abs() < 1e-6);
+        assert!(tap.latest().is_none());
+
+        for n in 2..=6 {
+            publisher.publish(&[n as f32], 1);
+        }
+        assert_eq!(publisher.published(), 6);
+        assert_eq!(tap.latest().unwrap().samples, [6.0]);
+        assert_eq!(tap.missed(), 4);
+
+        // A new tap starts from the newest block, and blocks are cut to size.
+        let mut late = tap.clone();
+        assert_eq!(late.latest().unwrap().number, 6);
+        publisher.publish(&[1.0; 20], 2);
+        assert_eq!(late.latest().unwrap().samples.len(), 8);
+        assert_eq!(late.missed(), 0);
+    }
+
+    // Block `n` is `n % 61 + 1` samples all equal to `n`, so a reader can
+    // tell a torn copy from a whole one.
+    fn block_for(number: u64) -> Vec<f32> {
+        vec![number as f32; (number % 61 + 1) as usize]
+    }
+
+    #[test]
+    fn readers_never_see_torn_or_stale_blocks() {
+        const BLOCKS: u64 = 200_000;
+        let (mut publisher, tap) = meter_bus(64);
+        // What the writer had finished publishing, for the freshness check.
+        let done = Arc::new(AtomicU64::new(0));
+
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let mut tap = tap.clone();
+                let done = Arc::clone(&done);
+                thread::spawn(move || {
+                    let (mut first, mut last, mut reads) = (0, 0, 0u64);
+                    loop {
+                        let finished = done.load(Ordering::Acquire);
+                        match tap.latest() {
+                            Some(block) => {
+                                assert_eq!(block.samples, block_for(block.number), "torn block {}", block.number);
+                                assert!(block.number > last, "block {} after {}", block.number, last);
+                                assert!(block.number >= finished, "stale block {} after {} was done", block.number, finished);
+                                if first == 0 {
+                                    first = block.number;
+                                }
+                                last = block.number;
+                                reads += 1;
+                            }
+                            None => assert!(last >= finished, "nothing new although {} was done", finished),
+                        }
+                        if finished == BLOCKS && last == BLOCKS {
+                            return (first, reads, tap.missed());
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        // The writer never waits on the readers: it gets through every
+        // block however they are scheduled.
+        for number in 1..=BLOCKS {
+            publisher.publish(&block_for(number), 1);
+            done.store(number, Ordering::Release);
+        }
+        for reader in readers {
+            // Every block from the first one read on was either read or
+            // counted as missed.
+            let (first, reads, missed) = reader.join().unwrap();
+            assert_eq!(reads + missed, BLOCKS - first + 1);
+        }
+    }
+}
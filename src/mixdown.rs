@@ -0,0 +1,229 @@
+// Mixing tracks down to a stereo pair, with an optional ceiling on the sum.
+use crate::limiter::{db_to_linear, Limiter, LimiterSettings};
+
+/// A mono or stereo source placed on the timeline.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Track {
+    /// One channel (panned) or two (balanced). Only the first two of a
+    /// wider track are mixed, so downmix those first.
+    pub channels: Vec<Vec<f32>>,
+    pub gain_db: f32,
+    /// -1 is hard left, 0 center, 1 hard right.
+    pub pan: f32,
+    /// Frame of the mix at which the track starts.
+    pub offset_frames: usize,
+}
+
+impl Track {
+    pub fn mono(samples: Vec<f32>) -> Self {
+        Self {
+            channels: vec![samples],
+            gain_db: 0.0,
+            pan: 0.0,
+            offset_frames: 0,
+        }
+    }
+
+    pub fn stereo(left: Vec<f32>, right: Vec<f32>) -> Self {
+        Self {
+            channels: vec![left, right],
+            ..Self::mono(Vec::new())
+        }
+    }
+
+    pub fn with_gain_db(mut self, gain_db: f32) -> Self {
+        self.gain_db = gain_db;
+        self
+    }
+
+    pub fn with_pan(mut self, pan: f32) -> Self {
+        self.pan = pan.clamp(-1.0, 1.0);
+        self
+    }
+
+    pub fn with_offset(mut self, offset_frames: usize) -> Self {
+        self.offset_frames = offset_frames;
+        self
+    }
+
+    fn frames(&self) -> usize {
+        self.channels.iter().map(|c| c.len()).max().unwrap_or(0)
+    }
+
+    // Left and right gains: constant power panning for mono tracks, plain
+    // balance for stereo ones.
+    fn stereo_gains(&self) -> (f32, f32) {
+        let gain = db_to_linear(self.gain_db);
+        if self.channels.len() == 1 {
+            let angle = (self.pan + 1.0) * std::f32::consts::FRAC_PI_4;
+            (gain * angle.cos(), gain * angle.sin())
+        } else {
+            (gain * (1.0 - self.pan).min(1.0), gain * (1.0 + self.pan).min(1.0))
+        }
+    }
+}
+
+/// What happens to the stereo sum when it gets too hot.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Ceiling {
+    /// Leave the sum as it is, even above 0 dBFS.
+    #[default]
+    None,
+    /// Clip at ±1.0.
+    HardClip,
+    /// Waveshape samples above `-knee_db` dBFS smoothly towards full scale.
+    /// Everything below the knee passes through bit for bit.
+    SoftSat { knee_db: f32 },
+    /// Run the sum through the lookahead limiter, compensating its delay.
+    Limiter { settings: LimiterSettings },
+}
+
+/// Processing applied to the stereo sum before `mixdown` returns it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct BusProcessor {
+    pub ceiling: Ceiling,
+}
+
+impl BusProcessor {
+    pub fn new(ceiling: Ceiling) -> Self {
+        Self { ceiling }
+    }
+
+    pub fn process(&self, left: &mut [f32], right: &mut [f32], sample_rate: u32) {
+        match self.ceiling {
+            Ceiling::None => {}
+            Ceiling::HardClip => {
+                for sample in left.iter_mut().chain(right.iter_mut()) {
+                    *sample = sample.clamp(-1.0, 1.0);
+                }
+            }
+            Ceiling::SoftSat { knee_db } => {
+                let knee = db_to_linear(-knee_db.abs());
+                for sample in left.iter_mut().chain(right.iter_mut()) {
+                    *sample = soft_saturate(*sample, knee);
+                }
+            }
+            Ceiling::Limiter { settings } => {
+                let interleaved: Vec<f32> = left.iter().zip(right.iter()).flat_map(|(&l, &r)| [l, r]).collect();
+                let limited = Limiter::new(sample_rate, 2, settings).process_offline(&interleaved);
+                for (i, frame) in limited.chunks_exact(2).enumerate() {
+                    left[i] = frame[0];
+                    right[i] = frame[1];
+                }
+            }
+        }
+    }
+}
+
+/// Identity up to `knee`; above it the excess is compressed with tanh so
+/// the curve stays continuous with slope 1 at the knee and never goes past
+/// full scale. In f32 tanh rounds to 1.0 for large inputs, so a sample far
+/// enough above the knee comes out at exactly ±1.0.
+pub fn soft_saturate(sample: f32, knee: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= knee || knee >= 1.0 {
+        return sample;
+    }
+    let headroom = 1.0 - knee;
+    sample.signum() * (knee + headroom * ((magnitude - knee) / headroom).tanh())
+}
+
+/// Sums `tracks` into a stereo pair long enough for the last one to end,
+/// then applies `bus`. Channels past the second of a track are ignored.
+pub fn mixdown(tracks: &[Track], sample_rate: u32, bus: &BusProcessor) -> (Vec<f32>, Vec<f32>) {
+    let frames = tracks.iter().map(|t| t.offset_frames + t.frames()).max().unwrap_or(0);
+    let mut left = vec![0.0f32; frames];
+    let mut right = vec![0.0f32; frames];
+
+    for track in tracks {
+        let (left_gain, right_gain) = track.stereo_gains();
+        let (source_left, source_right) = match track.channels.as_slice() {
+            [mono] => (mono, mono),
+            [l, r, ..] => (l, r),
+            [] => continue,
+        };
+        for (i, &sample) in source_left.iter().enumerate() {
+            left[track.offset_frames + i] += sample * left_gain;
+        }
+        for (i, &sample) in source_right.iter().enumerate() {
+            right[track.offset_frames + i] += sample * right_gain;
+        }
+    }
+
+    bus.process(&mut left, &mut right, sample_rate);
+    (left, right)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::thd;
+
+    const RATE: u32 = 48_000;
+
+    fn sine(amplitude: f32, frames: usize) -> Vec<f32> {
+        (0..frames).map(|i| amplitude * (2.0 * std::f32::consts::PI * 1_000.0 * i as f32 / RATE as f32).sin()).collect()
+    }
+
+    fn peak(samples: &[f32]) -> f32 {
+        samples.iter().fold(0.0f32, |peak, &sample| peak.max(sample.abs()))
+    }
+
+    type Stereo = (Vec<f32>, Vec<f32>);
+
+    // Two overlapping tracks summing to `peak_dbfs` on both sides, mixed
+    // without a ceiling and then with `ceiling`.
+    fn mix(peak_dbfs: f32, ceiling: Ceiling) -> (Stereo, Stereo) {
+        let tone = sine(db_to_linear(peak_dbfs) / 2.0, 16_384);
+        let track = Track::stereo(tone.clone(), tone);
+        let tracks = [track.clone(), track];
+        (mixdown(&tracks, RATE, &BusProcessor::default()), mixdown(&tracks, RATE, &BusProcessor::new(ceiling)))
+    }
+
+    #[test]
+    fn soft_saturation_leaves_a_quiet_mix_bit_identical() {
+        let (dry, saturated) = mix(-20.0, Ceiling::SoftSat { knee_db: 6.0 });
+        assert!((peak(&dry.0) - db_to_linear(-20.0)).abs() < 1e-4);
+        assert_eq!(saturated, dry);
+    }
+
+    #[test]
+    fn an_over_hot_mix_comes_back_below_full_scale() {
+        let ceilings = [Ceiling::HardClip, Ceiling::SoftSat { knee_db: 6.0 }, Ceiling::Limiter { settings: LimiterSettings::default() }];
+        for ceiling in ceilings {
+            let (dry, (left, right)) = mix(6.0, ceiling);
+            assert!(peak(&dry.0) > 1.9);
+            assert!(peak(&left) <= 1.0 && peak(&right) <= 1.0, "{:?} peaks at {}", ceiling, peak(&left).max(peak(&right)));
+        }
+        let (_, (left, _)) = mix(6.0, Ceiling::Limiter { settings: LimiterSettings::default() });
+        assert!(peak(&left) <= db_to_linear(-1.0) + 1e-6);
+    }
+
+    #[test]
+    fn soft_saturation_distorts_less_than_hard_clipping() {
+        let (_, (clipped, _)) = mix(3.0, Ceiling::HardClip);
+        let (_, (saturated, _)) = mix(3.0, Ceiling::SoftSat { knee_db: 6.0 });
+        let (clipped, saturated) = (thd(&clipped, RATE, 1_000.0, 10), thd(&saturated, RATE, 1_000.0, 10));
+        assert!(saturated < clipped, "THD {saturated} soft against {clipped} hard");
+    }
+
+    #[test]
+    fn soft_saturation_reaches_but_never_passes_full_scale() {
+        for knee_db in [0.5, 6.0, 20.0] {
+            let knee = db_to_linear(-knee_db);
+            assert_eq!(soft_saturate(knee, knee), knee);
+            assert_eq!(soft_saturate(-knee * 0.5, knee), -knee * 0.5);
+            assert!(soft_saturate(1.0, knee) < 1.0);
+            assert_eq!(soft_saturate(100.0, knee), 1.0);
+            assert_eq!(soft_saturate(-100.0, knee), -1.0);
+        }
+    }
+
+    #[test]
+    fn only_the_first_two_channels_of_a_wide_track_are_mixed() {
+        let wide = Track { channels: vec![vec![0.5; 4], vec![-0.25; 4], vec![1.0; 4]], ..Track::mono(Vec::new()) };
+        let (left, right) = mixdown(std::slice::from_ref(&wide), RATE, &BusProcessor::default());
+        let stereo = Track::stereo(wide.channels[0].clone(), wide.channels[1].clone());
+        assert_eq!((left, right), mixdown(&[stereo], RATE, &BusProcessor::default()));
+    }
+}
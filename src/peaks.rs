@@ -0,0 +1,426 @@
+// Waveform peak cache for big recordings.
+//
+// Min/max peaks are computed at a base resolution and folded into coarser
+// levels (4x per level), like the .pek files DAWs keep next to the audio.
+// The pyramid is stored in a sidecar file together with a fingerprint of
+// the source, so re-opening a file only decodes it again when it changed.
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use crate::read_wav::normalized_samples;
+
+/// Frames per peak at the finest level when `load_or_build` has to build.
+pub const DEFAULT_RESOLUTION: usize = 256;
+/// Each level holds a quarter of the peaks of the level below it.
+const LEVEL_FACTOR: usize = 4;
+
+const MAGIC: &[u8; 4] = b"CPPK";
+const FORMAT_VERSION: u32 = 1;
+/// Bytes hashed from each end of the source for the fingerprint.
+const FINGERPRINT_EDGE: u64 = 4096;
+
+/// Identifies a source file's contents cheaply: size, modification time and
+/// a hash of its first and last few kilobytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SourceFingerprint {
+    pub size: u64,
+    pub modified_nanos: u64,
+    pub edge_hash: u64,
+}
+
+impl SourceFingerprint {
+    pub fn of(path: &Path) -> std::io::Result<Self> {
+        let mut file = File::open(path)?;
+        let metadata = file.metadata()?;
+        let size = metadata.len();
+        let modified_nanos = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+
+        let mut hash = Fnv1a::new();
+        let mut edge = vec![0u8; FINGERPRINT_EDGE.min(size) as usize];
+        file.read_exact(&mut edge)?;
+        hash.update(&edge);
+        file.seek(SeekFrom::Start(size - edge.len() as u64))?;
+        file.read_exact(&mut edge)?;
+        hash.update(&edge);
+
+        Ok(Self { size, modified_nanos, edge_hash: hash.finish() })
+    }
+}
+
+struct Fnv1a(u64);
+
+impl Fnv1a {
+    fn new() -> Self {
+        Fnv1a(0xcbf2_9ce4_8422_2325)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.0 ^= b as u64;
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// One zoom level: for every block of `frames_per_peak` frames, the min and
+/// max of each channel, interleaved by channel.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeakLevel {
+    pub frames_per_peak: usize,
+    pub min: Vec<f32>,
+    pub max: Vec<f32>,
+}
+
+impl PeakLevel {
+    /// Number of peaks per channel.
+    pub fn len(&self, channels: u16) -> usize {
+        self.min.len() / channels.max(1) as usize
+    }
+
+    // Folds `LEVEL_FACTOR` peaks at a time into the next coarser level.
+    fn coarser(&self, channels: usize) -> PeakLevel {
+        let group = LEVEL_FACTOR * channels;
+        let fold = |values: &[f32], pick: fn(f32, f32) -> f32| -> Vec<f32> {
+            values
+                .chunks(group)
+                .flat_map(|chunk| {
+                    (0..channels).map(move |c| chunk.iter().skip(c).step_by(channels).copied().reduce(pick).unwrap())
+                })
+                .collect()
+        };
+        PeakLevel {
+            frames_per_peak: self.frames_per_peak * LEVEL_FACTOR,
+            min: fold(&self.min, f32::min),
+            max: fold(&self.max, f32::max),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PeakCache {
+    pub channels: u16,
+    pub sample_rate: u32,
+    pub frames: u64,
+    /// Finest level first.
+    pub levels: Vec<PeakLevel>,
+    pub source: SourceFingerprint,
+}
+
+impl PeakCache {
+    /// Decodes `path` once and computes the peak pyramid, with
+    /// `resolution` frames per peak at the finest level.
+    pub fn build(path: &Path, resolution: usize) -> Result<PeakCache, Box<dyn Error>> {
+        let resolution = resolution.max(1);
+        let source = SourceFingerprint::of(path)?;
+        let mut reader = hound::WavReader::open(path)?;
+        let spec = reader.spec();
+        let channels = spec.channels.max(1) as usize;
+
+        let mut min = Vec::new();
+        let mut max = Vec::new();
+        let mut block_min = vec![f32::INFINITY; channels];
+        let mut block_max = vec![f32::NEG_INFINITY; channels];
+        let mut frames: u64 = 0;
+        let mut frame_in_block = 0;
+
+        let mut samples = normalized_samples(&mut reader)?;
+        'frames: loop {
+            for c in 0..channels {
+                let sample = match samples.next() {
+                    Some(sample) => sample?,
+                    None => break 'frames,
+                };
+                block_min[c] = block_min[c].min(sample);
+                block_max[c] = block_max[c].max(sample);
+            }
+            frames += 1;
+            frame_in_block += 1;
+            if frame_in_block == resolution {
+                min.extend_from_slice(&block_min);
+                max.extend_from_slice(&block_max);
+                block_min.fill(f32::INFINITY);
+                block_max.fill(f32::NEG_INFINITY);
+                frame_in_block = 0;
+            }
+        }
+        if frame_in_block > 0 {
+            min.extend_from_slice(&block_min);
+            max.extend_from_slice(&block_max);
+        }
+
+        let mut levels = vec![PeakLevel { frames_per_peak: resolution, min, max }];
+        while levels.last().unwrap().len(spec.channels) > 1 {
+            let next = levels.last().unwrap().coarser(channels);
+            levels.push(next);
+        }
+
+        Ok(PeakCache {
+            channels: spec.channels,
+            sample_rate: spec.sample_rate,
+            frames,
+            levels,
+            source,
+        })
+    }
+
+    /// Where the cache for `path` lives: next to it, with `.peaks` appended.
+    pub fn sidecar_path(path: &Path) -> PathBuf {
+        let mut sidecar = path.as_os_str().to_owned();
+        sidecar.push(".peaks");
+        PathBuf::from(sidecar)
+    }
+
+    /// Returns the cached peaks when the sidecar matches the current source,
+    /// otherwise rebuilds at `DEFAULT_RESOLUTION` and rewrites the sidecar.
+    pub fn load_or_build(path: &Path) -> Result<PeakCache, Box<dyn Error>> {
+        let sidecar = PeakCache::sidecar_path(path);
+        let current = SourceFingerprint::of(path)?;
+        if let Ok(cache) = PeakCache::load(&sidecar) {
+            if cache.source == current {
+                return Ok(cache);
+            }
+        }
+
+        let cache = PeakCache::build(path, DEFAULT_RESOLUTION)?;
+        if let Err(e) = cache.save(&sidecar) {
+            eprintln!("Warning: could not write peak cache {}: {}", sidecar.display(), e);
+        }
+        Ok(cache)
+    }
+
+    /// The coarsest level that still has at least one peak per
+    /// `frames_per_pixel` frames, i.e. the cheapest one to draw at that zoom.
+    pub fn level_for(&self, frames_per_pixel: usize) -> &PeakLevel {
+        self.levels
+            .iter()
+            .rev()
+            .find(|level| level.frames_per_peak <= frames_per_pixel)
+            .unwrap_or(&self.levels[0])
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        out.write_all(MAGIC)?;
+        out.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        out.write_all(&self.source.size.to_le_bytes())?;
+        out.write_all(&self.source.modified_nanos.to_le_bytes())?;
+        out.write_all(&self.source.edge_hash.to_le_bytes())?;
+        out.write_all(&self.channels.to_le_bytes())?;
+        out.write_all(&self.sample_rate.to_le_bytes())?;
+        out.write_all(&self.frames.to_le_bytes())?;
+        out.write_all(&(self.levels.len() as u32).to_le_bytes())?;
+        for level in &self.levels {
+            out.write_all(&(level.frames_per_peak as u64).to_le_bytes())?;
+            out.write_all(&(level.min.len() as u64).to_le_bytes())?;
+            for (lo, hi) in level.min.iter().zip(&level.max) {
+                out.write_all(&lo.to_le_bytes())?;
+                out.write_all(&hi.to_le_bytes())?;
+            }
+        }
+        out.flush()
+    }
+
+    pub fn load(path: &Path) -> Result<PeakCache, Box<dyn Error>> {
+        let mut input = BufReader::new(File::open(path)?);
+        let mut magic = [0u8; 4];
+        input.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err("Not a peak cache file".into());
+        }
+        if read_u32(&mut input)? != FORMAT_VERSION {
+            return Err("Unsupported peak cache version".into());
+        }
+
+        let source = SourceFingerprint {
+            size: read_u64(&mut input)?,
+            modified_nanos: read_u64(&mut input)?,
+            edge_hash: read_u64(&mut input)?,
+        };
+        let mut channels = [0u8; 2];
+        input.read_exact(&mut channels)?;
+        let channels = u16::from_le_bytes(channels);
+        let sample_rate = read_u32(&mut input)?;
+        let frames = read_u64(&mut input)?;
+
+        let level_count = read_u32(&mut input)?;
+        let mut levels = Vec::with_capacity(level_count as usize);
+        for _ in 0..level_count {
+            let frames_per_peak = read_u64(&mut input)? as usize;
+            let count = read_u64(&mut input)? as usize;
+            let mut min = Vec::with_capacity(count);
+            let mut max = Vec::with_capacity(count);
+            for _ in 0..count {
+                min.push(f32::from_bits(read_u32(&mut input)?));
+                max.push(f32::from_bits(read_u32(&mut input)?));
+            }
+            levels.push(PeakLevel { frames_per_peak, min, max });
+        }
+        if levels.is_empty() {
+            return Err("Peak cache has no levels".into());
+        }
+
+        Ok(PeakCache { channels, sample_rate, frames, levels, source })
+    }
+}
+
+fn read_u32(input: &mut impl Read) -> std::io::Result<u32> {
+    let mut bytes = [0u8; 4];
+    input.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(input: &mut impl Read) -> std::io::Result<u64> {
+    let mut bytes = [0u8; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::write_wav::{write_wave_file, WavSampleFormat};
+    use std::fs::OpenOptions;
+    use std::time::{Duration, SystemTime};
+
+    // A WAV file in the temp directory, removed with its sidecar when dropped.
+    struct TempWav(PathBuf);
+
+    impl TempWav {
+        fn new(name: &str, samples: &[f32]) -> Self {
+            let path = std::env::temp_dir().join(format!("cpal_playbook_{}_{}", std::process::id(), name));
+            write_wave_file(path.to_str().unwrap(), samples, 48_000, 2, WavSampleFormat::Float32).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempWav {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+            let _ = std::fs::remove_file(PeakCache::sidecar_path(&self.0));
+        }
+    }
+
+    // 10 000 stereo frames of two different waves, so the channels' peaks differ.
+    fn stereo() -> Vec<f32> {
+        (0..10_000).flat_map(|i| [(i as f32 * 0.01).sin() * 0.9, ((i % 700) as f32 / 700.0 - 0.5) * 0.6]).collect()
+    }
+
+    // Min and max of every channel over blocks of `frames`, interleaved.
+    fn direct_peaks(samples: &[f32], frames: usize) -> (Vec<f32>, Vec<f32>) {
+        let (mut min, mut max) = (Vec::new(), Vec::new());
+        for block in samples.chunks(2 * frames) {
+            for channel in 0..2 {
+                let values = block.iter().skip(channel).step_by(2);
+                min.push(values.clone().copied().fold(f32::INFINITY, f32::min));
+                max.push(values.copied().fold(f32::NEG_INFINITY, f32::max));
+            }
+        }
+        (min, max)
+    }
+
+    // Marks the finest peak of the sidecar, so a reload shows whether the
+    // cache was used or rebuilt.
+    fn tamper_with_sidecar(path: &Path) {
+        let sidecar = PeakCache::sidecar_path(path);
+        let mut cache = PeakCache::load(&sidecar).unwrap();
+        cache.levels[0].max[0] = 42.0;
+        cache.save(&sidecar).unwrap();
+    }
+
+    fn set_modified(path: &Path, time: SystemTime) {
+        OpenOptions::new().write(true).open(path).unwrap().set_modified(time).unwrap();
+    }
+
+    #[test]
+    fn pyramid_matches_a_direct_computation_and_survives_a_reload() {
+        let samples = stereo();
+        let file = TempWav::new("pyramid.wav", &samples);
+        let cache = PeakCache::build(&file.0, 16).unwrap();
+        assert_eq!((cache.channels, cache.sample_rate, cache.frames), (2, 48_000, 10_000));
+        assert_eq!(cache.source, SourceFingerprint::of(&file.0).unwrap());
+
+        let mut frames_per_peak = 16;
+        for level in &cache.levels {
+            assert_eq!(level.frames_per_peak, frames_per_peak);
+            assert_eq!((level.min.clone(), level.max.clone()), direct_peaks(&samples, frames_per_peak));
+            frames_per_peak *= LEVEL_FACTOR;
+        }
+        // 10 000 frames at 16 per peak is 625 peaks: 157, 40, 10, 3, then 1.
+        let lengths: Vec<usize> = cache.levels.iter().map(|level| level.len(2)).collect();
+        assert_eq!(lengths, [625, 157, 40, 10, 3, 1]);
+
+        let sidecar = PeakCache::sidecar_path(&file.0);
+        cache.save(&sidecar).unwrap();
+        assert_eq!(PeakCache::load(&sidecar).unwrap(), cache);
+    }
+
+    #[test]
+    fn a_valid_sidecar_is_used_without_decoding() {
+        let file = TempWav::new("cached.wav", &stereo());
+        let built = PeakCache::load_or_build(&file.0).unwrap();
+        assert_eq!(built.levels[0].frames_per_peak, DEFAULT_RESOLUTION);
+        assert_eq!(PeakCache::load(&PeakCache::sidecar_path(&file.0)).unwrap(), built);
+
+        tamper_with_sidecar(&file.0);
+        assert_eq!(PeakCache::load_or_build(&file.0).unwrap().levels[0].max[0], 42.0);
+    }
+
+    #[test]
+    fn changing_the_source_invalidates_the_sidecar() {
+        let samples = stereo();
+        let file = TempWav::new("changed.wav", &samples);
+        let modified = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        set_modified(&file.0, modified);
+        let original = PeakCache::load_or_build(&file.0).unwrap();
+
+        // A newer modification time alone.
+        tamper_with_sidecar(&file.0);
+        set_modified(&file.0, modified + Duration::from_secs(60));
+        assert_eq!(PeakCache::load_or_build(&file.0).unwrap().levels, original.levels);
+
+        // The same size and time, but a different last sample.
+        tamper_with_sidecar(&file.0);
+        let mut bytes = std::fs::read(&file.0).unwrap();
+        let len = bytes.len();
+        bytes[len - 4..].copy_from_slice(&0.5f32.to_le_bytes());
+        std::fs::write(&file.0, &bytes).unwrap();
+        set_modified(&file.0, modified + Duration::from_secs(60));
+        let rebuilt = PeakCache::load_or_build(&file.0).unwrap();
+        assert_eq!(rebuilt.source.size, original.source.size);
+        assert_eq!(rebuilt.source.modified_nanos, original.source.modified_nanos + 60_000_000_000);
+        assert_ne!(rebuilt.source.edge_hash, original.source.edge_hash);
+        assert_eq!(rebuilt.levels[0].max[0], original.levels[0].max[0]);
+        assert_eq!(*rebuilt.levels[0].max.last().unwrap(), 0.5);
+
+        // A longer file, with the time set back.
+        tamper_with_sidecar(&file.0);
+        let mut longer = samples.clone();
+        longer.extend_from_slice(&[0.25; 200]);
+        write_wave_file(file.0.to_str().unwrap(), &longer, 48_000, 2, WavSampleFormat::Float32).unwrap();
+        set_modified(&file.0, modified);
+        let rebuilt = PeakCache::load_or_build(&file.0).unwrap();
+        assert_eq!(rebuilt.frames, 10_100);
+        assert_eq!(rebuilt.levels[0].max[0], original.levels[0].max[0]);
+    }
+
+    #[test]
+    fn level_for_picks_the_coarsest_level_with_enough_detail() {
+        let file = TempWav::new("levels.wav", &stereo());
+        let cache = PeakCache::build(&file.0, 16).unwrap();
+        for (frames_per_pixel, frames_per_peak) in [(0, 16), (1, 16), (16, 16), (63, 16), (64, 64), (255, 64), (256, 256), (1_000_000, 16 * 4usize.pow(5))] {
+            assert_eq!(cache.level_for(frames_per_pixel).frames_per_peak, frames_per_peak, "at {frames_per_pixel} frames per pixel");
+        }
+    }
+}
@@ -0,0 +1,680 @@
+// Effect chains described in JSON files, and hot-reloading them while audio
+// is running.
+//
+// A pipeline file lists its stages in processing order:
+//
+// {
+//   "stages": [
+//     { "type": "highpass", "cutoff_hz": 80, "q": 0.707 },
+//     { "type": "compressor", "threshold": 0.3, "ratio": 4, "attack_ms": 5, "release_ms": 80 },
+//     { "type": "limiter", "ceiling_db": -1 }
+//   ]
+// }
+//
+// Keys a stage doesn't know are ignored; keys with defaults may be left out.
+// Any stage may have a "wet" level between 0 and 1 to blend it with the dry
+// signal, e.g. { "type": "compressor", ..., "wet": 0.5 } for parallel
+// compression.
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use crate::effect::{Effect, EffectChain, Gain, Mix};
+use crate::filters::FilterSpec;
+use crate::fx::{AutoWah, CombReverb, Compressor, Delay, Distortion, Flanger, NoiseGate, Tremolo};
+use crate::json::{self, Value};
+use crate::limiter::{Limiter, LimiterSettings};
+
+#[derive(Debug)]
+pub enum PipelineError {
+    Io(io::Error),
+    Json(json::ParseError),
+    /// Every problem found in the stages, not just the first.
+    Invalid(Vec<String>),
+}
+
+impl fmt::Display for PipelineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PipelineError::Io(e) => write!(f, "Failed to read pipeline: {}", e),
+            PipelineError::Json(e) => write!(f, "Invalid pipeline JSON: {}", e),
+            PipelineError::Invalid(errors) => write!(f, "Invalid pipeline: {}", errors.join("; ")),
+        }
+    }
+}
+
+impl std::error::Error for PipelineError {}
+
+impl From<io::Error> for PipelineError {
+    fn from(e: io::Error) -> Self {
+        PipelineError::Io(e)
+    }
+}
+
+impl From<json::ParseError> for PipelineError {
+    fn from(e: json::ParseError) -> Self {
+        PipelineError::Json(e)
+    }
+}
+
+/// One processing stage of a pipeline file.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stage {
+    Filter(FilterSpec),
+    Compressor { threshold: f32, ratio: f32, attack_ms: f32, release_ms: f32 },
+    NoiseGate { threshold: f32, attack_ms: f32, release_ms: f32 },
+    Delay { time_ms: f32, feedback: f32 },
+    Reverb { room_size: f32, damping: f32 },
+    Tremolo { rate_hz: f32, depth: f32 },
+    Flanger { depth_ms: f32, rate_hz: f32, feedback: f32, mix: f32 },
+    Distortion { gain: f32, threshold: f32 },
+    AutoWah { sensitivity: f32, min_freq_hz: f32, max_freq_hz: f32, q: f32 },
+    Limiter(LimiterSettings),
+    Gain { gain_db: f32 },
+    /// Any stage with a `"wet"` key, blended with the dry signal.
+    Mix { stage: Box<Stage>, wet: f32 },
+}
+
+impl Stage {
+    pub fn build(&self, sample_rate: f32) -> Box<dyn Effect> {
+        match *self {
+            Stage::Filter(spec) => Box::new(spec.build(sample_rate)),
+            Stage::Compressor { threshold, ratio, attack_ms, release_ms } => {
+                Box::new(Compressor::new(threshold, ratio, attack_ms, release_ms, sample_rate))
+            }
+            Stage::NoiseGate { threshold, attack_ms, release_ms } => {
+                Box::new(NoiseGate::new(threshold, sample_rate, attack_ms, release_ms))
+            }
+            Stage::Delay { time_ms, feedback } => Box::new(Delay::new(sample_rate, time_ms, feedback)),
+            Stage::Reverb { room_size, damping } => Box::new(CombReverb::new(sample_rate, room_size, damping)),
+            Stage::Tremolo { rate_hz, depth } => Box::new(Tremolo::new(sample_rate, rate_hz, depth)),
+            Stage::Flanger { depth_ms, rate_hz, feedback, mix } => {
+                Box::new(Flanger::new(sample_rate, depth_ms, rate_hz, feedback, mix))
+            }
+            Stage::Distortion { gain, threshold } => Box::new(Distortion::new(gain, threshold)),
+            Stage::AutoWah { sensitivity, min_freq_hz, max_freq_hz, q } => {
+                Box::new(AutoWah::new(sample_rate, sensitivity, min_freq_hz, max_freq_hz, q))
+            }
+            Stage::Limiter(settings) => Box::new(Limiter::new(sample_rate as u32, 1, settings)),
+            Stage::Gain { gain_db } => Box::new(Gain::new(gain_db).with_sample_rate(sample_rate as u32)),
+            Stage::Mix { ref stage, wet } => {
+                Box::new(Mix::new(stage.build(sample_rate), wet).with_sample_rate(sample_rate as u32))
+            }
+        }
+    }
+
+    /// Parses one entry of `stages`, appending problems to `errors`.
+    fn from_json(value: &Value, errors: &mut Vec<String>) -> Option<Stage> {
+        let kind = match value.get("type").and_then(Value::as_str) {
+            Some(kind) => kind,
+            None => {
+                errors.push("missing \"type\"".to_string());
+                return None;
+            }
+        };
+        let mut params = Params { value, kind, errors };
+
+        let stage = match kind {
+            "lowpass" | "highpass" => {
+                let cutoff_freq = params.positive("cutoff_hz", None);
+                let q_factor = params.positive("q", Some(std::f32::consts::FRAC_1_SQRT_2));
+                if kind == "lowpass" {
+                    Stage::Filter(FilterSpec::LowPass { cutoff_freq, q_factor })
+                } else {
+                    Stage::Filter(FilterSpec::HighPass { cutoff_freq, q_factor })
+                }
+            }
+            "lowshelf" | "highshelf" => {
+                let cutoff_freq = params.positive("cutoff_hz", None);
+                let gain_db = params.number("gain_db", None);
+                let slope = params.positive("slope", Some(1.0));
+                if kind == "lowshelf" {
+                    Stage::Filter(FilterSpec::LowShelf { cutoff_freq, gain_db, slope })
+                } else {
+                    Stage::Filter(FilterSpec::HighShelf { cutoff_freq, gain_db, slope })
+                }
+            }
+            "peaking_eq" => Stage::Filter(FilterSpec::PeakingEq {
+                freq: params.positive("freq_hz", None),
+                q_factor: params.positive("q", Some(1.0)),
+                gain_db: params.number("gain_db", None),
+            }),
+            "compressor" => {
+                let ratio = params.number("ratio", None);
+                if ratio < 1.0 {
+                    params.error("\"ratio\" must be at least 1");
+                }
+                Stage::Compressor {
+                    threshold: params.positive("threshold", None),
+                    ratio,
+                    attack_ms: params.positive("attack_ms", Some(10.0)),
+                    release_ms: params.positive("release_ms", Some(100.0)),
+                }
+            }
+            "noise_gate" => Stage::NoiseGate {
+                threshold: params.positive("threshold", None),
+                attack_ms: params.positive("attack_ms", Some(1.0)),
+                release_ms: params.positive("release_ms", Some(100.0)),
+            },
+            "delay" => Stage::Delay {
+                time_ms: params.positive("time_ms", None),
+                feedback: params.number("feedback", Some(0.0)),
+            },
+            "reverb" => Stage::Reverb {
+                room_size: params.number("room_size", None),
+                damping: params.number("damping", Some(0.5)),
+            },
+            "tremolo" => Stage::Tremolo {
+                rate_hz: params.positive("rate_hz", None),
+                depth: params.number("depth", Some(0.5)),
+            },
+            "flanger" => Stage::Flanger {
+                depth_ms: params.positive("depth_ms", None),
+                rate_hz: params.positive("rate_hz", None),
+                feedback: params.number("feedback", Some(0.0)),
+                mix: params.number("mix", Some(0.5)),
+            },
+            "distortion" => Stage::Distortion {
+                gain: params.positive("gain", None),
+                threshold: params.positive("threshold", Some(0.5)),
+            },
+            "auto_wah" => Stage::AutoWah {
+                sensitivity: params.positive("sensitivity", None),
+                min_freq_hz: params.positive("min_freq_hz", Some(300.0)),
+                max_freq_hz: params.positive("max_freq_hz", Some(3000.0)),
+                q: params.positive("q", Some(2.0)),
+            },
+            "limiter" => {
+                let defaults = LimiterSettings::default();
+                Stage::Limiter(LimiterSettings {
+                    ceiling_db: params.number("ceiling_db", Some(defaults.ceiling_db)),
+                    lookahead_ms: params.non_negative("lookahead_ms", Some(defaults.lookahead_ms)),
+                    release_ms: params.positive("release_ms", Some(defaults.release_ms)),
+                })
+            }
+            "gain" => Stage::Gain { gain_db: params.number("gain_db", None) },
+            other => {
+                errors.push(format!("unknown stage type \"{}\"", other));
+                return None;
+            }
+        };
+
+        if value.get("wet").is_some() {
+            let wet = params.number("wet", None);
+            if !(0.0..=1.0).contains(&wet) {
+                params.error("\"wet\" must be between 0 and 1");
+            }
+            return Some(Stage::Mix { stage: Box::new(stage), wet });
+        }
+        Some(stage)
+    }
+}
+
+// Reads the parameters of one stage, recording every problem instead of
+// stopping at the first.
+struct Params<'a> {
+    value: &'a Value,
+    kind: &'a str,
+    errors: &'a mut Vec<String>,
+}
+
+impl Params<'_> {
+    fn error(&mut self, message: &str) {
+        self.errors.push(format!("{}: {}", self.kind, message));
+    }
+
+    fn number(&mut self, key: &str, default: Option<f32>) -> f32 {
+        match (self.value.get(key), default) {
+            (Some(v), _) => match v.as_f32() {
+                Some(n) => n,
+                None => {
+                    self.error(&format!("\"{}\" must be a number", key));
+                    0.0
+                }
+            },
+            (None, Some(default)) => default,
+            (None, None) => {
+                self.error(&format!("missing \"{}\"", key));
+                0.0
+            }
+        }
+    }
+
+    fn positive(&mut self, key: &str, default: Option<f32>) -> f32 {
+        let n = self.number(key, default);
+        if self.value.get(key).and_then(Value::as_f32).is_some() && n <= 0.0 {
+            self.error(&format!("\"{}\" must be positive", key));
+        }
+        n
+    }
+
+    fn non_negative(&mut self, key: &str, default: Option<f32>) -> f32 {
+        let n = self.number(key, default);
+        if n < 0.0 {
+            self.error(&format!("\"{}\" must not be negative", key));
+        }
+        n
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Pipeline {
+    pub stages: Vec<Stage>,
+}
+
+impl Pipeline {
+    pub fn load(path: &Path) -> Result<Pipeline, PipelineError> {
+        Pipeline::from_json(&std::fs::read_to_string(path)?)
+    }
+
+    pub fn from_json(text: &str) -> Result<Pipeline, PipelineError> {
+        let document = json::parse(text)?;
+        let entries = match document.get("stages").and_then(Value::as_array) {
+            Some(entries) => entries,
+            None => return Err(PipelineError::Invalid(vec!["missing \"stages\" array".to_string()])),
+        };
+
+        let mut errors = Vec::new();
+        let mut stages = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            let mut stage_errors = Vec::new();
+            if let Some(stage) = Stage::from_json(entry, &mut stage_errors) {
+                stages.push(stage);
+            }
+            errors.extend(stage_errors.into_iter().map(|e| format!("stage {}: {}", i + 1, e)));
+        }
+
+        if errors.is_empty() {
+            Ok(Pipeline { stages })
+        } else {
+            Err(PipelineError::Invalid(errors))
+        }
+    }
+
+    pub fn build(&self, sample_rate: f32) -> EffectChain {
+        EffectChain::new(self.stages.iter().map(|stage| stage.build(sample_rate)).collect())
+    }
+}
+
+/// Length of the crossfade when a reloaded chain takes over.
+pub const CROSSFADE_MS: f32 = 20.0;
+/// Samples the crossfade processes per pass, so its scratch buffer is
+/// allocated up front.
+const CROSSFADE_CHUNK: usize = 1024;
+
+/// Hands a new chain to the audio thread without locking.
+///
+/// The producer `offer`s a chain; the audio thread `take`s it at a block
+/// boundary and later gives the chain it replaced back through `retire`, so
+/// the producer can free it off the audio thread.
+#[derive(Default)]
+pub struct ChainSlot {
+    pending: AtomicPtr<EffectChain>,
+    retired: AtomicPtr<EffectChain>,
+}
+
+impl ChainSlot {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces any chain that hasn't been picked up yet.
+    pub fn offer(&self, chain: EffectChain) {
+        let previous = self.pending.swap(Box::into_raw(Box::new(chain)), Ordering::AcqRel);
+        if !previous.is_null() {
+            // Never seen by the audio thread, so it is ours to free.
+            drop(unsafe { Box::from_raw(previous) });
+        }
+    }
+
+    pub fn take(&self) -> Option<Box<EffectChain>> {
+        let chain = self.pending.swap(ptr::null_mut(), Ordering::AcqRel);
+        (!chain.is_null()).then(|| unsafe { Box::from_raw(chain) })
+    }
+
+    /// Parks a chain the audio thread is done with. If the previous one was
+    /// never collected it is freed here, on the calling thread.
+    pub fn retire(&self, chain: Box<EffectChain>) {
+        let previous = self.retired.swap(Box::into_raw(chain), Ordering::AcqRel);
+        if !previous.is_null() {
+            drop(unsafe { Box::from_raw(previous) });
+        }
+    }
+
+    /// Frees the retired chain, if any. Call from a non-audio thread.
+    pub fn collect_retired(&self) {
+        let chain = self.retired.swap(ptr::null_mut(), Ordering::AcqRel);
+        if !chain.is_null() {
+            drop(unsafe { Box::from_raw(chain) });
+        }
+    }
+}
+
+impl Drop for ChainSlot {
+    fn drop(&mut self) {
+        drop(self.take());
+        self.collect_retired();
+    }
+}
+
+/// Audio-thread side of a hot-reloaded chain: runs the current chain and
+/// crossfades to a new one from the slot when it arrives.
+pub struct HotSwapChain {
+    slot: Arc<ChainSlot>,
+    current: Box<EffectChain>,
+    outgoing: Option<Box<EffectChain>>,
+    fade_len: usize,
+    fade_position: usize,
+    scratch: Vec<f32>,
+}
+
+impl HotSwapChain {
+    pub fn new(initial: EffectChain, slot: Arc<ChainSlot>, sample_rate: f32) -> Self {
+        Self {
+            slot,
+            current: Box::new(initial),
+            outgoing: None,
+            fade_len: ((CROSSFADE_MS * 0.001 * sample_rate) as usize).max(1),
+            fade_position: 0,
+            scratch: vec![0.0; CROSSFADE_CHUNK],
+        }
+    }
+
+    pub fn is_crossfading(&self) -> bool {
+        self.outgoing.is_some()
+    }
+
+    pub fn current(&self) -> &EffectChain {
+        &self.current
+    }
+}
+
+impl Effect for HotSwapChain {
+    fn process_block(&mut self, block: &mut [f32]) {
+        // A chain arriving mid-fade waits until the fade is over.
+        if self.outgoing.is_none() {
+            if let Some(next) = self.slot.take() {
+                self.outgoing = Some(std::mem::replace(&mut self.current, next));
+                self.fade_position = 0;
+            }
+        }
+
+        let Some(outgoing) = self.outgoing.as_mut() else {
+            self.current.process_block(block);
+            return;
+        };
+
+        for chunk in block.chunks_mut(CROSSFADE_CHUNK) {
+            let old = &mut self.scratch[..chunk.len()];
+            old.copy_from_slice(chunk);
+            outgoing.process_block(old);
+            self.current.process_block(chunk);
+
+            for (new, &old) in chunk.iter_mut().zip(old.iter()) {
+                if self.fade_position >= self.fade_len {
+                    break;
+                }
+                let t = self.fade_position as f32 / self.fade_len as f32;
+                *new = old * (1.0 - t) + *new * t;
+                self.fade_position += 1;
+            }
+        }
+
+        if self.fade_position >= self.fade_len {
+            if let Some(outgoing) = self.outgoing.take() {
+                self.slot.retire(outgoing);
+            }
+        }
+    }
+
+    /// Latency of the current chain. A reload that changes it shifts the
+    /// output; the crossfade hides the jump but doesn't realign it.
+    fn latency_frames(&self) -> usize {
+        self.current.latency_frames()
+    }
+}
+
+/// What the watcher did after a change to the pipeline file.
+#[derive(Debug)]
+pub enum ReloadEvent {
+    Reloaded,
+    /// The edited file didn't load; the previous chain keeps running.
+    Failed(PipelineError),
+}
+
+/// Polls a pipeline file and offers a rebuilt chain whenever it changes.
+/// Stops when dropped.
+pub struct PipelineWatcher {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    events: Receiver<ReloadEvent>,
+}
+
+type Fingerprint = Option<(SystemTime, u64)>;
+
+fn fingerprint(path: &Path) -> Fingerprint {
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+impl PipelineWatcher {
+    pub fn spawn(path: PathBuf, sample_rate: f32, slot: Arc<ChainSlot>, poll_interval: Duration) -> Self {
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, events) = mpsc::channel();
+        let thread_stop = Arc::clone(&stop);
+        // Taken here rather than on the thread, so an edit made right after
+        // spawning is not mistaken for the starting state.
+        let last = fingerprint(&path);
+        let thread = thread::spawn(move || {
+            watch(&path, last, sample_rate, &slot, poll_interval, &thread_stop, &sender);
+        });
+        Self {
+            stop,
+            thread: Some(thread),
+            events,
+        }
+    }
+
+    pub fn try_event(&self) -> Option<ReloadEvent> {
+        self.events.try_recv().ok()
+    }
+
+    pub fn events(&self) -> &Receiver<ReloadEvent> {
+        &self.events
+    }
+}
+
+fn watch(
+    path: &Path,
+    mut last: Fingerprint,
+    sample_rate: f32,
+    slot: &ChainSlot,
+    poll_interval: Duration,
+    stop: &AtomicBool,
+    events: &Sender<ReloadEvent>,
+) {
+    while !stop.load(Ordering::Acquire) {
+        thread::sleep(poll_interval);
+        slot.collect_retired();
+
+        let current = fingerprint(path);
+        if current == last {
+            continue;
+        }
+        last = current;
+        let event = match Pipeline::load(path) {
+            Ok(pipeline) => {
+                slot.offer(pipeline.build(sample_rate));
+                ReloadEvent::Reloaded
+            }
+            Err(e) => ReloadEvent::Failed(e),
+        };
+        let _ = events.send(event);
+    }
+}
+
+impl Drop for PipelineWatcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Loads `path` and returns a chain for the audio thread that follows
+/// edits to the file, together with the watcher doing the polling.
+pub fn hot_reload(
+    path: &Path,
+    sample_rate: f32,
+    poll_interval: Duration,
+) -> Result<(HotSwapChain, PipelineWatcher), PipelineError> {
+    let initial = Pipeline::load(path)?.build(sample_rate);
+    let slot = Arc::new(ChainSlot::new());
+    let chain = HotSwapChain::new(initial, Arc::clone(&slot), sample_rate);
+    let watcher = PipelineWatcher::spawn(path.to_path_buf(), sample_rate, slot, poll_interval);
+    Ok((chain, watcher))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Outputs a constant, so which chain is playing is easy to see.
+    struct Constant(f32);
+
+    impl Effect for Constant {
+        fn process_block(&mut self, block: &mut [f32]) {
+            block.fill(self.0);
+        }
+    }
+
+    fn constant(value: f32) -> EffectChain {
+        EffectChain::new(vec![Box::new(Constant(value))])
+    }
+
+    // A pipeline file in the temp directory, removed when dropped.
+    struct TempPipeline(PathBuf);
+
+    impl TempPipeline {
+        fn new(name: &str, text: &str) -> Self {
+            let file = Self(std::env::temp_dir().join(format!("cpal_playbook_{}_{}", std::process::id(), name)));
+            file.write(text);
+            file
+        }
+
+        fn write(&self, text: &str) {
+            std::fs::write(&self.0, text).unwrap();
+        }
+    }
+
+    impl Drop for TempPipeline {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn next_event(watcher: &PipelineWatcher) -> ReloadEvent {
+        watcher.events().recv_timeout(Duration::from_secs(10)).expect("no reload event")
+    }
+
+    #[test]
+    fn slot_hands_over_the_newest_chain() {
+        let slot = ChainSlot::new();
+        assert!(slot.take().is_none());
+        slot.offer(constant(1.0));
+        slot.offer(constant(2.0));
+        let mut block = [0.0; 4];
+        slot.take().unwrap().process_block(&mut block);
+        assert_eq!(block, [2.0; 4]);
+        assert!(slot.take().is_none());
+
+        slot.retire(Box::new(constant(3.0)));
+        slot.retire(Box::new(constant(4.0)));
+        slot.collect_retired();
+        slot.offer(constant(5.0));
+        // Dropping the slot frees whatever it still holds.
+    }
+
+    #[test]
+    fn swap_crossfades_without_jumps() {
+        let sample_rate = 48_000.0;
+        let slot = Arc::new(ChainSlot::new());
+        let mut chain = HotSwapChain::new(constant(0.0), Arc::clone(&slot), sample_rate);
+        let fade_len = (CROSSFADE_MS * 0.001 * sample_rate) as usize;
+
+        let mut output = Vec::new();
+        let mut block = vec![0.0; 100];
+        chain.process_block(&mut block);
+        output.extend_from_slice(&block);
+
+        slot.offer(constant(1.0));
+        // Block sizes that don't divide the fade or the crossfade chunk.
+        for len in [1, 77, 1_500, 333].iter().cycle().take(12) {
+            let mut block = vec![0.0; *len];
+            chain.process_block(&mut block);
+            output.extend_from_slice(&block);
+        }
+        assert!(!chain.is_crossfading());
+
+        let start = output.iter().position(|&s| s > 0.0).unwrap();
+        assert_eq!(start, 101, "the new chain starts at the next block boundary");
+        for pair in output.windows(2) {
+            assert!(pair[1] >= pair[0] && pair[1] - pair[0] <= 1.0 / fade_len as f32 + 1e-6, "{:?}", pair);
+        }
+        assert!(output[100 + fade_len..].iter().all(|&s| s == 1.0));
+
+        // The outgoing chain is handed back for freeing off the audio thread.
+        slot.collect_retired();
+    }
+
+    #[test]
+    fn chains_arriving_mid_fade_wait_for_it() {
+        let slot = Arc::new(ChainSlot::new());
+        let mut chain = HotSwapChain::new(constant(0.0), Arc::clone(&slot), 48_000.0);
+        slot.offer(constant(1.0));
+        let mut block = vec![0.0; 10];
+        chain.process_block(&mut block);
+        assert!(chain.is_crossfading());
+
+        slot.offer(constant(-1.0));
+        for _ in 0..200 {
+            chain.process_block(&mut block);
+        }
+        // The fade to 1.0 finished, then the fade to -1.0 ran.
+        assert_eq!(block, [-1.0; 10]);
+    }
+
+    #[test]
+    fn bad_edits_keep_the_old_chain() {
+        let file = TempPipeline::new("reload.json", r#"{ "stages": [{ "type": "gain", "gain_db": 0 }] }"#);
+        let (mut chain, watcher) = hot_reload(&file.0, 48_000.0, Duration::from_millis(5)).unwrap();
+        let mut block = vec![0.5; 64];
+        chain.process_block(&mut block);
+        assert_eq!(block, [0.5; 64]);
+
+        file.write(r#"{ "stages": [{ "type": "gain", "gain_db": "#);
+        assert!(matches!(next_event(&watcher), ReloadEvent::Failed(PipelineError::Json(_))));
+        file.write(r#"{ "stages": [{ "type": "no such effect" }] }"#);
+        assert!(matches!(next_event(&watcher), ReloadEvent::Failed(PipelineError::Invalid(_))));
+        let mut block = vec![0.5; 64];
+        chain.process_block(&mut block);
+        assert_eq!(block, [0.5; 64], "a failed reload must not touch the running chain");
+        assert!(!chain.is_crossfading());
+
+        file.write(r#"{ "stages": [{ "type": "gain", "gain_db": -6.0206 }, { "type": "gain", "gain_db": 0 }] }"#);
+        assert!(matches!(next_event(&watcher), ReloadEvent::Reloaded));
+        for _ in 0..40 {
+            block.fill(0.5);
+            chain.process_block(&mut block);
+        }
+        assert_eq!(chain.current().len(), 2);
+        assert!(block.iter().all(|s| (s - 0.25).abs() < 1e-4), "{:?}", &block[..4]);
+    }
+}
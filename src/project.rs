@@ -0,0 +1,723 @@
+// Project files: the sources of an episode, the pipeline each one runs
+// through, how they are mixed and how the result is exported, in one TOML
+// file that can be shared and rendered again later.
+//
+// [export]
+// file = "episode.wav"            # relative to the output directory
+// format = "int24"                # "int16", "int24" or "float32"
+// loudness_target_lufs = -16      # optional
+// ceiling_db = -1                 # limiter on the final mix
+// sample_rate = 48000             # optional, defaults to the first track's
+//
+// [[tracks]]
+// name = "host"                   # defaults to the file name
+// file = "audio/host.wav"
+// pipeline = "chains/voice.json"  # optional
+// gain_db = -2
+// pan = -0.2
+// offset_seconds = 0
+//
+// Relative paths of sources and pipelines are resolved against the
+// directory of the project file. Sources at other sample rates are
+// resampled, and sources with more than two channels downmixed to stereo.
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::adapter::{convert_planar, AudioSpec};
+use crate::limiter::{db_to_linear, LimiterSettings};
+use crate::loudness::integrated_loudness;
+use crate::mixdown::{mixdown, BusProcessor, Ceiling, Track};
+use crate::pipeline::{Pipeline, PipelineError};
+use crate::read_wav::read_wav_data;
+use crate::render::{render_pipeline, DEFAULT_BOUNCE_BLOCK_SIZE};
+use crate::toml::{self, Table, Value};
+use crate::write_wav::{WavSampleFormat, WavStreamWriter};
+
+/// Newest project file version this build can read.
+pub const PROJECT_VERSION: i64 = 1;
+
+#[derive(Debug)]
+pub enum ProjectError {
+    Io(io::Error),
+    Toml(toml::ParseError),
+    /// Every problem found in the project, not just the first.
+    Invalid(Vec<String>),
+    Render(String),
+}
+
+impl fmt::Display for ProjectError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProjectError::Io(e) => write!(f, "Failed to read project: {}", e),
+            ProjectError::Toml(e) => write!(f, "Invalid project TOML: {}", e),
+            ProjectError::Invalid(errors) => write!(f, "Invalid project: {}", errors.join("; ")),
+            ProjectError::Render(msg) => write!(f, "Failed to render project: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for ProjectError {}
+
+impl From<io::Error> for ProjectError {
+    fn from(e: io::Error) -> Self {
+        ProjectError::Io(e)
+    }
+}
+
+impl From<toml::ParseError> for ProjectError {
+    fn from(e: toml::ParseError) -> Self {
+        ProjectError::Toml(e)
+    }
+}
+
+/// One source of the mix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectTrack {
+    pub name: String,
+    pub file: PathBuf,
+    pub pipeline: Option<PathBuf>,
+    pub gain_db: f32,
+    pub pan: f32,
+    pub offset_seconds: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExportSettings {
+    /// Output file, relative to the directory passed to `render`.
+    pub file: PathBuf,
+    pub format: WavSampleFormat,
+    /// The mix is gained to this integrated loudness before the limiter.
+    pub loudness_target_lufs: Option<f32>,
+    pub ceiling_db: f32,
+    /// Rate of the mix; the first track's when `None`.
+    pub sample_rate: Option<u32>,
+}
+
+impl Default for ExportSettings {
+    fn default() -> Self {
+        Self {
+            file: PathBuf::from("mix.wav"),
+            format: WavSampleFormat::Int24,
+            loudness_target_lufs: None,
+            ceiling_db: LimiterSettings::default().ceiling_db,
+            sample_rate: None,
+        }
+    }
+}
+
+/// Summary of a project render.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProjectReport {
+    pub output: PathBuf,
+    pub sample_rate: u32,
+    pub frames: usize,
+    /// Gain applied to meet the loudness target, 0 without one.
+    pub normalization_gain_db: f32,
+    /// Integrated loudness of the exported mix.
+    pub loudness_lufs: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Project {
+    /// Directory relative paths in the file were resolved against.
+    pub base_dir: PathBuf,
+    pub tracks: Vec<ProjectTrack>,
+    pub export: ExportSettings,
+}
+
+// Reads the keys of one table, recording every problem instead of stopping
+// at the first.
+struct Fields<'a> {
+    table: &'a Table,
+    context: String,
+    errors: &'a mut Vec<String>,
+}
+
+impl Fields<'_> {
+    fn error(&mut self, message: &str) {
+        self.errors.push(format!("{}: {}", self.context, message));
+    }
+
+    fn string(&mut self, key: &str) -> Option<String> {
+        match self.table.get(key) {
+            None => None,
+            Some(v) => match v.as_str() {
+                Some(s) => Some(s.to_string()),
+                None => {
+                    self.error(&format!("'{}' must be a string", key));
+                    None
+                }
+            },
+        }
+    }
+
+    fn number(&mut self, key: &str) -> Option<f64> {
+        match self.table.get(key) {
+            None => None,
+            Some(v) => match v.as_float() {
+                Some(n) => Some(n),
+                None => {
+                    self.error(&format!("'{}' must be a number", key));
+                    None
+                }
+            },
+        }
+    }
+}
+
+fn resolve(base_dir: &Path, path: &str) -> PathBuf {
+    let path = Path::new(path);
+    if path.is_absolute() {
+        path.to_path_buf()
+    } else {
+        base_dir.join(path)
+    }
+}
+
+fn parse_format(name: &str) -> Option<WavSampleFormat> {
+    match name {
+        "int16" => Some(WavSampleFormat::Int16),
+        "int24" => Some(WavSampleFormat::Int24),
+        "float32" => Some(WavSampleFormat::Float32),
+        _ => None,
+    }
+}
+
+impl Project {
+    pub fn load(path: &Path) -> Result<Project, ProjectError> {
+        let text = fs::read_to_string(path)?;
+        let base_dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        Project::from_toml(&text, &base_dir)
+    }
+
+    /// Parses a project, resolving relative paths against `base_dir`.
+    /// Only the file itself is checked here; see `validate`.
+    pub fn from_toml(text: &str, base_dir: &Path) -> Result<Project, ProjectError> {
+        let table = toml::parse(text)?;
+        let mut errors = Vec::new();
+
+        if let Some(version) = table.get("version") {
+            match version.as_integer() {
+                Some(v) if v > PROJECT_VERSION => errors.push(format!(
+                    "project version {} is newer than supported version {}",
+                    v, PROJECT_VERSION
+                )),
+                Some(_) => {}
+                None => errors.push("'version' must be an integer".to_string()),
+            }
+        }
+
+        let mut export = ExportSettings::default();
+        match table.get("export") {
+            None => {}
+            Some(Value::Table(section)) => {
+                let mut fields = Fields { table: section, context: "export".to_string(), errors: &mut errors };
+                if let Some(file) = fields.string("file") {
+                    export.file = PathBuf::from(file);
+                }
+                if let Some(format) = fields.string("format") {
+                    match parse_format(&format) {
+                        Some(format) => export.format = format,
+                        None => fields.error(&format!("unknown format '{}'", format)),
+                    }
+                }
+                export.loudness_target_lufs = fields.number("loudness_target_lufs").map(|n| n as f32);
+                if let Some(ceiling_db) = fields.number("ceiling_db") {
+                    if ceiling_db > 0.0 {
+                        fields.error("'ceiling_db' must not be above 0");
+                    }
+                    export.ceiling_db = ceiling_db as f32;
+                }
+                if let Some(rate) = fields.number("sample_rate") {
+                    if rate < 1.0 || rate.fract() != 0.0 || rate > u32::MAX as f64 {
+                        fields.error("'sample_rate' must be a positive integer");
+                    }
+                    export.sample_rate = Some(rate as u32);
+                }
+            }
+            Some(_) => errors.push("'export' must be a table".to_string()),
+        }
+
+        let mut tracks = Vec::new();
+        let entries = match table.get("tracks") {
+            None => {
+                errors.push("no [[tracks]] in project".to_string());
+                &[][..]
+            }
+            Some(Value::Array(entries)) => entries.as_slice(),
+            Some(_) => {
+                errors.push("'tracks' must be an array of tables".to_string());
+                &[][..]
+            }
+        };
+        for (i, entry) in entries.iter().enumerate() {
+            let Some(section) = entry.as_table() else {
+                errors.push(format!("track {}: must be a table", i + 1));
+                continue;
+            };
+            let mut fields = Fields { table: section, context: format!("track {}", i + 1), errors: &mut errors };
+            let file = fields.string("file");
+            if file.is_none() && !section.contains_key("file") {
+                fields.error("missing 'file'");
+            }
+            let name = fields.string("name");
+            let pipeline = fields.string("pipeline");
+            let gain_db = fields.number("gain_db").unwrap_or(0.0) as f32;
+            let pan = fields.number("pan").unwrap_or(0.0) as f32;
+            if !(-1.0..=1.0).contains(&pan) {
+                fields.error("'pan' must be between -1 and 1");
+            }
+            let offset_seconds = fields.number("offset_seconds").unwrap_or(0.0);
+            if offset_seconds < 0.0 {
+                fields.error("'offset_seconds' must not be negative");
+            }
+
+            if let Some(file) = file {
+                let name = name.unwrap_or_else(|| {
+                    Path::new(&file).file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_else(|| file.clone())
+                });
+                tracks.push(ProjectTrack {
+                    name,
+                    file: resolve(base_dir, &file),
+                    pipeline: pipeline.map(|p| resolve(base_dir, &p)),
+                    gain_db,
+                    pan,
+                    offset_seconds,
+                });
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(Project { base_dir: base_dir.to_path_buf(), tracks, export })
+        } else {
+            Err(ProjectError::Invalid(errors))
+        }
+    }
+
+    /// Checks everything the project refers to: every source must be a
+    /// readable WAV and every pipeline must load. All problems are reported
+    /// together.
+    pub fn validate(&self) -> Result<(), ProjectError> {
+        let mut errors = Vec::new();
+
+        for track in &self.tracks {
+            match hound::WavReader::open(&track.file) {
+                Ok(_) => {}
+                Err(hound::Error::IoError(e)) if e.kind() == io::ErrorKind::NotFound => {
+                    errors.push(format!("{}: missing file {}", track.name, track.file.display()));
+                }
+                Err(e) => errors.push(format!("{}: cannot read {}: {}", track.name, track.file.display(), e)),
+            }
+
+            if let Some(path) = &track.pipeline {
+                match Pipeline::load(path) {
+                    Ok(_) => {}
+                    Err(PipelineError::Invalid(stage_errors)) => {
+                        errors.extend(stage_errors.into_iter().map(|e| format!("{}: {}: {}", track.name, path.display(), e)));
+                    }
+                    Err(e) => errors.push(format!("{}: {}: {}", track.name, path.display(), e)),
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ProjectError::Invalid(errors))
+        }
+    }
+
+    pub fn output_path(&self, out_dir: &Path) -> PathBuf {
+        out_dir.join(&self.export.file)
+    }
+
+    /// What `render` would do, one step per line, for a dry run.
+    pub fn plan(&self, out_dir: &Path) -> Vec<String> {
+        let mut steps = Vec::new();
+        for track in &self.tracks {
+            let chain = match &track.pipeline {
+                Some(path) => format!("through {}", path.display()),
+                None => "unprocessed".to_string(),
+            };
+            steps.push(format!(
+                "track '{}': {} {}, {:+.1} dB, pan {:+.2}, at {:.3} s",
+                track.name,
+                track.file.display(),
+                chain,
+                track.gain_db,
+                track.pan,
+                track.offset_seconds
+            ));
+        }
+        match self.export.sample_rate {
+            Some(rate) => steps.push(format!("mix {} tracks to stereo at {} Hz", self.tracks.len(), rate)),
+            None => steps.push(format!("mix {} tracks to stereo", self.tracks.len())),
+        }
+        if let Some(target) = self.export.loudness_target_lufs {
+            steps.push(format!("normalize to {:.1} LUFS", target));
+        }
+        steps.push(format!("limit to {:.1} dBFS", self.export.ceiling_db));
+        steps.push(format!(
+            "write {} ({} bit)",
+            self.output_path(out_dir).display(),
+            self.export.format.bits_per_sample()
+        ));
+        steps
+    }
+
+    /// Validates the project, renders every track through its pipeline,
+    /// mixes them, masters the mix and writes it into `out_dir`.
+    pub fn render(&self, out_dir: &Path) -> Result<ProjectReport, ProjectError> {
+        self.validate()?;
+
+        let mut sample_rate = self.export.sample_rate;
+        let mut tracks = Vec::with_capacity(self.tracks.len());
+        for track in &self.tracks {
+            let data = read_wav_data(&track.file.to_string_lossy())
+                .map_err(|e| ProjectError::Render(format!("{}: {}", track.name, e)))?;
+            let sample_rate = *sample_rate.get_or_insert(data.sample_rate);
+
+            let mut channels = data.channels;
+            let src = AudioSpec::new(data.sample_rate, channels.len() as u16);
+            let dst = AudioSpec::new(sample_rate, src.channels.min(2));
+            if src != dst {
+                channels = convert_planar(&channels, src, dst);
+            }
+            if let Some(path) = &track.pipeline {
+                let pipeline = Pipeline::load(path).map_err(|e| ProjectError::Render(format!("{}: {}", track.name, e)))?;
+                channels = render_pipeline(&channels, sample_rate, &pipeline, DEFAULT_BOUNCE_BLOCK_SIZE).0;
+            }
+
+            let mixed = match channels.len() {
+                1 => Track::mono(channels.remove(0)),
+                _ => {
+                    let right = channels.remove(1);
+                    Track::stereo(channels.remove(0), right)
+                }
+            };
+            let offset_frames = (track.offset_seconds * sample_rate as f64).round() as usize;
+            tracks.push(mixed.with_gain_db(track.gain_db).with_pan(track.pan).with_offset(offset_frames));
+        }
+
+        let sample_rate = sample_rate.unwrap_or(48000);
+        let (mut left, mut right) = mixdown(&tracks, sample_rate, &BusProcessor::default());
+
+        let mut normalization_gain_db = 0.0;
+        if let Some(target) = self.export.loudness_target_lufs {
+            let loudness = integrated_loudness(&[left.clone(), right.clone()], sample_rate);
+            // Silence can't be brought to any loudness.
+            if loudness.is_finite() {
+                normalization_gain_db = target - loudness as f32;
+                let gain = db_to_linear(normalization_gain_db);
+                for sample in left.iter_mut().chain(right.iter_mut()) {
+                    *sample *= gain;
+                }
+            }
+        }
+
+        let settings = LimiterSettings { ceiling_db: self.export.ceiling_db, ..LimiterSettings::default() };
+        BusProcessor::new(Ceiling::Limiter { settings }).process(&mut left, &mut right, sample_rate);
+
+        let output = self.output_path(out_dir);
+        if let Some(dir) = output.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut writer = WavStreamWriter::create(&output, sample_rate, 2, self.export.format)?;
+        let interleaved: Vec<f32> = left.iter().zip(&right).flat_map(|(&l, &r)| [l, r]).collect();
+        writer.write_samples(&interleaved)?;
+        writer.finish()?;
+
+        let loudness_lufs = integrated_loudness(&[left.clone(), right], sample_rate);
+        Ok(ProjectReport { output, sample_rate, frames: left.len(), normalization_gain_db, loudness_lufs })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_wav::read_wave_file;
+    use crate::write_wav::write_wave_file;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("cpal_playbook_{}_{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            fs::create_dir_all(path.join("audio")).unwrap();
+            fs::create_dir_all(path.join("chains")).unwrap();
+            Self(path)
+        }
+
+        fn write(&self, name: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(name);
+            fs::write(&path, contents).unwrap();
+            path
+        }
+
+        fn wav(&self, name: &str, channels: &[Vec<f32>], sample_rate: u32) {
+            let interleaved: Vec<f32> = (0..channels[0].len()).flat_map(|i| channels.iter().map(move |c| c[i])).collect();
+            let path = self.0.join(name);
+            write_wave_file(&path.to_string_lossy(), &interleaved, sample_rate, channels.len() as u16, WavSampleFormat::Float32)
+                .unwrap();
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn sine(frequency: f32, amplitude: f32, frames: usize, sample_rate: u32) -> Vec<f32> {
+        (0..frames)
+            .map(|i| amplitude * (2.0 * std::f32::consts::PI * frequency * i as f32 / sample_rate as f32).sin())
+            .collect()
+    }
+
+    fn invalid(result: Result<impl fmt::Debug, ProjectError>) -> Vec<String> {
+        match result {
+            Err(ProjectError::Invalid(errors)) => errors,
+            other => panic!("expected an invalid project, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn paths_are_resolved_against_the_project_directory() {
+        let dir = TempDir::new("project_paths");
+        let path = dir.write(
+            "episode.toml",
+            r#"
+            [[tracks]]
+            file = "audio/host.wav"
+            pipeline = "chains/voice.json"
+            gain_db = -2
+            pan = -0.5
+
+            [[tracks]]
+            name = "theme"
+            file = "/elsewhere/theme.wav"
+            offset_seconds = 1.5
+            "#,
+        );
+        let project = Project::load(&path).unwrap();
+        assert_eq!(project.base_dir, dir.0);
+        assert_eq!(project.export, ExportSettings::default());
+        assert_eq!(
+            project.tracks,
+            [
+                ProjectTrack {
+                    name: "host".to_string(),
+                    file: dir.0.join("audio/host.wav"),
+                    pipeline: Some(dir.0.join("chains/voice.json")),
+                    gain_db: -2.0,
+                    pan: -0.5,
+                    offset_seconds: 0.0,
+                },
+                ProjectTrack {
+                    name: "theme".to_string(),
+                    file: PathBuf::from("/elsewhere/theme.wav"),
+                    pipeline: None,
+                    gain_db: 0.0,
+                    pan: 0.0,
+                    offset_seconds: 1.5,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn bad_settings_are_reported_together() {
+        let errors = invalid(Project::from_toml(
+            r#"
+            version = 2
+
+            [export]
+            format = "mp3"
+            ceiling_db = 3
+
+            [[tracks]]
+            file = "a.wav"
+            pan = 2
+
+            [[tracks]]
+            gain_db = "loud"
+            offset_seconds = -1
+            "#,
+            Path::new("."),
+        ));
+        assert_eq!(
+            errors,
+            [
+                "project version 2 is newer than supported version 1",
+                "export: unknown format 'mp3'",
+                "export: 'ceiling_db' must not be above 0",
+                "track 1: 'pan' must be between -1 and 1",
+                "track 2: missing 'file'",
+                "track 2: 'gain_db' must be a number",
+                "track 2: 'offset_seconds' must not be negative",
+            ]
+        );
+        assert_eq!(invalid(Project::from_toml("", Path::new("."))), ["no [[tracks]] in project"]);
+    }
+
+    #[test]
+    fn missing_files_and_bad_references_are_reported_together() {
+        let dir = TempDir::new("project_validate");
+        dir.wav("audio/host.wav", &[vec![0.0; 480]], 48000);
+        dir.write("audio/notes.wav", "not a wav file");
+        dir.write("chains/broken.json", r#"{ "stages": [{ "type": "gain", "gain_db": "loud" }, { "type": "no such effect" }] }"#);
+        dir.write("chains/voice.json", r#"{ "stages": [{ "type": "gain", "gain_db": -3 }] }"#);
+        let path = dir.write(
+            "episode.toml",
+            r#"
+            [[tracks]]
+            file = "audio/host.wav"
+            pipeline = "chains/voice.json"
+
+            [[tracks]]
+            file = "audio/guest.wav"
+            pipeline = "chains/missing.json"
+
+            [[tracks]]
+            file = "audio/notes.wav"
+            pipeline = "chains/broken.json"
+            "#,
+        );
+        let project = Project::load(&path).unwrap();
+        let errors = invalid(project.validate());
+        assert_eq!(errors.len(), 5, "{:#?}", errors);
+        assert_eq!(errors[0], format!("guest: missing file {}", dir.0.join("audio/guest.wav").display()));
+        assert!(errors[1].starts_with(&format!("guest: {}: ", dir.0.join("chains/missing.json").display())), "{}", errors[1]);
+        assert!(errors[2].starts_with(&format!("notes: cannot read {}: ", dir.0.join("audio/notes.wav").display())), "{}", errors[2]);
+        let broken = format!("notes: {}: ", dir.0.join("chains/broken.json").display());
+        assert!(errors[3].starts_with(&broken) && errors[3].contains("gain_db"), "{}", errors[3]);
+        assert!(errors[4].starts_with(&broken) && errors[4].contains("no such effect"), "{}", errors[4]);
+
+        // Nothing is written for an invalid project.
+        let out = dir.0.join("out");
+        invalid(project.render(&out));
+        assert!(!out.exists());
+    }
+
+    #[test]
+    fn render_runs_the_pipeline_and_places_the_tracks() {
+        // A mono track lowered 6 dB by its pipeline, then a stereo one at
+        // another rate starting half a second in.
+        let dir = TempDir::new("project_render");
+        dir.wav("audio/host.wav", &[sine(1000.0, 0.5, 24000, 48000)], 48000);
+        dir.wav("audio/theme.wav", &[sine(300.0, 0.2, 22050, 44100), sine(500.0, 0.2, 22050, 44100)], 44100);
+        dir.write("chains/voice.json", r#"{ "stages": [{ "type": "gain", "gain_db": -6.0206 }] }"#);
+        let path = dir.write(
+            "episode.toml",
+            r#"
+            [export]
+            file = "mix/episode.wav"
+            format = "float32"
+            ceiling_db = -1
+
+            [[tracks]]
+            file = "audio/host.wav"
+            pipeline = "chains/voice.json"
+
+            [[tracks]]
+            file = "audio/theme.wav"
+            offset_seconds = 0.5
+            "#,
+        );
+        let project = Project::load(&path).unwrap();
+        project.validate().unwrap();
+        let out = dir.0.join("out");
+        let report = project.render(&out).unwrap();
+
+        assert_eq!(report.output, out.join("mix/episode.wav"));
+        assert_eq!((report.sample_rate, report.normalization_gain_db), (48000, 0.0));
+        let (samples, spec) = read_wave_file(&report.output.to_string_lossy()).unwrap();
+        assert_eq!((spec.sample_rate, spec.channels), (48000, 2));
+        assert_eq!(samples.len(), report.frames * 2);
+        assert!((report.frames as i64 - 48000).abs() <= 8, "{} frames", report.frames);
+
+        // The first half is the host alone, centred at 0.25 * cos(pi/4).
+        let expected = 0.25 * std::f32::consts::FRAC_1_SQRT_2;
+        let first: Vec<&[f32]> = samples[..2 * 24000].chunks(2).collect();
+        let peak = first.iter().map(|f| f[0].abs()).fold(0.0, f32::max);
+        assert!((peak - expected).abs() < 1e-3, "host peaks at {}", peak);
+        assert!(first.iter().all(|f| (f[0] - f[1]).abs() < 1e-6));
+        // The second half is the theme alone, left and right kept apart.
+        let second: Vec<&[f32]> = samples[2 * 24100..].chunks(2).collect();
+        let (left, right) = (
+            second.iter().map(|f| f[0].abs()).fold(0.0, f32::max),
+            second.iter().map(|f| f[1].abs()).fold(0.0, f32::max),
+        );
+        assert!((left - 0.2).abs() < 5e-3 && (right - 0.2).abs() < 5e-3, "theme peaks at {} and {}", left, right);
+        assert!(second.iter().any(|f| (f[0] - f[1]).abs() > 0.1));
+    }
+
+    #[test]
+    fn render_meets_the_loudness_target_under_the_ceiling() {
+        let dir = TempDir::new("project_loudness");
+        dir.wav("audio/host.wav", &[sine(1000.0, 0.05, 96000, 48000)], 48000);
+        let path = dir.write(
+            "episode.toml",
+            r#"
+            [export]
+            loudness_target_lufs = -16
+            ceiling_db = -1
+
+            [[tracks]]
+            file = "audio/host.wav"
+            "#,
+        );
+        let project = Project::load(&path).unwrap();
+        let report = project.render(&dir.0).unwrap();
+        assert!(report.normalization_gain_db > 10.0, "gained {} dB", report.normalization_gain_db);
+        assert!((report.loudness_lufs - -16.0).abs() < 0.5, "{} LUFS", report.loudness_lufs);
+
+        let (samples, spec) = read_wave_file(&report.output.to_string_lossy()).unwrap();
+        assert_eq!(spec.bits_per_sample, 24);
+        let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        assert!(peak <= db_to_linear(-1.0) + 1e-4, "peak {}", peak);
+    }
+
+    #[test]
+    fn plan_describes_the_render_without_doing_it() {
+        let project = Project::from_toml(
+            r#"
+            [export]
+            file = "episode.wav"
+            format = "int16"
+            loudness_target_lufs = -16
+            ceiling_db = -1.5
+            sample_rate = 44100
+
+            [[tracks]]
+            file = "host.wav"
+            pipeline = "voice.json"
+            gain_db = -2
+            pan = -0.25
+            offset_seconds = 0.5
+            "#,
+            Path::new("show"),
+        )
+        .unwrap();
+        assert_eq!(
+            project.plan(Path::new("out")),
+            [
+                format!(
+                    "track 'host': {} through {}, -2.0 dB, pan -0.25, at 0.500 s",
+                    Path::new("show/host.wav").display(),
+                    Path::new("show/voice.json").display()
+                ),
+                "mix 1 tracks to stereo at 44100 Hz".to_string(),
+                "normalize to -16.0 LUFS".to_string(),
+                "limit to -1.5 dBFS".to_string(),
+                format!("write {} (16 bit)", Path::new("out/episode.wav").display()),
+            ]
+        );
+    }
+}
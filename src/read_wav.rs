@@ -1,32 +1,594 @@
-use hound;
 use hound::SampleFormat;
 
-pub fn read_wave_file(filepath: &str) -> Result<(Vec<f32>, u32), Box<dyn std::error::Error>> {
-    let mut reader = hound::WavReader::open(filepath)?;
+use crate::write_wav::{decode_bext, BextChunk, CuePoint};
+
+/// Samples of an open reader, converted to f32 one at a time.
+pub type NormalizedSamples<'r> = Box<dyn Iterator<Item = Result<f32, hound::Error>> + 'r>;
+
+pub fn normalized_samples<'r, R: std::io::Read + 'r>(
+    reader: &'r mut hound::WavReader<R>,
+) -> Result<NormalizedSamples<'r>, Box<dyn std::error::Error>> {
     let spec = reader.spec();
-    let sample_rate = spec.sample_rate;
-    println!("sample rate: {}", sample_rate);
-    let sample_format = spec.sample_format;
-    println!("sample format: {:?}", sample_format);
-    let bits_per_sample = spec.bits_per_sample;
-    println!("bits per sample: {}", bits_per_sample);
-
-    // Normalize to f32, downsample 24bit to 16bit
-    let samples = match (sample_format, bits_per_sample) {
-        (SampleFormat::Int, 16) => reader.samples::<i16>()
-            .map(|s| s.unwrap() as f32 / i16::MAX as f32) // Normalize i16 to f32
-            .collect(),
-        (SampleFormat::Int, 24) => reader.samples::<i32>() // Read as i32 for 24-bit audio
-            .map(|s| (s.unwrap() >> 8) as f32 / i16::MAX as f32) // Normalize 24-bit (shift by 8)
-            .collect(),
-        (SampleFormat::Int, 32) => reader.samples::<i32>()
-            .map(|s| s.unwrap() as f32 / i32::MAX as f32) // Normalize i32 to f32
-            .collect(),
-        (SampleFormat::Float, 32) => reader.samples::<f32>()
-            .map(|s| s.unwrap()) // Already in f32 format
-            .collect(),
+
+    // Normalize to f32, dividing by 2^(bits - 1) so the most negative value
+    // is exactly -1.0 and no bits are lost
+    let samples: NormalizedSamples<'r> = match (spec.sample_format, spec.bits_per_sample) {
+        (SampleFormat::Int, 8) => Box::new(reader.samples::<i8>() // hound recenters unsigned 8-bit on 0
+            .map(|s| s.map(|s| s as f32 / 128.0))), // Normalize i8 to f32
+        (SampleFormat::Int, 16) => Box::new(reader.samples::<i16>()
+            .map(|s| s.map(|s| s as f32 / 32_768.0))), // Normalize i16 to f32
+        (SampleFormat::Int, 24) => Box::new(reader.samples::<i32>() // Read as i32 for 24-bit audio
+            .map(|s| s.map(|s| s as f32 / 8_388_608.0))), // Normalize 24-bit to f32
+        (SampleFormat::Int, 32) => Box::new(reader.samples::<i32>()
+            .map(|s| s.map(|s| s as f32 / 2_147_483_648.0))), // Normalize i32 to f32
+        (SampleFormat::Float, 32) => Box::new(reader.samples::<f32>()), // Already in f32 format
         _ => return Err("Unsupported sample format or bit depth".into()),
     };
 
-    Ok((samples, sample_rate))
+    Ok(samples)
+}
+
+/// Reads a WAV file as it is stored, channels interleaved, with its spec
+/// for the channel count and rate. `read_wav_data` splits the channels,
+/// ready for the per-channel functions in `dsp`.
+pub fn read_wave_file(filepath: &str) -> Result<(Vec<f32>, hound::WavSpec), Box<dyn std::error::Error>> {
+    let mut reader = hound::WavReader::open(filepath)?;
+    let spec = reader.spec();
+    let samples = normalized_samples(&mut reader)?.collect::<Result<Vec<f32>, _>>()?;
+    Ok((samples, spec))
+}
+
+/// Decoded audio, one Vec per channel.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WavData {
+    pub channels: Vec<Vec<f32>>,
+    pub sample_rate: u32,
+}
+
+impl WavData {
+    pub fn channel_count(&self) -> u16 {
+        self.channels.len() as u16
+    }
+
+    pub fn frames(&self) -> usize {
+        self.channels.first().map_or(0, |c| c.len())
+    }
+
+    pub fn duration_seconds(&self) -> f64 {
+        self.frames() as f64 / self.sample_rate as f64
+    }
+
+    /// Each channel as a slice, borrowed rather than copied.
+    pub fn split_channels(&self) -> Vec<&[f32]> {
+        self.channels.iter().map(Vec::as_slice).collect()
+    }
+
+    /// Average of all channels.
+    pub fn to_mono(&self) -> Vec<f32> {
+        let scale = 1.0 / self.channels.len().max(1) as f32;
+        (0..self.frames())
+            .map(|i| self.channels.iter().map(|c| c[i]).sum::<f32>() * scale)
+            .collect()
+    }
+}
+
+/// Reads a WAV file into separate channels, without printing anything.
+pub fn read_wav_data(filepath: &str) -> Result<WavData, Box<dyn std::error::Error>> {
+    let mut reader = hound::WavReader::open(filepath)?;
+    let spec = reader.spec();
+    let channel_count = spec.channels.max(1) as usize;
+    let frames = reader.duration() as usize;
+
+    let mut channels = vec![Vec::with_capacity(frames); channel_count];
+    for (i, sample) in normalized_samples(&mut reader)?.enumerate() {
+        channels[i % channel_count].push(sample?);
+    }
+    // Drop a trailing partial frame so all channels have the same length.
+    let frames = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+    for channel in channels.iter_mut() {
+        channel.truncate(frames);
+    }
+
+    Ok(WavData {
+        channels,
+        sample_rate: spec.sample_rate,
+    })
+}
+
+/// Reads a WAV, AIFF or FLAC file into separate channels, telling them
+/// apart by the magic at the start of the file.
+pub fn read_audio_file(filepath: &str) -> Result<WavData, Box<dyn std::error::Error>> {
+    let mut magic = [0u8; 4];
+    std::io::Read::read_exact(&mut std::fs::File::open(filepath)?, &mut magic)?;
+    match &magic {
+        b"RIFF" => read_wav_data(filepath),
+        b"FORM" => read_aiff_data(filepath),
+        b"fLaC" => read_flac_file(filepath),
+        _ if compressed_format(&magic).is_some() => read_compressed(filepath),
+        _ => Err("Not a WAV, AIFF or FLAC file".into()),
+    }
+}
+
+// The lossy container a file starts like, from its first bytes: an ID3
+// tag or MPEG audio frame sync, or an Ogg page.
+fn compressed_format(magic: &[u8; 4]) -> Option<&'static str> {
+    match magic {
+        [b'I', b'D', b'3', _] => Some("MP3"),
+        [0xff, second, _, _] if second & 0xe0 == 0xe0 => Some("MP3"),
+        b"OggS" => Some("Ogg"),
+        _ => None,
+    }
+}
+
+/// Recognises MP3 and Ogg files and returns an error naming the format;
+/// convert them to WAV or FLAC first. Decoding them is left for when a
+/// decoder such as symphonia can be added as a dependency, and will get
+/// its own `compressed` feature then.
+pub fn read_compressed(filepath: &str) -> Result<WavData, Box<dyn std::error::Error>> {
+    let mut magic = [0u8; 4];
+    std::io::Read::read_exact(&mut std::fs::File::open(filepath)?, &mut magic)?;
+    match compressed_format(&magic) {
+        Some(format) => Err(format!(
+            "{}: {} files can't be decoded by this build, convert it to WAV or FLAC first",
+            filepath, format
+        )
+        .into()),
+        None => Err(format!("{}: not an MP3 or Ogg file", filepath).into()),
+    }
+}
+
+/// Reads a FLAC file into separate channels, normalized by the stream's
+/// bit depth like WAV samples. Needs the `flac` feature; without it this
+/// says so.
+pub fn read_flac_file(filepath: &str) -> Result<WavData, Box<dyn std::error::Error>> {
+    #[cfg(feature = "flac")]
+    {
+        let stream = crate::flac::decode(&std::fs::read(filepath)?)?;
+        let scale = 1.0 / (1u64 << (stream.bits_per_sample - 1)) as f32;
+        let channels = stream
+            .channels
+            .iter()
+            .map(|channel| channel.iter().map(|&s| s as f32 * scale).collect())
+            .collect();
+        Ok(WavData { channels, sample_rate: stream.sample_rate })
+    }
+    #[cfg(not(feature = "flac"))]
+    Err(format!("{}: compiled without flac support, rebuild with --features flac", filepath).into())
+}
+
+/// Reads an uncompressed AIFF (or AIFF-C `NONE`) file of 8 to 32-bit
+/// big-endian PCM into separate channels, normalized like WAV samples.
+pub fn read_aiff_data(filepath: &str) -> Result<WavData, Box<dyn std::error::Error>> {
+    let bytes = std::fs::read(filepath)?;
+    if bytes.len() < 12 || &bytes[0..4] != b"FORM" || !matches!(&bytes[8..12], b"AIFF" | b"AIFC") {
+        return Err("Not an AIFF file".into());
+    }
+    let chunks = aiff_chunks(&bytes[12..]);
+    let comm = chunks.iter().find(|(id, _)| *id == b"COMM").map(|(_, body)| *body).ok_or("AIFF file has no COMM chunk")?;
+    if comm.len() < 18 {
+        return Err("AIFF COMM chunk is too short".into());
+    }
+    let channel_count = u16::from_be_bytes(comm[0..2].try_into()?).max(1) as usize;
+    let frames = u32::from_be_bytes(comm[2..6].try_into()?) as usize;
+    let bits = u16::from_be_bytes(comm[6..8].try_into()?);
+    let sample_rate = extended_to_f64(comm[8..18].try_into()?).round() as u32;
+    // In AIFF-C the compression type follows the rate; only `NONE` is read.
+    if &bytes[8..12] == b"AIFC" && comm.get(18..22).is_some_and(|kind| kind != b"NONE") {
+        return Err("Compressed AIFF-C files are not supported".into());
+    }
+    if !(1..=32).contains(&bits) {
+        return Err("Unsupported sample format or bit depth".into());
+    }
+
+    let ssnd = chunks.iter().find(|(id, _)| *id == b"SSND").map(|(_, body)| *body).ok_or("AIFF file has no SSND chunk")?;
+    if ssnd.len() < 8 {
+        return Err("AIFF SSND chunk is too short".into());
+    }
+    let offset = u32::from_be_bytes(ssnd[0..4].try_into()?) as usize;
+    let data = ssnd.get(8 + offset..).unwrap_or(&[]);
+
+    // Samples are left-justified in whole bytes.
+    let width = bits.div_ceil(8) as usize;
+    let scale = 1.0 / (1u64 << (width * 8 - 1)) as f32;
+    let frames = frames.min(data.len() / (width * channel_count));
+    let mut channels = vec![Vec::with_capacity(frames); channel_count];
+    for (i, sample) in data.chunks_exact(width).take(frames * channel_count).enumerate() {
+        // Sign-extended by shifting the big-endian bytes to the top of an i32.
+        let value = sample.iter().fold(0u32, |acc, &b| acc << 8 | b as u32) << (32 - 8 * width);
+        let value = (value as i32 >> (32 - 8 * width)) as f32 * scale;
+        channels[i % channel_count].push(value);
+    }
+
+    Ok(WavData { channels, sample_rate })
+}
+
+// An IEEE 754 80-bit extended float, as AIFF stores its sample rate: sign
+// and 15-bit exponent, then a 64-bit mantissa with an explicit integer bit.
+fn extended_to_f64(bytes: [u8; 10]) -> f64 {
+    let sign = if bytes[0] & 0x80 != 0 { -1.0 } else { 1.0 };
+    let exponent = (u16::from_be_bytes([bytes[0] & 0x7f, bytes[1]])) as i32;
+    let mantissa = u64::from_be_bytes(bytes[2..10].try_into().unwrap());
+    if exponent == 0 && mantissa == 0 {
+        return 0.0;
+    }
+    sign * mantissa as f64 * 2f64.powi(exponent - 16383 - 63)
+}
+
+/// Reads a WAV file a chunk of frames at a time, so files of any length can
+/// be processed in constant memory.
+pub struct WavChunkReader {
+    reader: hound::WavReader<std::io::BufReader<std::fs::File>>,
+    chunk_frames: usize,
+    chunk: Vec<f32>,
+}
+
+impl WavChunkReader {
+    pub fn open(filepath: &str, chunk_frames: usize) -> Result<Self, Box<dyn std::error::Error>> {
+        let reader = hound::WavReader::open(filepath)?;
+        let chunk_frames = chunk_frames.max(1);
+        let chunk = Vec::with_capacity(chunk_frames * reader.spec().channels.max(1) as usize);
+        Ok(Self { reader, chunk_frames, chunk })
+    }
+
+    pub fn spec(&self) -> hound::WavSpec {
+        self.reader.spec()
+    }
+
+    /// Total length of the file in frames.
+    pub fn frames(&self) -> usize {
+        self.reader.duration() as usize
+    }
+
+    /// The next interleaved chunk of up to `chunk_frames` whole frames, or
+    /// `None` at the end of the file.
+    pub fn next_chunk(&mut self) -> Result<Option<&[f32]>, Box<dyn std::error::Error>> {
+        let channels = self.reader.spec().channels.max(1) as usize;
+        self.chunk.clear();
+        for sample in normalized_samples(&mut self.reader)?.take(self.chunk_frames * channels) {
+            self.chunk.push(sample?);
+        }
+        // Drop a trailing partial frame, as `read_wav_data` does.
+        self.chunk.truncate(self.chunk.len() / channels * channels);
+        Ok(if self.chunk.is_empty() { None } else { Some(&self.chunk) })
+    }
+}
+
+/// Opens a WAV file as an iterator over interleaved chunks of
+/// `chunk_frames` frames; the last may be shorter. Only one chunk is held
+/// at a time.
+pub fn open_chunked(filepath: &str, chunk_frames: usize) -> Result<WavChunks, Box<dyn std::error::Error>> {
+    Ok(WavChunks { reader: WavChunkReader::open(filepath, chunk_frames)? })
+}
+
+/// `WavChunkReader` as an iterator of owned chunks, from `open_chunked`.
+pub struct WavChunks {
+    reader: WavChunkReader,
+}
+
+impl WavChunks {
+    pub fn spec(&self) -> hound::WavSpec {
+        self.reader.spec()
+    }
+
+    /// Total length of the file in frames.
+    pub fn frames(&self) -> usize {
+        self.reader.frames()
+    }
+}
+
+impl Iterator for WavChunks {
+    type Item = Result<Vec<f32>, Box<dyn std::error::Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.reader.next_chunk().map(|chunk| chunk.map(<[f32]>::to_vec)).transpose()
+    }
+}
+
+/// Metadata chunks found in a WAV file.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WavMetadata {
+    pub bext: Option<BextChunk>,
+    pub cues: Vec<CuePoint>,
+}
+
+/// Reads the bext chunk and labelled cue points of a WAV file, skipping the audio.
+pub fn read_wav_metadata(filepath: &str) -> Result<WavMetadata, Box<dyn std::error::Error>> {
+    let bytes = std::fs::read(filepath)?;
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err("Not a RIFF/WAVE file".into());
+    }
+
+    let mut metadata = WavMetadata::default();
+    let mut positions: Vec<(u32, u32)> = Vec::new();
+    let mut labels: Vec<(u32, String)> = Vec::new();
+
+    for (id, body) in riff_chunks(&bytes[12..]) {
+        match id {
+            b"bext" => metadata.bext = decode_bext(body),
+            b"cue " if body.len() >= 4 => {
+                let count = u32::from_le_bytes(body[0..4].try_into()?) as usize;
+                for point in body[4..].chunks_exact(24).take(count) {
+                    let cue_id = u32::from_le_bytes(point[0..4].try_into()?);
+                    let offset = u32::from_le_bytes(point[20..24].try_into()?);
+                    positions.push((cue_id, offset));
+                }
+            }
+            b"LIST" if body.len() >= 4 && &body[0..4] == b"adtl" => {
+                for (sub_id, sub_body) in riff_chunks(&body[4..]) {
+                    if sub_id == b"labl" && sub_body.len() >= 4 {
+                        let cue_id = u32::from_le_bytes(sub_body[0..4].try_into()?);
+                        let text = &sub_body[4..];
+                        let end = text.iter().position(|&b| b == 0).unwrap_or(text.len());
+                        labels.push((cue_id, String::from_utf8_lossy(&text[..end]).into_owned()));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    metadata.cues = positions
+        .into_iter()
+        .map(|(cue_id, sample_position)| CuePoint {
+            label: labels
+                .iter()
+                .find(|(id, _)| *id == cue_id)
+                .map(|(_, label)| label.clone())
+                .unwrap_or_default(),
+            sample_position,
+        })
+        .collect();
+
+    Ok(metadata)
+}
+
+// `riff_chunks` for AIFF, whose sizes are big-endian.
+fn aiff_chunks(mut bytes: &[u8]) -> Vec<(&[u8; 4], &[u8])> {
+    let mut chunks = Vec::new();
+    while bytes.len() >= 8 {
+        let id: &[u8; 4] = bytes[0..4].try_into().unwrap();
+        let size = u32::from_be_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        let end = 8 + size;
+        if end > bytes.len() {
+            break;
+        }
+        chunks.push((id, &bytes[8..end]));
+        bytes = &bytes[(end + size % 2).min(bytes.len())..];
+    }
+    chunks
+}
+
+// Splits a run of RIFF chunks into (id, body) pairs, honouring the pad byte
+// after odd-sized chunks. Stops at the first truncated chunk.
+fn riff_chunks(mut bytes: &[u8]) -> Vec<(&[u8; 4], &[u8])> {
+    let mut chunks = Vec::new();
+    while bytes.len() >= 8 {
+        let id: &[u8; 4] = bytes[0..4].try_into().unwrap();
+        let size = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        let end = 8 + size;
+        if end > bytes.len() {
+            break;
+        }
+        chunks.push((id, &bytes[8..end]));
+        bytes = &bytes[(end + size % 2).min(bytes.len())..];
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // A file in the temp directory, removed when dropped.
+    struct TempFile(PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, bytes: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("cpal_playbook_{}_{}", std::process::id(), name));
+            std::fs::write(&path, bytes).unwrap();
+            Self(path)
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    // A PCM WAV file holding `data` as given, little-endian.
+    fn wav_bytes(channels: u16, sample_rate: u32, bits: u16, data: &[u8]) -> Vec<u8> {
+        let block_align = channels * bits.div_ceil(8);
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(b"RIFF");
+        bytes.extend_from_slice(&(36 + data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(b"WAVEfmt ");
+        bytes.extend_from_slice(&16u32.to_le_bytes());
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&channels.to_le_bytes());
+        bytes.extend_from_slice(&sample_rate.to_le_bytes());
+        bytes.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+        bytes.extend_from_slice(&block_align.to_le_bytes());
+        bytes.extend_from_slice(&bits.to_le_bytes());
+        bytes.extend_from_slice(b"data");
+        bytes.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(data);
+        bytes
+    }
+
+    #[test]
+    fn unsigned_8_bit_square_wave_decodes_to_full_scale() {
+        // 0 and 255 in runs of 50, as stored: unsigned, centered on 128.
+        let data: Vec<u8> = (0..1000).map(|i| if (i / 50) % 2 == 0 { 255 } else { 0 }).collect();
+        let file = TempFile::new("square8.wav", &wav_bytes(1, 8_000, 8, &data));
+
+        let (mut samples, spec) = read_wave_file(file.path()).unwrap();
+        assert_eq!((spec.sample_rate, spec.channels, spec.bits_per_sample), (8_000, 1, 8));
+        assert_eq!(samples.len(), 1000);
+        assert_eq!(samples[0], 127.0 / 128.0);
+        assert_eq!(samples[50], -1.0);
+
+        crate::fx::remove_dc_offset(&mut samples);
+        let dc = samples.iter().sum::<f32>() / samples.len() as f32;
+        assert!(dc.abs() < 1e-4, "{}", dc);
+        for sample in samples {
+            assert!((sample.abs() - 1.0).abs() < 0.01, "{}", sample);
+        }
+    }
+
+    fn le_bytes(values: &[i32], width: usize) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()[..width].to_vec()).collect()
+    }
+
+    #[test]
+    fn integer_full_scale_maps_to_one() {
+        for (name, bits, max, min) in [
+            ("full16.wav", 16, i16::MAX as i32, i16::MIN as i32),
+            ("full24.wav", 24, 8_388_607, -8_388_608),
+            ("full32.wav", 32, i32::MAX, i32::MIN),
+        ] {
+            let data = le_bytes(&[max, min], bits as usize / 8);
+            let file = TempFile::new(name, &wav_bytes(1, 48_000, bits, &data));
+            let (samples, _) = read_wave_file(file.path()).unwrap();
+            assert!((samples[0] - 1.0).abs() < 1e-4, "{} bits: {}", bits, samples[0]);
+            assert_eq!(samples[1], -1.0, "{} bits", bits);
+        }
+    }
+
+    #[test]
+    fn quiet_24_bit_signals_keep_their_low_bits() {
+        let values: Vec<i32> = (-255..=255).collect();
+        let file = TempFile::new("quiet24.wav", &wav_bytes(1, 48_000, 24, &le_bytes(&values, 3)));
+        let (samples, _) = read_wave_file(file.path()).unwrap();
+        for (value, sample) in values.iter().zip(samples) {
+            assert_eq!(sample, *value as f32 / 8_388_608.0);
+        }
+    }
+
+    // The 80-bit extended encodings of common rates, as other tools write them.
+    const RATE_44100: [u8; 10] = [0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0];
+    const RATE_48000: [u8; 10] = [0x40, 0x0e, 0xbb, 0x80, 0, 0, 0, 0, 0, 0];
+    const RATE_96000: [u8; 10] = [0x40, 0x0f, 0xbb, 0x80, 0, 0, 0, 0, 0, 0];
+
+    // An AIFF file holding big-endian `data` for the given COMM fields.
+    fn aiff_bytes(channels: u16, frames: u32, bits: u16, rate: [u8; 10], data: &[u8]) -> Vec<u8> {
+        let mut comm = Vec::new();
+        comm.extend_from_slice(&channels.to_be_bytes());
+        comm.extend_from_slice(&frames.to_be_bytes());
+        comm.extend_from_slice(&bits.to_be_bytes());
+        comm.extend_from_slice(&rate);
+        let mut body = b"AIFF".to_vec();
+        body.extend_from_slice(b"COMM");
+        body.extend_from_slice(&(comm.len() as u32).to_be_bytes());
+        body.extend_from_slice(&comm);
+        body.extend_from_slice(b"SSND");
+        body.extend_from_slice(&(8 + data.len() as u32).to_be_bytes());
+        body.extend_from_slice(&[0; 8]);
+        body.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            body.push(0);
+        }
+        let mut bytes = b"FORM".to_vec();
+        bytes.extend_from_slice(&(body.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&body);
+        bytes
+    }
+
+    fn be_bytes(values: &[i32], width: usize) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()[4 - width..].to_vec()).collect()
+    }
+
+    #[test]
+    fn extended_sample_rates_decode_exactly() {
+        assert_eq!(extended_to_f64(RATE_44100), 44_100.0);
+        assert_eq!(extended_to_f64(RATE_48000), 48_000.0);
+        assert_eq!(extended_to_f64(RATE_96000), 96_000.0);
+        assert_eq!(extended_to_f64([0; 10]), 0.0);
+    }
+
+    #[test]
+    fn aiff_16_bit_reads_big_endian_channels() {
+        let values = [i16::MAX as i32, i16::MIN as i32, 1, -1, 0, 16_384];
+        let file = TempFile::new("stereo16.aiff", &aiff_bytes(2, 3, 16, RATE_44100, &be_bytes(&values, 2)));
+        for data in [read_aiff_data(file.path()).unwrap(), read_audio_file(file.path()).unwrap()] {
+            assert_eq!(data.sample_rate, 44_100);
+            assert_eq!(data.channels[0], [32_767.0 / 32_768.0, 1.0 / 32_768.0, 0.0]);
+            assert_eq!(data.channels[1], [-1.0, -1.0 / 32_768.0, 0.5]);
+        }
+    }
+
+    #[test]
+    fn aiff_24_bit_reads_big_endian_samples() {
+        let values = [8_388_607, -8_388_608, 1, -1];
+        let file = TempFile::new("mono24.aiff", &aiff_bytes(1, 4, 24, RATE_96000, &be_bytes(&values, 3)));
+        let data = read_audio_file(file.path()).unwrap();
+        assert_eq!(data.sample_rate, 96_000);
+        let expected: Vec<f32> = values.iter().map(|&v| v as f32 / 8_388_608.0).collect();
+        assert_eq!(data.channels, [expected]);
+    }
+
+    #[test]
+    fn aiff_frames_are_capped_by_the_sound_data() {
+        // COMM claims more frames than SSND holds.
+        let file = TempFile::new("short.aiff", &aiff_bytes(1, 100, 16, RATE_48000, &be_bytes(&[1, 2], 2)));
+        let data = read_aiff_data(file.path()).unwrap();
+        assert_eq!(data.sample_rate, 48_000);
+        assert_eq!(data.frames(), 2);
+    }
+
+    #[test]
+    fn stereo_files_split_into_channels() {
+        // Left a ramp, right its negative plus an offset.
+        let left: Vec<i32> = (0..100).map(|i| i * 100).collect();
+        let right: Vec<i32> = left.iter().map(|&l| 1_000 - l).collect();
+        let interleaved: Vec<i32> = left.iter().zip(&right).flat_map(|(&l, &r)| [l, r]).collect();
+        let file = TempFile::new("stereo16.wav", &wav_bytes(2, 44_100, 16, &le_bytes(&interleaved, 2)));
+
+        let (samples, spec) = read_wave_file(file.path()).unwrap();
+        assert_eq!((spec.channels, samples.len()), (2, 200));
+
+        let data = read_wav_data(file.path()).unwrap();
+        assert_eq!((data.channel_count(), data.frames(), data.sample_rate), (2, 100, 44_100));
+        assert_eq!(data.split_channels()[0][3], 300.0 / 32_768.0);
+        assert_eq!(data.split_channels()[1][3], 700.0 / 32_768.0);
+        for (mono, (l, r)) in data.to_mono().iter().zip(left.iter().zip(&right)) {
+            assert_eq!(*mono, (*l + *r) as f32 / 32_768.0 / 2.0);
+        }
+
+        // The two channels feed the stereo functions in `dsp` directly.
+        let (mid, side) = crate::dsp::mid_side_encode(&data.channels[0], &data.channels[1]);
+        let (l, r) = crate::dsp::mid_side_decode(&mid, &side);
+        // The sum is constant, so mid is too.
+        assert!(mid.iter().all(|&m| (m - 500.0 / 32_768.0).abs() < 1e-7));
+        assert!((side[3] - (300.0 - 700.0) / 2.0 / 32_768.0).abs() < 1e-7);
+        for (decoded, original) in l.iter().chain(&r).zip(data.channels[0].iter().chain(&data.channels[1])) {
+            assert!((decoded - original).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn compressed_formats_are_named_in_the_error() {
+        for (name, bytes, format) in [
+            ("tagged.mp3", &b"ID3\x04\0\0\0\0\0\0"[..], "MP3"),
+            ("bare.mp3", &[0xff, 0xfb, 0x90, 0x64, 0, 0][..], "MP3"),
+            ("audio.ogg", &b"OggS\0\x02\0\0"[..], "Ogg"),
+        ] {
+            let file = TempFile::new(name, bytes);
+            let error = read_audio_file(file.path()).unwrap_err().to_string();
+            assert!(error.contains(format), "{}", error);
+            assert!(read_compressed(file.path()).is_err());
+        }
+    }
+
+    #[test]
+    fn unknown_files_are_rejected() {
+        let file = TempFile::new("unknown.bin", b"\0\0\0\0\0\0");
+        assert!(read_audio_file(file.path()).is_err());
+        assert!(read_compressed(file.path()).unwrap_err().to_string().contains("not an MP3 or Ogg file"));
+    }
 }
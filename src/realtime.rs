@@ -0,0 +1,297 @@
+// Keeping audio callbacks free of allocation.
+//
+// A callback runs on the device's thread against a deadline, and the
+// allocator can take a lock or fault in fresh pages at any moment. So
+// everything a callback touches is sized before its stream starts: scratch
+// buffers hold `CALLBACK_SCRATCH_SAMPLES` and longer blocks are worked
+// through in pieces, blocks handed to other threads come from pools filled
+// up front, and messages from callbacks go through a bounded queue to a
+// logging thread instead of straight to stdout or stderr, which lock and
+// may allocate.
+//
+// With the `alloc-check` feature `CountingAllocator` counts allocations
+// and frees made inside `callback_scope`, and debug builds assert after
+// every callback that it made none.
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, SyncSender};
+use std::sync::OnceLock;
+
+use crate::limiter::SafetyEvent;
+
+#[cfg(feature = "alloc-check")]
+use std::alloc::{GlobalAlloc, Layout, System};
+#[cfg(feature = "alloc-check")]
+use std::cell::Cell;
+
+/// Samples a callback's scratch buffer holds. Blocks longer than that are
+/// processed in pieces.
+pub const CALLBACK_SCRATCH_SAMPLES: usize = 16384;
+/// Messages queued for the logging thread before further ones are dropped.
+const LOG_QUEUE_MESSAGES: usize = 64;
+
+/// Scratch length for interleaved blocks of `channels`: as much of
+/// `CALLBACK_SCRATCH_SAMPLES` as whole frames fill.
+pub fn scratch_len(channels: usize) -> usize {
+    let channels = channels.max(1);
+    (CALLBACK_SCRATCH_SAMPLES / channels).max(1) * channels
+}
+
+/// Something a callback wants printed.
+#[derive(Debug)]
+pub enum CallbackLog {
+    /// Printed to stderr.
+    Error(cpal::StreamError),
+    /// Length and RMS of a block, printed to stdout.
+    Block { samples: usize, rms: f32 },
+    /// The safety limiter muted a stream, printed to stderr.
+    Muted(SafetyEvent),
+}
+
+impl fmt::Display for CallbackLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CallbackLog::Error(err) => write!(f, "Error: {}", err),
+            CallbackLog::Block { samples, rms } => write!(f, "{} samples, RMS {:.4}", samples, rms),
+            CallbackLog::Muted(event) => write!(f, "Output muted: {}", event),
+        }
+    }
+}
+
+/// The callbacks' end of the logging thread.
+pub struct CallbackLogger {
+    sender: SyncSender<CallbackLog>,
+    dropped: AtomicU64,
+}
+
+impl CallbackLogger {
+    /// Queues `message`. Never blocks or allocates; with the queue full the
+    /// message is dropped and counted, and the logging thread says how many
+    /// it missed with the next one.
+    pub fn log(&self, message: CallbackLog) {
+        if self.sender.try_send(message).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+static LOGGER: OnceLock<CallbackLogger> = OnceLock::new();
+
+/// The logger, starting its thread on the first call. Call it while
+/// setting a stream up, not from the callback.
+pub fn callback_logger() -> &'static CallbackLogger {
+    LOGGER.get_or_init(|| {
+        let (sender, messages) = mpsc::sync_channel::<CallbackLog>(LOG_QUEUE_MESSAGES);
+        std::thread::spawn(move || {
+            for message in messages {
+                let dropped = LOGGER.get().map_or(0, |logger| logger.dropped.swap(0, Ordering::Relaxed));
+                if dropped > 0 {
+                    eprintln!("({} callback messages dropped)", dropped);
+                }
+                match message {
+                    CallbackLog::Error(_) | CallbackLog::Muted(_) => eprintln!("{}", message),
+                    CallbackLog::Block { .. } => println!("{}", message),
+                }
+            }
+        });
+        CallbackLogger { sender, dropped: AtomicU64::new(0) }
+    })
+}
+
+/// An error callback for cpal that hands the error to the logging thread.
+pub fn log_stream_error() -> impl FnMut(cpal::StreamError) + Send + 'static {
+    let logger = callback_logger();
+    move |err| logger.log(CallbackLog::Error(err))
+}
+
+/// Allocations and frees counted inside `callback_scope` on all threads.
+#[cfg(feature = "alloc-check")]
+static CALLBACK_ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+#[cfg(feature = "alloc-check")]
+thread_local! {
+    // Const-initialized without a destructor, so touching these from the
+    // allocator never allocates itself.
+    static IN_CALLBACK: Cell<bool> = const { Cell::new(false) };
+    static SCOPE_ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+/// The system allocator, counting what happens inside `callback_scope`.
+/// Installed as the global allocator with the `alloc-check` feature.
+#[cfg(feature = "alloc-check")]
+pub struct CountingAllocator;
+
+#[cfg(feature = "alloc-check")]
+fn count_allocation() {
+    let inside = IN_CALLBACK.try_with(Cell::get).unwrap_or(false);
+    if inside {
+        CALLBACK_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        let _ = SCOPE_ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+    }
+}
+
+#[cfg(feature = "alloc-check")]
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        System.alloc(layout)
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        count_allocation();
+        System.alloc_zeroed(layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        count_allocation();
+        System.realloc(ptr, layout, new_size)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        count_allocation();
+        System.dealloc(ptr, layout)
+    }
+}
+
+// The binary installs it in main.rs; the library's own tests need it too.
+#[cfg(all(test, feature = "alloc-check"))]
+#[global_allocator]
+static TEST_ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Allocations and frees made inside callbacks so far; always 0 without
+/// the `alloc-check` feature.
+pub fn callback_allocations() -> u64 {
+    #[cfg(feature = "alloc-check")]
+    return CALLBACK_ALLOCATIONS.load(Ordering::Relaxed);
+    #[cfg(not(feature = "alloc-check"))]
+    0
+}
+
+/// Runs the body of an audio callback. With `alloc-check` whatever it
+/// allocates or frees is counted, and debug builds panic after a body that
+/// did; otherwise it just runs `body`.
+#[inline]
+pub fn callback_scope<R>(body: impl FnOnce() -> R) -> R {
+    #[cfg(feature = "alloc-check")]
+    {
+        let before = SCOPE_ALLOCATIONS.with(Cell::get);
+        let outer = IN_CALLBACK.with(|inside| inside.replace(true));
+        let result = body();
+        IN_CALLBACK.with(|inside| inside.set(outer));
+        let allocations = SCOPE_ALLOCATIONS.with(Cell::get) - before;
+        debug_assert!(allocations == 0, "an audio callback allocated or freed {} times", allocations);
+        result
+    }
+    #[cfg(not(feature = "alloc-check"))]
+    body()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scratch_holds_whole_frames() {
+        for channels in [0, 1, 2, 3, 6, 7, 8, 24, 20000] {
+            let len = scratch_len(channels);
+            assert_eq!(len % channels.max(1), 0, "{} channels", channels);
+            assert!(len <= CALLBACK_SCRATCH_SAMPLES.max(channels), "{} channels", channels);
+        }
+    }
+
+    #[test]
+    fn callback_scope_returns_the_body_result() {
+        assert_eq!(callback_scope(|| 6 * 7), 42);
+    }
+
+    #[cfg(not(feature = "alloc-check"))]
+    #[test]
+    fn allocations_are_not_counted_without_the_feature() {
+        callback_scope(|| drop(vec![0u8; 64]));
+        assert_eq!(callback_allocations(), 0);
+    }
+
+    #[cfg(all(feature = "alloc-check", debug_assertions))]
+    #[test]
+    #[should_panic(expected = "an audio callback allocated or freed")]
+    fn allocating_callback_panics() {
+        callback_scope(|| drop(std::hint::black_box(vec![0u8; 64])));
+    }
+
+    // The callback bodies of the streams, each run inside `callback_scope`
+    // after the same setup a stream does before it starts.
+    #[cfg(feature = "alloc-check")]
+    mod callback_bodies {
+        use super::super::*;
+        use crate::effect::{Effect, EffectChain, Gain};
+        use crate::filters::BiquadFilter;
+        use crate::fx::Compressor;
+        use crate::limiter::{OutputGuard, SafetyLimiterConfig};
+        use crate::scheduler::{AudioEvent, EventPlayer, Scheduler, SchedulerRunner};
+        use crate::stream::ring_buffer;
+        use std::sync::Arc;
+
+        #[test]
+        fn effect_chain_and_guard_do_not_allocate() {
+            let mut chain = EffectChain::new(vec![
+                Box::new(BiquadFilter::new_lowpass(48000.0, 2000.0, 0.707)),
+                Box::new(Compressor::new(-20.0, 4.0, 5.0, 50.0, 48000.0)),
+                Box::new(Gain::new(-6.0)),
+            ]);
+            let mut guard = OutputGuard::new(48000, 2, SafetyLimiterConfig::default());
+            let mut block = vec![0.5f32; 1024];
+            callback_scope(|| {
+                chain.process_block(&mut block);
+                guard.process(&mut block);
+                // Muting and telling the logging thread about it.
+                block[3] = f32::NAN;
+                guard.process(&mut block);
+            });
+            assert!(guard.is_muted());
+        }
+
+        #[test]
+        fn ring_buffer_does_not_allocate() {
+            let (mut producer, mut consumer) = ring_buffer::<f32>(4096);
+            let input = vec![0.25f32; 3000];
+            let mut output = vec![0.0f32; 3000];
+            callback_scope(|| {
+                for _ in 0..10 {
+                    producer.push_slice(&input);
+                    consumer.pop_slice(&mut output);
+                }
+            });
+        }
+
+        #[test]
+        fn scheduler_runner_does_not_allocate() {
+            let scheduler = Arc::new(Scheduler::new());
+            let mut runner = SchedulerRunner::new(Arc::clone(&scheduler), 2);
+            let mut player = EventPlayer::new(48000, 2);
+            for i in 0..100 {
+                scheduler.schedule(i * 37, AudioEvent::NoteOn { note: 60 + (i % 12) as u8, velocity: 0.5 });
+            }
+            scheduler.schedule(2000, AudioEvent::GainChange { gain: 0.5 });
+            let mut block = vec![0.0f32; 512];
+            callback_scope(|| {
+                for _ in 0..20 {
+                    runner.process(&mut block, &mut player);
+                }
+            });
+            assert_eq!(runner.pending(), 0);
+        }
+
+        #[test]
+        fn logging_does_not_allocate() {
+            // A queue of its own, so nothing is printed.
+            let (sender, _messages) = mpsc::sync_channel(LOG_QUEUE_MESSAGES);
+            let logger = CallbackLogger { sender, dropped: AtomicU64::new(0) };
+            callback_scope(|| {
+                for i in 0..(2 * LOG_QUEUE_MESSAGES) {
+                    logger.log(CallbackLog::Block { samples: i, rms: 0.0 });
+                }
+            });
+            assert_eq!(logger.dropped.load(Ordering::Relaxed), LOG_QUEUE_MESSAGES as u64);
+        }
+    }
+}
@@ -0,0 +1,569 @@
+// Sound-activated recording: stay armed on an input, write a file whenever
+// something loud enough happens, and re-arm afterwards.
+//
+// The decisions are made by `TriggerMachine`, a plain state machine fed one
+// envelope value per frame, so it works the same on a device, on a file or
+// in a test:
+//
+//   Armed ──above start──▶ Triggered ──for debounce──▶ Recording
+//     ▲                        │ dips below start            │ below stop
+//     │◀───────────────────────┘                             ▼
+//     └────────────below stop for hold────────────────── CoolingDown
+//
+// `TriggeredRecorder` adds the envelope follower, a pre-roll buffer so the
+// start of each event is kept, and one timestamped WAV file per event.
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use cpal::Device;
+
+use crate::analysis::linear_to_db;
+use crate::input::{InputStreamBuilder, StreamHandle};
+use crate::session::{error_action, ErrorAction};
+use crate::stream::{ring_buffer, Consumer, StreamError};
+use crate::write_wav::{WavSampleFormat, WavStreamWriter};
+
+/// How long the input ring holds audio for the writer thread.
+const INPUT_RING_MS: u32 = 2000;
+/// How often the writer thread drains the input ring.
+const WRITER_POLL: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct TriggerSettings {
+    /// Level the envelope has to exceed to start an event, in dBFS.
+    pub start_threshold_db: f32,
+    /// Level below which an event counts as over, in dBFS. Lower than the
+    /// start threshold so an event doesn't chatter on and off.
+    pub stop_threshold_db: f32,
+    /// How long the level has to stay above the start threshold.
+    pub debounce_ms: f32,
+    /// How long the level has to stay below the stop threshold.
+    pub hold_ms: f32,
+    /// Audio kept from before the trigger.
+    pub pre_roll_ms: f32,
+    /// Release of the envelope follower; its attack is instant.
+    pub release_ms: f32,
+    pub format: WavSampleFormat,
+    /// File names are `<prefix>-<UTC date>-<UTC time>.wav`.
+    pub file_prefix: String,
+}
+
+impl Default for TriggerSettings {
+    fn default() -> Self {
+        Self {
+            start_threshold_db: -30.0,
+            stop_threshold_db: -40.0,
+            debounce_ms: 50.0,
+            hold_ms: 2000.0,
+            pre_roll_ms: 1000.0,
+            release_ms: 100.0,
+            format: WavSampleFormat::Int24,
+            file_prefix: "event".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerState {
+    Armed,
+    /// Above the start threshold, waiting out the debounce time.
+    Triggered { frames: usize },
+    Recording,
+    /// Below the stop threshold, waiting out the hold time.
+    CoolingDown { frames: usize },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerEvent {
+    /// An event began; the current frame is its first frame after the
+    /// pre-roll.
+    Start,
+    /// The event ended with the current frame.
+    Stop,
+}
+
+/// The trigger logic on its own, fed one envelope value per frame.
+#[derive(Debug, Clone)]
+pub struct TriggerMachine {
+    start_threshold_db: f32,
+    stop_threshold_db: f32,
+    debounce_frames: usize,
+    hold_frames: usize,
+    state: TriggerState,
+}
+
+impl TriggerMachine {
+    pub fn new(settings: &TriggerSettings, sample_rate: u32) -> Self {
+        let frames = |ms: f32| (ms.max(0.0) * 0.001 * sample_rate as f32).round() as usize;
+        Self {
+            start_threshold_db: settings.start_threshold_db,
+            stop_threshold_db: settings.stop_threshold_db,
+            debounce_frames: frames(settings.debounce_ms),
+            hold_frames: frames(settings.hold_ms),
+            state: TriggerState::Armed,
+        }
+    }
+
+    pub fn state(&self) -> TriggerState {
+        self.state
+    }
+
+    /// True while frames belong to an event and should be written.
+    pub fn is_recording(&self) -> bool {
+        matches!(self.state, TriggerState::Recording | TriggerState::CoolingDown { .. })
+    }
+
+    /// Advances one frame with the envelope level in dBFS.
+    pub fn update(&mut self, envelope_db: f32) -> Option<TriggerEvent> {
+        let (state, event) = match self.state {
+            TriggerState::Armed | TriggerState::Triggered { .. } if envelope_db <= self.start_threshold_db => {
+                (TriggerState::Armed, None)
+            }
+            TriggerState::Armed => self.count_above(1),
+            TriggerState::Triggered { frames } => self.count_above(frames + 1),
+            TriggerState::Recording | TriggerState::CoolingDown { .. } if envelope_db >= self.stop_threshold_db => {
+                (TriggerState::Recording, None)
+            }
+            TriggerState::Recording => self.count_below(1),
+            TriggerState::CoolingDown { frames } => self.count_below(frames + 1),
+        };
+        self.state = state;
+        event
+    }
+
+    fn count_above(&self, frames: usize) -> (TriggerState, Option<TriggerEvent>) {
+        if frames > self.debounce_frames {
+            (TriggerState::Recording, Some(TriggerEvent::Start))
+        } else {
+            (TriggerState::Triggered { frames }, None)
+        }
+    }
+
+    fn count_below(&self, frames: usize) -> (TriggerState, Option<TriggerEvent>) {
+        if frames > self.hold_frames {
+            (TriggerState::Armed, Some(TriggerEvent::Stop))
+        } else {
+            (TriggerState::CoolingDown { frames }, None)
+        }
+    }
+}
+
+/// The last few frames of interleaved audio, oldest first.
+#[derive(Debug, Clone)]
+pub struct PreRollBuffer {
+    samples: VecDeque<f32>,
+    capacity: usize,
+}
+
+impl PreRollBuffer {
+    pub fn new(frames: usize, channels: u16) -> Self {
+        let capacity = frames * channels.max(1) as usize;
+        Self {
+            samples: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// Appends one frame, dropping the oldest once full.
+    pub fn push_frame(&mut self, frame: &[f32]) {
+        for &sample in frame {
+            if self.capacity == 0 {
+                return;
+            }
+            if self.samples.len() == self.capacity {
+                self.samples.pop_front();
+            }
+            self.samples.push_back(sample);
+        }
+    }
+
+    pub fn len_samples(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Hands the buffered audio to `write` in order and empties the buffer.
+    pub fn drain_into<E>(&mut self, mut write: impl FnMut(&[f32]) -> Result<(), E>) -> Result<(), E> {
+        let (first, second) = self.samples.as_slices();
+        write(first)?;
+        write(second)?;
+        self.samples.clear();
+        Ok(())
+    }
+}
+
+/// `YYYYMMDD-HHMMSS` in UTC.
+pub fn utc_timestamp(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, of_day) = ((seconds / 86400) as i64, seconds % 86400);
+
+    // Days since 1970-01-01 to a civil date (Howard Hinnant's algorithm).
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year,
+        month,
+        day,
+        of_day / 3600,
+        of_day / 60 % 60,
+        of_day % 60
+    )
+}
+
+/// First free `<prefix>-<timestamp>.wav` in `dir`, with `-2`, `-3`, ...
+/// appended when several events start within the same second.
+fn event_path(dir: &Path, prefix: &str, time: SystemTime) -> PathBuf {
+    let stem = format!("{}-{}", prefix, utc_timestamp(time));
+    let mut path = dir.join(format!("{}.wav", stem));
+    let mut n = 2;
+    while path.exists() {
+        path = dir.join(format!("{}-{}.wav", stem, n));
+        n += 1;
+    }
+    path
+}
+
+/// What a triggered recorder did.
+#[derive(Debug)]
+pub enum RecorderEvent {
+    Started(PathBuf),
+    Finished(PathBuf),
+    Failed(io::Error),
+    /// The stream failed for good (e.g. the device was unplugged). Any
+    /// event in progress was finished first; nothing more is recorded.
+    Interrupted(String),
+}
+
+/// Writes one WAV file per trigger event into a directory.
+pub struct TriggeredRecorder {
+    dir: PathBuf,
+    settings: TriggerSettings,
+    sample_rate: u32,
+    channels: u16,
+    machine: TriggerMachine,
+    pre_roll: PreRollBuffer,
+    envelope: f32,
+    release_coeff: f32,
+    current: Option<(PathBuf, WavStreamWriter)>,
+}
+
+impl TriggeredRecorder {
+    pub fn new(dir: &Path, settings: TriggerSettings, sample_rate: u32, channels: u16) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let release_samples = settings.release_ms.max(0.0) * 0.001 * sample_rate as f32;
+        let pre_roll_frames = (settings.pre_roll_ms.max(0.0) * 0.001 * sample_rate as f32) as usize;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            machine: TriggerMachine::new(&settings, sample_rate),
+            pre_roll: PreRollBuffer::new(pre_roll_frames, channels),
+            settings,
+            sample_rate,
+            channels: channels.max(1),
+            envelope: 0.0,
+            release_coeff: if release_samples > 0.0 { (-1.0 / release_samples).exp() } else { 0.0 },
+            current: None,
+        })
+    }
+
+    pub fn state(&self) -> TriggerState {
+        self.machine.state()
+    }
+
+    /// Feeds interleaved frames; `events` receives every file started and
+    /// finished along the way.
+    pub fn process(&mut self, samples: &[f32], events: &mut Vec<RecorderEvent>) {
+        for frame in samples.chunks_exact(self.channels as usize) {
+            let level = frame.iter().fold(0.0f32, |max, s| max.max(s.abs()));
+            self.envelope = if level > self.envelope { level } else { level + self.release_coeff * (self.envelope - level) };
+
+            match self.machine.update(linear_to_db(self.envelope)) {
+                Some(TriggerEvent::Start) => {
+                    self.pre_roll.push_frame(frame);
+                    match self.start_file() {
+                        Ok(path) => events.push(RecorderEvent::Started(path)),
+                        Err(e) => events.push(RecorderEvent::Failed(e)),
+                    }
+                }
+                Some(TriggerEvent::Stop) => {
+                    self.write(frame, events);
+                    self.finish(events);
+                }
+                None if self.machine.is_recording() => self.write(frame, events),
+                None => self.pre_roll.push_frame(frame),
+            }
+        }
+    }
+
+    /// Closes the file of an event still in progress.
+    pub fn finish(&mut self, events: &mut Vec<RecorderEvent>) {
+        if let Some((path, writer)) = self.current.take() {
+            match writer.finish() {
+                Ok(()) => events.push(RecorderEvent::Finished(path)),
+                Err(e) => events.push(RecorderEvent::Failed(e)),
+            }
+        }
+    }
+
+    fn start_file(&mut self) -> io::Result<PathBuf> {
+        let path = event_path(&self.dir, &self.settings.file_prefix, SystemTime::now());
+        let mut writer = WavStreamWriter::create(&path, self.sample_rate, self.channels, self.settings.format)?;
+        self.pre_roll.drain_into(|samples| writer.write_samples(samples))?;
+        self.current = Some((path.clone(), writer));
+        Ok(path)
+    }
+
+    fn write(&mut self, frame: &[f32], events: &mut Vec<RecorderEvent>) {
+        if let Some((_, writer)) = self.current.as_mut() {
+            if let Err(e) = writer.write_samples(frame) {
+                // Drop the broken file; the machine carries on and the next
+                // event gets a new one.
+                self.current = None;
+                events.push(RecorderEvent::Failed(e));
+            }
+        }
+    }
+}
+
+/// A running sound-activated recording; dropping it stops the stream and
+/// closes the file of an event in progress.
+pub struct TriggeredRecording {
+    _stream: StreamHandle,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    events: Receiver<RecorderEvent>,
+}
+
+impl TriggeredRecording {
+    pub fn try_event(&self) -> Option<RecorderEvent> {
+        self.events.try_recv().ok()
+    }
+
+    pub fn events(&self) -> &Receiver<RecorderEvent> {
+        &self.events
+    }
+}
+
+impl Drop for TriggeredRecording {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Records every event on `device` into `dir` until the returned handle is
+/// dropped. The callback only copies samples into a ring buffer; envelope,
+/// trigger and file writing run on a separate thread.
+pub fn record_triggered(device: &Device, dir: &Path, settings: TriggerSettings) -> Result<TriggeredRecording, StreamError> {
+    let builder = InputStreamBuilder::new(device);
+    let negotiated = builder.negotiate()?;
+    let (sample_rate, channels) = (negotiated.sample_rate, negotiated.channels);
+    let mut recorder = TriggeredRecorder::new(dir, settings, sample_rate, channels)
+        .map_err(|e| StreamError::Unsupported(format!("Cannot record into {}: {}", dir.display(), e)))?;
+
+    let (mut input_ring, mut ring) = ring_buffer::<f32>((sample_rate * INPUT_RING_MS / 1000) as usize * channels as usize);
+    let (error_sender, errors) = mpsc::channel();
+    let (stream, _) = builder
+        .on_data(move |data| {
+            input_ring.push_slice(data);
+        })
+        .on_error(move |err| {
+            let _ = error_sender.send(err);
+        })
+        .build()?;
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let (sender, events) = mpsc::channel();
+    let thread_stop = Arc::clone(&stop);
+    let thread = thread::spawn(move || write_events(&mut recorder, &mut ring, channels, &thread_stop, &errors, &sender));
+
+    Ok(TriggeredRecording {
+        _stream: stream,
+        stop,
+        thread: Some(thread),
+        events,
+    })
+}
+
+fn write_events(
+    recorder: &mut TriggeredRecorder,
+    ring: &mut Consumer<f32>,
+    channels: u16,
+    stop: &AtomicBool,
+    errors: &Receiver<cpal::StreamError>,
+    sender: &Sender<RecorderEvent>,
+) {
+    let channels = channels.max(1) as usize;
+    let mut chunk = vec![0.0f32; 4096 * channels];
+    let mut events = Vec::new();
+    loop {
+        let mut stopping = stop.load(Ordering::Acquire);
+        let mut lost = None;
+        while let Ok(error) = errors.try_recv() {
+            match error_action(&error) {
+                ErrorAction::Continue => eprintln!("Warning: {}", error),
+                ErrorAction::Stop => lost = Some(error.to_string()),
+            }
+        }
+        stopping |= lost.is_some();
+        loop {
+            // Only whole frames, so channels never get out of step.
+            let frames = ring.available().min(chunk.len()) / channels;
+            if frames == 0 {
+                break;
+            }
+            let len = ring.pop_slice(&mut chunk[..frames * channels]);
+            recorder.process(&chunk[..len], &mut events);
+        }
+        if stopping {
+            recorder.finish(&mut events);
+        }
+        events.extend(lost.map(RecorderEvent::Interrupted));
+        for event in events.drain(..) {
+            let _ = sender.send(event);
+        }
+        if stopping {
+            return;
+        }
+        thread::sleep(WRITER_POLL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_wav::read_wave_file;
+
+    // A directory of its own in the temp directory, removed when dropped.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!("cpal_playbook_{}_{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&path);
+            Self(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    // At 1 kHz a millisecond is a frame: 3 frames of debounce, 5 of hold
+    // and 10 of pre-roll, with an envelope that follows the level at once.
+    const RATE: u32 = 1000;
+
+    fn settings() -> TriggerSettings {
+        TriggerSettings {
+            debounce_ms: 3.0,
+            hold_ms: 5.0,
+            pre_roll_ms: 10.0,
+            release_ms: 0.0,
+            format: WavSampleFormat::Float32,
+            ..TriggerSettings::default()
+        }
+    }
+
+    #[test]
+    fn trigger_machine_walks_through_an_event() {
+        use TriggerState::*;
+        let mut machine = TriggerMachine::new(&settings(), RATE);
+        // Level, then the state and event it leads to.
+        let steps = [
+            (-60.0, Armed, None),
+            (-30.0, Armed, None),
+            // Above the start threshold, but one dip restarts the debounce.
+            (-20.0, Triggered { frames: 1 }, None),
+            (-20.0, Triggered { frames: 2 }, None),
+            (-35.0, Armed, None),
+            (-20.0, Triggered { frames: 1 }, None),
+            (-20.0, Triggered { frames: 2 }, None),
+            (-20.0, Triggered { frames: 3 }, None),
+            (-20.0, Recording, Some(TriggerEvent::Start)),
+            // Between the thresholds an event keeps going.
+            (-35.0, Recording, None),
+            (-40.0, Recording, None),
+            // Below the stop threshold, but coming back up restarts the hold.
+            (-45.0, CoolingDown { frames: 1 }, None),
+            (-45.0, CoolingDown { frames: 2 }, None),
+            (-35.0, Recording, None),
+            (-45.0, CoolingDown { frames: 1 }, None),
+            (-45.0, CoolingDown { frames: 2 }, None),
+            (-45.0, CoolingDown { frames: 3 }, None),
+            (-45.0, CoolingDown { frames: 4 }, None),
+            (-45.0, CoolingDown { frames: 5 }, None),
+            (-45.0, Armed, Some(TriggerEvent::Stop)),
+            // Re-armed: quiet stays armed, loud starts over.
+            (-45.0, Armed, None),
+            (-20.0, Triggered { frames: 1 }, None),
+        ];
+        for (frame, &(level, state, event)) in steps.iter().enumerate() {
+            assert_eq!(machine.update(level), event, "event at frame {frame}");
+            assert_eq!(machine.state(), state, "state at frame {frame}");
+            assert_eq!(machine.is_recording(), matches!(state, Recording | CoolingDown { .. }));
+        }
+    }
+
+    #[test]
+    fn triggered_recorder_writes_the_pre_roll_and_the_event() {
+        let dir = TempDir::new("triggered");
+        let mut recorder = TriggeredRecorder::new(&dir.0, settings(), RATE, 1).unwrap();
+        // Quiet (-60 dBFS and below) frames, 10 loud ones at -6 dBFS, quiet
+        // again; every sample is different so the file shows which were kept.
+        let quiet = |i: usize| 0.001 - i as f32 * 0.00001;
+        let samples: Vec<f32> = (0..60).map(|i| if (20..30).contains(&i) { 0.5 + i as f32 * 0.001 } else { quiet(i) }).collect();
+
+        let mut events = Vec::new();
+        for (frame, &sample) in samples.iter().enumerate().take(23) {
+            recorder.process(&[sample], &mut events);
+            let expected = if frame < 20 { TriggerState::Armed } else { TriggerState::Triggered { frames: frame - 19 } };
+            assert_eq!(recorder.state(), expected);
+            assert_eq!(recorder.pre_roll.len_samples(), (frame + 1).min(10));
+        }
+        assert!(events.is_empty());
+
+        // The fourth loud frame starts the event with the ten frames up to it.
+        recorder.process(&samples[23..24], &mut events);
+        assert_eq!(recorder.state(), TriggerState::Recording);
+        assert_eq!(recorder.pre_roll.len_samples(), 0);
+        let path = match events.as_slice() {
+            [RecorderEvent::Started(path)] => path.clone(),
+            other => panic!("expected a start, got {other:?}"),
+        };
+
+        // Frame 30 is the first quiet one; the sixth quiet frame stops it.
+        recorder.process(&samples[24..35], &mut events);
+        assert_eq!(recorder.state(), TriggerState::CoolingDown { frames: 5 });
+        recorder.process(&samples[35..36], &mut events);
+        assert_eq!(recorder.state(), TriggerState::Armed);
+        assert!(matches!(&events[1..], [RecorderEvent::Finished(finished)] if *finished == path));
+
+        // Re-armed, the pre-roll fills up again.
+        recorder.process(&samples[36..], &mut events);
+        assert_eq!(events.len(), 2);
+        assert_eq!(recorder.pre_roll.len_samples(), 10);
+
+        let (written, spec) = read_wave_file(path.to_str().unwrap()).unwrap();
+        assert_eq!((spec.sample_rate, spec.channels), (RATE, 1));
+        assert_eq!(written, samples[14..36]);
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 1);
+    }
+}
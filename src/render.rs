@@ -0,0 +1,414 @@
+// Offline rendering of effect chains over whole buffers.
+use std::thread;
+
+use crate::effect::{apply_scrub, debug_assert_finite, Effect, ScrubMode};
+use crate::pipeline::Pipeline;
+use crate::read_wav::read_wav_data;
+use crate::write_wav::{WavSampleFormat, WavStreamWriter};
+
+pub type Chain = Vec<Box<dyn Effect>>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RenderMode {
+    Serial,
+    Parallel(ParallelRender),
+}
+
+/// Parallel scheduling for the offline render.
+///
+/// Channels are rendered on separate threads. Within a channel, effects that
+/// report `is_stateless()` are split into chunks processed by separate
+/// instances of the chain, while stateful effects still see the whole
+/// channel in order. The output is bit-identical to `RenderMode::Serial`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParallelRender {
+    /// Total number of worker threads.
+    pub threads: usize,
+    /// Smallest chunk worth handing to a thread for a stateless effect.
+    pub min_chunk: usize,
+}
+
+impl Default for ParallelRender {
+    fn default() -> Self {
+        Self {
+            threads: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            min_chunk: 16384,
+        }
+    }
+}
+
+/// Summary of a render.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RenderReport {
+    /// NaN/inf samples found at the end of the chain (always 0 with `ScrubMode::Off`).
+    pub non_finite_samples: u64,
+    /// Channels that contained any of them.
+    pub affected_channels: usize,
+}
+
+/// Runs every channel through its own chain made by `make_chain`, in place,
+/// then applies `scrub` once to each rendered channel.
+pub fn render<F>(channels: &mut [Vec<f32>], make_chain: F, mode: RenderMode, scrub: &ScrubMode) -> RenderReport
+where
+    F: Fn() -> Chain + Sync,
+{
+    for channel in channels.iter() {
+        debug_assert_finite("render input", channel);
+    }
+
+    let found: Vec<usize> = match mode {
+        RenderMode::Serial => channels
+            .iter_mut()
+            .map(|channel| {
+                for effect in make_chain().iter_mut() {
+                    effect.process_block(channel);
+                }
+                apply_scrub(scrub, channel)
+            })
+            .collect(),
+        RenderMode::Parallel(options) => {
+            let threads_per_channel = (options.threads / channels.len().max(1)).max(1);
+            let make_chain = &make_chain;
+            thread::scope(|scope| {
+                let workers: Vec<_> = channels
+                    .iter_mut()
+                    .map(|channel| {
+                        scope.spawn(move || {
+                            render_channel_parallel(channel, make_chain, threads_per_channel, options.min_chunk);
+                            apply_scrub(scrub, channel)
+                        })
+                    })
+                    .collect();
+                workers.into_iter().map(|w| w.join().expect("render worker panicked")).collect()
+            })
+        }
+    };
+
+    RenderReport {
+        non_finite_samples: found.iter().map(|&n| n as u64).sum(),
+        affected_channels: found.iter().filter(|&&n| n > 0).count(),
+    }
+}
+
+fn render_channel_parallel<F>(channel: &mut [f32], make_chain: &F, threads: usize, min_chunk: usize)
+where
+    F: Fn() -> Chain + Sync,
+{
+    let mut chain = make_chain();
+
+    let workers = if chain.iter().any(|e| e.is_stateless()) {
+        threads.min(channel.len() / min_chunk.max(1)).max(1)
+    } else {
+        1
+    };
+    // Extra chain instances for the chunk workers; only their stateless
+    // effects are ever used.
+    let mut worker_chains: Vec<Chain> = (1..workers).map(|_| make_chain()).collect();
+    let chunk_len = channel.len().div_ceil(workers).max(1);
+
+    for (i, effect) in chain.iter_mut().enumerate() {
+        if workers == 1 || !effect.is_stateless() {
+            effect.process_block(channel);
+            continue;
+        }
+
+        let mut chunks = channel.chunks_mut(chunk_len);
+        let first = chunks.next().unwrap_or_default();
+        thread::scope(|scope| {
+            for (chunk, worker) in chunks.zip(worker_chains.iter_mut()) {
+                let worker_effect = &mut worker[i];
+                scope.spawn(move || worker_effect.process_block(chunk));
+            }
+            effect.process_block(first);
+        });
+    }
+}
+
+/// Block size `bounce_chain` renders at unless told otherwise; it should
+/// match the buffer size of the stream the chain runs in live.
+pub const DEFAULT_BOUNCE_BLOCK_SIZE: usize = 512;
+
+/// Summary of a bounce.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct BounceReport {
+    pub frames: usize,
+    /// Latency reported by the chain, trimmed from the front of the output.
+    pub latency_frames: usize,
+}
+
+/// Renders `input_path` through `pipeline` into a 32-bit float WAV at
+/// `output_path`, the same way the live passthrough would process it.
+pub fn bounce_chain(
+    input_path: &str,
+    pipeline: &Pipeline,
+    output_path: &str,
+) -> Result<BounceReport, Box<dyn std::error::Error>> {
+    bounce_chain_with_block_size(input_path, pipeline, output_path, DEFAULT_BOUNCE_BLOCK_SIZE)
+}
+
+/// `bounce_chain` at an explicit block size.
+///
+/// Each channel runs through its own instance of the chain, fed
+/// `block_size` frames at a time like a stream callback would. The chain's
+/// reported latency is compensated: the input is followed by that much
+/// silence to flush the tail out, and the same amount is cut from the
+/// front, so the output lines up with the input and has the same length.
+pub fn bounce_chain_with_block_size(
+    input_path: &str,
+    pipeline: &Pipeline,
+    output_path: &str,
+    block_size: usize,
+) -> Result<BounceReport, Box<dyn std::error::Error>> {
+    let data = read_wav_data(input_path)?;
+    let frames = data.frames();
+    let (rendered, latency_frames) = render_pipeline(&data.channels, data.sample_rate, pipeline, block_size);
+
+    let mut writer = WavStreamWriter::create(output_path, data.sample_rate, data.channel_count(), WavSampleFormat::Float32)?;
+    let interleaved: Vec<f32> = (0..frames).flat_map(|i| rendered.iter().map(move |c| c[i])).collect();
+    writer.write_samples(&interleaved)?;
+    writer.finish()?;
+
+    Ok(BounceReport { frames, latency_frames })
+}
+
+/// Runs every channel through its own instance of `pipeline`, `block_size`
+/// frames at a time, and compensates the chain's latency. Returns the
+/// rendered channels, as long as the input, and that latency.
+pub fn render_pipeline(
+    channels: &[Vec<f32>],
+    sample_rate: u32,
+    pipeline: &Pipeline,
+    block_size: usize,
+) -> (Vec<Vec<f32>>, usize) {
+    let frames = channels.iter().map(|c| c.len()).min().unwrap_or(0);
+    let mut latency_frames = 0;
+
+    let mut rendered = Vec::with_capacity(channels.len());
+    for channel in channels {
+        let mut chain = pipeline.build(sample_rate as f32);
+        latency_frames = chain.latency_frames();
+
+        let mut output = channel[..frames].to_vec();
+        output.resize(frames + latency_frames, 0.0);
+        for block in output.chunks_mut(block_size.max(1)) {
+            chain.process_block(block);
+        }
+        output.drain(..latency_frames);
+        rendered.push(output);
+    }
+    (rendered, latency_frames)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::effect::Gain;
+    use crate::filters::BiquadFilter;
+    use crate::fx::{Compressor, Delay, Distortion};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // Noise from a fixed seed, with a tone so the stateful effects have
+    // something to hold on to.
+    fn test_signal(frames: usize, seed: u32) -> Vec<f32> {
+        let mut state = seed;
+        (0..frames)
+            .map(|i| {
+                state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+                let noise = (state >> 8) as f32 / (1u32 << 24) as f32 - 0.5;
+                0.4 * (i as f32 * 0.013).sin() + 0.3 * noise
+            })
+            .collect()
+    }
+
+    // Stateless and stateful effects in turn, so the parallel path has to
+    // hand the whole channel back and forth between them.
+    fn mixed_chain() -> Chain {
+        vec![
+            Box::new(Distortion::new(4.0, 0.5)),
+            Box::new(BiquadFilter::new_lowpass(48000.0, 3000.0, 0.707)),
+            Box::new(Distortion::new(2.0, 0.7)),
+            Box::new(Compressor::new(-18.0, 4.0, 5.0, 50.0, 48000.0)),
+            Box::new(Delay::new(48000.0, 30.0, 0.4)),
+            Box::new(Distortion::new(1.5, 0.9)),
+            Box::new(Gain::new(-3.0)),
+        ]
+    }
+
+    fn render_with(mode: RenderMode, frames: usize) -> Vec<Vec<f32>> {
+        let mut channels = vec![test_signal(frames, 1), test_signal(frames, 2)];
+        render(&mut channels, mixed_chain, mode, &ScrubMode::Off);
+        channels
+    }
+
+    fn bits(channels: &[Vec<f32>]) -> Vec<Vec<u32>> {
+        channels.iter().map(|c| c.iter().map(|s| s.to_bits()).collect()).collect()
+    }
+
+    #[test]
+    fn parallel_render_is_bit_identical_to_serial() {
+        let frames = 100_003;
+        let serial = render_with(RenderMode::Serial, frames);
+        for (threads, min_chunk) in [(1, 16384), (2, 1000), (4, 1), (7, 333), (16, 16384)] {
+            let parallel = render_with(RenderMode::Parallel(ParallelRender { threads, min_chunk }), frames);
+            assert_eq!(bits(&parallel), bits(&serial), "{} threads, chunks of {}", threads, min_chunk);
+        }
+    }
+
+    // A path in the temp directory, removed when dropped.
+    struct TempPath(std::path::PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("cpal_playbook_{}_{}", std::process::id(), name)))
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn limiter_pipeline() -> Pipeline {
+        Pipeline::from_json(
+            r#"{ "stages": [
+                { "type": "compressor", "threshold": 0.3, "ratio": 4, "attack_ms": 5, "release_ms": 80 },
+                { "type": "limiter", "ceiling_db": -6, "lookahead_ms": 5 }
+            ] }"#,
+        )
+        .unwrap()
+    }
+
+    // What the live passthrough hears: blocks of `block_size` through one
+    // chain per channel, the stream running on past the end of the input,
+    // and the first `latency_frames()` of output thrown away.
+    fn live_driver(channels: &[Vec<f32>], sample_rate: u32, pipeline: &Pipeline, block_size: usize) -> Vec<Vec<f32>> {
+        channels
+            .iter()
+            .map(|channel| {
+                let mut chain = pipeline.build(sample_rate as f32);
+                let latency = chain.latency_frames();
+                let mut heard = Vec::new();
+                let mut position = 0;
+                while heard.len() < latency + channel.len() {
+                    let mut block: Vec<f32> =
+                        (position..position + block_size).map(|i| channel.get(i).copied().unwrap_or(0.0)).collect();
+                    chain.process_block(&mut block);
+                    heard.extend_from_slice(&block);
+                    position += block_size;
+                }
+                heard.drain(..latency);
+                heard.truncate(channel.len());
+                heard
+            })
+            .collect()
+    }
+
+    #[test]
+    fn bounce_matches_the_live_chain_after_latency_compensation() {
+        let sample_rate = 48_000;
+        let frames = 10_007;
+        let channels = [test_signal(frames, 3), test_signal(frames, 4)];
+        let interleaved: Vec<f32> = (0..frames).flat_map(|i| channels.iter().map(move |c| 1.5 * c[i])).collect();
+        let input = TempPath::new("bounce_in.wav");
+        let output = TempPath::new("bounce_out.wav");
+        crate::write_wav::write_wave_file(input.path(), &interleaved, sample_rate, 2, WavSampleFormat::Float32)
+            .unwrap();
+
+        let pipeline = limiter_pipeline();
+        for block_size in [64, DEFAULT_BOUNCE_BLOCK_SIZE, 1000] {
+            let report = bounce_chain_with_block_size(input.path(), &pipeline, output.path(), block_size).unwrap();
+            assert_eq!(report.frames, frames);
+            assert_eq!(report.latency_frames, 240);
+
+            let bounced = read_wav_data(output.path()).unwrap();
+            let source = read_wav_data(input.path()).unwrap();
+            let live = live_driver(&source.channels, sample_rate, &pipeline, block_size);
+            assert_eq!(bits(&bounced.channels), bits(&live), "block size {}", block_size);
+        }
+    }
+
+    #[test]
+    fn bounce_output_lines_up_with_the_input() {
+        // Under the ceiling the limiter only delays, so once that delay is
+        // compensated an impulse comes out where it went in.
+        let pipeline = Pipeline::from_json(r#"{ "stages": [{ "type": "limiter", "ceiling_db": -1 }] }"#).unwrap();
+        let mut impulse = vec![0.0; 2_000];
+        impulse[700] = 0.5;
+        let (rendered, latency) = render_pipeline(&[impulse.clone()], 48_000, &pipeline, 128);
+        assert!(latency > 0);
+        assert_eq!(rendered, [impulse]);
+    }
+
+    #[test]
+    fn short_and_empty_channels_render() {
+        let options = RenderMode::Parallel(ParallelRender { threads: 8, min_chunk: 1 });
+        for frames in [0, 1, 5] {
+            assert_eq!(bits(&render_with(options, frames)), bits(&render_with(RenderMode::Serial, frames)));
+        }
+        let mut none: Vec<Vec<f32>> = Vec::new();
+        assert_eq!(render(&mut none, mixed_chain, options, &ScrubMode::Off), RenderReport::default());
+    }
+
+    // Makes NaN of every 0.5 it is given.
+    struct Poison;
+
+    impl Effect for Poison {
+        fn process_block(&mut self, block: &mut [f32]) {
+            for sample in block.iter_mut().filter(|s| **s == 0.5) {
+                *sample = f32::NAN;
+            }
+        }
+
+        fn is_stateless(&self) -> bool {
+            true
+        }
+    }
+
+    fn poisoned_chain() -> Chain {
+        vec![Box::new(Gain::new(0.0)), Box::new(Poison)]
+    }
+
+    // Three 0.5s on the left only.
+    fn marked_channels() -> Vec<Vec<f32>> {
+        let mut left = vec![0.1; 40_000];
+        for at in [0, 17_000, 39_999] {
+            left[at] = 0.5;
+        }
+        vec![left, vec![0.1; 40_000]]
+    }
+
+    #[test]
+    fn the_report_counts_what_the_scrub_mode_finds() {
+        let parallel = RenderMode::Parallel(ParallelRender { threads: 4, min_chunk: 1000 });
+        for mode in [RenderMode::Serial, parallel] {
+            let found = Arc::new(AtomicUsize::new(0));
+            let callback = {
+                let found = Arc::clone(&found);
+                Arc::new(move |count: usize| {
+                    found.fetch_add(count, Ordering::Relaxed);
+                })
+            };
+            let mut detected = marked_channels();
+            let report = render(&mut detected, poisoned_chain, mode, &ScrubMode::Detect { callback });
+            assert_eq!(report, RenderReport { non_finite_samples: 3, affected_channels: 1 });
+            assert_eq!(found.load(Ordering::Relaxed), 3);
+            assert_eq!(detected[0].iter().filter(|s| s.is_nan()).count(), 3);
+
+            let mut scrubbed = marked_channels();
+            let report = render(&mut scrubbed, poisoned_chain, mode, &ScrubMode::Scrub { replace_with: 0.0 });
+            assert_eq!(report, RenderReport { non_finite_samples: 3, affected_channels: 1 });
+            assert!(scrubbed[0][17_000] == 0.0 && scrubbed.iter().flatten().all(|s| s.is_finite()));
+
+            // Off reports nothing and leaves the NaN where the chain put it.
+            let mut unchecked = marked_channels();
+            assert_eq!(render(&mut unchecked, poisoned_chain, mode, &ScrubMode::Off), RenderReport::default());
+            assert_eq!(bits(&unchecked), bits(&detected));
+        }
+    }
+}
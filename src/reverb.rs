@@ -0,0 +1,389 @@
+// Freeverb-style algorithmic reverb with production controls.
+//
+//   input ─ pre-delay ─┬─ early taps ────────────────── × early level ─┐
+//                      └─ 8 parallel combs ─ 4 allpasses ─ × late level ─┴─ out
+//
+// Each comb has a low and a high shelf in its feedback loop instead of
+// Freeverb's single one-pole damper, so the tail can lose lows and highs
+// independently, and does so faster the more often it goes round the loop.
+// The output is the reverb alone; wrap it in `Mix` to blend in the dry
+// signal.
+//
+// Every setter moves its parameter along a `SmoothedGain` ramp, so all
+// controls can be changed while audio runs through the reverb.
+use crate::effect::Effect;
+use crate::filters::BiquadFilter;
+use crate::limiter::db_to_linear;
+use crate::stream::SmoothedGain;
+
+/// Comb and allpass lengths from Freeverb, in samples at 44.1 kHz.
+const COMB_TUNING: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const ALLPASS_TUNING: [usize; 4] = [556, 441, 341, 225];
+const TUNING_RATE: f32 = 44_100.0;
+/// Input scaling of the comb bank, also from Freeverb.
+const COMB_INPUT_GAIN: f32 = 0.015;
+const ALLPASS_FEEDBACK: f32 = 0.5;
+
+pub const MAX_PRE_DELAY_MS: f32 = 500.0;
+/// Early taps further out than this after the pre-delay are dropped.
+pub const MAX_EARLY_TAP_MS: f32 = 100.0;
+/// Cut per trip round a comb at a damping of 1.
+pub const MAX_DAMP_DB: f32 = 12.0;
+pub const HIGH_DAMP_HZ: f32 = 3000.0;
+pub const LOW_DAMP_HZ: f32 = 250.0;
+/// While the damping ramps, the loop filters are recomputed
+/// every this many samples.
+const CONTROL_INTERVAL: usize = 32;
+
+/// One discrete early reflection, relative to the end of the pre-delay.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct EarlyTap {
+    pub delay_ms: f32,
+    pub gain: f32,
+}
+
+/// A small room: the first reflection right after the pre-delay, the
+/// rest thinning out over 45 ms.
+pub const DEFAULT_EARLY_TAPS: [EarlyTap; 8] = [
+    EarlyTap { delay_ms: 0.0, gain: 0.85 },
+    EarlyTap { delay_ms: 4.3, gain: 0.7 },
+    EarlyTap { delay_ms: 9.1, gain: 0.6 },
+    EarlyTap { delay_ms: 13.7, gain: 0.5 },
+    EarlyTap { delay_ms: 19.3, gain: 0.42 },
+    EarlyTap { delay_ms: 26.9, gain: 0.33 },
+    EarlyTap { delay_ms: 35.2, gain: 0.27 },
+    EarlyTap { delay_ms: 44.8, gain: 0.2 },
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReverbSettings {
+    /// 0 to 1, sets the comb feedback and so the length of the tail.
+    pub room_size: f32,
+    pub pre_delay_ms: f32,
+    /// 0 to 1, how much highs the tail loses per trip round a comb.
+    pub high_damp: f32,
+    /// 0 to 1, the same for lows.
+    pub low_damp: f32,
+    pub early_level_db: f32,
+    pub late_level_db: f32,
+    pub early_taps: Vec<EarlyTap>,
+}
+
+impl Default for ReverbSettings {
+    fn default() -> Self {
+        Self {
+            room_size: 0.5,
+            pre_delay_ms: 0.0,
+            high_damp: 0.5,
+            low_damp: 0.0,
+            early_level_db: -6.0,
+            late_level_db: 0.0,
+            early_taps: DEFAULT_EARLY_TAPS.to_vec(),
+        }
+    }
+}
+
+// Lowpass-feedback comb with shelves in place of the one-pole damper.
+struct Comb {
+    buffer: Vec<f32>,
+    index: usize,
+    low_shelf: BiquadFilter,
+    high_shelf: BiquadFilter,
+}
+
+impl Comb {
+    fn process(&mut self, input: f32, feedback: f32) -> f32 {
+        let output = self.buffer[self.index];
+        let damped = self.high_shelf.process_sample(self.low_shelf.process_sample(output));
+        self.buffer[self.index] = input + damped * feedback;
+        self.index = (self.index + 1) % self.buffer.len();
+        output
+    }
+}
+
+struct Allpass {
+    buffer: Vec<f32>,
+    index: usize,
+}
+
+impl Allpass {
+    fn process(&mut self, input: f32) -> f32 {
+        let delayed = self.buffer[self.index];
+        self.buffer[self.index] = input + delayed * ALLPASS_FEEDBACK;
+        self.index = (self.index + 1) % self.buffer.len();
+        delayed - input
+    }
+}
+
+pub struct Reverb {
+    sample_rate: f32,
+    combs: Vec<Comb>,
+    allpasses: Vec<Allpass>,
+    // Pre-delay and early taps read from one delay line, used as a ring.
+    delay_line: Vec<f32>,
+    write_pos: usize,
+    // (offset after the pre-delay in samples, gain)
+    taps: Vec<(usize, f32)>,
+    // Pre-delay in samples.
+    pre_delay: SmoothedGain,
+    room_size: SmoothedGain,
+    high_damp: SmoothedGain,
+    low_damp: SmoothedGain,
+    early_level: SmoothedGain,
+    late_level: SmoothedGain,
+    until_update: usize,
+    damping_changed: bool,
+}
+
+impl Reverb {
+    pub fn new(sample_rate: f32, settings: &ReverbSettings) -> Self {
+        let scale = sample_rate / TUNING_RATE;
+        let length = |tuning: usize| ((tuning as f32 * scale) as usize).max(1);
+        let ms_to_samples = |ms: f32| ms * 0.001 * sample_rate;
+        let max_tap = ms_to_samples(MAX_EARLY_TAP_MS) as usize;
+        let taps = settings
+            .early_taps
+            .iter()
+            .map(|tap| (ms_to_samples(tap.delay_ms.max(0.0)).round() as usize, tap.gain))
+            .filter(|&(offset, _)| offset <= max_tap)
+            .collect();
+        let smoothed = |value: f32| SmoothedGain::with_rate(value, sample_rate as u32);
+
+        let mut reverb = Self {
+            sample_rate,
+            combs: COMB_TUNING
+                .iter()
+                .map(|&tuning| Comb {
+                    buffer: vec![0.0; length(tuning)],
+                    index: 0,
+                    low_shelf: BiquadFilter::from_coefficients(1.0, 0.0, 0.0, 0.0, 0.0),
+                    high_shelf: BiquadFilter::from_coefficients(1.0, 0.0, 0.0, 0.0, 0.0),
+                })
+                .collect(),
+            allpasses: ALLPASS_TUNING.iter().map(|&tuning| Allpass { buffer: vec![0.0; length(tuning)], index: 0 }).collect(),
+            // Room for the longest pre-delay, the longest tap and one more
+            // sample to interpolate with.
+            delay_line: vec![0.0; ms_to_samples(MAX_PRE_DELAY_MS) as usize + max_tap + 2],
+            write_pos: 0,
+            taps,
+            pre_delay: smoothed(ms_to_samples(settings.pre_delay_ms.clamp(0.0, MAX_PRE_DELAY_MS))),
+            room_size: smoothed(settings.room_size.clamp(0.0, 1.0)),
+            high_damp: smoothed(settings.high_damp.clamp(0.0, 1.0)),
+            low_damp: smoothed(settings.low_damp.clamp(0.0, 1.0)),
+            early_level: smoothed(db_to_linear(settings.early_level_db)),
+            late_level: smoothed(db_to_linear(settings.late_level_db)),
+            until_update: 0,
+            damping_changed: false,
+        };
+        reverb.update_filters(settings.high_damp.clamp(0.0, 1.0), settings.low_damp.clamp(0.0, 1.0));
+        reverb
+    }
+
+    pub fn set_room_size(&mut self, room_size: f32) {
+        self.room_size.set_target(room_size.clamp(0.0, 1.0));
+    }
+
+    pub fn set_pre_delay_ms(&mut self, ms: f32) {
+        self.pre_delay.set_target(ms.clamp(0.0, MAX_PRE_DELAY_MS) * 0.001 * self.sample_rate);
+    }
+
+    pub fn set_high_damp(&mut self, damp: f32) {
+        self.high_damp.set_target(damp.clamp(0.0, 1.0));
+        self.damping_changed = true;
+    }
+
+    pub fn set_low_damp(&mut self, damp: f32) {
+        self.low_damp.set_target(damp.clamp(0.0, 1.0));
+        self.damping_changed = true;
+    }
+
+    pub fn set_early_level_db(&mut self, db: f32) {
+        self.early_level.set_target(db_to_linear(db));
+    }
+
+    pub fn set_late_level_db(&mut self, db: f32) {
+        self.late_level.set_target(db_to_linear(db));
+    }
+
+    // Recomputes the shelves in the loops from the current damping,
+    // keeping their state.
+    fn update_filters(&mut self, high_damp: f32, low_damp: f32) {
+        let high = BiquadFilter::new_highshelf(self.sample_rate, HIGH_DAMP_HZ, -high_damp * MAX_DAMP_DB, 1.0);
+        let low = BiquadFilter::new_lowshelf(self.sample_rate, LOW_DAMP_HZ, -low_damp * MAX_DAMP_DB, 1.0);
+        for comb in self.combs.iter_mut() {
+            comb.high_shelf.set_coefficients_from(&high);
+            comb.low_shelf.set_coefficients_from(&low);
+        }
+    }
+
+    // The delay line `delay` samples back from the newest sample, with
+    // linear interpolation between samples.
+    fn read_delayed(&self, delay: f32) -> f32 {
+        let len = self.delay_line.len();
+        let whole = delay as usize;
+        let frac = delay - whole as f32;
+        let at = |back: usize| self.delay_line[(self.write_pos + len - back.min(len - 1)) % len];
+        at(whole) * (1.0 - frac) + at(whole + 1) * frac
+    }
+
+    pub fn process_sample(&mut self, input: f32) -> f32 {
+        let high_damp = self.high_damp.next_gain();
+        let low_damp = self.low_damp.next_gain();
+        if self.until_update == 0 {
+            if self.damping_changed {
+                self.update_filters(high_damp, low_damp);
+                // Once more after the ramps end, to land on the targets.
+                self.damping_changed = self.high_damp.is_ramping() || self.low_damp.is_ramping();
+            }
+            self.until_update = CONTROL_INTERVAL;
+        }
+        self.until_update -= 1;
+
+        self.delay_line[self.write_pos] = input;
+        let pre_delay = self.pre_delay.next_gain();
+        let delayed = self.read_delayed(pre_delay);
+        let early: f32 = self
+            .taps
+            .iter()
+            .map(|&(offset, gain)| self.read_delayed(pre_delay + offset as f32) * gain)
+            .sum();
+        self.write_pos = (self.write_pos + 1) % self.delay_line.len();
+
+        // Freeverb's mapping: 0.7 for the smallest room up to 0.98.
+        let feedback = self.room_size.next_gain() * 0.28 + 0.7;
+        let comb_input = delayed * COMB_INPUT_GAIN;
+        let mut late: f32 = self.combs.iter_mut().map(|comb| comb.process(comb_input, feedback)).sum();
+        for allpass in self.allpasses.iter_mut() {
+            late = allpass.process(late);
+        }
+
+        early * self.early_level.next_gain() + late * self.late_level.next_gain()
+    }
+
+    pub fn process_block(&mut self, samples: &mut [f32]) {
+        for sample in samples.iter_mut() {
+            *sample = self.process_sample(*sample);
+        }
+    }
+}
+
+impl Effect for Reverb {
+    fn process_block(&mut self, block: &mut [f32]) {
+        Reverb::process_block(self, block);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = 48_000.0;
+
+    fn impulse_response(reverb: &mut Reverb, seconds: f32) -> Vec<f32> {
+        let mut ir = vec![0.0; (seconds * RATE) as usize];
+        ir[0] = 1.0;
+        reverb.process_block(&mut ir);
+        ir
+    }
+
+    fn late_only(high_damp: f32, low_damp: f32) -> ReverbSettings {
+        ReverbSettings { room_size: 0.8, high_damp, low_damp, early_taps: Vec::new(), ..ReverbSettings::default() }
+    }
+
+    fn onset(ir: &[f32]) -> usize {
+        ir.iter().position(|s| s.abs() > 1e-9).unwrap()
+    }
+
+    fn energy(samples: &[f32]) -> f64 {
+        samples.iter().map(|&s| s as f64 * s as f64).sum()
+    }
+
+    fn ms(ms: f32) -> usize {
+        (ms * 0.001 * RATE) as usize
+    }
+
+    // Energy lost in dB from 50-150 ms to 450-550 ms, below 200 Hz and
+    // above 6 kHz.
+    fn band_decays(ir: &[f32]) -> (f64, f64) {
+        let band = |mut first: BiquadFilter, mut second: BiquadFilter| {
+            let filtered: Vec<f32> = ir.iter().map(|&s| second.process_sample(first.process_sample(s))).collect();
+            10.0 * (energy(&filtered[ms(50.0)..ms(150.0)]) / energy(&filtered[ms(450.0)..ms(550.0)])).log10()
+        };
+        (
+            band(BiquadFilter::new_lowpass(RATE, 200.0, 0.707), BiquadFilter::new_lowpass(RATE, 200.0, 0.707)),
+            band(BiquadFilter::new_highpass(RATE, 6000.0, 0.707), BiquadFilter::new_highpass(RATE, 6000.0, 0.707)),
+        )
+    }
+
+    #[test]
+    fn the_first_reflection_arrives_after_the_pre_delay() {
+        for pre_delay_ms in [0.0, 20.0, 125.0] {
+            let mut reverb = Reverb::new(RATE, &ReverbSettings { pre_delay_ms, ..ReverbSettings::default() });
+            let ir = impulse_response(&mut reverb, 0.2);
+            assert_eq!(onset(&ir), ms(pre_delay_ms), "pre-delay of {} ms", pre_delay_ms);
+            // The first tap, at the early level.
+            assert!((ir[ms(pre_delay_ms)] - 0.85 * db_to_linear(-6.0)).abs() < 1e-4);
+        }
+
+        // Without early taps the tail starts with the shortest comb.
+        let mut reverb = Reverb::new(RATE, &ReverbSettings { pre_delay_ms: 20.0, ..late_only(0.5, 0.0) });
+        let shortest_comb = (COMB_TUNING[0] as f32 * RATE / TUNING_RATE) as usize;
+        assert_eq!(onset(&impulse_response(&mut reverb, 0.2)), ms(20.0) + shortest_comb);
+
+        // Set while running: once the ramp is over the new delay holds.
+        let mut reverb = Reverb::new(RATE, &ReverbSettings::default());
+        reverb.set_pre_delay_ms(40.0);
+        reverb.process_block(&mut vec![0.0; ms(100.0)]);
+        assert_eq!(onset(&impulse_response(&mut reverb, 0.2)), ms(40.0));
+    }
+
+    #[test]
+    fn damping_tilts_the_decay_of_the_tail() {
+        let decays = |high_damp, low_damp| band_decays(&impulse_response(&mut Reverb::new(RATE, &late_only(high_damp, low_damp)), 0.6));
+        let (flat_low, flat_high) = decays(0.0, 0.0);
+        let (dark_low, dark_high) = decays(1.0, 0.0);
+        let (thin_low, thin_high) = decays(0.0, 1.0);
+        // Undamped, both bands fade alike.
+        assert!((flat_high - flat_low).abs() < 3.0, "{:.1} and {:.1} dB", flat_low, flat_high);
+        // Each control speeds up the decay of its own band only.
+        assert!(dark_high - flat_high > 30.0 && (dark_low - flat_low).abs() < 1.0, "{:.1} and {:.1} dB", dark_low, dark_high);
+        assert!(thin_low - flat_low > 20.0 && (thin_high - flat_high).abs() < 1.0, "{:.1} and {:.1} dB", thin_low, thin_high);
+
+        // Changed while running, the loop filters follow.
+        let mut reverb = Reverb::new(RATE, &late_only(0.0, 0.0));
+        reverb.set_high_damp(1.0);
+        reverb.process_block(&mut vec![0.0; ms(100.0)]);
+        let (low, high) = band_decays(&impulse_response(&mut reverb, 0.6));
+        assert!((low - dark_low).abs() < 0.1 && (high - dark_high).abs() < 0.1, "{:.1} and {:.1} dB", low, high);
+    }
+
+    #[test]
+    fn early_and_late_levels_scale_their_own_part() {
+        let ir = |settings: &ReverbSettings| impulse_response(&mut Reverb::new(RATE, settings), 0.4);
+        let reference = ir(&ReverbSettings::default());
+        let quiet_early = ir(&ReverbSettings { early_level_db: -18.0, ..ReverbSettings::default() });
+        let quiet_late = ir(&ReverbSettings { late_level_db: -10.0, ..ReverbSettings::default() });
+        // Only the taps come before the shortest comb, only the tail after
+        // the last tap.
+        let (early, late) = ((COMB_TUNING[0] as f32 * RATE / TUNING_RATE) as usize, ms(44.8) + 2);
+        let scaled = |a: &[f32], b: &[f32], gain: f32| a.iter().zip(b).all(|(&a, &b)| (a - b * gain).abs() < 1e-6);
+        assert!(energy(&reference[..early]) > 0.0 && energy(&reference[late..]) > 0.0);
+
+        assert!(scaled(&quiet_early[..early], &reference[..early], db_to_linear(-12.0)));
+        assert_eq!(quiet_early[late..], reference[late..]);
+        assert_eq!(quiet_late[..early], reference[..early]);
+        assert!(scaled(&quiet_late[late..], &reference[late..], db_to_linear(-10.0)));
+        let ratio_db = |ir: &[f32]| 10.0 * (energy(&ir[..early]) / energy(&ir[late..])).log10();
+        assert!((ratio_db(&quiet_early) - (ratio_db(&reference) - 12.0)).abs() < 0.01);
+        assert!((ratio_db(&quiet_late) - (ratio_db(&reference) + 10.0)).abs() < 0.01);
+
+        // The same through the setters, once their ramps are over.
+        let mut reverb = Reverb::new(RATE, &ReverbSettings::default());
+        reverb.set_early_level_db(-18.0);
+        reverb.set_late_level_db(-10.0);
+        reverb.process_block(&mut vec![0.0; ms(100.0)]);
+        let both = impulse_response(&mut reverb, 0.4);
+        assert!(scaled(&both[..early], &quiet_early[..early], 1.0));
+        assert!(scaled(&both[late..], &quiet_late[late..], 1.0));
+    }
+}
@@ -0,0 +1,525 @@
+// Sample-accurate events for the output stream: metronome clicks,
+// triggered samples, test sequences.
+//
+// Any thread may `schedule` an event for an absolute sample position. The
+// event goes onto a lock-free stack; the output callback takes the whole
+// stack at the start of each block, sorts it into a heap it owns, and then
+// renders the block in pieces, stopping at each event's exact frame to
+// apply it. An event whose time has already passed is applied at the start
+// of the block and counted as late.
+//
+// The nodes the callback has emptied are handed back on a second stack and
+// freed by the next `schedule` call, so the callback doesn't free memory.
+// Nor does it allocate, as long as no more than the runner's capacity of
+// events wait at once.
+use cpal::traits::{DeviceTrait, StreamTrait};
+use cpal::{Device, SampleFormat, Stream};
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::BinaryHeap;
+use std::f32::consts::PI;
+use std::ptr;
+use std::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use std::sync::Arc;
+
+use crate::devices::{check_config, Direction};
+use crate::effect::debug_assert_finite;
+use crate::limiter::OutputGuard;
+use crate::realtime::{callback_scope, log_stream_error};
+use crate::stream::{SmoothedGain, StreamError};
+
+/// Events the callback can hold without allocating.
+pub const DEFAULT_EVENT_CAPACITY: usize = 1024;
+pub const MAX_VOICES: usize = 32;
+/// Length of a `NoteOn` tone, to -60 dB.
+pub const TONE_DECAY_MS: f32 = 150.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AudioEvent {
+    /// A short decaying sine at the MIDI note's pitch, `velocity` 0 to 1.
+    NoteOn { note: u8, velocity: f32 },
+    /// Starts playing a mono buffer on every channel. Keep a clone of the
+    /// buffer until it has played: the player drops its reference in the
+    /// callback, which would otherwise free the buffer there.
+    PlayBuffer(Arc<[f32]>),
+    /// Moves the output gain to `gain`, starting at the event's frame.
+    GainChange { gain: f32 },
+    /// Silences every voice that is playing.
+    Stop,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScheduledEvent {
+    pub at_sample: u64,
+    // Submission order, so events for the same frame keep it.
+    seq: u64,
+    pub event: AudioEvent,
+}
+
+// Reversed on (at_sample, seq) so the `BinaryHeap` pops the earliest.
+impl Ord for ScheduledEvent {
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        (other.at_sample, other.seq).cmp(&(self.at_sample, self.seq))
+    }
+}
+
+impl PartialOrd for ScheduledEvent {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Eq for ScheduledEvent {}
+
+struct Node {
+    event: Option<ScheduledEvent>,
+    next: *mut Node,
+}
+
+// Pushes `node` onto the stack at `head`. The stacks are only ever emptied
+// as a whole, so a push can't suffer from ABA.
+fn push_node(head: &AtomicPtr<Node>, node: *mut Node) {
+    let mut current = head.load(Ordering::Relaxed);
+    loop {
+        unsafe { (*node).next = current };
+        match head.compare_exchange_weak(current, node, Ordering::Release, Ordering::Relaxed) {
+            Ok(_) => return,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+fn free_list(mut node: *mut Node) {
+    while !node.is_null() {
+        let boxed = unsafe { Box::from_raw(node) };
+        node = boxed.next;
+    }
+}
+
+/// The shared side of the scheduler: events go in here from any thread.
+#[derive(Default)]
+pub struct Scheduler {
+    submitted: AtomicPtr<Node>,
+    retired: AtomicPtr<Node>,
+    next_seq: AtomicU64,
+    // Frame the next block starts at.
+    position: AtomicU64,
+    late_events: AtomicU64,
+    max_lateness: AtomicU64,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Queues `event` for frame `at_sample`, counted from the start of
+    /// the stream. Never blocks.
+    pub fn schedule(&self, at_sample: u64, event: AudioEvent) {
+        free_list(self.retired.swap(ptr::null_mut(), Ordering::Acquire));
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let node = Box::into_raw(Box::new(Node {
+            event: Some(ScheduledEvent { at_sample, seq, event }),
+            next: ptr::null_mut(),
+        }));
+        push_node(&self.submitted, node);
+    }
+
+    /// Frame the stream has reached, as of the last block.
+    pub fn position(&self) -> u64 {
+        self.position.load(Ordering::Relaxed)
+    }
+
+    /// Events applied after their frame had already been played.
+    pub fn late_events(&self) -> u64 {
+        self.late_events.load(Ordering::Relaxed)
+    }
+
+    /// The most frames a late event has missed its time by.
+    pub fn max_lateness_frames(&self) -> u64 {
+        self.max_lateness.load(Ordering::Relaxed)
+    }
+}
+
+impl Drop for Scheduler {
+    fn drop(&mut self) {
+        free_list(*self.submitted.get_mut());
+        free_list(*self.retired.get_mut());
+    }
+}
+
+/// What the runner drives: renders audio between events and applies them.
+pub trait EventTarget {
+    /// Fills `frames` of interleaved audio.
+    fn render(&mut self, frames: &mut [f32]);
+    fn apply(&mut self, event: &AudioEvent);
+}
+
+/// The callback side of a `Scheduler`. Only one runner per scheduler.
+pub struct SchedulerRunner {
+    scheduler: Arc<Scheduler>,
+    pending: BinaryHeap<ScheduledEvent>,
+    position: u64,
+    channels: usize,
+}
+
+impl SchedulerRunner {
+    pub fn new(scheduler: Arc<Scheduler>, channels: u16) -> Self {
+        Self::with_capacity(scheduler, channels, DEFAULT_EVENT_CAPACITY)
+    }
+
+    /// Holds up to `capacity` waiting events before the heap has to grow.
+    pub fn with_capacity(scheduler: Arc<Scheduler>, channels: u16, capacity: usize) -> Self {
+        let position = scheduler.position();
+        Self { scheduler, pending: BinaryHeap::with_capacity(capacity), position, channels: channels.max(1) as usize }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    // Moves submitted events into the heap and hands the nodes back.
+    fn collect_submitted(&mut self) {
+        let mut node = self.scheduler.submitted.swap(ptr::null_mut(), Ordering::Acquire);
+        while !node.is_null() {
+            let next = unsafe { (*node).next };
+            if let Some(event) = unsafe { (*node).event.take() } {
+                self.pending.push(event);
+            }
+            push_node(&self.scheduler.retired, node);
+            node = next;
+        }
+    }
+
+    /// Renders one block of interleaved `output`, applying each event due
+    /// within it at its exact frame.
+    pub fn process(&mut self, output: &mut [f32], target: &mut impl EventTarget) {
+        self.collect_submitted();
+        let frames = (output.len() / self.channels) as u64;
+        let end = self.position + frames;
+        let mut done = 0u64;
+        while let Some(next) = self.pending.peek() {
+            if next.at_sample >= end {
+                break;
+            }
+            let at = next.at_sample.max(self.position);
+            if at > self.position + done {
+                let offset = (at - self.position) as usize;
+                target.render(&mut output[done as usize * self.channels..offset * self.channels]);
+                done = at - self.position;
+            }
+            let Some(event) = self.pending.pop() else { break };
+            if event.at_sample < self.position {
+                self.scheduler.late_events.fetch_add(1, Ordering::Relaxed);
+                self.scheduler.max_lateness.fetch_max(self.position - event.at_sample, Ordering::Relaxed);
+            }
+            target.apply(&event.event);
+        }
+        target.render(&mut output[done as usize * self.channels..]);
+        self.position = end;
+        self.scheduler.position.store(end, Ordering::Relaxed);
+    }
+}
+
+enum Voice {
+    Tone { phase: f32, step: f32, amplitude: f32, decay: f32 },
+    Buffer { samples: Arc<[f32]>, position: usize },
+}
+
+impl Voice {
+    // None once the voice has finished.
+    fn next_sample(&mut self) -> Option<f32> {
+        match self {
+            Voice::Tone { phase, step, amplitude, decay } => {
+                if *amplitude < 0.001 {
+                    return None;
+                }
+                let sample = phase.sin() * *amplitude;
+                *phase = (*phase + *step) % (2.0 * PI);
+                *amplitude *= *decay;
+                Some(sample)
+            }
+            Voice::Buffer { samples, position } => {
+                let sample = samples.get(*position).copied();
+                *position += 1;
+                sample
+            }
+        }
+    }
+}
+
+/// Plays events as tones and buffers, mono on every channel. When all
+/// `MAX_VOICES` are busy a new voice replaces the oldest.
+pub struct EventPlayer {
+    sample_rate: f32,
+    channels: usize,
+    voices: Vec<Voice>,
+    gain: SmoothedGain,
+}
+
+impl EventPlayer {
+    pub fn new(sample_rate: u32, channels: u16) -> Self {
+        Self {
+            sample_rate: sample_rate as f32,
+            channels: channels.max(1) as usize,
+            voices: Vec::with_capacity(MAX_VOICES),
+            gain: SmoothedGain::with_rate(1.0, sample_rate),
+        }
+    }
+
+    pub fn active_voices(&self) -> usize {
+        self.voices.len()
+    }
+
+    fn start(&mut self, voice: Voice) {
+        if self.voices.len() == MAX_VOICES {
+            self.voices.remove(0);
+        }
+        self.voices.push(voice);
+    }
+}
+
+impl EventTarget for EventPlayer {
+    fn render(&mut self, frames: &mut [f32]) {
+        for frame in frames.chunks_mut(self.channels) {
+            let mut sample = 0.0;
+            self.voices.retain_mut(|voice| match voice.next_sample() {
+                Some(s) => {
+                    sample += s;
+                    true
+                }
+                None => false,
+            });
+            frame.fill(sample * self.gain.next_gain());
+        }
+    }
+
+    fn apply(&mut self, event: &AudioEvent) {
+        match event {
+            AudioEvent::NoteOn { note, velocity } => {
+                let frequency = 440.0 * 2.0_f32.powf((*note as f32 - 69.0) / 12.0);
+                let decay_frames = TONE_DECAY_MS * 0.001 * self.sample_rate;
+                self.start(Voice::Tone {
+                    phase: 0.0,
+                    step: 2.0 * PI * frequency / self.sample_rate,
+                    amplitude: velocity.clamp(0.0, 1.0),
+                    decay: 0.001_f32.powf(1.0 / decay_frames),
+                });
+            }
+            AudioEvent::PlayBuffer(samples) => self.start(Voice::Buffer { samples: Arc::clone(samples), position: 0 }),
+            AudioEvent::GainChange { gain } => self.gain.set_target(*gain),
+            AudioEvent::Stop => self.voices.clear(),
+        }
+    }
+}
+
+/// Output stream on the device default config that plays whatever is
+/// scheduled on `scheduler` through an `EventPlayer`. Returns the stream,
+/// already playing, and its sample rate for converting times to frames.
+pub fn play_scheduled(device: &Device, scheduler: Arc<Scheduler>) -> Result<(Stream, u32), StreamError> {
+    let config = device.default_output_config()?.config();
+    check_config(device, &config, SampleFormat::F32, Direction::Output)?;
+    let sample_rate = config.sample_rate.0;
+    let mut runner = SchedulerRunner::new(scheduler, config.channels);
+    let mut player = EventPlayer::new(sample_rate, config.channels);
+    let mut guard = OutputGuard::with_defaults(sample_rate, config.channels);
+    let stream = device.build_output_stream(
+        &config,
+        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            callback_scope(|| {
+                runner.process(data, &mut player);
+                debug_assert_finite("output callback", data);
+                guard.process(data);
+            })
+        },
+        log_stream_error(),
+        None,
+    )?;
+    stream.play()?;
+    Ok((stream, sample_rate))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    // Records the frame each event was applied at; `GainChange`'s gain
+    // carries the event's id.
+    #[derive(Default)]
+    struct Recorder {
+        frame: u64,
+        channels: usize,
+        applied: Vec<(u64, u64)>,
+    }
+
+    impl EventTarget for Recorder {
+        fn render(&mut self, frames: &mut [f32]) {
+            self.frame += (frames.len() / self.channels) as u64;
+        }
+
+        fn apply(&mut self, event: &AudioEvent) {
+            if let AudioEvent::GainChange { gain } = event {
+                self.applied.push((*gain as u64, self.frame));
+            }
+        }
+    }
+
+    #[test]
+    fn events_apply_at_their_frame_in_order() {
+        let scheduler = Arc::new(Scheduler::new());
+        let mut runner = SchedulerRunner::new(Arc::clone(&scheduler), 2);
+        let mut target = Recorder { channels: 2, ..Recorder::default() };
+        for (id, at) in [(0, 300), (1, 5), (2, 300), (3, 128), (4, 127)] {
+            scheduler.schedule(at, AudioEvent::GainChange { gain: id as f32 });
+        }
+        let mut block = vec![0.0; 256];
+        for _ in 0..3 {
+            runner.process(&mut block, &mut target);
+        }
+        assert_eq!(target.applied, [(1, 5), (4, 127), (3, 128), (0, 300), (2, 300)]);
+        assert_eq!(scheduler.late_events(), 0);
+    }
+
+    #[test]
+    fn late_events_apply_at_the_block_start() {
+        let scheduler = Arc::new(Scheduler::new());
+        let mut runner = SchedulerRunner::new(Arc::clone(&scheduler), 1);
+        let mut target = Recorder { channels: 1, ..Recorder::default() };
+        let mut block = vec![0.0; 100];
+        runner.process(&mut block, &mut target);
+        scheduler.schedule(40, AudioEvent::GainChange { gain: 7.0 });
+        runner.process(&mut block, &mut target);
+        assert_eq!(target.applied, [(7, 100)]);
+        assert_eq!((scheduler.late_events(), scheduler.max_lateness_frames()), (1, 60));
+    }
+
+    // Runs `player` through `blocks` blocks of `block_frames` stereo frames
+    // and returns the left channel.
+    fn play(scheduler: &Arc<Scheduler>, player: &mut EventPlayer, blocks: usize, block_frames: usize) -> Vec<f32> {
+        let mut runner = SchedulerRunner::new(Arc::clone(scheduler), 2);
+        let mut left = Vec::new();
+        let mut block = vec![0.0; block_frames * 2];
+        for _ in 0..blocks {
+            runner.process(&mut block, player);
+            for frame in block.chunks_exact(2) {
+                assert_eq!(frame[0], frame[1]);
+                left.push(frame[0]);
+            }
+        }
+        left
+    }
+
+    #[test]
+    fn buffers_start_on_their_exact_frame() {
+        let scheduler = Arc::new(Scheduler::new());
+        let buffer: Arc<[f32]> = Arc::from(vec![0.5; 10]);
+        // Inside a block, on a block boundary, and one frame before one.
+        for at in [37, 128, 255] {
+            scheduler.schedule(at, AudioEvent::PlayBuffer(Arc::clone(&buffer)));
+        }
+        let output = play(&scheduler, &mut EventPlayer::new(48_000, 2), 4, 128);
+        for (i, &sample) in output.iter().enumerate() {
+            let playing = [37, 128, 255].iter().filter(|&&at| i >= at && i < at + 10).count();
+            assert_eq!(sample, 0.5 * playing as f32, "frame {}", i);
+        }
+    }
+
+    #[test]
+    fn gain_changes_start_on_their_frame_and_stop_silences() {
+        let scheduler = Arc::new(Scheduler::new());
+        let buffer: Arc<[f32]> = Arc::from(vec![1.0; 4_000]);
+        scheduler.schedule(0, AudioEvent::PlayBuffer(buffer));
+        scheduler.schedule(1_000, AudioEvent::GainChange { gain: 0.0 });
+        scheduler.schedule(2_000, AudioEvent::GainChange { gain: 1.0 });
+        scheduler.schedule(3_001, AudioEvent::Stop);
+        let mut player = EventPlayer::new(48_000, 2);
+        let output = play(&scheduler, &mut player, 8, 500);
+
+        assert!(output[..1_000].iter().all(|&s| s == 1.0));
+        // Each ramp takes its first step on the event's frame and is done
+        // 10 ms later.
+        assert!(output[1_000] < 1.0);
+        assert!(output[1_480..2_000].iter().all(|&s| s == 0.0));
+        assert!(output[2_000] > 0.0);
+        assert!(output[2_480..3_001].iter().all(|&s| s == 1.0));
+        assert!(output[3_001..].iter().all(|&s| s == 0.0));
+        assert_eq!(player.active_voices(), 0);
+    }
+
+    #[test]
+    fn notes_start_on_their_frame_and_decay() {
+        let scheduler = Arc::new(Scheduler::new());
+        scheduler.schedule(300, AudioEvent::NoteOn { note: 69, velocity: 0.8 });
+        let mut player = EventPlayer::new(48_000, 2);
+        let output = play(&scheduler, &mut player, 40, 256);
+
+        // A sine from phase 0: silent on the event frame, sounding after it.
+        assert!(output[..=300].iter().all(|&s| s == 0.0));
+        assert!(output[301] > 0.0);
+        let peak = |range: std::ops::Range<usize>| output[range].iter().fold(0.0f32, |p, s| p.max(s.abs()));
+        assert!(peak(300..500) > 0.7);
+        // Down to -50 dB at 5/6 of TONE_DECAY_MS and gone at -60 dB.
+        assert!(peak(300 + 6_000..300 + 6_500) < 0.8 * 0.004);
+        assert!(output[300 + 7_300..].iter().all(|&s| s == 0.0));
+        assert_eq!(player.active_voices(), 0);
+    }
+
+    #[test]
+    fn the_oldest_voice_makes_room() {
+        let scheduler = Arc::new(Scheduler::new());
+        let mut player = EventPlayer::new(48_000, 2);
+        for _ in 0..MAX_VOICES + 5 {
+            scheduler.schedule(0, AudioEvent::PlayBuffer(Arc::from(vec![0.01; 1_000])));
+        }
+        play(&scheduler, &mut player, 1, 64);
+        assert_eq!(player.active_voices(), MAX_VOICES);
+    }
+
+    #[test]
+    fn events_from_many_threads_are_applied_once() {
+        const THREADS: u64 = 4;
+        const EVENTS: u64 = 20_000;
+        let scheduler = Arc::new(Scheduler::new());
+        let producers: Vec<_> = (0..THREADS)
+            .map(|t| {
+                let scheduler = Arc::clone(&scheduler);
+                thread::spawn(move || {
+                    (0..EVENTS)
+                        .map(|i| {
+                            let id = t * EVENTS + i;
+                            let at = scheduler.position() + id % 700;
+                            scheduler.schedule(at, AudioEvent::GainChange { gain: id as f32 });
+                            at
+                        })
+                        .collect::<Vec<u64>>()
+                })
+            })
+            .collect();
+
+        let mut runner = SchedulerRunner::new(Arc::clone(&scheduler), 2);
+        let mut target = Recorder { channels: 2, ..Recorder::default() };
+        let mut block = vec![0.0; 512];
+        let started = Instant::now();
+        while (target.applied.len() as u64) < THREADS * EVENTS {
+            assert!(started.elapsed() < Duration::from_secs(30), "only {} events arrived", target.applied.len());
+            runner.process(&mut block, &mut target);
+        }
+        let scheduled: Vec<u64> = producers.into_iter().flat_map(|p| p.join().unwrap()).collect();
+
+        let mut seen = vec![false; scheduled.len()];
+        let mut late = 0;
+        for &(id, frame) in &target.applied {
+            assert!(!seen[id as usize], "event {} applied twice", id);
+            seen[id as usize] = true;
+            let at = scheduled[id as usize];
+            assert!(frame >= at, "event {} applied early", id);
+            late += (frame > at) as u64;
+        }
+        assert_eq!(late, scheduler.late_events());
+        // Nothing left behind for the next block.
+        runner.process(&mut block, &mut target);
+        assert_eq!(target.applied.len() as u64, THREADS * EVENTS);
+        assert_eq!(runner.pending(), 0);
+    }
+}
@@ -0,0 +1,696 @@
+// Continuous recording into a WAV file that survives the device going away.
+//
+// When a stream reports a fatal error (the USB interface was unplugged),
+// the file is finalized at once so everything captured so far stays
+// playable. With a resume timeout, the session then waits for the device,
+// or the default input, to come back and carries on into a new numbered
+// file (`take.wav`, `take-2.wav`, ...):
+//
+//   Recording ──fatal error──▶ WaitingForDevice ──reopened──▶ Recording
+//       │                            │ timeout                (next file)
+//       │ fatal error, no resume     ▼
+//       └──────────────────────▶ Finished
+//
+// `RecordingSession` only makes these decisions, fed with errors and the
+// time, so it can be driven without a device.
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use cpal::traits::{DeviceTrait, HostTrait};
+use cpal::Device;
+
+use crate::input::{InputStreamBuilder, StreamHandle};
+use crate::stream::{ring_buffer, Consumer, StreamError};
+use crate::write_wav::{WavSampleFormat, WavStreamWriter};
+
+/// How long the input ring holds audio for the writing loop.
+const INPUT_RING_MS: u32 = 2000;
+/// How often the writing loop drains the input ring.
+const WRITER_POLL: Duration = Duration::from_millis(20);
+/// How often a lost device is looked for while waiting for it.
+pub const DEFAULT_RETRY_INTERVAL: Duration = Duration::from_millis(500);
+
+/// What to do about an error reported by a running stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorAction {
+    /// Report it and keep recording; e.g. an overrun the backend recovered from.
+    Continue,
+    /// The stream is gone and won't deliver any more audio.
+    Stop,
+}
+
+pub fn error_action(error: &cpal::StreamError) -> ErrorAction {
+    match error {
+        cpal::StreamError::DeviceNotAvailable => ErrorAction::Stop,
+        cpal::StreamError::BackendSpecific { .. } => ErrorAction::Continue,
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ResumePolicy {
+    /// How long to wait for the device before giving up.
+    pub timeout: Duration,
+    /// Time between attempts to reopen it.
+    pub retry_interval: Duration,
+}
+
+impl ResumePolicy {
+    pub fn new(timeout: Duration) -> Self {
+        Self { timeout, retry_interval: DEFAULT_RETRY_INTERVAL }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionState {
+    /// Writing file number `segment`, counting from 1.
+    Recording { segment: u32 },
+    /// File `segment` was finalized when the device went away at `lost_at`.
+    WaitingForDevice { segment: u32, lost_at: Duration, last_attempt: Option<Duration> },
+    Finished,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SessionAction {
+    Continue,
+    /// Finalize the current file; it won't get any more audio.
+    Finalize,
+    /// Look for the device and try to open file `segment` on it, then
+    /// report back with `on_reopen`.
+    TryReopen { segment: u32 },
+    /// Waited the whole timeout; the session is over.
+    GiveUp,
+}
+
+/// Decides what happens to a recording when its stream fails. Times are
+/// measured from any fixed point, such as the start of the recording.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingSession {
+    resume: Option<ResumePolicy>,
+    state: SessionState,
+}
+
+impl RecordingSession {
+    /// A session recording its first file. Without a resume policy, a fatal
+    /// error ends it.
+    pub fn new(resume: Option<ResumePolicy>) -> Self {
+        Self { resume, state: SessionState::Recording { segment: 1 } }
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state
+    }
+
+    pub fn on_stream_error(&mut self, error: &cpal::StreamError, now: Duration) -> SessionAction {
+        // Errors from a stream that was already given up on don't matter.
+        let SessionState::Recording { segment } = self.state else {
+            return SessionAction::Continue;
+        };
+        match error_action(error) {
+            ErrorAction::Continue => SessionAction::Continue,
+            ErrorAction::Stop => {
+                self.state = match self.resume {
+                    Some(_) => SessionState::WaitingForDevice { segment, lost_at: now, last_attempt: None },
+                    None => SessionState::Finished,
+                };
+                SessionAction::Finalize
+            }
+        }
+    }
+
+    /// Called regularly; while waiting, says when to try reopening the
+    /// device and when to give up.
+    pub fn on_tick(&mut self, now: Duration) -> SessionAction {
+        let (SessionState::WaitingForDevice { segment, lost_at, last_attempt }, Some(resume)) = (self.state, self.resume)
+        else {
+            return SessionAction::Continue;
+        };
+        if now.saturating_sub(lost_at) >= resume.timeout {
+            self.state = SessionState::Finished;
+            return SessionAction::GiveUp;
+        }
+        if last_attempt.is_some_and(|last| now.saturating_sub(last) < resume.retry_interval) {
+            return SessionAction::Continue;
+        }
+        self.state = SessionState::WaitingForDevice { segment, lost_at, last_attempt: Some(now) };
+        SessionAction::TryReopen { segment: segment + 1 }
+    }
+
+    /// Outcome of a `TryReopen`: on success the next file is being written.
+    pub fn on_reopen(&mut self, opened: bool) {
+        if let (SessionState::WaitingForDevice { segment, .. }, true) = (self.state, opened) {
+            self.state = SessionState::Recording { segment: segment + 1 };
+        }
+    }
+
+    /// The user stopped the recording.
+    pub fn stop(&mut self) {
+        self.state = SessionState::Finished;
+    }
+}
+
+/// File number `segment` of a recording into `path`: `path` itself for the
+/// first, then `<stem>-<segment>.<extension>` next to it.
+pub fn segment_path(path: &Path, segment: u32) -> PathBuf {
+    if segment <= 1 {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, segment, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, segment),
+    };
+    path.with_file_name(name)
+}
+
+/// The input device named `name`, or the default input when no name is
+/// given or `fallback_to_default` allows it. Unlike
+/// `devices::input_device_or_default` this prints nothing, as it is called
+/// over and over while a device is missing.
+fn find_input_device(name: Option<&str>, fallback_to_default: bool) -> Option<Device> {
+    let host = cpal::default_host();
+    if let Some(name) = name {
+        let found = host.input_devices().ok()?.find(|d| d.name().is_ok_and(|n| n == name));
+        if found.is_some() || !fallback_to_default {
+            return found;
+        }
+    }
+    host.default_input_device()
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordSettings {
+    /// Input device name; the default input when `None`.
+    pub device: Option<String>,
+    pub format: WavSampleFormat,
+    /// Stops by itself after this long, gaps included.
+    pub max_duration: Option<Duration>,
+    /// Waits for a lost device to come back instead of stopping.
+    pub resume: Option<ResumePolicy>,
+    /// While waiting, the default input will do too.
+    pub fallback_to_default: bool,
+}
+
+impl Default for RecordSettings {
+    fn default() -> Self {
+        Self {
+            device: None,
+            format: WavSampleFormat::Int24,
+            max_duration: None,
+            resume: None,
+            fallback_to_default: true,
+        }
+    }
+}
+
+/// A recording the device went away from; the files written up to then are
+/// complete and playable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecordingInterrupted {
+    /// Audio recorded before the interruption, in seconds.
+    pub at_seconds: f64,
+    pub error: String,
+    pub files: Vec<PathBuf>,
+}
+
+impl fmt::Display for RecordingInterrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Recording interrupted after {:.2} s: {}", self.at_seconds, self.error)
+    }
+}
+
+impl std::error::Error for RecordingInterrupted {}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct RecordingSummary {
+    pub files: Vec<PathBuf>,
+    /// Audio recorded over all files, in seconds.
+    pub seconds: f64,
+    /// Device losses the recording resumed from.
+    pub interruptions: Vec<RecordingInterrupted>,
+}
+
+#[derive(Debug)]
+pub enum RecordError {
+    NoDevice,
+    Stream(StreamError),
+    Io(io::Error),
+    /// The device went away and didn't come back.
+    Interrupted(RecordingInterrupted),
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::NoDevice => write!(f, "No input device available"),
+            RecordError::Stream(e) => write!(f, "{}", e),
+            RecordError::Io(e) => write!(f, "Cannot write recording: {}", e),
+            RecordError::Interrupted(interrupted) => write!(f, "{}", interrupted),
+        }
+    }
+}
+
+impl std::error::Error for RecordError {}
+
+impl From<StreamError> for RecordError {
+    fn from(e: StreamError) -> Self {
+        RecordError::Stream(e)
+    }
+}
+
+impl From<io::Error> for RecordError {
+    fn from(e: io::Error) -> Self {
+        RecordError::Io(e)
+    }
+}
+
+// One file being recorded from one stream.
+struct Segment<S = StreamHandle> {
+    stream: S,
+    errors: Receiver<cpal::StreamError>,
+    file: SegmentFile,
+}
+
+// What the writing loop needs of a segment's stream, so it can also be
+// run on a ring that isn't fed by a device.
+trait SegmentStream {
+    fn stop(&self);
+}
+
+impl SegmentStream for StreamHandle {
+    fn stop(&self) {
+        StreamHandle::stop(self);
+    }
+}
+
+// The writing end of a segment: the ring the callback fills and the file
+// it is drained into.
+struct SegmentFile {
+    ring: Consumer<f32>,
+    writer: WavStreamWriter,
+    path: PathBuf,
+    sample_rate: u32,
+    channels: usize,
+    chunk: Vec<f32>,
+}
+
+impl Segment {
+    fn open(device: &Device, path: &Path, format: WavSampleFormat) -> Result<Segment, RecordError> {
+        let builder = InputStreamBuilder::new(device);
+        let negotiated = builder.negotiate()?;
+        let channels = negotiated.channels.max(1) as usize;
+        let (mut input_ring, ring) = ring_buffer::<f32>((negotiated.sample_rate * INPUT_RING_MS / 1000) as usize * channels);
+        let (sender, errors) = mpsc::channel();
+        let (stream, negotiated) = builder
+            .on_data(move |data| {
+                input_ring.push_slice(data);
+            })
+            .on_error(move |err| {
+                let _ = sender.send(err);
+            })
+            .build()?;
+        // Only create the file once the device has accepted the stream.
+        let writer = WavStreamWriter::create(path, negotiated.sample_rate, negotiated.channels, format)?;
+
+        Ok(Segment { stream, errors, file: SegmentFile::new(ring, writer, path, negotiated.sample_rate, channels) })
+    }
+}
+
+impl<S: SegmentStream> Segment<S> {
+    fn drain(&mut self) -> io::Result<()> {
+        self.file.drain()
+    }
+
+    // Stops the stream and finalizes the file, returning its path and
+    // length in seconds.
+    fn finish(self) -> io::Result<(PathBuf, f64)> {
+        self.stream.stop();
+        self.file.finish()
+    }
+}
+
+impl SegmentFile {
+    fn new(ring: Consumer<f32>, writer: WavStreamWriter, path: &Path, sample_rate: u32, channels: usize) -> Self {
+        Self { ring, writer, path: path.to_path_buf(), sample_rate, channels, chunk: vec![0.0; 4096 * channels] }
+    }
+
+    // Writes everything the callback has delivered so far.
+    fn drain(&mut self) -> io::Result<()> {
+        loop {
+            // Only whole frames, so channels never get out of step.
+            let frames = self.ring.available().min(self.chunk.len()) / self.channels;
+            if frames == 0 {
+                return Ok(());
+            }
+            let len = self.ring.pop_slice(&mut self.chunk[..frames * self.channels]);
+            self.writer.write_samples(&self.chunk[..len])?;
+        }
+    }
+
+    // Finalizes the file with whatever is left in the ring, returning its
+    // path and length in seconds.
+    fn finish(mut self) -> io::Result<(PathBuf, f64)> {
+        self.drain()?;
+        let seconds = self.writer.frames_written() as f64 / self.sample_rate as f64;
+        self.writer.finish()?;
+        Ok((self.path, seconds))
+    }
+}
+
+/// Records the input into `path` until `stop` is set or the maximum
+/// duration has passed.
+///
+/// If the device goes away, the file is finalized right away. Without a
+/// resume policy, or when the device doesn't come back in time, the result
+/// is `RecordError::Interrupted`; otherwise recording carries on into the
+/// next numbered file and the interruption is listed in the summary.
+pub fn record_to_wav(path: &Path, settings: &RecordSettings, stop: &AtomicBool) -> Result<RecordingSummary, RecordError> {
+    let device = find_input_device(settings.device.as_deref(), false).ok_or(RecordError::NoDevice)?;
+    record_device_to_wav(&device, path, settings, stop)
+}
+
+/// `record_to_wav` on `device` rather than the one `settings` names, which
+/// is only looked for when resuming.
+pub fn record_device_to_wav(
+    device: &Device,
+    path: &Path,
+    settings: &RecordSettings,
+    stop: &AtomicBool,
+) -> Result<RecordingSummary, RecordError> {
+    let first = Segment::open(device, path, settings.format)?;
+    record_segments(path, settings, stop, first, |path| {
+        find_input_device(settings.device.as_deref(), settings.fallback_to_default)
+            .and_then(|device| Segment::open(&device, path, settings.format).ok())
+    })
+}
+
+// The writing loop of `record_device_to_wav`, from its first segment on;
+// `reopen` opens the stream and file of a later one.
+fn record_segments<S: SegmentStream>(
+    path: &Path,
+    settings: &RecordSettings,
+    stop: &AtomicBool,
+    first: Segment<S>,
+    mut reopen: impl FnMut(&Path) -> Option<Segment<S>>,
+) -> Result<RecordingSummary, RecordError> {
+    let mut session = RecordingSession::new(settings.resume);
+    let mut summary = RecordingSummary::default();
+    // The last loss of the device, until recording resumes.
+    let mut interruption: Option<RecordingInterrupted> = None;
+
+    let mut segment = Some(first);
+    let started = Instant::now();
+
+    loop {
+        let now = started.elapsed();
+        let stopping = stop.load(Ordering::Acquire) || settings.max_duration.is_some_and(|max| now >= max);
+
+        let mut lost = None;
+        if let Some(current) = segment.as_mut() {
+            current.drain()?;
+            while let Ok(error) = current.errors.try_recv() {
+                if session.on_stream_error(&error, now) == SessionAction::Finalize {
+                    lost = Some(error);
+                    break;
+                }
+                eprintln!("Warning: {}", error);
+            }
+        }
+        if let Some(error) = lost {
+            if let Some(current) = segment.take() {
+                let (file, seconds) = current.finish()?;
+                summary.files.push(file);
+                summary.seconds += seconds;
+            }
+            interruption = Some(RecordingInterrupted {
+                at_seconds: summary.seconds,
+                error: error.to_string(),
+                files: summary.files.clone(),
+            });
+        }
+
+        if stopping {
+            session.stop();
+            if let Some(current) = segment.take() {
+                let (file, seconds) = current.finish()?;
+                summary.files.push(file);
+                summary.seconds += seconds;
+            }
+            return match interruption {
+                Some(interrupted) => Err(RecordError::Interrupted(RecordingInterrupted { files: summary.files, ..interrupted })),
+                None => Ok(summary),
+            };
+        }
+
+        if let SessionAction::TryReopen { segment: number } = session.on_tick(now) {
+            let opened = reopen(&segment_path(path, number));
+            session.on_reopen(opened.is_some());
+            if opened.is_some() {
+                segment = opened;
+                summary.interruptions.extend(interruption.take());
+            }
+        }
+        // Lost without a resume policy, or given up on.
+        if session.state() == SessionState::Finished {
+            if let Some(interrupted) = interruption.take() {
+                return Err(RecordError::Interrupted(interrupted));
+            }
+        }
+        thread::sleep(WRITER_POLL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_wav::read_wave_file;
+    use crate::stream::Producer;
+    use std::sync::Arc;
+
+    fn backend_error() -> cpal::StreamError {
+        cpal::StreamError::BackendSpecific { err: cpal::BackendSpecificError { description: "buffer overrun".to_string() } }
+    }
+
+    fn seconds(s: f32) -> Duration {
+        Duration::from_secs_f32(s)
+    }
+
+    #[test]
+    fn device_errors_map_to_actions_and_record_errors() {
+        assert_eq!(error_action(&cpal::StreamError::DeviceNotAvailable), ErrorAction::Stop);
+        assert_eq!(error_action(&backend_error()), ErrorAction::Continue);
+
+        let stream: RecordError = StreamError::Unsupported("u8 samples".to_string()).into();
+        assert!(matches!(stream, RecordError::Stream(StreamError::Unsupported(_))));
+        let io: RecordError = io::Error::new(io::ErrorKind::StorageFull, "disk full").into();
+        assert!(matches!(&io, RecordError::Io(e) if e.kind() == io::ErrorKind::StorageFull));
+        assert_eq!(io.to_string(), "Cannot write recording: disk full");
+        let interrupted = RecordingInterrupted { at_seconds: 12.345, error: "device gone".to_string(), files: vec![] };
+        assert_eq!(RecordError::Interrupted(interrupted).to_string(), "Recording interrupted after 12.35 s: device gone");
+    }
+
+    #[test]
+    fn a_fatal_error_without_resume_finishes_the_session() {
+        let mut session = RecordingSession::new(None);
+        assert_eq!(session.on_stream_error(&backend_error(), seconds(1.0)), SessionAction::Continue);
+        assert_eq!(session.state(), SessionState::Recording { segment: 1 });
+        assert_eq!(session.on_stream_error(&cpal::StreamError::DeviceNotAvailable, seconds(2.0)), SessionAction::Finalize);
+        assert_eq!(session.state(), SessionState::Finished);
+        // Later errors and ticks change nothing.
+        assert_eq!(session.on_stream_error(&cpal::StreamError::DeviceNotAvailable, seconds(3.0)), SessionAction::Continue);
+        assert_eq!(session.on_tick(seconds(10.0)), SessionAction::Continue);
+        assert_eq!(session.state(), SessionState::Finished);
+    }
+
+    #[test]
+    fn a_lost_device_is_retried_until_it_comes_back_or_times_out() {
+        let policy = ResumePolicy { timeout: seconds(2.0), retry_interval: seconds(0.5) };
+        let mut session = RecordingSession::new(Some(policy));
+        assert_eq!(session.on_tick(seconds(0.5)), SessionAction::Continue);
+
+        assert_eq!(session.on_stream_error(&cpal::StreamError::DeviceNotAvailable, seconds(1.0)), SessionAction::Finalize);
+        let lost_at = seconds(1.0);
+        assert_eq!(session.state(), SessionState::WaitingForDevice { segment: 1, lost_at, last_attempt: None });
+        // The dead stream may still report errors; they don't count twice.
+        assert_eq!(session.on_stream_error(&cpal::StreamError::DeviceNotAvailable, seconds(1.1)), SessionAction::Continue);
+
+        assert_eq!(session.on_tick(seconds(1.2)), SessionAction::TryReopen { segment: 2 });
+        session.on_reopen(false);
+        assert_eq!(session.state(), SessionState::WaitingForDevice { segment: 1, lost_at, last_attempt: Some(seconds(1.2)) });
+        // Not again before the retry interval is up.
+        assert_eq!(session.on_tick(seconds(1.5)), SessionAction::Continue);
+        assert_eq!(session.on_tick(seconds(1.7)), SessionAction::TryReopen { segment: 2 });
+        session.on_reopen(true);
+        assert_eq!(session.state(), SessionState::Recording { segment: 2 });
+        assert_eq!(session.on_tick(seconds(1.8)), SessionAction::Continue);
+
+        // Lost again; this time it never comes back.
+        assert_eq!(session.on_stream_error(&cpal::StreamError::DeviceNotAvailable, seconds(5.0)), SessionAction::Finalize);
+        let mut attempts = 0;
+        let mut now = 5_000;
+        let action = loop {
+            match session.on_tick(Duration::from_millis(now)) {
+                SessionAction::TryReopen { segment } => {
+                    assert_eq!(segment, 3);
+                    attempts += 1;
+                    session.on_reopen(false);
+                }
+                SessionAction::Continue => {}
+                action => break action,
+            }
+            now += 100;
+        };
+        assert_eq!(action, SessionAction::GiveUp);
+        assert_eq!(now, 7_000);
+        // At 5.0, 5.5, 6.0 and 6.5 s.
+        assert_eq!(attempts, 4);
+        assert_eq!(session.state(), SessionState::Finished);
+    }
+
+    #[test]
+    fn stopping_while_waiting_ends_the_session() {
+        let mut session = RecordingSession::new(Some(ResumePolicy::new(seconds(60.0))));
+        session.on_stream_error(&cpal::StreamError::DeviceNotAvailable, seconds(1.0));
+        session.stop();
+        assert_eq!(session.state(), SessionState::Finished);
+        assert_eq!(session.on_tick(seconds(2.0)), SessionAction::Continue);
+    }
+
+    #[test]
+    fn segments_are_numbered_next_to_the_first_file() {
+        let path = Path::new("/recordings/take.wav");
+        assert_eq!(segment_path(path, 1), path);
+        assert_eq!(segment_path(path, 2), Path::new("/recordings/take-2.wav"));
+        assert_eq!(segment_path(Path::new("take"), 3), Path::new("take-3"));
+    }
+
+    #[test]
+    fn a_segment_finalized_on_a_device_error_is_a_valid_wav() {
+        let path = std::env::temp_dir().join(format!("cpal_playbook_{}_interrupted.wav", std::process::id()));
+        let (mut producer, ring) = ring_buffer::<f32>(48_000);
+        let writer = WavStreamWriter::create(&path, 48_000, 2, WavSampleFormat::Float32).unwrap();
+        let mut file = SegmentFile::new(ring, writer, &path, 48_000, 2);
+        let samples: Vec<f32> = (0..2_401 * 2).map(|i| (i as f32 * 0.001).sin() * 0.5).collect();
+
+        // The callback delivers audio in blocks, the writing loop drains
+        // some of it, then the device goes away with the rest still queued.
+        let mut session = RecordingSession::new(None);
+        producer.push_slice(&samples[..1_000 * 2]);
+        file.drain().unwrap();
+        assert_eq!(session.on_stream_error(&backend_error(), seconds(0.02)), SessionAction::Continue);
+        producer.push_slice(&samples[1_000 * 2..]);
+        assert_eq!(session.on_stream_error(&cpal::StreamError::DeviceNotAvailable, seconds(0.04)), SessionAction::Finalize);
+        let (finished, length) = file.finish().unwrap();
+        assert_eq!(finished, path);
+        assert!((length - 2_401.0 / 48_000.0).abs() < 1e-9);
+
+        let bytes = std::fs::read(&path).unwrap();
+        let read = read_wave_file(path.to_str().unwrap());
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, bytes.len() - 8);
+        let data = bytes.windows(4).position(|id| id == b"data").unwrap();
+        assert_eq!(u32::from_le_bytes(bytes[data + 4..data + 8].try_into().unwrap()), 2_401 * 2 * 4);
+        let (read, spec) = read.unwrap();
+        assert_eq!((spec.sample_rate, spec.channels), (48_000, 2));
+        assert_eq!(read, samples);
+    }
+
+    // Stands in for a device stream, noting when the loop stops it.
+    struct FakeStream(Arc<AtomicBool>);
+
+    impl SegmentStream for FakeStream {
+        fn stop(&self) {
+            self.0.store(true, Ordering::Release);
+        }
+    }
+
+    type FakeSegment = (Segment<FakeStream>, Producer<f32>, mpsc::Sender<cpal::StreamError>, Arc<AtomicBool>);
+
+    fn fake_segment(path: &Path) -> FakeSegment {
+        let (producer, ring) = ring_buffer::<f32>(48_000);
+        let writer = WavStreamWriter::create(path, 48_000, 2, WavSampleFormat::Int16).unwrap();
+        let (sender, errors) = mpsc::channel();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let segment = Segment { stream: FakeStream(Arc::clone(&stopped)), errors, file: SegmentFile::new(ring, writer, path, 48_000, 2) };
+        (segment, producer, sender, stopped)
+    }
+
+    // The RIFF and data sizes in the header of `path`, and the file length.
+    fn header_sizes(path: &Path) -> (usize, usize, usize) {
+        let bytes = std::fs::read(path).unwrap();
+        let data = bytes.windows(4).position(|id| id == b"data").unwrap();
+        let size = |at: usize| u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap()) as usize;
+        (size(4), size(data + 4), bytes.len())
+    }
+
+    fn quantized(samples: &[f32]) -> Vec<f32> {
+        samples.iter().map(|&s| (s * 32_767.0).round() / 32_768.0).collect()
+    }
+
+    #[test]
+    fn setting_the_stop_flag_mid_recording_leaves_a_finished_wav() {
+        let path = std::env::temp_dir().join(format!("cpal_playbook_{}_stopped.wav", std::process::id()));
+        let (segment, mut producer, _sender, stopped) = fake_segment(&path);
+        let samples: Vec<f32> = (0..4_800 * 2).map(|i| (i as f32 * 0.003).sin() * 0.5).collect();
+        // What Ctrl-C sets; `main` passes `shutdown::flag()` here.
+        let stop = AtomicBool::new(false);
+
+        let result = thread::scope(|scope| {
+            let recording = scope.spawn(|| {
+                record_segments(&path, &RecordSettings::default(), &stop, segment, |_| None)
+            });
+            // Blocks arrive while the loop is writing, and the last one
+            // only just before the flag is set.
+            for block in samples.chunks(960 * 2) {
+                producer.push_slice(block);
+                thread::sleep(WRITER_POLL / 2);
+            }
+            stop.store(true, Ordering::Release);
+            recording.join().unwrap()
+        });
+
+        let bytes = std::fs::read(&path).unwrap();
+        let read = read_wave_file(path.to_str().unwrap());
+        let sizes = header_sizes(&path);
+        let _ = std::fs::remove_file(&path);
+        let summary = result.unwrap();
+        assert_eq!(summary, RecordingSummary { files: vec![path.clone()], seconds: 0.1, interruptions: Vec::new() });
+        assert!(stopped.load(Ordering::Acquire));
+        // The sizes written at creation are replaced with the real ones.
+        assert_eq!(sizes, (bytes.len() - 8, 4_800 * 2 * 2, bytes.len()));
+        let (read, spec) = read.unwrap();
+        assert_eq!((spec.sample_rate, spec.channels, spec.bits_per_sample), (48_000, 2, 16));
+        assert_eq!(read, quantized(&samples));
+    }
+
+    #[test]
+    fn a_lost_device_without_resume_interrupts_with_the_file_finished() {
+        let path = std::env::temp_dir().join(format!("cpal_playbook_{}_lost.wav", std::process::id()));
+        let (segment, mut producer, sender, stopped) = fake_segment(&path);
+        let samples: Vec<f32> = (0..2_400 * 2).map(|i| (i as f32 * 0.002).cos() * 0.25).collect();
+        producer.push_slice(&samples);
+        sender.send(backend_error()).unwrap();
+        sender.send(cpal::StreamError::DeviceNotAvailable).unwrap();
+
+        let stop = AtomicBool::new(false);
+        let result = record_segments(&path, &RecordSettings::default(), &stop, segment, |_| None);
+        let read = read_wave_file(path.to_str().unwrap());
+        let sizes = header_sizes(&path);
+        let _ = std::fs::remove_file(&path);
+        match result {
+            Err(RecordError::Interrupted(interrupted)) => {
+                assert_eq!(interrupted.files, std::slice::from_ref(&path));
+                assert!((interrupted.at_seconds - 0.05).abs() < 1e-9);
+                assert_eq!(interrupted.error, cpal::StreamError::DeviceNotAvailable.to_string());
+            }
+            other => panic!("expected an interruption, got {:?}", other),
+        }
+        assert!(stopped.load(Ordering::Acquire));
+        assert_eq!(sizes, (sizes.2 - 8, 2_400 * 2 * 2, sizes.2));
+        assert_eq!(read.unwrap().0, quantized(&samples));
+    }
+}
@@ -0,0 +1,41 @@
+// Ctrl-C as a request to stop, so recordings still get finished files.
+//
+// The handler only sets a flag. The loops that keep streams running poll
+// it and then stop and finish the same way as when the user ends them
+// normally; killing the process instead would leave each WAV header with
+// the placeholder sizes written when the file was created. A second
+// Ctrl-C, while that is still going on, exits at once.
+use std::sync::atomic::{AtomicBool, Ordering};
+
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Exit status after a second Ctrl-C, as shells report death by SIGINT.
+const FORCED_EXIT_STATUS: libc::c_int = 130;
+
+extern "C" fn on_interrupt(_: libc::c_int) {
+    if REQUESTED.swap(true, Ordering::SeqCst) {
+        // Nothing but async-signal-safe calls in here.
+        unsafe { libc::_exit(FORCED_EXIT_STATUS) };
+    }
+}
+
+/// Routes Ctrl-C (and SIGTERM on Unix) to `requested` instead of ending
+/// the process.
+pub fn install() {
+    let handler = on_interrupt as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    unsafe {
+        libc::signal(libc::SIGINT, handler);
+        #[cfg(unix)]
+        libc::signal(libc::SIGTERM, handler);
+    }
+}
+
+/// The flag the handler sets, for code that takes a stop flag; setting it
+/// from elsewhere stops the same loops.
+pub fn flag() -> &'static AtomicBool {
+    &REQUESTED
+}
+
+pub fn requested() -> bool {
+    REQUESTED.load(Ordering::Acquire)
+}
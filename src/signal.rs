@@ -0,0 +1,171 @@
+// Test signals, one sample at a time.
+//
+// Every generator keeps its own state between calls, so the same one can
+// feed an output callback block by block or fill a buffer offline, and the
+// result is the same sample for sample. Levels are peak values: each
+// generator stays within -1..1 and is scaled by the caller.
+use std::f32::consts::PI;
+
+/// A resumable source of mono samples.
+pub trait Generator: Send {
+    fn next_sample(&mut self) -> f32;
+
+    /// Fills `out` with the next samples.
+    fn fill(&mut self, out: &mut [f32]) {
+        for sample in out.iter_mut() {
+            *sample = self.next_sample();
+        }
+    }
+}
+
+pub struct Sine {
+    phase: f32,
+    step: f32,
+}
+
+impl Sine {
+    pub fn new(freq_hz: f32, sample_rate: u32) -> Self {
+        Self { phase: 0.0, step: 2.0 * PI * freq_hz / sample_rate.max(1) as f32 }
+    }
+}
+
+impl Generator for Sine {
+    fn next_sample(&mut self) -> f32 {
+        let sample = self.phase.sin();
+        self.phase = (self.phase + self.step) % (2.0 * PI);
+        sample
+    }
+}
+
+/// Uniform white noise from an xorshift32, the same sequence for the
+/// same seed.
+pub struct WhiteNoise {
+    state: u32,
+}
+
+impl WhiteNoise {
+    pub fn new(seed: u32) -> Self {
+        // xorshift never leaves 0.
+        Self { state: seed.max(1) }
+    }
+}
+
+impl Default for WhiteNoise {
+    fn default() -> Self {
+        Self::new(0x9E37_79B9)
+    }
+}
+
+impl Generator for WhiteNoise {
+    fn next_sample(&mut self) -> f32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state >> 8) as f32 / (1u32 << 23) as f32 - 1.0
+    }
+}
+
+/// Rows of the Voss–McCartney pink noise generator. Each adds an octave
+/// of -3 dB/octave slope below the one before; 16 reach under 1 Hz at
+/// 48 kHz.
+const PINK_ROWS: usize = 16;
+
+/// Pink noise (-3 dB per octave) by the Voss–McCartney method: rows of
+/// white noise, row `n` redrawn every `2^n` samples, summed with a fresh
+/// white sample each time.
+pub struct PinkNoise {
+    white: WhiteNoise,
+    rows: [f32; PINK_ROWS],
+    sum: f32,
+    counter: u32,
+}
+
+impl PinkNoise {
+    pub fn new(seed: u32) -> Self {
+        let mut white = WhiteNoise::new(seed);
+        let mut rows = [0.0; PINK_ROWS];
+        white.fill(&mut rows);
+        let sum = rows.iter().sum();
+        Self { white, rows, sum, counter: 0 }
+    }
+}
+
+impl Default for PinkNoise {
+    fn default() -> Self {
+        Self::new(0x9E37_79B9)
+    }
+}
+
+impl Generator for PinkNoise {
+    fn next_sample(&mut self) -> f32 {
+        self.counter = self.counter.wrapping_add(1);
+        // One row per sample, the one of the counter's lowest set bit.
+        let row = (self.counter.trailing_zeros() as usize).min(PINK_ROWS - 1);
+        let fresh = self.white.next_sample();
+        self.sum += fresh - self.rows[row];
+        self.rows[row] = fresh;
+        (self.sum + self.white.next_sample()) / (PINK_ROWS + 1) as f32
+    }
+}
+
+/// Logarithmic sine sweep from `start_hz` to `end_hz` in `duration`
+/// seconds, equal time per octave. The phase is accumulated, so the sweep
+/// is continuous, and it starts over from `start_hz` after `duration`
+/// without a jump.
+pub struct LogSweep {
+    sample_rate: f32,
+    start_hz: f32,
+    // ln(end / start)
+    octaves_ln: f32,
+    len: usize,
+    position: usize,
+    phase: f32,
+}
+
+impl LogSweep {
+    pub fn new(start_hz: f32, end_hz: f32, duration_secs: f32, sample_rate: u32) -> Self {
+        // Kept below Nyquist, where the sweep would fold back down.
+        let nyquist = sample_rate.max(2) as f32 / 2.0;
+        let start_hz = start_hz.clamp(1.0, nyquist);
+        let end_hz = end_hz.clamp(1.0, nyquist);
+        Self {
+            sample_rate: sample_rate.max(1) as f32,
+            start_hz,
+            octaves_ln: (end_hz / start_hz).ln(),
+            len: ((duration_secs * sample_rate as f32) as usize).max(1),
+            position: 0,
+            phase: 0.0,
+        }
+    }
+
+    /// Frequency of the next sample.
+    pub fn frequency(&self) -> f32 {
+        self.start_hz * (self.octaves_ln * self.position as f32 / self.len as f32).exp()
+    }
+}
+
+impl Generator for LogSweep {
+    fn next_sample(&mut self) -> f32 {
+        let sample = self.phase.sin();
+        self.phase = (self.phase + 2.0 * PI * self.frequency() / self.sample_rate) % (2.0 * PI);
+        self.position = (self.position + 1) % self.len;
+        sample
+    }
+}
+
+/// A single full-scale sample, then silence.
+#[derive(Default)]
+pub struct Impulse {
+    fired: bool,
+}
+
+impl Generator for Impulse {
+    fn next_sample(&mut self) -> f32 {
+        if self.fired {
+            0.0
+        } else {
+            self.fired = true;
+            1.0
+        }
+    }
+}
@@ -0,0 +1,305 @@
+// Live spectrum frames and smoothing for realtime spectrum displays.
+//
+// Raw FFT frames jitter too much to read. `SmoothedSpectrum` follows each
+// bin with a fast rise and a slow fall, like the ballistics of a hardware
+// RTA, and can keep decaying peak markers on top. `SpectrumFrame` is what
+// `stream::spectrum_stream` delivers, and sums its bins into fractional
+// octave bands on request.
+use std::sync::Arc;
+
+use crate::analysis::linear_to_db;
+
+/// Lowest band edge of `SpectrumFrame::octave_bands`.
+pub const BANDS_MIN_FREQ: f32 = 20.0;
+/// Equivalent noise bandwidth of the Hann window in bins: how much a tone
+/// smeared over neighbouring bins adds up to too much when summed.
+const HANN_ENBW: f32 = 1.5;
+
+/// Magnitude spectrum of one Hann-windowed frame of live input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpectrumFrame {
+    /// Counts from 0; frames start a hop apart.
+    pub number: u64,
+    pub sample_rate: u32,
+    /// Frequency of each bin in Hz, shared by all frames of a stream.
+    pub frequencies: Arc<[f32]>,
+    /// Level of each bin in dBFS; a full-scale sine on a bin reads 0.
+    pub magnitudes_db: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BandLevel {
+    pub low: f32,
+    pub center: f32,
+    pub high: f32,
+    /// Power sum of the band's bins, in dBFS; a full-scale sine in the
+    /// band reads 0.
+    pub level_db: f32,
+}
+
+impl SpectrumFrame {
+    /// Bands of 1/`fraction` octave (3 for third octaves) from
+    /// `BANDS_MIN_FREQ` up to Nyquist. A band narrower than a bin at the
+    /// bottom takes the level of the bin its centre falls in.
+    pub fn octave_bands(&self, fraction: u32) -> Vec<BandLevel> {
+        let nyquist = self.sample_rate as f32 / 2.0;
+        let bin_width = match self.frequencies.get(1) {
+            Some(&f) if f > 0.0 => f,
+            _ => return Vec::new(),
+        };
+        let step = 2.0f32.powf(1.0 / fraction.max(1) as f32);
+        let mut bands = Vec::new();
+        let mut low = BANDS_MIN_FREQ;
+        while low < nyquist {
+            let high = (low * step).min(nyquist);
+            let center = (low * high).sqrt();
+            let first = (low / bin_width).ceil() as usize;
+            let last = ((high / bin_width).ceil() as usize).min(self.magnitudes_db.len());
+            let power: f32 = if first < last {
+                self.magnitudes_db[first..last].iter().map(|db| 10.0f32.powf(db / 10.0)).sum::<f32>() / HANN_ENBW
+            } else {
+                let bin = ((center / bin_width).round() as usize).min(self.magnitudes_db.len() - 1);
+                10.0f32.powf(self.magnitudes_db[bin] / 10.0)
+            };
+            bands.push(BandLevel { low, center, high, level_db: linear_to_db(power.sqrt()) });
+            low = high;
+        }
+        bands
+    }
+}
+
+/// Per-bin ballistics for a stream of spectrum frames, in dB.
+///
+/// Nothing allocates after construction, so `update` can run on the audio
+/// or UI thread once per analysis frame.
+#[derive(Debug, Clone)]
+pub struct SmoothedSpectrum {
+    values: Vec<f32>,
+    peaks: Vec<f32>,
+    // Frames left before each peak starts to decay.
+    hold_left: Vec<u32>,
+    initialized: bool,
+    attack_ms: f32,
+    release_ms: f32,
+    frame_rate: f32,
+    attack_coeff: f32,
+    release_coeff: f32,
+    peak_hold: Option<PeakHold>,
+    hold_frames: u32,
+    decay_per_frame: f32,
+}
+
+/// How long peak markers stay put, and how fast they fall afterwards.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeakHold {
+    pub hold_ms: f32,
+    pub decay_db_per_second: f32,
+}
+
+// One-pole coefficient reaching 1 - 1/e of a step after `time_ms`.
+fn coefficient(time_ms: f32, frame_rate: f32) -> f32 {
+    if time_ms <= 0.0 {
+        0.0
+    } else {
+        (-1.0 / (time_ms * 0.001 * frame_rate)).exp()
+    }
+}
+
+impl SmoothedSpectrum {
+    /// `frame_rate` is the number of `update` calls per second.
+    pub fn new(num_bins: usize, attack_ms: f32, release_ms: f32, frame_rate: f32) -> Self {
+        let mut spectrum = Self {
+            values: vec![f32::NEG_INFINITY; num_bins],
+            peaks: vec![f32::NEG_INFINITY; num_bins],
+            hold_left: vec![0; num_bins],
+            initialized: false,
+            attack_ms,
+            release_ms,
+            frame_rate,
+            attack_coeff: 0.0,
+            release_coeff: 0.0,
+            peak_hold: None,
+            hold_frames: 0,
+            decay_per_frame: 0.0,
+        };
+        spectrum.set_frame_rate(frame_rate);
+        spectrum
+    }
+
+    pub fn with_peak_hold(mut self, peak_hold: PeakHold) -> Self {
+        self.peak_hold = Some(peak_hold);
+        self.set_frame_rate(self.frame_rate);
+        self
+    }
+
+    /// Recomputes the coefficients, e.g. after the FFT size or hop changed.
+    /// The current values are kept.
+    pub fn set_frame_rate(&mut self, frame_rate: f32) {
+        self.frame_rate = frame_rate.max(f32::MIN_POSITIVE);
+        self.attack_coeff = coefficient(self.attack_ms, self.frame_rate);
+        self.release_coeff = coefficient(self.release_ms, self.frame_rate);
+        if let Some(peak_hold) = self.peak_hold {
+            self.hold_frames = (peak_hold.hold_ms * 0.001 * self.frame_rate).round() as u32;
+            self.decay_per_frame = peak_hold.decay_db_per_second / self.frame_rate;
+        }
+    }
+
+    pub fn frame_rate(&self) -> f32 {
+        self.frame_rate
+    }
+
+    pub fn num_bins(&self) -> usize {
+        self.values.len()
+    }
+
+    /// Feeds one frame of levels in dB, one per bin. The first frame is
+    /// taken as is.
+    pub fn update(&mut self, frame: &[f32]) {
+        debug_assert_eq!(frame.len(), self.values.len(), "frame has the wrong number of bins");
+        if !self.initialized {
+            for (value, &level) in self.values.iter_mut().zip(frame) {
+                *value = level;
+            }
+            self.initialized = true;
+        } else {
+            for (value, &level) in self.values.iter_mut().zip(frame) {
+                let coeff = if level > *value { self.attack_coeff } else { self.release_coeff };
+                *value = level + coeff * (*value - level);
+            }
+        }
+
+        if self.peak_hold.is_some() {
+            for ((peak, hold_left), &level) in self.peaks.iter_mut().zip(self.hold_left.iter_mut()).zip(frame) {
+                if level >= *peak {
+                    *peak = level;
+                    *hold_left = self.hold_frames;
+                } else if *hold_left > 0 {
+                    *hold_left -= 1;
+                } else {
+                    *peak = (*peak - self.decay_per_frame).max(level);
+                }
+            }
+        }
+    }
+
+    /// Smoothed level of each bin in dB.
+    pub fn values(&self) -> &[f32] {
+        &self.values
+    }
+
+    /// Peak markers in dB; negative infinity without peak hold.
+    pub fn peaks(&self) -> &[f32] {
+        &self.peaks
+    }
+
+    pub fn reset(&mut self) {
+        self.values.fill(f32::NEG_INFINITY);
+        self.peaks.fill(f32::NEG_INFINITY);
+        self.hold_left.fill(0);
+        self.initialized = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 100 frames a second: 5 frames of attack and 50 of release.
+    fn smoothed() -> SmoothedSpectrum {
+        SmoothedSpectrum::new(3, 50.0, 500.0, 100.0)
+    }
+
+    // Frames until `values` first gets past 1 - 1/e of a step from `from` to `to`.
+    fn time_constant(spectrum: &mut SmoothedSpectrum, bin: usize, frame: &[f32], from: f32, to: f32) -> usize {
+        let target = to + (from - to) / std::f32::consts::E;
+        (1..1000)
+            .find(|_| {
+                spectrum.update(frame);
+                (spectrum.values()[bin] - target) * (to - from).signum() >= -1e-4
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn bins_rise_fast_and_fall_slowly() {
+        // Bin 0 steps up, bin 1 down, bin 2 stays where it is.
+        let mut spectrum = smoothed();
+        spectrum.update(&[-80.0, 0.0, -40.0]);
+        assert_eq!(spectrum.values(), [-80.0, 0.0, -40.0]);
+        spectrum.update(&[0.0, -80.0, -40.0]);
+        let (rise, fall) = (spectrum.values()[0], spectrum.values()[1]);
+        assert!((rise - -80.0 * (-1.0f32 / 5.0).exp()).abs() < 1e-3, "rose to {rise}");
+        assert!((fall - -80.0 * (1.0 - (-1.0f32 / 50.0).exp())).abs() < 1e-3, "fell to {fall}");
+        assert_eq!(spectrum.values()[2], -40.0);
+
+        let mut spectrum = smoothed();
+        spectrum.update(&[-80.0, 0.0, -40.0]);
+        assert_eq!(time_constant(&mut spectrum.clone(), 0, &[0.0, -80.0, -40.0], -80.0, 0.0), 5);
+        assert_eq!(time_constant(&mut spectrum, 1, &[0.0, -80.0, -40.0], 0.0, -80.0), 50);
+    }
+
+    #[test]
+    fn alternating_frames_settle_near_the_loud_level() {
+        // Loud and quiet frames in turn: the fast attack catches every loud
+        // frame, the slow release hardly lets go in between.
+        let mut spectrum = SmoothedSpectrum::new(2, 50.0, 500.0, 100.0);
+        spectrum.update(&[-80.0, -80.0]);
+        for i in 0..400 {
+            let (loud, quiet) = (-10.0, -70.0);
+            let frame = if i % 2 == 0 { [loud, quiet] } else { [quiet, loud] };
+            spectrum.update(&frame);
+        }
+        let (a, r) = ((-1.0f32 / 5.0).exp(), (-1.0f32 / 50.0).exp());
+        // Steady state right after a quiet frame: v = q + r (l + a (v - l) - q).
+        let after_quiet = (-70.0 * (1.0 - r) + r * -10.0 * (1.0 - a)) / (1.0 - r * a);
+        let after_loud = -10.0 + a * (after_quiet - -10.0);
+        assert!((spectrum.values()[0] - after_quiet).abs() < 0.01, "{} against {}", spectrum.values()[0], after_quiet);
+        assert!((spectrum.values()[1] - after_loud).abs() < 0.01, "{} against {}", spectrum.values()[1], after_loud);
+        assert!(after_quiet > -25.0 && after_loud > -20.0);
+    }
+
+    #[test]
+    fn a_new_frame_rate_rescales_the_time_constants() {
+        let mut spectrum = smoothed();
+        spectrum.update(&[-80.0, 0.0, -40.0]);
+        spectrum.set_frame_rate(50.0);
+        assert_eq!(spectrum.frame_rate(), 50.0);
+        // The values are kept, and 50 ms is now 2.5 frames.
+        assert_eq!(spectrum.values(), [-80.0, 0.0, -40.0]);
+        for _ in 0..5 {
+            spectrum.update(&[0.0, -80.0, -40.0]);
+        }
+        assert!((spectrum.values()[0] - -80.0 * (-2.0f32).exp()).abs() < 1e-3);
+        assert!((spectrum.values()[1] - -80.0 * (1.0 - (-0.2f32).exp())).abs() < 1e-3);
+    }
+
+    #[test]
+    fn peaks_hold_then_decay_at_the_set_rate() {
+        // Held for 20 frames, then falling 0.2 dB a frame.
+        let hold = PeakHold { hold_ms: 200.0, decay_db_per_second: 20.0 };
+        let mut spectrum = SmoothedSpectrum::new(2, 0.0, 0.0, 100.0).with_peak_hold(hold);
+        assert_eq!(smoothed().peaks(), [f32::NEG_INFINITY; 3]);
+        spectrum.update(&[0.0, -20.0]);
+        for frame in 1..=20 {
+            spectrum.update(&[-60.0, -30.0]);
+            assert_eq!(spectrum.peaks(), [0.0, -20.0], "held at frame {frame}");
+        }
+        for frame in 1..=45 {
+            spectrum.update(&[-60.0, -30.0]);
+            let decayed = -0.2 * frame as f32;
+            assert!((spectrum.peaks()[0] - decayed).abs() < 1e-3, "{} at frame {frame}", spectrum.peaks()[0]);
+            // The peak never falls below the level under it.
+            assert!((spectrum.peaks()[1] - (-20.0 + decayed).max(-30.0)).abs() < 1e-3);
+        }
+        // A higher level takes over and starts a new hold.
+        spectrum.update(&[-3.0, -30.0]);
+        spectrum.update(&[-60.0, -30.0]);
+        assert_eq!(spectrum.peaks()[0], -3.0);
+        assert_eq!(spectrum.values(), [-60.0, -30.0]);
+
+        spectrum.reset();
+        assert_eq!(spectrum.peaks(), [f32::NEG_INFINITY; 2]);
+        spectrum.update(&[-50.0, -50.0]);
+        assert_eq!((spectrum.values(), spectrum.peaks()), (&[-50.0, -50.0][..], &[-50.0, -50.0][..]));
+    }
+}
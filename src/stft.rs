@@ -0,0 +1,231 @@
+// Short-time Fourier transform with overlap-add resynthesis.
+//
+// `stft` windows the signal and keeps the non-negative frequency bins of
+// each frame (fft_size / 2 + 1). `istft` mirrors them back, inverse
+// transforms, and overlap-adds without a synthesis window, dividing by the
+// constant overlap-add sum of the analysis window. That is only exact when
+// the window/hop pair satisfies COLA, which `verify_cola` checks; `istft`
+// hands its report back with the samples.
+use std::fmt;
+
+use rustfft::{num_complex::Complex, FftPlanner};
+
+/// Ripple up to which a window/hop pair is close enough to COLA that the
+/// modulation at the hop rate won't be heard; for `ColaReport::passes`.
+pub const COLA_RIPPLE_WARN_DB: f32 = 0.1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StftError {
+    ZeroHop,
+}
+
+impl fmt::Display for StftError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StftError::ZeroHop => write!(f, "The hop must be at least one sample"),
+        }
+    }
+}
+
+impl std::error::Error for StftError {}
+
+/// Periodic Hann window, the variant that is COLA at hops of N/2, N/4, ...
+pub fn hann_window(size: usize) -> Vec<f32> {
+    (0..size)
+        .map(|i| 0.5 * (1.0 - (2.0 * std::f32::consts::PI * i as f32 / size as f32).cos()))
+        .collect()
+}
+
+/// Result of `verify_cola`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColaReport {
+    pub hop: usize,
+    /// Smallest and largest steady-state overlap-add sum.
+    pub min: f32,
+    pub max: f32,
+    /// The constant the sum should have, `sum(window) / hop`.
+    pub mean: f32,
+    /// `20 * log10(max / min)`; infinite when the sum drops to zero.
+    pub ripple_db: f32,
+}
+
+impl ColaReport {
+    pub fn passes(&self, tolerance_db: f32) -> bool {
+        self.ripple_db <= tolerance_db
+    }
+}
+
+/// Checks the constant-overlap-add property of `window` at `hop`.
+///
+/// Pass the window that actually gets overlap-added: the analysis window
+/// for plain OLA (what `istft` does), or the product of the analysis and
+/// synthesis windows for a weighted overlap-add scheme.
+pub fn verify_cola(window: &[f32], hop: usize) -> Result<ColaReport, StftError> {
+    if hop == 0 {
+        return Err(StftError::ZeroHop);
+    }
+    Ok(cola_report(window, hop))
+}
+
+// `verify_cola` for a hop already known to be positive.
+fn cola_report(window: &[f32], hop: usize) -> ColaReport {
+    // In steady state the overlap-add sum is periodic in the hop, so one
+    // period of it covers every position.
+    let sums: Vec<f32> = (0..hop)
+        .map(|offset| window.iter().skip(offset).step_by(hop).sum())
+        .collect();
+
+    let min = sums.iter().copied().fold(f32::INFINITY, f32::min);
+    let max = sums.iter().copied().fold(0.0, f32::max);
+    let ripple_db = if min > 0.0 {
+        20.0 * (max / min).log10()
+    } else {
+        f32::INFINITY
+    };
+
+    ColaReport {
+        hop,
+        min,
+        max,
+        mean: window.iter().sum::<f32>() / hop as f32,
+        ripple_db,
+    }
+}
+
+/// Padding added in front of the signal so that its first sample already
+/// sits under fully overlapped frames.
+fn lead_padding(window_len: usize, hop: usize) -> usize {
+    window_len.saturating_sub(hop)
+}
+
+/// Analyses `samples` into frames of `window.len() / 2 + 1` bins.
+pub fn stft(samples: &[f32], window: &[f32], hop: usize) -> Vec<Vec<Complex<f32>>> {
+    let n = window.len();
+    assert!(n > 0 && hop > 0, "window and hop must be non-empty");
+
+    let lead = lead_padding(n, hop);
+    let padded_len = lead + samples.len() + lead;
+    let num_frames = padded_len.saturating_sub(n).div_ceil(hop) + 1;
+
+    let mut planner = FftPlanner::new();
+    let fft = planner.plan_fft_forward(n);
+    let mut buffer = vec![Complex { re: 0.0, im: 0.0 }; n];
+
+    (0..num_frames)
+        .map(|frame| {
+            let start = frame * hop;
+            for (i, bin) in buffer.iter_mut().enumerate() {
+                // Index into the original signal, accounting for the padding.
+                let sample = (start + i)
+                    .checked_sub(lead)
+                    .and_then(|j| samples.get(j))
+                    .copied()
+                    .unwrap_or(0.0);
+                *bin = Complex { re: sample * window[i], im: 0.0 };
+            }
+            fft.process(&mut buffer);
+            buffer[..n / 2 + 1].to_vec()
+        })
+        .collect()
+}
+
+/// Resynthesizes `output_len` samples from frames produced by `stft`,
+/// along with the COLA report of the window/hop pair: unless it
+/// `passes(COLA_RIPPLE_WARN_DB)`, expect modulation at the hop rate.
+pub fn istft(frames: &[Vec<Complex<f32>>], window: &[f32], hop: usize, output_len: usize) -> (Vec<f32>, ColaReport) {
+    let n = window.len();
+    assert!(n > 0 && hop > 0, "window and hop must be non-empty");
+    let cola = cola_report(window, hop);
+
+    let lead = lead_padding(n, hop);
+    let mut output = vec![0.0; lead + output_len + n];
+
+    let mut planner = FftPlanner::new();
+    let ifft = planner.plan_fft_inverse(n);
+    let mut buffer = vec![Complex { re: 0.0, im: 0.0 }; n];
+    let scale = 1.0 / (n as f32 * cola.mean);
+
+    for (frame_index, frame) in frames.iter().enumerate() {
+        // Rebuild the full spectrum from the non-negative bins.
+        for (k, bin) in buffer.iter_mut().enumerate() {
+            *bin = if k <= n / 2 {
+                frame[k]
+            } else {
+                frame[n - k].conj()
+            };
+        }
+        ifft.process(&mut buffer);
+
+        let start = frame_index * hop;
+        if start >= output.len() {
+            break;
+        }
+        for (out, bin) in output[start..].iter_mut().zip(&buffer) {
+            *out += bin.re * scale;
+        }
+    }
+
+    output.drain(..lead);
+    output.truncate(output_len);
+    (output, cola)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The overlap-add sum of `window` by brute force, over enough frames
+    // that the middle is in steady state; its ripple in dB.
+    fn brute_force_ripple_db(window: &[f32], hop: usize) -> f32 {
+        let frames = 4 * window.len() / hop + 1;
+        let mut sum = vec![0.0f32; (frames - 1) * hop + window.len()];
+        for frame in 0..frames {
+            for (s, &w) in sum[frame * hop..].iter_mut().zip(window) {
+                *s += w;
+            }
+        }
+        let steady = &sum[window.len()..sum.len() - window.len()];
+        let min = steady.iter().copied().fold(f32::INFINITY, f32::min);
+        let max = steady.iter().copied().fold(0.0, f32::max);
+        20.0 * (max / min).log10()
+    }
+
+    #[test]
+    fn hann_at_half_and_quarter_hops_is_cola() {
+        let window = hann_window(1024);
+        for hop in [512, 256] {
+            let report = verify_cola(&window, hop).unwrap();
+            assert!(report.passes(1e-3), "hop {}: {:?}", hop, report);
+            assert!((report.min - report.mean).abs() < 1e-3 && (report.max - report.mean).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn hann_at_sixty_percent_hop_reports_its_ripple() {
+        let window = hann_window(1000);
+        let report = verify_cola(&window, 600).unwrap();
+        assert!(!report.passes(COLA_RIPPLE_WARN_DB), "{:?}", report);
+        let expected = brute_force_ripple_db(&window, 600);
+        assert!((report.ripple_db - expected).abs() < 1e-3, "{} dB, expected {} dB", report.ripple_db, expected);
+    }
+
+    #[test]
+    fn zero_hop_is_an_error() {
+        assert_eq!(verify_cola(&hann_window(64), 0), Err(StftError::ZeroHop));
+    }
+
+    #[test]
+    fn istft_reconstructs_and_reports_the_pair() {
+        let samples: Vec<f32> = (0..5000).map(|i| (i as f32 * 0.013).sin() * 0.5 + (i as f32 * 0.31).cos() * 0.2).collect();
+        let window = hann_window(512);
+        let (output, report) = istft(&stft(&samples, &window, 128), &window, 128, samples.len());
+        assert_eq!(report, verify_cola(&window, 128).unwrap());
+        assert_eq!(output.len(), samples.len());
+        for (i, (a, b)) in output.iter().zip(&samples).enumerate() {
+            assert!((a - b).abs() < 1e-4, "sample {}: {} vs {}", i, a, b);
+        }
+
+        let (_, report) = istft(&stft(&samples, &window, 300), &window, 300, samples.len());
+        assert!(!report.passes(COLA_RIPPLE_WARN_DB));
+    }
+}
@@ -1,20 +1,3786 @@
-use cpal::{Device, Stream, BuildStreamError};
-use cpal::traits::DeviceTrait;
+use cpal::{BufferSize, BuildStreamError, Device, FromSample, SampleFormat, SizedSample, Stream, StreamConfig};
+use cpal::traits::{DeviceTrait, StreamTrait};
+use std::cell::{Cell, UnsafeCell};
+use std::f32::consts::PI;
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-#[allow(dead_code)]
-pub fn make_input_stream(device: &Device) -> Result<Stream, BuildStreamError> {
-    let config = device.default_input_config().unwrap();
-    let stream = device.build_input_stream(
-        &config.config(),
-        move |data: &[f32], _: &cpal::InputCallbackInfo| {
-            println!("{:?}", &data[0..5]);
+use crate::adapter::{AudioSpec, ChannelMapper, FormatAdapter};
+use crate::analysis::{cross_correlate, linear_to_db};
+use crate::devices::{
+    check_buffer_size, check_config, min_latency_config, preferred_format, supported_sample_formats, DeviceError,
+    Direction,
+};
+use crate::dsp::{calculate_rms, peak_detection};
+use crate::effect::{debug_assert_finite, Effect, EffectChain};
+use crate::input::{samples_to_f32, InputStreamBuilder, NegotiatedConfig, StreamControl, StreamHandle};
+use crate::limiter::{db_to_linear, safety_defaults, OutputGuard, SafetyLimiterConfig};
+use crate::meter_bus::MeterPublisher;
+use crate::read_wav::open_chunked;
+use crate::realtime::{callback_logger, callback_scope, log_stream_error, scratch_len, CallbackLog};
+use crate::shutdown;
+use crate::signal::{Generator, Impulse, LogSweep, PinkNoise, Sine, WhiteNoise};
+use crate::session::{error_action, record_device_to_wav, ErrorAction, RecordError, RecordSettings};
+use crate::spectrum::SpectrumFrame;
+use crate::stft::hann_window;
+
+/// What to open a stream with; `None` leaves the choice to the device
+/// default config.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct StreamOptions {
+    pub sample_rate: Option<u32>,
+    /// Frames per callback. A size the backend refuses falls back to its
+    /// default, with a warning.
+    pub buffer_frames: Option<u32>,
+    pub channels: Option<u16>,
+}
+
+impl StreamOptions {
+    // `config` with the options that are set applied over it.
+    fn apply(&self, config: &StreamConfig) -> StreamConfig {
+        StreamConfig {
+            channels: self.channels.unwrap_or(config.channels),
+            sample_rate: self.sample_rate.map_or(config.sample_rate, cpal::SampleRate),
+            buffer_size: self.buffer_frames.map_or(BufferSize::Default, BufferSize::Fixed),
+        }
+    }
+}
+
+/// Input stream handing every block to `callback`, opened with `options`
+/// over the device default config. The device is opened in f32, i16 or
+/// u16, whichever it offers first, and the samples arrive as f32 in -1..1
+/// either way. A buffer size the device can't do is clamped to its range,
+/// and the negotiated config says what was granted. See
+/// `input::InputStreamBuilder` for more control.
+pub fn make_input_stream<F>(
+    device: &Device,
+    options: &StreamOptions,
+    callback: F,
+) -> Result<(StreamHandle, NegotiatedConfig), StreamError>
+where
+    F: FnMut(&[f32], &cpal::InputCallbackInfo) + Send + 'static,
+{
+    // One attempt, so the callback moves straight into the stream.
+    input_builder(device, options, options.buffer_frames).on_data_with_info(callback).build()
+}
+
+// The builder `make_input_stream` opens, asking for `buffer_frames`.
+fn input_builder<'d>(device: &'d Device, options: &StreamOptions, buffer_frames: Option<u32>) -> InputStreamBuilder<'d> {
+    let mut builder = InputStreamBuilder::new(device);
+    if let Some(format) = preferred_format(device, Direction::Input, &CALLBACK_FORMATS) {
+        builder = builder.sample_format(format);
+    }
+    if let Some(rate) = options.sample_rate {
+        builder = builder.sample_rate(rate);
+    }
+    if let Some(channels) = options.channels {
+        builder = builder.channels(channels);
+    }
+    if let Some(frames) = buffer_frames {
+        builder = builder.buffer_size(frames);
+    }
+    builder
+}
+
+/// How long `frames` last at `sample_rate`.
+pub fn frames_to_duration(frames: u64, sample_rate: u32) -> Duration {
+    Duration::from_nanos((frames as u128 * 1_000_000_000 / sample_rate.max(1) as u128) as u64)
+}
+
+/// `make_input_stream` printing the length and RMS of every block, from
+/// the logging thread.
+pub fn make_debug_input_stream(
+    device: &Device,
+    options: &StreamOptions,
+) -> Result<(StreamHandle, NegotiatedConfig), StreamError> {
+    let logger = callback_logger();
+    make_input_stream(device, options, move |data, _| {
+        logger.log(CallbackLog::Block { samples: data.len(), rms: calculate_rms(data) });
+    })
+}
+
+// Averages interleaved `frames` of `channels` into `mono` and returns how
+// many it wrote; `mono` has to hold a frame per `channels` samples.
+fn mix_to_mono(frames: &[f32], channels: usize, mono: &mut [f32]) -> usize {
+    let mut len = 0;
+    for (out, frame) in mono.iter_mut().zip(frames.chunks(channels.max(1))) {
+        *out = frame.iter().sum::<f32>() / frame.len() as f32;
+        len += 1;
+    }
+    len
+}
+
+/// Mono input stream running `chain` in order on every block and sending
+/// the result to `sink`. More channels than the device grants one are
+/// averaged to mono first, since the effects are mono.
+///
+/// The callback never allocates: the chain works in a scratch buffer sized
+/// up front and the results are queued in pooled buffers, as with
+/// `input_to_channel`. A thread of its own copies each of them into the
+/// `Vec` that goes to `sink` and returns the buffer to the pool, until the
+/// stream is dropped or the sink's receiver is. Blocks the callback had no
+/// buffer for are counted in `ChannelInput::stats`.
+pub fn make_processed_input_stream(
+    device: &Device,
+    chain: Vec<Box<dyn Effect>>,
+    sink: Sender<Vec<f32>>,
+) -> Result<ChannelInput, StreamError> {
+    let builder = InputStreamBuilder::new(device).channels(1);
+    let negotiated = builder.negotiate()?;
+    let channels = negotiated.channels.max(1) as usize;
+    let frames = negotiated.buffer_size.map_or(0, |frames| frames as usize);
+    let mut scratch = vec![0.0f32; scratch_len(1)];
+    let mut chain = EffectChain::new(chain);
+    let (mut sender, blocks) = block_channel(negotiated.sample_rate, 1, frames);
+    let (recycle, counters, block_samples) = (sender.recycle.clone(), Arc::clone(&sender.counters), sender.block_samples);
+    let (handle, config) = builder
+        .on_data_with_info(move |data, info| {
+            let timestamp = info.timestamp();
+            process_and_send(data, channels, &mut scratch, &mut chain, &mut sender, timestamp.capture, timestamp.callback);
+        })
+        .build()?;
+    let pool = recycle.clone();
+    std::thread::spawn(move || forward_blocks(blocks, &pool, &sink));
+    Ok(ChannelInput { handle, config, recycle, counters, block_samples })
+}
+
+// The callback of `make_processed_input_stream`: mixes interleaved `data`
+// to mono in `scratch` a piece at a time, runs `chain` on each piece and
+// queues it.
+fn process_and_send<I: CaptureInstant>(
+    data: &[f32],
+    channels: usize,
+    scratch: &mut [f32],
+    chain: &mut EffectChain,
+    sender: &mut BlockSender<I>,
+    capture: I,
+    callback: I,
+) {
+    let mut offset = 0;
+    for chunk in data.chunks(channels * scratch.len()) {
+        let len = mix_to_mono(chunk, channels, scratch);
+        chain.process_block(&mut scratch[..len]);
+        sender.send_at(&scratch[..len], capture, callback, offset);
+        offset += len as u64;
+    }
+}
+
+// Hands every block to `sink` in a `Vec` of its own and puts the pooled
+// buffer back, until the stream's end of `blocks` or the sink's receiver
+// is gone.
+fn forward_blocks<I>(blocks: Receiver<CapturedBlock<I>>, pool: &SyncSender<Vec<f32>>, sink: &Sender<Vec<f32>>) {
+    for block in blocks {
+        let samples = block.samples.clone();
+        let _ = pool.try_send(block.samples);
+        if sink.send(samples).is_err() {
+            return;
+        }
+    }
+}
+
+/// Level frames `make_metering_stream` holds for a slow reader before it
+/// drops new ones.
+const LEVEL_QUEUE_FRAMES: usize = 64;
+
+/// Levels of one callback block, over all its channels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LevelFrame {
+    /// dBFS; -inf for digital silence.
+    pub rms_db: f32,
+    pub peak_db: f32,
+    /// A sample reached full scale.
+    pub clipped: bool,
+    /// When the block arrived.
+    pub timestamp: Instant,
+}
+
+/// Input stream at the device default config sending the levels of every
+/// block. Frames arriving while `LEVEL_QUEUE_FRAMES` are unread are
+/// dropped, so a slow reader costs nothing but frames.
+pub fn make_metering_stream(device: &Device) -> Result<(StreamHandle, Receiver<LevelFrame>), StreamError> {
+    let (sender, frames) = mpsc::sync_channel(LEVEL_QUEUE_FRAMES);
+    let (handle, _) = make_input_stream(device, &StreamOptions::default(), move |data, _| {
+        let peak = peak_detection(data);
+        let _ = sender.try_send(LevelFrame {
+            rms_db: linear_to_db(calculate_rms(data)),
+            peak_db: linear_to_db(peak),
+            clipped: peak >= 1.0,
+            timestamp: Instant::now(),
+        });
+    })?;
+    Ok((handle, frames))
+}
+
+/// Blocks `input_to_channel` queues for the reader, and buffers it keeps
+/// ready for them.
+const CHANNEL_BLOCKS: usize = 8;
+/// Samples each pooled buffer holds at least; more if the granted buffer
+/// size needs it. Longer blocks are split.
+const CHANNEL_BLOCK_SAMPLES: usize = 8192;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ChannelStats {
+    pub sent: u64,
+    /// Blocks left out because the reader had `CHANNEL_BLOCKS` unread.
+    pub dropped: u64,
+    /// Blocks left out because every pooled buffer was still with the
+    /// reader, waiting for `ChannelInput::recycle`.
+    pub starved: u64,
+}
+
+#[derive(Debug, Default)]
+struct ChannelCounters {
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    starved: AtomicU64,
+}
+
+/// Instants of the clock a stream's timestamps come from.
+pub trait CaptureInstant: Copy {
+    /// `self` moved on by `duration`, or `self` if that can't be
+    /// represented.
+    fn after(self, duration: Duration) -> Self;
+}
+
+impl CaptureInstant for cpal::StreamInstant {
+    fn after(self, duration: Duration) -> Self {
+        self.add(duration).unwrap_or(self)
+    }
+}
+
+/// One block from `input_to_channel`. The instants are the backend's,
+/// and another clock's only where the blocks are made without a device.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CapturedBlock<I = cpal::StreamInstant> {
+    /// Interleaved.
+    pub samples: Vec<f32>,
+    pub channels: u16,
+    /// Frames the stream had captured before this block, counting blocks
+    /// that were dropped, so a gap shows up as a jump.
+    pub first_frame: u64,
+    /// When the device captured the first frame, as the backend reports it.
+    pub capture_instant: I,
+    /// When the callback was called, on the same clock.
+    pub callback_instant: I,
+}
+
+impl<I> CapturedBlock<I> {
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    /// Time of the first frame counted from the start of the stream.
+    pub fn offset(&self, sample_rate: u32) -> Duration {
+        frames_to_duration(self.first_frame, sample_rate)
+    }
+}
+
+/// The stream behind `input_to_channel`. Dropping it stops the stream.
+pub struct ChannelInput {
+    pub handle: StreamHandle,
+    pub config: NegotiatedConfig,
+    recycle: SyncSender<Vec<f32>>,
+    counters: Arc<ChannelCounters>,
+    block_samples: usize,
+}
+
+impl ChannelInput {
+    /// Hands a block back to the pool once read. The pool is all the
+    /// callback has to copy into, so blocks that are kept are eventually
+    /// missing from it. A buffer that lost its capacity isn't taken back.
+    pub fn recycle(&self, block: CapturedBlock) {
+        if block.samples.capacity() >= self.block_samples {
+            let _ = self.recycle.try_send(block.samples);
+        }
+    }
+
+    pub fn stats(&self) -> ChannelStats {
+        ChannelStats {
+            sent: self.counters.sent.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            starved: self.counters.starved.load(Ordering::Relaxed),
+        }
+    }
+}
+
+// The callback's end of a `ChannelInput`: copies blocks into pooled
+// buffers and queues them without blocking or allocating.
+struct BlockSender<I = cpal::StreamInstant> {
+    blocks: SyncSender<CapturedBlock<I>>,
+    pool: Receiver<Vec<f32>>,
+    recycle: SyncSender<Vec<f32>>,
+    counters: Arc<ChannelCounters>,
+    sample_rate: u32,
+    channels: u16,
+    block_samples: usize,
+    next_frame: u64,
+}
+
+impl BlockSender {
+    // Queues interleaved `samples` that start `offset` frames into the
+    // callback block `info` describes.
+    fn send(&mut self, samples: &[f32], info: &cpal::InputCallbackInfo, offset: u64) {
+        let timestamp = info.timestamp();
+        self.send_at(samples, timestamp.capture, timestamp.callback, offset);
+    }
+}
+
+impl<I: CaptureInstant> BlockSender<I> {
+    // `send` for a callback block captured at `capture` and handed over at
+    // `callback`, split to fit the pooled buffers. The frame counter only
+    // follows the samples, whatever the clock does.
+    fn send_at(&mut self, samples: &[f32], capture: I, callback: I, offset: u64) {
+        let channels = self.channels.max(1) as usize;
+        let mut offset = offset;
+        for part in samples.chunks(self.block_samples) {
+            let frames = (part.len() / channels) as u64;
+            let first_frame = self.next_frame;
+            self.next_frame += frames;
+            let Ok(mut buffer) = self.pool.try_recv() else {
+                self.counters.starved.fetch_add(1, Ordering::Relaxed);
+                offset += frames;
+                continue;
+            };
+            buffer.clear();
+            buffer.extend_from_slice(part);
+            let later = frames_to_duration(offset, self.sample_rate);
+            offset += frames;
+            let block = CapturedBlock {
+                samples: buffer,
+                channels: self.channels,
+                first_frame,
+                capture_instant: capture.after(later),
+                callback_instant: callback,
+            };
+            match self.blocks.try_send(block) {
+                Ok(()) => {
+                    self.counters.sent.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(TrySendError::Full(block)) | Err(TrySendError::Disconnected(block)) => {
+                    self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    let _ = self.recycle.try_send(block.samples);
+                }
+            }
+        }
+    }
+}
+
+// A pool of `CHANNEL_BLOCKS` buffers, each big enough for `buffer_frames`
+// of `channels` (0 when the backend decides) and `CHANNEL_BLOCK_SAMPLES`.
+fn block_channel<I>(sample_rate: u32, channels: u16, buffer_frames: usize) -> (BlockSender<I>, Receiver<CapturedBlock<I>>) {
+    let frame = channels.max(1) as usize;
+    let block_samples = (buffer_frames * frame).max(CHANNEL_BLOCK_SAMPLES / frame * frame).max(frame);
+    let (sender, blocks) = mpsc::sync_channel(CHANNEL_BLOCKS);
+    let (recycle, pool) = mpsc::sync_channel(CHANNEL_BLOCKS);
+    for _ in 0..CHANNEL_BLOCKS {
+        let _ = recycle.try_send(Vec::with_capacity(block_samples));
+    }
+    let sender = BlockSender {
+        blocks: sender,
+        pool,
+        recycle,
+        counters: Arc::new(ChannelCounters::default()),
+        sample_rate,
+        channels,
+        block_samples,
+        next_frame: 0,
+    };
+    (sender, blocks)
+}
+
+/// Input stream sending every block, interleaved f32 with its timestamps,
+/// to the receiver. Blocks are copied into buffers from a pool filled up
+/// front and topped up by `ChannelInput::recycle`, so the callback never
+/// allocates. A full channel or an empty pool drops the block instead of
+/// blocking the audio thread, counted in `ChannelInput::stats`.
+pub fn input_to_channel(
+    device: &Device,
+    options: &StreamOptions,
+) -> Result<(ChannelInput, Receiver<CapturedBlock>), StreamError> {
+    // Settled first, so the callback knows the channels it counts frames by.
+    let negotiated = input_builder(device, options, options.buffer_frames).negotiate()?;
+    let channels = negotiated.channels;
+    let options =
+        StreamOptions { sample_rate: Some(negotiated.sample_rate), channels: Some(channels), ..*options };
+    let frames = negotiated.buffer_size.map_or(0, |frames| frames as usize);
+    let (mut sender, blocks) = block_channel(negotiated.sample_rate, channels, frames);
+    let (recycle, counters, block_samples) = (sender.recycle.clone(), Arc::clone(&sender.counters), sender.block_samples);
+    let (handle, config) = make_input_stream(device, &options, move |data, info| sender.send(data, info, 0))?;
+    Ok((ChannelInput { handle, config, recycle, counters, block_samples }, blocks))
+}
+
+/// Longest wait for the next block before `record_fixed` gives up.
+const RECORD_STALL: Duration = Duration::from_secs(2);
+
+/// Interleaved audio from `record_fixed`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Recording {
+    pub samples: Vec<f32>,
+    pub sample_rate: u32,
+    pub channels: u16,
+}
+
+impl Recording {
+    pub fn frames(&self) -> usize {
+        self.samples.len() / self.channels.max(1) as usize
+    }
+
+    pub fn duration(&self) -> Duration {
+        frames_to_duration(self.frames() as u64, self.sample_rate)
+    }
+}
+
+#[derive(Debug)]
+pub enum RecordFixedError {
+    Stream(StreamError),
+    /// The input stopped delivering before `wanted`; what came is kept.
+    Stalled { recording: Recording, wanted: Duration },
+}
+
+impl fmt::Display for RecordFixedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordFixedError::Stream(e) => write!(f, "{}", e),
+            RecordFixedError::Stalled { recording, wanted } => write!(
+                f,
+                "The input stopped after {:.2} s of {:.2} s",
+                recording.duration().as_secs_f64(),
+                wanted.as_secs_f64()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RecordFixedError {}
+
+impl From<StreamError> for RecordFixedError {
+    fn from(e: StreamError) -> Self {
+        RecordFixedError::Stream(e)
+    }
+}
+
+/// Records exactly `duration` of `device` at its default config, for
+/// trying things out on real input. Blocks the channel dropped are filled
+/// with silence, so the length is right either way. If the input stops
+/// delivering for `RECORD_STALL`, the error holds what was recorded.
+pub fn record_fixed(device: &Device, duration: Duration) -> Result<Recording, RecordFixedError> {
+    let (input, blocks) = input_to_channel(device, &StreamOptions::default())?;
+    let (sample_rate, channels) = (input.config.sample_rate, input.config.channels);
+    let wanted = (duration.as_secs_f64() * sample_rate as f64).round() as usize * channels.max(1) as usize;
+    let mut samples = Vec::with_capacity(wanted);
+    while samples.len() < wanted {
+        let Ok(block) = blocks.recv_timeout(RECORD_STALL) else {
+            let recording = Recording { samples, sample_rate, channels };
+            return Err(RecordFixedError::Stalled { recording, wanted: duration });
+        };
+        let start = (block.first_frame as usize * channels.max(1) as usize).min(wanted);
+        if start > samples.len() {
+            samples.resize(start, 0.0);
+        }
+        let take = block.samples.len().min(wanted - samples.len());
+        samples.extend_from_slice(&block.samples[..take]);
+        input.recycle(block);
+    }
+    input.handle.stop();
+    Ok(Recording { samples, sample_rate, channels })
+}
+
+/// Records `device` into the WAV file at `path` for `duration`, at its
+/// default rate and channels. The callback only fills a ring buffer; this
+/// thread writes the file. If the device goes away the file is finalized
+/// and playable, and the result is `RecordError::Interrupted`. See
+/// `session::record_to_wav` for more options.
+pub fn record_to_wav(device: &Device, path: &str, duration: Duration) -> Result<(), RecordError> {
+    let settings = RecordSettings { max_duration: Some(duration), ..RecordSettings::default() };
+    record_device_to_wav(device, Path::new(path), &settings, &AtomicBool::new(false))?;
+    Ok(())
+}
+
+/// Output stream playing a sine of `freq_hz` on every channel at
+/// `amplitude`, clamped to 0..=1, opened with `options` over the device
+/// default config in f32, i16 or u16. Plays until dropped, and silence
+/// while paused. The config returned is the one it was opened with.
+pub fn make_output_stream(
+    device: &Device,
+    freq_hz: f32,
+    amplitude: f32,
+    options: &StreamOptions,
+) -> Result<(StreamHandle, StreamConfig), StreamError> {
+    let mut config = options.apply(&device.default_output_config()?.config());
+    let format = preferred_format(device, Direction::Output, &CALLBACK_FORMATS)
+        .ok_or_else(|| StreamError::Unsupported("the device offers none of f32, i16 or u16 samples".to_string()))?;
+    check_config(device, &config, format, Direction::Output)?;
+    let control = Arc::new(StreamControl::new());
+    let open = |config: &StreamConfig| {
+        let control = Arc::clone(&control);
+        match format {
+            SampleFormat::I16 => sine_stream::<i16>(device, config, freq_hz, amplitude, control),
+            SampleFormat::U16 => sine_stream::<u16>(device, config, freq_hz, amplitude, control),
+            _ => sine_stream::<f32>(device, config, freq_hz, amplitude, control),
+        }
+    };
+    let stream = match (open(&config), config.buffer_size) {
+        (Err(e), BufferSize::Fixed(frames)) => {
+            eprintln!("Warning: a buffer of {} frames was refused ({}), using the default", frames, e);
+            config.buffer_size = BufferSize::Default;
+            open(&config)?
+        }
+        (result, _) => result?,
+    };
+    stream.play()?;
+    Ok((StreamHandle::new(stream, control), config))
+}
+
+fn sine_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    freq_hz: f32,
+    amplitude: f32,
+    control: Arc<StreamControl>,
+) -> Result<Stream, BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels.max(1) as usize;
+    let step = 2.0 * PI * freq_hz / config.sample_rate.0 as f32;
+    let amplitude = amplitude.clamp(0.0, 1.0);
+    // Carried from one callback to the next, so blocks join without clicks.
+    let mut phase = 0.0f32;
+    let mut scratch = vec![0.0f32; scratch_len(channels)];
+    let mut guard = OutputGuard::with_defaults(config.sample_rate.0, config.channels);
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            callback_scope(|| {
+                if !control.begin_block(data.len() / channels) {
+                    data.fill(T::EQUILIBRIUM);
+                    return;
+                }
+                write_guarded(data, &mut scratch, &mut guard, |block| {
+                    for frame in block.chunks_mut(channels) {
+                        frame.fill(phase.sin() * amplitude);
+                        phase = (phase + step) % (2.0 * PI);
+                    }
+                });
+            })
+        },
+        log_stream_error(),
+        None,
+    )
+}
+
+#[derive(Debug)]
+pub enum StreamError {
+    DefaultConfig(cpal::DefaultStreamConfigError),
+    SupportedConfigs(cpal::SupportedStreamConfigsError),
+    Build(BuildStreamError),
+    Play(cpal::PlayStreamError),
+    /// The devices or sources can't be combined as requested.
+    Unsupported(String),
+    Device(DeviceError),
+    /// A measurement found nothing it could trust in the input.
+    NoSignal(String),
+}
+
+impl fmt::Display for StreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StreamError::DefaultConfig(e) => write!(f, "No usable stream config: {}", e),
+            StreamError::SupportedConfigs(e) => write!(f, "Cannot list stream configs: {}", e),
+            StreamError::Build(e) => write!(f, "Failed to build stream: {}", e),
+            StreamError::Play(e) => write!(f, "Failed to start stream: {}", e),
+            StreamError::Unsupported(reason) => write!(f, "Unsupported stream setup: {}", reason),
+            StreamError::Device(e) => write!(f, "{}", e),
+            StreamError::NoSignal(reason) => write!(f, "No usable signal: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for StreamError {}
+
+impl From<cpal::DefaultStreamConfigError> for StreamError {
+    fn from(e: cpal::DefaultStreamConfigError) -> Self {
+        StreamError::DefaultConfig(e)
+    }
+}
+
+impl From<cpal::SupportedStreamConfigsError> for StreamError {
+    fn from(e: cpal::SupportedStreamConfigsError) -> Self {
+        StreamError::SupportedConfigs(e)
+    }
+}
+
+impl From<BuildStreamError> for StreamError {
+    fn from(e: BuildStreamError) -> Self {
+        StreamError::Build(e)
+    }
+}
+
+impl From<DeviceError> for StreamError {
+    fn from(e: DeviceError) -> Self {
+        StreamError::Device(e)
+    }
+}
+
+impl From<cpal::PlayStreamError> for StreamError {
+    fn from(e: cpal::PlayStreamError) -> Self {
+        StreamError::Play(e)
+    }
+}
+
+/// Single-producer single-consumer queue for handing samples between
+/// callbacks. Allocated once; push and pop never lock or allocate.
+///
+/// Made with `ring_buffer`, which hands out the only `Producer` and the
+/// only `Consumer`. The queue itself just reports its fill and counts, so
+/// any thread can watch it.
+pub struct RingBuffer<T> {
+    buffer: Box<[UnsafeCell<T>]>,
+    // Total number of items ever popped / pushed. Their difference is the
+    // fill level; the slot of an index is `index % capacity`.
+    head: AtomicUsize,
+    tail: AtomicUsize,
+    overflows: AtomicU64,
+    underflows: AtomicU64,
+}
+
+// Slots between head and tail belong to the consumer, the rest to the
+// producer, and the indices are handed over with acquire/release. Only
+// the one `Producer` and the one `Consumer` can get at the slots.
+unsafe impl<T: Send> Sync for RingBuffer<T> {}
+
+/// A queue of `capacity` items, as its pushing and popping halves. Each
+/// half can move to another thread but can't be cloned or shared, so
+/// there is only ever one thread on either side:
+///
+/// ```
+/// use cpal_playbook::stream::ring_buffer;
+///
+/// let (mut producer, mut consumer) = ring_buffer::<f32>(4);
+/// std::thread::spawn(move || producer.push_slice(&[1.0, 2.0])).join().unwrap();
+/// let mut out = [0.0; 2];
+/// assert_eq!(consumer.pop_slice(&mut out), 2);
+/// ```
+///
+/// A second producer doesn't compile:
+///
+/// ```compile_fail,E0599
+/// let (producer, _consumer) = cpal_playbook::stream::ring_buffer::<f32>(4);
+/// let second = producer.clone();
+/// ```
+///
+/// and nor does lending one to another thread:
+///
+/// ```compile_fail,E0277
+/// let (producer, _consumer) = cpal_playbook::stream::ring_buffer::<f32>(4);
+/// let producer = &producer;
+/// std::thread::scope(|scope| {
+///     scope.spawn(move || producer.capacity());
+/// });
+/// ```
+pub fn ring_buffer<T: Copy + Default>(capacity: usize) -> (Producer<T>, Consumer<T>) {
+    let ring = Arc::new(RingBuffer {
+        buffer: (0..capacity.max(1)).map(|_| UnsafeCell::new(T::default())).collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+        overflows: AtomicU64::new(0),
+        underflows: AtomicU64::new(0),
+    });
+    (
+        Producer { ring: Arc::clone(&ring), _not_sync: PhantomData },
+        Consumer { ring, _not_sync: PhantomData },
+    )
+}
+
+impl<T: Copy + Default> RingBuffer<T> {
+    pub fn capacity(&self) -> usize {
+        self.buffer.len()
+    }
+
+    /// Items waiting to be popped.
+    pub fn available(&self) -> usize {
+        // Head first: it can only move towards the tail loaded after it.
+        let head = self.head.load(Ordering::Acquire);
+        self.tail.load(Ordering::Acquire) - head
+    }
+
+    // Safety: only one thread may push at a time.
+    unsafe fn push_slice(&self, items: &[T]) -> usize {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        let free = self.capacity() - (tail - head);
+        let count = items.len().min(free);
+        for (i, item) in items[..count].iter().enumerate() {
+            unsafe { *self.buffer[(tail + i) % self.capacity()].get() = *item };
+        }
+        self.tail.store(tail + count, Ordering::Release);
+        if count < items.len() {
+            self.overflows.fetch_add(1, Ordering::Relaxed);
+        }
+        count
+    }
+
+    // Safety: only one thread may pop at a time.
+    unsafe fn pop_slice(&self, out: &mut [T]) -> usize {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        let count = out.len().min(tail - head);
+        for (i, item) in out[..count].iter_mut().enumerate() {
+            *item = unsafe { *self.buffer[(head + i) % self.capacity()].get() };
+        }
+        self.head.store(head + count, Ordering::Release);
+        if count < out.len() {
+            self.underflows.fetch_add(1, Ordering::Relaxed);
+        }
+        count
+    }
+
+    /// Number of pushes that didn't fit completely.
+    pub fn overflows(&self) -> u64 {
+        self.overflows.load(Ordering::Relaxed)
+    }
+
+    /// Number of pops that came up short.
+    pub fn underflows(&self) -> u64 {
+        self.underflows.load(Ordering::Relaxed)
+    }
+}
+
+/// The pushing half of a `ring_buffer`. Derefs to the queue for its fill
+/// and counts.
+pub struct Producer<T> {
+    ring: Arc<RingBuffer<T>>,
+    // Send but not Sync: pushing takes `&mut self`, and no `&Producer` may
+    // reach a second thread either.
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl<T: Copy + Default> Producer<T> {
+    /// Pushes as much of `items` as fits and returns how many were taken.
+    /// Counts an overflow when some had to be dropped.
+    pub fn push_slice(&mut self, items: &[T]) -> usize {
+        // Safety: this is the only producer, and `&mut self` keeps it on
+        // one thread.
+        unsafe { self.ring.push_slice(items) }
+    }
+
+    /// The queue, for watching it from another thread.
+    pub fn ring(&self) -> Arc<RingBuffer<T>> {
+        Arc::clone(&self.ring)
+    }
+}
+
+impl<T> Deref for Producer<T> {
+    type Target = RingBuffer<T>;
+
+    fn deref(&self) -> &RingBuffer<T> {
+        &self.ring
+    }
+}
+
+/// The popping half of a `ring_buffer`. Derefs to the queue for its fill
+/// and counts.
+pub struct Consumer<T> {
+    ring: Arc<RingBuffer<T>>,
+    _not_sync: PhantomData<Cell<()>>,
+}
+
+impl<T: Copy + Default> Consumer<T> {
+    /// Pops up to `out.len()` items into `out` and returns how many were
+    /// written. Counts an underflow when there weren't enough.
+    pub fn pop_slice(&mut self, out: &mut [T]) -> usize {
+        // Safety: this is the only consumer, and `&mut self` keeps it on
+        // one thread.
+        unsafe { self.ring.pop_slice(out) }
+    }
+
+    /// The queue, for watching it from another thread.
+    pub fn ring(&self) -> Arc<RingBuffer<T>> {
+        Arc::clone(&self.ring)
+    }
+}
+
+impl<T> Deref for Consumer<T> {
+    type Target = RingBuffer<T>;
+
+    fn deref(&self) -> &RingBuffer<T> {
+        &self.ring
+    }
+}
+
+/// An output stream playing whatever a producer thread pushes, e.g. from a
+/// synth or a network decoder. The callback takes what the queue holds and
+/// plays silence for the rest, counting an underrun. Stops when dropped.
+pub struct QueuedOutput {
+    pub handle: StreamHandle,
+    pub config: StreamConfig,
+    producer: Producer<f32>,
+    target_fill: usize,
+}
+
+impl QueuedOutput {
+    /// Opens `device` with `options` over its default config, with a queue
+    /// of `capacity_ms`. The producer should keep about `target_fill_ms`
+    /// queued: more adds latency, less risks underruns.
+    pub fn new(
+        device: &Device,
+        options: &StreamOptions,
+        capacity_ms: u32,
+        target_fill_ms: u32,
+    ) -> Result<Self, StreamError> {
+        let config = options.apply(&device.default_output_config()?.config());
+        let format = preferred_format(device, Direction::Output, &CALLBACK_FORMATS)
+            .ok_or_else(|| StreamError::Unsupported("the device offers none of f32, i16 or u16 samples".to_string()))?;
+        check_config(device, &config, format, Direction::Output)?;
+        let frames_in = |ms: u32| (config.sample_rate.0 as u64 * ms as u64 / 1000) as usize;
+        let channels = config.channels.max(1) as usize;
+        let (producer, consumer) = ring_buffer(frames_in(capacity_ms).max(1) * channels);
+        let control = Arc::new(StreamControl::new());
+        let callback_control = Arc::clone(&control);
+        let stream = match format {
+            SampleFormat::I16 => queued_stream::<i16>(device, &config, consumer, callback_control)?,
+            SampleFormat::U16 => queued_stream::<u16>(device, &config, consumer, callback_control)?,
+            _ => queued_stream::<f32>(device, &config, consumer, callback_control)?,
+        };
+        stream.play()?;
+        let target_fill = frames_in(target_fill_ms).min(producer.capacity() / channels);
+        Ok(Self { handle: StreamHandle::new(stream, control), config, producer, target_fill })
+    }
+
+    /// Queues as many whole frames of interleaved `samples` as fit and
+    /// returns the number of samples taken.
+    pub fn push(&mut self, samples: &[f32]) -> usize {
+        push_frames(&mut self.producer, samples, self.config.channels.max(1) as usize)
+    }
+
+    pub fn buffered_frames(&self) -> usize {
+        self.producer.available() / self.config.channels.max(1) as usize
+    }
+
+    /// Fill level the producer should aim for, in frames.
+    pub fn target_fill(&self) -> usize {
+        self.target_fill
+    }
+
+    /// Frames to push to get back up to `target_fill`.
+    pub fn frames_wanted(&self) -> usize {
+        self.target_fill.saturating_sub(self.buffered_frames())
+    }
+
+    /// Callbacks that found the queue short, not counting paused ones.
+    pub fn underruns(&self) -> u64 {
+        self.producer.underflows()
+    }
+}
+
+// Pushes as many whole frames of `samples` as fit; `QueuedOutput::push`.
+fn push_frames(producer: &mut Producer<f32>, samples: &[f32], channels: usize) -> usize {
+    let free = producer.capacity() - producer.available();
+    let count = samples.len().min(free);
+    producer.push_slice(&samples[..count - count % channels])
+}
+
+fn queued_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    mut ring: Consumer<f32>,
+    control: Arc<StreamControl>,
+) -> Result<Stream, BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels.max(1) as usize;
+    let mut popped = vec![0.0f32; scratch_len(channels)];
+    let mut guard = OutputGuard::with_defaults(config.sample_rate.0, config.channels);
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            callback_scope(|| {
+                if !control.begin_block(data.len() / channels) {
+                    data.fill(T::EQUILIBRIUM);
+                    return;
+                }
+                write_guarded(data, &mut popped, &mut guard, |popped| {
+                    let count = ring.pop_slice(popped);
+                    popped[count..].fill(0.0);
+                });
+            })
+        },
+        log_stream_error(),
+        None,
+    )
+}
+
+/// Interleaved audio held in memory and played from a shared cursor, so a
+/// UI thread can start, pause and seek while a callback reads from it.
+///
+/// Only one callback should read from a source at a time.
+pub struct PlayerSource {
+    samples: Vec<f32>,
+    sample_rate: u32,
+    channels: u16,
+    // Read position in samples, always on a frame boundary.
+    position: AtomicUsize,
+    playing: AtomicBool,
+}
+
+impl PlayerSource {
+    pub fn new(samples: Vec<f32>, sample_rate: u32, channels: u16) -> Self {
+        Self {
+            samples,
+            sample_rate,
+            channels: channels.max(1),
+            position: AtomicUsize::new(0),
+            playing: AtomicBool::new(false),
+        }
+    }
+
+    /// A source for a sink with spec `dst`, converting `samples` from `src`
+    /// up front so the audio callback can read it as is.
+    pub fn adapted(samples: &[f32], src: AudioSpec, dst: AudioSpec) -> Self {
+        Self::adapted_with(samples, FormatAdapter::new(src, dst))
+    }
+
+    /// `adapted` with an adapter set up by the caller, e.g. with its own
+    /// channel mapping.
+    pub fn adapted_with(samples: &[f32], mut adapter: FormatAdapter) -> Self {
+        let dst = adapter.dst();
+        let samples = if adapter.is_identity() { samples.to_vec() } else { adapter.process_all(samples) };
+        Self::new(samples, dst.sample_rate, dst.channels)
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    pub fn play(&self) {
+        self.playing.store(true, Ordering::Release);
+    }
+
+    pub fn pause(&self) {
+        self.playing.store(false, Ordering::Release);
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing.load(Ordering::Acquire)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.position.load(Ordering::Acquire) >= self.samples.len()
+    }
+
+    pub fn seek(&self, frame: usize) {
+        let position = (frame * self.channels as usize).min(self.samples.len());
+        self.position.store(position, Ordering::Release);
+    }
+
+    pub fn position_frames(&self) -> usize {
+        self.position.load(Ordering::Acquire) / self.channels as usize
+    }
+
+    pub fn len_frames(&self) -> usize {
+        self.samples.len() / self.channels as usize
+    }
+
+    /// Copies the next whole frames into `out` and advances the cursor.
+    /// Returns the number of samples written, which is less than
+    /// `out.len()` only at the end of the source. Writes nothing while paused.
+    pub fn read(&self, out: &mut [f32]) -> usize {
+        if !self.is_playing() {
+            return 0;
+        }
+        let wanted = out.len() - out.len() % self.channels as usize;
+        loop {
+            let position = self.position.load(Ordering::Acquire);
+            let count = wanted.min(self.samples.len().saturating_sub(position));
+            // Retry if a seek moved the cursor in the meantime.
+            if self
+                .position
+                .compare_exchange(position, position + count, Ordering::AcqRel, Ordering::Acquire)
+                .is_ok()
+            {
+                out[..count].copy_from_slice(&self.samples[position..position + count]);
+                return count;
+            }
+        }
+    }
+}
+
+/// Frames of the source `start_playback` converts at a time.
+const PLAYBACK_CONVERT_FRAMES: usize = 1024;
+
+/// Converts a `PlayerSource` to the device's format a chunk at a time as
+/// the callback reads it, keeping what the device hasn't taken yet. Sized
+/// up front, so reading never allocates.
+struct AdaptedReader {
+    adapter: FormatAdapter,
+    src_channels: usize,
+    input: Vec<f32>,
+    pending: Vec<f32>,
+    pending_pos: usize,
+    flushed: bool,
+    // Source frame the next read continues from, to notice seeks.
+    expected_frame: usize,
+}
+
+impl AdaptedReader {
+    fn new(mut adapter: FormatAdapter) -> Self {
+        let (src, dst) = (adapter.src(), adapter.dst());
+        adapter.reserve(PLAYBACK_CONVERT_FRAMES);
+        // One chunk's worth of output; the flushed tail is shorter.
+        let out_frames = (PLAYBACK_CONVERT_FRAMES as u64 * dst.sample_rate as u64).div_ceil(src.sample_rate.max(1) as u64) + 1;
+        Self {
+            adapter,
+            src_channels: src.channels.max(1) as usize,
+            input: vec![0.0; PLAYBACK_CONVERT_FRAMES * src.channels.max(1) as usize],
+            pending: Vec::with_capacity(out_frames as usize * dst.channels.max(1) as usize),
+            pending_pos: 0,
+            flushed: false,
+            expected_frame: 0,
+        }
+    }
+
+    /// Fills `out` with converted audio and returns the samples written,
+    /// fewer when the source is paused or has run out.
+    fn fill(&mut self, source: &PlayerSource, out: &mut [f32]) -> usize {
+        // A seek from outside: what is buffered belongs to the old position.
+        if source.position_frames() != self.expected_frame {
+            self.adapter.reset();
+            self.pending.clear();
+            self.pending_pos = 0;
+            self.flushed = false;
+            self.expected_frame = source.position_frames();
+        }
+        let mut written = 0;
+        while written < out.len() {
+            if self.pending_pos < self.pending.len() {
+                let count = (out.len() - written).min(self.pending.len() - self.pending_pos);
+                out[written..written + count].copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + count]);
+                written += count;
+                self.pending_pos += count;
+                continue;
+            }
+            let count = source.read(&mut self.input);
+            if count == 0 {
+                break;
+            }
+            self.expected_frame += count / self.src_channels;
+            self.pending.clear();
+            self.pending_pos = 0;
+            self.pending.extend_from_slice(self.adapter.process(&self.input[..count]));
+        }
+        written
+    }
+
+    /// The source was sought back to its start for a repeat. The converter
+    /// keeps its state, so the loop joins as if the audio were continuous.
+    fn rewind(&mut self) {
+        self.expected_frame = 0;
+    }
+
+    /// Queues the converter's tail once the source has ended. False when
+    /// there is nothing more to play.
+    fn finish(&mut self) -> bool {
+        if self.flushed || self.pending_pos < self.pending.len() {
+            return false;
+        }
+        self.flushed = true;
+        self.pending.clear();
+        self.pending_pos = 0;
+        self.pending.extend_from_slice(self.adapter.flush());
+        !self.pending.is_empty()
+    }
+}
+
+/// A buffer playing on an output device, from `start_playback`. Stops
+/// when dropped.
+pub struct Playback {
+    handle: StreamHandle,
+    source: Arc<PlayerSource>,
+    // Set by the first callback after the source ran out, when the device
+    // has taken the last samples, after a stop, or when the stream fails.
+    done: Arc<AtomicBool>,
+    gain: Arc<SharedGain>,
+}
+
+impl Playback {
+    /// The buffer as given, at its own rate; positions and seeks are in
+    /// its frames.
+    pub fn source(&self) -> &PlayerSource {
+        &self.source
+    }
+
+    /// Ends playback, loops included, at the next block.
+    pub fn stop(&self) {
+        self.handle.stop();
+    }
+
+    /// For stopping from another thread.
+    pub fn control(&self) -> Arc<StreamControl> {
+        self.handle.control()
+    }
+
+    /// The playback volume, unity at the start; changes ramp over
+    /// `GAIN_RAMP_MS`.
+    pub fn gain(&self) -> Arc<SharedGain> {
+        Arc::clone(&self.gain)
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done.load(Ordering::Acquire)
+    }
+
+    /// Blocks until the whole buffer has been played, or Ctrl-C.
+    pub fn wait(self) {
+        while !self.is_done() && !shutdown::requested() {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+/// How `start_playback` maps the buffer's channels onto the device's.
+/// The defaults are those of `ChannelMapper::new`: mono on the first two
+/// channels, and anything to mono averaged.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PlaybackOptions {
+    /// Device channels a mono buffer plays on, counting from 0.
+    pub mono_outputs: Option<Vec<usize>>,
+    /// Downmix to a mono device at equal power (-3 dB per channel from
+    /// stereo) instead of averaging.
+    pub equal_power_downmix: bool,
+    pub repeat: Repeat,
+    /// Ends playback after this long, even in the middle of the buffer.
+    pub stop_after: Option<Duration>,
+}
+
+/// How often `start_playback` plays the buffer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Repeat {
+    #[default]
+    Once,
+    /// This many times in all.
+    Times(u32),
+    /// Until stopped.
+    Forever,
+}
+
+impl PlaybackOptions {
+    // The mapper for these options, `None` for the default one.
+    fn mapper(&self, src_channels: u16, dst_channels: u16) -> Option<ChannelMapper> {
+        match (src_channels, dst_channels) {
+            (1, dst) if dst > 1 => self.mono_outputs.as_ref().map(|outputs| ChannelMapper::mono_to(dst, outputs)),
+            (src, 1) if src > 1 && self.equal_power_downmix => Some(ChannelMapper::equal_power_mono(src)),
+            _ => None,
+        }
+    }
+}
+
+/// Starts playing interleaved `samples` on `device` at its default config.
+/// Channels are mapped to the device's as `options` say, and the sample
+/// rate is converted to the device's in the callback, a chunk at a time,
+/// so a 44.1 kHz file plays at the right pitch on a 48 kHz device without
+/// a second copy of it being made first. A repeat starts in the same block
+/// the buffer ends in, so loops join without a gap. Silence follows the
+/// end.
+pub fn start_playback(
+    device: &Device,
+    samples: &[f32],
+    sample_rate: u32,
+    channels: u16,
+    options: &PlaybackOptions,
+) -> Result<Playback, StreamError> {
+    let config = device.default_output_config()?.config();
+    check_config(device, &config, SampleFormat::F32, Direction::Output)?;
+    let mut adapter =
+        FormatAdapter::new(AudioSpec::new(sample_rate, channels), AudioSpec::new(config.sample_rate.0, config.channels));
+    if let Some(mapper) = options.mapper(channels, config.channels) {
+        adapter = adapter.with_mapper(mapper);
+    }
+    let mut reader = AdaptedReader::new(adapter);
+    let source = Arc::new(PlayerSource::new(samples.to_vec(), sample_rate, channels));
+    let done = Arc::new(AtomicBool::new(false));
+    let control = Arc::new(StreamControl::new());
+
+    let channels = config.channels.max(1) as usize;
+    let mut repeats_left = match options.repeat {
+        Repeat::Once => Some(0),
+        Repeat::Times(times) => Some(times.saturating_sub(1)),
+        Repeat::Forever => None,
+    };
+    let mut frames_left = options.stop_after.map(|after| (after.as_secs_f64() * config.sample_rate.0 as f64) as usize);
+    let (callback_source, callback_done, error_done) = (Arc::clone(&source), Arc::clone(&done), Arc::clone(&done));
+    let callback_control = Arc::clone(&control);
+    let gain = Arc::new(SharedGain::default());
+    let callback_gain = Arc::clone(&gain);
+    let mut smoothed = SmoothedGain::with_rate(gain.linear(), config.sample_rate.0);
+    let mut guard = OutputGuard::with_defaults(config.sample_rate.0, config.channels);
+    let mut log_error = log_stream_error();
+    let stream = device.build_output_stream(
+        &config,
+        move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
+            callback_scope(|| {
+                if !callback_control.begin_block(data.len() / channels) {
+                    data.fill(0.0);
+                    if callback_control.is_stopped() {
+                        callback_done.store(true, Ordering::Release);
+                    }
+                    return;
+                }
+                let limit = frames_left.map_or(data.len(), |frames| data.len().min(frames * channels));
+                let mut written = 0;
+                while written < limit {
+                    written += reader.fill(&callback_source, &mut data[written..limit]);
+                    if written == limit || !callback_source.is_finished() {
+                        break;
+                    }
+                    // The end of the buffer inside this block: carry on from
+                    // its start for a repeat, or play out the converter.
+                    let repeat = callback_source.len_frames() > 0
+                        && match repeats_left.as_mut() {
+                            Some(0) => false,
+                            Some(left) => {
+                                *left -= 1;
+                                true
+                            }
+                            None => true,
+                        };
+                    if repeat {
+                        callback_source.seek(0);
+                        reader.rewind();
+                    } else if !reader.finish() {
+                        break;
+                    }
+                }
+                smoothed.set_target(callback_gain.linear());
+                smoothed.apply_frames(&mut data[..written], channels);
+                data[written..].fill(0.0);
+                debug_assert_finite("output callback", data);
+                guard.process(data);
+                if let Some(frames) = frames_left.as_mut() {
+                    *frames -= written / channels;
+                }
+                if (written == 0 && callback_source.is_finished()) || frames_left == Some(0) {
+                    callback_done.store(true, Ordering::Release);
+                }
+            })
         },
         move |err| {
-            eprintln!("Error: {}", err);
+            error_done.store(true, Ordering::Release);
+            log_error(err);
         },
         None,
-        
     )?;
+    source.play();
+    stream.play()?;
+    Ok(Playback { handle: StreamHandle::new(stream, control), source, done, gain })
+}
+
+/// Plays interleaved `samples` on `device` and returns when they have
+/// been played, which with `Repeat::Forever` takes a `stop_after`. See
+/// `start_playback`.
+pub fn play_samples(
+    device: &Device,
+    samples: &[f32],
+    sample_rate: u32,
+    channels: u16,
+    options: &PlaybackOptions,
+) -> Result<(), StreamError> {
+    start_playback(device, samples, sample_rate, channels, options)?.wait();
+    Ok(())
+}
+
+/// Where `play_files` is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PlaylistPosition {
+    /// Index into the paths.
+    pub file: usize,
+    /// Frames of it played, at the device rate.
+    pub frame: usize,
+    pub frames: usize,
+}
+
+/// How often `play_files` reports its position.
+const PLAYLIST_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+/// Frames `play_files` reads from a file at a time.
+const PLAYLIST_CHUNK_FRAMES: usize = 4096;
+/// Audio `play_files` keeps queued for the device, and the most it can
+/// hold.
+const PLAYLIST_TARGET_MS: u32 = 500;
+const PLAYLIST_QUEUE_MS: u32 = 1000;
+/// How often `play_files` tops the queue up.
+const PLAYLIST_POLL: Duration = Duration::from_millis(20);
+/// Longest the device may go without taking audio before `play_files`
+/// gives up on it and returns.
+const PLAYLIST_STALL: Duration = Duration::from_secs(2);
+
+/// Plays the WAV files at `paths` one after the other on `device`, at its
+/// default config, and returns when the last has been played. The files
+/// are opened before the stream starts, so one that can't be read fails
+/// early, but read a chunk at a time while playing and converted to the
+/// device's rate and channels on the way, so they needn't fit in memory.
+/// Each file is queued right behind the one before, so there is no gap
+/// between them. `progress` is called on this thread every
+/// `PLAYLIST_PROGRESS_INTERVAL`.
+pub fn play_files<F: FnMut(PlaylistPosition)>(
+    device: &Device,
+    paths: &[&str],
+    progress: F,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut files = Vec::with_capacity(paths.len());
+    for path in paths {
+        files.push(open_chunked(path, PLAYLIST_CHUNK_FRAMES).map_err(|e| format!("{}: {}", path, e))?);
+    }
+    let mut output = QueuedOutput::new(device, &StreamOptions::default(), PLAYLIST_QUEUE_MS, PLAYLIST_TARGET_MS)?;
+    let dst = AudioSpec::new(output.config.sample_rate.0, output.config.channels);
+    let mut feed = PlaylistFeed {
+        output: &mut output,
+        progress,
+        starts: Vec::with_capacity(files.len()),
+        lengths: Vec::with_capacity(files.len()),
+        pushed: 0,
+        last_report: Instant::now(),
+        last_taken: Instant::now(),
+    };
+
+    for (path, file) in paths.iter().zip(files) {
+        let spec = file.spec();
+        let mut adapter = FormatAdapter::new(AudioSpec::new(spec.sample_rate, spec.channels), dst);
+        let frames = file.frames() as u64 * dst.sample_rate as u64 / spec.sample_rate.max(1) as u64;
+        feed.starts.push(feed.pushed);
+        feed.lengths.push(frames as usize);
+        for chunk in file {
+            let chunk = chunk.map_err(|e| format!("{}: {}", path, e))?;
+            if !feed.push_all(adapter.process(&chunk)) {
+                return Ok(());
+            }
+        }
+        if !feed.push_all(adapter.flush()) {
+            return Ok(());
+        }
+    }
+    feed.drain();
+    Ok(())
+}
+
+// The producer side of `play_files`.
+struct PlaylistFeed<'a, F> {
+    output: &'a mut QueuedOutput,
+    progress: F,
+    // Frame at which each file started, counted over the whole playlist.
+    starts: Vec<u64>,
+    lengths: Vec<usize>,
+    pushed: u64,
+    last_report: Instant,
+    // When the device was last seen taking audio.
+    last_taken: Instant,
+}
+
+impl<F: FnMut(PlaylistPosition)> PlaylistFeed<'_, F> {
+    /// Queues all of `samples`, waiting for room. False if playback was
+    /// interrupted or the device stalled.
+    fn push_all(&mut self, samples: &[f32]) -> bool {
+        let channels = self.output.config.channels.max(1) as usize;
+        let mut offset = 0;
+        while offset < samples.len() {
+            let taken = self.output.push(&samples[offset..]);
+            offset += taken;
+            self.pushed += (taken / channels) as u64;
+            if offset < samples.len() && (shutdown::requested() || !self.wait()) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Waits for everything queued to be played.
+    fn drain(&mut self) {
+        while self.output.buffered_frames() > 0 && !shutdown::requested() && self.wait() {}
+    }
+
+    /// Sleeps for a poll and reports progress. False once the device has
+    /// taken nothing for `PLAYLIST_STALL`.
+    fn wait(&mut self) -> bool {
+        let before = self.output.buffered_frames();
+        std::thread::sleep(PLAYLIST_POLL);
+        let buffered = self.output.buffered_frames();
+        if buffered < before || buffered == 0 {
+            self.last_taken = Instant::now();
+        } else if self.last_taken.elapsed() >= PLAYLIST_STALL {
+            return false;
+        }
+
+        if self.last_report.elapsed() >= PLAYLIST_PROGRESS_INTERVAL {
+            self.last_report = Instant::now();
+            let played = self.pushed.saturating_sub(buffered as u64);
+            if let Some(file) = self.starts.iter().rposition(|&start| start <= played) {
+                let frames = self.lengths[file];
+                let frame = ((played - self.starts[file]) as usize).min(frames);
+                (self.progress)(PlaylistPosition { file, frame, frames });
+            }
+        }
+        true
+    }
+}
+
+/// How long `play_signal` waits past the signal's length for the device
+/// to take it before giving up on the stream.
+const SIGNAL_GRACE: Duration = Duration::from_secs(2);
+
+/// A reference signal for `play_signal`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SignalKind {
+    Sine { freq: f32 },
+    WhiteNoise,
+    PinkNoise,
+    /// Logarithmic from `start` to `end` Hz over `duration` seconds, then
+    /// again from `start`.
+    Sweep { start: f32, end: f32, duration: f32 },
+    /// One full-scale sample at the start.
+    Impulse,
+}
+
+impl SignalKind {
+    /// A generator of this signal at `sample_rate`, peaking at ±1.
+    pub fn generator(&self, sample_rate: u32) -> Box<dyn Generator> {
+        match *self {
+            SignalKind::Sine { freq } => Box::new(Sine::new(freq, sample_rate)),
+            SignalKind::WhiteNoise => Box::new(WhiteNoise::default()),
+            SignalKind::PinkNoise => Box::new(PinkNoise::default()),
+            SignalKind::Sweep { start, end, duration } => Box::new(LogSweep::new(start, end, duration, sample_rate)),
+            SignalKind::Impulse => Box::new(Impulse::default()),
+        }
+    }
+
+    /// The first `frames` samples of the signal, offline.
+    pub fn render(&self, sample_rate: u32, frames: usize) -> Vec<f32> {
+        let mut samples = vec![0.0; frames];
+        self.generator(sample_rate).fill(&mut samples);
+        samples
+    }
+}
+
+/// Plays `kind` at a peak of `amplitude` (linear, up to 1) on every
+/// channel of `device` at its default config, and returns once `duration`
+/// of it has been played.
+pub fn play_signal(device: &Device, kind: SignalKind, amplitude: f32, duration: Duration) -> Result<(), StreamError> {
+    let builder = StreamBuilder::new(device);
+    let (config, _) = builder.config(Direction::Output)?;
+    let channels = config.channels.max(1) as usize;
+    let mut generator = kind.generator(config.sample_rate.0);
+    let amplitude = amplitude.clamp(0.0, 1.0);
+    let mut frames_left = (duration.as_secs_f64() * config.sample_rate.0 as f64) as usize;
+    let done = Arc::new(AtomicBool::new(false));
+    let callback_done = Arc::clone(&done);
+    let (_handle, _) = builder.output(move |data| {
+        // Done once a block comes after the last sample, so the device
+        // has taken it.
+        if frames_left == 0 {
+            callback_done.store(true, Ordering::Release);
+        }
+        for frame in data.chunks_mut(channels) {
+            let sample = if frames_left > 0 {
+                frames_left -= 1;
+                generator.next_sample() * amplitude
+            } else {
+                0.0
+            };
+            frame.fill(sample);
+        }
+    })?;
+    let deadline = Instant::now() + duration + SIGNAL_GRACE;
+    while !done.load(Ordering::Acquire) && Instant::now() < deadline && !shutdown::requested() {
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    Ok(())
+}
+
+/// Length of the linear gain ramp used by `SmoothedGain`.
+pub const GAIN_RAMP_MS: f32 = 10.0;
+
+/// A gain that moves to new targets along a linear ramp instead of
+/// jumping, so changes don't produce zipper noise.
+#[derive(Debug, Clone)]
+pub struct SmoothedGain {
+    current: f32,
+    target: f32,
+    step: f32,
+    remaining: usize,
+    ramp_len: usize,
+}
+
+impl SmoothedGain {
+    pub fn new(initial: f32, ramp_len: usize) -> Self {
+        Self {
+            current: initial,
+            target: initial,
+            step: 0.0,
+            remaining: 0,
+            ramp_len: ramp_len.max(1),
+        }
+    }
+
+    /// Ramp length of `GAIN_RAMP_MS` at the given rate.
+    pub fn with_rate(initial: f32, sample_rate: u32) -> Self {
+        Self::new(initial, (GAIN_RAMP_MS * 0.001 * sample_rate as f32) as usize)
+    }
+
+    /// Starts a ramp from the current value. Does nothing if `target` is
+    /// already where the gain is heading.
+    pub fn set_target(&mut self, target: f32) {
+        if target == self.target {
+            return;
+        }
+        self.target = target;
+        self.remaining = self.ramp_len;
+        self.step = (target - self.current) / self.ramp_len as f32;
+    }
+
+    pub fn target(&self) -> f32 {
+        self.target
+    }
+
+    pub fn is_ramping(&self) -> bool {
+        self.remaining > 0
+    }
+
+    /// Advances one step and returns the gain to apply.
+    pub fn next_gain(&mut self) -> f32 {
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            self.current = if self.remaining == 0 {
+                self.target
+            } else {
+                self.current + self.step
+            };
+        }
+        self.current
+    }
+
+    /// Applies the gain to interleaved `samples` of `channels`, a step per
+    /// frame, so every channel of a frame gets the same gain.
+    pub fn apply_frames(&mut self, samples: &mut [f32], channels: usize) {
+        for frame in samples.chunks_mut(channels.max(1)) {
+            let gain = self.next_gain();
+            for sample in frame {
+                *sample *= gain;
+            }
+        }
+    }
+}
+
+/// A gain for a running output stream, settable from any thread. The
+/// callback follows it with a `SmoothedGain`, so each change ramps over
+/// `GAIN_RAMP_MS` instead of jumping.
+#[derive(Debug)]
+pub struct SharedGain {
+    // f32 bits, linear
+    gain: AtomicU32,
+}
+
+impl SharedGain {
+    pub fn new(linear: f32) -> Self {
+        Self { gain: AtomicU32::new(linear.max(0.0).to_bits()) }
+    }
+
+    /// Negative gains are taken as 0.
+    pub fn set_linear(&self, gain: f32) {
+        self.gain.store(gain.max(0.0).to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_db(&self, db: f32) {
+        self.set_linear(db_to_linear(db));
+    }
+
+    pub fn linear(&self) -> f32 {
+        f32::from_bits(self.gain.load(Ordering::Relaxed))
+    }
+
+    /// -inf when muted.
+    pub fn db(&self) -> f32 {
+        linear_to_db(self.linear())
+    }
+}
+
+impl Default for SharedGain {
+    fn default() -> Self {
+        Self::new(1.0)
+    }
+}
+
+/// Gains of a running monitor mix, settable from any thread.
+#[derive(Debug)]
+pub struct MonitorControls {
+    // f32 bits
+    live_gain: AtomicU32,
+    playback_gain: AtomicU32,
+}
+
+impl MonitorControls {
+    pub fn new(live_gain: f32, playback_gain: f32) -> Self {
+        Self {
+            live_gain: AtomicU32::new(live_gain.to_bits()),
+            playback_gain: AtomicU32::new(playback_gain.to_bits()),
+        }
+    }
+
+    pub fn set_live_gain(&self, gain: f32) {
+        self.live_gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn set_playback_gain(&self, gain: f32) {
+        self.playback_gain.store(gain.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn live_gain(&self) -> f32 {
+        f32::from_bits(self.live_gain.load(Ordering::Relaxed))
+    }
+
+    pub fn playback_gain(&self) -> f32 {
+        f32::from_bits(self.playback_gain.load(Ordering::Relaxed))
+    }
+}
+
+impl Default for MonitorControls {
+    fn default() -> Self {
+        Self::new(1.0, 1.0)
+    }
+}
+
+/// The mixing core of `monitor_mix`: sums a mono live signal, spread to
+/// every output channel, with interleaved playback audio, each through its
+/// own smoothed gain.
+pub struct MonitorMixer {
+    channels: usize,
+    live_gain: SmoothedGain,
+    playback_gain: SmoothedGain,
+    live_underruns: u64,
+    playback_underruns: u64,
+}
+
+impl MonitorMixer {
+    pub fn new(sample_rate: u32, channels: u16, live_gain: f32, playback_gain: f32) -> Self {
+        Self {
+            channels: channels.max(1) as usize,
+            live_gain: SmoothedGain::with_rate(live_gain, sample_rate),
+            playback_gain: SmoothedGain::with_rate(playback_gain, sample_rate),
+            live_underruns: 0,
+            playback_underruns: 0,
+        }
+    }
+
+    pub fn set_gains(&mut self, live_gain: f32, playback_gain: f32) {
+        self.live_gain.set_target(live_gain);
+        self.playback_gain.set_target(playback_gain);
+    }
+
+    /// Writes one block of interleaved output.
+    ///
+    /// `live` holds one sample per output frame, `playback` one per output
+    /// sample. `None` means the source is deliberately silent; a slice that
+    /// is too short means it underran, and the missing part is played as
+    /// silence and counted.
+    pub fn mix(&mut self, output: &mut [f32], live: Option<&[f32]>, playback: Option<&[f32]>) {
+        let frames = output.len() / self.channels;
+        if live.is_some_and(|live| live.len() < frames) {
+            self.live_underruns += 1;
+        }
+        if playback.is_some_and(|playback| playback.len() < output.len()) {
+            self.playback_underruns += 1;
+        }
+
+        for (f, frame) in output.chunks_mut(self.channels).enumerate() {
+            let live_gain = self.live_gain.next_gain();
+            let playback_gain = self.playback_gain.next_gain();
+            let live_sample = live.and_then(|live| live.get(f)).copied().unwrap_or(0.0) * live_gain;
+            for (c, out) in frame.iter_mut().enumerate() {
+                let playback_sample = playback
+                    .and_then(|playback| playback.get(f * self.channels + c))
+                    .copied()
+                    .unwrap_or(0.0);
+                *out = live_sample + playback_sample * playback_gain;
+            }
+        }
+    }
+
+    pub fn live_underruns(&self) -> u64 {
+        self.live_underruns
+    }
+
+    pub fn playback_underruns(&self) -> u64 {
+        self.playback_underruns
+    }
+}
+
+/// Frames handled per pass inside the monitor callbacks, so their scratch
+/// buffers can be allocated up front.
+const MONITOR_CHUNK_FRAMES: usize = 512;
+/// Live input buffered between the two devices.
+const MONITOR_RING_MS: u32 = 200;
+/// Most the live path is sped up or slowed down to follow the input
+/// device's clock, in parts per million. Sound card crystals are rarely
+/// more than 100 ppm apart.
+const DRIFT_MAX_PPM: f64 = 200.0;
+/// Ring fill readings averaged for the drift correction, one per output
+/// chunk: a few seconds at common block sizes.
+const DRIFT_WINDOW: usize = 256;
+/// Fill error at which the drift correction reaches `DRIFT_MAX_PPM`.
+const DRIFT_FULL_SCALE_MS: f64 = 10.0;
+/// Frames the drift compensator's interpolator holds back.
+pub const DRIFT_LATENCY_FRAMES: usize = 2;
+
+/// Reads a ring buffer fed from one device's clock at the rate of
+/// another's.
+///
+/// No two devices run at exactly the same rate, so the ring between them
+/// slowly fills until it overruns or empties until it underruns. The
+/// compensator averages the ring's fill over the last `DRIFT_WINDOW` reads
+/// and takes slightly more or fewer samples than it produces, at most
+/// `DRIFT_MAX_PPM` either way, to hold the fill where it settled during the
+/// first window. The samples in between are interpolated with a four-point
+/// cubic (Catmull-Rom), which at these ratios is inaudible.
+pub struct DriftCompensator {
+    // Input samples taken per output sample.
+    ratio: f64,
+    // How far the next output lies from `history[1]` towards `history[2]`.
+    position: f64,
+    // The last four input samples, oldest first.
+    history: [f32; 4],
+    input: Vec<f32>,
+    max_frames: usize,
+    fills: Vec<usize>,
+    fill_index: usize,
+    fill_count: usize,
+    fill_sum: usize,
+    target: Option<f64>,
+    // Correction per frame of fill error.
+    gain: f64,
+}
+
+impl DriftCompensator {
+    /// A compensator for `sample_rate` that is asked for at most
+    /// `max_frames` at a time; longer reads are split.
+    pub fn new(sample_rate: u32, max_frames: usize) -> Self {
+        let max_frames = max_frames.max(1);
+        let full_scale = (DRIFT_FULL_SCALE_MS * sample_rate as f64 / 1000.0).max(1.0);
+        Self {
+            ratio: 1.0,
+            position: 0.0,
+            history: [0.0; 4],
+            // Enough for `max_frames` at the highest ratio.
+            input: vec![0.0; 2 * max_frames + 2],
+            max_frames,
+            fills: vec![0; DRIFT_WINDOW],
+            fill_index: 0,
+            fill_count: 0,
+            fill_sum: 0,
+            target: None,
+            gain: DRIFT_MAX_PPM * 1e-6 / full_scale,
+        }
+    }
+
+    /// Holds the fill at `frames` instead of where it settles.
+    pub fn with_target_fill(mut self, frames: usize) -> Self {
+        self.target = Some(frames as f64);
+        self
+    }
+
+    /// Input samples currently taken per output sample; above 1 when the
+    /// input's clock runs fast.
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    /// The fill being held, once it has settled.
+    pub fn target_fill(&self) -> Option<f64> {
+        self.target
+    }
+
+    /// Fills `out` from `ring` and returns how much of it was filled, which
+    /// is less than all of it when the ring ran dry.
+    pub fn process(&mut self, ring: &mut Consumer<f32>, out: &mut [f32]) -> usize {
+        self.observe(ring.available());
+        let mut produced = 0;
+        for piece in out.chunks_mut(self.max_frames) {
+            let len = self.read(ring, piece);
+            produced += len;
+            if len < piece.len() {
+                break;
+            }
+        }
+        produced
+    }
+
+    fn observe(&mut self, fill: usize) {
+        if self.fill_count == self.fills.len() {
+            self.fill_sum -= self.fills[self.fill_index];
+        } else {
+            self.fill_count += 1;
+        }
+        self.fills[self.fill_index] = fill;
+        self.fill_sum += fill;
+        self.fill_index = (self.fill_index + 1) % self.fills.len();
+        if self.fill_count < self.fills.len() {
+            return;
+        }
+
+        let average = self.fill_sum as f64 / self.fill_count as f64;
+        let target = *self.target.get_or_insert(average);
+        let limit = DRIFT_MAX_PPM * 1e-6;
+        self.ratio = 1.0 + (self.gain * (average - target)).clamp(-limit, limit);
+    }
+
+    fn read(&mut self, ring: &mut Consumer<f32>, out: &mut [f32]) -> usize {
+        let wanted = ((self.position + out.len() as f64 * self.ratio) as usize).min(self.input.len());
+        let popped = ring.pop_slice(&mut self.input[..wanted]);
+        let mut next = 0;
+        for (produced, sample) in out.iter_mut().enumerate() {
+            let advance = self.position + self.ratio;
+            if next + advance as usize > popped {
+                // Ran dry; the samples taken for this one are lost with
+                // the rest of the block.
+                return produced;
+            }
+            *sample = catmull_rom(&self.history, self.position as f32);
+            self.position = advance;
+            while self.position >= 1.0 {
+                self.history.rotate_left(1);
+                self.history[3] = self.input[next];
+                next += 1;
+                self.position -= 1.0;
+            }
+        }
+        out.len()
+    }
+}
+
+// Between `points[1]` (t = 0) and `points[2]` (t = 1).
+fn catmull_rom(points: &[f32; 4], t: f32) -> f32 {
+    let [p0, p1, p2, p3] = *points;
+    let a = -0.5 * p0 + 1.5 * p1 - 1.5 * p2 + 0.5 * p3;
+    let b = p0 - 2.5 * p1 + 2.0 * p2 - 0.5 * p3;
+    let c = -0.5 * p0 + 0.5 * p2;
+    ((a * t + b) * t + c) * t + p1
+}
+
+/// Processing options for `monitor_mix`.
+pub struct MonitorOptions {
+    /// Run on the live input (mono) before it is mixed in.
+    pub live_chain: Option<EffectChain>,
+    pub safety_limiter: SafetyLimiterConfig,
+    /// Receives every output block as it goes to the device, after the
+    /// safety limiter, for meters to tap.
+    pub meter: Option<MeterPublisher>,
+}
+
+impl Default for MonitorOptions {
+    fn default() -> Self {
+        Self { live_chain: None, safety_limiter: safety_defaults(), meter: None }
+    }
+}
+
+#[derive(Debug, Default)]
+struct MonitorStats {
+    live_underruns: AtomicU64,
+    playback_underruns: AtomicU64,
+    input_latency_nanos: AtomicU64,
+    output_latency_nanos: AtomicU64,
+    buffered_frames: AtomicUsize,
+    // f64 bits of the drift correction in ppm, `DriftCompensator::ratio`
+    // less 1.
+    drift_ppm: AtomicU64,
+}
+
+/// Keeps the streams of a monitor mix running; dropping it stops them.
+pub struct MonitorMix {
+    _input: Stream,
+    _output: Stream,
+    sample_rate: u32,
+    // Frames the input's format conversion holds back.
+    conversion_frames: usize,
+    stats: Arc<MonitorStats>,
+    // The live ring, for its overflow count.
+    live_ring: Arc<RingBuffer<f32>>,
+}
+
+impl MonitorMix {
+    /// Time from the microphone to the speakers on the live path: the input
+    /// and output device latency reported by the backend plus whatever sits
+    /// in the ring buffer, the input's resampler and the drift compensator
+    /// between them. The device part is zero until both callbacks have run.
+    pub fn live_latency(&self) -> Duration {
+        let buffered = (self.buffered_frames() + self.conversion_frames + DRIFT_LATENCY_FRAMES) as f64
+            / self.sample_rate as f64;
+        Duration::from_nanos(self.stats.input_latency_nanos.load(Ordering::Relaxed))
+            + Duration::from_secs_f64(buffered)
+            + Duration::from_nanos(self.stats.output_latency_nanos.load(Ordering::Relaxed))
+    }
+
+    pub fn live_underruns(&self) -> u64 {
+        self.stats.live_underruns.load(Ordering::Relaxed)
+    }
+
+    /// Times the input delivered more than the output took in time and
+    /// live audio was dropped.
+    pub fn live_overruns(&self) -> u64 {
+        self.live_ring.overflows()
+    }
+
+    pub fn playback_underruns(&self) -> u64 {
+        self.stats.playback_underruns.load(Ordering::Relaxed)
+    }
+
+    /// Live input waiting in the ring buffer, as of the last output
+    /// callback.
+    pub fn buffered_frames(&self) -> usize {
+        self.stats.buffered_frames.load(Ordering::Relaxed)
+    }
+
+    /// Live input samples taken per output sample to follow the input
+    /// device's clock; 1 until the ring fill has settled.
+    pub fn drift_ratio(&self) -> f64 {
+        1.0 + f64::from_bits(self.stats.drift_ppm.load(Ordering::Relaxed)) * 1e-6
+    }
+}
+
+/// Overdub monitoring: plays `playback` on `output_device` together with
+/// the live signal from `input_device`, with the balance set through
+/// `controls` while running.
+///
+/// The live input is converted to mono at the output's sample rate, handed
+/// to the output callback through a ring buffer read by a
+/// `DriftCompensator`, optionally processed by `options.live_chain`, and
+/// sent to every output channel. The playback
+/// source has to match the output device; see `PlayerSource::adapted`.
+pub fn monitor_mix(
+    input_device: &Device,
+    output_device: &Device,
+    playback: Arc<PlayerSource>,
+    controls: Arc<MonitorControls>,
+    options: MonitorOptions,
+) -> Result<MonitorMix, StreamError> {
+    let input_config = input_device.default_input_config()?.config();
+    let output_config = output_device.default_output_config()?.config();
+    // Both callbacks take f32, which the default configs needn't be.
+    check_config(input_device, &input_config, SampleFormat::F32, Direction::Input)?;
+    check_config(output_device, &output_config, SampleFormat::F32, Direction::Output)?;
+    let sample_rate = output_config.sample_rate.0;
+    if playback.sample_rate() != sample_rate {
+        return Err(StreamError::Unsupported(format!(
+            "Playback is at {} Hz, output device at {} Hz",
+            playback.sample_rate(),
+            sample_rate
+        )));
+    }
+    if playback.channels() != output_config.channels {
+        return Err(StreamError::Unsupported(format!(
+            "Playback has {} channels, output device has {}",
+            playback.channels(),
+            output_config.channels
+        )));
+    }
+
+    let (mut input_ring, mut ring) = ring_buffer::<f32>((sample_rate * MONITOR_RING_MS / 1000) as usize);
+    let live_ring = ring.ring();
+    let stats = Arc::new(MonitorStats::default());
+
+    let input_channels = input_config.channels.max(1) as usize;
+    let mut input_adapter = FormatAdapter::new(
+        AudioSpec::new(input_config.sample_rate.0, input_config.channels),
+        AudioSpec::new(sample_rate, 1),
+    );
+    input_adapter.reserve(MONITOR_CHUNK_FRAMES);
+    let conversion_frames = input_adapter.latency_frames();
+    let input_stats = Arc::clone(&stats);
+    let input = input_device.build_input_stream(
+        &input_config,
+        move |data: &[f32], info: &cpal::InputCallbackInfo| {
+            callback_scope(|| {
+                debug_assert_finite("input callback", data);
+                let timestamp = info.timestamp();
+                if let Some(latency) = timestamp.callback.duration_since(&timestamp.capture) {
+                    input_stats.input_latency_nanos.store(latency.as_nanos() as u64, Ordering::Relaxed);
+                }
+                for chunk in data.chunks(MONITOR_CHUNK_FRAMES * input_channels) {
+                    input_ring.push_slice(input_adapter.process(chunk));
+                }
+            })
+        },
+        log_stream_error(),
+        None,
+    )?;
+
+    let channels = output_config.channels;
+    let mut mixer = MonitorMixer::new(sample_rate, channels, controls.live_gain(), controls.playback_gain());
+    let mut guard = OutputGuard::new(sample_rate, channels, options.safety_limiter);
+    let mut live_chain = options.live_chain;
+    let mut meter = options.meter;
+    let mut live = vec![0.0f32; MONITOR_CHUNK_FRAMES];
+    let mut compensator = DriftCompensator::new(sample_rate, MONITOR_CHUNK_FRAMES);
+    let mut played = vec![0.0f32; MONITOR_CHUNK_FRAMES * channels as usize];
+    let output_stats = Arc::clone(&stats);
+    let output = output_device.build_output_stream(
+        &output_config,
+        move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+            callback_scope(|| {
+                let timestamp = info.timestamp();
+                if let Some(latency) = timestamp.playback.duration_since(&timestamp.callback) {
+                    output_stats.output_latency_nanos.store(latency.as_nanos() as u64, Ordering::Relaxed);
+                }
+                output_stats.buffered_frames.store(ring.available(), Ordering::Relaxed);
+
+                mixer.set_gains(controls.live_gain(), controls.playback_gain());
+                for block in data.chunks_mut(MONITOR_CHUNK_FRAMES * channels as usize) {
+                    let frames = block.len() / channels as usize;
+                    let live_len = compensator.process(&mut ring, &mut live[..frames]);
+                    if let Some(chain) = live_chain.as_mut() {
+                        chain.process_block(&mut live[..live_len]);
+                    }
+
+                    let playback_block = if playback.is_playing() {
+                        let mut played_len = playback.read(&mut played[..block.len()]);
+                        // Running off the end of the source is not an underrun.
+                        if playback.is_finished() {
+                            played[played_len..block.len()].fill(0.0);
+                            played_len = block.len();
+                        }
+                        Some(&played[..played_len])
+                    } else {
+                        None
+                    };
+
+                    mixer.mix(block, Some(&live[..live_len]), playback_block);
+                }
+                debug_assert_finite("output callback", data);
+                guard.process(data);
+                if let Some(meter) = meter.as_mut() {
+                    meter.publish(data, channels);
+                }
+
+                output_stats.live_underruns.store(mixer.live_underruns(), Ordering::Relaxed);
+                output_stats.playback_underruns.store(mixer.playback_underruns(), Ordering::Relaxed);
+                let ppm = (compensator.ratio() - 1.0) * 1e6;
+                output_stats.drift_ppm.store(ppm.to_bits(), Ordering::Relaxed);
+            })
+        },
+        log_stream_error(),
+        None,
+    )?;
+
+    input.play()?;
+    output.play()?;
+
+    Ok(MonitorMix {
+        _input: input,
+        _output: output,
+        sample_rate,
+        conversion_frames,
+        stats,
+        live_ring,
+    })
+}
+
+// A closure as the live chain of a monitor mix.
+struct ProcessEffect<F>(F);
+
+impl<F: FnMut(&mut [f32]) + Send> Effect for ProcessEffect<F> {
+    fn process_block(&mut self, block: &mut [f32]) {
+        (self.0)(block)
+    }
+}
+
+/// Plays `input_device` on `output_device` through `process`, which gets
+/// the live signal as mono blocks at the output's rate while each output
+/// block is filled; what it leaves goes to every output channel.
+///
+/// This is a `monitor_mix` with nothing to play back: the ring buffer
+/// between the callbacks absorbs their different block sizes, the drift
+/// compensator their different clocks, the handle counts underruns and
+/// overruns and shows the drift correction, and dropping it stops both
+/// streams.
+pub fn duplex<F>(input_device: &Device, output_device: &Device, process: F) -> Result<MonitorMix, StreamError>
+where
+    F: FnMut(&mut [f32]) + Send + 'static,
+{
+    let output_config = output_device.default_output_config()?;
+    let playback = Arc::new(PlayerSource::new(Vec::new(), output_config.sample_rate().0, output_config.channels()));
+    let options = MonitorOptions {
+        live_chain: Some(EffectChain::new(vec![Box::new(ProcessEffect(process))])),
+        safety_limiter: safety_defaults(),
+        meter: None,
+    };
+    monitor_mix(input_device, output_device, playback, Arc::new(MonitorControls::default()), options)
+}
+
+/// Capture of what an output device is playing, from
+/// `make_loopback_stream`. Stops when dropped.
+pub struct LoopbackStream {
+    _stream: Stream,
+    /// The output's default config, which the captured blocks are in.
+    pub config: StreamConfig,
+    /// Interleaved samples as the callback captures them; has to be
+    /// drained within `LOOPBACK_RING_MS`.
+    pub ring: Consumer<f32>,
+}
+
+impl LoopbackStream {
+    /// Waits for `duration` of audio and returns it interleaved. Returns
+    /// what there is if the stream stops first.
+    pub fn capture(&mut self, duration: Duration) -> Vec<f32> {
+        let wanted = (duration.as_secs_f64() * self.config.sample_rate.0 as f64) as usize * self.config.channels as usize;
+        let mut samples = vec![0.0; wanted];
+        let mut len = 0;
+        let mut last_data = Instant::now();
+        while len < wanted && last_data.elapsed() < LOOPBACK_STALL && !shutdown::requested() {
+            let popped = self.ring.pop_slice(&mut samples[len..(len + self.ring.available()).min(wanted)]);
+            if popped > 0 {
+                len += popped;
+                last_data = Instant::now();
+            }
+            std::thread::sleep(LOOPBACK_POLL);
+        }
+        samples.truncate(len);
+        samples
+    }
+}
+
+/// How much captured audio the loopback ring holds.
+pub const LOOPBACK_RING_MS: u32 = 2000;
+/// How often `LoopbackStream::capture` drains the ring.
+const LOOPBACK_POLL: Duration = Duration::from_millis(20);
+/// Longest wait for the next block before a capture gives up. Loopback
+/// delivers nothing while nothing is playing on some systems, so this is
+/// generous.
+const LOOPBACK_STALL: Duration = Duration::from_secs(2);
+
+/// Sample formats the streams here have callbacks for, best first. Many
+/// Windows devices offer nothing but i16.
+pub const CALLBACK_FORMATS: [SampleFormat; 3] = [SampleFormat::F32, SampleFormat::I16, SampleFormat::U16];
+
+/// Opens `device`, an output device such as `devices::loopback_device`,
+/// as an input capturing what it plays, at its default output config, in
+/// f32, i16 or u16 (delivered as f32 either way).
+pub fn make_loopback_stream(device: &Device) -> Result<LoopbackStream, StreamError> {
+    let config = device.default_output_config()?.config();
+    let format = preferred_format(device, Direction::Output, &CALLBACK_FORMATS)
+        .ok_or_else(|| StreamError::Unsupported("the device offers none of f32, i16 or u16 samples".to_string()))?;
+    check_config(device, &config, format, Direction::Output)?;
+    let samples = (config.sample_rate.0 * LOOPBACK_RING_MS / 1000) as usize * config.channels.max(1) as usize;
+    let (mut input_ring, ring) = ring_buffer(samples);
+    let stream = match format {
+        SampleFormat::I16 => loopback_input::<i16>(device, &config, input_ring)?,
+        SampleFormat::U16 => loopback_input::<u16>(device, &config, input_ring)?,
+        _ => device.build_input_stream(
+            &config,
+            move |data: &[f32], _: &cpal::InputCallbackInfo| {
+                callback_scope(|| {
+                    debug_assert_finite("input callback", data);
+                    input_ring.push_slice(data);
+                })
+            },
+            log_stream_error(),
+            None,
+        )?,
+    };
+    stream.play()?;
+    Ok(LoopbackStream { _stream: stream, config, ring })
+}
+
+// Converts integer samples to f32 on their way into `ring`.
+fn loopback_input<T>(device: &Device, config: &StreamConfig, mut ring: Producer<f32>) -> Result<Stream, BuildStreamError>
+where
+    T: SizedSample,
+    f32: FromSample<T>,
+{
+    let mut converted = vec![0.0f32; scratch_len(config.channels as usize)];
+    device.build_input_stream(
+        config,
+        move |data: &[T], _: &cpal::InputCallbackInfo| {
+            callback_scope(|| {
+                for piece in data.chunks(converted.len()) {
+                    let block = samples_to_f32(piece, &mut converted);
+                    debug_assert_finite("input callback", block);
+                    ring.push_slice(block);
+                }
+            })
+        },
+        log_stream_error(),
+        None,
+    )
+}
+
+/// Largest buffer `build_lowest_latency_*` tries before giving up.
+const MAX_RETRY_BUFFER_FRAMES: u32 = 8192;
+
+/// A stream opened with the smallest buffer the backend would take.
+/// Stops when dropped.
+pub struct LowLatencyStream {
+    _stream: Stream,
+    pub config: StreamConfig,
+    /// Frames per callback that the stream was opened with.
+    pub buffer_frames: u32,
+    /// Smaller sizes the backend refused, in the order they were tried.
+    pub refused: Vec<u32>,
+}
+
+impl LowLatencyStream {
+    /// What the buffer alone adds, before the backend's own latency.
+    pub fn latency_ms(&self) -> f32 {
+        self.buffer_frames as f32 * 1000.0 / self.config.sample_rate.0 as f32
+    }
+}
+
+// The smallest allowed size, then the powers of two above it.
+fn retry_sizes(min: u32) -> impl Iterator<Item = u32> {
+    let limit = MAX_RETRY_BUFFER_FRAMES.max(min);
+    let powers = std::iter::successors(Some((min.max(1) + 1).next_power_of_two()), |size| size.checked_mul(2));
+    std::iter::once(min).chain(powers).take_while(move |&size| size <= limit)
+}
+
+// Opens with `devices::min_latency_config`, doubling the buffer each time
+// the backend refuses it. Errors other than a refused config end it early.
+fn build_lowest_latency(
+    device: &Device,
+    direction: Direction,
+    mut open: impl FnMut(&StreamConfig) -> Result<Stream, BuildStreamError>,
+) -> Result<LowLatencyStream, StreamError> {
+    let mut config = min_latency_config(device, direction)?;
+    check_config(device, &config, SampleFormat::F32, direction)?;
+    let BufferSize::Fixed(min) = config.buffer_size else {
+        unreachable!("min_latency_config always fixes the buffer size");
+    };
+    let mut refused = Vec::new();
+    let mut last_error = None;
+    for size in retry_sizes(min) {
+        config.buffer_size = BufferSize::Fixed(size);
+        match open(&config) {
+            Ok(stream) => {
+                stream.play()?;
+                return Ok(LowLatencyStream { _stream: stream, config, buffer_frames: size, refused });
+            }
+            Err(e @ (BuildStreamError::StreamConfigNotSupported | BuildStreamError::BackendSpecific { .. })) => {
+                refused.push(size);
+                last_error = Some(e);
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Err(last_error.map_or_else(
+        || StreamError::Unsupported(format!("no buffer size up to {} frames", MAX_RETRY_BUFFER_FRAMES)),
+        StreamError::Build,
+    ))
+}
+
+/// Output stream on `device` with the smallest buffer it opens with, for
+/// latency measurements. `callback` fills interleaved f32 in the default
+/// config's layout and is cloned for every size tried.
+pub fn build_lowest_latency_output<F>(device: &Device, callback: F) -> Result<LowLatencyStream, StreamError>
+where
+    F: FnMut(&mut [f32], &cpal::OutputCallbackInfo) + Clone + Send + 'static,
+{
+    build_lowest_latency(device, Direction::Output, |config| {
+        let mut callback = callback.clone();
+        let mut guard = OutputGuard::with_defaults(config.sample_rate.0, config.channels);
+        let scoped = move |data: &mut [f32], info: &cpal::OutputCallbackInfo| {
+            callback_scope(|| {
+                callback(data, info);
+                debug_assert_finite("output callback", data);
+                guard.process(data);
+            })
+        };
+        device.build_output_stream(config, scoped, log_stream_error(), None)
+    })
+}
+
+/// Input stream on `device` with the smallest buffer it opens with. See
+/// `build_lowest_latency_output`.
+pub fn build_lowest_latency_input<F>(device: &Device, callback: F) -> Result<LowLatencyStream, StreamError>
+where
+    F: FnMut(&[f32], &cpal::InputCallbackInfo) + Clone + Send + 'static,
+{
+    build_lowest_latency(device, Direction::Input, |config| {
+        let mut callback = callback.clone();
+        let scoped = move |data: &[f32], info: &cpal::InputCallbackInfo| {
+            callback_scope(|| {
+                debug_assert_finite("input callback", data);
+                callback(data, info);
+            })
+        };
+        device.build_input_stream(config, scoped, log_stream_error(), None)
+    })
+}
+
+/// How `SupervisedStream` retries after the device goes away.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RestartPolicy {
+    /// Failed attempts in a row before giving up.
+    pub max_attempts: u32,
+    /// Wait before the first attempt; doubled after every failure.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self { max_attempts: 8, initial_delay: Duration::from_millis(250), max_delay: Duration::from_secs(8) }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent {
+    /// Running again, after `attempts` tries.
+    Restarted { attempts: u32 },
+    /// `RestartPolicy::max_attempts` failed in a row; the stream is gone
+    /// for good.
+    GaveUp { attempts: u32, error: String },
+}
+
+/// How often the supervisor looks at its stop flag while it waits.
+const SUPERVISOR_POLL: Duration = Duration::from_millis(50);
+/// Stream errors queued for the supervisor; the error callback drops
+/// further ones rather than allocate or block.
+const SUPERVISOR_ERROR_QUEUE: usize = 16;
+
+/// An input stream that is rebuilt when its device goes away, e.g. a USB
+/// interface hiccuping. Every rebuild hands the blocks to the same
+/// callback, so whatever it records or processes carries on. The stream
+/// lives on a thread of its own; dropping this stops it.
+pub struct SupervisedStream {
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl SupervisedStream {
+    /// Opens the device `find` returns, again on every restart, with
+    /// `options` (see `make_input_stream`). Restarts and giving up are
+    /// reported on `events`.
+    pub fn input<D, F>(
+        mut find: D,
+        options: StreamOptions,
+        callback: F,
+        policy: RestartPolicy,
+        events: Sender<StreamEvent>,
+    ) -> Self
+    where
+        D: FnMut() -> Result<Device, DeviceError> + Send + 'static,
+        F: FnMut(&[f32], &cpal::InputCallbackInfo) + Send + 'static,
+    {
+        // Only one stream runs at a time, so the lock is only ever contended
+        // while a stream is being replaced. A callback that finds it taken
+        // then drops its block rather than wait on the audio thread.
+        let callback = Arc::new(Mutex::new(callback));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let thread = std::thread::spawn(move || {
+            let (error_sender, errors) = mpsc::sync_channel(SUPERVISOR_ERROR_QUEUE);
+            let open = || -> Result<StreamHandle, StreamError> {
+                let device = find()?;
+                let callback = Arc::clone(&callback);
+                let error_sender = error_sender.clone();
+                let mut builder = InputStreamBuilder::new(&device);
+                if let Some(rate) = options.sample_rate {
+                    builder = builder.sample_rate(rate);
+                }
+                if let Some(channels) = options.channels {
+                    builder = builder.channels(channels);
+                }
+                if let Some(frames) = options.buffer_frames {
+                    builder = builder.buffer_size(frames);
+                }
+                let (handle, _) = builder
+                    .on_data_with_info(move |data, info| {
+                        if let Ok(mut callback) = callback.try_lock() {
+                            callback(data, info);
+                        }
+                    })
+                    .on_error(move |err| {
+                        let _ = error_sender.try_send(err);
+                    })
+                    .build()?;
+                Ok(handle)
+            };
+            supervise(open, &errors, policy, &events, &thread_stop);
+        });
+        Self { stop, thread: Some(thread) }
+    }
+
+    /// Stops the stream and waits for its thread.
+    pub fn stop(mut self) {
+        self.shutdown();
+    }
+
+    fn shutdown(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for SupervisedStream {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+// Keeps a stream from `open` running until `stop` is set, reopening it
+// per `policy` whenever `errors` says its device went away.
+fn supervise<H>(
+    mut open: impl FnMut() -> Result<H, StreamError>,
+    errors: &Receiver<cpal::StreamError>,
+    policy: RestartPolicy,
+    events: &Sender<StreamEvent>,
+    stop: &AtomicBool,
+) {
+    // Waits `delay`, or less if stopped meanwhile. False if stopped.
+    let wait = |delay: Duration| {
+        let started = Instant::now();
+        while started.elapsed() < delay {
+            if stop.load(Ordering::Relaxed) {
+                return false;
+            }
+            std::thread::sleep(SUPERVISOR_POLL.min(delay));
+        }
+        !stop.load(Ordering::Relaxed)
+    };
+
+    // The first open isn't a restart, but is retried the same way.
+    let mut result = open();
+    let mut restarting = false;
+    loop {
+        let mut attempts = 1;
+        let mut delay = policy.initial_delay;
+        let handle = loop {
+            match result {
+                Ok(handle) => break handle,
+                Err(e) if attempts >= policy.max_attempts => {
+                    let _ = events.send(StreamEvent::GaveUp { attempts, error: e.to_string() });
+                    return;
+                }
+                Err(_) => {
+                    if !wait(delay) {
+                        return;
+                    }
+                    delay = (delay * 2).min(policy.max_delay);
+                    attempts += 1;
+                    result = open();
+                }
+            }
+        };
+        if restarting {
+            let _ = events.send(StreamEvent::Restarted { attempts });
+        }
+
+        // Run until the device goes away or we are stopped.
+        loop {
+            match errors.recv_timeout(SUPERVISOR_POLL) {
+                Ok(err) if error_action(&err) == ErrorAction::Stop => break,
+                Ok(err) => eprintln!("Error: {}", err),
+                Err(RecvTimeoutError::Timeout) => {
+                    if stop.load(Ordering::Relaxed) {
+                        return;
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return,
+            }
+        }
+        drop(handle);
+        // Errors the dead stream reported after the fatal one.
+        while errors.try_recv().is_ok() {}
+        restarting = true;
+        if !wait(policy.initial_delay) {
+            return;
+        }
+        result = open();
+    }
+}
+
+/// Silence before the probe, so both streams have settled.
+const PROBE_LEAD_IN_MS: u32 = 300;
+/// Longest round trip `measure_latency` waits for.
+const MAX_ROUND_TRIP_MS: u32 = 1000;
+/// Shortest stretch the confidence of a match is measured over, so a
+/// single-sample impulse can't match any one loud sample of noise.
+const MIN_MATCH_MS: u32 = 10;
+/// Confidence below which the probe counts as not having come back.
+pub const MIN_LATENCY_CONFIDENCE: f32 = 0.3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LatencyProbe {
+    /// One sample at -2 dBFS.
+    Impulse,
+    /// Exponential sweep from 100 Hz to 0.4 times the sample rate at -6
+    /// dBFS; holds up better than an impulse against noise and filtering.
+    Chirp { seconds: f32 },
+}
+
+impl LatencyProbe {
+    pub fn samples(&self, sample_rate: u32) -> Vec<f32> {
+        match *self {
+            LatencyProbe::Impulse => vec![0.8],
+            LatencyProbe::Chirp { seconds } => {
+                let rate = sample_rate as f32;
+                let len = ((seconds.max(0.01) * rate) as usize).max(1);
+                let (f0, f1) = (100.0f32, 0.4 * rate);
+                let duration = len as f32 / rate;
+                let k = (f1 / f0).ln();
+                let fade = ((rate * 0.005) as usize).clamp(1, len / 2 + 1);
+                (0..len)
+                    .map(|i| {
+                        let t = i as f32 / rate;
+                        let phase = 2.0 * PI * f0 * duration / k * ((t * k / duration).exp() - 1.0);
+                        let edge = i.min(len - 1 - i);
+                        let envelope = if edge < fade { 0.5 - 0.5 * (PI * edge as f32 / fade as f32).cos() } else { 1.0 };
+                        0.5 * envelope * phase.sin()
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LatencyReport {
+    pub frames: usize,
+    pub ms: f32,
+    pub sample_rate: u32,
+    /// Normalized correlation of the probe with what came back, 0 to 1.
+    pub confidence: f32,
+}
+
+/// Round-trip latency from `output_device` to `input_device`, e.g. through
+/// a cable from an output back to an input: plays `probe`, records the
+/// input meanwhile and finds the probe in the recording by
+/// cross-correlation. The time is counted in input frames from when the
+/// output callback is handed the probe to when the input callback delivers
+/// it, so it covers both streams' buffering as well as the converters.
+/// Both devices run at the output's default rate. A probe found with less
+/// than `MIN_LATENCY_CONFIDENCE` is `StreamError::NoSignal`.
+pub fn measure_latency(
+    output_device: &Device,
+    input_device: &Device,
+    probe: LatencyProbe,
+) -> Result<LatencyReport, StreamError> {
+    let config = output_device.default_output_config()?.config();
+    let format = preferred_format(output_device, Direction::Output, &CALLBACK_FORMATS)
+        .ok_or_else(|| StreamError::Unsupported("the device offers none of f32, i16 or u16 samples".to_string()))?;
+    check_config(output_device, &config, format, Direction::Output)?;
+    let rate = config.sample_rate.0;
+    let frames_in = |ms: u32| (rate as u64 * ms as u64 / 1000) as usize;
+    let signal = Arc::new(probe.samples(rate));
+    let lead_in = frames_in(PROBE_LEAD_IN_MS);
+    let wanted = lead_in + signal.len() + frames_in(MAX_ROUND_TRIP_MS);
+
+    // Mono input, counted so the output callback can tell where it is.
+    let builder = InputStreamBuilder::new(input_device).sample_rate(rate).channels(1);
+    let negotiated = builder.negotiate()?;
+    if negotiated.sample_rate != rate {
+        return Err(StreamError::Unsupported(format!(
+            "the input can't run at the output's {} Hz (it offered {} Hz)",
+            rate, negotiated.sample_rate
+        )));
+    }
+    let channels = negotiated.channels.max(1) as usize;
+    let (mut input_ring, mut ring) = ring_buffer(2 * wanted);
+    let captured = Arc::new(AtomicU64::new(0));
+    let input_captured = Arc::clone(&captured);
+    let mut mono = vec![0.0f32; scratch_len(1)];
+    let (_input, _) = builder
+        .on_data(move |data| {
+            for chunk in data.chunks(channels * mono.len()) {
+                let len = mix_to_mono(chunk, channels, &mut mono);
+                input_ring.push_slice(&mono[..len]);
+                input_captured.fetch_add(len as u64, Ordering::Release);
+            }
+        })
+        .build()?;
+
+    // Input frame at which the output was handed the probe's first sample.
+    let started = Arc::new(AtomicU64::new(u64::MAX));
+    let (output_signal, output_captured, output_started) = (Arc::clone(&signal), Arc::clone(&captured), Arc::clone(&started));
+    let stream = match format {
+        SampleFormat::I16 => probe_stream::<i16>(output_device, &config, output_signal, lead_in, output_captured, output_started)?,
+        SampleFormat::U16 => probe_stream::<u16>(output_device, &config, output_signal, lead_in, output_captured, output_started)?,
+        _ => probe_stream::<f32>(output_device, &config, output_signal, lead_in, output_captured, output_started)?,
+    };
+    stream.play()?;
+
+    let mut recorded = Vec::new();
+    let mut chunk = vec![0.0f32; 4096];
+    let deadline = Instant::now() + Duration::from_millis((2 * (PROBE_LEAD_IN_MS + MAX_ROUND_TRIP_MS)) as u64)
+        + Duration::from_secs_f32(signal.len() as f32 / rate as f32);
+    loop {
+        let len = ring.available().min(chunk.len());
+        let len = ring.pop_slice(&mut chunk[..len]);
+        recorded.extend_from_slice(&chunk[..len]);
+        let start = started.load(Ordering::Acquire);
+        if start != u64::MAX && recorded.len() as u64 >= start + (signal.len() + frames_in(MAX_ROUND_TRIP_MS)) as u64 {
+            break;
+        }
+        if Instant::now() > deadline {
+            return Err(StreamError::NoSignal(if start == u64::MAX {
+                "the output never played the probe".to_string()
+            } else {
+                format!("the input delivered only {} frames", recorded.len())
+            }));
+        }
+        std::thread::sleep(LOOPBACK_POLL);
+    }
+    drop(stream);
+
+    let start = started.load(Ordering::Acquire) as usize;
+    let (lag, confidence) =
+        find_probe(&signal, &recorded[start..], frames_in(MAX_ROUND_TRIP_MS), frames_in(MIN_MATCH_MS));
+    if confidence < MIN_LATENCY_CONFIDENCE {
+        return Err(StreamError::NoSignal(format!(
+            "the probe didn't come back (confidence {:.2}); is the output connected to the input?",
+            confidence
+        )));
+    }
+    Ok(LatencyReport { frames: lag, ms: lag as f32 * 1000.0 / rate as f32, sample_rate: rate, confidence })
+}
+
+// Lag of the best match of `probe` in `recorded` within `max_lag`, and
+// its normalized correlation over at least `min_match` frames.
+fn find_probe(probe: &[f32], recorded: &[f32], max_lag: usize, min_match: usize) -> (usize, f32) {
+    if probe.is_empty() || recorded.is_empty() {
+        return (0, 0.0);
+    }
+    let correlation = cross_correlate(probe, recorded);
+    let zero_lag = probe.len() - 1;
+    let (lag, peak) = (0..=max_lag.min(recorded.len() - 1))
+        .map(|lag| (lag, correlation[zero_lag + lag]))
+        .fold((0, f32::NEG_INFINITY), |best, (lag, value)| if value > best.1 { (lag, value) } else { best });
+    let energy = |samples: &[f32]| samples.iter().map(|s| s * s).sum::<f32>();
+    let segment = &recorded[lag..(lag + probe.len().max(min_match)).min(recorded.len())];
+    let norm = (energy(probe) * energy(segment)).sqrt();
+    (lag, if norm > 0.0 { (peak / norm).clamp(0.0, 1.0) } else { 0.0 })
+}
+
+// Fills `data` a scratch-sized piece at a time: `render` writes the piece
+// as f32, `guard` limits it and it is converted to `T`. `scratch` has to
+// hold whole frames.
+fn write_guarded<T>(data: &mut [T], scratch: &mut [f32], guard: &mut OutputGuard, mut render: impl FnMut(&mut [f32]))
+where
+    T: SizedSample + FromSample<f32>,
+{
+    for piece in data.chunks_mut(scratch.len()) {
+        let block = &mut scratch[..piece.len()];
+        render(block);
+        debug_assert_finite("output callback", block);
+        guard.process(block);
+        for (out, &sample) in piece.iter_mut().zip(block.iter()) {
+            *out = T::from_sample_(sample);
+        }
+    }
+}
+
+// Plays `lead_in` frames of silence, then `signal` on every channel, then
+// silence, noting in `started` how far the input had got at the probe.
+fn probe_stream<T>(
+    device: &Device,
+    config: &StreamConfig,
+    signal: Arc<Vec<f32>>,
+    lead_in: usize,
+    captured: Arc<AtomicU64>,
+    started: Arc<AtomicU64>,
+) -> Result<Stream, BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+{
+    let channels = config.channels.max(1) as usize;
+    let mut position = 0usize;
+    let mut scratch = vec![0.0f32; scratch_len(channels)];
+    let mut guard = OutputGuard::with_defaults(config.sample_rate.0, config.channels);
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            callback_scope(|| {
+                let mut frame_index = 0u64;
+                write_guarded(data, &mut scratch, &mut guard, |block| {
+                    for frame in block.chunks_mut(channels) {
+                        if position == lead_in {
+                            started.store(captured.load(Ordering::Acquire) + frame_index, Ordering::Release);
+                        }
+                        let sample =
+                            position.checked_sub(lead_in).and_then(|n| signal.get(n)).copied().unwrap_or(0.0);
+                        frame.fill(sample);
+                        position += 1;
+                        frame_index += 1;
+                    }
+                });
+            })
+        },
+        log_stream_error(),
+        None,
+    )
+}
+
+/// Frames of each input `mix_inputs` converts at a time in its callback.
+const MIX_CHUNK_FRAMES: usize = 512;
+/// Length of the blocks `mix_inputs` sends.
+const MIX_BLOCK_MS: u32 = 10;
+/// How much converted input each device's ring holds for the mixer.
+const MIX_RING_MS: u32 = 1000;
+/// How often the mixer looks for a block's worth on every input.
+const MIX_POLL: Duration = Duration::from_millis(2);
+/// Blocks the mixer averages the inputs' fill levels over before taking
+/// their difference as the starting point drift is measured from.
+const MIX_SETTLE_BLOCKS: u64 = 200;
+/// Smoothing of the fill level differences, per block.
+const MIX_DRIFT_SMOOTHING: f64 = 1.0 / 64.0;
+/// How far an input may drift from the first before a frame is dropped from
+/// it or repeated.
+const MIX_DRIFT_TOLERANCE_MS: f64 = 2.0;
+
+/// How `mix_inputs` combines the devices.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum InputMix {
+    /// All devices mapped to the most channels any of them has and added.
+    #[default]
+    Sum,
+    /// Every device's channels side by side, in the order of the devices.
+    Separate,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct MixInputsOptions {
+    /// Asked of every device; the first device's granted rate is the rate
+    /// of the mix unless `sample_rate` is set.
+    pub stream: StreamOptions,
+    pub mix: InputMix,
+}
+
+/// Clock drift corrections made on one input of `mix_inputs`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DriftStats {
+    /// Frames left out because the device ran fast against the first.
+    pub dropped: u64,
+    /// Frames played twice because it ran slow.
+    pub duplicated: u64,
+    /// Times its ring overflowed because the mix fell behind.
+    pub overflows: u64,
+}
+
+#[derive(Debug, Default)]
+struct DriftCounters {
+    dropped: AtomicU64,
+    duplicated: AtomicU64,
+}
+
+// One device of a mix: the ring of its converted samples, and when the
+// first of them was captured, in nanoseconds after the mix was set up
+// (`u64::MAX` until then). The mixer thread holds the ring's consumer.
+struct MixedSource {
+    ring: Arc<RingBuffer<f32>>,
+    channels: usize,
+    started: Arc<AtomicU64>,
+    // Frames the converter delays the signal by.
+    latency_frames: usize,
+    counters: DriftCounters,
+}
+
+/// The streams and mixer behind `mix_inputs`. Dropping it stops them.
+pub struct MixedInputs {
+    pub handles: Vec<StreamHandle>,
+    pub configs: Vec<NegotiatedConfig>,
+    pub sample_rate: u32,
+    /// Of the mixed blocks.
+    pub channels: u16,
+    sources: Arc<Vec<MixedSource>>,
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl MixedInputs {
+    /// Corrections so far for each device, in order. The first device is
+    /// the clock the others follow, so it never has any.
+    pub fn drift(&self) -> Vec<DriftStats> {
+        self.sources
+            .iter()
+            .map(|source| DriftStats {
+                dropped: source.counters.dropped.load(Ordering::Relaxed),
+                duplicated: source.counters.duplicated.load(Ordering::Relaxed),
+                overflows: source.ring.overflows(),
+            })
+            .collect()
+    }
+}
+
+impl Drop for MixedInputs {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Records several input devices as one: every device gets its own stream,
+/// converted to a common rate (and for `InputMix::Sum` channel count) in
+/// its callback, and a mixer thread lines the inputs up by the capture
+/// time of their first frames and sends `MIX_BLOCK_MS` blocks, interleaved,
+/// to the receiver.
+///
+/// Devices on separate clocks drift apart. The first device sets the pace;
+/// when another's backlog moves more than `MIX_DRIFT_TOLERANCE_MS` from
+/// where it settled, one of its frames is dropped or repeated per block
+/// until it is back, counted in `MixedInputs::drift`.
+pub fn mix_inputs(devices: &[Device], options: &MixInputsOptions) -> Result<(MixedInputs, Receiver<Vec<f32>>), StreamError> {
+    let mut negotiated = Vec::with_capacity(devices.len());
+    for device in devices {
+        negotiated.push(input_builder(device, &options.stream, options.stream.buffer_frames).negotiate()?);
+    }
+    let Some(first) = negotiated.first() else {
+        return Err(StreamError::Unsupported("no input devices to mix".to_string()));
+    };
+    let sample_rate = options.stream.sample_rate.unwrap_or(first.sample_rate);
+    let sum_channels = negotiated.iter().map(|config| config.channels).max().unwrap_or(1).max(1);
+
+    let epoch = Instant::now();
+    let mut sources = Vec::with_capacity(devices.len());
+    let mut handles = Vec::with_capacity(devices.len());
+    let mut configs = Vec::with_capacity(devices.len());
+    let mut consumers = Vec::with_capacity(devices.len());
+    for (device, config) in devices.iter().zip(&negotiated) {
+        let channels = match options.mix {
+            InputMix::Sum => sum_channels,
+            InputMix::Separate => config.channels.max(1),
+        };
+        let mut adapter =
+            FormatAdapter::new(AudioSpec::new(config.sample_rate, config.channels), AudioSpec::new(sample_rate, channels));
+        adapter.reserve(MIX_CHUNK_FRAMES);
+        let ring_samples = (sample_rate * MIX_RING_MS / 1000) as usize * channels as usize;
+        let (mut ring, consumer) = ring_buffer(ring_samples);
+        let source = MixedSource {
+            ring: ring.ring(),
+            channels: channels as usize,
+            started: Arc::new(AtomicU64::new(u64::MAX)),
+            latency_frames: adapter.latency_frames(),
+            counters: DriftCounters::default(),
+        };
+        let started = Arc::clone(&source.started);
+        let device_channels = config.channels.max(1) as usize;
+        let stream_options = StreamOptions {
+            sample_rate: Some(config.sample_rate),
+            channels: Some(config.channels),
+            buffer_frames: options.stream.buffer_frames,
+        };
+        let (handle, granted) = make_input_stream(device, &stream_options, move |data, info| {
+            if started.load(Ordering::Relaxed) == u64::MAX {
+                let timestamp = info.timestamp();
+                let delay = timestamp.callback.duration_since(&timestamp.capture).unwrap_or_default();
+                started.store(epoch.elapsed().saturating_sub(delay).as_nanos() as u64, Ordering::Relaxed);
+            }
+            for chunk in data.chunks(MIX_CHUNK_FRAMES * device_channels) {
+                ring.push_slice(adapter.process(chunk));
+            }
+        })?;
+        sources.push(source);
+        consumers.push(consumer);
+        handles.push(handle);
+        configs.push(granted);
+    }
+
+    let channels = match options.mix {
+        InputMix::Sum => sum_channels,
+        InputMix::Separate => sources.iter().map(|source| source.channels as u16).sum(),
+    };
+    let sources = Arc::new(sources);
+    let mode = options.mix;
+    let (sender, blocks) = mpsc::channel();
+    let stop = Arc::new(AtomicBool::new(false));
+    let (mixer_sources, mixer_stop) = (Arc::clone(&sources), Arc::clone(&stop));
+    let thread = std::thread::spawn(move || {
+        mix_sources(&mixer_sources, &mut consumers, sample_rate, channels as usize, mode, &mixer_stop, &sender)
+    });
+    Ok((MixedInputs { handles, configs, sample_rate, channels, sources, stop, thread: Some(thread) }, blocks))
+}
+
+// The mixer thread of `mix_inputs`, until `stop` or the receiver is gone.
+fn mix_sources(
+    sources: &[MixedSource],
+    consumers: &mut [Consumer<f32>],
+    sample_rate: u32,
+    channels: usize,
+    mode: InputMix,
+    stop: &AtomicBool,
+    sender: &Sender<Vec<f32>>,
+) {
+    let rate = sample_rate.max(1) as f64;
+    let block = ((sample_rate * MIX_BLOCK_MS / 1000) as usize).max(2);
+    let tolerance = MIX_DRIFT_TOLERANCE_MS * rate / 1000.0;
+    let frames_in = |source: &MixedSource| source.ring.available() / source.channels.max(1);
+
+    while !sources.iter().all(|source| source.started.load(Ordering::Relaxed) != u64::MAX) {
+        if stop.load(Ordering::Relaxed) {
+            return;
+        }
+        std::thread::sleep(MIX_POLL);
+    }
+    // Capture time of the first frame in each ring, and the frames to skip
+    // so all of them start at the time of the latest.
+    let origins: Vec<f64> = sources
+        .iter()
+        .map(|source| source.started.load(Ordering::Relaxed) as f64 / 1e9 - source.latency_frames as f64 / rate)
+        .collect();
+    let latest = origins.iter().copied().fold(f64::MIN, f64::max);
+    let mut skip: Vec<usize> = origins.iter().map(|origin| ((latest - origin) * rate).round() as usize).collect();
+
+    let mut scratch: Vec<Vec<f32>> = sources.iter().map(|source| vec![0.0; (block + 1) * source.channels]).collect();
+    // Smoothed backlog of every input minus that of the first, and where it
+    // settled.
+    let mut drift: Vec<Option<f64>> = vec![None; sources.len()];
+    let mut baseline = vec![0.0f64; sources.len()];
+    let mut number = 0u64;
+    while !stop.load(Ordering::Relaxed) {
+        for (((source, consumer), skip), scratch) in sources.iter().zip(consumers.iter_mut()).zip(skip.iter_mut()).zip(scratch.iter_mut()) {
+            let frames = (*skip).min(frames_in(source)).min(block + 1);
+            *skip -= consumer.pop_slice(&mut scratch[..frames * source.channels]) / source.channels;
+        }
+        if skip.iter().any(|&frames| frames > 0) || sources.iter().any(|source| frames_in(source) < block + 1) {
+            std::thread::sleep(MIX_POLL);
+            continue;
+        }
+
+        let reference = frames_in(&sources[0]) as f64;
+        let mut mixed = vec![0.0f32; block * channels];
+        let mut offset = 0;
+        for (i, source) in sources.iter().enumerate() {
+            let mut take = block;
+            if i > 0 {
+                let difference = frames_in(source) as f64 - reference;
+                let smoothed = drift[i].map_or(difference, |d| d + (difference - d) * MIX_DRIFT_SMOOTHING);
+                if number == MIX_SETTLE_BLOCKS {
+                    baseline[i] = smoothed;
+                }
+                let mut corrected = smoothed;
+                if number > MIX_SETTLE_BLOCKS {
+                    // Taking a frame more or less moves the backlog by one
+                    // straight away, so the average is moved with it.
+                    if smoothed - baseline[i] > tolerance {
+                        take = block + 1;
+                        corrected -= 1.0;
+                        source.counters.dropped.fetch_add(1, Ordering::Relaxed);
+                    } else if baseline[i] - smoothed > tolerance {
+                        take = block - 1;
+                        corrected += 1.0;
+                        source.counters.duplicated.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                drift[i] = Some(corrected);
+            }
+
+            let width = source.channels;
+            let taken = &mut scratch[i][..take * width];
+            consumers[i].pop_slice(taken);
+            // One frame more is the last one left out, one less repeats it.
+            for f in 0..block {
+                let frame = &taken[f.min(take - 1) * width..][..width];
+                let out = &mut mixed[f * channels..][..channels];
+                match mode {
+                    InputMix::Sum => {
+                        for (out, &sample) in out.iter_mut().zip(frame) {
+                            *out += sample;
+                        }
+                    }
+                    InputMix::Separate => out[offset..offset + width].copy_from_slice(frame),
+                }
+            }
+            offset += width;
+        }
+        number += 1;
+        if sender.send(mixed).is_err() {
+            return;
+        }
+    }
+}
+
+/// Spectrum frames `spectrum_stream` holds for a slow reader before it
+/// drops new ones.
+const SPECTRUM_QUEUE_FRAMES: usize = 16;
+/// How often the spectrum worker looks for new input.
+const SPECTRUM_POLL: Duration = Duration::from_millis(5);
+
+/// The stream and worker behind `spectrum_stream`. Dropping it stops both.
+pub struct SpectrumStream {
+    pub handle: StreamHandle,
+    pub config: NegotiatedConfig,
+    stop: Arc<AtomicBool>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Drop for SpectrumStream {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Live spectrum of `device` at its default config, mixed to mono: a frame
+/// of `fft_size` samples every `hop` samples, Hann windowed. The callback
+/// only fills a ring; a worker thread does the FFTs, with one plan made up
+/// front. Frames arriving while `SPECTRUM_QUEUE_FRAMES` are unread are
+/// dropped. See `SpectrumFrame::octave_bands` for band levels.
+pub fn spectrum_stream(
+    device: &Device,
+    fft_size: usize,
+    hop: usize,
+) -> Result<(SpectrumStream, Receiver<SpectrumFrame>), StreamError> {
+    let fft_size = fft_size.max(2);
+    let hop = hop.clamp(1, fft_size);
+    let negotiated = input_builder(device, &StreamOptions::default(), None).negotiate()?;
+    let (sample_rate, channels) = (negotiated.sample_rate, negotiated.channels.max(1) as usize);
+    let options = StreamOptions { sample_rate: Some(sample_rate), channels: Some(negotiated.channels), buffer_frames: None };
+
+    // A second of input, or four frames if that is more.
+    let (mut input_ring, mut ring) = ring_buffer((sample_rate as usize).max(4 * fft_size));
+    let mut mono = vec![0.0f32; scratch_len(1)];
+    let (handle, config) = make_input_stream(device, &options, move |data, _| {
+        for chunk in data.chunks(channels * mono.len()) {
+            let len = mix_to_mono(chunk, channels, &mut mono);
+            input_ring.push_slice(&mono[..len]);
+        }
+    })?;
+
+    let (sender, frames) = mpsc::sync_channel(SPECTRUM_QUEUE_FRAMES);
+    let stop = Arc::new(AtomicBool::new(false));
+    let worker_stop = Arc::clone(&stop);
+    let thread = std::thread::spawn(move || {
+        let window = hann_window(fft_size);
+        // Scaled so a full-scale sine on a bin reads 0 dBFS.
+        let scale = 2.0 / window.iter().sum::<f32>();
+        let fft = rustfft::FftPlanner::new().plan_fft_forward(fft_size);
+        let bins = fft_size / 2 + 1;
+        let frequencies: Arc<[f32]> = (0..bins).map(|k| k as f32 * sample_rate as f32 / fft_size as f32).collect();
+        let mut history = vec![0.0f32; fft_size];
+        let mut buffer = vec![rustfft::num_complex::Complex { re: 0.0f32, im: 0.0 }; fft_size];
+        let mut filled = 0;
+        let mut number = 0;
+        while !worker_stop.load(Ordering::Relaxed) {
+            // Fill up to a whole frame first, then take a hop at a time.
+            let wanted = if filled < fft_size { fft_size - filled } else { hop };
+            if ring.available() < wanted {
+                std::thread::sleep(SPECTRUM_POLL);
+                continue;
+            }
+            if filled < fft_size {
+                ring.pop_slice(&mut history[filled..]);
+                filled = fft_size;
+            } else {
+                history.copy_within(hop.., 0);
+                ring.pop_slice(&mut history[fft_size - hop..]);
+            }
+            for ((bin, &sample), &w) in buffer.iter_mut().zip(&history).zip(&window) {
+                *bin = rustfft::num_complex::Complex { re: sample * w, im: 0.0 };
+            }
+            fft.process(&mut buffer);
+            let frame = SpectrumFrame {
+                number,
+                sample_rate,
+                frequencies: Arc::clone(&frequencies),
+                magnitudes_db: buffer[..bins].iter().map(|c| linear_to_db(c.norm() * scale)).collect(),
+            };
+            number += 1;
+            if let Err(TrySendError::Disconnected(_)) = sender.try_send(frame) {
+                return;
+            }
+        }
+    });
+    Ok((SpectrumStream { handle, config, stop, thread: Some(thread) }, frames))
+}
+
+/// Opens streams in either direction with options checked against the
+/// device first, so a config it can't do is an error naming the field
+/// rather than a fallback:
+///
+/// ```text
+/// let builder = StreamBuilder::new(&device).sample_rate(48000).channels(2).buffer_frames(256);
+/// let (handle, config) = builder.output(|data| data.fill(0.0))?;
+/// ```
+///
+/// Whatever isn't set comes from the device default config for the
+/// direction. Callbacks see f32 in any sample format.
+#[derive(Clone)]
+pub struct StreamBuilder<'d> {
+    device: &'d Device,
+    options: StreamOptions,
+    formats: Vec<SampleFormat>,
+}
+
+impl<'d> StreamBuilder<'d> {
+    pub fn new(device: &'d Device) -> Self {
+        Self { device, options: StreamOptions::default(), formats: CALLBACK_FORMATS.to_vec() }
+    }
+
+    pub fn sample_rate(mut self, sample_rate: u32) -> Self {
+        self.options.sample_rate = Some(sample_rate);
+        self
+    }
+
+    pub fn channels(mut self, channels: u16) -> Self {
+        self.options.channels = Some(channels);
+        self
+    }
+
+    pub fn buffer_frames(mut self, frames: u32) -> Self {
+        self.options.buffer_frames = Some(frames);
+        self
+    }
+
+    /// Sample formats to open the device in, best first; the first one the
+    /// device offers is used. `CALLBACK_FORMATS` by default.
+    pub fn format_preference(mut self, formats: &[SampleFormat]) -> Self {
+        self.formats = formats.to_vec();
+        self
+    }
+
+    /// The config and format the builder opens for `direction`, checked
+    /// against the device.
+    pub fn config(&self, direction: Direction) -> Result<(StreamConfig, SampleFormat), StreamError> {
+        let default = match direction {
+            Direction::Input => self.device.default_input_config()?,
+            Direction::Output => self.device.default_output_config()?,
+        };
+        let config = self.options.apply(&default.config());
+        let format = preferred_format(self.device, direction, &self.formats).ok_or_else(|| {
+            let offered = supported_sample_formats(self.device, direction);
+            DeviceError::UnsupportedConfig(format!(
+                "{} sample format: none of {:?} supported, the device offers {:?}",
+                direction, self.formats, offered
+            ))
+        })?;
+        check_config(self.device, &config, format, direction)?;
+        check_buffer_size(self.device, &config, format, direction)?;
+        Ok((config, format))
+    }
+
+    /// Opens an input stream handing `callback` interleaved f32 blocks.
+    pub fn input<F>(&self, callback: F) -> Result<(StreamHandle, StreamConfig), StreamError>
+    where
+        F: FnMut(&[f32]) + Send + 'static,
+    {
+        let (config, format) = self.config(Direction::Input)?;
+        let mut builder = InputStreamBuilder::new(self.device)
+            .sample_rate(config.sample_rate.0)
+            .channels(config.channels)
+            .sample_format(format);
+        if let BufferSize::Fixed(frames) = config.buffer_size {
+            builder = builder.buffer_size(frames);
+        }
+        let (handle, _) = builder.on_data(callback).build()?;
+        Ok((handle, config))
+    }
+
+    /// Opens an output stream asking `fill` for every interleaved f32
+    /// block; it plays silence while paused.
+    pub fn output<F>(&self, fill: F) -> Result<(StreamHandle, StreamConfig), StreamError>
+    where
+        F: FnMut(&mut [f32]) + Send + 'static,
+    {
+        let (config, format) = self.config(Direction::Output)?;
+        let control = Arc::new(StreamControl::new());
+        let (device, shared) = (self.device, Arc::clone(&control));
+        let stream = match format {
+            SampleFormat::F32 => fill_stream::<f32, F>(device, &config, shared, fill)?,
+            SampleFormat::F64 => fill_stream::<f64, F>(device, &config, shared, fill)?,
+            SampleFormat::I8 => fill_stream::<i8, F>(device, &config, shared, fill)?,
+            SampleFormat::I16 => fill_stream::<i16, F>(device, &config, shared, fill)?,
+            SampleFormat::I32 => fill_stream::<i32, F>(device, &config, shared, fill)?,
+            SampleFormat::I64 => fill_stream::<i64, F>(device, &config, shared, fill)?,
+            SampleFormat::U8 => fill_stream::<u8, F>(device, &config, shared, fill)?,
+            SampleFormat::U16 => fill_stream::<u16, F>(device, &config, shared, fill)?,
+            SampleFormat::U32 => fill_stream::<u32, F>(device, &config, shared, fill)?,
+            SampleFormat::U64 => fill_stream::<u64, F>(device, &config, shared, fill)?,
+            format => return Err(StreamError::Unsupported(format!("{} samples", format))),
+        };
+        stream.play()?;
+        Ok((StreamHandle::new(stream, control), config))
+    }
+}
+
+// Output stream filling f32 blocks with `fill` and converting them to `T`.
+fn fill_stream<T, F>(
+    device: &Device,
+    config: &StreamConfig,
+    control: Arc<StreamControl>,
+    mut fill: F,
+) -> Result<Stream, BuildStreamError>
+where
+    T: SizedSample + FromSample<f32>,
+    F: FnMut(&mut [f32]) + Send + 'static,
+{
+    let channels = config.channels.max(1) as usize;
+    // Sized for the granted buffer; a longer block is filled in pieces.
+    let mut block = vec![0.0f32; match config.buffer_size {
+        BufferSize::Fixed(frames) => (frames as usize * channels).max(scratch_len(channels)),
+        BufferSize::Default => scratch_len(channels),
+    }];
+    let mut guard = OutputGuard::with_defaults(config.sample_rate.0, config.channels);
+    device.build_output_stream(
+        config,
+        move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            callback_scope(|| {
+                if !control.begin_block(data.len() / channels) {
+                    data.fill(T::EQUILIBRIUM);
+                    return;
+                }
+                write_guarded(data, &mut block, &mut guard, |block| {
+                    block.fill(0.0);
+                    fill(block);
+                });
+            })
+        },
+        log_stream_error(),
+        None,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    fn sine(rate: u32, freq: f32, frames: usize) -> Vec<f32> {
+        (0..frames).map(|i| (std::f32::consts::TAU * freq * i as f32 / rate as f32).sin() * 0.5).collect()
+    }
+
+    // What the output callback does with the reader, a `block` at a time,
+    // repeating the buffer `repeats` times, until it has nothing left.
+    fn drain(reader: &mut AdaptedReader, source: &PlayerSource, block: usize, mut repeats: usize) -> Vec<f32> {
+        let mut out = Vec::new();
+        let mut data = vec![0.0; block];
+        loop {
+            let mut written = 0;
+            let mut ended = false;
+            while written < block {
+                written += reader.fill(source, &mut data[written..]);
+                if written == block || !source.is_finished() {
+                    break;
+                }
+                if repeats > 0 {
+                    repeats -= 1;
+                    source.seek(0);
+                    reader.rewind();
+                } else if !reader.finish() {
+                    ended = true;
+                    break;
+                }
+            }
+            out.extend_from_slice(&data[..written]);
+            if ended {
+                return out;
+            }
+        }
+    }
+
+    fn playing(samples: Vec<f32>, rate: u32, channels: u16) -> PlayerSource {
+        let source = PlayerSource::new(samples, rate, channels);
+        source.play();
+        source
+    }
+
+    #[test]
+    fn playback_conversion_keeps_the_pitch() {
+        let source = playing(sine(44100, 1000.0, 44100), 44100, 1);
+        let mut reader = AdaptedReader::new(FormatAdapter::new(AudioSpec::new(44100, 1), AudioSpec::new(48000, 2)));
+        let out = drain(&mut reader, &source, 512 * 2, 0);
+        let left: Vec<f32> = out.chunks(2).map(|frame| frame[0]).collect();
+        assert_eq!(left.len(), 44100usize * 48000 / 44100);
+        // A second at 48 kHz: one bin per hertz.
+        let bins = crate::fft::fft(&left[..32768]);
+        let peak = (1..bins.len() / 2).max_by(|&a, &b| bins[a].norm().total_cmp(&bins[b].norm())).unwrap();
+        let freq = peak as f32 * 48000.0 / 32768.0;
+        assert!((freq - 1000.0).abs() < 48000.0 / 32768.0 * 1.5, "peak at {freq} Hz");
+    }
+
+    #[test]
+    fn playback_conversion_per_block_matches_converting_up_front() {
+        let samples = sine(44100, 440.0, 10_000);
+        let (src, dst) = (AudioSpec::new(44100, 1), AudioSpec::new(48000, 2));
+        let expected = FormatAdapter::new(src, dst).process_all(&samples);
+        for block in [64, 1000, 4096] {
+            let source = playing(samples.clone(), 44100, 1);
+            let mut reader = AdaptedReader::new(FormatAdapter::new(src, dst));
+            assert_eq!(drain(&mut reader, &source, block, 0), expected, "block {block}");
+        }
+    }
+
+    #[test]
+    fn playback_conversion_joins_loops_without_a_gap() {
+        let samples = sine(44100, 300.0, 3000);
+        let (src, dst) = (AudioSpec::new(44100, 1), AudioSpec::new(48000, 1));
+        let looped = [samples.as_slice(); 3].concat();
+        let expected = FormatAdapter::new(src, dst).process_all(&looped);
+        let source = playing(samples, 44100, 1);
+        let mut reader = AdaptedReader::new(FormatAdapter::new(src, dst));
+        assert_eq!(drain(&mut reader, &source, 700, 2), expected);
+    }
+
+    #[test]
+    fn playback_conversion_starts_over_after_a_seek() {
+        let samples = sine(44100, 500.0, 8000);
+        let (src, dst) = (AudioSpec::new(44100, 1), AudioSpec::new(48000, 1));
+        let source = playing(samples.clone(), 44100, 1);
+        let mut reader = AdaptedReader::new(FormatAdapter::new(src, dst));
+        let mut data = [0.0; 300];
+        assert_eq!(reader.fill(&source, &mut data), 300);
+        source.seek(5000);
+        let expected = FormatAdapter::new(src, dst).process_all(&samples[5000..]);
+        assert_eq!(drain(&mut reader, &source, 300, 0), expected);
+    }
+
+    #[test]
+    fn ring_buffer_counts_overflows_and_underflows() {
+        let (mut producer, mut consumer) = ring_buffer::<u32>(4);
+        let ring = producer.ring();
+        assert_eq!(producer.push_slice(&[1, 2, 3]), 3);
+        assert_eq!(producer.push_slice(&[4, 5]), 1);
+        assert_eq!((ring.available(), ring.overflows()), (4, 1));
+        let mut out = [0; 6];
+        assert_eq!(consumer.pop_slice(&mut out), 4);
+        assert_eq!(out[..4], [1, 2, 3, 4]);
+        assert_eq!((ring.available(), ring.underflows()), (0, 1));
+        // Wrapping around the end of the storage.
+        assert_eq!(producer.push_slice(&[6, 7, 8]), 3);
+        assert_eq!(consumer.pop_slice(&mut out[..3]), 3);
+        assert_eq!(out[..3], [6, 7, 8]);
+        assert_eq!(consumer.underflows(), 1);
+    }
+
+    #[test]
+    fn smoothed_gain_reaches_its_target_in_exactly_the_ramp_length() {
+        for (ramp, rate) in [(1, 0), (7, 0), (480, 48_000), (441, 44_100)] {
+            let mut gain = if rate == 0 { SmoothedGain::new(0.0, ramp) } else { SmoothedGain::with_rate(0.0, rate) };
+            gain.set_target(1.0);
+            let steps: Vec<f32> = (0..ramp).map(|_| gain.next_gain()).collect();
+            assert_eq!(steps[ramp - 1], 1.0, "ramp of {}", ramp);
+            assert!(steps[..ramp - 1].iter().all(|&g| g < 1.0), "ramp of {} ends early", ramp);
+            assert!(steps.windows(2).all(|pair| pair[1] > pair[0]), "ramp of {} is not monotonic", ramp);
+            assert!(!gain.is_ramping());
+            assert!((0..ramp).all(|_| gain.next_gain() == 1.0), "ramp of {} overshoots", ramp);
+        }
+
+        // Down again, from the middle of a ramp.
+        let mut gain = SmoothedGain::new(0.0, 100);
+        gain.set_target(1.0);
+        for _ in 0..50 {
+            gain.next_gain();
+        }
+        gain.set_target(0.0);
+        let steps: Vec<f32> = (0..100).map(|_| gain.next_gain()).collect();
+        assert_eq!(steps[99], 0.0);
+        assert!(steps.windows(2).all(|pair| pair[1] < pair[0] && pair[1] >= 0.0));
+
+        // What the callback does with a knob turned from another thread.
+        let knob = Arc::new(SharedGain::new(1.0));
+        let mut smoothed = SmoothedGain::with_rate(knob.linear(), 48_000);
+        let turner = Arc::clone(&knob);
+        thread::spawn(move || turner.set_db(-6.0)).join().unwrap();
+        smoothed.set_target(knob.linear());
+        let mut block = vec![1.0; 2 * 480 * 2];
+        smoothed.apply_frames(&mut block, 2);
+        assert!((block[2 * 479] - db_to_linear(-6.0)).abs() < 1e-6 && block[2 * 479] == block[2 * 479 + 1]);
+        assert!(block[2 * 478] > block[2 * 479]);
+    }
+
+    #[test]
+    fn monitor_mixer_sums_live_into_every_channel_with_playback() {
+        let mut mixer = MonitorMixer::new(48_000, 2, 1.0, 0.5);
+        let live = [0.1, -0.2, 0.3];
+        let playback = [0.4, 0.6, -0.8, 1.0, 0.2, 0.0];
+        let mut out = [0.0; 6];
+        mixer.mix(&mut out, Some(&live), Some(&playback));
+        let expected = [0.3, 0.4, -0.6, 0.3, 0.4, 0.3];
+        for (got, want) in out.iter().zip(expected) {
+            assert!((got - want).abs() < 1e-6, "{:?}", out);
+        }
+        mixer.mix(&mut out, None, Some(&playback));
+        assert_eq!(out, playback.map(|s| s * 0.5));
+        assert_eq!((mixer.live_underruns(), mixer.playback_underruns()), (0, 0));
+    }
+
+    #[test]
+    fn monitor_mixer_ramps_gain_changes() {
+        // 10 ms at 48 kHz.
+        const RAMP: usize = 480;
+        let mut mixer = MonitorMixer::new(48_000, 1, 0.0, 1.0);
+        mixer.set_gains(1.0, 0.0);
+        let (live, playback) = (vec![1.0; 2 * RAMP], vec![1.0; 2 * RAMP]);
+        let mut out = vec![0.0; 2 * RAMP];
+        mixer.mix(&mut out, Some(&live), Some(&playback));
+        // One gain goes up as the other comes down, so the sum stays at 1.
+        assert!(out.iter().all(|s| (s - 1.0).abs() < 1e-5));
+
+        let mut live_only = vec![0.0; 2 * RAMP];
+        let mut mixer = MonitorMixer::new(48_000, 1, 0.0, 1.0);
+        mixer.set_gains(1.0, 1.0);
+        mixer.mix(&mut live_only, Some(&live), None);
+        assert!(live_only.windows(2).all(|pair| pair[1] >= pair[0] && pair[1] - pair[0] <= 1.0 / RAMP as f32 + 1e-6));
+        assert!(live_only[RAMP - 2] < 1.0);
+        assert!(live_only[RAMP - 1..].iter().all(|&s| s == 1.0));
+    }
+
+    #[test]
+    fn monitor_mixer_plays_an_underrun_as_silence_and_counts_it() {
+        let mut mixer = MonitorMixer::new(48_000, 2, 1.0, 1.0);
+        let mut out = [9.0; 8];
+        mixer.mix(&mut out, Some(&[0.5, 0.25]), Some(&[0.1; 8]));
+        assert_eq!(out, [0.6, 0.6, 0.35, 0.35, 0.1, 0.1, 0.1, 0.1]);
+        assert_eq!((mixer.live_underruns(), mixer.playback_underruns()), (1, 0));
+        mixer.mix(&mut out, Some(&[0.5; 4]), Some(&[0.1; 3]));
+        assert_eq!(out, [0.6, 0.6, 0.6, 0.5, 0.5, 0.5, 0.5, 0.5]);
+        assert_eq!((mixer.live_underruns(), mixer.playback_underruns()), (1, 1));
+    }
+
+    #[test]
+    fn monitor_path_counts_the_live_ring_running_dry() {
+        let (mut input, mut ring) = ring_buffer::<f32>(4096);
+        let mut compensator = DriftCompensator::new(48_000, MONITOR_CHUNK_FRAMES);
+        let mut mixer = MonitorMixer::new(48_000, 1, 1.0, 1.0);
+        let mut live = [0.0; 256];
+        let mut out = [0.0; 256];
+        input.push_slice(&[0.5; 1000]);
+        for _ in 0..3 {
+            let len = compensator.process(&mut ring, &mut live);
+            mixer.mix(&mut out, Some(&live[..len]), None);
+        }
+        // 1000 samples cover three blocks of 256 but not a fourth.
+        assert_eq!(mixer.live_underruns(), 0);
+        let len = compensator.process(&mut ring, &mut live);
+        assert!(len < live.len());
+        mixer.mix(&mut out, Some(&live[..len]), None);
+        assert_eq!(mixer.live_underruns(), 1);
+        assert!(out[len..].iter().all(|&s| s == 0.0));
+        assert_eq!(ring.underflows(), 1);
+    }
+
+    // Minutes of a producer and a consumer on clocks at different rates,
+    // 480-frame blocks each, joined by a monitor-sized ring read through a
+    // compensator. Returns the lowest and highest fill after the first
+    // window, short blocks and overflowed pushes.
+    fn simulate_drift(producer_rate: f64, consumer_rate: f64, minutes: u32) -> (usize, usize, usize, u64) {
+        const BLOCK: usize = 480;
+        let (mut producer, mut consumer) = ring_buffer::<f32>(9600);
+        let mut compensator = DriftCompensator::new(48_000, MONITOR_CHUNK_FRAMES);
+        let input: Vec<f32> = (0..BLOCK).map(|i| (i as f32 * 0.05).sin() * 0.5).collect();
+        let mut out = [0.0; BLOCK];
+        // Half full before the consumer starts.
+        while producer.available() < 4800 {
+            producer.push_slice(&input);
+        }
+        let (produce_every, consume_every) = (BLOCK as f64 / producer_rate, BLOCK as f64 / consumer_rate);
+        let (mut next_produce, mut next_consume) = (0.0, 0.0);
+        let (mut low, mut high, mut short, mut reads) = (usize::MAX, 0, 0, 0);
+        while next_consume < minutes as f64 * 60.0 {
+            if next_produce <= next_consume {
+                producer.push_slice(&input);
+                next_produce += produce_every;
+            } else {
+                if compensator.process(&mut consumer, &mut out) < BLOCK {
+                    short += 1;
+                }
+                reads += 1;
+                if reads > 2 * DRIFT_WINDOW {
+                    low = low.min(consumer.available());
+                    high = high.max(consumer.available());
+                }
+                next_consume += consume_every;
+            }
+        }
+        (low, high, short, producer.overflows())
+    }
+
+    #[test]
+    fn drift_compensator_holds_the_fill_between_mismatched_clocks() {
+        // 9 Hz apart is about 190 ppm, inside `DRIFT_MAX_PPM`. Uncorrected,
+        // that is 2700 frames over five minutes, more than half the ring.
+        for (producer_rate, consumer_rate) in [(48_000.0, 48_009.0), (48_009.0, 48_000.0)] {
+            let (low, high, short, overflows) = simulate_drift(producer_rate, consumer_rate, 5);
+            assert_eq!((short, overflows), (0, 0), "{} -> {} Hz", producer_rate, consumer_rate);
+            assert!(high - low < 1000, "{} -> {} Hz: fill between {} and {}", producer_rate, consumer_rate, low, high);
+        }
+    }
+
+    #[test]
+    fn queued_output_keeps_the_order_and_counts_underruns() {
+        const FRAMES: usize = 200_000;
+        const CHANNELS: usize = 2;
+        let (mut producer, mut consumer) = ring_buffer::<f32>(512 * CHANNELS);
+        // The callback's side: blocks of one size, silence for what's missing.
+        let mut block = [0.0f32; 64 * CHANNELS];
+        assert_eq!(consumer.pop_slice(&mut block), 0);
+        let mut short_blocks = 1;
+
+        let feeder = thread::spawn(move || {
+            // Frame n carries n + 1 on both channels, so silence stands out.
+            let samples: Vec<f32> = (0..FRAMES).flat_map(|n| [(n + 1) as f32; CHANNELS]).collect();
+            let mut offset = 0;
+            let mut size = 1;
+            while offset < samples.len() {
+                // Irregular chunks, odd lengths included: only whole frames go in.
+                size = size * 7 % 331 + 1;
+                let end = (offset + size).min(samples.len());
+                let taken = push_frames(&mut producer, &samples[offset..end], CHANNELS);
+                assert_eq!(taken % CHANNELS, 0);
+                offset += taken;
+                if taken == 0 || size % 5 == 0 {
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut next = 1.0;
+        while next <= FRAMES as f32 {
+            let count = consumer.pop_slice(&mut block);
+            block[count..].fill(0.0);
+            if count < block.len() {
+                short_blocks += 1;
+                thread::yield_now();
+            }
+            for frame in block[..count].chunks(CHANNELS) {
+                assert_eq!(frame, [next; CHANNELS], "frames out of order");
+                next += 1.0;
+            }
+        }
+        feeder.join().unwrap();
+        assert_eq!(consumer.pop_slice(&mut block), 0);
+        short_blocks += 1;
+        assert_eq!(consumer.underflows(), short_blocks);
+    }
+
+    #[test]
+    fn ring_buffer_hands_over_every_item_once_between_threads() {
+        const ITEMS: u64 = 2_000_000;
+        let (mut producer_ring, mut ring) = ring_buffer::<u64>(1023);
+        let producer = thread::spawn(move || {
+            let mut next = 0u64;
+            let mut chunk = [0u64; 97];
+            let mut size = 1;
+            while next < ITEMS {
+                // Chunk sizes that move around the capacity.
+                size = size % chunk.len() + 1;
+                let count = (size as u64).min(ITEMS - next) as usize;
+                for (i, item) in chunk[..count].iter_mut().enumerate() {
+                    *item = next + i as u64;
+                }
+                let pushed = producer_ring.push_slice(&chunk[..count]);
+                next += pushed as u64;
+                if pushed < count {
+                    thread::yield_now();
+                }
+            }
+        });
+
+        let mut expected = 0u64;
+        let mut out = [0u64; 61];
+        let mut size = 1;
+        while expected < ITEMS {
+            size = size % out.len() + 1;
+            let count = ring.pop_slice(&mut out[..size]);
+            for &item in &out[..count] {
+                assert_eq!(item, expected, "lost or duplicated an item");
+                expected += 1;
+            }
+            if count == 0 {
+                thread::yield_now();
+            }
+        }
+        producer.join().unwrap();
+        assert_eq!(ring.available(), 0);
+    }
+
+    // A clock the test controls, in nanoseconds.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    struct FakeInstant(u64);
+
+    impl CaptureInstant for FakeInstant {
+        fn after(self, duration: Duration) -> Self {
+            FakeInstant(self.0 + duration.as_nanos() as u64)
+        }
+    }
+
+    #[test]
+    fn block_frame_counter_follows_the_samples_when_the_clock_jumps() {
+        let (rate, channels) = (48_000, 2u16);
+        let (mut sender, blocks) = block_channel::<FakeInstant>(rate, channels, 64);
+        let part_frames = sender.block_samples as u64 / channels as u64;
+        let mut expected = std::collections::HashMap::new();
+        let mut received: Vec<CapturedBlock<FakeInstant>> = Vec::new();
+        let (mut now, mut frame, mut seed) = (1_000_000_000u64, 0u64, 7u32);
+        for callback in 0..200 {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let frames = 1 + (seed >> 8) as u64 % 10_000;
+            if callback == 50 {
+                now += 10_000_000_000;
+            }
+            if callback == 120 {
+                now -= 3_000_000_000;
+            }
+            let capture = FakeInstant(now);
+            let handed_over = FakeInstant(now + 2_000_000);
+            let samples: Vec<f32> = (frame..frame + frames).flat_map(|f| [f as f32, -(f as f32)]).collect();
+            let mut offset = 0;
+            while offset < frames {
+                let part = part_frames.min(frames - offset);
+                expected.insert(frame + offset, (part, capture.after(frames_to_duration(offset, rate)), handed_over));
+                offset += part;
+            }
+            sender.send_at(&samples, capture, handed_over, 0);
+            frame += frames;
+            now += frames_to_duration(frames, rate).as_nanos() as u64;
+            // Leave the blocks queued for a while so the pool runs dry.
+            if (80..90).contains(&callback) {
+                continue;
+            }
+            while let Ok(block) = blocks.try_recv() {
+                received.push(CapturedBlock { samples: block.samples.clone(), ..block });
+                sender.recycle.try_send(block.samples).unwrap();
+            }
+        }
+        assert_eq!(sender.next_frame, frame);
+        let starved = sender.counters.starved.load(Ordering::Relaxed) as usize;
+        assert!(starved > 0);
+        assert_eq!(received.len() + starved, expected.len());
+        let mut end = 0;
+        let mut jumps = 0;
+        for block in &received {
+            assert!(block.first_frame >= end);
+            if block.first_frame > end {
+                jumps += 1;
+            }
+            let (frames, capture, handed_over) = expected[&block.first_frame];
+            assert_eq!(block.frames() as u64, frames);
+            assert_eq!(block.capture_instant, capture);
+            assert_eq!(block.callback_instant, handed_over);
+            assert_eq!(block.samples[0], block.first_frame as f32);
+            end = block.first_frame + frames;
+        }
+        assert!(jumps > 0);
+    }
+
+    struct Scale(f32);
+
+    impl Effect for Scale {
+        fn process_block(&mut self, block: &mut [f32]) {
+            block.iter_mut().for_each(|sample| *sample *= self.0);
+        }
+    }
+
+    struct Clip(f32);
+
+    impl Effect for Clip {
+        fn process_block(&mut self, block: &mut [f32]) {
+            block.iter_mut().for_each(|sample| *sample = sample.clamp(-self.0, self.0));
+        }
+    }
+
+    // Runs `process_and_send` on one callback's `data` and returns the
+    // samples queued, in order.
+    fn processed(data: &[f32], channels: usize, scratch: usize, effects: Vec<Box<dyn Effect>>) -> Vec<f32> {
+        let (mut sender, blocks) = block_channel::<FakeInstant>(48_000, 1, 64);
+        let mut scratch = vec![0.0; scratch];
+        let mut chain = EffectChain::new(effects);
+        callback_scope(|| {
+            process_and_send(data, channels, &mut scratch, &mut chain, &mut sender, FakeInstant(0), FakeInstant(1))
+        });
+        assert_eq!(sender.counters.starved.load(Ordering::Relaxed), 0);
+        drop(sender);
+        blocks.into_iter().flat_map(|block| block.samples).collect()
+    }
+
+    #[test]
+    fn processed_input_runs_the_chain_in_order() {
+        let data = [0.1, 0.3, -0.4, 0.6];
+        let scale_then_clip = processed(&data, 1, 64, vec![Box::new(Scale(2.0)), Box::new(Clip(0.5))]);
+        let clip_then_scale = processed(&data, 1, 64, vec![Box::new(Clip(0.5)), Box::new(Scale(2.0))]);
+        assert_eq!(scale_then_clip, [0.2, 0.5, -0.5, 0.5]);
+        assert_eq!(clip_then_scale, [0.2, 0.6, -0.8, 1.0]);
+    }
+
+    #[test]
+    fn processed_input_mixes_to_mono_across_scratch_sized_pieces() {
+        let frames = 10;
+        let data: Vec<f32> = (0..frames).flat_map(|f| [f as f32, f as f32 + 2.0]).collect();
+        let expected: Vec<f32> = (0..frames).map(|f| f as f32 + 1.0).collect();
+        assert_eq!(processed(&data, 2, 64, Vec::new()), expected);
+        assert_eq!(processed(&data, 2, 3, Vec::new()), expected);
+    }
+
+    #[test]
+    fn forwarded_blocks_reach_the_sink_and_return_to_the_pool() {
+        let (mut sender, blocks) = block_channel::<FakeInstant>(48_000, 1, 64);
+        let (sink, received) = mpsc::channel();
+        let pool = sender.recycle.clone();
+        let forwarder = thread::spawn(move || forward_blocks(blocks, &pool, &sink));
+        let count = CHANNEL_BLOCKS * 4;
+        for block in 0..count {
+            // The buffer is back in the pool before the sink sees the
+            // block, so none is dropped however many go through.
+            sender.send_at(&[block as f32; 8], FakeInstant(0), FakeInstant(0), 0);
+            assert_eq!(received.recv_timeout(Duration::from_secs(5)).unwrap(), [block as f32; 8]);
+        }
+        assert_eq!(sender.counters.starved.load(Ordering::Relaxed), 0);
+        drop(sender);
+        forwarder.join().unwrap();
+        assert!(received.try_recv().is_err());
+    }
+
+    #[test]
+    fn supervisor_restarts_after_the_device_goes_and_gives_up_after_its_backoff() {
+        let delay = Duration::from_millis(20);
+        let policy = RestartPolicy { max_attempts: 4, initial_delay: delay, max_delay: delay * 3 };
+        // Opens: fine, then the device goes; one failure and it is back.
+        // It goes again and never comes back.
+        let mut script = vec![true, false, true, false, false, false, false].into_iter();
+        let (opened, opens) = mpsc::channel();
+        let open = move || {
+            opened.send(Instant::now()).unwrap();
+            match script.next() {
+                Some(true) => Ok(()),
+                _ => Err(StreamError::Build(BuildStreamError::DeviceNotAvailable)),
+            }
+        };
+        let (device_errors, errors) = mpsc::sync_channel(SUPERVISOR_ERROR_QUEUE);
+        let (events, received) = mpsc::channel();
+        let stop = AtomicBool::new(false);
+        let wait = Duration::from_secs(5);
+        let mut first = Instant::now();
+        thread::scope(|scope| {
+            scope.spawn(move || supervise(open, &errors, policy, &events, &stop));
+            first = opens.recv_timeout(wait).unwrap();
+            device_errors.send(cpal::StreamError::DeviceNotAvailable).unwrap();
+            assert_eq!(received.recv_timeout(wait).unwrap(), StreamEvent::Restarted { attempts: 2 });
+            device_errors.send(cpal::StreamError::DeviceNotAvailable).unwrap();
+            match received.recv_timeout(wait).unwrap() {
+                StreamEvent::GaveUp { attempts, error } => {
+                    assert_eq!(attempts, 4);
+                    assert!(error.contains("Failed to build stream"), "{}", error);
+                }
+                event => panic!("{:?}", event),
+            }
+        });
+        assert!(received.try_recv().is_err());
 
-    Ok(stream)
+        let opens: Vec<Instant> = std::iter::once(first).chain(opens.try_iter()).collect();
+        assert_eq!(opens.len(), 7);
+        // Each time the device goes there is a pause before the first
+        // attempt, then the delay doubles after every failure up to
+        // `max_delay`.
+        let expected = [delay, delay, delay, delay, delay * 2, delay * 3];
+        for (gap, (pair, least)) in opens.windows(2).zip(&expected).enumerate() {
+            assert!(pair[1] - pair[0] >= *least, "gap {}: {:?}", gap, pair[1] - pair[0]);
+        }
+    }
 }
@@ -0,0 +1,634 @@
+// Minimal TOML reader/writer for the config and project files.
+//
+// Supports the subset those files use: comments, `[table]` and `[[array]]`
+// headers (dotted names allowed), bare/quoted keys, basic and literal
+// strings, integers, floats, booleans, arrays and inline tables.
+use std::collections::BTreeMap;
+use std::fmt;
+
+pub type Table = BTreeMap<String, Value>;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Boolean(bool),
+    Array(Vec<Value>),
+    Table(Table),
+}
+
+impl Value {
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<i64> {
+        match self {
+            Value::Integer(i) => Some(*i),
+            _ => None,
+        }
+    }
+
+    /// Integers are accepted where a float is expected.
+    pub fn as_float(&self) -> Option<f64> {
+        match self {
+            Value::Float(f) => Some(*f),
+            Value::Integer(i) => Some(*i as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Boolean(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Value>> {
+        match self {
+            Value::Array(a) => Some(a),
+            _ => None,
+        }
+    }
+
+    pub fn as_table(&self) -> Option<&Table> {
+        match self {
+            Value::Table(t) => Some(t),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+pub fn parse(input: &str) -> Result<Table, ParseError> {
+    Parser { chars: input.chars().collect(), pos: 0, line: 1 }.parse_document()
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    line: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, message: impl Into<String>) -> Result<T, ParseError> {
+        Err(ParseError { line: self.line, message: message.into() })
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.pos += 1;
+        if c == '\n' {
+            self.line += 1;
+        }
+        Some(c)
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), ParseError> {
+        match self.peek() {
+            Some(c) if c == expected => {
+                self.bump();
+                Ok(())
+            }
+            Some('\n') => self.error(format!("expected '{}', found end of line", expected)),
+            Some(c) => self.error(format!("expected '{}', found '{}'", expected, c)),
+            None => self.error(format!("expected '{}', found end of file", expected)),
+        }
+    }
+
+    // Skips spaces and tabs on the current line.
+    fn skip_inline_whitespace(&mut self) {
+        while matches!(self.peek(), Some(' ') | Some('\t')) {
+            self.bump();
+        }
+    }
+
+    // Skips whitespace, newlines and comments.
+    fn skip_blank(&mut self) {
+        loop {
+            match self.peek() {
+                Some(' ') | Some('\t') | Some('\r') | Some('\n') => {
+                    self.bump();
+                }
+                Some('#') => self.skip_comment(),
+                _ => break,
+            }
+        }
+    }
+
+    fn skip_comment(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '\n' {
+                break;
+            }
+            self.bump();
+        }
+    }
+
+    // After a value or header only a comment may follow on the same line.
+    fn end_of_line(&mut self) -> Result<(), ParseError> {
+        self.skip_inline_whitespace();
+        match self.peek() {
+            None | Some('\n') => Ok(()),
+            Some('\r') => {
+                self.bump();
+                Ok(())
+            }
+            Some('#') => {
+                self.skip_comment();
+                Ok(())
+            }
+            Some(c) => self.error(format!("unexpected '{}' after value", c)),
+        }
+    }
+
+    fn parse_document(&mut self) -> Result<Table, ParseError> {
+        let mut root = Table::new();
+        // Path of the table that key/value pairs currently go into.
+        let mut current: Vec<String> = Vec::new();
+
+        loop {
+            self.skip_blank();
+            match self.peek() {
+                None => break,
+                Some('[') => {
+                    self.bump();
+                    let is_array = self.peek() == Some('[');
+                    if is_array {
+                        self.bump();
+                    }
+                    self.skip_inline_whitespace();
+                    let path = self.parse_key_path()?;
+                    self.skip_inline_whitespace();
+                    self.expect(']')?;
+                    if is_array {
+                        self.expect(']')?;
+                        self.push_array_table(&mut root, &path)?;
+                    } else {
+                        self.table_at(&mut root, &path)?;
+                    }
+                    self.end_of_line()?;
+                    current = path;
+                }
+                Some(_) => {
+                    let path = self.parse_key_path()?;
+                    self.skip_inline_whitespace();
+                    self.expect('=')?;
+                    self.skip_inline_whitespace();
+                    let value = self.parse_value()?;
+                    self.end_of_line()?;
+                    let table = self.table_at(&mut root, &current)?;
+                    self.insert(table, &path, value)?;
+                }
+            }
+        }
+
+        Ok(root)
+    }
+
+    fn parse_key_path(&mut self) -> Result<Vec<String>, ParseError> {
+        let mut path = vec![self.parse_key()?];
+        loop {
+            self.skip_inline_whitespace();
+            if self.peek() != Some('.') {
+                break;
+            }
+            self.bump();
+            self.skip_inline_whitespace();
+            path.push(self.parse_key()?);
+        }
+        Ok(path)
+    }
+
+    fn parse_key(&mut self) -> Result<String, ParseError> {
+        match self.peek() {
+            Some('"') => self.parse_basic_string(),
+            Some('\'') => self.parse_literal_string(),
+            _ => {
+                let mut key = String::new();
+                while let Some(c) = self.peek() {
+                    if c.is_ascii_alphanumeric() || c == '_' || c == '-' {
+                        key.push(c);
+                        self.bump();
+                    } else {
+                        break;
+                    }
+                }
+                if key.is_empty() {
+                    return self.error("expected a key");
+                }
+                Ok(key)
+            }
+        }
+    }
+
+    // Returns the table at `path`, creating intermediate tables as needed.
+    // When a path component is an array of tables, its last element is used.
+    fn table_at<'a>(&self, root: &'a mut Table, path: &[String]) -> Result<&'a mut Table, ParseError> {
+        let mut table = root;
+        for key in path {
+            let entry = table.entry(key.clone()).or_insert_with(|| Value::Table(Table::new()));
+            table = match entry {
+                Value::Table(t) => t,
+                Value::Array(items) => match items.last_mut() {
+                    Some(Value::Table(t)) => t,
+                    _ => return self.error(format!("'{}' is not a table", key)),
+                },
+                _ => return self.error(format!("'{}' is not a table", key)),
+            };
+        }
+        Ok(table)
+    }
+
+    fn push_array_table(&self, root: &mut Table, path: &[String]) -> Result<(), ParseError> {
+        let (last, parents) = path.split_last().expect("key path is never empty");
+        let parent = self.table_at(root, parents)?;
+        match parent.entry(last.clone()).or_insert_with(|| Value::Array(Vec::new())) {
+            Value::Array(items) => {
+                items.push(Value::Table(Table::new()));
+                Ok(())
+            }
+            _ => self.error(format!("'{}' is not an array of tables", last)),
+        }
+    }
+
+    fn insert(&self, table: &mut Table, path: &[String], value: Value) -> Result<(), ParseError> {
+        let (last, parents) = path.split_last().expect("key path is never empty");
+        let table = self.table_at(table, parents)?;
+        if table.contains_key(last) {
+            return self.error(format!("duplicate key '{}'", last));
+        }
+        table.insert(last.clone(), value);
+        Ok(())
+    }
+
+    fn parse_value(&mut self) -> Result<Value, ParseError> {
+        match self.peek() {
+            Some('"') => Ok(Value::String(self.parse_basic_string()?)),
+            Some('\'') => Ok(Value::String(self.parse_literal_string()?)),
+            Some('[') => self.parse_array(),
+            Some('{') => self.parse_inline_table(),
+            Some('t') | Some('f') => self.parse_bool(),
+            Some(_) => self.parse_number(),
+            None => self.error("expected a value"),
+        }
+    }
+
+    fn parse_basic_string(&mut self) -> Result<String, ParseError> {
+        self.expect('"')?;
+        let mut s = String::new();
+        loop {
+            match self.string_char()? {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escaped = match self.bump() {
+                        Some('n') => '\n',
+                        Some('t') => '\t',
+                        Some('r') => '\r',
+                        Some('"') => '"',
+                        Some('\\') => '\\',
+                        Some('u') => self.parse_unicode_escape()?,
+                        Some(c) => return self.error(format!("invalid escape '\\{}'", c)),
+                        None => return self.error("unterminated string"),
+                    };
+                    s.push(escaped);
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    // The next character of a string, which ends with its line. Looked at
+    // before moving past it, so an error has the string's line.
+    fn string_char(&mut self) -> Result<char, ParseError> {
+        match self.peek() {
+            None | Some('\n') => self.error("unterminated string"),
+            Some(c) => {
+                self.bump();
+                Ok(c)
+            }
+        }
+    }
+
+    fn parse_unicode_escape(&mut self) -> Result<char, ParseError> {
+        let mut code = 0;
+        for _ in 0..4 {
+            let digit = self.bump().and_then(|c| c.to_digit(16));
+            match digit {
+                Some(d) => code = code * 16 + d,
+                None => return self.error("invalid unicode escape"),
+            }
+        }
+        match char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => self.error("invalid unicode escape"),
+        }
+    }
+
+    fn parse_literal_string(&mut self) -> Result<String, ParseError> {
+        self.expect('\'')?;
+        let mut s = String::new();
+        loop {
+            match self.string_char()? {
+                '\'' => return Ok(s),
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn parse_bool(&mut self) -> Result<Value, ParseError> {
+        let word = self.take_while(|c| c.is_ascii_alphabetic());
+        match word.as_str() {
+            "true" => Ok(Value::Boolean(true)),
+            "false" => Ok(Value::Boolean(false)),
+            _ => self.error(format!("invalid value '{}'", word)),
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Value, ParseError> {
+        let text = self.take_while(|c| c.is_ascii_alphanumeric() || matches!(c, '+' | '-' | '.' | '_'));
+        let cleaned = text.replace('_', "");
+        if let Ok(i) = cleaned.parse::<i64>() {
+            return Ok(Value::Integer(i));
+        }
+        match cleaned.as_str() {
+            "inf" | "+inf" => return Ok(Value::Float(f64::INFINITY)),
+            "-inf" => return Ok(Value::Float(f64::NEG_INFINITY)),
+            "nan" | "+nan" | "-nan" => return Ok(Value::Float(f64::NAN)),
+            _ => {}
+        }
+        match cleaned.parse::<f64>() {
+            Ok(f) if !cleaned.is_empty() => Ok(Value::Float(f)),
+            _ => self.error(format!("invalid value '{}'", text)),
+        }
+    }
+
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> String {
+        let mut s = String::new();
+        while let Some(c) = self.peek() {
+            if !pred(c) {
+                break;
+            }
+            s.push(c);
+            self.bump();
+        }
+        s
+    }
+
+    fn parse_array(&mut self) -> Result<Value, ParseError> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_blank();
+            if self.peek() == Some(']') {
+                self.bump();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.parse_value()?);
+            self.skip_blank();
+            match self.bump() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(items)),
+                _ => return self.error("expected ',' or ']' in array"),
+            }
+        }
+    }
+
+    fn parse_inline_table(&mut self) -> Result<Value, ParseError> {
+        self.expect('{')?;
+        let mut table = Table::new();
+        self.skip_inline_whitespace();
+        if self.peek() == Some('}') {
+            self.bump();
+            return Ok(Value::Table(table));
+        }
+        loop {
+            self.skip_inline_whitespace();
+            let path = self.parse_key_path()?;
+            self.skip_inline_whitespace();
+            self.expect('=')?;
+            self.skip_inline_whitespace();
+            let value = self.parse_value()?;
+            self.insert(&mut table, &path, value)?;
+            self.skip_inline_whitespace();
+            match self.peek() {
+                Some(',') => {}
+                Some('}') => {
+                    self.bump();
+                    return Ok(Value::Table(table));
+                }
+                _ => return self.error("expected ',' or '}' in inline table"),
+            }
+            self.bump();
+        }
+    }
+}
+
+/// Serializes a table. Scalars and arrays of scalars are written first,
+/// followed by sub-tables and arrays of tables as `[section]`s.
+pub fn to_string(table: &Table) -> String {
+    let mut out = String::new();
+    write_table(&mut out, table, &[]);
+    out
+}
+
+fn is_table_array(value: &Value) -> bool {
+    match value {
+        Value::Array(items) => !items.is_empty() && items.iter().all(|v| matches!(v, Value::Table(_))),
+        _ => false,
+    }
+}
+
+fn write_table(out: &mut String, table: &Table, path: &[String]) {
+    for (key, value) in table {
+        if matches!(value, Value::Table(_)) || is_table_array(value) {
+            continue;
+        }
+        out.push_str(&format!("{} = {}\n", format_key(key), format_value(value)));
+    }
+
+    for (key, value) in table {
+        let mut child_path = path.to_vec();
+        child_path.push(key.clone());
+        let header: Vec<String> = child_path.iter().map(|k| format_key(k)).collect();
+        match value {
+            Value::Table(child) => {
+                out.push_str(&format!("\n[{}]\n", header.join(".")));
+                write_table(out, child, &child_path);
+            }
+            Value::Array(items) if is_table_array(value) => {
+                for item in items {
+                    if let Value::Table(child) = item {
+                        out.push_str(&format!("\n[[{}]]\n", header.join(".")));
+                        write_table(out, child, &child_path);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn format_key(key: &str) -> String {
+    let bare = !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+    if bare {
+        key.to_string()
+    } else {
+        format_string(key)
+    }
+}
+
+fn format_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            '\r' => out.push_str("\\r"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04X}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::String(s) => format_string(s),
+        Value::Integer(i) => i.to_string(),
+        Value::Float(f) if f.is_nan() => "nan".to_string(),
+        Value::Float(f) if f.is_infinite() => if *f > 0.0 { "inf" } else { "-inf" }.to_string(),
+        // Keep a decimal point so the value reads back as a float.
+        Value::Float(f) if f.fract() == 0.0 => format!("{:.1}", f),
+        Value::Float(f) => f.to_string(),
+        Value::Boolean(b) => b.to_string(),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(format_value).collect();
+            format!("[{}]", items.join(", "))
+        }
+        Value::Table(t) => {
+            let items: Vec<String> = t
+                .iter()
+                .map(|(k, v)| format!("{} = {}", format_key(k), format_value(v)))
+                .collect();
+            format!("{{ {} }}", items.join(", "))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn string(s: &str) -> Value {
+        Value::String(s.to_string())
+    }
+
+    #[test]
+    fn parses_scalars() {
+        let table = parse(
+            "# comment\n\
+             name = \"basic \\\"quoted\\\" \\u00e9\\n\" # trailing\n\
+             path = 'C:\\literal'\n\
+             \"quoted key\" = 1\n\
+             negative = -42\n\
+             ratio = 0.5\n\
+             big = 1e3\n\
+             on = true\n\
+             off = false\n",
+        )
+        .unwrap();
+        assert_eq!(table["name"], string("basic \"quoted\" \u{e9}\n"));
+        assert_eq!(table["path"], string("C:\\literal"));
+        assert_eq!(table["quoted key"], Value::Integer(1));
+        assert_eq!(table["negative"], Value::Integer(-42));
+        assert_eq!(table["ratio"], Value::Float(0.5));
+        assert_eq!(table["big"], Value::Float(1000.0));
+        assert_eq!(table["on"], Value::Boolean(true));
+        assert_eq!(table["off"], Value::Boolean(false));
+    }
+
+    #[test]
+    fn parses_tables_and_arrays() {
+        let table = parse(
+            "values = [1, 2,\n  3]\n\
+             point = { x = 1, y = \"two\" }\n\
+             [output.device]\n\
+             name = \"USB\"\n\
+             [[track]]\n\
+             file = \"a.wav\"\n\
+             [[track]]\n\
+             file = \"b.wav\"\n",
+        )
+        .unwrap();
+        assert_eq!(table["values"], Value::Array(vec![Value::Integer(1), Value::Integer(2), Value::Integer(3)]));
+        let point = table["point"].as_table().unwrap();
+        assert_eq!(point["x"], Value::Integer(1));
+        assert_eq!(point["y"], string("two"));
+        let device = table["output"].as_table().unwrap()["device"].as_table().unwrap();
+        assert_eq!(device["name"], string("USB"));
+        let tracks = table["track"].as_array().unwrap();
+        assert_eq!(tracks.len(), 2);
+        assert_eq!(tracks[1].as_table().unwrap()["file"], string("b.wav"));
+    }
+
+    #[test]
+    fn malformed_input_reports_the_line() {
+        for (text, line) in [
+            ("a = 1\nb = \"unterminated\n", 2),
+            ("a = 1\n\nb\n", 3),
+            ("a = [1, 2\n", 2),
+            ("[table\n", 1),
+            ("a = 1\na = 2\n", 2),
+            ("a = { b = 1\n", 1),
+            ("a = nope\n", 1),
+        ] {
+            let error = parse(text).unwrap_err();
+            assert_eq!(error.line, line, "{:?}: {}", text, error);
+        }
+    }
+
+    #[test]
+    fn round_trips_through_to_string() {
+        let mut inner = Table::new();
+        inner.insert("gain".to_string(), Value::Float(-3.0));
+        inner.insert("odd key".to_string(), string("tab\there"));
+        let mut track = Table::new();
+        track.insert("file".to_string(), string("a \"b\".wav"));
+        let mut table = Table::new();
+        table.insert("version".to_string(), Value::Integer(1));
+        table.insert("ratio".to_string(), Value::Float(0.25));
+        table.insert("flags".to_string(), Value::Array(vec![Value::Boolean(true), Value::Boolean(false)]));
+        table.insert("mix".to_string(), Value::Table(inner));
+        table.insert("track".to_string(), Value::Array(vec![Value::Table(track.clone()), Value::Table(track)]));
+
+        let text = to_string(&table);
+        assert_eq!(parse(&text).unwrap(), table, "{}", text);
+    }
+}
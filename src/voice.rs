@@ -0,0 +1,320 @@
+// Live voice chain for a microphone, built for `monitor_mix`:
+//
+//   input meter → high-pass → noise gate → EQ → compressor → limiter → record tap
+//
+// Every stage sits in a `Mix` that is fully wet while the stage is on and
+// fully dry while it is bypassed, so toggling one from another thread ramps
+// over `GAIN_RAMP_MS` instead of clicking, and the limiter's lookahead is
+// matched on the dry path. Bypassed stages keep running, so switching one
+// back on doesn't start from a stale state and the cost per block stays the
+// same either way.
+//
+// The meters and the recording leave the callback without locks: the input
+// level through a `MeterBus`, the gain reductions as atomics holding the
+// most since they were last read, and the processed signal through a ring
+// buffer that `VoiceRecording` drains into a WAV file from another thread.
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+
+use crate::effect::{Effect, EffectChain, Mix};
+use crate::eq::{EqBand, EqPreset, Equalizer};
+use crate::filters::BiquadFilter;
+use crate::fx::{Compressor, NoiseGate};
+use crate::limiter::{Limiter, LimiterSettings};
+use crate::meter_bus::MeterPublisher;
+use crate::stream::{ring_buffer, Consumer, Producer};
+use crate::write_wav::{WavSampleFormat, WavStreamWriter};
+
+/// Longest block the input meter keeps of each publish.
+pub const INPUT_METER_SAMPLES: usize = 4096;
+/// How much processed audio the recording ring holds for the writer.
+const RECORD_RING_MS: u32 = 2000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    HighPass,
+    Gate,
+    Eq,
+    Compressor,
+    Limiter,
+}
+
+/// The stages in processing order.
+pub const STAGES: [Stage; 5] = [Stage::HighPass, Stage::Gate, Stage::Eq, Stage::Compressor, Stage::Limiter];
+
+impl Stage {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Stage::HighPass => "high-pass",
+            Stage::Gate => "gate",
+            Stage::Eq => "EQ",
+            Stage::Compressor => "compressor",
+            Stage::Limiter => "limiter",
+        }
+    }
+
+    fn index(&self) -> usize {
+        STAGES.iter().position(|stage| stage == self).unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VoiceSettings {
+    pub highpass_hz: f32,
+    /// Linear level below which the gate closes.
+    pub gate_threshold: f32,
+    pub eq: EqPreset,
+    /// Linear threshold.
+    pub compressor_threshold: f32,
+    pub compressor_ratio: f32,
+    pub compressor_attack_ms: f32,
+    pub compressor_release_ms: f32,
+    pub limiter: LimiterSettings,
+}
+
+impl Default for VoiceSettings {
+    fn default() -> Self {
+        Self {
+            highpass_hz: 80.0,
+            gate_threshold: 0.01,
+            // Less boxiness, more presence and a little air.
+            eq: EqPreset::new(vec![
+                EqBand { frequency: 300.0, gain_db: -3.0, q_factor: 1.0 },
+                EqBand { frequency: 3000.0, gain_db: 3.0, q_factor: 1.0 },
+                EqBand { frequency: 10000.0, gain_db: 2.0, q_factor: 0.7 },
+            ]),
+            compressor_threshold: 0.25,
+            compressor_ratio: 4.0,
+            compressor_attack_ms: 5.0,
+            compressor_release_ms: 80.0,
+            limiter: LimiterSettings::default(),
+        }
+    }
+}
+
+/// Shared between the chain in the callback and whoever shows its state.
+#[derive(Debug, Default)]
+pub struct VoiceControls {
+    bypassed: [AtomicBool; STAGES.len()],
+    // f32 bits of the most gain reduction since the last read, in dB. Bits
+    // of non-negative floats order like the floats, so `fetch_max` works.
+    compressor_reduction: AtomicU32,
+    limiter_reduction: AtomicU32,
+}
+
+impl VoiceControls {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_bypassed(&self, stage: Stage) -> bool {
+        self.bypassed[stage.index()].load(Ordering::Relaxed)
+    }
+
+    pub fn set_bypassed(&self, stage: Stage, bypassed: bool) {
+        self.bypassed[stage.index()].store(bypassed, Ordering::Relaxed);
+    }
+
+    /// Flips the stage between on and bypassed and returns whether it is
+    /// now bypassed.
+    pub fn toggle(&self, stage: Stage) -> bool {
+        !self.bypassed[stage.index()].fetch_xor(true, Ordering::Relaxed)
+    }
+
+    /// Most compressor gain reduction in dB since the last call.
+    pub fn take_compressor_reduction_db(&self) -> f32 {
+        f32::from_bits(self.compressor_reduction.swap(0, Ordering::Relaxed))
+    }
+
+    /// Most limiter gain reduction in dB since the last call.
+    pub fn take_limiter_reduction_db(&self) -> f32 {
+        f32::from_bits(self.limiter_reduction.swap(0, Ordering::Relaxed))
+    }
+}
+
+fn report_reduction(slot: &AtomicU32, reduction_db: f32) {
+    slot.fetch_max(reduction_db.max(0.0).to_bits(), Ordering::Relaxed);
+}
+
+// A stage in its bypass mix, following the stage's switch at every block.
+struct Switched {
+    mix: Mix,
+    stage: Stage,
+    controls: Arc<VoiceControls>,
+}
+
+impl Effect for Switched {
+    fn process_block(&mut self, block: &mut [f32]) {
+        self.mix.set_wet(if self.controls.is_bypassed(self.stage) { 0.0 } else { 1.0 });
+        self.mix.process_block(block);
+    }
+
+    fn latency_frames(&self) -> usize {
+        self.mix.latency_frames()
+    }
+}
+
+struct MeteredCompressor {
+    compressor: Compressor,
+    controls: Arc<VoiceControls>,
+}
+
+impl Effect for MeteredCompressor {
+    fn process_block(&mut self, block: &mut [f32]) {
+        let mut most = 0.0f32;
+        for sample in block.iter_mut() {
+            self.compressor.process_block(std::slice::from_mut(sample));
+            most = most.max(self.compressor.gain_reduction_db());
+        }
+        report_reduction(&self.controls.compressor_reduction, most);
+    }
+}
+
+struct MeteredLimiter {
+    limiter: Limiter,
+    controls: Arc<VoiceControls>,
+}
+
+impl Effect for MeteredLimiter {
+    fn process_block(&mut self, block: &mut [f32]) {
+        let mut lowest = 1.0f32;
+        for sample in block.iter_mut() {
+            self.limiter.process_block(std::slice::from_mut(sample));
+            lowest = lowest.min(self.limiter.current_gain());
+        }
+        report_reduction(&self.controls.limiter_reduction, -20.0 * lowest.max(1e-6).log10());
+    }
+
+    fn latency_frames(&self) -> usize {
+        self.limiter.latency_frames()
+    }
+}
+
+// Publishes the unprocessed input for the input meter.
+struct InputMeter {
+    publisher: MeterPublisher,
+}
+
+impl Effect for InputMeter {
+    fn process_block(&mut self, block: &mut [f32]) {
+        self.publisher.publish(block, 1);
+    }
+
+    fn is_stateless(&self) -> bool {
+        true
+    }
+}
+
+// Hands the processed signal to the recording thread.
+struct RecordTap {
+    ring: Producer<f32>,
+}
+
+impl Effect for RecordTap {
+    fn process_block(&mut self, block: &mut [f32]) {
+        self.ring.push_slice(block);
+    }
+}
+
+/// The chain for `MonitorOptions::live_chain`, at the output device's
+/// `sample_rate`. `record` receives what comes out of the last stage.
+pub fn voice_chain(
+    sample_rate: u32,
+    settings: &VoiceSettings,
+    controls: Arc<VoiceControls>,
+    input_meter: MeterPublisher,
+    record: Option<Producer<f32>>,
+) -> EffectChain {
+    let rate = sample_rate as f32;
+    let stage_effect = |stage: Stage| -> Box<dyn Effect> {
+        match stage {
+            Stage::HighPass => Box::new(BiquadFilter::new_highpass(rate, settings.highpass_hz, 0.707)),
+            Stage::Gate => Box::new(NoiseGate::new(settings.gate_threshold, rate, 10.0, 100.0)),
+            Stage::Eq => Box::new(Equalizer::new(rate, settings.eq.clone())),
+            Stage::Compressor => Box::new(MeteredCompressor {
+                compressor: Compressor::new(
+                    settings.compressor_threshold,
+                    settings.compressor_ratio,
+                    settings.compressor_attack_ms,
+                    settings.compressor_release_ms,
+                    rate,
+                ),
+                controls: Arc::clone(&controls),
+            }),
+            Stage::Limiter => Box::new(MeteredLimiter {
+                limiter: Limiter::new(sample_rate, 1, settings.limiter),
+                controls: Arc::clone(&controls),
+            }),
+        }
+    };
+
+    let mut chain = EffectChain::new(vec![Box::new(InputMeter { publisher: input_meter })]);
+    for stage in STAGES {
+        let wet = if controls.is_bypassed(stage) { 0.0 } else { 1.0 };
+        chain.push(Box::new(Switched {
+            mix: Mix::new(stage_effect(stage), wet).with_sample_rate(sample_rate),
+            stage,
+            controls: Arc::clone(&controls),
+        }));
+    }
+    if let Some(ring) = record {
+        chain.push(Box::new(RecordTap { ring }));
+    }
+    chain
+}
+
+/// A mono float WAV file fed from the chain's record tap.
+pub struct VoiceRecording {
+    ring: Consumer<f32>,
+    tap: Option<Producer<f32>>,
+    writer: WavStreamWriter,
+    sample_rate: u32,
+    chunk: Vec<f32>,
+}
+
+impl VoiceRecording {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<Self> {
+        let (tap, ring) = ring_buffer((sample_rate * RECORD_RING_MS / 1000) as usize);
+        Ok(Self {
+            ring,
+            tap: Some(tap),
+            writer: WavStreamWriter::create(path, sample_rate, 1, WavSampleFormat::Float32)?,
+            sample_rate,
+            chunk: vec![0.0; 4096],
+        })
+    }
+
+    /// The end of the ring to pass to `voice_chain`; there is only one,
+    /// so `None` once taken.
+    pub fn take_tap(&mut self) -> Option<Producer<f32>> {
+        self.tap.take()
+    }
+
+    /// Writes everything the chain has delivered so far. Has to be called
+    /// well within `RECORD_RING_MS`.
+    pub fn drain(&mut self) -> io::Result<()> {
+        loop {
+            let len = self.ring.available().min(self.chunk.len());
+            if len == 0 {
+                return Ok(());
+            }
+            let len = self.ring.pop_slice(&mut self.chunk[..len]);
+            self.writer.write_samples(&self.chunk[..len])?;
+        }
+    }
+
+    /// Times the writer fell behind and audio was lost from the file.
+    pub fn overflows(&self) -> u64 {
+        self.ring.overflows()
+    }
+
+    /// Finalizes the file and returns its length in seconds.
+    pub fn finish(mut self) -> io::Result<f64> {
+        self.drain()?;
+        let seconds = self.writer.frames_written() as f64 / self.sample_rate as f64;
+        self.writer.finish()?;
+        Ok(seconds)
+    }
+}
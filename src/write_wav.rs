@@ -0,0 +1,529 @@
+// Incremental WAV writer with Broadcast WAV (bext) and cue point support.
+//
+// Layout of the written file:
+//
+//   RIFF WAVE
+//     JUNK/bext  space reserved up front, turned into `bext` on finish
+//     fmt
+//     fact       float files only
+//     data
+//     cue        if any cue points were added
+//     LIST adtl  labels for the cue points
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WavSampleFormat {
+    Int16,
+    Int24,
+    Float32,
+}
+
+impl WavSampleFormat {
+    pub fn bits_per_sample(&self) -> u16 {
+        match self {
+            WavSampleFormat::Int16 => 16,
+            WavSampleFormat::Int24 => 24,
+            WavSampleFormat::Float32 => 32,
+        }
+    }
+
+    fn bytes_per_sample(&self) -> usize {
+        self.bits_per_sample() as usize / 8
+    }
+}
+
+#[derive(Debug)]
+pub enum WavError {
+    Hound(hound::Error),
+    NoChannels,
+    /// The samples don't make up whole frames.
+    PartialFrame { samples: usize, channels: u16 },
+}
+
+impl fmt::Display for WavError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WavError::Hound(e) => write!(f, "Cannot write WAV file: {}", e),
+            WavError::NoChannels => write!(f, "A WAV file needs at least one channel"),
+            WavError::PartialFrame { samples, channels } => {
+                write!(f, "{} samples are not whole frames of {} channels", samples, channels)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WavError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            WavError::Hound(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<hound::Error> for WavError {
+    fn from(e: hound::Error) -> Self {
+        WavError::Hound(e)
+    }
+}
+
+/// Writes interleaved `samples` to a plain WAV file, the counterpart of
+/// `read_wave_file`. Integer formats clamp to [-1.0, 1.0] as
+/// `WavStreamWriter` does; that one also writes metadata and takes the
+/// audio as it arrives.
+pub fn write_wave_file(
+    filepath: &str,
+    samples: &[f32],
+    sample_rate: u32,
+    channels: u16,
+    format: WavSampleFormat,
+) -> Result<(), WavError> {
+    if channels == 0 {
+        return Err(WavError::NoChannels);
+    }
+    if !samples.len().is_multiple_of(channels as usize) {
+        return Err(WavError::PartialFrame { samples: samples.len(), channels });
+    }
+    let spec = hound::WavSpec {
+        channels,
+        sample_rate,
+        bits_per_sample: format.bits_per_sample(),
+        sample_format: match format {
+            WavSampleFormat::Float32 => hound::SampleFormat::Float,
+            WavSampleFormat::Int16 | WavSampleFormat::Int24 => hound::SampleFormat::Int,
+        },
+    };
+    let mut writer = hound::WavWriter::create(filepath, spec)?;
+    for &sample in samples {
+        match format {
+            WavSampleFormat::Int16 => writer.write_sample((sample.clamp(-1.0, 1.0) * 32767.0).round() as i16)?,
+            WavSampleFormat::Int24 => writer.write_sample((sample.clamp(-1.0, 1.0) * 8_388_607.0).round() as i32)?,
+            WavSampleFormat::Float32 => writer.write_sample(sample)?,
+        }
+    }
+    writer.finalize()?;
+    Ok(())
+}
+
+/// Broadcast WAV extension chunk (EBU Tech 3285, version 1).
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BextChunk {
+    /// Free text, up to 256 bytes.
+    pub description: String,
+    /// Up to 32 bytes.
+    pub originator: String,
+    /// Up to 32 bytes.
+    pub originator_reference: String,
+    /// `yyyy-mm-dd`
+    pub origination_date: String,
+    /// `hh:mm:ss`
+    pub origination_time: String,
+    /// Position of the first sample, in samples since midnight. This is what
+    /// DAWs use to line recordings up.
+    pub time_reference: u64,
+    /// Up to `BEXT_CODING_HISTORY_LEN` bytes.
+    pub coding_history: String,
+}
+
+/// Size of the fixed part of the bext chunk.
+const BEXT_FIXED_LEN: usize = 602;
+/// Room reserved for the coding history, so the chunk can be written in
+/// place after the audio without moving the data chunk.
+pub const BEXT_CODING_HISTORY_LEN: usize = 256;
+const BEXT_RESERVED_LEN: usize = BEXT_FIXED_LEN + BEXT_CODING_HISTORY_LEN;
+
+// Offsets of the fields patched by `finish()`.
+const RIFF_SIZE_OFFSET: u64 = 4;
+const RESERVED_CHUNK_OFFSET: u64 = 12;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CuePoint {
+    pub label: String,
+    /// Position in sample frames from the start of the data.
+    pub sample_position: u32,
+}
+
+pub struct WavStreamWriter {
+    writer: BufWriter<File>,
+    channels: u16,
+    format: WavSampleFormat,
+    fact_offset: Option<u64>,
+    data_size_offset: u64,
+    data_bytes: u64,
+    bext: Option<BextChunk>,
+    cues: Vec<CuePoint>,
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+impl WavStreamWriter {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        sample_rate: u32,
+        channels: u16,
+        format: WavSampleFormat,
+    ) -> io::Result<Self> {
+        if channels == 0 {
+            return Err(invalid_input("a WAV file needs at least one channel".to_string()));
+        }
+        let mut writer = BufWriter::new(File::create(path)?);
+
+        writer.write_all(b"RIFF")?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(b"WAVE")?;
+
+        writer.write_all(b"JUNK")?;
+        writer.write_all(&(BEXT_RESERVED_LEN as u32).to_le_bytes())?;
+        writer.write_all(&[0u8; BEXT_RESERVED_LEN])?;
+
+        let block_align = channels * format.bits_per_sample() / 8;
+        let is_float = format == WavSampleFormat::Float32;
+        writer.write_all(b"fmt ")?;
+        writer.write_all(&(if is_float { 18u32 } else { 16u32 }).to_le_bytes())?;
+        writer.write_all(&(if is_float { 3u16 } else { 1u16 }).to_le_bytes())?;
+        writer.write_all(&channels.to_le_bytes())?;
+        writer.write_all(&sample_rate.to_le_bytes())?;
+        writer.write_all(&(sample_rate * block_align as u32).to_le_bytes())?;
+        writer.write_all(&block_align.to_le_bytes())?;
+        writer.write_all(&format.bits_per_sample().to_le_bytes())?;
+        if is_float {
+            // cbSize
+            writer.write_all(&0u16.to_le_bytes())?;
+        }
+
+        let fact_offset = if is_float {
+            writer.write_all(b"fact")?;
+            writer.write_all(&4u32.to_le_bytes())?;
+            let offset = writer.stream_position()?;
+            writer.write_all(&0u32.to_le_bytes())?;
+            Some(offset)
+        } else {
+            None
+        };
+
+        writer.write_all(b"data")?;
+        let data_size_offset = writer.stream_position()?;
+        writer.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self {
+            writer,
+            channels,
+            format,
+            fact_offset,
+            data_size_offset,
+            data_bytes: 0,
+            bext: None,
+            cues: Vec::new(),
+        })
+    }
+
+    /// Appends interleaved samples. Integer formats clamp to [-1.0, 1.0].
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let new_size = self.data_bytes + (samples.len() * self.format.bytes_per_sample()) as u64;
+        if new_size > u32::MAX as u64 - BEXT_RESERVED_LEN as u64 - 1024 {
+            return Err(invalid_input("WAV file would exceed 4 GiB".to_string()));
+        }
+
+        for &sample in samples {
+            match self.format {
+                WavSampleFormat::Int16 => {
+                    let value = (sample.clamp(-1.0, 1.0) * 32767.0).round() as i16;
+                    self.writer.write_all(&value.to_le_bytes())?;
+                }
+                WavSampleFormat::Int24 => {
+                    let value = (sample.clamp(-1.0, 1.0) * 8_388_607.0).round() as i32;
+                    self.writer.write_all(&value.to_le_bytes()[..3])?;
+                }
+                WavSampleFormat::Float32 => {
+                    self.writer.write_all(&sample.to_le_bytes())?;
+                }
+            }
+        }
+        self.data_bytes = new_size;
+        Ok(())
+    }
+
+    /// Number of complete frames written so far.
+    pub fn frames_written(&self) -> u64 {
+        self.data_bytes / (self.channels as u64 * self.format.bytes_per_sample() as u64)
+    }
+
+    /// Sets the Broadcast WAV metadata, written when `finish()` runs.
+    pub fn set_bext(&mut self, bext: BextChunk) -> io::Result<()> {
+        let limits = [
+            ("description", &bext.description, 256),
+            ("originator", &bext.originator, 32),
+            ("originator_reference", &bext.originator_reference, 32),
+            ("origination_date", &bext.origination_date, 10),
+            ("origination_time", &bext.origination_time, 8),
+            ("coding_history", &bext.coding_history, BEXT_CODING_HISTORY_LEN),
+        ];
+        for (name, value, max) in limits {
+            if value.len() > max {
+                return Err(invalid_input(format!("bext {} is longer than {} bytes", name, max)));
+            }
+        }
+        self.bext = Some(bext);
+        Ok(())
+    }
+
+    /// Adds a labelled cue point at a frame position, written when `finish()` runs.
+    pub fn add_cue(&mut self, label: &str, sample_position: u32) {
+        self.cues.push(CuePoint { label: label.to_string(), sample_position });
+    }
+
+    /// Writes the metadata chunks and patches the header sizes.
+    pub fn finish(mut self) -> io::Result<()> {
+        // Chunks are word aligned: an odd-sized data chunk gets a pad byte
+        // that isn't counted in its size.
+        if self.data_bytes % 2 == 1 {
+            self.writer.write_all(&[0])?;
+        }
+
+        if !self.cues.is_empty() {
+            self.write_cue_chunks()?;
+        }
+
+        let file_len = self.writer.stream_position()?;
+
+        if let Some(bext) = &self.bext {
+            let body = encode_bext(bext);
+            self.writer.seek(SeekFrom::Start(RESERVED_CHUNK_OFFSET))?;
+            self.writer.write_all(b"bext")?;
+            self.writer.write_all(&(body.len() as u32).to_le_bytes())?;
+            self.writer.write_all(&body)?;
+        }
+
+        if let Some(offset) = self.fact_offset {
+            self.writer.seek(SeekFrom::Start(offset))?;
+            self.writer.write_all(&(self.frames_written() as u32).to_le_bytes())?;
+        }
+
+        self.writer.seek(SeekFrom::Start(self.data_size_offset))?;
+        self.writer.write_all(&(self.data_bytes as u32).to_le_bytes())?;
+
+        self.writer.seek(SeekFrom::Start(RIFF_SIZE_OFFSET))?;
+        self.writer.write_all(&((file_len - 8) as u32).to_le_bytes())?;
+
+        self.writer.flush()
+    }
+
+    fn write_cue_chunks(&mut self) -> io::Result<()> {
+        let cue_len = 4 + 24 * self.cues.len();
+        self.writer.write_all(b"cue ")?;
+        self.writer.write_all(&(cue_len as u32).to_le_bytes())?;
+        self.writer.write_all(&(self.cues.len() as u32).to_le_bytes())?;
+        for (i, cue) in self.cues.iter().enumerate() {
+            let id = i as u32 + 1;
+            self.writer.write_all(&id.to_le_bytes())?;
+            self.writer.write_all(&cue.sample_position.to_le_bytes())?;
+            self.writer.write_all(b"data")?;
+            // Chunk start and block start are zero for a single data chunk.
+            self.writer.write_all(&0u32.to_le_bytes())?;
+            self.writer.write_all(&0u32.to_le_bytes())?;
+            self.writer.write_all(&cue.sample_position.to_le_bytes())?;
+        }
+
+        let mut adtl = Vec::new();
+        adtl.extend_from_slice(b"adtl");
+        for (i, cue) in self.cues.iter().enumerate() {
+            let mut text = cue.label.as_bytes().to_vec();
+            text.push(0);
+            adtl.extend_from_slice(b"labl");
+            adtl.extend_from_slice(&(4 + text.len() as u32).to_le_bytes());
+            adtl.extend_from_slice(&(i as u32 + 1).to_le_bytes());
+            adtl.extend_from_slice(&text);
+            if text.len() % 2 == 1 {
+                adtl.push(0);
+            }
+        }
+        self.writer.write_all(b"LIST")?;
+        self.writer.write_all(&(adtl.len() as u32).to_le_bytes())?;
+        self.writer.write_all(&adtl)
+    }
+}
+
+fn put_fixed_string(out: &mut Vec<u8>, value: &str, len: usize) {
+    let start = out.len();
+    out.extend_from_slice(value.as_bytes());
+    out.resize(start + len, 0);
+}
+
+// Encodes the bext body at its reserved size; the coding history is
+// null-padded to fill the space.
+fn encode_bext(bext: &BextChunk) -> Vec<u8> {
+    let mut body = Vec::with_capacity(BEXT_RESERVED_LEN);
+    put_fixed_string(&mut body, &bext.description, 256);
+    put_fixed_string(&mut body, &bext.originator, 32);
+    put_fixed_string(&mut body, &bext.originator_reference, 32);
+    put_fixed_string(&mut body, &bext.origination_date, 10);
+    put_fixed_string(&mut body, &bext.origination_time, 8);
+    body.extend_from_slice(&(bext.time_reference as u32).to_le_bytes());
+    body.extend_from_slice(&((bext.time_reference >> 32) as u32).to_le_bytes());
+    // Version 1, then the 64-byte UMID (unused) and reserved space.
+    body.extend_from_slice(&1u16.to_le_bytes());
+    body.resize(BEXT_FIXED_LEN, 0);
+    put_fixed_string(&mut body, &bext.coding_history, BEXT_CODING_HISTORY_LEN);
+    body
+}
+
+/// Parses a bext chunk body.
+pub fn decode_bext(body: &[u8]) -> Option<BextChunk> {
+    if body.len() < BEXT_FIXED_LEN {
+        return None;
+    }
+    let text = |range: std::ops::Range<usize>| {
+        let bytes = &body[range];
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).into_owned()
+    };
+    let low = u32::from_le_bytes(body[338..342].try_into().ok()?) as u64;
+    let high = u32::from_le_bytes(body[342..346].try_into().ok()?) as u64;
+    Some(BextChunk {
+        description: text(0..256),
+        originator: text(256..288),
+        originator_reference: text(288..320),
+        origination_date: text(320..330),
+        origination_time: text(330..338),
+        time_reference: (high << 32) | low,
+        coding_history: text(BEXT_FIXED_LEN..body.len()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::read_wav::{read_wav_metadata, read_wave_file, WavMetadata};
+    use std::path::PathBuf;
+
+    // A path in the temp directory, removed when dropped.
+    struct TempPath(PathBuf);
+
+    impl TempPath {
+        fn new(name: &str) -> Self {
+            Self(std::env::temp_dir().join(format!("cpal_playbook_{}_{}", std::process::id(), name)))
+        }
+
+        fn path(&self) -> &str {
+            self.0.to_str().unwrap()
+        }
+    }
+
+    impl Drop for TempPath {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    fn ramp() -> Vec<f32> {
+        (0..200).map(|i| (i as f32 / 100.0 - 1.0) * 0.999).collect()
+    }
+
+    fn round_trip(name: &str, samples: &[f32], format: WavSampleFormat) -> Vec<f32> {
+        let file = TempPath::new(name);
+        write_wave_file(file.path(), samples, 44_100, 2, format).unwrap();
+        let (read, spec) = read_wave_file(file.path()).unwrap();
+        assert_eq!((spec.sample_rate, spec.channels), (44_100, 2));
+        assert_eq!(read.len(), samples.len());
+        read
+    }
+
+    #[test]
+    fn samples_survive_within_quantization_error() {
+        // Writing scales by 2^(bits - 1) - 1 and reading divides by
+        // 2^(bits - 1), so besides half a step of rounding a full-scale
+        // sample can be off by up to one more step.
+        for (name, format, tolerance) in [
+            ("trip16.wav", WavSampleFormat::Int16, 1.5 / 32_768.0),
+            ("trip24.wav", WavSampleFormat::Int24, 1.5 / 8_388_608.0),
+            ("trip32.wav", WavSampleFormat::Float32, 0.0),
+        ] {
+            let samples = ramp();
+            for (written, read) in samples.iter().zip(round_trip(name, &samples, format)) {
+                assert!((written - read).abs() <= tolerance, "{:?}: {} became {}", format, written, read);
+            }
+        }
+    }
+
+    #[test]
+    fn integer_formats_clamp_rather_than_wrap() {
+        let samples = [1.5, -1.5, 2.0, -4.0];
+        for (name, format) in [("clamp16.wav", WavSampleFormat::Int16), ("clamp24.wav", WavSampleFormat::Int24)] {
+            let read = round_trip(name, &samples, format);
+            for (written, read) in samples.iter().zip(read) {
+                // Full scale is one step short of 1.0 on the positive side.
+                assert!((read - written.signum()).abs() < 1e-4, "{:?}: {} became {}", format, written, read);
+            }
+        }
+    }
+
+    #[test]
+    fn float_files_keep_out_of_range_samples() {
+        let samples = [1.5, -1.5, 2.0, -4.0];
+        assert_eq!(round_trip("loud32.wav", &samples, WavSampleFormat::Float32), samples);
+    }
+
+    #[test]
+    fn bad_shapes_are_rejected() {
+        let file = TempPath::new("bad.wav");
+        assert!(matches!(
+            write_wave_file(file.path(), &[0.0; 4], 44_100, 0, WavSampleFormat::Int16),
+            Err(WavError::NoChannels)
+        ));
+        assert!(matches!(
+            write_wave_file(file.path(), &[0.0; 5], 44_100, 2, WavSampleFormat::Int16),
+            Err(WavError::PartialFrame { samples: 5, channels: 2 })
+        ));
+    }
+
+    #[test]
+    fn bext_and_cues_survive_the_round_trip() {
+        let bext = BextChunk {
+            description: "Dawn chorus, north meadow".to_string(),
+            originator: "cpal_playbook".to_string(),
+            originator_reference: "X".repeat(32),
+            origination_date: "2024-05-01".to_string(),
+            origination_time: "05:42:17".to_string(),
+            // Past 2^32, so both halves of the field are used.
+            time_reference: 20_595 * 48_000 + (5u64 << 32),
+            coding_history: "A=PCM,F=48000,W=24,M=mono,T=cpal_playbook\r\n".to_string(),
+        };
+        // Odd and even label lengths, an empty one, and positions out of order.
+        let cues = [("start", 0), ("bird", 1), ("plane overhead", 2), ("", 2), ("last", 3)]
+            .map(|(label, sample_position)| CuePoint { label: label.to_string(), sample_position });
+
+        let file = TempPath::new("bext.wav");
+        // Three mono 24-bit frames make an odd-sized data chunk.
+        let samples = [0.5, -0.25, 0.125];
+        let mut writer = WavStreamWriter::create(file.path(), 48_000, 1, WavSampleFormat::Int24).unwrap();
+        writer.write_samples(&samples).unwrap();
+        writer.set_bext(bext.clone()).unwrap();
+        for cue in cues.iter().rev() {
+            writer.add_cue(&cue.label, cue.sample_position);
+        }
+        writer.finish().unwrap();
+
+        let expected: Vec<CuePoint> = cues.iter().rev().cloned().collect();
+        assert_eq!(read_wav_metadata(file.path()).unwrap(), WavMetadata { bext: Some(bext), cues: expected });
+        let bytes = std::fs::read(file.path()).unwrap();
+        assert_eq!(u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize, bytes.len() - 8);
+        let (read, spec) = read_wave_file(file.path()).unwrap();
+        assert_eq!((spec.sample_rate, spec.channels), (48_000, 1));
+        assert_eq!(read, samples);
+    }
+
+    #[test]
+    fn a_file_without_metadata_reads_back_empty() {
+        let file = TempPath::new("plain.wav");
+        let mut writer = WavStreamWriter::create(file.path(), 44_100, 2, WavSampleFormat::Float32).unwrap();
+        writer.write_samples(&ramp()).unwrap();
+        writer.finish().unwrap();
+        assert_eq!(read_wav_metadata(file.path()).unwrap(), WavMetadata::default());
+        assert_eq!(read_wave_file(file.path()).unwrap().0, ramp());
+    }
+}
@@ -0,0 +1,128 @@
+# Writes the FLAC fixtures for src/flac.rs's tests, each with a .raw file
+# of the samples it holds (little-endian i32, interleaved).
+#
+#     python3 make_fixtures.py testdata/flac
+#
+# The stereo files go through every stereo mode and constant, verbatim,
+# fixed (orders 0 to 4) and LPC (orders 1 to 32) subframes, with both
+# residual coding methods and an escaped partition, and end on a short
+# block. The CRCs are left at zero; the decoder doesn't check them.
+import math, random, struct, sys
+class BW:
+    def __init__(s): s.bits=[]
+    def put(s,v,n):
+        for i in range(n-1,-1,-1): s.bits.append((v>>i)&1)
+    def sput(s,v,n): s.put(v & ((1<<n)-1), n)
+    def unary(s,q): s.bits += [0]*q + [1]
+    def align(s):
+        while len(s.bits)%8: s.bits.append(0)
+    def bytes(s):
+        s.align(); out=bytearray()
+        for i in range(0,len(s.bits),8):
+            b=0
+            for x in s.bits[i:i+8]: b=b<<1|x
+            out.append(b)
+        return bytes(out)
+def zig(v): return (v<<1) if v>=0 else ((-v<<1)-1)
+def residual(w, res, order, bs, porder, method, escape_part=None):
+    # Method 0 has 4-bit Rice parameters (escape 15), method 1 5-bit (escape 31).
+    pbits, escape = (4, 15) if method == 0 else (5, 31)
+    w.put(method,2); w.put(porder,4)
+    plen = bs>>porder; idx=0
+    for p in range(1<<porder):
+        cnt = plen - order if p==0 else plen
+        part = res[idx:idx+cnt]; idx+=cnt
+        if escape_part == p:
+            w.put(escape,pbits); rb = max(2, max((abs(x) for x in part), default=0).bit_length()+1); w.put(rb,5)
+            for x in part: w.sput(x, rb)
+        else:
+            m = sum(zig(x) for x in part)//max(1,len(part))
+            param = min(escape-1, max(0, m.bit_length()-1))
+            w.put(param,pbits)
+            for x in part:
+                z=zig(x); w.unary(z>>param); w.put(z & ((1<<param)-1), param)
+def subframe(w, samples, bits, kind, wasted=0, rng=None):
+    w.put(0,1)
+    if wasted:
+        samples=[x>>wasted for x in samples]; bits-=wasted
+    bs=len(samples)
+    if kind=='const':
+        w.put(0,6); w.put(1 if wasted else 0,1)
+        if wasted: w.unary(wasted-1)
+        w.sput(samples[0],bits)
+    elif kind=='verb':
+        w.put(1,6); w.put(1 if wasted else 0,1)
+        if wasted: w.unary(wasted-1)
+        for x in samples: w.sput(x,bits)
+    elif kind.startswith('fixed'):
+        order=int(kind[5:]); w.put(8+order,6); w.put(1 if wasted else 0,1)
+        if wasted: w.unary(wasted-1)
+        co=[[],[1],[2,-1],[3,-3,1],[4,-6,4,-1]][order]
+        for x in samples[:order]: w.sput(x,bits)
+        res=[samples[i]-sum(c*samples[i-1-j] for j,c in enumerate(co)) for i in range(order,bs)]
+        residual(w,res,order,bs,2,0,escape_part=1)
+    elif kind.startswith('lpc'):
+        order=int(kind[3:]); w.put(31+order,6); w.put(1 if wasted else 0,1)
+        if wasted: w.unary(wasted-1)
+        prec=12; shift=9
+        co=[rng.randint(-600,600) for _ in range(order)]
+        for x in samples[:order]: w.sput(x,bits)
+        w.put(prec-1,4); w.sput(shift,5)
+        for c in co: w.sput(c,prec)
+        res=[samples[i]-(sum(c*samples[i-1-j] for j,c in enumerate(co))>>shift) for i in range(order,bs)]
+        residual(w,res,order,bs,1,1)
+def utf8(n):
+    if n<0x80: return [n]
+    if n<0x800: return [0xC0|(n>>6),0x80|(n&63)]
+    return [0xE0|(n>>12),0x80|((n>>6)&63),0x80|(n&63)]
+def encode(channels, rate, bits, bs, plan, path):
+    rng=random.Random(1)
+    n=len(channels[0]); nch=len(channels)
+    out=bytearray(b'fLaC')
+    w=BW(); w.put(1,1); w.put(0,7); w.put(34,24)
+    w.put(bs,16); w.put(bs,16); w.put(0,24); w.put(0,24); w.put(rate,20); w.put(nch-1,3); w.put(bits-1,5); w.put(n,36); w.put(0,64); w.put(0,64)
+    out+=w.bytes()
+    fi=0
+    for start in range(0,n,bs):
+        blk=[c[start:start+bs] for c in channels]; size=len(blk[0])
+        mode, kinds, wasted = plan[fi % len(plan)]
+        if nch != 2: mode = 'ind'
+        w=BW(); w.put(0x3FFE,14); w.put(0,1); w.put(0,1)
+        if size == 512: w.put(9,4); szextra=None
+        else: w.put(7,4); szextra=size-1
+        w.put(0,4)  # rate from streaminfo
+        assign={'ind':nch-1,'ls':8,'sr':9,'ms':10}[mode]; w.put(assign,4)
+        w.put({16:4,24:6,8:1,12:2,20:5}[bits],3); w.put(0,1)
+        for b in utf8(fi): w.put(b,8)
+        if szextra is not None: w.put(szextra,16)
+        w.put(0,8)
+        L=blk[0]; R=blk[1] if nch>1 else None
+        if mode=='ind': subs=[(c,bits) for c in blk]
+        elif mode=='ls': subs=[(L,bits),([a-b for a,b in zip(L,R)],bits+1)]
+        elif mode=='sr': subs=[([a-b for a,b in zip(L,R)],bits+1),(R,bits)]
+        else: subs=[([(a+b)>>1 for a,b in zip(L,R)],bits),([a-b for a,b in zip(L,R)],bits+1)]
+        for (s,b),k in zip(subs,kinds):
+            if k=='const' and len(set(s))!=1: k='verb'
+            subframe(w,s,b,k,wasted=wasted if all(x % (1<<wasted)==0 for x in s) else 0,rng=rng)
+        w.align(); w.put(0,16)
+        out+=w.bytes(); fi+=1
+    open(path,'wb').write(out)
+    with open(path+'.raw','wb') as f:
+        for i in range(n):
+            for c in channels: f.write(struct.pack('<i',c[i]))
+out_dir = sys.argv[1] if len(sys.argv) > 1 else '.'
+rng=random.Random(7)
+bs=512
+n=bs*6+100
+for bits in (16,24):
+    amp=(1<<(bits-1))-1
+    L=[int(amp*0.6*math.sin(i*0.03))+rng.randint(-50,50) for i in range(n)]
+    R=[int(amp*0.5*math.sin(i*0.021+1))+rng.randint(-50,50) for i in range(n)]
+    L[0]=amp; R[0]=-amp-1
+    plan=[('ind',['fixed2','lpc8'],0),('ls',['fixed1','lpc4'],0),('sr',['lpc12','fixed3'],0),('ms',['fixed4','verb'],0),('ind',['const','fixed0'],0),('ms',['lpc1','lpc32'],0)]
+    # Block 4 is constant on the left channel.
+    for i in range(4*bs,5*bs): L[i]=1234
+    encode([L,R],[44100,48000][bits==24],bits,bs,plan,f'{out_dir}/stereo{bits}.flac')
+# Mono with three wasted bits.
+M=[(int(20000*math.sin(i*0.05))>>3)<<3 for i in range(bs*3+40)]
+encode([M],96000,16,bs,[('ind',['fixed2'],3),('ind',['lpc5'],3),('ind',['verb'],3)],f'{out_dir}/mono_wasted.flac')
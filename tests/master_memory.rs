@@ -0,0 +1,84 @@
+// Peak memory of the streaming mastering: it must stay bounded by the chunk
+// size however long the file is. Its own test binary, so the allocator
+// that tracks the peak doesn't sit under the library's other tests.
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use cpal_playbook::master::{master_file_for_streaming, MasterSettings, MASTER_CHUNK_FRAMES};
+use cpal_playbook::write_wav::{WavSampleFormat, WavStreamWriter};
+
+struct PeakAllocator;
+
+static LIVE: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for PeakAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            let live = LIVE.fetch_add(layout.size(), Ordering::Relaxed) + layout.size();
+            PEAK.fetch_max(live, Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        LIVE.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: PeakAllocator = PeakAllocator;
+
+const SAMPLE_RATE: u32 = 48_000;
+
+// Writes `seconds` of a stereo tone in pieces, so making the file doesn't
+// hold it in memory either.
+fn generate(path: &str, seconds: usize) {
+    let mut writer = WavStreamWriter::create(path, SAMPLE_RATE, 2, WavSampleFormat::Int16).unwrap();
+    let mut block = Vec::with_capacity(2 * SAMPLE_RATE as usize);
+    for second in 0..seconds {
+        block.clear();
+        for i in 0..SAMPLE_RATE as usize {
+            let t = (second * SAMPLE_RATE as usize + i) as f32 / SAMPLE_RATE as f32;
+            let s = 0.3 * (2.0 * std::f32::consts::PI * 220.0 * t).sin();
+            block.extend_from_slice(&[s, -s]);
+        }
+        writer.write_samples(&block).unwrap();
+    }
+    writer.finish().unwrap();
+}
+
+// Bytes allocated at the peak of mastering `seconds` of audio, above what
+// was live before.
+fn peak_while_mastering(seconds: usize) -> usize {
+    let dir = std::env::temp_dir();
+    let input = dir.join(format!("cpal_playbook_{}_long_{}.wav", std::process::id(), seconds));
+    let output = dir.join(format!("cpal_playbook_{}_long_{}_out.wav", std::process::id(), seconds));
+    let (input, output) = (input.to_str().unwrap().to_string(), output.to_str().unwrap().to_string());
+    generate(&input, seconds);
+
+    let before = LIVE.load(Ordering::Relaxed);
+    PEAK.store(before, Ordering::Relaxed);
+    let report = master_file_for_streaming(&input, &output, WavSampleFormat::Int16, &MasterSettings::default()).unwrap();
+    let peak = PEAK.load(Ordering::Relaxed) - before;
+
+    assert_eq!(report.frames, seconds * SAMPLE_RATE as usize);
+    let _ = std::fs::remove_file(&input);
+    let _ = std::fs::remove_file(&output);
+    peak
+}
+
+#[test]
+fn peak_memory_does_not_grow_with_the_file() {
+    // A chunk of stereo f32 samples, which the code holds a few of at once.
+    let chunk_bytes = MASTER_CHUNK_FRAMES * 2 * std::mem::size_of::<f32>();
+    let short = peak_while_mastering(10);
+    let long = peak_while_mastering(120);
+
+    // The long file is 46 MB as f32; the peak stays at a few chunks.
+    assert!(long < 8 * chunk_bytes, "{} bytes at the peak for 120 s", long);
+    // The meter keeps one value per 100 ms, so a little growth is expected.
+    assert!(long < short + chunk_bytes / 4, "{} bytes for 10 s, {} for 120 s", short, long);
+}